@@ -142,7 +142,7 @@ pub fn bench_status(c: &mut Criterion) {
                     let ws = setup_workspace(WS_FILES);
                     let mut repo = Repo::init(ws.path()).unwrap();
                     for_first_n_files(ws.path(), count, |path| {
-                        assert_eq!(repo.add(vec![path]).unwrap().len(), 1);
+                        assert_eq!(repo.add(vec![path.to_str().unwrap()]).unwrap().len(), 1);
                     });
                     (ws, repo)
                 },
@@ -170,6 +170,53 @@ pub fn bench_status(c: &mut Criterion) {
     group.finish();
 }
 
+fn index_entries(count: u64) -> impl Iterator<Item = index::Entry> {
+    (0..count).map(|i| {
+        let path = WsPath::new_unchecked(format!("dir_{}/file_{}.txt", i / 100, i));
+        index::Entry::new(path, Oid::zero(), Stat::zeroed())
+    })
+}
+
+/// Writing is one buffered, vectored write per entry, and reading borrows
+/// from a memory map
+pub fn bench_index(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index");
+    for &count in &[1_000, 10_000, 100_000] {
+        group.throughput(Throughput::Elements(count));
+        group.bench_with_input(BenchmarkId::new("commit", count), &count, |b, &count| {
+            b.iter_with_large_setup(
+                || {
+                    let dir = tempdir().unwrap();
+                    let index = Index::load(dir.path()).unwrap();
+                    (dir, index)
+                },
+                |(_dir, mut index)| {
+                    let mut index = index.modify().unwrap();
+                    for entry in index_entries(count) {
+                        index.add(entry);
+                    }
+                    index.commit().unwrap();
+                },
+            )
+        });
+
+        let dir = tempdir().unwrap();
+        let mut index = Index::load(dir.path()).unwrap();
+        let mut index_mut = index.modify().unwrap();
+        for entry in index_entries(count) {
+            index_mut.add(entry);
+        }
+        index_mut.commit().unwrap();
+        group.bench_with_input(BenchmarkId::new("load", count), &dir, |b, dir| {
+            b.iter(|| Index::load(dir.path()).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("load_mapped", count), &dir, |b, dir| {
+            b.iter(|| MappedIndex::open(dir.path()).unwrap())
+        });
+    }
+    group.finish();
+}
+
 // criterion_group!(core, bench_add, bench_status);
-criterion_group!(core, bench_status);
+criterion_group!(core, bench_status, bench_index);
 criterion_main!(core);
@@ -0,0 +1,69 @@
+//! Interrupting long-running operations, like status walks, pack writing,
+//! fetches and checkouts, from another thread
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Shared between the caller and the operations given it in their options.
+/// Once cancelled, they stop at their next check with [`Cancelled`]. The
+/// default is a fresh token, which is only cancelled if something holding a
+/// clone of it cancels it.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop every operation using this token (or a clone of it)
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fails if cancelled, for operations to call between steps
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Tokens are equal if both or neither are cancelled, so that options compare
+/// by what they'd do
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        self.is_cancelled() == other.is_cancelled()
+    }
+}
+
+impl Eq for CancelToken {}
+
+/// Operation was cancelled
+#[derive(Debug, Clone, Copy, Eq, PartialEq, thiserror::Error, displaydoc::Display)]
+pub struct Cancelled;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_are_cancelled_together() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(clone.check().is_ok());
+        token.cancel();
+        assert!(clone.is_cancelled());
+        assert_eq!(Err(Cancelled), clone.check());
+        assert!(!CancelToken::new().is_cancelled());
+        assert!(token != CancelToken::new());
+    }
+}
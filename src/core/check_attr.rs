@@ -0,0 +1,78 @@
+//! Reporting the attributes of paths, like `git check-attr`
+
+use std::{fmt, path::Path};
+
+use bstr::BString;
+use tracing::instrument;
+
+use crate::core::{
+    repo::BareError,
+    ws::{
+        self,
+        attributes::{self, State},
+        Attributes,
+    },
+    Repo, WsPath,
+};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AttrCheck {
+    pub path: WsPath,
+    pub attr: BString,
+    /// `None` if unspecified
+    pub state: Option<State>,
+}
+
+impl Repo {
+    /// The state of each of the attributes for each path (relative to the
+    /// workspace root), in order. With no attributes, every attribute
+    /// specified for each path is reported instead, like `--all`.
+    #[instrument(err)]
+    pub fn check_attr<I, P>(
+        &self,
+        paths: I,
+        attrs: &[&str],
+    ) -> Result<Vec<AttrCheck>, CheckAttrError>
+    where
+        I: IntoIterator<Item = P> + fmt::Debug,
+        P: AsRef<Path>,
+    {
+        let work = Self::workspace_of(self.workspace.as_ref(), self.git_dir())?;
+        let mut attributes = Attributes::new(self.common_dir())?;
+
+        let mut checks = Vec::new();
+        for path in paths {
+            let path = WsPath::new_normalized(path)?;
+            attributes.load_parents(work, &path)?;
+            if attrs.is_empty() {
+                checks.extend(
+                    attributes
+                        .all(&path)
+                        .into_iter()
+                        .map(|(attr, state)| AttrCheck {
+                            path: path.clone(),
+                            attr,
+                            state: Some(state),
+                        }),
+                );
+            } else {
+                checks.extend(attrs.iter().map(|attr| AttrCheck {
+                    path: path.clone(),
+                    attr: (*attr).into(),
+                    state: attributes.get(&path, attr),
+                }));
+            }
+        }
+        Ok(checks)
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum CheckAttrError {
+    /// {0}
+    Bare(#[from] BareError),
+    /// {0}
+    Normalize(#[from] ws::path::NormalizeError),
+    /// Failed to load attributes
+    LoadAttributes(#[from] attributes::LoadError),
+}
@@ -0,0 +1,77 @@
+//! Explaining why paths are or aren't ignored, like `git check-ignore
+//! --verbose --non-matching`
+
+use std::{fmt, path::Path};
+
+use tracing::instrument;
+
+use crate::core::{
+    index,
+    repo::BareError,
+    ws::{self, ignore::Pattern, IgnoreRules},
+    Repo, WsPath,
+};
+
+#[derive(Debug, Clone)]
+pub struct IgnoreCheck {
+    pub path: WsPath,
+    pub ignored: bool,
+    /// The pattern that decided, which may be a negated pattern re-including
+    /// the path, or the pattern that ignored a directory containing it. Its
+    /// source has the file and line it came from. `None` if no pattern
+    /// matches, or the path is tracked.
+    pub pattern: Option<Pattern>,
+}
+
+impl Repo {
+    /// Checks each path (relative to the workspace root) against the ignore
+    /// rules. Like git, tracked files are never ignored, whatever the rules
+    /// say, and paths that are directories in the workspace are checked as
+    /// directories.
+    #[instrument(err)]
+    pub fn check_ignore<I, P>(&mut self, paths: I) -> Result<Vec<IgnoreCheck>, CheckIgnoreError>
+    where
+        I: IntoIterator<Item = P> + fmt::Debug,
+        P: AsRef<Path>,
+    {
+        let work = Self::workspace_of(self.workspace.as_ref(), self.git_dir())?;
+        self.index.reload()?;
+        let mut rules = IgnoreRules::new(self.common_dir())?;
+
+        let mut checks = Vec::new();
+        for path in paths {
+            let path = WsPath::new_normalized(path)?;
+            if self.index.is_tracked_file(&path) {
+                checks.push(IgnoreCheck {
+                    path,
+                    ignored: false,
+                    pattern: None,
+                });
+                continue;
+            }
+
+            let is_dir = path.to_absolute(work)?.is_dir();
+            let pattern = rules.matching_with_parents(work, &path, is_dir)?.cloned();
+            checks.push(IgnoreCheck {
+                ignored: pattern.as_ref().is_some_and(|pattern| !pattern.negated),
+                path,
+                pattern,
+            });
+        }
+        Ok(checks)
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum CheckIgnoreError {
+    /// {0}
+    Bare(#[from] BareError),
+    /// {0}
+    Normalize(#[from] ws::path::NormalizeError),
+    /// {0}
+    InvalidPath(#[from] ws::path::InvalidPathError),
+    /// Failed to reload index
+    ReloadIndex(#[from] index::LoadError),
+    /// Failed to load ignore rules
+    LoadIgnores(#[from] ws::ignore::LoadError),
+}
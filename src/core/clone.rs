@@ -0,0 +1,166 @@
+//! Cloning a repository from another directory on this machine
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use bstr::{BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use crate::core::{
+    config, pack,
+    progress::{Progress, Reporter, Stage},
+    refs,
+    repo::{CheckoutError, InitError, ReadError},
+    Repo,
+};
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CloneOptions {
+    /// Copy objects rather than hard linking them to the source's
+    pub no_hardlinks: bool,
+    /// Leave the workspace and index empty
+    pub no_checkout: bool,
+}
+
+impl Repo {
+    /// Like `git clone <path>`. The branches of the source become
+    /// remote-tracking branches of `origin`, and its current branch is
+    /// checked out and set to track `origin`'s. If the source's HEAD is
+    /// detached, so is ours. `progress` hears how the objects are copied.
+    #[instrument(err, skip(progress))]
+    pub fn clone_local(
+        src: impl AsRef<Path> + fmt::Debug,
+        dst: impl Into<PathBuf> + fmt::Debug,
+        options: &CloneOptions,
+        progress: &mut dyn Progress,
+    ) -> Result<Self, CloneError> {
+        let src = src.as_ref();
+        let src_path = src
+            .canonicalize()
+            .map_err(|e| CloneError::Source(src.to_owned(), e))?;
+        let source = Repo::new(&src_path)?;
+        let mut repo = Repo::init(dst)?;
+
+        Self::copy_objects(
+            source.db.objects_dir(),
+            repo.db.objects_dir(),
+            options,
+            progress,
+        )?;
+        repo.db.load_packs()?;
+
+        for (name, oid) in source.refs.list(b"refs/heads/".as_bstr())? {
+            let branch = name.strip_prefix(b"refs/heads/").expect("Listed by prefix");
+            let tracking = BString::from([b"refs/remotes/origin/", branch].concat());
+            repo.refs.update_ref(tracking.as_bstr(), &oid)?;
+        }
+        for (name, oid) in source.refs.list(b"refs/tags/".as_bstr())? {
+            repo.refs.update_ref(name.as_bstr(), &oid)?;
+        }
+
+        let url = src_path.to_string_lossy();
+        repo.config.set("remote.origin.url", &url)?;
+        repo.config
+            .set("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?;
+
+        let head = source.refs.head()?;
+        let branch = source.refs.current_branch()?;
+        if let Some(branch) = &branch {
+            let branch = branch.to_str_lossy();
+            repo.config
+                .set(&format!("branch.{branch}.remote"), "origin")?;
+            repo.config.set(
+                &format!("branch.{branch}.merge"),
+                &format!("refs/heads/{branch}"),
+            )?;
+            repo.refs.update_symbolic_ref(
+                b"refs/remotes/origin/HEAD".as_bstr(),
+                format!("refs/remotes/origin/{branch}").as_bytes().as_bstr(),
+            )?;
+        }
+        repo.save_config()?;
+
+        if let Some(head) = head {
+            if options.no_checkout {
+                repo.refs.detach_head(&head)?;
+            } else {
+                // Before HEAD is set, so everything is checked out
+                repo.checkout(head)?;
+            }
+        }
+        if let Some(branch) = branch {
+            let branch = BString::from([b"refs/heads/", branch.as_bytes()].concat());
+            if let Some(head) = head {
+                repo.refs.update_ref(branch.as_bstr(), &head)?;
+            }
+            repo.refs
+                .update_symbolic_ref(b"HEAD".as_bstr(), branch.as_bstr())?;
+        }
+
+        Ok(repo)
+    }
+
+    /// Objects are never modified, so they can be shared with the source.
+    /// If hard linking fails (e.g. because they're on different devices) we
+    /// copy instead.
+    fn copy_objects(
+        src: &Path,
+        dst: &Path,
+        options: &CloneOptions,
+        progress: &mut dyn Progress,
+    ) -> Result<(), CloneError> {
+        let mut files = Vec::new();
+        for entry in walkdir::WalkDir::new(src) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                files.push(entry);
+            }
+        }
+
+        let mut reporter = Reporter::new(progress, Stage::Copying, Some(files.len()));
+        for entry in files {
+            let len = entry.metadata()?.len();
+            let rel = entry.path().strip_prefix(src).expect("Walked from src");
+            let to = dst.join(rel);
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent).map_err(|e| CloneError::CopyObject(to.clone(), e))?;
+            }
+
+            let linked = !options.no_hardlinks && fs::hard_link(entry.path(), &to).is_ok();
+            if !linked {
+                debug!("Copying {:?}", entry.path());
+                fs::copy(entry.path(), &to).map_err(|e| CloneError::CopyObject(to.clone(), e))?;
+            }
+            reporter.add(1, len);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum CloneError {
+    /// Failed to find repository to clone at {0:?}
+    Source(PathBuf, #[source] io::Error),
+    /// Failed to open repository to clone
+    Open(#[from] ReadError),
+    /// Failed to create repository
+    Init(#[from] InitError),
+    /// Failed to list objects to copy
+    ListObjects(#[from] walkdir::Error),
+    /// Failed to copy object to {0:?}
+    CopyObject(PathBuf, #[source] io::Error),
+    /// Failed to open copied packs
+    OpenPacks(#[from] pack::file::OpenPackError),
+    /// Failed to read refs
+    ReadRefs(#[from] refs::ReadError),
+    /// Failed to write refs
+    UpdateRefs(#[from] refs::UpdateError),
+    /// Failed to configure remote
+    Configure(#[from] config::EditError),
+    /// Failed to save config
+    SaveConfig(#[from] config::SaveError),
+    /// Failed to check out HEAD
+    Checkout(#[from] CheckoutError),
+}
@@ -0,0 +1,294 @@
+//! Composing commit messages, editing them, and cleaning them up before
+//! they're committed, like `git commit` and `git stripspace`
+
+use std::{
+    env,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use tracing::{debug, instrument};
+
+use crate::core::{config, merge::commits::MERGE_MSG, Config, Repo, WsPath};
+
+/// How a message is cleaned up before it's committed, like `git commit
+/// --cleanup`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Cleanup {
+    /// Like [`Self::Whitespace`], also removing commented lines
+    Strip,
+    /// Removes trailing whitespace, leading and trailing blank lines, and
+    /// runs of blank lines
+    #[default]
+    Whitespace,
+    /// Leaves the message as it is
+    Verbatim,
+}
+
+impl Cleanup {
+    /// `commit.cleanup`, `None` if it's unset or `default`
+    pub fn from_config(config: &Config) -> Result<Option<Self>, config::ValueError> {
+        match config
+            .get("commit.cleanup")
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("strip") => Ok(Some(Self::Strip)),
+            Some("whitespace") => Ok(Some(Self::Whitespace)),
+            Some("verbatim") => Ok(Some(Self::Verbatim)),
+            Some("default") | None => Ok(None),
+            Some(value) => Err(config::ValueError::Invalid(
+                "commit.cleanup".to_owned(),
+                value.to_owned(),
+            )),
+        }
+    }
+
+    /// Cleans up the message, which is left ending in a newline unless it's
+    /// empty or verbatim. Lines are commented if they start with
+    /// `comment_char`.
+    pub fn apply(self, msg: &str, comment_char: char) -> String {
+        if self == Self::Verbatim {
+            return msg.to_owned();
+        }
+        let mut cleaned = String::with_capacity(msg.len());
+        let mut blank_lines = 0;
+        for line in msg.lines() {
+            if self == Self::Strip && line.starts_with(comment_char) {
+                continue;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                blank_lines += 1;
+                continue;
+            }
+            // Runs of blank lines become one, unless they lead
+            if blank_lines > 0 && !cleaned.is_empty() {
+                cleaned.push('\n');
+            }
+            blank_lines = 0;
+            cleaned.push_str(line);
+            cleaned.push('\n');
+        }
+        cleaned
+    }
+}
+
+/// `core.commentChar`, by default `#`
+pub fn comment_char(config: &Config) -> char {
+    config
+        .get("core.commentChar")
+        .and_then(|value| value.chars().next())
+        .unwrap_or('#')
+}
+
+/// The command to edit messages with: `GIT_EDITOR`, `core.editor`, `VISUAL`
+/// or `EDITOR`, as git looks for it. `vi` if none is set, unless the terminal
+/// is `dumb`.
+pub fn editor(config: &Config) -> Option<String> {
+    editor_with(config, |var| env::var(var).ok())
+}
+
+fn editor_with(config: &Config, env: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let var = |name| env(name).filter(|value| !value.is_empty());
+    let dumb = env("TERM").is_none_or(|term| term == "dumb");
+    var("GIT_EDITOR")
+        .or_else(|| config.get("core.editor").map(str::to_owned))
+        .or_else(|| var("VISUAL").filter(|_| !dumb))
+        .or_else(|| var("EDITOR"))
+        .or_else(|| (!dumb).then(|| "vi".to_owned()))
+}
+
+/// Added to a message to be edited, to say how it will be cleaned up
+fn edit_hint(cleanup: Cleanup, comment_char: char) -> String {
+    let c = comment_char;
+    let lines = if cleanup == Cleanup::Strip {
+        format!("{c} with '{c}' will be ignored, and an empty message aborts the commit.\n")
+    } else {
+        format!(
+            "{c} with '{c}' will be kept; you may remove them yourself if you want to.\n\
+             {c} An empty message aborts the commit.\n"
+        )
+    };
+    format!("\n{c} Please enter the commit message for your changes. Lines starting\n{lines}")
+}
+
+/// The message of a merge with the paths that conflicted listed in comments,
+/// as git leaves it in `MERGE_MSG`
+pub(crate) fn with_conflicts(msg: &str, conflicts: &[WsPath], comment_char: char) -> String {
+    let mut msg = msg.to_owned();
+    if !conflicts.is_empty() {
+        write!(msg, "\n{comment_char} Conflicts:\n").expect("Writing to a string");
+        for path in conflicts {
+            writeln!(msg, "{comment_char}\t{path}").expect("Writing to a string");
+        }
+    }
+    msg
+}
+
+impl Repo {
+    /// The message the next commit starts from: `MERGE_MSG` while a merge is
+    /// in progress, or else the file `commit.template` names. `None` if there
+    /// is neither.
+    #[instrument(err)]
+    pub fn initial_commit_message(&self) -> Result<Option<String>, CommitMessageError> {
+        match fs::read_to_string(self.git_dir().join(MERGE_MSG)) {
+            Ok(msg) => return Ok(Some(msg)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(CommitMessageError::MergeMessage(err)),
+        }
+
+        let Some(template) = self.config.get_path("commit.template")? else {
+            return Ok(None);
+        };
+        fs::read_to_string(&template)
+            .map(Some)
+            .map_err(|e| CommitMessageError::Template(template, e))
+    }
+
+    /// Has the user edit the message in `.git/COMMIT_EDITMSG`, with a hint
+    /// as to how it will be cleaned up, and returns it cleaned up. Empty if
+    /// they emptied it, which aborts a commit.
+    #[instrument(err)]
+    pub fn edit_commit_message(&self, msg: &str, cleanup: Cleanup) -> Result<String, EditorError> {
+        let edited = self.edit_message_file(msg, cleanup)?;
+        Ok(cleanup.apply(&edited, comment_char(&self.config)))
+    }
+
+    /// The message as the user left it in `.git/COMMIT_EDITMSG`
+    pub(crate) fn edit_message_file(
+        &self,
+        msg: &str,
+        cleanup: Cleanup,
+    ) -> Result<String, EditorError> {
+        let path = self.git_dir().join("COMMIT_EDITMSG");
+        let hint = edit_hint(cleanup, comment_char(&self.config));
+        fs::write(&path, format!("{msg}{hint}")).map_err(EditorError::Write)?;
+        self.launch_editor(&path)?;
+        fs::read_to_string(&path).map_err(EditorError::Read)
+    }
+
+    /// Runs [`editor`] on the file and waits for the user to close it. The
+    /// editor is run by the shell, with the file as its argument.
+    #[instrument(err)]
+    pub fn launch_editor(&self, path: &Path) -> Result<(), EditorError> {
+        let editor = editor(&self.config).ok_or(EditorError::NoEditor)?;
+        // `:` is what tests use to leave messages as they are
+        if editor == ":" {
+            return Ok(());
+        }
+        debug!(%editor, "Launching");
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{editor} \"$@\""))
+            .arg(&editor)
+            .arg(path)
+            .status()
+            .map_err(|e| EditorError::Start(editor.clone(), e))?;
+        if !status.success() {
+            return Err(EditorError::Failed(editor));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum EditorError {
+    /// No editor is set and the terminal is dumb, set `core.editor` or
+    /// `GIT_EDITOR`
+    NoEditor,
+    /// Failed to write the message to edit
+    Write(#[source] io::Error),
+    /// Failed to start editor `{0}`
+    Start(String, #[source] io::Error),
+    /// Editor `{0}` failed
+    Failed(String),
+    /// Failed to read the edited message
+    Read(#[source] io::Error),
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum CommitMessageError {
+    /// Failed to read `MERGE_MSG`
+    MergeMessage(#[source] io::Error),
+    /// Failed to read commit template {0:?}
+    Template(PathBuf, #[source] io::Error),
+    /// Invalid config
+    Config(#[from] config::ValueError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const MSG: &str = "\n\n  \nSummary  \n\n\n# A comment\nBody\t\n\n";
+
+    #[test]
+    fn cleans_up_like_git() {
+        assert_eq!(
+            "Summary\n\n# A comment\nBody\n",
+            Cleanup::Whitespace.apply(MSG, '#')
+        );
+        assert_eq!("Summary\n\nBody\n", Cleanup::Strip.apply(MSG, '#'));
+        assert_eq!(
+            "Summary\n\n# A comment\nBody\n",
+            Cleanup::Strip.apply(MSG, ';')
+        );
+        assert_eq!(MSG, Cleanup::Verbatim.apply(MSG, '#'));
+        assert_eq!("", Cleanup::Strip.apply("# Only a comment\n", '#'));
+    }
+
+    #[test]
+    fn hints_at_cleanup() {
+        assert_eq!(
+            "\n; Please enter the commit message for your changes. Lines starting\n\
+             ; with ';' will be ignored, and an empty message aborts the commit.\n",
+            edit_hint(Cleanup::Strip, ';')
+        );
+        assert_eq!(
+            "",
+            Cleanup::Strip.apply(&edit_hint(Cleanup::Strip, '#'), '#')
+        );
+        assert!(edit_hint(Cleanup::Whitespace, '#').contains("will be kept"));
+    }
+
+    #[test]
+    fn lists_conflicts_in_merge_messages() {
+        let conflicts = [
+            WsPath::new_unchecked("a.txt"),
+            WsPath::new_unchecked("dir/b.txt"),
+        ];
+        assert_eq!(
+            "Merge commit 'x'\n\n# Conflicts:\n#\ta.txt\n#\tdir/b.txt\n",
+            with_conflicts("Merge commit 'x'\n", &conflicts, '#')
+        );
+        assert_eq!("Merge\n", with_conflicts("Merge\n", &[], '#'));
+    }
+
+    #[test]
+    fn finds_editor_like_git() {
+        let editor = |config: &str, env: &[(&str, &str)]| {
+            let config = Config::parse(config).unwrap();
+            editor_with(&config, |var| {
+                env.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| (*value).to_owned())
+            })
+        };
+        let config = "[core]\n\teditor = nano\n";
+        let env = [("TERM", "xterm"), ("VISUAL", "code"), ("EDITOR", "ed")];
+        assert_eq!(Some("nano"), editor(config, &env).as_deref());
+        assert_eq!(
+            Some("emacs"),
+            editor(config, &[("GIT_EDITOR", "emacs")]).as_deref()
+        );
+        assert_eq!(Some("code"), editor("", &env).as_deref());
+        // VISUAL is ignored on dumb terminals, and vi isn't assumed
+        assert_eq!(Some("ed"), editor("", &env[1..]).as_deref());
+        assert_eq!(None, editor("", &[("GIT_EDITOR", "")]));
+        assert_eq!(Some("vi"), editor("", &env[..1]).as_deref());
+    }
+}
@@ -0,0 +1,160 @@
+//! Color values, like `color.diff.old = red bold`
+
+use std::collections::BTreeSet;
+
+/// Up to two colors (the foreground, then the background) and any
+/// attributes, in any order. An empty value is no color.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Color {
+    pub foreground: Option<ColorValue>,
+    pub background: Option<ColorValue>,
+    pub attributes: BTreeSet<Attribute>,
+    /// Attributes turned off, given as e.g. `nobold` or `no-bold`
+    pub negated: BTreeSet<Attribute>,
+    /// Given as `reset`, to reset all colors and attributes first
+    pub reset: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ColorValue {
+    /// Leave the color as it is
+    Normal,
+    /// The terminal's default color
+    Default,
+    /// One of the eight basic colors, from black (0) to white (7)
+    Ansi(u8),
+    /// A bright version of a basic color
+    Bright(u8),
+    /// A color from the 256 color palette, given as a number
+    Ansi256(u8),
+    /// Given as `#rrggbb`
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Attribute {
+    Bold,
+    Dim,
+    Italic,
+    Underline,
+    Blink,
+    Reverse,
+    Strike,
+}
+
+impl Color {
+    const NAMES: [&'static str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+
+    /// `None` if the value isn't a valid color
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut color = Self::default();
+        for word in value.split_whitespace() {
+            let word = word.to_ascii_lowercase();
+            if let Some(value) = Self::parse_value(&word) {
+                if color.foreground.is_none() {
+                    color.foreground = Some(value);
+                } else if color.background.is_none() {
+                    color.background = Some(value);
+                } else {
+                    return None;
+                }
+            } else if word == "reset" {
+                color.reset = true;
+            } else if let Some(attribute) = Attribute::parse(&word) {
+                color.attributes.insert(attribute);
+            } else {
+                let negated = word
+                    .strip_prefix("no-")
+                    .or_else(|| word.strip_prefix("no"))?;
+                color.negated.insert(Attribute::parse(negated)?);
+            }
+        }
+        Some(color)
+    }
+
+    fn parse_value(word: &str) -> Option<ColorValue> {
+        let basic = |name: &str| {
+            (0..)
+                .zip(Self::NAMES.iter())
+                .find_map(|(idx, &n)| (n == name).then_some(idx))
+        };
+        if word == "normal" {
+            Some(ColorValue::Normal)
+        } else if word == "default" {
+            Some(ColorValue::Default)
+        } else if let Some(idx) = basic(word) {
+            Some(ColorValue::Ansi(idx))
+        } else if let Some(idx) = word.strip_prefix("bright").and_then(basic) {
+            Some(ColorValue::Bright(idx))
+        } else if let Some(hex) = word.strip_prefix('#') {
+            let bytes = hex::decode(hex).ok()?;
+            match bytes.as_slice() {
+                &[r, g, b] => Some(ColorValue::Rgb(r, g, b)),
+                _ => None,
+            }
+        } else {
+            word.parse().ok().map(ColorValue::Ansi256)
+        }
+    }
+}
+
+impl Attribute {
+    fn parse(word: &str) -> Option<Self> {
+        Some(match word {
+            "bold" => Self::Bold,
+            "dim" => Self::Dim,
+            "italic" => Self::Italic,
+            "ul" => Self::Underline,
+            "blink" => Self::Blink,
+            "reverse" => Self::Reverse,
+            "strike" => Self::Strike,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(Some(Color::default()), Color::parse(""));
+        assert_eq!(
+            Some(Color {
+                foreground: Some(ColorValue::Ansi(1)),
+                background: Some(ColorValue::Bright(4)),
+                attributes: [Attribute::Bold, Attribute::Underline]
+                    .iter()
+                    .copied()
+                    .collect(),
+                negated: [Attribute::Italic, Attribute::Dim]
+                    .iter()
+                    .copied()
+                    .collect(),
+                reset: true,
+            }),
+            Color::parse("bold Red reset brightblue ul noitalic no-dim")
+        );
+        assert_eq!(
+            Some(Color {
+                foreground: Some(ColorValue::Normal),
+                background: Some(ColorValue::Rgb(0xff, 0x00, 0x7f)),
+                ..Color::default()
+            }),
+            Color::parse("normal #FF007F")
+        );
+        assert_eq!(
+            Some(ColorValue::Ansi256(208)),
+            Color::parse("208").and_then(|color| color.foreground)
+        );
+
+        assert_eq!(None, Color::parse("red blue green"));
+        assert_eq!(None, Color::parse("red sparkly"));
+        assert_eq!(None, Color::parse("#ff00"));
+        assert_eq!(None, Color::parse("256"));
+    }
+}
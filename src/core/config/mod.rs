@@ -0,0 +1,1139 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use regex::bytes::Regex;
+
+use crate::core::{locked_file, platform, ws::ignore, LockedFile};
+
+pub mod color;
+
+pub use color::Color;
+
+/// A git config file (e.g. `.git/config`). Edits keep the comments, order
+/// and whitespace of everything they don't touch.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Config {
+    entries: Vec<Entry>,
+    /// The file as written, which edits are made to
+    text: String,
+    /// Where each entry is in the text
+    spans: Vec<Span>,
+    sections: Vec<Section>,
+    /// The resolved entries of the files included by each `include.path`
+    /// or `includeIf.<condition>.path`
+    included: BTreeMap<Directive, Vec<Entry>>,
+    /// Our entries, with included entries following the directive that
+    /// included them
+    resolved: Vec<Entry>,
+}
+
+/// The subsection (condition) and value of an include directive
+type Directive = (Option<String>, String);
+
+/// What `includeIf` conditions are checked against. A condition that needs
+/// something that isn't given doesn't hold.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Conditions {
+    /// For `gitdir:` and `gitdir/i:`
+    pub git_dir: Option<PathBuf>,
+    /// The short name of the branch checked out, for `onbranch:`
+    pub branch: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Entry {
+    /// Lowercased, as section names are case insensitive
+    pub section: String,
+    pub subsection: Option<String>,
+    /// Lowercased, as keys are case insensitive
+    pub key: String,
+    /// `None` if the key was given without an `=`, which means true
+    pub value: Option<String>,
+}
+
+/// Zero-based line numbers
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct Span {
+    start: usize,
+    /// Exclusive. More than one line after `start` if the value was continued.
+    end: usize,
+    /// Index into [`Config::sections`]
+    section: usize,
+    /// If the entry follows its section header on the same line, the length
+    /// of the header
+    after_header: Option<usize>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Section {
+    name: String,
+    subsection: Option<String>,
+    line: usize,
+}
+
+impl Config {
+    /// Includes are nested at most this deep, so that a cycle is an error
+    const MAX_INCLUDE_DEPTH: usize = 10;
+
+    /// Like [`Self::load_with`], with no `includeIf` conditions holding
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        Self::load_with(path, &Conditions::default())
+    }
+
+    /// A missing file is treated as empty, as is a missing included file.
+    /// Edits only change this file, not the files it includes.
+    pub fn load_with(path: impl AsRef<Path>, conditions: &Conditions) -> Result<Self, LoadError> {
+        Self::load_at_depth(path.as_ref(), conditions, 0)
+    }
+
+    fn load_at_depth(
+        path: &Path,
+        conditions: &Conditions,
+        depth: usize,
+    ) -> Result<Self, LoadError> {
+        let mut config = Self::load_file(path)?;
+        for entry in &config.entries {
+            let Some(directive) = Self::directive(entry) else {
+                continue;
+            };
+            if config.included.contains_key(&directive) {
+                continue;
+            }
+            let Some(target) = Self::include_target(&directive, path, conditions) else {
+                continue;
+            };
+            if depth >= Self::MAX_INCLUDE_DEPTH {
+                return Err(LoadError::IncludeDepth(target));
+            }
+            let included = Self::load_at_depth(&target, conditions, depth + 1)?;
+            config.included.insert(directive, included.resolved);
+        }
+        config.resolve();
+        Ok(config)
+    }
+
+    fn load_file(path: &Path) -> Result<Self, LoadError> {
+        match fs::read_to_string(path) {
+            Ok(input) => Self::parse(&input).map_err(|e| LoadError::Parse(path.to_owned(), e)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(LoadError::Read(path.to_owned(), err)),
+        }
+    }
+
+    pub fn parse(input: &str) -> Result<Self, ParseError> {
+        let mut config = Self {
+            text: input.to_owned(),
+            ..Self::default()
+        };
+
+        let mut lines = input.lines().enumerate();
+        while let Some((idx, line)) = lines.next() {
+            let line_no = idx + 1;
+            let trimmed = line.trim_start();
+
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+                continue;
+            }
+
+            let after_header = if trimmed.starts_with('[') {
+                let ((section, subsection), rest) =
+                    parse_section_header(trimmed).ok_or(ParseError::InvalidSection(line_no))?;
+                config.sections.push(Section {
+                    name: section,
+                    subsection,
+                    line: idx,
+                });
+
+                // A key can follow the header on the same line
+                let rest = rest.trim_start();
+                if rest.is_empty() || rest.starts_with('#') || rest.starts_with(';') {
+                    continue;
+                }
+                Some((line.len() - rest.len(), rest))
+            } else {
+                None
+            };
+
+            let section = config
+                .sections
+                .len()
+                .checked_sub(1)
+                .ok_or(ParseError::EntryOutsideSection(line_no))?;
+            let text = after_header.map_or(trimmed, |(_, rest)| rest);
+            let (entry, last) = parse_entry(text, &mut lines, idx)?;
+            config.entries.push(Entry {
+                section: config.sections[section].name.clone(),
+                subsection: config.sections[section].subsection.clone(),
+                ..entry
+            });
+            config.spans.push(Span {
+                start: idx,
+                end: last + 1,
+                section,
+                after_header: after_header.map(|(len, _)| len),
+            });
+        }
+
+        config.resolve();
+        Ok(config)
+    }
+
+    /// The directive of an `include.path` or `includeIf.<condition>.path`
+    /// entry
+    fn directive(entry: &Entry) -> Option<Directive> {
+        let is_include = matches!(
+            (entry.section.as_str(), &entry.subsection),
+            ("include", None) | ("includeif", Some(_))
+        );
+        match &entry.value {
+            Some(value) if is_include && entry.key == "path" && !value.is_empty() => {
+                Some((entry.subsection.clone(), value.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The file to include, if the condition holds. Relative paths are
+    /// relative to the directory of the including file.
+    fn include_target(
+        (condition, value): &Directive,
+        including: &Path,
+        conditions: &Conditions,
+    ) -> Option<PathBuf> {
+        let dir = including.parent().unwrap_or_else(|| Path::new(""));
+        if let Some(condition) = condition {
+            if !Self::condition_holds(condition, dir, conditions) {
+                return None;
+            }
+        }
+        let target = expand_home(value)?;
+        Some(if target.is_absolute() {
+            target
+        } else {
+            dir.join(target)
+        })
+    }
+
+    /// Conditions we don't know never hold, like in git
+    fn condition_holds(condition: &str, dir: &Path, conditions: &Conditions) -> bool {
+        let git_dir = || conditions.git_dir.clone().map(platform::into_bytes);
+        if let Some(pattern) = condition.strip_prefix("gitdir:") {
+            Self::matches(Self::gitdir_pattern(pattern, dir), git_dir(), false)
+        } else if let Some(pattern) = condition.strip_prefix("gitdir/i:") {
+            Self::matches(Self::gitdir_pattern(pattern, dir), git_dir(), true)
+        } else if let Some(pattern) = condition.strip_prefix("onbranch:") {
+            let branch = conditions.branch.clone().map(String::into_bytes);
+            Self::matches(Some(Self::dir_pattern(pattern.to_owned())), branch, false)
+        } else {
+            false
+        }
+    }
+
+    /// Patterns are globs, as in `.gitignore`
+    fn matches(pattern: Option<String>, subject: Option<Vec<u8>>, case_insensitive: bool) -> bool {
+        let (Some(pattern), Some(subject)) = (pattern, subject) else {
+            return false;
+        };
+        let mut re = ignore::Pattern::to_regex(pattern.as_bytes(), true);
+        if case_insensitive {
+            re.insert_str(0, "(?i)");
+        }
+        Regex::new(&re).is_ok_and(|re| re.is_match(&subject))
+    }
+
+    /// `~/` is the home directory and `./` the directory of the including
+    /// file. Other relative patterns match at any depth.
+    fn gitdir_pattern(pattern: &str, dir: &Path) -> Option<String> {
+        let pattern = if let Some(rest) = pattern.strip_prefix("./") {
+            let dir = platform::into_bytes(dir.to_owned());
+            format!("{}/{rest}", String::from_utf8_lossy(&dir))
+        } else {
+            let expanded = platform::into_bytes(expand_home(pattern)?);
+            String::from_utf8_lossy(&expanded).into_owned()
+        };
+        let pattern = if pattern.starts_with('/') || Path::new(&pattern).is_absolute() {
+            pattern
+        } else {
+            format!("**/{pattern}")
+        };
+        Some(Self::dir_pattern(pattern))
+    }
+
+    /// A pattern ending in `/` matches everything within
+    fn dir_pattern(mut pattern: String) -> String {
+        if pattern.ends_with('/') {
+            pattern.push_str("**");
+        }
+        pattern
+    }
+
+    /// Our entries with the included entries spliced in
+    fn resolve(&mut self) {
+        let mut resolved = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            resolved.push(entry.clone());
+            if let Some(included) = Self::directive(entry).and_then(|d| self.included.get(&d)) {
+                resolved.extend(included.iter().cloned());
+            }
+        }
+        self.resolved = resolved;
+    }
+
+    /// The file as it would be saved
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Written through a lock, so readers never see a partly written file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SaveError> {
+        let path = path.as_ref();
+        let mut lock = LockedFile::acquire(path)?;
+        lock.write_all(self.text.as_bytes())
+            .map_err(|e| SaveError::Io(path.to_owned(), e))?;
+        lock.commit()
+            .map_err(|e| SaveError::Io(path.to_owned(), e))?;
+        Ok(())
+    }
+
+    /// Replace the value of the name, or add it if it isn't set. Like
+    /// `git config`, this refuses to replace more than one value.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), EditError> {
+        let parsed = Self::parse_name_for_edit(name)?;
+        let matching = self.matching(&parsed);
+        match matching.as_slice() {
+            [] => self.add(name, value),
+            &[idx] => {
+                let line = self.entry_line(idx, parsed.key_as_given, value);
+                self.replace_lines(self.spans[idx], Some(&line));
+                Ok(())
+            }
+            _ => Err(EditError::MultipleValues(name.to_owned())),
+        }
+    }
+
+    /// Add a value for the name, after any it already has. The value goes at
+    /// the end of the last section it could go in, and a section is added if
+    /// there isn't one.
+    pub fn add(&mut self, name: &str, value: &str) -> Result<(), EditError> {
+        let parsed = Self::parse_name_for_edit(name)?;
+        let section = self.sections.iter().rposition(|section| {
+            section.name == parsed.name.section
+                && section.subsection.as_deref() == parsed.name.subsection
+        });
+
+        let mut lines = self.lines();
+        if lines.last().is_some_and(|line| !line.ends_with('\n')) {
+            lines.last_mut().expect("Just checked").push('\n');
+        }
+        let entry = format!(
+            "\t{} = {}\n",
+            parsed.key_as_given,
+            Self::format_value(value)
+        );
+        if let Some(section) = section {
+            let end = self
+                .spans
+                .iter()
+                .filter(|span| span.section == section)
+                .map(|span| span.end)
+                .max()
+                .unwrap_or(self.sections[section].line + 1);
+            lines.insert(end.min(lines.len()), entry);
+        } else {
+            let mut header = format!("[{}", parsed.section_as_given);
+            if let Some(subsection) = parsed.name.subsection {
+                let escaped = subsection.replace('\\', "\\\\").replace('"', "\\\"");
+                write!(header, " \"{escaped}\"").expect("Writing to a string");
+            }
+            header.push_str("]\n");
+            lines.push(header);
+            lines.push(entry);
+        }
+        self.set_text(&lines.concat());
+        Ok(())
+    }
+
+    /// Remove the value of the name, returning whether it was set. Like
+    /// `git config --unset`, this refuses to remove more than one value.
+    pub fn unset(&mut self, name: &str) -> Result<bool, EditError> {
+        let parsed = Self::parse_name_for_edit(name)?;
+        match self.matching(&parsed).as_slice() {
+            [] => Ok(false),
+            &[idx] => {
+                self.replace_lines(self.spans[idx], None);
+                Ok(true)
+            }
+            _ => Err(EditError::MultipleValues(name.to_owned())),
+        }
+    }
+
+    /// Remove every value of the name, returning how many there were
+    pub fn unset_all(&mut self, name: &str) -> Result<usize, EditError> {
+        let parsed = Self::parse_name_for_edit(name)?;
+        let matching = self.matching(&parsed);
+        let mut lines = self.lines();
+        // Backwards, so that earlier spans stay where they are
+        for &idx in matching.iter().rev() {
+            Self::replace_in(&mut lines, self.spans[idx], None);
+        }
+        self.set_text(&lines.concat());
+        Ok(matching.len())
+    }
+
+    fn parse_name_for_edit(name: &str) -> Result<EditName<'_>, EditError> {
+        let invalid = || EditError::InvalidName(name.to_owned());
+        let parsed = Name::parse(name).ok_or_else(invalid)?;
+        let (section_as_given, rest) = name.split_once('.').ok_or_else(invalid)?;
+        let key_as_given = rest.rsplit_once('.').map_or(rest, |(_, key)| key);
+        if !is_valid_section(section_as_given)
+            || section_as_given.contains('.')
+            || !is_valid_key(key_as_given)
+            || parsed.subsection.is_some_and(|sub| sub.contains('\n'))
+        {
+            return Err(invalid());
+        }
+        Ok(EditName {
+            name: parsed,
+            section_as_given,
+            key_as_given,
+        })
+    }
+
+    /// Indexes of the entries for the name
+    fn matching(&self, name: &EditName) -> Vec<usize> {
+        (0..self.entries.len())
+            .filter(|&idx| name.name.matches(&self.entries[idx]))
+            .collect()
+    }
+
+    /// The line to write for a new value of an entry, indented like the old
+    /// one
+    fn entry_line(&self, idx: usize, key: &str, value: &str) -> String {
+        let span = self.spans[idx];
+        let old = self.text.lines().nth(span.start).unwrap_or_default();
+        let value = Self::format_value(value);
+        if let Some(len) = span.after_header {
+            format!("{} {key} = {value}\n", old[..len].trim_end())
+        } else {
+            let indent = &old[..old.len() - old.trim_start().len()];
+            format!("{indent}{key} = {value}\n")
+        }
+    }
+
+    fn replace_lines(&mut self, span: Span, with: Option<&str>) {
+        let mut lines = self.lines();
+        Self::replace_in(&mut lines, span, with);
+        self.set_text(&lines.concat());
+    }
+
+    /// Replace the lines of the span with the replacement, if any. A header
+    /// before the entry on the same line is kept.
+    fn replace_in(lines: &mut Vec<String>, span: Span, with: Option<&str>) {
+        let end = span.end.min(lines.len());
+        let replacement = match (with, span.after_header) {
+            (Some(with), _) => vec![with.to_owned()],
+            (None, Some(len)) => vec![format!("{}\n", lines[span.start][..len].trim_end())],
+            (None, None) => Vec::new(),
+        };
+        lines.splice(span.start..end, replacement);
+    }
+
+    /// The lines of the text, each with its line ending
+    fn lines(&self) -> Vec<String> {
+        self.text.split_inclusive('\n').map(str::to_owned).collect()
+    }
+
+    /// Directives added by an edit don't include anything until the config
+    /// is loaded again
+    fn set_text(&mut self, text: &str) {
+        let included = std::mem::take(&mut self.included);
+        *self = Self::parse(text).expect("Edits keep config valid");
+        self.included = included;
+        self.resolve();
+    }
+
+    /// Quoted and escaped as needed to parse back to the same value
+    fn format_value(value: &str) -> String {
+        let quote = value.starts_with(char::is_whitespace)
+            || value.ends_with(char::is_whitespace)
+            || value.contains(['#', ';']);
+        let mut out = String::with_capacity(value.len() + 2);
+        if quote {
+            out.push('"');
+        }
+        for c in value.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                '\t' => out.push_str("\\t"),
+                '\u{8}' => out.push_str("\\b"),
+                c => out.push(c),
+            }
+        }
+        if quote {
+            out.push('"');
+        }
+        out
+    }
+
+    /// Including those of included files
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.resolved.iter()
+    }
+
+    /// The last value for a name of the form `section.key` or
+    /// `section.subsection.key`. A key given without a value is `""`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_entry(name)
+            .map(|entry| entry.value.as_deref().unwrap_or(""))
+    }
+
+    /// Every value for the name, in the order they were given
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        let name = Name::parse(name);
+        self.resolved
+            .iter()
+            .filter(move |entry| name.as_ref().is_some_and(|name| name.matches(entry)))
+            .map(|entry| entry.value.as_deref().unwrap_or(""))
+    }
+
+    /// Parses the last value like git does: `true`/`yes`/`on`/`1` and
+    /// `false`/`no`/`off`/`0`/`""`, ignoring case. A key without a value is
+    /// true.
+    pub fn get_bool(&self, name: &str) -> Result<Option<bool>, ValueError> {
+        let Some(entry) = self.get_entry(name) else {
+            return Ok(None);
+        };
+        let Some(value) = &entry.value else {
+            return Ok(Some(true));
+        };
+
+        match value.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(Some(true)),
+            "false" | "no" | "off" | "0" | "" => Ok(Some(false)),
+            _ => Err(ValueError::NotBool(name.to_owned(), value.clone())),
+        }
+    }
+
+    /// Parses the last value as an integer, which can have a `k`, `m` or `g`
+    /// suffix (ignoring case) to multiply it by 1024, 1024² or 1024³
+    pub fn get_int(&self, name: &str) -> Result<Option<i64>, ValueError> {
+        let Some(value) = self.get_value(name)? else {
+            return Ok(None);
+        };
+        parse_int(value)
+            .map(Some)
+            .ok_or_else(|| ValueError::NotInt(name.to_owned(), value.to_owned()))
+    }
+
+    /// The last value as a path, with a leading `~/` expanded to the home
+    /// directory
+    pub fn get_path(&self, name: &str) -> Result<Option<PathBuf>, ValueError> {
+        let Some(value) = self.get_value(name)? else {
+            return Ok(None);
+        };
+        let value = if value == "~" { "~/" } else { value };
+        expand_home(value)
+            .map(Some)
+            .ok_or_else(|| ValueError::NoHome(name.to_owned()))
+    }
+
+    /// Parses the last value as a color, see [`Color`]
+    pub fn get_color(&self, name: &str) -> Result<Option<Color>, ValueError> {
+        let Some(value) = self.get_value(name)? else {
+            return Ok(None);
+        };
+        Color::parse(value)
+            .map(Some)
+            .ok_or_else(|| ValueError::NotColor(name.to_owned(), value.to_owned()))
+    }
+
+    /// The last value, which must have been given with an `=`
+    fn get_value(&self, name: &str) -> Result<Option<&str>, ValueError> {
+        match self.get_entry(name) {
+            Some(Entry {
+                value: Some(value), ..
+            }) => Ok(Some(value)),
+            Some(Entry { value: None, .. }) => Err(ValueError::Missing(name.to_owned())),
+            None => Ok(None),
+        }
+    }
+
+    fn get_entry(&self, name: &str) -> Option<&Entry> {
+        let name = Name::parse(name)?;
+        self.resolved.iter().rev().find(|entry| name.matches(entry))
+    }
+}
+
+/// A name being edited, with the parts we write as they were given
+struct EditName<'a> {
+    name: Name<'a>,
+    section_as_given: &'a str,
+    key_as_given: &'a str,
+}
+
+struct Name<'a> {
+    section: String,
+    subsection: Option<&'a str>,
+    key: String,
+}
+
+impl<'a> Name<'a> {
+    fn parse(name: &'a str) -> Option<Self> {
+        let (section, rest) = name.split_once('.')?;
+        let (subsection, key) = match rest.rsplit_once('.') {
+            Some((subsection, key)) => (Some(subsection), key),
+            None => (None, rest),
+        };
+        Some(Self {
+            section: section.to_ascii_lowercase(),
+            subsection,
+            key: key.to_ascii_lowercase(),
+        })
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        self.section == entry.section
+            && self.subsection == entry.subsection.as_deref()
+            && self.key == entry.key
+    }
+}
+
+/// Returns the header and the rest of the line
+fn parse_section_header(line: &str) -> Option<((String, Option<String>), &str)> {
+    let line = line.strip_prefix('[')?;
+    let name_end = line.find(|c: char| c == ']' || c.is_whitespace())?;
+    let name = &line[..name_end];
+    let rest = line[name_end..].trim_start();
+
+    if let Some(rest) = rest.strip_prefix(']') {
+        // Deprecated `[section.subsection]` syntax, where the subsection is
+        // case insensitive
+        let header = match name.split_once('.') {
+            Some((section, subsection)) => (section, Some(subsection.to_ascii_lowercase())),
+            None => (name, None),
+        };
+        if !is_valid_section(header.0) {
+            return None;
+        }
+        return Some(((header.0.to_ascii_lowercase(), header.1), rest));
+    }
+
+    if !is_valid_section(name) {
+        return None;
+    }
+
+    let mut chars = rest.strip_prefix('"')?.char_indices();
+    let mut subsection = String::new();
+    loop {
+        match chars.next()? {
+            (_, '\\') => subsection.push(chars.next()?.1),
+            (idx, '"') => {
+                let rest = rest[idx + 2..].strip_prefix(']')?;
+                return Some(((name.to_ascii_lowercase(), Some(subsection)), rest));
+            }
+            (_, c) => subsection.push(c),
+        }
+    }
+}
+
+fn is_valid_section(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+fn is_valid_key(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_alphabetic())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Parses `key = value`, reading more lines if the value is continued with a
+/// trailing backslash. The section fields of the returned entry are empty.
+/// Also returns the index of the last line read.
+fn parse_entry<'a>(
+    line: &'a str,
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    idx: usize,
+) -> Result<(Entry, usize), ParseError> {
+    let line_no = idx + 1;
+    let key_end = line
+        .find(|c: char| c == '=' || c.is_whitespace() || c == '#' || c == ';')
+        .unwrap_or(line.len());
+    let key = &line[..key_end];
+    if !is_valid_key(key) {
+        return Err(ParseError::InvalidKey(line_no));
+    }
+    let key = key.to_ascii_lowercase();
+
+    let rest = line[key_end..].trim_start();
+    let (value, last) = if let Some(rest) = rest.strip_prefix('=') {
+        let (value, last) = parse_value(rest, lines, idx)?;
+        (Some(value), last)
+    } else if rest.is_empty() || rest.starts_with('#') || rest.starts_with(';') {
+        (None, idx)
+    } else {
+        return Err(ParseError::InvalidKey(line_no));
+    };
+
+    let entry = Entry {
+        section: String::new(),
+        subsection: None,
+        key,
+        value,
+    };
+    Ok((entry, last))
+}
+
+fn parse_value<'a>(
+    mut line: &'a str,
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+    mut idx: usize,
+) -> Result<(String, usize), ParseError> {
+    let line_no = idx + 1;
+    let mut value = String::new();
+    let mut in_quotes = false;
+    // Whitespace is only kept if it's followed by something else
+    let mut pending_space = String::new();
+
+    loop {
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    let escaped = match chars.next() {
+                        None => break,
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('b') => '\u{8}',
+                        Some(c @ ('\\' | '"')) => c,
+                        Some(_) => return Err(ParseError::InvalidEscape(line_no)),
+                    };
+                    value.push_str(&pending_space);
+                    pending_space.clear();
+                    value.push(escaped);
+                }
+                '"' => {
+                    value.push_str(&pending_space);
+                    pending_space.clear();
+                    in_quotes = !in_quotes;
+                }
+                c if c.is_whitespace() && !in_quotes => {
+                    if !value.is_empty() {
+                        pending_space.push(c);
+                    }
+                }
+                '#' | ';' if !in_quotes => return Ok((value, idx)),
+                c => {
+                    value.push_str(&pending_space);
+                    pending_space.clear();
+                    value.push(c);
+                }
+            }
+        }
+
+        if !ends_with_continuation(line) {
+            break;
+        }
+        match lines.next() {
+            Some((next_idx, next)) => {
+                idx = next_idx;
+                line = next;
+            }
+            None => break,
+        }
+    }
+
+    if in_quotes {
+        return Err(ParseError::UnclosedQuote(line_no));
+    }
+    Ok((value, idx))
+}
+
+/// If the line ends with an unescaped backslash
+/// `None` if invalid or out of range
+fn parse_int(value: &str) -> Option<i64> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last()? {
+        (idx, 'k' | 'K') => (&value[..idx], 1 << 10),
+        (idx, 'm' | 'M') => (&value[..idx], 1 << 20),
+        (idx, 'g' | 'G') => (&value[..idx], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<i64>().ok()?.checked_mul(multiplier)
+}
+
+/// `None` if the path is under the home directory but there isn't one
+pub(crate) fn expand_home(path: &str) -> Option<PathBuf> {
+    match path.strip_prefix("~/") {
+        Some(rest) => Some(PathBuf::from(env::var_os("HOME")?).join(rest)),
+        None => Some(PathBuf::from(path)),
+    }
+}
+
+fn ends_with_continuation(line: &str) -> bool {
+    line.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum LoadError {
+    /// Failed to read config file {0:?}
+    Read(PathBuf, #[source] io::Error),
+    /// Failed to parse config file {0:?}
+    Parse(PathBuf, #[source] ParseError),
+    /// Config includes nested too deeply including {0:?}, there may be a cycle
+    IncludeDepth(PathBuf),
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error, Eq, PartialEq)]
+pub enum ParseError {
+    /// Invalid section header on line {0}
+    InvalidSection(usize),
+    /// Entry outside of any section on line {0}
+    EntryOutsideSection(usize),
+    /// Invalid key on line {0}
+    InvalidKey(usize),
+    /// Invalid escape sequence on line {0}
+    InvalidEscape(usize),
+    /// Unclosed quote in value on line {0}
+    UnclosedQuote(usize),
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum SaveError {
+    /// Failed to lock config file
+    Lock(#[from] locked_file::Error),
+    /// Failed to write config file {0:?}
+    Io(PathBuf, #[source] io::Error),
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error, Eq, PartialEq)]
+pub enum EditError {
+    /// Invalid config name {0:?}
+    InvalidName(String),
+    /// Config {0} has multiple values
+    MultipleValues(String),
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error, Eq, PartialEq)]
+pub enum ValueError {
+    /// Config value {0} is not a boolean: {1:?}
+    NotBool(String, String),
+    /// Config value {0} is not an integer: {1:?}
+    NotInt(String, String),
+    /// Config value {0} is not a color: {1:?}
+    NotColor(String, String),
+    /// Config {0} is given without a value
+    Missing(String),
+    /// Config {0} is a path in the home directory, but there's no home
+    /// directory
+    NoHome(String),
+    /// Invalid value for config {0}: {1:?}
+    Invalid(String, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const SAMPLE: &str = r#"
+# A comment
+[core]
+	repositoryformatversion = 0
+	fileMode = false
+	bare
+[remote "origin"]
+	url = https://example.com/repo.git ; trailing comment
+	fetch = +refs/heads/*:refs/remotes/origin/*
+[Branch "Main"]
+	remote = origin
+[alias]
+	quoted = "  spaced # not a comment  "
+	escaped = a\tb\\c\"d
+	continued = first \
+second
+"#;
+
+    #[test]
+    fn gets_values() -> eyre::Result<()> {
+        let config = Config::parse(SAMPLE)?;
+
+        assert_eq!(Some("0"), config.get("core.repositoryformatversion"));
+        assert_eq!(Some("false"), config.get("CORE.filemode"));
+        assert_eq!(
+            Some("https://example.com/repo.git"),
+            config.get("remote.origin.url")
+        );
+        assert_eq!(Some("origin"), config.get("branch.Main.remote"));
+        assert_eq!(None, config.get("branch.main.remote"));
+        assert_eq!(
+            Some("  spaced # not a comment  "),
+            config.get("alias.quoted")
+        );
+        assert_eq!(Some("a\tb\\c\"d"), config.get("alias.escaped"));
+        assert_eq!(Some("first second"), config.get("alias.continued"));
+        assert_eq!(None, config.get("core.missing"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn gets_bools() -> eyre::Result<()> {
+        let config = Config::parse(SAMPLE)?;
+
+        assert_eq!(Ok(Some(false)), config.get_bool("core.filemode"));
+        assert_eq!(Ok(Some(true)), config.get_bool("core.bare"));
+        assert_eq!(Ok(None), config.get_bool("core.missing"));
+        assert!(config.get_bool("remote.origin.url").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn gets_typed_values() -> eyre::Result<()> {
+        let config = Config::parse(
+            "[a]\n\tint = -12\n\tkilo = 2k\n\tgiga = 1G\n\thuge = 9999999999g\n\tbare\n\tpath = ~/dir\n\tcolor = bold red\n",
+        )?;
+
+        assert_eq!(Ok(Some(-12)), config.get_int("a.int"));
+        assert_eq!(Ok(Some(2048)), config.get_int("a.kilo"));
+        assert_eq!(Ok(Some(1 << 30)), config.get_int("a.giga"));
+        assert_eq!(Ok(None), config.get_int("a.missing"));
+        assert_eq!(
+            Err(ValueError::NotInt(
+                "a.huge".to_owned(),
+                "9999999999g".to_owned()
+            )),
+            config.get_int("a.huge")
+        );
+        assert_eq!(
+            Err(ValueError::NotInt("a.path".to_owned(), "~/dir".to_owned())),
+            config.get_int("a.path")
+        );
+        assert_eq!(
+            Err(ValueError::Missing("a.bare".to_owned())),
+            config.get_int("a.bare")
+        );
+
+        if let Some(home) = env::var_os("HOME") {
+            assert_eq!(
+                Ok(Some(PathBuf::from(home).join("dir"))),
+                config.get_path("a.path")
+            );
+        }
+        assert_eq!(Ok(Some(PathBuf::from("2k"))), config.get_path("a.kilo"));
+
+        let color = config.get_color("a.color")?.expect("Is set");
+        assert_eq!(Some(color::ColorValue::Ansi(1)), color.foreground);
+        assert!(color.attributes.contains(&color::Attribute::Bold));
+        assert!(config.get_color("a.int").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn last_value_wins() -> eyre::Result<()> {
+        let config = Config::parse("[a]\nb = 1\n[a]\nb = 2\n")?;
+        assert_eq!(Some("2"), config.get("a.b"));
+        assert_eq!(vec!["1", "2"], config.get_all("a.b").collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn edits_keep_formatting() -> eyre::Result<()> {
+        let mut config = Config::parse(SAMPLE)?;
+
+        config.set("core.fileMode", "true")?;
+        config.set("remote.origin.url", "git@example.com:repo.git")?;
+        config.set("alias.continued", "one line")?;
+        config.add("remote.origin.fetch", "+refs/tags/*:refs/tags/*")?;
+        config.set("branch.feature.remote", "origin")?;
+        assert!(config.unset("core.bare")?);
+        assert!(!config.unset("core.missing")?);
+
+        assert_eq!(
+            r#"
+# A comment
+[core]
+	repositoryformatversion = 0
+	fileMode = true
+[remote "origin"]
+	url = git@example.com:repo.git
+	fetch = +refs/heads/*:refs/remotes/origin/*
+	fetch = +refs/tags/*:refs/tags/*
+[Branch "Main"]
+	remote = origin
+[alias]
+	quoted = "  spaced # not a comment  "
+	escaped = a\tb\\c\"d
+	continued = one line
+[branch "feature"]
+	remote = origin
+"#,
+            config.text()
+        );
+        assert_eq!(Some("true"), config.get("core.filemode"));
+        assert_eq!(Some("origin"), config.get("branch.feature.remote"));
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_values() -> eyre::Result<()> {
+        let mut config = Config::default();
+        for value in [
+            "",
+            " padded ",
+            "a # b",
+            "quote \" and \\",
+            "tab\tand\nnewline",
+        ] {
+            config.set("a.b", value)?;
+            assert_eq!(Some(value), config.get("a.b"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn edits_entries_after_headers() -> eyre::Result<()> {
+        let mut config = Config::parse("[a] b = 1 ; note\n[c]\n")?;
+        config.set("a.b", "2")?;
+        assert_eq!("[a] b = 2\n[c]\n", config.text());
+        config.unset("a.b")?;
+        assert_eq!("[a]\n[c]\n", config.text());
+        config.add("c.d", "3")?;
+        assert_eq!("[a]\n[c]\n\td = 3\n", config.text());
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_ambiguous_edits() -> eyre::Result<()> {
+        let mut config = Config::parse("[a]\nb = 1\nb = 2\n")?;
+        assert_eq!(
+            Err(EditError::MultipleValues("a.b".to_owned())),
+            config.set("a.b", "3")
+        );
+        assert_eq!(
+            Err(EditError::MultipleValues("a.b".to_owned())),
+            config.unset("a.b").map(|_| ())
+        );
+        assert_eq!(
+            Err(EditError::InvalidName("a".to_owned())),
+            config.set("a", "3")
+        );
+        assert_eq!(2, config.unset_all("a.b")?);
+        assert_eq!("[a]\n", config.text());
+        Ok(())
+    }
+
+    #[test]
+    fn follows_includes() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir_all(dir.path().join("sub"))?;
+        fs::write(
+            dir.path().join("config"),
+            "[user]\n\tname = Before\n[include]\n\tpath = sub/identity\n\tpath = missing\n[user]\n\temail = after@example.com\n",
+        )?;
+        fs::write(
+            dir.path().join("sub/identity"),
+            "[user]\n\tname = Included\n\temail = included@example.com\n[include]\n\tpath = nested\n",
+        )?;
+        fs::write(dir.path().join("sub/nested"), "[core]\n\tbare = false\n")?;
+
+        let mut config = Config::load(dir.path().join("config"))?;
+        assert_eq!(Some("Included"), config.get("user.name"));
+        assert_eq!(Some("after@example.com"), config.get("user.email"));
+        assert_eq!(Ok(Some(false)), config.get_bool("core.bare"));
+
+        // Edits only touch the including file
+        let text = config.text().to_owned();
+        config.set("user.name", "Edited")?;
+        assert_eq!(text.replace("Before", "Edited"), config.text());
+        assert_eq!(Some("Included"), config.get("user.name"));
+        assert_eq!(Ok(Some(false)), config.get_bool("core.bare"));
+        Ok(())
+    }
+
+    #[test]
+    fn checks_include_conditions() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(
+            dir.path().join("config"),
+            r#"[includeIf "gitdir:work/"]
+	path = work
+[includeIf "gitdir/i:**/PERSONAL/.git"]
+	path = personal
+[includeIf "onbranch:release/"]
+	path = release
+[includeIf "hasconfig:remote.*.url:x"]
+	path = unknown
+"#,
+        )?;
+        for (name, contents) in [
+            ("work", "[user]\n\tname = Work\n"),
+            ("personal", "[user]\n\tname = Personal\n"),
+            ("release", "[release]\n\tok\n"),
+            ("unknown", "[user]\n\tname = Unknown\n"),
+        ] {
+            fs::write(dir.path().join(name), contents)?;
+        }
+
+        let load = |git_dir: &str, branch: Option<&str>| {
+            Config::load_with(
+                dir.path().join("config"),
+                &Conditions {
+                    git_dir: Some(git_dir.into()),
+                    branch: branch.map(str::to_owned),
+                },
+            )
+        };
+
+        let config = load("/src/work/project/.git", Some("main"))?;
+        assert_eq!(Some("Work"), config.get("user.name"));
+        assert_eq!(None, config.get("release.ok"));
+
+        let config = load("/src/personal/.git", Some("release/1.0"))?;
+        assert_eq!(Some("Personal"), config.get("user.name"));
+        assert_eq!(Ok(Some(true)), config.get_bool("release.ok"));
+
+        let config = load("/src/other/.git", None)?;
+        assert_eq!(None, config.get("user.name"));
+        assert_eq!(
+            None,
+            Config::load(dir.path().join("config"))?.get("user.name")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_include_cycles() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("config"), "[include]\n\tpath = config\n")?;
+        assert!(matches!(
+            Config::load(dir.path().join("config")),
+            Err(LoadError::IncludeDepth(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        assert_eq!(
+            Err(ParseError::EntryOutsideSection(1)),
+            Config::parse("a = b")
+        );
+        assert_eq!(Err(ParseError::InvalidSection(1)), Config::parse("[a"));
+        assert_eq!(Err(ParseError::InvalidKey(2)), Config::parse("[a]\n1b = c"));
+        assert_eq!(
+            Err(ParseError::UnclosedQuote(2)),
+            Config::parse("[a]\nb = \"c")
+        );
+    }
+}
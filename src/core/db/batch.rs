@@ -0,0 +1,159 @@
+//! Looking up many objects at once, like `git cat-file --batch`
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+};
+
+use bstr::ByteSlice;
+use flate2::read::ZlibDecoder;
+
+use super::{Db, LoadRawError, UntypedOid};
+use crate::core::pack::{file::LoadPackedError, ObjectType};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BatchOptions {
+    /// Load the contents of each object, not just its type and size, like
+    /// `--batch` rather than `--batch-check`
+    pub contents: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Batched {
+    Found(BatchedObject),
+    Missing(UntypedOid),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BatchedObject {
+    pub oid: UntypedOid,
+    pub object_type: ObjectType,
+    pub size: usize,
+    /// `None` unless asked for
+    pub contents: Option<Vec<u8>>,
+}
+
+/// Where an object is, ordered so that each pack is read front to back
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+enum Location {
+    Missing,
+    Loose,
+    Packed { pack: usize, offset: u64 },
+}
+
+impl Batched {
+    pub fn oid(&self) -> UntypedOid {
+        match self {
+            Self::Found(object) => object.oid,
+            Self::Missing(oid) => *oid,
+        }
+    }
+}
+
+impl Db {
+    /// Looks up each object as stored, without replacements. They're yielded
+    /// in the order they're stored rather than the order given (missing
+    /// objects, then loose, then each pack by offset) so that looking up
+    /// thousands of objects doesn't jump around packs. Without contents,
+    /// deltas aren't applied: only the headers of their bases are read.
+    pub fn batch<I>(
+        &self,
+        oids: I,
+        options: BatchOptions,
+    ) -> impl Iterator<Item = Result<Batched, BatchError>> + '_
+    where
+        I: IntoIterator<Item = UntypedOid>,
+    {
+        let mut located = oids
+            .into_iter()
+            .map(|oid| (self.locate(&oid), oid))
+            .collect::<Vec<_>>();
+        located.sort();
+        located
+            .into_iter()
+            .map(move |(location, oid)| self.load_batched(oid, location, options))
+    }
+
+    fn locate(&self, oid: &UntypedOid) -> Location {
+        if self.oid_path(oid).exists() {
+            return Location::Loose;
+        }
+        self.packs
+            .iter()
+            .enumerate()
+            .find_map(|(pack, file)| {
+                let offset = file.index().offset(oid)?;
+                Some(Location::Packed { pack, offset })
+            })
+            .unwrap_or(Location::Missing)
+    }
+
+    fn load_batched(
+        &self,
+        oid: UntypedOid,
+        location: Location,
+        options: BatchOptions,
+    ) -> Result<Batched, BatchError> {
+        let packed = |e: LoadPackedError| BatchError::Packed(oid, e);
+        let (object_type, size, contents) = match location {
+            Location::Missing => return Ok(Batched::Missing(oid)),
+            Location::Loose if options.contents => {
+                let Some((ty, data)) = self.load_raw(&oid)? else {
+                    return Ok(Batched::Missing(oid));
+                };
+                let ty = ObjectType::from_name(&ty).ok_or(BatchError::Corrupt(oid))?;
+                (ty, data.len(), Some(data))
+            }
+            Location::Loose => {
+                let (ty, size) = self.loose_header(&oid)?;
+                (ty, size, None)
+            }
+            Location::Packed { pack, offset } if options.contents => {
+                let (ty, data) = self.packs[pack].load_at(offset, self).map_err(packed)?;
+                (ty, data.len(), Some(data))
+            }
+            Location::Packed { pack, offset } => {
+                let (ty, size) = self.packs[pack].header_at(offset, self).map_err(packed)?;
+                (ty, size, None)
+            }
+        };
+        Ok(Batched::Found(BatchedObject {
+            oid,
+            object_type,
+            size,
+            contents,
+        }))
+    }
+
+    /// Inflates only as far as the end of the header
+    fn loose_header(&self, oid: &UntypedOid) -> Result<(ObjectType, usize), BatchError> {
+        let file = File::open(self.oid_path(oid)).map_err(|e| BatchError::Read(*oid, e))?;
+        let mut header = Vec::new();
+        BufReader::new(ZlibDecoder::new(file))
+            .read_until(b'\0', &mut header)
+            .map_err(|e| BatchError::Read(*oid, e))?;
+
+        let corrupt = || BatchError::Corrupt(*oid);
+        let header = header.strip_suffix(b"\0").ok_or_else(corrupt)?;
+        let type_end = header.find_byte(b' ').ok_or_else(corrupt)?;
+        let ty = ObjectType::from_name(&header[..type_end]).ok_or_else(corrupt)?;
+        let size = header[type_end + 1..]
+            .to_str()
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .ok_or_else(corrupt)?;
+        Ok((ty, size))
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum BatchError {
+    /// {0}
+    Load(#[from] LoadRawError),
+    /// Failed to read {0:?} from the database
+    Read(UntypedOid, #[source] io::Error),
+    /// Database entry for {0:?} is corrupt
+    Corrupt(UntypedOid),
+    /// Failed to read {0:?} from a pack
+    Packed(UntypedOid, #[source] LoadPackedError),
+}
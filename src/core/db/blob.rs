@@ -1,9 +1,9 @@
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Read};
 
 use bstr::{BStr, BString, ByteSlice};
 
 use crate::core::{
-    db::{self, Db},
+    db::{self, Db, UntypedOid},
     Object, ObjectBuilder, Oid,
 };
 
@@ -15,9 +15,8 @@ pub struct Blob {
 
 impl Blob {
     pub fn oid_for_file(file: &BStr) -> Oid<Self> {
-        let mut bytes = Db::serialized_prefix(Self::TYPE, &file);
-        bytes.extend_from_slice(file.as_bytes());
-        Oid::for_serialized_bytes(&bytes)
+        let prefix = Db::serialized_prefix(Self::TYPE, file);
+        UntypedOid::for_parts(&[&prefix, file.as_bytes()]).to_typed()
     }
 }
 
@@ -37,12 +36,65 @@ impl Object for Blob {
         len: usize,
         mut data: impl BufRead,
     ) -> Result<Self, Self::DeserializeError> {
-        let mut bytes: BString = Vec::with_capacity(len).into();
+        let mut bytes: BString = vec![0; len].into();
         data.read_exact(&mut bytes)?;
         Ok(Self { bytes, oid })
     }
 }
 
+/// A blob's contents, read from the database as they're needed rather than
+/// all at once. See [`Db::open_blob`].
+pub struct Reader {
+    oid: Oid<Blob>,
+    len: usize,
+    inner: io::Take<Box<dyn BufRead + Send>>,
+}
+
+impl Reader {
+    pub(super) fn new(oid: Oid<Blob>, len: usize, inner: Box<dyn BufRead + Send>) -> Self {
+        Self {
+            oid,
+            len,
+            inner: inner.take(len as u64),
+        }
+    }
+
+    pub fn oid(&self) -> Oid<Blob> {
+        self.oid
+    }
+
+    /// The length of the whole blob
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() && self.inner.limit() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{:?} is shorter than its header says", self.oid),
+            ));
+        }
+        Ok(read)
+    }
+}
+
+impl std::fmt::Debug for Reader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reader")
+            .field("oid", &self.oid)
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Builder(BString);
 
@@ -60,3 +112,35 @@ impl ObjectBuilder for Builder {
         db.store_bytes::<Self>(contents)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn streams_contents() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("objects"))?;
+        let mut db = Db::new(dir.path());
+        let contents = (0..200_000_u32)
+            .map(|n| (n % 251) as u8)
+            .collect::<Vec<_>>();
+        let oid = Builder::new(contents.clone()).store(&db)?;
+
+        let mut reader = db.open_blob(oid)?;
+        assert_eq!(contents.len(), reader.len());
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read)?;
+        assert_eq!(contents, read);
+        Ok(())
+    }
+
+    #[test]
+    fn errors_if_truncated() {
+        let oid = Blob::oid_for_file(b"abc".as_bstr());
+        let mut reader = Reader::new(oid, 4, Box::new(io::Cursor::new(b"abc".to_vec())));
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+}
@@ -7,7 +7,9 @@ use crate::core::{Object, Oid};
 
 use super::UntypedOid;
 
-pub(super) struct Cache(LruCache<UntypedOid, Box<dyn Any>>);
+/// Objects are `Send + Sync`, so that a [`super::Db`] can be shared between
+/// threads
+pub(super) struct Cache(LruCache<UntypedOid, Box<dyn Any + Send + Sync>>);
 
 impl Cache {
     const CAPACITY: usize = 5000;
@@ -17,7 +19,7 @@ impl Cache {
     }
 
     pub(super) fn insert<O: Object + 'static>(&mut self, oid: Oid<O>, object: O) {
-        self.0.put(oid.clone().into_untyped(), Box::new(object));
+        self.0.put(oid.into_untyped(), Box::new(object));
     }
 
     pub(super) fn get<'c, O>(&'c mut self, oid: &Oid<O>) -> Option<&'c O>
@@ -27,7 +29,6 @@ impl Cache {
         let object = self.0.get(oid.as_untyped())?.downcast_ref::<O>();
         if object.is_none() {
             warn!("Object stored in cache under different type than requested");
-        } else {
         }
         object
     }
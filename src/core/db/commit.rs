@@ -1,20 +1,26 @@
 use std::{
     borrow::Cow,
+    collections::BTreeSet,
     io::{self, BufRead},
 };
 
-use bstr::{BString, ByteSlice};
+use bstr::{BStr, BString, ByteSlice};
 use tracing::warn;
 
-use super::{author, object::ParseOidError, Author, Tree};
+use super::{object::ParseOidError, signature, Signature, SignatureRef, Tree, UntypedOid};
 use crate::core::{db, Db, Object, ObjectBuilder, Oid};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Commit {
     pub oid: Oid<Commit>,
     pub parent: Option<Oid<Commit>>,
+    /// The parents after the first, which a merge merged in
+    pub merged: Vec<Oid<Commit>>,
     pub tree: Oid<Tree>,
-    pub author: db::Author,
+    pub author: Signature,
+    pub committer: Signature,
+    #[cfg_attr(feature = "serde", serde(with = "crate::core::serialize::bytes"))]
     pub msg: BString,
 }
 
@@ -31,37 +37,65 @@ impl Object for Commit {
 
     fn deserialize(
         oid: Oid<Commit>,
-        _len: usize,
+        len: usize,
         mut data: impl BufRead,
     ) -> Result<Self, Self::DeserializeError> {
-        let mut parent = None;
+        let mut bytes = Vec::with_capacity(len);
+        data.read_to_end(&mut bytes)?;
+        Ok(CommitRef::parse(&bytes)?.to_commit(oid))
+    }
+
+    fn cut_at_shallow(&mut self, shallow: &BTreeSet<UntypedOid>) {
+        if shallow.contains(self.oid.as_untyped()) {
+            self.parent = None;
+            self.merged.clear();
+        }
+    }
+}
+
+/// A commit borrowed from its raw data (see [`Db::load_raw`]), so that
+/// reading it doesn't copy its message or signatures
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct CommitRef<'a> {
+    pub tree: Oid<Tree>,
+    pub author: SignatureRef<'a>,
+    pub committer: SignatureRef<'a>,
+    pub msg: &'a BStr,
+    /// For finding the parents without collecting them
+    headers: &'a [u8],
+}
+
+impl<'a> CommitRef<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, DeserializeError> {
         let mut tree = None;
         let mut author = None;
+        let mut committer = None;
 
-        let mut line = BString::from(Vec::new());
+        let mut rest = data;
         loop {
-            line.clear();
-            let bytes_read = data.read_until(b'\n', &mut line)?;
-            if bytes_read == 0 {
-                return Err(DeserializeError::UnexpectedHeadersEnd);
-            } else if bytes_read == 1 {
+            let line_end = rest
+                .find_byte(b'\n')
+                .ok_or(DeserializeError::UnexpectedHeadersEnd)?;
+            let line = &rest[..line_end];
+            rest = &rest[line_end + 1..];
+            if line.is_empty() {
                 break;
             }
 
             let i = line.find(b" ").ok_or(DeserializeError::MalformedHeader)?;
             let (key, value) = line.split_at(i);
-            let value = &value[1..value.len() - 1];
+            let value = &value[1..];
 
             match key {
                 b"parent" => {
-                    let oid = Oid::parse(value).map_err(DeserializeError::ParseParent)?;
-                    parent = Some(oid);
+                    Oid::<Commit>::parse(value).map_err(DeserializeError::ParseParent)?;
                 }
                 b"tree" => {
                     let oid = Oid::parse(value).map_err(DeserializeError::ParseTree)?;
                     tree = Some(oid);
                 }
-                b"author" => author = Some(Author::parse(value.as_bstr())?),
+                b"author" => author = Some(SignatureRef::parse(value.as_bstr())?),
+                b"committer" => committer = Some(SignatureRef::parse(value.as_bstr())?),
                 _ => warn!(
                     key = ?key.to_str_lossy(),
                     value = ?value.to_str_lossy(),
@@ -70,27 +104,45 @@ impl Object for Commit {
             }
         }
 
-        let tree = tree.ok_or(DeserializeError::MissingTree)?;
-        let author = author.ok_or(DeserializeError::MissingAuthor)?;
+        Ok(Self {
+            tree: tree.ok_or(DeserializeError::MissingTree)?,
+            author: author.ok_or(DeserializeError::MissingAuthor)?,
+            committer: committer.ok_or(DeserializeError::MissingCommitter)?,
+            msg: rest.as_bstr(),
+            headers: &data[..data.len() - rest.len()],
+        })
+    }
 
-        let mut msg = BString::from(Vec::new());
-        data.read_to_end(&mut msg)?;
+    /// In order, so the first is the parent and the rest were merged in
+    pub fn parents(&self) -> impl Iterator<Item = Oid<Commit>> + 'a {
+        ByteSlice::lines(self.headers).filter_map(|line| {
+            let oid = line.strip_prefix(b"parent ")?;
+            Some(Oid::parse(oid).expect("Checked when parsed"))
+        })
+    }
 
-        Ok(Self {
+    /// Copy the commit out of the data
+    pub fn to_commit(&self, oid: Oid<Commit>) -> Commit {
+        let mut parents = self.parents();
+        Commit {
             oid,
-            parent,
-            tree,
-            author,
-            msg,
-        })
+            parent: parents.next(),
+            merged: parents.collect(),
+            tree: self.tree,
+            author: self.author.to_signature(),
+            committer: self.committer.to_signature(),
+            msg: self.msg.to_owned(),
+        }
     }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Builder {
     pub parent: Option<Oid<Commit>>,
+    pub merged: Vec<Oid<Commit>>,
     pub tree: Oid<Tree>,
-    pub author: db::Author,
+    pub author: Signature,
+    pub committer: Signature,
     pub msg: BString,
 }
 
@@ -98,13 +150,16 @@ impl Builder {
     pub fn new(
         parent: Option<Oid<Commit>>,
         tree: Oid<Tree>,
-        author: db::Author,
+        author: Signature,
+        committer: Signature,
         msg: impl Into<BString>,
     ) -> Self {
         Self {
             parent,
+            merged: Vec::new(),
             tree,
             author,
+            committer,
             msg: msg.into(),
         }
     }
@@ -115,9 +170,14 @@ impl ObjectBuilder for Builder {
 
     fn store(self, db: &Db) -> db::StoreResult<Commit> {
         let author = self.author.serialize();
+        let committer = self.committer.serialize();
 
         let parent_line = if let Some(parent) = self.parent.as_ref() {
-            Cow::Owned(format!("\nparent {}", parent.to_hex()))
+            let merged = self.merged.iter();
+            let lines = std::iter::once(parent)
+                .chain(merged)
+                .map(|parent| format!("\nparent {}", parent.to_hex()));
+            Cow::Owned(lines.collect())
         } else {
             Cow::Borrowed("")
         };
@@ -126,8 +186,8 @@ impl ObjectBuilder for Builder {
             "tree {}{}\nauthor {}\ncommitter {}\n\n{}",
             self.tree.to_hex(),
             &parent_line,
-            &author,
-            &author,
+            author,
+            committer,
             &self.msg
         );
         let ser = BString::from(ser);
@@ -148,10 +208,49 @@ pub enum DeserializeError {
     ParseParent(#[source] ParseOidError),
     /// Failed to parse oid of tree
     ParseTree(#[source] ParseOidError),
-    /// Failed to parse author header
-    ParseAuthor(#[from] author::ParseError),
+    /// Failed to parse author or committer header
+    ParseSignature(#[from] signature::ParseError),
     /// Header tree not present
     MissingTree,
     /// Header author not present
     MissingAuthor,
+    /// Header committer not present
+    MissingCommitter,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn borrows_from_data() -> eyre::Result<()> {
+        let data = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+parent ce013625030ba8dba906f756967f9e9ca394464a
+parent e69de29bb2d1d6434b8b29ae775ad8c2e48c5391
+author A <a@example.com> 1619863200 +0200
+committer C <c@example.com> 1619863300 +0200
+
+Message
+";
+        let commit = CommitRef::parse(data)?;
+        assert_eq!("A", commit.author.name);
+        assert_eq!("c@example.com", commit.committer.email);
+        assert_eq!("Message\n", commit.msg);
+        assert_eq!(2, commit.parents().count());
+
+        let owned = commit.to_commit(Oid::zero());
+        assert_eq!(commit.parents().next(), owned.parent);
+        assert_eq!(
+            vec![Oid::parse("e69de29bb2d1d6434b8b29ae775ad8c2e48c5391")?],
+            owned.merged
+        );
+        assert_eq!(commit.msg, owned.msg);
+
+        assert!(matches!(
+            CommitRef::parse(b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n"),
+            Err(DeserializeError::UnexpectedHeadersEnd)
+        ));
+        Ok(())
+    }
 }
@@ -0,0 +1,376 @@
+//! Dates as people write them, for `GIT_AUTHOR_DATE` and the like. Exact
+//! formats are tried first, then git's "approxidate": anything from
+//! `2005-04-07 15:13` or `Apr 7 2005` to `3 weeks ago` and `last friday at
+//! noon`. What isn't given is taken from now, so `2005-04-07` is at the
+//! current time of day, and dates without a zone are in the local one.
+
+use std::{convert::TryFrom, sync::LazyLock};
+
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, Offset,
+    TimeZone, Timelike,
+};
+use regex::Regex;
+
+/// Git's internal format, as in commits
+const INTERNAL_FORMAT: &str = "%s %z";
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+/// In the order of [`Datelike::weekday`] counted from Sunday
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const NUMBERS: [&str; 11] = [
+    "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+];
+
+/// Relative to now in the local timezone, see [`parse_at`]
+pub fn parse(date: &str) -> Option<DateTime<FixedOffset>> {
+    parse_at(date, &Local::now())
+}
+
+/// Accepts git's internal format (`<seconds> <offset>`, optionally
+/// prefixed with `@`), RFC 2822, ISO 8601, and otherwise whatever git's
+/// approxidate understands. Relative dates are relative to `now`, and dates
+/// without a zone are in its timezone. `None` if nothing in `date` looks
+/// like part of a date.
+pub fn parse_at<Tz: TimeZone>(date: &str, now: &DateTime<Tz>) -> Option<DateTime<FixedOffset>> {
+    let date = date.trim();
+    DateTime::parse_from_str(date.strip_prefix('@').unwrap_or(date), INTERNAL_FORMAT)
+        .or_else(|_| DateTime::parse_from_rfc2822(date))
+        .or_else(|_| DateTime::parse_from_rfc3339(date))
+        .ok()
+        .or_else(|| approximate(date, now))
+}
+
+/// What's been read so far. Relative parts move [`Self::base`] as they're
+/// read, and absolute parts replace its fields at the end.
+struct Approx {
+    base: NaiveDateTime,
+    year: Option<i32>,
+    month: Option<u32>,
+    day: Option<u32>,
+    time: Option<NaiveTime>,
+    offset: Option<FixedOffset>,
+    /// Seconds since the epoch, given as a single large number
+    epoch: Option<i64>,
+    /// A number waiting to find out what it counts, like the `3` of `3 days`
+    pending: Option<u32>,
+}
+
+fn approximate<Tz: TimeZone>(date: &str, now: &DateTime<Tz>) -> Option<DateTime<FixedOffset>> {
+    static TOKEN: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(
+            r"(?x)
+            (?P<date>\d+[-./]\d+[-./]\d+)
+            | (?P<time>\d{1,2}:\d{2}(?::\d{2})?)
+            | (?P<zone>[+-]\d{2}:?\d{2})
+            | (?P<number>\d+)
+            | (?P<word>[a-z]+)",
+        )
+        .unwrap()
+    });
+
+    let date = date.to_lowercase();
+    let mut approx = Approx {
+        base: now.naive_local(),
+        year: None,
+        month: None,
+        day: None,
+        time: None,
+        offset: None,
+        epoch: None,
+        pending: None,
+    };
+    let mut understood = false;
+    for caps in TOKEN.captures_iter(&date) {
+        understood |= if let Some(token) = caps.name("date") {
+            approx.date(token.as_str())?
+        } else if let Some(token) = caps.name("time") {
+            approx.time(token.as_str())?
+        } else if let Some(token) = caps.name("zone") {
+            approx.zone(token.as_str())?
+        } else if let Some(token) = caps.name("number") {
+            approx.number(token.as_str())?
+        } else {
+            approx.word(&caps["word"])?
+        };
+    }
+    if !understood {
+        return None;
+    }
+    approx.finish(&now.timezone())
+}
+
+impl Approx {
+    /// Like `2005-04-07`, `04/07/2005` (month first) or `7.4.2005` (day
+    /// first)
+    fn date(&mut self, token: &str) -> Option<bool> {
+        let separator = token.find(|c: char| !c.is_ascii_digit())?;
+        let separator = token.as_bytes()[separator];
+        let mut parts = token.split(['-', '.', '/']).map(str::parse::<u16>);
+        let (a, b, c) = (
+            parts.next()?.ok()?,
+            parts.next()?.ok()?,
+            parts.next()?.ok()?,
+        );
+        let (year, month, day) = if a > 31 {
+            (a, b, c)
+        } else if separator == b'.' {
+            (c, b, a)
+        } else {
+            (c, a, b)
+        };
+        self.year = Some(full_year(year));
+        self.month = Some(month.into());
+        self.day = Some(day.into());
+        Some(true)
+    }
+
+    fn time(&mut self, token: &str) -> Option<bool> {
+        let mut parts = token.split(':').map(str::parse::<u32>);
+        let hour = parts.next()?.ok()?;
+        let min = parts.next()?.ok()?;
+        let sec = parts.next().transpose().ok()?.unwrap_or(0);
+        self.time = Some(NaiveTime::from_hms_opt(hour, min, sec)?);
+        Some(true)
+    }
+
+    fn zone(&mut self, token: &str) -> Option<bool> {
+        let sign = if token.starts_with('-') { -1 } else { 1 };
+        let digits = token[1..].replace(':', "");
+        let hours = digits[..2].parse::<i32>().ok()?;
+        let mins = digits[2..].parse::<i32>().ok()?;
+        self.offset = Some(FixedOffset::east_opt(sign * (hours * 3600 + mins * 60))?);
+        Some(true)
+    }
+
+    /// Years have four digits, and seconds since the epoch at least nine.
+    /// Other numbers count what follows, or are the day of a month.
+    fn number(&mut self, token: &str) -> Option<bool> {
+        if token.len() >= 9 {
+            self.epoch = Some(token.parse().ok()?);
+        } else if token.len() == 4 {
+            self.year = Some(token.parse().ok()?);
+        } else {
+            let number = token.parse().ok()?;
+            if let Some(day) = self.pending.replace(number) {
+                self.day.get_or_insert(day);
+            }
+        }
+        Some(true)
+    }
+
+    fn word(&mut self, word: &str) -> Option<bool> {
+        // Before months and weekdays, as `month` starts like `mon`
+        if let Some(seconds) = unit_seconds(word) {
+            let count = self.pending.take().unwrap_or(0);
+            self.base -= Duration::seconds(seconds * i64::from(count));
+            return Some(true);
+        }
+        if matches!(word.trim_end_matches('s'), "month" | "year") {
+            let count = self.pending.take().unwrap_or(0);
+            let months = if word.starts_with('y') { 12 } else { 1 };
+            self.base = months_ago(self.base, count * months)?;
+            return Some(true);
+        }
+
+        let prefix = word.get(..3);
+        let find = |names: &[&str]| names.iter().position(|&name| Some(name) == prefix);
+        if let Some(month) = find(&MONTHS).and_then(|month| u32::try_from(month + 1).ok()) {
+            self.month = Some(month);
+            // Like `7 April`, otherwise a day follows like `April 7`
+            if let Some(day) = self.pending.take() {
+                self.day = Some(day);
+            }
+        } else if let Some(weekday) = find(&WEEKDAYS).and_then(|day| i64::try_from(day).ok()) {
+            // The most recent one before today with `last`, or earlier with
+            // `2 fridays ago`. Like git, without a count it's ignored.
+            let weeks = i64::from(self.pending.take().unwrap_or(0)) - 1;
+            let today = self.base.weekday().num_days_from_sunday();
+            let mut days = i64::from(today) - weekday;
+            if days <= 0 {
+                days += 7;
+            }
+            self.base -= Duration::days((days + 7 * weeks).max(0));
+        } else if let Some(number) = NUMBERS.iter().position(|&name| name == word) {
+            self.pending = u32::try_from(number).ok();
+        } else {
+            match word {
+                "last" => self.pending = Some(1),
+                "now" | "today" => (),
+                "yesterday" => self.base -= Duration::days(1),
+                "midnight" => self.at_hour(0),
+                "noon" => self.at_hour(12),
+                "tea" => self.at_hour(17),
+                "am" | "pm" => self.meridiem(word == "pm")?,
+                "utc" | "gmt" | "z" => self.offset = Some(FixedOffset::east_opt(0)?),
+                _ => return Some(false),
+            }
+        }
+        Some(true)
+    }
+
+    /// The last time it was that hour, like git
+    fn at_hour(&mut self, hour: u32) {
+        if self.base.hour() < hour {
+            self.base -= Duration::days(1);
+        }
+        let time = NaiveTime::from_hms_opt(hour, 0, 0).expect("Valid hour");
+        self.base = self.base.date().and_time(time);
+    }
+
+    /// Like `3pm`, or `3:30 pm`
+    fn meridiem(&mut self, pm: bool) -> Option<()> {
+        let hour = |hour: u32| hour % 12 + if pm { 12 } else { 0 };
+        let time = match (self.pending.take(), self.time) {
+            (Some(hour_of_day), _) => NaiveTime::from_hms_opt(hour(hour_of_day), 0, 0)?,
+            (None, Some(time)) => time.with_hour(hour(time.hour()))?,
+            (None, None) => return Some(()),
+        };
+        self.time = Some(time);
+        Some(())
+    }
+
+    fn finish<Tz: TimeZone>(mut self, timezone: &Tz) -> Option<DateTime<FixedOffset>> {
+        if let Some(epoch) = self.epoch {
+            let offset = self
+                .offset
+                .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+            return offset.timestamp_opt(epoch, 0).single();
+        }
+        if self.month.is_some() && self.day.is_none() {
+            self.day = self.pending.take();
+        }
+
+        let date = NaiveDate::from_ymd_opt(
+            self.year.unwrap_or_else(|| self.base.year()),
+            self.month.unwrap_or_else(|| self.base.month()),
+            self.day.unwrap_or_else(|| self.base.day()),
+        )?;
+        let local = date.and_time(self.time.unwrap_or_else(|| self.base.time()));
+        if let Some(offset) = self.offset {
+            return offset.from_local_datetime(&local).single();
+        }
+        let time = timezone.from_local_datetime(&local).earliest()?;
+        let offset = time.offset().fix();
+        Some(time.with_timezone(&offset))
+    }
+}
+
+fn unit_seconds(word: &str) -> Option<i64> {
+    Some(match word.trim_end_matches('s') {
+        "second" | "sec" => 1,
+        "minute" | "min" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        _ => return None,
+    })
+}
+
+/// Clamped to the end of shorter months
+fn months_ago(time: NaiveDateTime, months: u32) -> Option<NaiveDateTime> {
+    let total = i64::from(time.year()) * 12 + i64::from(time.month0()) - i64::from(months);
+    let year = i32::try_from(total.div_euclid(12)).ok()?;
+    let month = u32::try_from(total.rem_euclid(12)).ok()? + 1;
+    let date = (1..=time.day())
+        .rev()
+        .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))?;
+    Some(date.and_time(time.time()))
+}
+
+/// Two digit years are from 1970 to 2069
+fn full_year(year: u16) -> i32 {
+    let year = i32::from(year);
+    match year {
+        0..=69 => year + 2000,
+        70..=99 => year + 1900,
+        _ => year,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// Thursday 2026-10-15 02:19:09 in UTC-5
+    fn now() -> DateTime<FixedOffset> {
+        FixedOffset::east_opt(-5 * 3600)
+            .unwrap()
+            .timestamp_opt(1_792_030_749, 0)
+            .unwrap()
+    }
+
+    fn parse(date: &str) -> Option<String> {
+        parse_at(date, &now()).map(|time| time.format("%Y-%m-%d %H:%M:%S %z").to_string())
+    }
+
+    #[test]
+    fn parses_exact_dates() {
+        let expected = FixedOffset::east_opt(-5 * 3600)
+            .unwrap()
+            .timestamp_opt(1_500_000_000, 0)
+            .unwrap();
+        for date in [
+            "1500000000 -0500",
+            "@1500000000 -0500",
+            "Thu, 13 Jul 2017 21:40:00 -0500",
+            "2017-07-13T21:40:00-05:00",
+            "2017-07-13 21:40:00 -0500",
+            "Thu Jul 13 21:40:00 2017 -0500",
+        ] {
+            assert_eq!(Some(expected), parse_at(date, &now()), "{date}");
+        }
+    }
+
+    #[test]
+    fn parses_approximate_dates_like_git() {
+        let now = "2026-10-14 21:19:09 -0500";
+        for (date, expected) in [
+            ("now", now),
+            ("2005-04-07", "2005-04-07 21:19:09 -0500"),
+            ("Apr 7 2005", "2005-04-07 21:19:09 -0500"),
+            ("7 April, 2005", "2005-04-07 21:19:09 -0500"),
+            ("04/07/2005", "2005-04-07 21:19:09 -0500"),
+            ("7.4.2005", "2005-04-07 21:19:09 -0500"),
+            ("2005.04.07", "2005-04-07 21:19:09 -0500"),
+            ("2005-04-07 15:13", "2005-04-07 15:13:00 -0500"),
+            ("2005-04-07 3pm", "2005-04-07 15:00:00 -0500"),
+            ("7 April 2005 10:00 UTC", "2005-04-07 10:00:00 +0000"),
+            ("12:30", "2026-10-14 12:30:00 -0500"),
+            ("yesterday", "2026-10-13 21:19:09 -0500"),
+            ("2 days ago", "2026-10-12 21:19:09 -0500"),
+            ("three weeks ago", "2026-09-23 21:19:09 -0500"),
+            ("last month", "2026-09-14 21:19:09 -0500"),
+            ("noon", "2026-10-14 12:00:00 -0500"),
+            ("midnight", "2026-10-14 00:00:00 -0500"),
+            ("last friday", "2026-10-09 21:19:09 -0500"),
+            ("2 fridays ago", "2026-10-02 21:19:09 -0500"),
+            ("friday", now),
+            ("last wednesday at noon", "2026-10-07 12:00:00 -0500"),
+            ("1500000000", "2017-07-14 02:40:00 +0000"),
+        ] {
+            assert_eq!(Some(expected.to_owned()), parse(date), "{date}");
+        }
+        assert_eq!(None, parse("soon"));
+        assert_eq!(None, parse("2005-13-40"));
+    }
+
+    #[test]
+    fn months_ago_clamps_to_the_end_of_the_month() {
+        let time = NaiveDate::from_ymd_opt(2024, 3, 31)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2024, 2, 29).unwrap(),
+            months_ago(time, 1).unwrap().date()
+        );
+        assert_eq!(
+            NaiveDate::from_ymd_opt(2023, 12, 31).unwrap(),
+            months_ago(time, 3).unwrap().date()
+        );
+    }
+}
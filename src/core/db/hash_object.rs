@@ -0,0 +1,83 @@
+//! Hashing objects of any type from readers, like `git hash-object`
+
+use std::io::{self, Read};
+
+use bstr::BString;
+
+use super::{
+    commit, object::CollisionError, tag, tree, CommitRef, Db, StoreRawError, TagRef, TreeRef,
+    UntypedOid,
+};
+use crate::core::pack::ObjectType;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HashOptions {
+    /// Store the object, like `-w`
+    pub write: bool,
+    /// Take the type and contents as they are, like `--literally`, so that
+    /// broken or made-up objects can be made for tests
+    pub literally: bool,
+}
+
+impl Db {
+    /// The oid of the object of type `o_type` (like `blob`) with what's read
+    /// from `reader` as its contents. Unless literally, the type has to be
+    /// one git knows, and commits, trees and tags have to parse.
+    pub fn hash_object(
+        &self,
+        o_type: &[u8],
+        mut reader: impl Read,
+        options: HashOptions,
+    ) -> Result<UntypedOid, HashObjectError> {
+        let mut content = Vec::new();
+        reader
+            .read_to_end(&mut content)
+            .map_err(HashObjectError::Read)?;
+
+        if !options.literally {
+            check(o_type, &content)?;
+        }
+
+        if options.write {
+            Ok(self.store_raw(o_type, &content)?)
+        } else {
+            let prefix = Self::serialized_prefix(o_type, &content);
+            Ok(UntypedOid::try_for_parts(&[&prefix, &content])?)
+        }
+    }
+}
+
+fn check(o_type: &[u8], content: &[u8]) -> Result<(), HashObjectError> {
+    match ObjectType::from_name(o_type) {
+        Some(ObjectType::Blob) => {}
+        Some(ObjectType::Commit) => {
+            CommitRef::parse(content).map_err(HashObjectError::InvalidCommit)?;
+        }
+        Some(ObjectType::Tree) => {
+            TreeRef::parse(content).map_err(HashObjectError::InvalidTree)?;
+        }
+        Some(ObjectType::Tag) => {
+            TagRef::parse(content).map_err(HashObjectError::InvalidTag)?;
+        }
+        None => return Err(HashObjectError::UnknownType(o_type.into())),
+    }
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum HashObjectError {
+    /// Failed to read the contents of the object
+    Read(#[source] io::Error),
+    /// Unknown object type {0}
+    UnknownType(BString),
+    /// Invalid commit
+    InvalidCommit(#[source] commit::DeserializeError),
+    /// Invalid tree
+    InvalidTree(#[source] tree::DeserializeError),
+    /// Invalid tag
+    InvalidTag(#[source] tag::DeserializeError),
+    /// {0}
+    Collision(#[from] CollisionError),
+    /// {0}
+    Store(#[from] StoreRawError),
+}
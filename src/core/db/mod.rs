@@ -1,66 +1,247 @@
-pub mod author;
+pub mod batch;
 pub mod blob;
 pub mod cache;
 pub mod commit;
+pub mod date;
+pub mod hash_object;
 pub mod object;
+pub mod signature;
+pub mod tag;
 pub mod tree;
 
-pub use author::Author;
+pub use batch::{BatchOptions, Batched, BatchedObject};
 pub use blob::Blob;
-pub use commit::Commit;
+pub use commit::{Commit, CommitRef};
+pub use hash_object::HashOptions;
 pub use object::{Object, ObjectBuilder, Oid, UntypedOid};
-pub use tree::Tree;
+pub use signature::{Signature, SignatureRef};
+pub use tag::TagRef;
+pub use tree::{Tree, TreeRef};
 
 use bstr::{BString, ByteSlice};
 use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 use tempfile::NamedTempFile;
+use tracing::trace;
 
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fs::{self, File},
     io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
     num::ParseIntError,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use self::cache::Cache;
-use crate::core::WsPath;
+use self::{cache::Cache, object::OID_HEX_SIZE};
+use crate::core::{
+    fsync, locked_file,
+    pack::{
+        file::{LoadPackedError, OpenPackError},
+        ObjectType, PackFile,
+    },
+    Fsync, LockedFile, WsPath,
+};
 
 /// Note: Cloning doesn't keep the cache
 #[derive(Debug)]
 pub struct Db {
     path: PathBuf,
+    shallow_path: PathBuf,
     cache: Cache,
+    /// Commits whose parents we don't have, as after a shallow fetch
+    shallow: BTreeSet<UntypedOid>,
+    /// Objects loaded in place of others, from `refs/replace/`
+    replacements: BTreeMap<UntypedOid, UntypedOid>,
+    /// Looked in for objects that aren't loose
+    packs: Vec<Arc<PackFile>>,
+    /// Which of loose objects and packs are synced to disk
+    fsync: Fsync,
 }
 
 impl Db {
     pub fn new<P: Into<PathBuf>>(git_dir: P) -> Self {
+        let git_dir = git_dir.into();
+        Self::with_objects_dir(git_dir.join("objects"), &git_dir)
+    }
+
+    /// With objects kept somewhere other than `objects` in the git
+    /// directory, like `GIT_OBJECT_DIRECTORY`
+    pub fn with_objects_dir(objects_dir: impl Into<PathBuf>, git_dir: impl AsRef<Path>) -> Self {
         Self {
-            path: git_dir.into().join("objects"),
+            path: objects_dir.into(),
+            shallow_path: git_dir.as_ref().join("shallow"),
             cache: Cache::new(),
+            shallow: BTreeSet::new(),
+            replacements: BTreeMap::new(),
+            packs: Vec::new(),
+            fsync: Fsync::default(),
+        }
+    }
+
+    /// Reads the packs in `objects/pack`, after which objects are loaded
+    /// from them if they aren't loose
+    pub fn load_packs(&mut self) -> Result<(), OpenPackError> {
+        let dir = self.pack_dir();
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.packs = Vec::new();
+                return Ok(());
+            }
+            Err(err) => return Err(OpenPackError::List(dir, err)),
+        };
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|e| OpenPackError::List(dir.clone(), e))?
+                .path();
+            if path.extension().is_some_and(|ext| ext == "pack") {
+                paths.push(path);
+            }
         }
+        paths.sort();
+        self.packs = paths
+            .into_iter()
+            .map(|path| Ok(Arc::new(PackFile::open(path)?)))
+            .collect::<Result<_, OpenPackError>>()?;
+        Ok(())
+    }
+
+    pub fn packs(&self) -> &[Arc<PackFile>] {
+        &self.packs
+    }
+
+    /// Where packs are kept
+    pub fn pack_dir(&self) -> PathBuf {
+        self.path.join("pack")
+    }
+
+    /// Where loose objects and packs are kept
+    pub fn objects_dir(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// Only the loose object and pack components are used here
+    pub fn set_fsync(&mut self, fsync: Fsync) {
+        self.fsync = fsync;
+    }
+
+    pub fn fsync(&self) -> Fsync {
+        self.fsync
+    }
+
+    /// Reads the shallow commits from `.git/shallow`, after which they're
+    /// loaded without parents
+    pub fn load_shallow(&mut self) -> Result<(), ShallowError> {
+        let path = self.shallow_path();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(ShallowError::Read(path, err)),
+        };
+        self.shallow = text
+            .lines()
+            .map(|line| UntypedOid::parse(line).map_err(|_| ShallowError::Parse(line.into())))
+            .collect::<Result<_, _>>()?;
+        self.cache = Cache::new();
+        Ok(())
+    }
+
+    pub fn shallow(&self) -> &BTreeSet<UntypedOid> {
+        &self.shallow
     }
 
+    pub fn is_shallow(&self, oid: &UntypedOid) -> bool {
+        self.shallow.contains(oid)
+    }
+
+    /// Writes `.git/shallow`, which is removed if there are none
+    pub fn set_shallow(&mut self, shallow: BTreeSet<UntypedOid>) -> Result<(), ShallowError> {
+        let path = self.shallow_path();
+        if shallow.is_empty() {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(ShallowError::Write(path, err))
+                }
+                _ => {}
+            }
+        } else {
+            let mut lock = LockedFile::acquire(&path).map_err(ShallowError::Lock)?;
+            for oid in &shallow {
+                writeln!(lock, "{oid}").map_err(|e| ShallowError::Write(path.clone(), e))?;
+            }
+            lock.commit().map_err(|e| ShallowError::Write(path, e))?;
+        }
+        self.shallow = shallow;
+        // Cached commits may have gained or lost parents
+        self.cache = Cache::new();
+        Ok(())
+    }
+
+    pub fn replacements(&self) -> &BTreeMap<UntypedOid, UntypedOid> {
+        &self.replacements
+    }
+
+    /// After which loading an object loads its replacement instead, though
+    /// it keeps its own oid, like `git replace`. Raw loads, as used to send
+    /// objects, aren't replaced.
+    pub fn set_replacements(&mut self, replacements: BTreeMap<UntypedOid, UntypedOid>) {
+        self.replacements = replacements;
+        self.cache = Cache::new();
+    }
+
+    /// What to load for `oid`, which is itself unless it's replaced
+    pub fn replaced(&self, oid: &UntypedOid) -> UntypedOid {
+        self.replacements.get(oid).copied().unwrap_or(*oid)
+    }
+
+    fn shallow_path(&self) -> PathBuf {
+        self.shallow_path.clone()
+    }
+
+    #[cfg_attr(feature = "trace", tracing::instrument(level = "trace", skip(self)))]
     pub fn load<O: Object>(&mut self, oid: Oid<O>) -> Result<O, LoadError<O>> {
         if let Some(cached) = self.cache.get(&oid) {
+            trace!("Cached");
             return Ok(cached.clone());
         }
 
-        let (len, bytes) = self.load_bytes(O::TYPE, &oid)?;
-        let object = O::deserialize(oid, len, bytes).map_err(|e| LoadError::Deserialize(oid, e))?;
+        let source = self.replaced(oid.as_untyped()).to_typed();
+        let (len, bytes) = self.load_bytes(O::TYPE, &source)?;
+        let mut object =
+            O::deserialize(oid, len, bytes).map_err(|e| LoadError::Deserialize(oid, e))?;
+        object.cut_at_shallow(&self.shallow);
         self.cache.insert(oid, object.clone());
 
         Ok(object)
     }
 
+    /// Like loading a [`Blob`], but the contents are read as needed, so that
+    /// large blobs needn't fit in memory
+    pub fn open_blob(&mut self, oid: Oid<Blob>) -> Result<blob::Reader, LoadBytesError<Blob>> {
+        if let Some(cached) = self.cache.get(&oid) {
+            let bytes = cached.bytes.clone();
+            let len = bytes.len();
+            return Ok(blob::Reader::new(
+                oid,
+                len,
+                Box::new(io::Cursor::new(bytes)),
+            ));
+        }
+        let source = self.replaced(oid.as_untyped()).to_typed();
+        let (len, bytes) = self.load_bytes(Blob::TYPE, &source)?;
+        Ok(blob::Reader::new(oid, len, Box::new(bytes)))
+    }
+
     pub fn load_tree_file(
         &mut self,
         mut tree: Oid<Tree>,
         path: &WsPath,
     ) -> Result<Option<tree::FileNode>, LoadError<Tree>> {
         for parent in path.parents() {
-            if let Some(tree::Node::Tree { oid: next_tree, .. }) =
-                self.load(tree)?.direct_child(parent.file_name())
+            if let Some(tree::Node::Tree { oid: next_tree, .. }) = self
+                .load(tree)?
+                .direct_child(parent.file_name().unwrap_or_default())
             {
                 tree = *next_tree;
             } else {
@@ -68,7 +249,9 @@ impl Db {
             }
         }
 
-        if let Some(tree::Node::File(file)) = self.load(tree)?.direct_child(path.file_name()) {
+        // No file is named "", as the root has no name
+        let name = path.file_name().unwrap_or_default();
+        if let Some(tree::Node::File(file)) = self.load(tree)?.direct_child(name) {
             Ok(Some(file.clone()))
         } else {
             Ok(None)
@@ -85,6 +268,34 @@ impl Db {
         Ok(files)
     }
 
+    /// Like [`Self::load_tree_files`] from the root, but only loads subtrees
+    /// that are within one of the prefixes.
+    pub fn load_tree_files_under(
+        &mut self,
+        tree: Oid<Tree>,
+        prefixes: &[WsPath],
+    ) -> Result<BTreeMap<WsPath, tree::FileNode>, LoadError<Tree>> {
+        let mut files = BTreeMap::new();
+
+        'prefixes: for prefix in WsPath::minimal_prefixes(prefixes) {
+            let mut subtree = tree;
+            let mut components = prefix.components().peekable();
+            while let Some(name) = components.next() {
+                match self.load(subtree)?.direct_child(name) {
+                    Some(tree::Node::Tree { oid, .. }) => subtree = *oid,
+                    Some(tree::Node::File(file)) if components.peek().is_none() => {
+                        files.insert(prefix.clone(), file.clone());
+                        continue 'prefixes;
+                    }
+                    _ => continue 'prefixes,
+                }
+            }
+            self.load_all_tree_files_into(&mut files, prefix, subtree)?;
+        }
+
+        Ok(files)
+    }
+
     fn load_all_tree_files_into(
         &mut self,
         files: &mut BTreeMap<WsPath, tree::FileNode>,
@@ -112,12 +323,26 @@ impl Db {
         &self,
         expected_type: &[u8],
         oid: &Oid<O>,
-    ) -> Result<(usize, impl BufRead), LoadBytesError<O>> {
-        let path = self.oid_path(&oid);
+    ) -> Result<(usize, Box<dyn BufRead + Send>), LoadBytesError<O>> {
+        let path = self.oid_path(oid);
         let file = match File::open(&path) {
             Ok(file) => Ok(file),
             Err(err) if err.kind() == io::ErrorKind::NotFound => {
-                Err(LoadBytesError::NotFound(*oid))
+                trace!(?oid, "Not loose, loading from packs");
+                let packed = self
+                    .load_packed(oid.as_untyped())
+                    .map_err(|e| LoadBytesError::Packed(*oid, e))?;
+                let Some((ty, data)) = packed else {
+                    return Err(LoadBytesError::NotFound(*oid));
+                };
+                if ty.name() != expected_type {
+                    return Err(LoadBytesError::WrongType {
+                        oid: *oid,
+                        expected: expected_type.into(),
+                        actual: ty.name().into(),
+                    });
+                }
+                return Ok((data.len(), Box::new(io::Cursor::new(data))));
             }
             Err(err) => Err(LoadBytesError::Open(*oid, err)),
         }?;
@@ -152,50 +377,134 @@ impl Db {
             .parse()
             .map_err(|e| LoadBytesError::ParseLenToInt(*oid, e))?;
 
-        Ok((len, bytes))
+        Ok((len, Box::new(bytes)))
+    }
+
+    /// From the first pack that has it
+    fn load_packed(
+        &self,
+        oid: &UntypedOid,
+    ) -> Result<Option<(ObjectType, Vec<u8>)>, LoadPackedError> {
+        for pack in &self.packs {
+            if let Some(object) = pack.load(oid, self)? {
+                return Ok(Some(object));
+            }
+        }
+        Ok(None)
     }
 
     /// Doesn't cache
     pub fn store_bytes<OB: ObjectBuilder>(&self, content: &[u8]) -> StoreResult<OB::Object> {
-        fn helper<O: Object>(db: &Db, oid: &Oid<O>, bytes: &[u8]) -> io::Result<()> {
-            let path = db.oid_path(oid);
+        let o_type = OB::Object::TYPE;
 
-            if path.exists() {
-                return Ok(());
-            }
+        let prefix = Self::serialized_prefix(o_type, content);
+        let oid: Oid<OB::Object> = match UntypedOid::try_for_parts(&[&prefix, content]) {
+            Ok(oid) => oid.to_typed(),
+            Err(err) => return Err(StoreError(err.0.to_typed(), err.into())),
+        };
 
-            let mut temp = NamedTempFile::new()?;
+        self.write_loose(&oid, &[&prefix, content])
+            .map_err(|e| StoreError(oid, e))?;
 
-            {
-                let mut writer = BufWriter::new(&mut temp);
-                let mut writer = ZlibEncoder::new(&mut writer, Compression::default());
-                writer.write_all(bytes)?;
-            }
+        Ok(oid)
+    }
 
-            // We use a temp file to get an atomic write
-            temp.flush()?;
+    /// Like [`Self::store_bytes`], but for an object of any type, such as one
+    /// received in a pack
+    pub fn store_raw(&self, o_type: &[u8], content: &[u8]) -> Result<UntypedOid, StoreRawError> {
+        let prefix = Self::serialized_prefix(o_type, content);
+        let oid = UntypedOid::try_for_parts(&[&prefix, content])
+            .map_err(|err| StoreRawError(err.0, err.into()))?;
 
-            match fs::rename(temp.path(), &path) {
-                Err(err) if err.kind() == ErrorKind::NotFound => {
-                    fs::create_dir(&path.parent().expect("has parent"))?;
-                    fs::rename(temp.path(), &path)?;
-                }
-                Err(err) => return Err(err),
-                Ok(()) => (),
+        self.write_loose(&oid, &[&prefix, content])
+            .map_err(|e| StoreRawError(oid, e))?;
+
+        Ok(oid)
+    }
+
+    /// The type and contents of an object of any type, or `None` if we don't
+    /// have it. Doesn't cache.
+    #[cfg_attr(feature = "trace", tracing::instrument(level = "trace", skip(self)))]
+    pub fn load_raw(&self, oid: &UntypedOid) -> Result<Option<(BString, Vec<u8>)>, LoadRawError> {
+        let file = match File::open(self.oid_path(oid)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let packed = self
+                    .load_packed(oid)
+                    .map_err(|e| LoadRawError::Packed(*oid, Box::new(e)))?;
+                return Ok(packed.map(|(ty, data)| (ty.name().into(), data)));
             }
+            Err(err) => return Err(LoadRawError::Read(*oid, err)),
+        };
+
+        let mut bytes = Vec::new();
+        ZlibDecoder::new(file)
+            .read_to_end(&mut bytes)
+            .map_err(|e| LoadRawError::Read(*oid, e))?;
+
+        let corrupt = || LoadRawError::Corrupt(*oid);
+        let type_end = bytes.find_byte(b' ').ok_or_else(corrupt)?;
+        let len_end = bytes.find_byte(b'\0').ok_or_else(corrupt)?;
+        let len = bytes
+            .get(type_end + 1..len_end)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(corrupt)?;
+        let content = bytes.split_off(len_end + 1);
+        if content.len() != len {
+            return Err(corrupt());
+        }
+        bytes.truncate(type_end);
+
+        Ok(Some((bytes.into(), content)))
+    }
 
-            Ok(())
+    pub fn contains(&self, oid: &UntypedOid) -> bool {
+        self.oid_path(oid).exists() || self.packs.iter().any(|pack| pack.contains(oid))
+    }
+
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(
+            level = "trace",
+            skip(self, parts),
+            fields(len = parts.iter().map(|p| p.len()).sum::<usize>())
+        )
+    )]
+    /// The parts are written one after another
+    fn write_loose(&self, oid: &UntypedOid, parts: &[&[u8]]) -> io::Result<()> {
+        let path = self.oid_path(oid);
+
+        if path.exists() {
+            trace!("Already stored");
+            return Ok(());
         }
 
-        let o_type = OB::Object::TYPE;
+        let mut temp = NamedTempFile::new()?;
 
-        let mut bytes = Self::serialized_prefix(o_type, content);
-        bytes.extend_from_slice(content);
-        let oid = Oid::for_serialized_bytes(&bytes);
+        {
+            let mut writer = BufWriter::new(&mut temp);
+            let mut writer = ZlibEncoder::new(&mut writer, Compression::default());
+            for part in parts {
+                writer.write_all(part)?;
+            }
+        }
 
-        helper::<OB::Object>(self, &oid, &bytes).map_err(|e| StoreError(oid, e))?;
+        // We use a temp file to get an atomic write
+        temp.flush()?;
+        fsync::file(temp.as_file(), self.fsync.loose_objects)?;
 
-        Ok(oid)
+        match fs::rename(temp.path(), &path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                fs::create_dir(path.parent().expect("has parent"))?;
+                fs::rename(temp.path(), &path)?;
+            }
+            Err(err) => return Err(err),
+            Ok(()) => (),
+        }
+        fsync::dir(path.parent().expect("has parent"), self.fsync.loose_objects)?;
+
+        Ok(())
     }
 
     fn serialized_prefix(o_type: &[u8], serialized: &[u8]) -> Vec<u8> {
@@ -211,8 +520,9 @@ impl Db {
         ser
     }
 
-    fn oid_path<O: Object>(&self, oid: &Oid<O>) -> PathBuf {
-        let oid = oid.to_hex();
+    pub(crate) fn oid_path(&self, oid: &UntypedOid) -> PathBuf {
+        let mut buf = [0; OID_HEX_SIZE];
+        let oid = oid.to_hex_buf(&mut buf);
         let dir = self.path.join(&oid[0..2]);
         let name = &oid[2..];
         dir.join(name)
@@ -221,7 +531,15 @@ impl Db {
 
 impl Clone for Db {
     fn clone(&self) -> Self {
-        Self::new(&self.path)
+        Self {
+            path: self.path.clone(),
+            shallow_path: self.shallow_path.clone(),
+            cache: Cache::new(),
+            shallow: self.shallow.clone(),
+            replacements: self.replacements.clone(),
+            packs: self.packs.clone(),
+            fsync: self.fsync,
+        }
     }
 }
 
@@ -231,6 +549,32 @@ pub type StoreResult<O> = Result<Oid<O>, StoreError<O>>;
 /// Failed to store {0:?}
 pub struct StoreError<O: Object>(Oid<O>, #[source] io::Error);
 
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+/// Failed to store {0:?}
+pub struct StoreRawError(UntypedOid, #[source] io::Error);
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ShallowError {
+    /// Failed to read shallow commits from {0:?}
+    Read(PathBuf, #[source] io::Error),
+    /// Invalid shallow commit {0:?}
+    Parse(String),
+    /// Failed to lock shallow commits
+    Lock(#[source] locked_file::Error),
+    /// Failed to write shallow commits to {0:?}
+    Write(PathBuf, #[source] io::Error),
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum LoadRawError {
+    /// Failed to read {0:?} from the database
+    Read(UntypedOid, #[source] io::Error),
+    /// Database entry for {0:?} is corrupt
+    Corrupt(UntypedOid),
+    /// Failed to read {0:?} from a pack
+    Packed(UntypedOid, #[source] Box<LoadPackedError>),
+}
+
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum LoadError<O: Object + 'static> {
     /// Failed to load bytes of object {0:?}
@@ -243,6 +587,8 @@ pub enum LoadError<O: Object + 'static> {
 pub enum LoadBytesError<O: Object + 'static> {
     /// {0:?} not found in database
     NotFound(Oid<O>),
+    /// Failed to read {0:?} from a pack
+    Packed(Oid<O>, #[source] LoadPackedError),
     /// Failed to open the file for {0:?} in the database
     Open(Oid<O>, #[source] io::Error),
     /// Failed to read the prefix from the file for {0:?} in the database
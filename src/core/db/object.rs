@@ -1,18 +1,25 @@
-use std::{convert::TryInto, fmt, io::BufRead, marker::PhantomData};
-
-use crate::core::{db, Db};
-use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY as SHA1};
+use std::{
+    collections::BTreeSet,
+    convert::TryInto,
+    fmt,
+    io::{self, BufRead},
+    marker::PhantomData,
+    str::FromStr,
+};
+
+use crate::core::{db, init::ObjectFormat, Db};
+use ring::digest::{digest, Context, SHA1_FOR_LEGACY_USE_ONLY as SHA1};
 
 pub struct Oid<O: Object> {
     inner: UntypedOid,
     _ty: PhantomData<O>,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct UntypedOid([u8; OID_SIZE]);
 
 pub const OID_SIZE: usize = 20;
-pub(crate) const OID_HEX_SIZE: usize = 40;
+pub const OID_HEX_SIZE: usize = 40;
 
 /// Note that none of the constructors check the type of the Oid.
 impl<O: Object> Oid<O> {
@@ -25,7 +32,11 @@ impl<O: Object> Oid<O> {
     }
 
     pub fn parse(hex: impl AsRef<[u8]>) -> Result<Self, ParseOidError> {
-        let inner = UntypedOid::parse(hex)?;
+        Self::from_hex(hex)
+    }
+
+    pub fn from_hex(hex: impl AsRef<[u8]>) -> Result<Self, ParseOidError> {
+        let inner = UntypedOid::from_hex(hex)?;
         Ok(Self::from_untyped(inner))
     }
 
@@ -34,11 +45,6 @@ impl<O: Object> Oid<O> {
         Ok(Self::from_untyped(inner))
     }
 
-    pub(crate) fn for_serialized_bytes<B: AsRef<[u8]>>(bytes: B) -> Self {
-        let inner = UntypedOid::for_bytes(bytes);
-        Self::from_untyped(inner)
-    }
-
     pub fn into_untyped(self) -> UntypedOid {
         self.inner
     }
@@ -55,7 +61,7 @@ impl<O: Object> Oid<O> {
     }
 
     fn type_as_str() -> &'static str {
-        &std::str::from_utf8(O::TYPE).expect("TYPE always utf8")
+        std::str::from_utf8(O::TYPE).expect("TYPE always utf8")
     }
 }
 
@@ -76,7 +82,13 @@ impl UntypedOid {
         Self::new([0; OID_SIZE])
     }
 
+    /// Same as [`Self::from_hex`]
     pub fn parse(hex: impl AsRef<[u8]>) -> Result<Self, ParseOidError> {
+        Self::from_hex(hex)
+    }
+
+    /// Exactly [`OID_HEX_SIZE`] hex digits, in either case
+    pub fn from_hex(hex: impl AsRef<[u8]>) -> Result<Self, ParseOidError> {
         let hex = hex.as_ref();
         let len = hex.len();
         let hex = hex
@@ -86,8 +98,8 @@ impl UntypedOid {
     }
 
     pub fn parse_sized(hex: &[u8; OID_HEX_SIZE]) -> Result<Self, ParseSizedOidError> {
-        let oid = hex::decode(hex.as_ref())?;
-        let oid: [u8; OID_SIZE] = oid.try_into().expect("size known correct");
+        let mut oid = [0; OID_SIZE];
+        hex::decode_to_slice(hex, &mut oid)?;
         Ok(Self::new(oid))
     }
 
@@ -97,6 +109,45 @@ impl UntypedOid {
         Self::new(hash)
     }
 
+    /// Like [`Self::for_bytes`] of the parts one after another, without
+    /// copying them together
+    pub(crate) fn for_parts(parts: &[&[u8]]) -> Self {
+        let mut ctx = Context::new(&SHA1);
+        for part in parts {
+            ctx.update(part);
+        }
+        let hash = ctx
+            .finish()
+            .as_ref()
+            .try_into()
+            .expect("Digest has correct len");
+        Self::new(hash)
+    }
+
+    /// Like [`Self::for_parts`], for objects we're about to store. With the
+    /// `sha1dc` feature this fails if the parts look like they're part of a
+    /// SHA-1 collision attack.
+    #[cfg(feature = "sha1dc")]
+    pub(crate) fn try_for_parts(parts: &[&[u8]]) -> Result<Self, CollisionError> {
+        let mut hasher = sha1collisiondetection::Sha1CD::default();
+        for part in parts {
+            hasher.update(part);
+        }
+        match hasher.finalize_cd() {
+            Ok(hash) => Ok(Self::new(hash.into())),
+            Err(_) => Err(CollisionError(Self::for_parts(parts))),
+        }
+    }
+
+    /// Like [`Self::for_parts`], for objects we're about to store. With the
+    /// `sha1dc` feature this fails if the parts look like they're part of a
+    /// SHA-1 collision attack.
+    #[cfg(not(feature = "sha1dc"))]
+    #[allow(clippy::unnecessary_wraps)]
+    pub(crate) fn try_for_parts(parts: &[&[u8]]) -> Result<Self, CollisionError> {
+        Ok(Self::for_parts(parts))
+    }
+
     pub const fn as_bytes(&self) -> &[u8; OID_SIZE] {
         &self.0
     }
@@ -105,17 +156,38 @@ impl UntypedOid {
         hex::encode(self.as_bytes())
     }
 
+    /// Like [`Self::to_hex`], without allocating
+    pub fn to_hex_buf<'b>(&self, buf: &'b mut [u8; OID_HEX_SIZE]) -> &'b str {
+        hex::encode_to_slice(self.as_bytes(), buf).expect("Buffer is the hex size");
+        std::str::from_utf8(buf).expect("Hex is ASCII")
+    }
+
+    /// The first `len` hex digits, like git's abbreviated oids. Same as
+    /// formatting with a precision, like `{:.7}`.
+    pub fn to_abbrev(&self, len: usize) -> String {
+        format!("{self:.len$}")
+    }
+
+    /// Which hash function made the oid. Only SHA-1 is supported for now.
+    pub const fn object_format(&self) -> ObjectFormat {
+        ObjectFormat::Sha1
+    }
+
+    /// Compares in time that doesn't depend on where the oids differ, for
+    /// when one is a secret
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        ring::constant_time::verify_slices_are_equal(&self.0, &other.0).is_ok()
+    }
+
     pub fn to_typed<O: Object>(self) -> Oid<O> {
         Oid::from_untyped(self)
     }
 }
 
+#[allow(clippy::expl_impl_clone_on_copy)] // Deriving would require O: Clone
 impl<O: Object> Clone for Oid<O> {
     fn clone(&self) -> Self {
-        Self {
-            inner: self.inner,
-            _ty: PhantomData,
-        }
+        *self
     }
 }
 
@@ -123,7 +195,7 @@ impl<O: Object> Copy for Oid<O> {}
 
 impl<O: Object> std::hash::Hash for Oid<O> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.hash(state)
+        self.0.hash(state);
     }
 }
 
@@ -136,9 +208,83 @@ impl<O: Object> fmt::Debug for Oid<O> {
     }
 }
 
+/// The type and the hex, like `commit 4b825dc..`. Format the
+/// [`UntypedOid`] (with `*oid`) for just the hex.
 impl<O: Object> fmt::Display for Oid<O> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", &Self::type_as_str(), self.to_hex())
+        write!(f, "{} {}", Self::type_as_str(), self.to_hex())
+    }
+}
+
+/// Either the hex, or the type and the hex as [`fmt::Display`] writes them,
+/// in which case the type has to match
+impl<O: Object> FromStr for Oid<O> {
+    type Err = ParseOidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = match s.split_once(' ') {
+            Some((ty, hex)) if ty.as_bytes() == O::TYPE => hex,
+            Some((ty, _)) => {
+                return Err(ParseOidError::WrongType {
+                    expected: Self::type_as_str(),
+                    got: ty.to_owned(),
+                })
+            }
+            None => s,
+        };
+        Self::from_hex(hex)
+    }
+}
+
+/// The hex, or with a precision (like `{:.7}`) only that many digits of it
+impl fmt::Display for UntypedOid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut buf = [0; OID_HEX_SIZE];
+        let hex = self.to_hex_buf(&mut buf);
+        let len = f
+            .precision()
+            .map_or(OID_HEX_SIZE, |len| len.min(OID_HEX_SIZE));
+        f.write_str(&hex[..len])
+    }
+}
+
+impl FromStr for UntypedOid {
+    type Err = ParseOidError;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        Self::from_hex(hex)
+    }
+}
+
+/// Serialized as the hex string, without the type
+#[cfg(feature = "serde")]
+impl<O: Object> serde::Serialize for Oid<O> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+/// Deserialized from the hex string. The type isn't checked.
+#[cfg(feature = "serde")]
+impl<'de, O: Object> serde::Deserialize<'de> for Oid<O> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <UntypedOid as serde::Deserialize>::deserialize(deserializer).map(Self::from_untyped)
+    }
+}
+
+/// Serialized as the hex string
+#[cfg(feature = "serde")]
+impl serde::Serialize for UntypedOid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for UntypedOid {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = <String as serde::Deserialize>::deserialize(deserializer)?;
+        Self::parse(hex).map_err(serde::de::Error::custom)
     }
 }
 
@@ -150,7 +296,19 @@ impl<O: Object> PartialEq for Oid<O> {
 
 impl<O: Object> Eq for Oid<O> {}
 
-pub trait Object: fmt::Debug + Clone {
+impl<O: Object> PartialOrd for Oid<O> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<O: Object> Ord for Oid<O> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.inner.cmp(&other.inner)
+    }
+}
+
+pub trait Object: fmt::Debug + Clone + Send + Sync {
     const TYPE: &'static [u8];
 
     type Builder: ObjectBuilder;
@@ -164,6 +322,10 @@ pub trait Object: fmt::Debug + Clone {
         len: usize,
         data: impl BufRead,
     ) -> Result<Self, Self::DeserializeError>;
+
+    /// Leaves out parents that we don't have, if this is one of the
+    /// `shallow` commits
+    fn cut_at_shallow(&mut self, _shallow: &BTreeSet<UntypedOid>) {}
 }
 
 #[allow(clippy::module_name_repetitions)]
@@ -180,8 +342,122 @@ pub enum ParseOidError {
     WrongHexSize(usize),
     #[error(transparent)]
     Parse(#[from] ParseSizedOidError),
+    #[error("Expected a {expected} oid, got a {got} one")]
+    WrongType { expected: &'static str, got: String },
+}
+
+/// Object {0} looks like part of a SHA-1 collision attack
+#[derive(Debug, Clone, Copy, Eq, PartialEq, thiserror::Error, displaydoc::Display)]
+pub struct CollisionError(pub UntypedOid);
+
+/// For storing objects, which fails with IO errors
+impl From<CollisionError> for io::Error {
+    fn from(err: CollisionError) -> Self {
+        Self::new(io::ErrorKind::InvalidData, err)
+    }
 }
 
 #[derive(thiserror::Error, displaydoc::Display, Debug)]
 /// Failed to parse Oid
 pub struct ParseSizedOidError(#[from] hex::FromHexError);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const HEX: &str = "ce013625030ba8dba906f756967f9e9ca394464a";
+
+    #[test]
+    fn hashes_parts_as_if_together() -> eyre::Result<()> {
+        let together = UntypedOid::for_bytes(b"blob 5\0hello");
+        assert_eq!(together, UntypedOid::for_parts(&[b"blob 5\0", b"hello"]));
+        assert_eq!(
+            together,
+            UntypedOid::try_for_parts(&[b"blob 5", b"\0hello"])?
+        );
+        Ok(())
+    }
+
+    /// The start of the first PDF of the `SHAttered` attack, up to the blocks
+    /// that make it hash the same as the second
+    #[cfg(feature = "sha1dc")]
+    #[test]
+    fn detects_shattered() {
+        let prefix = include_bytes!("../../../test_data/shattered-1-prefix.pdf");
+        let err = UntypedOid::try_for_parts(&[&prefix[..100], &prefix[100..]]).unwrap_err();
+        assert_eq!(CollisionError(UntypedOid::for_bytes(prefix)), err);
+
+        // Behind an object header the blocks no longer line up, so collide
+        // with nothing
+        assert!(UntypedOid::try_for_parts(&[b"blob 320\0", prefix]).is_ok());
+    }
+
+    #[test]
+    fn formats_and_parses_hex() -> eyre::Result<()> {
+        let oid: UntypedOid = HEX.parse()?;
+        assert_eq!(HEX, oid.to_string());
+        assert_eq!("ce01362", format!("{oid:.7}"));
+        assert_eq!("ce01362", oid.to_abbrev(7));
+        assert_eq!(HEX, oid.to_abbrev(100));
+        let mut buf = [0; OID_HEX_SIZE];
+        assert_eq!(HEX, oid.to_hex_buf(&mut buf));
+        assert_eq!(oid, UntypedOid::from_hex(HEX.to_uppercase())?);
+        assert_eq!(ObjectFormat::Sha1, oid.object_format());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_through_display() -> eyre::Result<()> {
+        let oid: UntypedOid = HEX.parse()?;
+        assert_eq!(oid, oid.to_string().parse()?);
+
+        let blob: Oid<db::Blob> = HEX.parse()?;
+        assert_eq!(format!("blob {HEX}"), blob.to_string());
+        assert_eq!(blob, blob.to_string().parse()?);
+        assert!(matches!(
+            format!("tree {HEX}").parse::<Oid<db::Blob>>(),
+            Err(ParseOidError::WrongType { expected: "blob", got }) if got == "tree"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn compares_in_constant_time() -> eyre::Result<()> {
+        let oid: UntypedOid = HEX.parse()?;
+        assert!(oid.ct_eq(&oid));
+        assert!(!oid.ct_eq(&UntypedOid::zero()));
+        Ok(())
+    }
+
+    /// Random inputs, a few bytes changed from valid hex, are only accepted
+    /// if they're still valid
+    #[test]
+    fn rejects_invalid_hex() {
+        // A fixed xorshift, so failures reproduce
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..10_000 {
+            let mut input = HEX.as_bytes().to_vec();
+            #[allow(clippy::cast_possible_truncation)]
+            match next() % 3 {
+                0 => input.truncate((next() % 40) as usize),
+                1 => input.push(next() as u8),
+                _ => input[(next() % 40) as usize] = next() as u8,
+            }
+            let valid = input.len() == OID_HEX_SIZE && input.iter().all(u8::is_ascii_hexdigit);
+            match UntypedOid::from_hex(&input) {
+                Ok(oid) => {
+                    assert!(valid, "{:?}", input);
+                    assert_eq!(input.to_ascii_lowercase(), oid.to_hex().into_bytes());
+                }
+                Err(_) => assert!(!valid, "{:?}", input),
+            }
+        }
+    }
+}
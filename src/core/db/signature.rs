@@ -0,0 +1,288 @@
+use std::{env, fmt, sync::LazyLock};
+
+use bstr::{BStr, BString, ByteSlice, Utf8Error};
+use chrono::{DateTime, FixedOffset, Local};
+use regex::bytes::Regex;
+
+use super::date;
+use crate::core::Config;
+
+/// Who made a commit, and when. Also used for the committer.
+/// With the `serde` feature the time serializes in git's format, like
+/// `"1619863200 +0200"`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Signature {
+    #[cfg_attr(feature = "serde", serde(with = "crate::core::serialize::bytes"))]
+    name: BString,
+    #[cfg_attr(feature = "serde", serde(with = "crate::core::serialize::bytes"))]
+    email: BString,
+    #[cfg_attr(feature = "serde", serde(with = "crate::core::serialize::time"))]
+    time: DateTime<FixedOffset>,
+}
+
+/// Which identity to resolve with [`Signature::resolve`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Author,
+    Committer,
+}
+
+impl Signature {
+    const TIME_FORMAT: &'static str = "%s %z";
+
+    pub fn new(
+        name: impl Into<BString>,
+        email: impl Into<BString>,
+        time: DateTime<FixedOffset>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            email: email.into(),
+            time,
+        }
+    }
+
+    pub fn new_local(
+        name: impl Into<BString>,
+        email: impl Into<BString>,
+        time: DateTime<Local>,
+    ) -> Self {
+        let offset = time.offset();
+        let time = time.with_timezone(offset);
+        Self::new(name, email, time)
+    }
+
+    /// Like git, from `GIT_AUTHOR_NAME`, `GIT_AUTHOR_EMAIL` and
+    /// `GIT_AUTHOR_DATE` (or the `GIT_COMMITTER_` equivalents), falling back
+    /// to `author.name` (or `committer.name`), then `user.name`, and so on in
+    /// the config. The email can also come from `EMAIL`. The time defaults
+    /// to now, and the date can be any that [`date::parse`] accepts, like
+    /// `yesterday`.
+    pub fn resolve(role: Role, config: &Config) -> Result<Self, IdentityError> {
+        Self::resolve_with(role, config, |var| env::var(var).ok(), Local::now())
+    }
+
+    fn resolve_with(
+        role: Role,
+        config: &Config,
+        env: impl Fn(&str) -> Option<String>,
+        now: DateTime<Local>,
+    ) -> Result<Self, IdentityError> {
+        let lookup = |field: &str| {
+            env(&format!(
+                "{}_{}",
+                role.env_prefix(),
+                field.to_ascii_uppercase()
+            ))
+            .or_else(|| config.get(&format!("{role}.{field}")).map(str::to_owned))
+            .or_else(|| config.get(&format!("user.{field}")).map(str::to_owned))
+            .filter(|value| !value.is_empty())
+        };
+
+        let name = lookup("name").ok_or(IdentityError::MissingName(role))?;
+        let email = lookup("email")
+            .or_else(|| env("EMAIL").filter(|email| !email.is_empty()))
+            .ok_or(IdentityError::MissingEmail(role))?;
+
+        let date_var = format!("{}_DATE", role.env_prefix());
+        match env(&date_var) {
+            Some(date) => {
+                let time = date::parse_at(&date, &now)
+                    .ok_or_else(|| IdentityError::InvalidDate(date_var, date.clone()))?;
+                Ok(Self::new(name, email, time))
+            }
+            None => Ok(Self::new_local(name, email, now)),
+        }
+    }
+
+    pub fn name(&self) -> &BStr {
+        self.name.as_bstr()
+    }
+
+    pub fn email(&self) -> &BStr {
+        self.email.as_bstr()
+    }
+
+    pub fn time(&self) -> DateTime<FixedOffset> {
+        self.time
+    }
+
+    pub(crate) fn serialize(&self) -> BString {
+        let time = self.time.format(Self::TIME_FORMAT);
+        format!("{} <{}> {}", &self.name, &self.email, time).into()
+    }
+
+    pub(crate) fn parse(serialized: &BStr) -> Result<Signature, ParseError> {
+        SignatureRef::parse(serialized).map(|signature| signature.to_signature())
+    }
+}
+
+/// A [`Signature`] borrowed from the data of a
+/// [`CommitRef`](super::commit::CommitRef)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignatureRef<'a> {
+    pub name: &'a BStr,
+    pub email: &'a BStr,
+    pub time: DateTime<FixedOffset>,
+}
+
+impl<'a> SignatureRef<'a> {
+    pub fn parse(serialized: &'a BStr) -> Result<Self, ParseError> {
+        static RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new("^(?P<name>.*) <(?P<email>.*?)> (?P<time>.*)$").unwrap());
+
+        let caps = RE
+            .captures(serialized)
+            .ok_or_else(|| ParseError::MatchFailed(serialized.to_owned()))?;
+
+        let name = caps.name("name").unwrap().as_bytes().as_bstr();
+        let email = caps.name("email").unwrap().as_bytes().as_bstr();
+
+        let time = caps.name("time").unwrap().as_bytes();
+        let time = time.to_str().map_err(|source| {
+            ParseError::MalformedTimeEncoding(time.as_bstr().to_owned(), source)
+        })?;
+        let time = DateTime::parse_from_str(time, Signature::TIME_FORMAT)
+            .map_err(|e| ParseError::InvalidTime(time.to_owned(), e))?;
+
+        Ok(Self { name, email, time })
+    }
+
+    pub fn to_signature(&self) -> Signature {
+        Signature::new(self.name, self.email, self.time)
+    }
+}
+
+impl Role {
+    fn env_prefix(self) -> &'static str {
+        match self {
+            Self::Author => "GIT_AUTHOR",
+            Self::Committer => "GIT_COMMITTER",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Author => f.write_str("author"),
+            Self::Committer => f.write_str("committer"),
+        }
+    }
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ParseError {
+    /// Failed to match expected pattern. Got: {0}
+    MatchFailed(BString),
+    /// Time is not valid utf8: {0}
+    MalformedTimeEncoding(BString, #[source] Utf8Error),
+    /// Failed to parse time: {0}
+    InvalidTime(String, #[source] chrono::ParseError),
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error, PartialEq, Eq)]
+pub enum IdentityError {
+    /// No {0} name given. Set user.name in the config.
+    MissingName(Role),
+    /// No {0} email given. Set user.email in the config.
+    MissingEmail(Role),
+    /// Invalid date in {0}: {1:?}
+    InvalidDate(String, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use pretty_assertions::assert_eq;
+    use std::collections::BTreeMap;
+
+    fn resolve(role: Role, config: &str, env: &[(&str, &str)]) -> Result<Signature, IdentityError> {
+        let config = Config::parse(config).unwrap();
+        let env = env
+            .iter()
+            .map(|&(var, value)| (var.to_owned(), value.to_owned()))
+            .collect::<BTreeMap<_, _>>();
+        let now = Local.timestamp_opt(1_600_000_000, 0).unwrap();
+        Signature::resolve_with(role, &config, |var| env.get(var).cloned(), now)
+    }
+
+    const CONFIG: &str = "[user]\n\tname = User\n\temail = user@example.com\n";
+
+    #[test]
+    fn environment_overrides_config() -> eyre::Result<()> {
+        let env = [
+            ("GIT_AUTHOR_NAME", "Author"),
+            ("GIT_AUTHOR_DATE", "@1500000000 +0200"),
+            ("GIT_COMMITTER_EMAIL", "committer@example.com"),
+        ];
+
+        let author = resolve(Role::Author, CONFIG, &env)?;
+        assert_eq!("Author", author.name());
+        assert_eq!("user@example.com", author.email());
+        assert_eq!(
+            b"Author <user@example.com> 1500000000 +0200".as_bstr(),
+            author.serialize()
+        );
+
+        let committer = resolve(Role::Committer, CONFIG, &env)?;
+        assert_eq!("User", committer.name());
+        assert_eq!("committer@example.com", committer.email());
+        assert_eq!(1_600_000_000, committer.time().timestamp());
+
+        let env = [("GIT_COMMITTER_DATE", "2 days ago")];
+        let committer = resolve(Role::Committer, CONFIG, &env)?;
+        assert_eq!(
+            1_600_000_000 - 2 * 24 * 60 * 60,
+            committer.time().timestamp()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn role_config_overrides_user() -> eyre::Result<()> {
+        let config = format!("{CONFIG}[committer]\n\tname = Committer\n");
+        assert_eq!("User", resolve(Role::Author, &config, &[])?.name());
+        assert_eq!("Committer", resolve(Role::Committer, &config, &[])?.name());
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_the_offset_it_was_made_in() -> eyre::Result<()> {
+        for serialized in [
+            "Name <a@example.com> 1500000000 +0530",
+            "Name <a@example.com> 1500000000 -1200",
+            "Name <a@example.com> 0 +0000",
+        ] {
+            let signature = Signature::parse(serialized.into())?;
+            assert_eq!(serialized, signature.serialize());
+        }
+        let signature = Signature::parse("Name <a@example.com> 1500000000 +0530".into())?;
+        assert_eq!(
+            5 * 3600 + 30 * 60,
+            signature.time().offset().local_minus_utc()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn errors_without_identity() {
+        assert_eq!(
+            Err(IdentityError::MissingName(Role::Author)),
+            resolve(Role::Author, "", &[("EMAIL", "a@example.com")])
+        );
+        assert_eq!(
+            Err(IdentityError::MissingEmail(Role::Committer)),
+            resolve(Role::Committer, "[user]\n\tname = User\n", &[])
+        );
+        assert_eq!(
+            Err(IdentityError::InvalidDate(
+                "GIT_AUTHOR_DATE".to_owned(),
+                "soon".to_owned()
+            )),
+            resolve(Role::Author, CONFIG, &[("GIT_AUTHOR_DATE", "soon")])
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use bstr::{BStr, ByteSlice};
+
+use super::{object::ParseOidError, UntypedOid};
+
+/// An annotated tag borrowed from its raw data (see
+/// [`Db::load_raw`](super::Db::load_raw)), read only as far as its headers
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TagRef<'a> {
+    /// What's tagged, usually a commit
+    pub object: UntypedOid,
+    /// The type of what's tagged, like `commit`
+    pub object_type: &'a BStr,
+    pub name: &'a BStr,
+}
+
+impl<'a> TagRef<'a> {
+    /// Like git, a tag needs to say what it tags and be named
+    pub fn parse(data: &'a [u8]) -> Result<Self, DeserializeError> {
+        let mut object = None;
+        let mut object_type = None;
+        let mut name = None;
+        for line in ByteSlice::lines(data) {
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix(b"object ") {
+                object = Some(UntypedOid::parse(value).map_err(DeserializeError::ParseObject)?);
+            } else if let Some(value) = line.strip_prefix(b"type ") {
+                object_type = Some(value.as_bstr());
+            } else if let Some(value) = line.strip_prefix(b"tag ") {
+                name = Some(value.as_bstr());
+            }
+        }
+        Ok(Self {
+            object: object.ok_or(DeserializeError::MissingObject)?,
+            object_type: object_type.ok_or(DeserializeError::MissingType)?,
+            name: name.ok_or(DeserializeError::MissingName)?,
+        })
+    }
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum DeserializeError {
+    /// Failed to parse oid of tagged object
+    ParseObject(#[source] ParseOidError),
+    /// Header object not present
+    MissingObject,
+    /// Header type not present
+    MissingType,
+    /// Header tag not present
+    MissingName,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn borrows_from_data() -> eyre::Result<()> {
+        let data = b"object 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+type commit
+tag v1.0
+tagger T <t@example.com> 1619863200 +0200
+
+object in the message
+";
+        let tag = TagRef::parse(data)?;
+        assert_eq!(
+            UntypedOid::parse("4b825dc642cb6eb9a060e54bf8d69288fbee4904")?,
+            tag.object
+        );
+        assert_eq!("commit", tag.object_type);
+        assert_eq!("v1.0", tag.name);
+
+        assert!(matches!(
+            TagRef::parse(b"type commit\ntag v1.0\n"),
+            Err(DeserializeError::MissingObject)
+        ));
+        Ok(())
+    }
+}
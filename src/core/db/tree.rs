@@ -1,7 +1,4 @@
-use std::{
-    collections::BTreeMap,
-    io::{self, BufRead},
-};
+use std::{collections::BTreeMap, io};
 
 use bstr::{BStr, BString, ByteSlice};
 
@@ -9,25 +6,67 @@ use crate::core::{db, stat, Db, Object, Oid, WsPath};
 
 use super::{object::OID_SIZE, Blob, ObjectBuilder, UntypedOid};
 
+/// With the `serde` feature the nodes serialize as a list, since each has
+/// its name
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Tree {
     oid: Oid<Tree>,
+    #[cfg_attr(feature = "serde", serde(with = "nodes_serde"))]
     nodes: BTreeMap<BString, Node>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum Node {
     File(FileNode),
-    Tree { name: BString, oid: Oid<Tree> },
+    Tree {
+        #[cfg_attr(feature = "serde", serde(with = "crate::core::serialize::bytes"))]
+        name: BString,
+        oid: Oid<Tree>,
+    },
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FileNode {
     pub oid: Oid<Blob>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::core::serialize::bytes"))]
     pub name: BString,
     pub mode: stat::Mode,
 }
 
+#[cfg(feature = "serde")]
+mod nodes_serde {
+    use std::collections::BTreeMap;
+
+    use bstr::BString;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::Node;
+
+    pub(super) fn serialize<S: Serializer>(
+        nodes: &BTreeMap<BString, Node>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(nodes.values())
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<BString, Node>, D::Error> {
+        let nodes = Vec::<Node>::deserialize(deserializer)?;
+        Ok(nodes
+            .into_iter()
+            .map(|node| (node.name().to_owned(), node))
+            .collect())
+    }
+}
+
 impl Tree {
     const MODE: &'static [u8] = b"40000";
 
@@ -52,64 +91,156 @@ impl Object for Tree {
 
     fn deserialize(
         oid: Oid<Self>,
-        _len: usize,
+        len: usize,
         mut data: impl std::io::BufRead,
     ) -> Result<Self, Self::DeserializeError> {
-        let mut nodes = BTreeMap::new();
+        let mut bytes = Vec::with_capacity(len);
+        data.read_to_end(&mut bytes)?;
+
+        Ok(TreeRef::parse(&bytes)?.to_tree(oid))
+    }
+}
+
+/// A tree borrowed from its raw data (see [`Db::load_raw`]), so that reading
+/// it doesn't copy the name of every node
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TreeRef<'a> {
+    data: &'a [u8],
+}
+
+/// A [`Node`] borrowed from the data of a [`TreeRef`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum NodeRef<'a> {
+    File {
+        oid: Oid<Blob>,
+        name: &'a BStr,
+        mode: stat::Mode,
+    },
+    Tree {
+        name: &'a BStr,
+        oid: Oid<Tree>,
+    },
+}
 
-        while let Some(node) = Node::deserialize(&mut data)? {
-            nodes.insert(node.name().to_owned(), node);
+impl<'a> TreeRef<'a> {
+    /// Fails if any node is corrupt
+    pub fn parse(data: &'a [u8]) -> Result<Self, DeserializeError> {
+        let mut rest = data;
+        while !rest.is_empty() {
+            NodeRef::parse(&mut rest)?;
         }
+        Ok(Self { data })
+    }
+
+    /// In the order they're stored, which is by name
+    pub fn nodes(&self) -> impl Iterator<Item = NodeRef<'a>> {
+        let mut rest = self.data;
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                None
+            } else {
+                Some(NodeRef::parse(&mut rest).expect("Checked when parsed"))
+            }
+        })
+    }
 
-        Ok(Self { oid, nodes })
+    pub fn direct_child(&self, name: &BStr) -> Option<NodeRef<'a>> {
+        self.nodes().find(|node| node.name() == name)
+    }
+
+    /// Copy the nodes out of the data
+    pub fn to_tree(&self, oid: Oid<Tree>) -> Tree {
+        let nodes = self
+            .nodes()
+            .map(|node| (node.name().to_owned(), node.to_node()))
+            .collect();
+        Tree { oid, nodes }
     }
 }
 
-impl Node {
+impl<'a> NodeRef<'a> {
     pub fn untyped_oid(&self) -> UntypedOid {
         match self {
-            Self::File(FileNode { oid, .. }) => oid.into_untyped(),
+            Self::File { oid, .. } => oid.into_untyped(),
             Self::Tree { oid, .. } => oid.into_untyped(),
         }
     }
 
-    pub fn name(&self) -> &BStr {
-        match self {
-            Node::File(FileNode { name, .. }) | Node::Tree { name, .. } => name.as_bstr(),
+    pub fn name(&self) -> &'a BStr {
+        match *self {
+            Self::File { name, .. } | Self::Tree { name, .. } => name,
         }
     }
 
-    fn deserialize(mut data: impl BufRead) -> Result<Option<Self>, DeserializeError> {
-        let mut mode = Vec::new();
-        let bytes_read = data.read_until(b' ', &mut mode)?;
-        if bytes_read == 0 {
-            return Ok(None);
-        }
-        mode.pop().unwrap();
-
-        let mut name = BString::from(Vec::new());
-        data.read_until(b'\0', &mut name)?;
-        name.pop().unwrap();
+    /// A submodule's commit, which is in its own repository rather than ours
+    pub fn is_gitlink(&self) -> bool {
+        matches!(
+            self,
+            Self::File {
+                mode: stat::Mode::Gitlink,
+                ..
+            }
+        )
+    }
 
-        let mut oid = [0; OID_SIZE];
-        data.read_exact(&mut oid)?;
-        let oid = UntypedOid::new(oid);
+    pub fn to_node(&self) -> Node {
+        match *self {
+            Self::File { oid, name, mode } => Node::File(FileNode {
+                oid,
+                name: name.to_owned(),
+                mode,
+            }),
+            Self::Tree { name, oid } => Node::Tree {
+                name: name.to_owned(),
+                oid,
+            },
+        }
+    }
 
-        let entry = if mode == Tree::MODE {
+    /// Parse the node at the start of `data`, advancing it past the node
+    fn parse(data: &mut &'a [u8]) -> Result<Self, DeserializeError> {
+        let rest: &'a [u8] = data;
+        let truncated = || DeserializeError(io::ErrorKind::UnexpectedEof.into());
+        let mode_end = rest.find_byte(b' ').ok_or_else(truncated)?;
+        let name_end = rest.find_byte(b'\0').ok_or_else(truncated)?;
+        let mode = rest[..mode_end].as_bstr();
+        let name = rest.get(mode_end + 1..name_end).ok_or_else(truncated)?;
+        let oid = rest
+            .get(name_end + 1..name_end + 1 + OID_SIZE)
+            .ok_or_else(truncated)?;
+        let mut bytes = [0; OID_SIZE];
+        bytes.copy_from_slice(oid);
+        let oid = UntypedOid::new(bytes);
+        *data = &rest[name_end + 1 + OID_SIZE..];
+
+        let name = name.as_bstr();
+        Ok(if mode == Tree::MODE {
             Self::Tree {
-                oid: oid.to_typed(),
                 name,
+                oid: oid.to_typed(),
             }
         } else {
-            let mode = stat::Mode::from_base8(mode.as_bstr());
-            Self::File(FileNode {
+            Self::File {
                 oid: oid.to_typed(),
-                mode,
                 name,
-            })
-        };
+                mode: stat::Mode::from_base8(mode),
+            }
+        })
+    }
+}
+
+impl Node {
+    pub fn untyped_oid(&self) -> UntypedOid {
+        match self {
+            Self::File(FileNode { oid, .. }) => oid.into_untyped(),
+            Self::Tree { oid, .. } => oid.into_untyped(),
+        }
+    }
 
-        Ok(Some(entry))
+    pub fn name(&self) -> &BStr {
+        match self {
+            Node::File(FileNode { name, .. }) | Node::Tree { name, .. } => name.as_bstr(),
+        }
     }
 }
 
@@ -134,8 +265,14 @@ impl Builder {
         }
     }
 
+    #[must_use]
     pub fn entries(mut self, entries: impl IntoIterator<Item = EntryBuilder>) -> Self {
         for desc in entries {
+            // The root isn't a file
+            let name = match desc.path.file_name() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
             let mut parent = 0;
 
             for name in desc.path.parent_components() {
@@ -156,7 +293,7 @@ impl Builder {
             }
 
             self.tree(parent).insert(
-                desc.path.file_name().to_owned(),
+                name,
                 SerializeNode::Entry {
                     oid: desc.oid,
                     mode: desc.mode,
@@ -228,6 +365,29 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn borrows_nodes_from_data() -> eyre::Result<()> {
+        let mut data = b"100644 a.txt\0".to_vec();
+        data.extend_from_slice(&[1; OID_SIZE]);
+        data.extend_from_slice(b"40000 dir\0");
+        data.extend_from_slice(&[2; OID_SIZE]);
+
+        let tree = TreeRef::parse(&data)?;
+        let names = tree.nodes().map(|node| node.name()).collect::<Vec<_>>();
+        assert_eq!(vec!["a.txt", "dir"], names);
+        assert!(matches!(
+            tree.direct_child(b"dir".as_bstr()),
+            Some(NodeRef::Tree { .. })
+        ));
+
+        let owned = tree.to_tree(Oid::zero());
+        assert!(owned
+            .direct_children()
+            .eq(&tree.nodes().map(|node| node.to_node()).collect::<Vec<_>>()));
+        assert!(TreeRef::parse(&data[..data.len() - 1]).is_err());
+        Ok(())
+    }
+
     #[test]
     fn builder_entries() {
         let builder = Builder::new().entries(vec![
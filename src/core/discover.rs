@@ -0,0 +1,217 @@
+//! Finding the repository a directory is in by looking in it and then its
+//! parents, like git does, so that commands can be run from anywhere inside
+//! a workspace. Scripts and hooks can instead say where the repository is
+//! with the environment, as [`OpenOptions`] describes.
+
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, instrument};
+
+use crate::core::{platform, repo::ReadError, Repo};
+
+/// Where looking for a repository stops. [`Self::from_env`] reads these from
+/// the environment like git does.
+#[derive(Debug, Clone, Default)]
+pub struct DiscoverOptions {
+    /// Directories not to look in, or above, unless the search starts in
+    /// one of them, like `GIT_CEILING_DIRECTORIES`. These are compared after
+    /// resolving symlinks.
+    pub ceiling_dirs: Vec<PathBuf>,
+    /// Keep looking in parents on other filesystems than where the search
+    /// started, like `GIT_DISCOVERY_ACROSS_FILESYSTEM`
+    pub across_filesystems: bool,
+}
+
+impl DiscoverOptions {
+    /// `GIT_CEILING_DIRECTORIES` is a list separated like `PATH`, of which
+    /// relative paths are ignored
+    pub fn from_env() -> Self {
+        let ceiling_dirs = env::var_os("GIT_CEILING_DIRECTORIES")
+            .map(|dirs| {
+                env::split_paths(&dirs)
+                    .filter(|dir| dir.is_absolute())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let across_filesystems = env::var("GIT_DISCOVERY_ACROSS_FILESYSTEM").is_ok_and(|value| {
+            matches!(
+                value.to_ascii_lowercase().as_str(),
+                "true" | "yes" | "on" | "1"
+            )
+        });
+        Self {
+            ceiling_dirs,
+            across_filesystems,
+        }
+    }
+}
+
+/// Where the parts of a repository are, overriding what's found by looking
+/// for it. [`Self::from_env`] reads these from the environment like git
+/// does. Relative paths are relative to where the repository is opened.
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    /// The git directory, or a `.git` file pointing at one, like `GIT_DIR`.
+    /// The repository isn't looked for, and unless `core.bare` or
+    /// `core.worktree` say otherwise the workspace is where it's opened.
+    pub git_dir: Option<PathBuf>,
+    /// The workspace, even of a bare repository, like `GIT_WORK_TREE`
+    pub work_tree: Option<PathBuf>,
+    /// Where objects are kept instead of `objects` in the git directory,
+    /// like `GIT_OBJECT_DIRECTORY`
+    pub objects_dir: Option<PathBuf>,
+    /// The index instead of `index` in the git directory, like
+    /// `GIT_INDEX_FILE`
+    pub index_file: Option<PathBuf>,
+    /// How the repository is looked for without [`Self::git_dir`]
+    pub discover: DiscoverOptions,
+}
+
+impl OpenOptions {
+    /// Empty variables are ignored
+    pub fn from_env() -> Self {
+        fn path(name: &str) -> Option<PathBuf> {
+            env::var_os(name)
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+        }
+        Self {
+            git_dir: path("GIT_DIR"),
+            work_tree: path("GIT_WORK_TREE"),
+            objects_dir: path("GIT_OBJECT_DIRECTORY"),
+            index_file: path("GIT_INDEX_FILE"),
+            discover: DiscoverOptions::from_env(),
+        }
+    }
+}
+
+impl Repo {
+    /// Opens the repository git would in the current directory, with
+    /// options from the environment. See [`Self::open_with`].
+    #[instrument(err)]
+    pub fn open() -> Result<Self, DiscoverError> {
+        let dir = env::current_dir().map_err(|e| DiscoverError::Io(PathBuf::from("."), e))?;
+        Self::open_with(dir, &OpenOptions::from_env())
+    }
+
+    /// Opens [`OpenOptions::git_dir`] if it's set, otherwise discovers the
+    /// repository of `start` like [`Self::discover_with`]
+    #[instrument(err)]
+    pub fn open_with(
+        start: impl AsRef<Path> + fmt::Debug,
+        options: &OpenOptions,
+    ) -> Result<Self, DiscoverError> {
+        let start = start.as_ref();
+        let start = start
+            .canonicalize()
+            .map_err(|e| DiscoverError::Io(start.to_owned(), e))?;
+        let absolute = |path: &Option<PathBuf>| path.as_ref().map(|path| start.join(path));
+        let resolved = OpenOptions {
+            git_dir: absolute(&options.git_dir),
+            work_tree: absolute(&options.work_tree),
+            objects_dir: absolute(&options.objects_dir),
+            index_file: absolute(&options.index_file),
+            discover: options.discover.clone(),
+        };
+
+        let (git_dir, default_workspace) = match &resolved.git_dir {
+            Some(git_dir) if git_dir.is_file() => (Self::read_git_file(git_dir)?, Some(start)),
+            Some(git_dir) => {
+                let git_dir = git_dir
+                    .canonicalize()
+                    .map_err(|e| DiscoverError::Io(git_dir.clone(), e))?;
+                if !Self::is_git_dir(&git_dir) {
+                    return Err(ReadError::NotRepo(git_dir).into());
+                }
+                (git_dir, Some(start))
+            }
+            None => Self::find_git_dir(find(&start, &resolved.discover)?)?,
+        };
+        Ok(Self::open_git_dir(git_dir, default_workspace, &resolved)?)
+    }
+
+    /// Like [`Self::discover_with`], with options from the environment
+    #[instrument(err)]
+    pub fn discover(start: impl AsRef<Path> + fmt::Debug) -> Result<Self, DiscoverError> {
+        Self::discover_with(start, &DiscoverOptions::from_env())
+    }
+
+    /// Opens the nearest repository of `start` and its parents: a workspace
+    /// with `.git` in it, or a git directory. See [`Self::new`].
+    #[instrument(err)]
+    pub fn discover_with(
+        start: impl AsRef<Path> + fmt::Debug,
+        options: &DiscoverOptions,
+    ) -> Result<Self, DiscoverError> {
+        let start = start.as_ref();
+        let start = start
+            .canonicalize()
+            .map_err(|e| DiscoverError::Io(start.to_owned(), e))?;
+        Ok(Self::new(find(&start, options)?)?)
+    }
+}
+
+/// The nearest directory of `start` and its parents containing a
+/// repository
+fn find(start: &Path, options: &DiscoverOptions) -> Result<PathBuf, DiscoverError> {
+    // Only the deepest ceiling above where we start matters
+    let ceiling = options
+        .ceiling_dirs
+        .iter()
+        .map(|dir| dir.canonicalize().unwrap_or_else(|_| dir.clone()))
+        .filter(|dir| start.starts_with(dir) && start != *dir)
+        .max_by_key(|dir| dir.components().count());
+    let start_device = device(start)?;
+
+    let mut dir = start;
+    loop {
+        if contains_repo(dir)? {
+            debug!(?dir, "Found repository");
+            return Ok(dir.to_owned());
+        }
+        let parent = match dir.parent() {
+            Some(parent) if Some(parent) != ceiling.as_deref() => parent,
+            _ => return Err(DiscoverError::NotFound(start.to_owned())),
+        };
+        if !options.across_filesystems && device(parent)? != start_device {
+            return Err(DiscoverError::FilesystemBoundary {
+                start: start.to_owned(),
+                boundary: dir.to_owned(),
+            });
+        }
+        dir = parent;
+    }
+}
+
+/// Has a `.git` file or directory, or is a git directory
+fn contains_repo(dir: &Path) -> Result<bool, DiscoverError> {
+    let dot_git = dir.join(".git");
+    let is_dot_git = match fs::metadata(&dot_git) {
+        Ok(meta) => meta.is_file() || Repo::is_git_dir(&dot_git),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+        Err(err) => return Err(DiscoverError::Io(dot_git, err)),
+    };
+    Ok(is_dot_git || Repo::is_git_dir(dir))
+}
+
+/// The filesystem the directory is on
+fn device(dir: &Path) -> Result<u64, DiscoverError> {
+    let meta = fs::metadata(dir).map_err(|e| DiscoverError::Io(dir.to_owned(), e))?;
+    Ok(platform::metadata(&meta).dev)
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum DiscoverError {
+    /// Neither {0:?} nor any of its parents is a git repository
+    NotFound(PathBuf),
+    /// Neither {start:?} nor any of its parents up to {boundary:?} is a git
+    /// repository, and the search stopped at the filesystem boundary there
+    FilesystemBoundary { start: PathBuf, boundary: PathBuf },
+    /// IO error while looking for a git repository in {0:?}
+    Io(PathBuf, #[source] io::Error),
+    /// Failed to open the git repository found
+    Read(#[from] ReadError),
+}
@@ -0,0 +1,360 @@
+//! Writing history as a stream `git fast-import` reads, like `git
+//! fast-export`, to move it to other tools and repositories
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Write},
+};
+
+use bstr::{BStr, BString, ByteSlice};
+use tracing::instrument;
+
+use crate::core::{
+    db::{self, tree::FileNode, Blob, Commit, LoadRawError, TagRef, Tree, UntypedOid},
+    refs,
+    rev_parse::RevRange,
+    revwalk::RevWalkError,
+    stat::Mode,
+    Repo, WsPath,
+};
+
+/// The mark each object was given in a stream, by oid
+pub type Marks = BTreeMap<UntypedOid, usize>;
+
+type Files = BTreeMap<WsPath, FileNode>;
+
+/// A ref to write, with the commit it ends up at
+struct ExportedRef {
+    name: BString,
+    commit: UntypedOid,
+    /// The raw data of the annotated tag the ref points to, if it does
+    tag: Option<Vec<u8>>,
+}
+
+impl Repo {
+    /// Writes the commits in the range to `writer` as a `git fast-import`
+    /// stream, like `git fast-export`, and returns the marks given to the
+    /// blobs and commits written. Parents are written before their
+    /// children, and each blob is written once, before the first commit
+    /// with it.
+    ///
+    /// Each commit is written to the ref under `refs/` it was reached from,
+    /// preferring branches, or to `HEAD` if no ref points to it or a
+    /// descendant. Other refs pointing to the commits written are reset to
+    /// them afterwards, and annotated tags of them are written as tags.
+    ///
+    /// A commit whose first parent isn't written is written with all of its
+    /// files and no parent, like git does without
+    /// `--reference-excluded-parents`.
+    #[instrument(err, skip(writer))]
+    pub fn fast_export(
+        &mut self,
+        range: &RevRange,
+        mut writer: impl Write,
+    ) -> Result<Marks, FastExportError> {
+        let (mut walked, mut parents) = (Vec::new(), BTreeMap::new());
+        for commit in range.walk(&self.db)? {
+            let commit = commit?;
+            walked.push(commit.oid);
+            parents.insert(commit.oid, commit.parents);
+        }
+        let refs = self.exported_refs(&parents)?;
+
+        // Each commit goes to the first ref it's reachable from
+        let mut labels = BTreeMap::<UntypedOid, BString>::new();
+        for exported in &refs {
+            let mut stack = vec![exported.commit];
+            while let Some(oid) = stack.pop() {
+                if labels.contains_key(&oid) {
+                    continue;
+                }
+                labels.insert(oid, exported.name.clone());
+                stack.extend(parents[&oid].iter().filter(|p| parents.contains_key(*p)));
+            }
+        }
+
+        let mut marks = Marks::new();
+        for oid in topo_order(&walked, &parents) {
+            let label = labels.get(&oid).map_or(b"HEAD".as_bstr(), |l| l.as_bstr());
+            self.export_commit(oid, label, &mut marks, &mut writer)?;
+        }
+
+        for exported in refs {
+            let mark = marks[&exported.commit];
+            let out = match exported.tag {
+                Some(tag) => write_tag(&mut writer, exported.name.as_bstr(), mark, &tag),
+                None if labels.get(&exported.commit) == Some(&exported.name) => continue,
+                None => writeln!(writer, "reset {}\nfrom :{mark}\n", exported.name),
+            };
+            out.map_err(FastExportError::Write)?;
+        }
+        writer.flush().map_err(FastExportError::Write)?;
+        Ok(marks)
+    }
+
+    /// The refs under `refs/` pointing to the commits, directly or through
+    /// an annotated tag, with branches first
+    fn exported_refs(
+        &self,
+        commits: &BTreeMap<UntypedOid, Vec<UntypedOid>>,
+    ) -> Result<Vec<ExportedRef>, FastExportError> {
+        let mut refs = Vec::new();
+        for (name, oid) in self.refs.list(b"refs/".as_bstr())? {
+            let oid = oid.into_untyped();
+            let (ty, data) = self
+                .db
+                .load_raw(&self.db.replaced(&oid))?
+                .unwrap_or_default();
+            let exported = match ty.as_bytes() {
+                b"commit" => ExportedRef {
+                    name,
+                    commit: oid,
+                    tag: None,
+                },
+                b"tag" => {
+                    let commit = TagRef::parse(&data)
+                        .map_err(|_| FastExportError::Corrupt(oid))?
+                        .object;
+                    ExportedRef {
+                        name,
+                        commit,
+                        tag: Some(data),
+                    }
+                }
+                _ => continue,
+            };
+            if commits.contains_key(&exported.commit) {
+                refs.push(exported);
+            }
+        }
+        refs.sort_by_key(|exported| !exported.name.starts_with(b"refs/heads/"));
+        Ok(refs)
+    }
+
+    fn export_commit(
+        &mut self,
+        oid: UntypedOid,
+        label: &BStr,
+        marks: &mut Marks,
+        writer: &mut impl Write,
+    ) -> Result<(), FastExportError> {
+        let commit = self.db.load(oid.to_typed::<Commit>())?;
+        let from = commit
+            .parent
+            .and_then(|parent| Some((parent, *marks.get(parent.as_untyped())?)));
+        let old = match from {
+            Some((parent, _)) => {
+                let parent = self.db.load(parent)?;
+                self.db.load_tree_files(&WsPath::root(), parent.tree)?
+            }
+            None => Files::new(),
+        };
+        let new = self.db.load_tree_files(&WsPath::root(), commit.tree)?;
+
+        let mut changes = Vec::new();
+        for (path, node) in &new {
+            let unchanged = old
+                .get(path)
+                .is_some_and(|old| old.oid == node.oid && old.mode == node.mode);
+            if unchanged {
+                continue;
+            }
+            let blob = node.oid.into_untyped();
+            let data = if node.mode == Mode::Gitlink {
+                blob.to_hex()
+            } else {
+                if !marks.contains_key(&blob) {
+                    let mark = marks.len() + 1;
+                    marks.insert(blob, mark);
+                    let blob = self.db.load(node.oid)?;
+                    write_blob(writer, mark, &blob).map_err(FastExportError::Write)?;
+                }
+                format!(":{}", marks[&blob])
+            };
+            let mode = node.mode.as_base8();
+            changes.push(format!("M {mode} {data} {}\n", quote_path(path)));
+        }
+        for path in old.keys().filter(|path| !new.contains_key(*path)) {
+            changes.push(format!("D {}\n", quote_path(path)));
+        }
+
+        let mark = marks.len() + 1;
+        marks.insert(oid, mark);
+        let merged = commit
+            .merged
+            .iter()
+            .filter_map(|merged| marks.get(merged.as_untyped()).copied())
+            .collect::<Vec<_>>();
+        let from = from.map(|(_, parent)| parent);
+        write_commit(writer, label, mark, &commit, from, &merged, &changes)
+            .map_err(FastExportError::Write)
+    }
+}
+
+/// The walked commits with their parents before them, otherwise oldest first
+/// as they were walked
+fn topo_order(
+    walked: &[UntypedOid],
+    parents: &BTreeMap<UntypedOid, Vec<UntypedOid>>,
+) -> Vec<UntypedOid> {
+    let mut order = Vec::with_capacity(walked.len());
+    let mut done = BTreeSet::new();
+    for &tip in walked.iter().rev() {
+        // Each commit is pushed once to expand it, then again to emit it
+        let mut stack = vec![(tip, false)];
+        while let Some((oid, expanded)) = stack.pop() {
+            if done.contains(&oid) {
+                continue;
+            }
+            if expanded {
+                done.insert(oid);
+                order.push(oid);
+                continue;
+            }
+            stack.push((oid, true));
+            for parent in parents[&oid].iter().rev() {
+                if parents.contains_key(parent) && !done.contains(parent) {
+                    stack.push((*parent, false));
+                }
+            }
+        }
+    }
+    order
+}
+
+fn write_commit(
+    writer: &mut impl Write,
+    label: &BStr,
+    mark: usize,
+    commit: &Commit,
+    from: Option<usize>,
+    merged: &[usize],
+    changes: &[String],
+) -> io::Result<()> {
+    if from.is_none() {
+        writeln!(writer, "reset {label}")?;
+    }
+    writeln!(writer, "commit {label}\nmark :{mark}")?;
+    writer.write_all(b"author ")?;
+    writer.write_all(&commit.author.serialize())?;
+    writer.write_all(b"\ncommitter ")?;
+    writer.write_all(&commit.committer.serialize())?;
+    writeln!(writer, "\ndata {}", commit.msg.len())?;
+    writer.write_all(&commit.msg)?;
+    if let Some(parent) = from {
+        writeln!(writer, "from :{parent}")?;
+    }
+    for merged in merged {
+        writeln!(writer, "merge :{merged}")?;
+    }
+    for change in changes {
+        writer.write_all(change.as_bytes())?;
+    }
+    writer.write_all(b"\n")
+}
+
+fn write_blob(writer: &mut impl Write, mark: usize, blob: &Blob) -> io::Result<()> {
+    writeln!(writer, "blob\nmark :{mark}\ndata {}", blob.bytes.len())?;
+    writer.write_all(&blob.bytes)?;
+    writer.write_all(b"\n")
+}
+
+/// Writes an annotated tag from its raw data. The tag is named by the ref,
+/// as git does, rather than by its `tag` header.
+fn write_tag(writer: &mut impl Write, ref_name: &BStr, mark: usize, data: &[u8]) -> io::Result<()> {
+    let name = ref_name
+        .strip_prefix(b"refs/tags/")
+        .unwrap_or(ref_name.as_bytes());
+    let (headers, msg) = match data.find(b"\n\n") {
+        Some(end) => (&data[..end], &data[end + 2..]),
+        None => (data, &[][..]),
+    };
+    writeln!(writer, "tag {}\nfrom :{mark}", name.as_bstr())?;
+    if let Some(tagger) = headers
+        .lines()
+        .find_map(|line| line.strip_prefix(b"tagger "))
+    {
+        writer.write_all(b"tagger ")?;
+        writer.write_all(tagger)?;
+        writer.write_all(b"\n")?;
+    }
+    writeln!(writer, "data {}", msg.len())?;
+    writer.write_all(msg)?;
+    writer.write_all(b"\n")
+}
+
+/// Paths are written as they are, unless they start with a quote or have a
+/// newline, which fast-import needs quoted like C strings
+fn quote_path(path: &WsPath) -> String {
+    let path = path.to_string();
+    if !path.starts_with('"') && !path.contains('\n') {
+        return path;
+    }
+    let mut quoted = String::from("\"");
+    for c in path.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum FastExportError {
+    /// Failed to walk commits
+    RevWalk(#[from] RevWalkError),
+    /// Failed to read refs
+    ReadRef(#[from] refs::ReadError),
+    /// Failed to load object
+    LoadRaw(#[from] LoadRawError),
+    /// Failed to load commit
+    LoadCommit(#[from] db::LoadError<Commit>),
+    /// Failed to load tree
+    LoadTree(#[from] db::LoadError<Tree>),
+    /// Failed to load blob
+    LoadBlob(#[from] db::LoadError<Blob>),
+    /// Tag {0} is corrupt
+    Corrupt(UntypedOid),
+    /// Failed to write stream
+    Write(#[source] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn quotes_paths_fast_import_cannot_take() {
+        let path = |path| WsPath::new_unchecked(path);
+        assert_eq!(
+            "dir/with space.txt",
+            quote_path(&path("dir/with space.txt"))
+        );
+        assert_eq!(r#""\"quoted\".txt""#, quote_path(&path("\"quoted\".txt")));
+        assert_eq!(r#""a\nb""#, quote_path(&path("a\nb")));
+    }
+
+    #[test]
+    fn orders_parents_first() {
+        let oid = |n: u8| UntypedOid::parse(format!("{n:040}")).unwrap();
+        // 1 <- 2 <- 4, 1 <- 3 <- 4 with 4 a merge, and 3's parent 0 left out.
+        // Walked as if 4 were oldest, which it can be with clock skew.
+        let walked = [oid(1), oid(3), oid(2), oid(4)];
+        let parents = BTreeMap::from([
+            (oid(4), vec![oid(2), oid(3)]),
+            (oid(3), vec![oid(1), oid(0)]),
+            (oid(2), vec![oid(1)]),
+            (oid(1), vec![]),
+        ]);
+        let order = topo_order(&walked, &parents);
+        assert_eq!(4, order.len());
+        let at = |n| order.iter().position(|&o| o == oid(n)).unwrap();
+        assert!(at(1) < at(2) && at(1) < at(3));
+        assert!(at(2) < at(4) && at(3) < at(4));
+    }
+}
@@ -0,0 +1,617 @@
+//! Reading history from a stream like `git fast-import`, to bring it in from
+//! other tools and version control systems
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead},
+};
+
+use bstr::{BStr, BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use crate::core::{
+    db::{
+        self, blob, commit, signature, tree::EntryBuilder, Blob, Commit, Signature, StoreRawError,
+        Tree, UntypedOid,
+    },
+    fast_export::Marks,
+    refs,
+    rev_parse::RevParseError,
+    revwalk::{self, RevWalkError},
+    stat::Mode,
+    ObjectBuilder, Oid, Repo, WsPath,
+};
+
+type Files = BTreeMap<WsPath, (Mode, Oid<Blob>)>;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FastImportOptions {
+    /// Update refs even if they don't fast-forward, like `--force`
+    pub force: bool,
+}
+
+/// Where a ref is up to as the stream is read
+#[derive(Debug)]
+struct Branch {
+    tip: Option<Oid<Commit>>,
+    /// The files of the tip, once they're needed
+    files: Option<Files>,
+}
+
+/// The lines of a stream, with one line of lookahead
+struct Stream<R> {
+    reader: R,
+    peeked: Option<Vec<u8>>,
+    line: usize,
+}
+
+impl Repo {
+    /// Reads a `git fast-import` stream, storing the objects it describes
+    /// and updating the refs it names once it's read, and returns the marks
+    /// it gave objects. The commands are
+    ///
+    /// - `blob`, `commit` and `tag`, with the marks, `data` (counted or
+    ///   delimited) and headers git takes, and `M`, `D` and `deleteall` for
+    ///   the files of commits
+    /// - `reset`, to start a ref again or point it elsewhere
+    /// - `checkpoint`, `progress`, `feature`, `option` and `done`, which
+    ///   are accepted and otherwise ignored
+    ///
+    /// A commit without `from` continues its ref, like git. Refs that
+    /// wouldn't fast-forward aren't updated unless forced, and nothing is if
+    /// any is refused.
+    #[instrument(err, skip(reader))]
+    pub fn fast_import(
+        &mut self,
+        reader: impl BufRead,
+        options: FastImportOptions,
+    ) -> Result<Marks, FastImportError> {
+        let mut stream = Stream {
+            reader,
+            peeked: None,
+            line: 0,
+        };
+        let mut marks = BTreeMap::<usize, UntypedOid>::new();
+        let mut branches = BTreeMap::<BString, Branch>::new();
+        let mut tags = BTreeMap::<BString, UntypedOid>::new();
+
+        while let Some(line) = stream.next_line()? {
+            let (command, arg) = match line.find_byte(b' ') {
+                Some(space) => (&line[..space], line[space + 1..].as_bstr()),
+                None => (&line[..], b"".as_bstr()),
+            };
+            match command {
+                b"blob" => {
+                    let mark = stream.mark()?;
+                    stream.optional(b"original-oid ")?;
+                    let data = stream.data()?;
+                    let oid = blob::Builder::new(data).store(&self.db)?;
+                    if let Some(mark) = mark {
+                        marks.insert(mark, oid.into_untyped());
+                    }
+                }
+                b"commit" => {
+                    let (mark, oid) =
+                        self.import_commit(&mut stream, arg, &marks, &mut branches)?;
+                    if let Some(mark) = mark {
+                        marks.insert(mark, oid.into_untyped());
+                    }
+                }
+                b"tag" => {
+                    let (mark, oid) = self.import_tag(&mut stream, arg, &marks, &branches)?;
+                    if let Some(mark) = mark {
+                        marks.insert(mark, oid);
+                    }
+                    tags.insert(format!("refs/tags/{arg}").into(), oid);
+                }
+                b"reset" => {
+                    let tip = match stream.optional(b"from ")? {
+                        Some(from) => {
+                            let from = self.commit_ish(&stream, &from, &marks, &branches)?;
+                            Some(from.to_typed())
+                        }
+                        None => None,
+                    };
+                    branches.insert(arg.to_owned(), Branch { tip, files: None });
+                    stream.optional_blank()?;
+                }
+                b"done" => break,
+                b"checkpoint" | b"progress" | b"feature" | b"option" => {
+                    debug!(command = %line.as_bstr(), "Ignoring");
+                }
+                _ => return Err(stream.invalid("a command")),
+            }
+        }
+
+        let mut updates = Vec::new();
+        for (name, branch) in branches {
+            let Some(new) = branch.tip else {
+                continue;
+            };
+            let new = new.into_untyped();
+            let old = self.refs.read_ref(name.as_bstr())?.map(Oid::into_untyped);
+            if let Some(old) = old.filter(|&old| !options.force && old != new) {
+                if !revwalk::is_ancestor(&self.db, old, new)? {
+                    return Err(FastImportError::NotFastForward(name));
+                }
+            }
+            updates.push((name, new));
+        }
+        // Like git, tags are replaced whatever they pointed to
+        updates.extend(tags);
+        for (name, oid) in updates {
+            self.refs.update_ref(name.as_bstr(), &oid.to_typed())?;
+        }
+
+        Ok(marks.into_iter().map(|(mark, oid)| (oid, mark)).collect())
+    }
+
+    fn import_commit(
+        &mut self,
+        stream: &mut Stream<impl BufRead>,
+        ref_name: &BStr,
+        marks: &BTreeMap<usize, UntypedOid>,
+        branches: &mut BTreeMap<BString, Branch>,
+    ) -> Result<(Option<usize>, Oid<Commit>), FastImportError> {
+        let mark = stream.mark()?;
+        stream.optional(b"original-oid ")?;
+        let author = stream.optional(b"author ")?;
+        let committer = stream
+            .optional(b"committer ")?
+            .ok_or_else(|| stream.invalid("committer"))?;
+        if stream.optional(b"encoding ")?.is_some() {
+            return Err(FastImportError::Unsupported("encoding".to_owned()));
+        }
+        let msg = stream.data()?;
+
+        let (tip, files) = match branches.get_mut(ref_name) {
+            Some(branch) => (branch.tip, branch.files.take()),
+            None => (self.refs.read_ref(ref_name)?, None),
+        };
+        let (parent, files) = match stream.optional(b"from ")? {
+            Some(from) => {
+                let from = self.commit_ish(stream, &from, marks, branches)?;
+                (Some(from.to_typed()), None)
+            }
+            None => (tip, files),
+        };
+        let mut merged = Vec::new();
+        while let Some(merge) = stream.optional(b"merge ")? {
+            merged.push(self.commit_ish(stream, &merge, marks, branches)?.to_typed());
+        }
+
+        let mut files = match (files, parent) {
+            (Some(files), _) => files,
+            (None, Some(parent)) => {
+                let parent = self.db.load(parent)?;
+                self.branch_files(parent.tree)?
+            }
+            (None, None) => Files::new(),
+        };
+        while let Some(line) = stream.peek_line()? {
+            if line.is_empty() {
+                stream.next_line()?;
+                break;
+            }
+            let change = line.to_vec();
+            if change == b"deleteall" {
+                stream.next_line()?;
+                files.clear();
+                continue;
+            }
+            let Some(space) = change.find_byte(b' ') else {
+                break;
+            };
+            let (op, rest) = (&change[..space], &change[space + 1..]);
+            match op {
+                b"M" => {
+                    stream.next_line()?;
+                    let (mode, oid, path) = self.parse_modify(stream, rest, marks)?;
+                    // A file replaces a directory of the same name, and the
+                    // other way around
+                    files.retain(|existing, _| {
+                        !existing.as_path().starts_with(path.as_path())
+                            && !path.as_path().starts_with(existing.as_path())
+                    });
+                    files.insert(path, (mode, oid));
+                }
+                b"D" => {
+                    stream.next_line()?;
+                    let path = stream.path(rest)?;
+                    files.retain(|existing, _| !existing.as_path().starts_with(path.as_path()));
+                }
+                b"C" | b"R" | b"N" | b"ls" => {
+                    return Err(FastImportError::Unsupported(op.as_bstr().to_string()));
+                }
+                _ => break,
+            }
+        }
+
+        let tree = db::tree::Builder::new()
+            .entries(files.iter().map(|(path, &(mode, oid))| EntryBuilder {
+                oid,
+                path: path.clone(),
+                mode,
+            }))
+            .store(&self.db)?;
+        let committer = stream.signature(&committer)?;
+        let author = match author {
+            Some(author) => stream.signature(&author)?,
+            None => committer.clone(),
+        };
+        let oid = commit::Builder {
+            merged,
+            ..commit::Builder::new(parent, tree, author, committer, msg)
+        }
+        .store(&self.db)?;
+
+        branches.insert(
+            ref_name.to_owned(),
+            Branch {
+                tip: Some(oid),
+                files: Some(files),
+            },
+        );
+        Ok((mark, oid))
+    }
+
+    fn import_tag(
+        &mut self,
+        stream: &mut Stream<impl BufRead>,
+        name: &BStr,
+        marks: &BTreeMap<usize, UntypedOid>,
+        branches: &BTreeMap<BString, Branch>,
+    ) -> Result<(Option<usize>, UntypedOid), FastImportError> {
+        let mark = stream.mark()?;
+        let from = stream
+            .optional(b"from ")?
+            .ok_or_else(|| stream.invalid("from"))?;
+        let object = self.commit_ish(stream, &from, marks, branches)?;
+        stream.optional(b"original-oid ")?;
+        let tagger = stream.optional(b"tagger ")?;
+        let msg = stream.data()?;
+
+        let (ty, _) = self
+            .db
+            .load_raw(&object)?
+            .ok_or_else(|| stream.invalid("an object that exists"))?;
+        let mut data = BString::from(format!("object {object}\ntype {ty}\ntag {name}\n"));
+        if let Some(tagger) = tagger {
+            data.extend_from_slice(b"tagger ");
+            data.extend_from_slice(&stream.signature(&tagger)?.serialize());
+            data.push(b'\n');
+        }
+        data.push(b'\n');
+        data.extend_from_slice(&msg);
+        Ok((mark, self.db.store_raw(b"tag", &data)?))
+    }
+
+    /// A file change like `<mode> <dataref> <path>`, where the data is a
+    /// mark, an oid, or `inline` with the data following
+    fn parse_modify(
+        &mut self,
+        stream: &mut Stream<impl BufRead>,
+        change: &[u8],
+        marks: &BTreeMap<usize, UntypedOid>,
+    ) -> Result<(Mode, Oid<Blob>, WsPath), FastImportError> {
+        let mut parts = change.splitn_str(3, " ");
+        let (Some(mode), Some(data), Some(path)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(stream.invalid("a mode, data and path"));
+        };
+        let mode = match mode {
+            b"644" | b"100644" => Mode::Regular,
+            b"755" | b"100755" => Mode::Executable,
+            b"120000" => Mode::Symlink,
+            b"160000" => Mode::Gitlink,
+            b"040000" => return Err(FastImportError::Unsupported("trees".to_owned())),
+            _ => return Err(stream.invalid("a file mode")),
+        };
+        let path = stream.path(path)?;
+        let oid = if data == b"inline" {
+            let data = stream.data()?;
+            blob::Builder::new(data).store(&self.db)?
+        } else {
+            stream.data_ref(data, marks)?.to_typed()
+        };
+        Ok((mode, oid, path))
+    }
+
+    /// A mark, an oid, a ref the stream has written, or a revision of the
+    /// repository
+    fn commit_ish(
+        &self,
+        stream: &Stream<impl BufRead>,
+        commit_ish: &[u8],
+        marks: &BTreeMap<usize, UntypedOid>,
+        branches: &BTreeMap<BString, Branch>,
+    ) -> Result<UntypedOid, FastImportError> {
+        if commit_ish.starts_with(b":") || commit_ish.len() == 40 {
+            if let Ok(oid) = stream.data_ref(commit_ish, marks) {
+                return Ok(oid);
+            }
+        }
+        if let Some(branch) = branches.get(commit_ish.as_bstr()) {
+            return branch
+                .tip
+                .map(Oid::into_untyped)
+                .ok_or_else(|| stream.invalid("a ref with commits"));
+        }
+        let rev = commit_ish
+            .to_str()
+            .map_err(|_| stream.invalid("a commit"))?;
+        Ok(self.rev_parse(rev)?)
+    }
+
+    fn branch_files(&mut self, tree: Oid<Tree>) -> Result<Files, FastImportError> {
+        Ok(self
+            .db
+            .load_tree_files(&WsPath::root(), tree)?
+            .into_iter()
+            .map(|(path, node)| (path, (node.mode, node.oid)))
+            .collect())
+    }
+}
+
+impl<R: BufRead> Stream<R> {
+    /// The next line without its newline, skipping comments
+    fn next_line(&mut self) -> Result<Option<Vec<u8>>, FastImportError> {
+        if let Some(line) = self.peeked.take() {
+            return Ok(Some(line));
+        }
+        loop {
+            let mut line = Vec::new();
+            if self
+                .reader
+                .read_until(b'\n', &mut line)
+                .map_err(FastImportError::Read)?
+                == 0
+            {
+                return Ok(None);
+            }
+            self.line += 1;
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            if !line.starts_with(b"#") {
+                return Ok(Some(line));
+            }
+        }
+    }
+
+    fn peek_line(&mut self) -> Result<Option<&[u8]>, FastImportError> {
+        if self.peeked.is_none() {
+            self.peeked = self.next_line()?;
+        }
+        Ok(self.peeked.as_deref())
+    }
+
+    /// What follows `prefix` on the next line if it starts with it
+    fn optional(&mut self, prefix: &[u8]) -> Result<Option<Vec<u8>>, FastImportError> {
+        match self.peek_line()? {
+            Some(line) if line.starts_with(prefix) => {
+                let line = self.next_line()?.expect("Peeked");
+                Ok(Some(line[prefix.len()..].to_vec()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn optional_blank(&mut self) -> Result<(), FastImportError> {
+        if self.peek_line()?.is_some_and(<[u8]>::is_empty) {
+            self.next_line()?;
+        }
+        Ok(())
+    }
+
+    fn mark(&mut self) -> Result<Option<usize>, FastImportError> {
+        match self.optional(b"mark :")? {
+            Some(mark) => Ok(Some(
+                parse_mark(&mark).ok_or_else(|| self.invalid("a mark"))?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Data counted like `data <bytes>` or delimited like `data <<EOF`,
+    /// with an optional newline after
+    fn data(&mut self) -> Result<Vec<u8>, FastImportError> {
+        let header = self
+            .optional(b"data ")?
+            .ok_or_else(|| self.invalid("data"))?;
+
+        if let Some(delimiter) = header.strip_prefix(b"<<") {
+            let mut data = Vec::new();
+            loop {
+                let line = self
+                    .raw_line()?
+                    .ok_or_else(|| self.invalid("a delimiter"))?;
+                if line == delimiter {
+                    break;
+                }
+                data.extend_from_slice(&line);
+                data.push(b'\n');
+            }
+            return Ok(data);
+        }
+
+        let len = header
+            .to_str()
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| self.invalid("a length"))?;
+        let mut data = vec![0; len];
+        self.reader
+            .read_exact(&mut data)
+            .map_err(FastImportError::Read)?;
+        self.line += data.find_iter(b"\n").count();
+        let next = self.reader.fill_buf().map_err(FastImportError::Read)?;
+        if next.first() == Some(&b'\n') {
+            self.reader.consume(1);
+            self.line += 1;
+        }
+        Ok(data)
+    }
+
+    /// The next line, even if it looks like a comment
+    fn raw_line(&mut self) -> Result<Option<Vec<u8>>, FastImportError> {
+        let mut line = Vec::new();
+        if self
+            .reader
+            .read_until(b'\n', &mut line)
+            .map_err(FastImportError::Read)?
+            == 0
+        {
+            return Ok(None);
+        }
+        self.line += 1;
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+
+    /// A path as it is, or quoted like a C string
+    fn path(&self, path: &[u8]) -> Result<WsPath, FastImportError> {
+        let path = match path.strip_prefix(b"\"") {
+            Some(quoted) => unquote(quoted).ok_or_else(|| self.invalid("a quoted path"))?,
+            None => path.to_vec(),
+        };
+        let path = path.to_path().map_err(|_| self.invalid("a path"))?;
+        WsPath::new_normalized(path).map_err(|_| self.invalid("a path"))
+    }
+
+    /// A mark like `:1` or an oid
+    fn data_ref(
+        &self,
+        data: &[u8],
+        marks: &BTreeMap<usize, UntypedOid>,
+    ) -> Result<UntypedOid, FastImportError> {
+        match data.strip_prefix(b":") {
+            Some(mark) => {
+                let mark = parse_mark(mark).ok_or_else(|| self.invalid("a mark"))?;
+                marks
+                    .get(&mark)
+                    .copied()
+                    .ok_or(FastImportError::UnknownMark(mark))
+            }
+            None => UntypedOid::parse(data).map_err(|_| self.invalid("a mark or oid")),
+        }
+    }
+
+    fn signature(&self, signature: &[u8]) -> Result<Signature, FastImportError> {
+        Signature::parse(signature.as_bstr()).map_err(|e| FastImportError::Signature(self.line, e))
+    }
+
+    fn invalid(&self, expected: &'static str) -> FastImportError {
+        FastImportError::Invalid {
+            line: self.line,
+            expected,
+        }
+    }
+}
+
+fn parse_mark(mark: &[u8]) -> Option<usize> {
+    mark.to_str().ok()?.parse().ok().filter(|&mark| mark > 0)
+}
+
+/// The contents of a C-style quoted string after its opening quote, which
+/// has to end with a quote
+fn unquote(quoted: &[u8]) -> Option<Vec<u8>> {
+    let mut unquoted = Vec::new();
+    let mut bytes = quoted.iter().copied();
+    loop {
+        match bytes.next()? {
+            b'"' => return bytes.next().is_none().then_some(unquoted),
+            b'\\' => {
+                let escaped = match bytes.next()? {
+                    b'a' => 0x07,
+                    b'b' => 0x08,
+                    b'f' => 0x0c,
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'v' => 0x0b,
+                    digit @ b'0'..=b'3' => {
+                        let (a, b) = (bytes.next()?, bytes.next()?);
+                        if !(b'0'..=b'7').contains(&a) || !(b'0'..=b'7').contains(&b) {
+                            return None;
+                        }
+                        (digit - b'0') * 64 + (a - b'0') * 8 + (b - b'0')
+                    }
+                    other => other,
+                };
+                unquoted.push(escaped);
+            }
+            b => unquoted.push(b),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum FastImportError {
+    /// Failed to read stream
+    Read(#[source] io::Error),
+    /// Invalid stream at line {line}, expected {expected}
+    Invalid { line: usize, expected: &'static str },
+    /// Invalid signature at line {0}
+    Signature(usize, #[source] signature::ParseError),
+    /// Unknown mark :{0}
+    UnknownMark(usize),
+    /// Importing {0} isn't supported
+    Unsupported(String),
+    /// Ref {0} would not fast-forward
+    NotFastForward(BString),
+    /// Failed to resolve commit
+    RevParse(#[from] RevParseError),
+    /// Failed to check ancestry
+    RevWalk(#[from] RevWalkError),
+    /// Failed to load commit
+    LoadCommit(#[from] db::LoadError<Commit>),
+    /// Failed to load tree
+    LoadTree(#[from] db::LoadError<Tree>),
+    /// Failed to load object
+    LoadRaw(#[from] db::LoadRawError),
+    /// {0}
+    StoreBlob(#[from] db::StoreError<Blob>),
+    /// {0}
+    StoreTree(#[from] db::StoreError<Tree>),
+    /// {0}
+    StoreCommit(#[from] db::StoreError<Commit>),
+    /// {0}
+    StoreRaw(#[from] StoreRawError),
+    /// Failed to read ref
+    ReadRef(#[from] refs::ReadError),
+    /// Failed to update ref
+    UpdateRef(#[from] refs::UpdateError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn unquotes_c_strings() {
+        assert_eq!(Some(b"a b".to_vec()), unquote(b"a b\""));
+        assert_eq!(Some(b"\"a\"\n\\".to_vec()), unquote(br#"\"a\"\n\\""#));
+        assert_eq!(Some("é".as_bytes().to_vec()), unquote(br#"\303\251""#));
+        assert_eq!(None, unquote(b"unterminated"));
+        assert_eq!(None, unquote(b"trailing\" text"));
+    }
+
+    #[test]
+    fn reads_counted_and_delimited_data() -> eyre::Result<()> {
+        let input = b"data 4\nab\nc\ndata <<EOF\nline\n# not a comment\nEOF\n# comment\nnext\n";
+        let mut stream = Stream {
+            reader: &input[..],
+            peeked: None,
+            line: 0,
+        };
+        assert_eq!(b"ab\nc".to_vec(), stream.data()?);
+        assert_eq!(b"line\n# not a comment\n".to_vec(), stream.data()?);
+        assert_eq!(Some(b"next".to_vec()), stream.next_line()?);
+        assert_eq!(9, stream.line);
+        Ok(())
+    }
+}
@@ -0,0 +1,526 @@
+//! Fetching from a remote repository over the network
+
+use std::collections::BTreeSet;
+
+use bstr::{BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use crate::core::{
+    cancel::{CancelToken, Cancelled},
+    config,
+    db::{Commit, ShallowError, UntypedOid},
+    negotiate::{negotiate, NegotiateError},
+    pack::{self, UnpackError},
+    progress::Progress,
+    refs,
+    refspec::{self, Refspec},
+    revwalk::{self, RevWalkError},
+    transport::{
+        self,
+        upload_pack::{Depth, Filter, UploadPackError},
+        RemoteRef, Service, TransportError, UploadPack,
+    },
+    Oid, Repo,
+};
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FetchOptions {
+    /// Fetch only this much history, leaving the oldest commits fetched
+    /// shallow (recorded in `.git/shallow`)
+    pub depth: Option<Depth>,
+    /// Leave out these objects, making the remote a promisor remote we fetch
+    /// them from when they're needed. Later fetches from the remote use the
+    /// same filter.
+    pub filter: Option<Filter>,
+    /// Delete the refs we store the remote's refs in when the remote no
+    /// longer has them. If `None`, `remote.<name>.prune` or `fetch.prune`
+    /// decides.
+    pub prune: Option<bool>,
+    /// Which tags to fetch besides those the refspecs map. If `None`,
+    /// `remote.<name>.tagOpt` decides, following tags by default.
+    pub tags: Option<Tags>,
+    /// Checked before each step of the fetch. Refs are only updated once
+    /// everything is fetched, so a cancelled fetch leaves them as they were.
+    pub cancel: CancelToken,
+}
+
+/// Which tags a fetch from a configured remote stores in `refs/tags/`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Tags {
+    /// The tags pointing into the history we fetch, or already have
+    Follow,
+    /// Every tag, like `--tags`
+    All,
+    /// Only those the refspecs map, like `--no-tags`
+    Ignore,
+}
+
+/// What [`Repo::fetch`] did
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Fetched {
+    /// Everything the remote listed
+    pub refs: Vec<RemoteRef>,
+    /// The local refs that changed, with their old values
+    pub updated: Vec<(BString, Option<Oid<Commit>>, Oid<Commit>)>,
+    /// The local refs left alone, as updating them would lose commits (or
+    /// move a tag) and their refspec isn't forced
+    pub rejected: Vec<BString>,
+    /// The local refs deleted as the remote no longer has them, with their
+    /// old values
+    pub pruned: Vec<(BString, Oid<Commit>)>,
+}
+
+impl Repo {
+    /// Like `git fetch <remote>`, where `remote` is either the name of a
+    /// remote configured with `remote.<name>.url` or a url. The refs of a
+    /// named remote are stored by its `remote.<name>.fetch` refspecs, or else
+    /// as remote-tracking branches (like `refs/remotes/origin/main`), while
+    /// for a url only the objects of its branches are fetched.
+    pub fn fetch(&mut self, remote: &str) -> Result<Fetched, FetchError> {
+        self.fetch_with(remote, &FetchOptions::default(), &mut ())
+    }
+
+    /// If we're shallow, the remote is told which commits we don't have the
+    /// parents of, and in a shallow fetch we ask for every ref (even the ones
+    /// we have) so that the history of each is cut to the depth.
+    ///
+    /// `progress` hears how the pack is received and stored, and what the
+    /// remote says about making it.
+    #[instrument(err, skip(progress))]
+    pub fn fetch_with(
+        &mut self,
+        remote: &str,
+        options: &FetchOptions,
+        progress: &mut dyn Progress,
+    ) -> Result<Fetched, FetchError> {
+        let (name, url) = self.remote_url(remote);
+        let (mut refspecs, tags) = match name {
+            Some(name) => (self.fetch_refspecs(name)?, self.tags(name, options)?),
+            None => (
+                vec![Refspec::parse("refs/heads/*").expect("Valid")],
+                Tags::Ignore,
+            ),
+        };
+        if tags == Tags::All {
+            refspecs.push(Refspec::parse("refs/tags/*:refs/tags/*").expect("Valid"));
+        }
+        options.cancel.check()?;
+        let transport = transport::connect(url, Service::UploadPack, &self.config)?;
+        let (name, url) = (name.map(str::to_owned), url.to_owned());
+        let mut upload_pack = UploadPack::connect(transport)?;
+        // HEAD is listed for its target
+        let mut prefixes = vec![String::from("HEAD")];
+        prefixes.extend(
+            refspecs
+                .iter()
+                .filter(|refspec| !refspec.negative)
+                .map(|refspec| refspec.prefix().to_str_lossy().into_owned()),
+        );
+        if tags == Tags::Follow {
+            prefixes.push(String::from("refs/tags/"));
+        }
+        let prefixes = prefixes.iter().map(String::as_str).collect::<Vec<_>>();
+        let refs = upload_pack.ls_refs(&prefixes)?;
+        upload_pack.set_shallow(self.db.shallow().iter().copied().collect(), options.depth)?;
+        let filter = match (options.filter, &name) {
+            (Some(filter), Some(name)) => {
+                self.record_promisor(name, filter)?;
+                Some(filter)
+            }
+            (Some(_), None) => return Err(FetchError::FilterWithoutRemote),
+            (None, Some(name)) => self.promisor_filter(name)?,
+            (None, None) => None,
+        };
+        upload_pack.set_filter(filter)?;
+        upload_pack.set_include_tag(tags == Tags::Follow);
+
+        // For a url everything listed is fetched
+        let wanted = |remote_ref: &&RemoteRef| {
+            let name = remote_ref.name.as_bstr();
+            let matched = refspecs
+                .iter()
+                .any(|refspec| !refspec.negative && refspec.matches(name));
+            remote_ref.name == "HEAD" || matched && !refspec::excluded(&refspecs, name)
+        };
+        let new_tags = match tags {
+            Tags::Follow => self.new_tags(&refs, &refspecs)?,
+            Tags::All | Tags::Ignore => Vec::new(),
+        };
+        let followed = |remote_ref: &&RemoteRef| {
+            new_tags.contains(remote_ref) && self.db.contains(&peeled(remote_ref))
+        };
+        let wants = refs
+            .iter()
+            .filter(|remote_ref| name.is_none() || wanted(remote_ref) || followed(remote_ref))
+            .map(|remote_ref| remote_ref.oid)
+            .filter(|oid| options.depth.is_some() || !self.db.contains(oid))
+            .collect::<BTreeSet<_>>();
+        if !wants.is_empty() {
+            // What the remote has that we have too
+            let common = refs
+                .iter()
+                .map(|remote_ref| remote_ref.oid)
+                .filter(|oid| self.db.contains(oid));
+            let wants = wants.into_iter().collect::<Vec<_>>();
+            let tips = self.local_tips()?;
+            options.cancel.check()?;
+            let negotiated =
+                negotiate(&self.db, &mut upload_pack, &wants, &tips, common, progress)?;
+            options.cancel.check()?;
+            let stored = pack::unpack(&self.db, &negotiated.pack, progress)?;
+            debug!(stored = stored.len());
+
+            let mut shallow = self.db.shallow().clone();
+            shallow.extend(negotiated.shallow);
+            for oid in &negotiated.unshallow {
+                shallow.remove(oid);
+            }
+            if &shallow != self.db.shallow() {
+                debug!(shallow = shallow.len(), "Updating shallow commits");
+                self.db.set_shallow(shallow)?;
+            }
+        }
+
+        options.cancel.check()?;
+        let mut updated = Vec::new();
+        let mut rejected = Vec::new();
+        if name.is_some() {
+            let mapped = refs.iter().flat_map(|remote_ref| {
+                refspec::map(&refspecs, remote_ref.name.as_bstr())
+                    .into_iter()
+                    .map(move |(local, force)| (remote_ref, local, force))
+            });
+            for (remote_ref, local, force) in mapped {
+                let new = remote_ref.oid.to_typed();
+                let old = self.refs.read_ref(local.as_bstr())?;
+                match old {
+                    Some(old) if old == new => {}
+                    // Tags aren't expected to move, so only forcing moves them
+                    Some(_) if !force && local.starts_with(b"refs/tags/") => {
+                        rejected.push(local);
+                    }
+                    Some(old)
+                        if !force
+                            && !revwalk::is_ancestor(
+                                &self.db,
+                                old.into_untyped(),
+                                remote_ref.oid,
+                            )? =>
+                    {
+                        rejected.push(local);
+                    }
+                    _ => {
+                        self.refs.update_ref(local.as_bstr(), &new)?;
+                        updated.push((local, old, new));
+                    }
+                }
+            }
+        }
+
+        let mut fetched = refs
+            .iter()
+            .filter(|remote_ref| name.is_none() || remote_ref.name != "HEAD" && wanted(remote_ref))
+            .collect::<Vec<_>>();
+        // The tags pointing into what we fetched came with it
+        for remote_ref in new_tags {
+            if self.db.contains(&remote_ref.oid) && self.db.contains(&peeled(remote_ref)) {
+                debug!(tag = ?remote_ref.name, "Following");
+                let new = remote_ref.oid.to_typed();
+                self.refs.update_ref(remote_ref.name.as_bstr(), &new)?;
+                updated.push((remote_ref.name.clone(), None, new));
+                fetched.push(remote_ref);
+            }
+        }
+        let fetch_head = self.fetch_head(name.as_deref(), &url, &refspecs, &fetched)?;
+        self.refs.update_fetch_head(&fetch_head)?;
+
+        let pruned = match &name {
+            Some(name) if self.prunes(name, options)? => self.prune(&refspecs, &refs)?,
+            _ => Vec::new(),
+        };
+
+        Ok(Fetched {
+            refs,
+            updated,
+            rejected,
+            pruned,
+        })
+    }
+
+    /// The lines of `FETCH_HEAD`, those to merge first. From a url the
+    /// remote's HEAD is to be merged. From a remote it's the upstream of the
+    /// current branch if that's on the remote, or else what the first
+    /// refspec fetches if it names a single ref.
+    fn fetch_head(
+        &self,
+        remote: Option<&str>,
+        url: &str,
+        refspecs: &[Refspec],
+        fetched: &[&RemoteRef],
+    ) -> Result<Vec<BString>, FetchError> {
+        let upstream = match (remote, self.refs.current_branch()?) {
+            (Some(remote), Some(branch)) => {
+                let branch = branch.to_str_lossy();
+                let branch_remote = self.config.get(&format!("branch.{branch}.remote"));
+                if branch_remote == Some(remote) {
+                    self.config.get(&format!("branch.{branch}.merge"))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        let for_merge = |remote_ref: &RemoteRef| match (remote, upstream) {
+            (None, _) => remote_ref.name == "HEAD",
+            (Some(_), Some(upstream)) => remote_ref.name == upstream,
+            (Some(_), None) => refspecs.first().is_some_and(|refspec| {
+                !refspec.negative
+                    && !refspec.is_wildcard()
+                    && refspec.matches(remote_ref.name.as_bstr())
+            }),
+        };
+
+        // Like git, `https://host/repo.git/` is described as `https://host/repo`
+        let url = url.trim_end_matches('/');
+        let url = url.strip_suffix(".git").unwrap_or(url);
+        let (merge, not_for_merge): (Vec<&RemoteRef>, Vec<_>) = fetched
+            .iter()
+            .copied()
+            .partition(|remote_ref| for_merge(remote_ref));
+        let lines = merge
+            .into_iter()
+            .map(|remote_ref| (remote_ref, ""))
+            .chain(
+                not_for_merge
+                    .into_iter()
+                    .map(|remote_ref| (remote_ref, "not-for-merge")),
+            )
+            .map(|(remote_ref, status)| {
+                let name = remote_ref.name.to_str_lossy();
+                let description = if name == "HEAD" {
+                    url.to_owned()
+                } else if let Some(branch) = name.strip_prefix("refs/heads/") {
+                    format!("branch '{branch}' of {url}")
+                } else if let Some(tag) = name.strip_prefix("refs/tags/") {
+                    format!("tag '{tag}' of {url}")
+                } else {
+                    format!("'{name}' of {url}")
+                };
+                BString::from(format!(
+                    "{}\t{status}\t{description}",
+                    remote_ref.oid.to_hex()
+                ))
+            })
+            .collect();
+        Ok(lines)
+    }
+
+    fn prunes(&self, remote: &str, options: &FetchOptions) -> Result<bool, FetchError> {
+        if let Some(prune) = options.prune {
+            return Ok(prune);
+        }
+        let configured = self.config.get_bool(&format!("remote.{remote}.prune"))?;
+        let prune = match configured {
+            Some(prune) => Some(prune),
+            None => self.config.get_bool("fetch.prune")?,
+        };
+        Ok(prune.unwrap_or(false))
+    }
+
+    fn tags(&self, remote: &str, options: &FetchOptions) -> Result<Tags, FetchError> {
+        if let Some(tags) = options.tags {
+            return Ok(tags);
+        }
+        match self.config.get(&format!("remote.{remote}.tagOpt")) {
+            Some("--tags") => Ok(Tags::All),
+            Some("--no-tags") => Ok(Tags::Ignore),
+            Some(tag_opt) => Err(FetchError::InvalidTagOpt(tag_opt.to_owned())),
+            None => Ok(Tags::Follow),
+        }
+    }
+
+    /// The listed tags we don't have that the refspecs don't map, which we
+    /// follow if we have what they point at
+    fn new_tags<'a>(
+        &self,
+        listed: &'a [RemoteRef],
+        refspecs: &[Refspec],
+    ) -> Result<Vec<&'a RemoteRef>, refs::ReadError> {
+        let mut new_tags = Vec::new();
+        for remote_ref in listed {
+            let tag = remote_ref.name.as_bstr();
+            if tag.starts_with(b"refs/tags/")
+                && refspec::map(refspecs, tag).is_empty()
+                && self.refs.read_ref(tag)?.is_none()
+            {
+                new_tags.push(remote_ref);
+            }
+        }
+        Ok(new_tags)
+    }
+
+    /// Deletes the refs the refspecs would store remote refs in whose remote
+    /// ref isn't listed. Symbolic refs (like `refs/remotes/origin/HEAD`) are
+    /// left alone, as git does.
+    fn prune(
+        &self,
+        refspecs: &[Refspec],
+        listed: &[RemoteRef],
+    ) -> Result<Vec<(BString, Oid<Commit>)>, FetchError> {
+        let reversed = refspecs
+            .iter()
+            .filter(|refspec| !refspec.negative)
+            .filter_map(Refspec::reversed)
+            .collect::<Vec<_>>();
+        let listed = listed
+            .iter()
+            .map(|remote_ref| remote_ref.name.as_bstr())
+            .collect::<BTreeSet<_>>();
+
+        let mut pruned = Vec::new();
+        for (local, oid) in self.refs.list(b"refs/".as_bstr())? {
+            let gone = reversed.iter().any(|refspec| {
+                refspec.map(local.as_bstr()).is_some_and(|remote| {
+                    !listed.contains(remote.as_bstr())
+                        && !refspec::excluded(refspecs, remote.as_bstr())
+                })
+            });
+            if gone && self.refs.read_symbolic(local.as_bstr())?.is_none() {
+                debug!(?local, "Pruning");
+                self.refs.delete_ref(local.as_bstr())?;
+                pruned.push((local, oid));
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// `remote.<name>.fetch`, or else the branches as remote-tracking
+    /// branches
+    fn fetch_refspecs(&self, remote: &str) -> Result<Vec<Refspec>, refspec::ParseError> {
+        let refspecs = self
+            .config
+            .get_all(&format!("remote.{remote}.fetch"))
+            .map(Refspec::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if !refspecs.is_empty() {
+            return Ok(refspecs);
+        }
+        Refspec::parse(&format!("+refs/heads/*:refs/remotes/{remote}/*"))
+            .map(|refspec| vec![refspec])
+    }
+
+    /// Fetches objects left out by a filtered fetch from the promisor remote
+    /// (`extensions.partialClone`), without their history
+    #[instrument(err, skip(oids), fields(oids = oids.len()))]
+    pub fn fetch_promised(&self, oids: &[UntypedOid]) -> Result<(), FetchError> {
+        let remote = self
+            .config
+            .get("extensions.partialClone")
+            .ok_or(FetchError::NoPromisor)?;
+        let (_, url) = self.remote_url(remote);
+        let transport = transport::connect(url, Service::UploadPack, &self.config)?;
+        let mut upload_pack = UploadPack::connect(transport)?;
+        let pack = upload_pack
+            .fetch(oids, &[], true, &mut ())?
+            .pack
+            .ok_or(UploadPackError::NoPack)?;
+        let stored = pack::unpack(&self.db, &pack, &mut ())?;
+        debug!(stored = stored.len());
+        match oids.iter().find(|oid| !self.db.contains(oid)) {
+            Some(&oid) => Err(FetchError::NotPromised(oid)),
+            None => Ok(()),
+        }
+    }
+
+    /// Configures the remote as git does for a partial clone
+    fn record_promisor(&mut self, remote: &str, filter: Filter) -> Result<(), FetchError> {
+        self.config.set("core.repositoryformatversion", "1")?;
+        self.config.set("extensions.partialClone", remote)?;
+        self.config
+            .set(&format!("remote.{remote}.promisor"), "true")?;
+        self.config.set(
+            &format!("remote.{remote}.partialclonefilter"),
+            &filter.to_string(),
+        )?;
+        self.save_config()?;
+        Ok(())
+    }
+
+    fn promisor_filter(&self, remote: &str) -> Result<Option<Filter>, FetchError> {
+        match self
+            .config
+            .get(&format!("remote.{remote}.partialclonefilter"))
+        {
+            Some(spec) => Filter::parse(spec)
+                .map(Some)
+                .ok_or_else(|| FetchError::InvalidFilter(spec.to_owned())),
+            None => Ok(None),
+        }
+    }
+
+    /// The name of the remote (if it's configured) and its url, or `remote`
+    /// as the url
+    pub(crate) fn remote_url<'a>(&'a self, remote: &'a str) -> (Option<&'a str>, &'a str) {
+        match self.config.get(&format!("remote.{remote}.url")) {
+            Some(url) => (Some(remote), url),
+            None => (None, remote),
+        }
+    }
+
+    /// Where we start looking for what we have in common with the remote
+    fn local_tips(&self) -> Result<Vec<UntypedOid>, refs::ReadError> {
+        let mut tips = self
+            .refs
+            .list(b"refs/".as_bstr())?
+            .into_iter()
+            .map(|(_, oid)| oid.into_untyped())
+            .collect::<BTreeSet<_>>();
+        tips.extend(self.refs.head()?.map(Oid::into_untyped));
+        Ok(tips.into_iter().collect())
+    }
+}
+
+/// What an annotated tag points to, or the ref's own oid
+fn peeled(remote_ref: &RemoteRef) -> UntypedOid {
+    remote_ref.peeled.unwrap_or(remote_ref.oid)
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum FetchError {
+    /// Failed to connect to remote
+    Connect(#[from] TransportError),
+    /// Failed to fetch from remote
+    UploadPack(#[from] UploadPackError),
+    /// Failed to find what we have in common with remote
+    Negotiate(#[from] NegotiateError),
+    /// Failed to unpack objects from remote
+    Unpack(#[from] UnpackError),
+    /// Failed to update shallow commits
+    Shallow(#[from] ShallowError),
+    /// Filtered fetches are only supported from configured remotes
+    FilterWithoutRemote,
+    /// Invalid partial clone filter {0:?}
+    InvalidFilter(String),
+    /// Invalid `tagOpt` {0:?}, expected `--tags` or `--no-tags`
+    InvalidTagOpt(String),
+    /// Failed to configure promisor remote
+    Configure(#[from] config::EditError),
+    /// Failed to save config
+    SaveConfig(#[from] config::SaveError),
+    /// Invalid config
+    Config(#[from] config::ValueError),
+    /// No promisor remote to fetch missing objects from
+    NoPromisor,
+    /// Promisor remote didn't send {0:?}
+    NotPromised(UntypedOid),
+    /// Invalid fetch refspec
+    Refspec(#[from] refspec::ParseError),
+    /// Failed to check for fast-forwards
+    Walk(#[from] RevWalkError),
+    /// Failed to read refs
+    ReadRefs(#[from] refs::ReadError),
+    /// Failed to update remote-tracking branches
+    UpdateRefs(#[from] refs::UpdateError),
+    /// {0}
+    Cancelled(#[from] Cancelled),
+}
@@ -0,0 +1,202 @@
+//! Which writes are synced to disk before they're renamed into place, like
+//! git's `core.fsync`. Renames alone are atomic but not durable: after a
+//! crash a file can be found empty, or a ref pointing at an object that
+//! never made it to disk. Syncing the file, and the directory it's renamed
+//! into, prevents that at the cost of waiting for the disk.
+
+use std::{fs, io, path::Path};
+
+use tracing::warn;
+
+use crate::core::{config, platform, Config};
+
+/// What's synced. The default is git's: packs, but not loose objects, refs
+/// or the index, which are cheap to lose and slow to sync one by one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[allow(clippy::struct_excessive_bools)] // One per component
+pub struct Fsync {
+    pub loose_objects: bool,
+    /// Both the pack and its index
+    pub packs: bool,
+    pub refs: bool,
+    pub index: bool,
+}
+
+impl Default for Fsync {
+    fn default() -> Self {
+        Self {
+            loose_objects: false,
+            packs: true,
+            refs: false,
+            index: false,
+        }
+    }
+}
+
+impl Fsync {
+    /// Nothing is synced
+    pub const NONE: Self = Self {
+        loose_objects: false,
+        packs: false,
+        refs: false,
+        index: false,
+    };
+
+    /// Everything is synced
+    pub const ALL: Self = Self {
+        loose_objects: true,
+        packs: true,
+        refs: true,
+        index: true,
+    };
+
+    /// `core.fsync` is a comma separated list of components to sync on top
+    /// of the default, or not to if they start with `-`. `none` starts from
+    /// nothing instead. Components are:
+    ///
+    /// - `loose-object`, `pack` and `pack-metadata`, or `objects` for all
+    ///   three
+    /// - `reference`
+    /// - `index`
+    /// - `committed` for objects and refs, `added` for those and the index,
+    ///   and `all`
+    ///
+    /// Unknown components, like `commit-graph` which we don't write, are
+    /// ignored like git does. The older `core.fsyncObjectFiles` syncs loose
+    /// objects too.
+    pub fn from_config(config: &Config) -> Result<Self, config::ValueError> {
+        let mut fsync = match config.get("core.fsync") {
+            Some(value) => Self::parse(value),
+            None => Self::default(),
+        };
+        if config.get_bool("core.fsyncObjectFiles")?.unwrap_or(false) {
+            fsync.loose_objects = true;
+        }
+        Ok(fsync)
+    }
+
+    fn parse(value: &str) -> Self {
+        let mut current = Self::default();
+        let mut add = Self::NONE;
+        let mut remove = Self::NONE;
+        for component in value.split(',').map(str::trim) {
+            if component == "none" {
+                current = Self::NONE;
+                continue;
+            }
+            let (component, target) = match component.strip_prefix('-') {
+                Some(component) => (component, &mut remove),
+                None => (component, &mut add),
+            };
+            let Some(components) = Self::component(component) else {
+                warn!(component, "Ignoring unknown core.fsync component");
+                continue;
+            };
+            *target = target.union(components);
+        }
+        // Like git, additions win over removals whatever their order
+        current.difference(remove).union(add)
+    }
+
+    fn component(name: &str) -> Option<Self> {
+        let none = Self::NONE;
+        Some(match name {
+            "" | "commit-graph" | "derived-metadata" => none,
+            "loose-object" => Self {
+                loose_objects: true,
+                ..none
+            },
+            "pack" | "pack-metadata" => Self {
+                packs: true,
+                ..none
+            },
+            "objects" => Self {
+                loose_objects: true,
+                packs: true,
+                ..none
+            },
+            "reference" => Self { refs: true, ..none },
+            "index" => Self {
+                index: true,
+                ..none
+            },
+            "committed" => Self {
+                index: false,
+                ..Self::ALL
+            },
+            "added" | "all" => Self::ALL,
+            _ => return None,
+        })
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            loose_objects: self.loose_objects || other.loose_objects,
+            packs: self.packs || other.packs,
+            refs: self.refs || other.refs,
+            index: self.index || other.index,
+        }
+    }
+
+    fn difference(self, other: Self) -> Self {
+        Self {
+            loose_objects: self.loose_objects && !other.loose_objects,
+            packs: self.packs && !other.packs,
+            refs: self.refs && !other.refs,
+            index: self.index && !other.index,
+        }
+    }
+}
+
+/// Syncs `file`, which is about to be renamed into `dir`, if `sync` says to
+pub(crate) fn file(file: &fs::File, sync: bool) -> io::Result<()> {
+    if sync {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Syncs the directory something was just renamed into, if `sync` says to
+pub(crate) fn dir(dir: &Path, sync: bool) -> io::Result<()> {
+    if sync {
+        platform::sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_like_git() {
+        assert_eq!(Fsync::default(), Fsync::parse(""));
+        assert_eq!(Fsync::default(), Fsync::parse("commit-graph,unknown"));
+        assert_eq!(Fsync::NONE, Fsync::parse("none"));
+        assert_eq!(Fsync::NONE, Fsync::parse("-pack"));
+        assert_eq!(Fsync::ALL, Fsync::parse("added"));
+        assert_eq!(
+            Fsync {
+                refs: true,
+                ..Fsync::NONE
+            },
+            Fsync::parse("none, reference")
+        );
+        assert_eq!(
+            Fsync {
+                index: false,
+                ..Fsync::ALL
+            },
+            Fsync::parse("committed")
+        );
+        // Additions win
+        assert_eq!(
+            Fsync {
+                loose_objects: true,
+                ..Fsync::NONE
+            },
+            Fsync::parse("loose-object,-objects")
+        );
+    }
+}
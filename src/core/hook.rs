@@ -0,0 +1,143 @@
+//! Running hooks, the programs in `.git/hooks` (or `core.hooksPath`) that
+//! git runs at points in its commands, see <https://git-scm.com/docs/githooks>.
+//! Embedding applications can register [`Hooks`] to run in process too.
+
+use std::{
+    ffi::OsStr,
+    fmt, fs,
+    io::{self, Write},
+    path::Path,
+    process::{Command, Stdio},
+    sync::Arc,
+    thread,
+};
+
+use bstr::{BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use crate::core::{
+    config,
+    db::Commit,
+    platform,
+    refs::{self, TransactionState},
+    Oid, Pushed, Repo,
+};
+
+/// Called at points in operations, after any hook programs. Every method does
+/// nothing by default, and those returning a `Result` stop the operation if
+/// they fail. Unlike hook programs, these run even with `--no-verify`.
+pub trait Hooks: fmt::Debug + Send + Sync {
+    /// Before a commit is made, once the index is final
+    fn pre_commit(&self, _repo: &Repo) -> Result<(), Rejected> {
+        Ok(())
+    }
+
+    /// Once HEAD points at the new commit
+    fn post_commit(&self, _repo: &Repo, _commit: Oid<Commit>) {}
+
+    /// Before sending the updates to the remote, with only those that will
+    /// be sent
+    fn pre_push(&self, _remote: &str, _url: &str, _pushing: &[Pushed]) -> Result<(), Rejected> {
+        Ok(())
+    }
+
+    /// As a [`refs::Transaction`] commits, where failing once it's
+    /// [`TransactionState::Prepared`] aborts it
+    fn reference_transaction(
+        &self,
+        _state: TransactionState,
+        _updates: &[refs::Update],
+    ) -> Result<(), Rejected> {
+        Ok(())
+    }
+}
+
+impl Repo {
+    /// Run in the order they're registered. They're kept by clones of the
+    /// repo.
+    pub fn register_hooks(&mut self, hooks: impl Hooks + 'static) {
+        let hooks: Arc<dyn Hooks> = Arc::new(hooks);
+        self.refs.register_hooks(Arc::clone(&hooks));
+        self.hooks.push(hooks);
+    }
+
+    /// Runs the hook if there's an executable one, from the root of the
+    /// workspace (or the git directory if bare) like git, returning whether
+    /// there was. Fails with what it wrote to stderr if it exits with an
+    /// error. Hooks are in `core.hooksPath` if it's set, which is relative to
+    /// where they run from, or else in `.git/hooks`.
+    pub(crate) fn run_hook(&self, name: &str, args: &[&OsStr]) -> Result<bool, HookError> {
+        self.run_hook_with(name, args, &[])
+    }
+
+    /// Like [`Self::run_hook`], writing `input` to its stdin
+    #[instrument(err, skip(self, input))]
+    pub(crate) fn run_hook_with(
+        &self,
+        name: &str,
+        args: &[&OsStr],
+        input: &[u8],
+    ) -> Result<bool, HookError> {
+        let dir = self.hook_dir();
+        let hooks = match self.config.get_path("core.hooksPath")? {
+            Some(hooks) => dir.join(hooks),
+            None => self.common_dir().join("hooks"),
+        };
+        let hook = hooks.join(name);
+        let executable =
+            fs::metadata(&hook).is_ok_and(|meta| meta.is_file() && platform::is_executable(&meta));
+        if !executable {
+            return Ok(false);
+        }
+
+        debug!(?hook, "Running");
+        let run_err = |err| HookError::Run(name.to_owned(), err);
+        let index = self.index.path();
+        let mut child = Command::new(&hook)
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_INDEX_FILE", index)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(run_err)?;
+        // Written as it reads, so that neither waits on the other's output
+        let mut stdin = child.stdin.take().expect("Piped");
+        let input = input.to_vec();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output().map_err(run_err)?;
+        match writer.join().expect("Writing doesn't panic") {
+            // It doesn't have to read everything
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(run_err(err)),
+            _ => {}
+        }
+
+        if !output.status.success() {
+            let stderr = output.stderr.trim_end().into();
+            return Err(HookError::Failed(name.to_owned(), stderr));
+        }
+        Ok(true)
+    }
+
+    fn hook_dir(&self) -> &Path {
+        match &self.workspace {
+            Some(workspace) => workspace.path(),
+            None => self.git_dir(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum HookError {
+    /// Invalid `core.hooksPath`
+    Config(#[from] config::ValueError),
+    /// Failed to run {0} hook
+    Run(String, #[source] io::Error),
+    /// {0} hook failed: {1}
+    Failed(String, BString),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, thiserror::Error, displaydoc::Display)]
+/// Rejected by hook: {0}
+pub struct Rejected(pub String);
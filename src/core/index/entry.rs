@@ -1,16 +1,23 @@
-use bstr::{BStr, BString, ByteSlice};
+use bstr::{BStr, ByteSlice};
 use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
-use std::{convert::TryInto, fmt, io};
+use std::{
+    convert::TryInto,
+    fmt,
+    io::{self, Read, Write},
+};
 use tracing::{debug, instrument};
 
+use super::{CorruptError, LoadError};
 use crate::core::{
     db::{object::OID_SIZE, Blob},
     stat::{self, Mode},
-    ws::{ReadFileError, StatFileError},
+    with_digest,
+    ws::{path::InvalidPathError, Attributes, ReadForGitError, StatFileError},
     Oid, Stat, Workspace, WsPath,
 };
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Entry {
     pub oid: Oid<Blob>,
     pub stat: Stat,
@@ -19,11 +26,37 @@ pub struct Entry {
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Flags {
     path_len: PathLen,
+    stage: Stage,
+    /// Set by sparse checkout for paths that aren't in the workspace, so
+    /// their absence isn't a deletion. Stored in the extended flags, which
+    /// need index version 3.
+    skip_worktree: bool,
+}
+
+/// The merge stage of an entry. Paths with unresolved conflicts have one
+/// entry per side present instead of a single [`Self::Resolved`] entry.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Stage {
+    Resolved,
+    Base,
+    Ours,
+    Theirs,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum PathLen {
     Exactly(usize),
     MaxOrGreater,
@@ -32,6 +65,7 @@ pub enum PathLen {
 impl Entry {
     const BLOCK_SIZE: usize = 8;
     const PATH_OFFSET: usize = 62;
+    const EXTENDED_PATH_OFFSET: usize = 64;
 
     pub fn new(path: impl Into<WsPath>, oid: Oid<Blob>, stat: Stat) -> Self {
         let path = path.into();
@@ -43,30 +77,178 @@ impl Entry {
         }
     }
 
+    /// Like [`Self::new`], but fails if the path is the root or isn't valid
+    /// (see [`WsPath::check`])
+    pub fn try_new(path: WsPath, oid: Oid<Blob>, stat: Stat) -> Result<Self, InvalidPathError> {
+        path.check()?;
+        if path == WsPath::root() {
+            return Err(InvalidPathError::new(path));
+        }
+        Ok(Self::new(path, oid, stat))
+    }
+
     pub fn key(&self) -> &BStr {
         self.path.as_ref()
     }
 
-    pub fn filename(&self) -> &BStr {
-        self.path.file_name().as_bstr()
+    /// `None` if the entry is for the root, which a valid entry never is
+    pub fn filename(&self) -> Option<&BStr> {
+        self.path.file_name()
     }
 
     pub fn mode(&self) -> stat::Mode {
         self.stat.mode
     }
 
+    pub fn stage(&self) -> Stage {
+        self.flags.stage
+    }
+
+    #[must_use]
+    pub fn with_stage(mut self, stage: Stage) -> Self {
+        self.flags.stage = stage;
+        self
+    }
+
+    pub fn skip_worktree(&self) -> bool {
+        self.flags.skip_worktree
+    }
+
+    pub fn set_skip_worktree(&mut self, skip_worktree: bool) {
+        self.flags.skip_worktree = skip_worktree;
+    }
+
+    /// Whether the entry needs a version 3 index
+    pub(crate) fn is_extended(&self) -> bool {
+        self.flags.is_extended()
+    }
+
     pub fn update_stat(&mut self, stat: Stat) -> Stat {
         let old = self.stat;
         self.stat = stat;
         old
     }
 
-    #[instrument(err)]
+    /// Borrow the entry as if it were in a [`MappedIndex`](super::MappedIndex)
+    pub fn as_entry_ref(&self) -> EntryRef<'_> {
+        EntryRef {
+            oid: self.oid,
+            stat: self.stat,
+            flags: self.flags,
+            path: self.key(),
+        }
+    }
+
+    /// Assembles the fixed-size fields first, so that the entry takes one
+    /// vectored write rather than one per field
+    #[allow(clippy::similar_names)] // unixisms
+    pub fn write_to_index(&self, writer: &mut impl io::Write) -> io::Result<()> {
+        let mut header = [0; Self::EXTENDED_PATH_OFFSET];
+        let mut fields = &mut header[..];
+
+        let (ctime_i, ctime_n) = self.stat.ctime_epoch();
+        fields.write_u32::<NetworkEndian>(ctime_i)?; // offset 0
+        fields.write_u32::<NetworkEndian>(ctime_n)?; // offset 4
+
+        let (mtime_i, mtime_n) = self.stat.mtime_epoch();
+        fields.write_u32::<NetworkEndian>(mtime_i)?; // offset 8
+        fields.write_u32::<NetworkEndian>(mtime_n)?; // offset 12
+
+        fields.write_u32::<NetworkEndian>(self.stat.dev)?; // offset 16
+        fields.write_u32::<NetworkEndian>(self.stat.ino)?; // offset 24
+        fields.write_u32::<NetworkEndian>(self.stat.mode.as_u32())?; // offset 28
+        fields.write_u32::<NetworkEndian>(self.stat.uid)?; // offset 32
+        fields.write_u32::<NetworkEndian>(self.stat.gid)?; // offset 36
+        fields.write_u32::<NetworkEndian>(self.stat.size)?; // offset 40
+
+        fields.write_all(self.oid.as_bytes())?; // offset 60
+        fields.write_u16::<NetworkEndian>(self.flags.as_u16())?; // offset 62
+        let header_len = if self.is_extended() {
+            fields.write_u16::<NetworkEndian>(self.flags.extended_as_u16())?; // offset 64
+            Self::EXTENDED_PATH_OFFSET
+        } else {
+            Self::PATH_OFFSET
+        };
+
+        let path = self.path.as_bstr();
+        let padding = Self::padding_size(path, self.is_extended());
+        with_digest::write_all_vectored(
+            writer,
+            &mut [
+                io::IoSlice::new(&header[..header_len]),
+                io::IoSlice::new(path),
+                io::IoSlice::new(&[0; Self::BLOCK_SIZE][..padding]),
+            ],
+        )
+    }
+
+    /// Parse the entry at the start of `data`, advancing it past the entry
+    pub fn parse_from_index(data: &mut &[u8]) -> Result<Self, LoadError> {
+        EntryRef::parse(data).map(|entry| entry.to_entry())
+    }
+
+    fn padding_size(path: &[u8], extended: bool) -> usize {
+        let offset = if extended {
+            Self::EXTENDED_PATH_OFFSET
+        } else {
+            Self::PATH_OFFSET
+        };
+        let len = offset + path.len();
+        // See <https://stackoverflow.com/a/11642218>
+        let mut padding = (Self::BLOCK_SIZE - (len % Self::BLOCK_SIZE)) % Self::BLOCK_SIZE;
+        if padding == 0 {
+            padding = Self::BLOCK_SIZE;
+        }
+        padding
+    }
+}
+
+/// An entry borrowed from the bytes of an index, see
+/// [`MappedIndex`](super::MappedIndex)
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct EntryRef<'a> {
+    pub oid: Oid<Blob>,
+    pub stat: Stat,
+    pub flags: Flags,
+    pub path: &'a BStr,
+}
+
+impl<'a> EntryRef<'a> {
+    pub fn key(&self) -> &'a BStr {
+        self.path
+    }
+
+    /// Copy the path out of the index
+    pub fn to_path(&self) -> WsPath {
+        WsPath::new_unchecked_bytes(self.path)
+    }
+
+    /// `None` if the entry is for the root, which a valid entry never is
+    pub fn filename(&self) -> Option<&'a BStr> {
+        let name = self.path.rsplit_str("/").next()?;
+        (!name.is_empty()).then(|| name.as_bstr())
+    }
+
+    pub fn mode(&self) -> stat::Mode {
+        self.stat.mode
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.flags.stage
+    }
+
+    pub fn skip_worktree(&self) -> bool {
+        self.flags.skip_worktree
+    }
+
+    #[instrument(err, skip(attrs))]
     pub(crate) fn index_status_chatty(
         &self,
         workspace: &Workspace,
+        attrs: &Attributes,
     ) -> Result<StatusChatty, IsUnchangedError> {
-        let new_stat = match workspace.stat(&self.path) {
+        let path = self.to_path();
+        let new_stat = match workspace.stat_tracked(&path, self.stat.mode) {
             Ok(stat) => stat,
             Err(err) if err.is_not_found() => {
                 debug!("Determined deleted based on stat failure");
@@ -75,6 +257,17 @@ impl Entry {
             Err(err) => return Err(err.into()),
         };
 
+        if !self.stat.mode.is_same_type(new_stat.mode) {
+            debug!("Determined typechange. other: {:?}", new_stat);
+            return Ok(StatusChatty::TypeChanged);
+        }
+
+        if self.stat.mode == Mode::Gitlink {
+            // We'd need to look inside the submodule to tell if it moved
+            debug!("Assuming gitlink is unchanged");
+            return Ok(StatusChatty::Unmodified);
+        }
+
         if self.stat.size != new_stat.size || self.stat.mode != new_stat.mode {
             debug!(
                 "Determined changed based on size or mode. other: {:?}",
@@ -83,7 +276,7 @@ impl Entry {
             return Ok(StatusChatty::Modified);
         }
 
-        if self.times_match(&new_stat) {
+        if self.stat.times_match(&new_stat) {
             debug!(
                 "Determined unchanged based on timestamps. other: {:?}",
                 new_stat
@@ -91,7 +284,7 @@ impl Entry {
             return Ok(StatusChatty::Unmodified);
         }
 
-        let new_data = workspace.read_file(&self.path)?;
+        let new_data = workspace.read_for_hashing(&path, attrs)?;
         let new_oid = Blob::oid_for_file(new_data.as_bstr());
 
         if self.oid == new_oid {
@@ -106,58 +299,50 @@ impl Entry {
         }
     }
 
-    fn times_match(&self, other: &Stat) -> bool {
-        self.stat.mtime == other.mtime && self.stat.ctime == other.ctime
+    /// Copy the entry out of the index
+    pub fn to_entry(&self) -> Entry {
+        Entry {
+            oid: self.oid,
+            stat: self.stat,
+            flags: self.flags,
+            path: WsPath::new_unchecked_bytes(self.path),
+        }
     }
 
-    #[allow(clippy::similar_names)] // unixisms
-    pub fn write_to_index(&self, writer: &mut impl io::Write) -> io::Result<()> {
-        let (ctime_i, ctime_n) = self.stat.ctime_epoch();
-        writer.write_u32::<NetworkEndian>(ctime_i)?; // offset 0
-        writer.write_u32::<NetworkEndian>(ctime_n)?; // offset 4
-
-        let (mtime_i, mtime_n) = self.stat.mtime_epoch();
-        writer.write_u32::<NetworkEndian>(mtime_i)?; // offset 8
-        writer.write_u32::<NetworkEndian>(mtime_n)?; // offset 12
-
-        writer.write_u32::<NetworkEndian>(self.stat.dev)?; // offset 16
-        writer.write_u32::<NetworkEndian>(self.stat.ino)?; // offset 24
-        writer.write_u32::<NetworkEndian>(self.stat.mode.as_u32())?; // offset 28
-        writer.write_u32::<NetworkEndian>(self.stat.uid)?; // offset 32
-        writer.write_u32::<NetworkEndian>(self.stat.gid)?; // offset 36
-        writer.write_u32::<NetworkEndian>(self.stat.size)?; // offset 40
-
-        writer.write_all(self.oid.as_bytes())?; // offset 60
-        writer.write_u16::<NetworkEndian>(self.flags.as_u16())?; // offset 62
-
-        let path = self.path.as_bstr();
-        writer.write_all(path)?;
-        for _ in 0..Self::padding_size(path) {
-            writer.write_all(b"\0")?;
+    /// Parse the entry at the start of `data`, advancing it past the entry
+    pub fn parse(data: &mut &'a [u8]) -> Result<Self, LoadError> {
+        let entry = Self::read(data)?;
+        if entry.path.is_empty() {
+            return Err(CorruptError::EmptyPath.into());
         }
-
-        Ok(())
+        if !WsPath::is_valid_bytes(entry.path) {
+            let path = WsPath::new_unchecked_bytes(entry.path);
+            return Err(CorruptError::InvalidPath(InvalidPathError::new(path)).into());
+        }
+        Ok(entry)
     }
 
+    /// Like [`Self::parse`], but without checking the path, for entries
+    /// we've already parsed
     #[allow(clippy::similar_names)] // unixisms
-    pub fn parse_from_index(reader: &mut impl io::Read) -> io::Result<Self> {
-        let ctime_i = reader.read_u32::<NetworkEndian>()?; // offset 0
-        let ctime_n = reader.read_u32::<NetworkEndian>()?; // offset 4
+    pub(super) fn read(data: &mut &'a [u8]) -> io::Result<Self> {
+        let ctime_i = data.read_u32::<NetworkEndian>()?; // offset 0
+        let ctime_n = data.read_u32::<NetworkEndian>()?; // offset 4
         let ctime = Stat::systemtime_from_epoch(ctime_i, ctime_n);
 
-        let mtime_i = reader.read_u32::<NetworkEndian>()?; // offset 8
-        let mtime_n = reader.read_u32::<NetworkEndian>()?; // offset 12
+        let mtime_i = data.read_u32::<NetworkEndian>()?; // offset 8
+        let mtime_n = data.read_u32::<NetworkEndian>()?; // offset 12
         let mtime = Stat::systemtime_from_epoch(mtime_i, mtime_n);
 
-        let dev = reader.read_u32::<NetworkEndian>()?; // offset 16
-        let ino = reader.read_u32::<NetworkEndian>()?; // offset 24
+        let dev = data.read_u32::<NetworkEndian>()?; // offset 16
+        let ino = data.read_u32::<NetworkEndian>()?; // offset 24
 
-        let mode = reader.read_u32::<NetworkEndian>()?; // offset 28
+        let mode = data.read_u32::<NetworkEndian>()?; // offset 28
         let mode = Mode::from_u32(mode);
 
-        let uid = reader.read_u32::<NetworkEndian>()?; // offset 32
-        let gid = reader.read_u32::<NetworkEndian>()?; // offset 36
-        let size = reader.read_u32::<NetworkEndian>()?; // offset 40
+        let uid = data.read_u32::<NetworkEndian>()?; // offset 32
+        let gid = data.read_u32::<NetworkEndian>()?; // offset 36
+        let size = data.read_u32::<NetworkEndian>()?; // offset 40
 
         let stat = Stat {
             ctime,
@@ -171,28 +356,26 @@ impl Entry {
         };
 
         let mut oid = [0; OID_SIZE];
-        reader.read_exact(&mut oid)?; // offset 60
+        data.read_exact(&mut oid)?; // offset 60
         let oid = Oid::new(oid);
 
-        let flags = reader.read_u16::<NetworkEndian>()?; // offset 62
-        let flags = Flags::from_u16(flags);
-
-        let mut path = BString::from(Vec::new());
-        loop {
-            let byte = reader.read_u8()?;
-            if byte == b'\0' {
-                break;
-            }
-            path.push(byte);
-        }
-        let padding_size = Self::padding_size(&path);
-        // we already read one null byte
-        if padding_size > 1 {
-            for _ in 0..padding_size - 1 {
-                reader.read_u8()?;
-            }
-        }
-        let path = WsPath::new_unchecked_bytes(path);
+        let flags = data.read_u16::<NetworkEndian>()?; // offset 62
+        let extended = if flags & Flags::EXTENDED == 0 {
+            0
+        } else {
+            data.read_u16::<NetworkEndian>()? // offset 64
+        };
+        let flags = Flags::from_u16(flags, extended);
+
+        let rest: &'a [u8] = data;
+        let len = rest
+            .find_byte(b'\0')
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let path = rest[..len].as_bstr();
+        // The padding includes the null terminating the path
+        *data = rest
+            .get(len + Entry::padding_size(path, flags.is_extended())..)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
 
         Ok(Self {
             oid,
@@ -201,16 +384,6 @@ impl Entry {
             path,
         })
     }
-
-    fn padding_size(path: &[u8]) -> usize {
-        let len = Self::PATH_OFFSET + path.len();
-        // See <https://stackoverflow.com/a/11642218>
-        let mut padding = (Self::BLOCK_SIZE - (len % Self::BLOCK_SIZE)) % Self::BLOCK_SIZE;
-        if padding == 0 {
-            padding = Self::BLOCK_SIZE;
-        }
-        padding
-    }
 }
 
 impl fmt::Display for Entry {
@@ -220,33 +393,84 @@ impl fmt::Display for Entry {
 }
 
 impl Flags {
+    const STAGE_SHIFT: u16 = 12;
+    const EXTENDED: u16 = 0x4000;
+    const SKIP_WORKTREE: u16 = 0x4000;
+
     fn from_path(path: &WsPath) -> Self {
         Self {
             path_len: PathLen::from(path),
+            stage: Stage::Resolved,
+            skip_worktree: false,
         }
     }
 
-    fn from_u16(val: u16) -> Self {
-        let val = val as usize;
-        let path_len = if val <= PathLen::MAX {
-            PathLen::Exactly(val)
+    fn from_u16(val: u16, extended: u16) -> Self {
+        let len = (val & PathLen::MAX_U16) as usize;
+        let path_len = if len <= PathLen::MAX {
+            PathLen::Exactly(len)
         } else {
             PathLen::MaxOrGreater
         };
-        Self { path_len }
+        let stage = Stage::from_u16(val >> Self::STAGE_SHIFT);
+        let skip_worktree = extended & Self::SKIP_WORKTREE != 0;
+        Self {
+            path_len,
+            stage,
+            skip_worktree,
+        }
+    }
+
+    fn is_extended(self) -> bool {
+        self.skip_worktree
     }
 
     fn as_u16(&self) -> u16 {
-        match self.path_len {
+        let len = match self.path_len {
             PathLen::Exactly(len) => len.try_into().expect("len < MAX"),
-            #[allow(clippy::cast_possible_truncation)]
-            PathLen::MaxOrGreater => PathLen::MAX as u16,
+            PathLen::MaxOrGreater => PathLen::MAX_U16,
+        };
+        let extended = if self.is_extended() {
+            Self::EXTENDED
+        } else {
+            0
+        };
+        len | extended | (self.stage.as_u16() << Self::STAGE_SHIFT)
+    }
+
+    fn extended_as_u16(self) -> u16 {
+        if self.skip_worktree {
+            Self::SKIP_WORKTREE
+        } else {
+            0
+        }
+    }
+}
+
+impl Stage {
+    fn from_u16(val: u16) -> Self {
+        match val & 0b11 {
+            0 => Self::Resolved,
+            1 => Self::Base,
+            2 => Self::Ours,
+            _ => Self::Theirs,
+        }
+    }
+
+    fn as_u16(self) -> u16 {
+        match self {
+            Self::Resolved => 0,
+            Self::Base => 1,
+            Self::Ours => 2,
+            Self::Theirs => 3,
         }
     }
 }
 
 impl PathLen {
     pub const MAX: usize = 0xfff;
+    #[allow(clippy::cast_possible_truncation)]
+    const MAX_U16: u16 = Self::MAX as u16;
 
     fn from(path: &WsPath) -> Self {
         let path = path.as_bstr();
@@ -263,12 +487,13 @@ pub enum IsUnchangedError {
     /// Failed to stat file
     Stat(#[from] StatFileError),
     /// Failed to read file
-    Read(#[from] ReadFileError),
+    Read(#[from] ReadForGitError),
 }
 
 pub(crate) enum StatusChatty {
     Unmodified,
     UnmodifiedButNewStat(Stat),
     Modified,
+    TypeChanged,
     Deleted,
 }
@@ -0,0 +1,195 @@
+use std::{
+    convert::TryInto,
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use byteorder::{NetworkEndian, ReadBytesExt};
+use memmap2::Mmap;
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY as SHA1};
+use tracing::{debug, trace};
+
+use super::{CorruptError, EntryRef, Index, LoadError, Stage};
+use crate::core::WsPath;
+
+/// The index as it is on disk, read in place from a memory map. Entries are
+/// borrowed from the map, and only copied by [`EntryRef::to_entry`], or by
+/// modifying an [`Index`] loaded from it, which copies them all so they can
+/// be changed. For large indexes this is much cheaper when you only need to
+/// look.
+pub struct MappedIndex {
+    /// `None` if there's no index yet
+    map: Option<Mmap>,
+    /// Where each entry starts in the map
+    offsets: Vec<usize>,
+}
+
+impl MappedIndex {
+    /// Empty if there's no index yet
+    pub fn open<P: AsRef<Path>>(git_dir: P) -> Result<Self, LoadError> {
+        Self::open_file(&Index::file_path(git_dir))
+    }
+
+    /// From a file other than `index` in the git directory
+    #[cfg_attr(feature = "trace", tracing::instrument(level = "trace"))]
+    pub fn open_file(path: &Path) -> Result<Self, LoadError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("Index does not exist");
+                return Ok(Self {
+                    map: None,
+                    offsets: Vec::new(),
+                });
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        // Safety: The index is only ever replaced by renaming a new file over
+        // it (by us and by git), never changed in place, so what we've mapped
+        // can't change under us.
+        let map = unsafe { Mmap::map(&file)? };
+        let offsets = parse(&map)?;
+        trace!(entries = offsets.len(), "Mapped index");
+        Ok(Self {
+            map: Some(map),
+            offsets,
+        })
+    }
+
+    /// Every entry, including conflict stages, sorted by path and then stage
+    /// as they're stored
+    pub fn entries(&self) -> impl Iterator<Item = EntryRef<'_>> {
+        entries_at(self.data(), &self.offsets)
+    }
+
+    /// The resolved entry for the path
+    pub fn entry(&self, path: &WsPath) -> Option<EntryRef<'_>> {
+        let key = path.as_bstr();
+        let data = self.data();
+        let start = self
+            .offsets
+            .partition_point(|&offset| entry_at(data, offset).key() < key);
+        entries_at(data, &self.offsets[start..])
+            .take_while(|entry| entry.key() == key)
+            .find(|entry| entry.stage() == Stage::Resolved)
+    }
+
+    pub fn has_conflicts(&self) -> bool {
+        self.entries().any(|entry| entry.stage() != Stage::Resolved)
+    }
+
+    /// Includes conflict stages
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    fn data(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for MappedIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MappedIndex")
+            .field("entries", &self.offsets.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Check the whole index, returning where each entry starts
+pub(super) fn parse(data: &[u8]) -> Result<Vec<usize>, LoadError> {
+    let split = data
+        .len()
+        .checked_sub(Index::CHECKSUM_LEN)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    let (body, actual_checksum) = data.split_at(split);
+    let mut input = body;
+
+    let mut sig = [0; 4];
+    input.read_exact(&mut sig)?; // offset 0
+    if sig != Index::SIG {
+        return Err(CorruptError::MissingSignature.into());
+    }
+
+    let version = input.read_u32::<NetworkEndian>()?; // offset 4
+    if version != Index::VERSION && version != Index::EXTENDED_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let count = input.read_u32::<NetworkEndian>()?; // offset 8
+
+    // offset 12. Don't trust the count to size the offsets, as every entry
+    // takes at least this many bytes.
+    let max_count = body.len() / 64;
+    let mut offsets = Vec::with_capacity(max_count.min(count.try_into().unwrap_or(usize::MAX)));
+    for _ in 0..count {
+        offsets.push(body.len() - input.len());
+        EntryRef::parse(&mut input)?;
+    }
+
+    // Extensions, then the checksum
+    Index::check_extensions(input)?;
+
+    if actual_checksum != digest(&SHA1, body).as_ref() {
+        return Err(CorruptError::IncorrectChecksum.into());
+    }
+
+    Ok(offsets)
+}
+
+/// The offsets must be from [`parse`]ing the data
+pub(super) fn entries_at<'a>(
+    data: &'a [u8],
+    offsets: &'a [usize],
+) -> impl Iterator<Item = EntryRef<'a>> {
+    offsets.iter().map(move |&offset| entry_at(data, offset))
+}
+
+fn entry_at(data: &[u8], offset: usize) -> EntryRef<'_> {
+    EntryRef::read(&mut &data[offset..]).expect("Parsed when opened")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{index::Entry, Oid, Stat};
+    use bstr::ByteSlice;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn borrows_entries_from_map() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert!(MappedIndex::open(dir.path())?.is_empty());
+
+        let mut index = Index::load(dir.path())?;
+        let mut index_mut = index.modify()?;
+        for path in &["b.txt", "a.txt", "dir/c.txt"] {
+            let path = WsPath::new_unchecked(path);
+            index_mut.add(Entry::new(path, Oid::zero(), Stat::zeroed()));
+        }
+        index_mut.commit()?;
+
+        let mapped = MappedIndex::open(dir.path())?;
+        let paths = mapped
+            .entries()
+            .map(|entry| entry.key())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["a.txt", "b.txt", "dir/c.txt"], paths);
+
+        let entry = mapped.entry(&WsPath::new_unchecked("b.txt"));
+        assert_eq!(Some(b"b.txt".as_bstr()), entry.map(|entry| entry.key()));
+        assert!(mapped.entry(&WsPath::new_unchecked("c.txt")).is_none());
+        assert!(!mapped.has_conflicts());
+
+        let index = Index::load(dir.path())?;
+        assert!(mapped.entries().eq(index.entries()));
+        Ok(())
+    }
+}
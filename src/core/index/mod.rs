@@ -1,115 +1,260 @@
 pub mod entry;
-pub use entry::Entry;
+mod mapped;
+pub use entry::{Entry, EntryRef, Stage};
+pub use mapped::MappedIndex;
 
 use std::{
     collections::{BTreeMap, BTreeSet},
     convert::TryInto,
     fmt,
-    fs::File,
-    io::{self, Read, Write},
+    io::{self, BufWriter, Write},
     ops::Deref,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use crate::core::{locked_file, LockedFile, Stat, WithDigest, WsPath};
-use bstr::BString;
-use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use crate::core::{
+    locked_file::{self, LockOptions},
+    ws::{attributes, path::InvalidPathError, Attributes},
+    LockedFile, Stat, WithDigest, Workspace, WsPath,
+};
+use bstr::{BStr, BString, ByteSlice};
+use byteorder::{ByteOrder, NetworkEndian, WriteBytesExt};
+use entry::{IsUnchangedError, StatusChatty};
+use rayon::prelude::*;
 use ring::digest::SHA1_FOR_LEGACY_USE_ONLY as SHA1;
-use tracing::debug;
+use tracing::{debug, trace};
 
 type EntriesMap = BTreeMap<BString, Entry>;
+type ConflictsMap = BTreeMap<BString, Conflict>;
 
 #[derive(Clone)]
 pub struct Index {
-    entries: EntriesMap,
+    entries: Entries,
+    conflicts: ConflictsMap,
     path: PathBuf,
+    /// Sync to disk on commit, see [`Self::set_fsync`]
+    fsync: bool,
+    /// See [`Self::set_lock_options`]
+    lock_options: LockOptions,
+}
+
+/// The resolved entries, borrowed from the mapped index until something
+/// modifies them. Conflicts are few, so they're always copied.
+#[derive(Clone)]
+enum Entries {
+    Mapped(Arc<MappedIndex>),
+    Owned(EntriesMap),
+}
+
+/// The entries of an unmerged path, one per side that has the path. Each
+/// entry's stage matches the field it's in.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Conflict {
+    pub base: Option<Entry>,
+    pub ours: Option<Entry>,
+    pub theirs: Option<Entry>,
 }
 
 impl Index {
     const SIG: &'static [u8] = b"DIRC";
     const VERSION: u32 = 2;
+    /// Needed for entries with extended flags
+    const EXTENDED_VERSION: u32 = 3;
     const CHECKSUM_LEN: usize = 20;
 
     pub fn load<P: AsRef<Path>>(git_dir: P) -> Result<Self, LoadError> {
-        let path = Self::file_path(git_dir);
-        let entries = Self::load_entries(&path)?;
+        Self::load_file(Self::file_path(git_dir))
+    }
+
+    /// From a file other than `index` in the git directory, like
+    /// `GIT_INDEX_FILE`
+    pub fn load_file(path: impl Into<PathBuf>) -> Result<Self, LoadError> {
+        let path = path.into();
+        let (entries, conflicts) = Self::load_entries(&path)?;
 
-        Ok(Self { entries, path })
+        Ok(Self {
+            entries,
+            conflicts,
+            path,
+            fsync: false,
+            lock_options: LockOptions::default(),
+        })
+    }
+
+    /// Whether [`IndexMut::commit`] syncs the index to disk, like the
+    /// `index` component of [`Fsync`](crate::core::Fsync)
+    pub fn set_fsync(&mut self, fsync: bool) {
+        self.fsync = fsync;
+    }
+
+    /// How hard [`Self::modify`] tries for the lock on the index when
+    /// someone else holds it
+    pub fn set_lock_options(&mut self, options: LockOptions) {
+        self.lock_options = options;
     }
 
     /// Reload the index from disk. You don't need to do this after using
     /// [`Self::modify`] on this instance, this is for getting changes made by
     /// external programs.
     pub fn reload(&mut self) -> Result<(), LoadError> {
-        let entries = Self::load_entries(&self.path)?;
+        let (entries, conflicts) = Self::load_entries(&self.path)?;
         self.entries = entries;
+        self.conflicts = conflicts;
         Ok(())
     }
 
-    fn load_entries(path: &Path) -> Result<EntriesMap, LoadError> {
-        let file = match File::open(&path) {
-            Ok(file) => file,
-            Err(err) if err.kind() == io::ErrorKind::NotFound => {
-                debug!("Index does not exist");
-                return Ok(BTreeMap::new());
-            }
-            Err(err) => return Err(err.into()),
-        };
-
-        Self::load_entries_from(file)
+    fn load_entries(path: &Path) -> Result<(Entries, ConflictsMap), LoadError> {
+        let mapped = MappedIndex::open_file(path)?;
+        let conflicts = Self::collect_conflicts(mapped.entries());
+        trace!(
+            entries = mapped.len(),
+            conflicts = conflicts.len(),
+            "Loaded index"
+        );
+        Ok((Entries::Mapped(Arc::new(mapped)), conflicts))
     }
 
-    fn load_entries_from(mut reader: impl Read) -> Result<EntriesMap, LoadError> {
-        let mut input = WithDigest::new(&SHA1, &mut reader);
-
-        let mut sig = [0; 4];
-        input.read_exact(&mut sig)?; // offset 0
-        if sig != Self::SIG {
-            return Err(CorruptError::MissingSignature.into());
-        }
+    #[cfg(test)]
+    fn load_entries_from(data: &[u8]) -> Result<(EntriesMap, ConflictsMap), LoadError> {
+        let offsets = mapped::parse(data)?;
+        let entries = Entries::collect(mapped::entries_at(data, &offsets));
+        let conflicts = Self::collect_conflicts(mapped::entries_at(data, &offsets));
+        Ok((entries, conflicts))
+    }
 
-        let version = input.read_u32::<NetworkEndian>()?; // offset 4
-        if version != Self::VERSION {
-            return Err(LoadError::UnsupportedVersion(version));
+    /// Copy the conflict stages out of the index
+    fn collect_conflicts<'a>(all: impl Iterator<Item = EntryRef<'a>>) -> ConflictsMap {
+        let mut conflicts = BTreeMap::new();
+        for entry in all.filter(|entry| entry.stage() != Stage::Resolved) {
+            conflicts
+                .entry(entry.key().to_owned())
+                .or_insert_with(Conflict::default)
+                .insert(entry.to_entry());
         }
+        conflicts
+    }
 
-        let count = input.read_u32::<NetworkEndian>()?; // offset 8
-
-        // offset 12
-        let mut entries = BTreeMap::new();
-        for _ in 0..count {
-            let entry = Entry::parse_from_index(&mut input)?;
-            entries.insert(entry.key().to_owned(), entry);
+    /// Extensions starting with an uppercase letter (like the cached tree)
+    /// are optional, and we don't use them. We can't read an index with any
+    /// others.
+    fn check_extensions(mut extensions: &[u8]) -> Result<(), CorruptError> {
+        while !extensions.is_empty() {
+            let sig = extensions
+                .get(..4)
+                .ok_or(CorruptError::TruncatedExtension)?;
+            let len = extensions
+                .get(4..8)
+                .map(|len| NetworkEndian::read_u32(len) as usize)
+                .ok_or(CorruptError::TruncatedExtension)?;
+            if !sig[0].is_ascii_uppercase() {
+                return Err(CorruptError::UnsupportedExtension(sig.as_bstr().to_owned()));
+            }
+            debug!("Skipping index extension {}", sig.as_bstr());
+            extensions = extensions
+                .get(8 + len..)
+                .ok_or(CorruptError::TruncatedExtension)?;
         }
+        Ok(())
+    }
 
-        let expected_checksum = input.finish();
-        let expected_checksum: [u8; Self::CHECKSUM_LEN] =
-            expected_checksum.as_ref().try_into().unwrap();
-
-        let mut actual_checksum = [0; Self::CHECKSUM_LEN];
-        reader.read_exact(&mut actual_checksum)?;
+    /// Resolved entries only, see [`Self::conflicts`] for the rest. They're
+    /// borrowed from the file the index was loaded from, until it's modified.
+    pub fn entries(&self) -> impl Iterator<Item = EntryRef<'_>> {
+        let (mapped, owned) = match &self.entries {
+            Entries::Mapped(mapped) => (Some(mapped.entries()), None),
+            Entries::Owned(entries) => (None, Some(entries.values())),
+        };
+        let mapped = mapped
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.stage() == Stage::Resolved);
+        let owned = owned.into_iter().flatten().map(Entry::as_entry_ref);
+        mapped.chain(owned)
+    }
 
-        if actual_checksum != expected_checksum {
-            return Err(CorruptError::IncorrectChecksum.into());
-        }
+    pub fn conflicts(&self) -> impl Iterator<Item = (&BStr, &Conflict)> {
+        self.conflicts
+            .iter()
+            .map(|(path, conflict)| (path.as_ref(), conflict))
+    }
 
-        Ok(entries)
+    pub fn conflict(&self, path: &WsPath) -> Option<&Conflict> {
+        self.conflicts.get(path.as_bstr())
     }
 
-    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
-        self.entries.values()
+    pub fn has_conflicts(&self) -> bool {
+        !self.conflicts.is_empty()
     }
 
-    pub fn modify(&mut self) -> Result<IndexMut, OpenForModificationsError> {
+    pub fn modify(&mut self) -> Result<IndexMut<'_>, OpenForModificationsError> {
         IndexMut::new(self)
     }
 
+    /// Includes paths with unresolved conflicts
     pub fn is_tracked_file(&self, path: &WsPath) -> bool {
-        self.entries.contains_key(path.as_bstr())
+        self.entry(path).is_some() || self.conflicts.contains_key(path.as_bstr())
     }
 
-    pub fn entry(&self, path: &WsPath) -> Option<&Entry> {
-        self.entries.get(path.as_bstr())
+    pub fn entry(&self, path: &WsPath) -> Option<EntryRef<'_>> {
+        match &self.entries {
+            Entries::Mapped(mapped) => mapped.entry(path),
+            Entries::Owned(entries) => entries.get(path.as_bstr()).map(Entry::as_entry_ref),
+        }
+    }
+
+    /// Re-stats the tracked files, like `git update-index --refresh`, so that
+    /// those touched but unchanged aren't hashed again by every status. Only
+    /// files whose stat doesn't match are hashed, and the index is written
+    /// once at the end. Returns the files whose contents did change, which
+    /// keep their old stat.
+    pub fn refresh(
+        &mut self,
+        workspace: &Workspace,
+        attrs: &mut Attributes,
+    ) -> Result<Vec<WsPath>, RefreshError> {
+        self.reload()?;
+        let mut index = self.modify()?;
+        let paths = index
+            .entries()
+            .filter(|entry| !entry.skip_worktree())
+            .map(|entry| entry.to_path())
+            .collect::<Vec<_>>();
+        for path in &paths {
+            attrs.load_parents(workspace, path)?;
+        }
+
+        // Checked in parallel, as hashing is the slow part
+        let checked = {
+            let index: &Index = &index;
+            let attrs = &*attrs;
+            paths
+                .into_par_iter()
+                .map(|path| {
+                    let entry = index.entry(&path).expect("Listed from the index");
+                    Ok((path, entry.index_status_chatty(workspace, attrs)?))
+                })
+                .collect::<Result<Vec<_>, RefreshError>>()?
+        };
+        let mut changed = Vec::new();
+        for (path, status) in checked {
+            match status {
+                StatusChatty::Unmodified => {}
+                StatusChatty::UnmodifiedButNewStat(stat) => {
+                    index.update_stat(&path, stat).expect("Entry exists");
+                }
+                StatusChatty::Modified | StatusChatty::TypeChanged | StatusChatty::Deleted => {
+                    changed.push(path);
+                }
+            }
+        }
+        index.commit()?;
+        Ok(changed)
+    }
+
+    /// The file the index is loaded from and written to
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     fn file_path(git_dir: impl AsRef<Path>) -> PathBuf {
@@ -125,6 +270,44 @@ impl fmt::Debug for Index {
     }
 }
 
+impl Conflict {
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.base
+            .iter()
+            .chain(self.ours.iter())
+            .chain(self.theirs.iter())
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        match entry.stage() {
+            Stage::Base => self.base = Some(entry),
+            Stage::Ours => self.ours = Some(entry),
+            Stage::Theirs => self.theirs = Some(entry),
+            Stage::Resolved => panic!("Resolved entry isn't part of a conflict"),
+        }
+    }
+}
+
+impl Entries {
+    /// Copy the resolved entries out of the index, so they can be changed
+    fn collect<'a>(all: impl Iterator<Item = EntryRef<'a>>) -> EntriesMap {
+        all.filter(|entry| entry.stage() == Stage::Resolved)
+            .map(|entry| (entry.key().to_owned(), entry.to_entry()))
+            .collect()
+    }
+
+    /// The first time, the entries are copied out of the map
+    fn to_mut(&mut self) -> &mut EntriesMap {
+        if let Self::Mapped(mapped) = self {
+            *self = Self::Owned(Self::collect(mapped.entries()));
+        }
+        match self {
+            Self::Owned(entries) => entries,
+            Self::Mapped(_) => unreachable!("Just copied"),
+        }
+    }
+}
+
 type ParentsMap = BTreeMap<BString, BTreeSet<BString>>;
 
 #[derive(Debug)]
@@ -137,11 +320,12 @@ pub struct IndexMut<'i> {
 
 impl<'i> IndexMut<'i> {
     fn new(index: &'i mut Index) -> Result<Self, OpenForModificationsError> {
-        let lock = LockedFile::acquire(&index.path)?;
+        let mut lock = LockedFile::acquire_with(&index.path, &index.lock_options)?;
+        lock.set_fsync(index.fsync);
 
         let mut parents = BTreeMap::new();
-        for entry in index.entries() {
-            Self::populate_parents_for(&mut parents, entry)
+        for entry in index.entries.to_mut().values() {
+            Self::populate_parents_for(&mut parents, entry);
         }
 
         Ok(Self {
@@ -151,17 +335,61 @@ impl<'i> IndexMut<'i> {
         })
     }
 
+    /// Adding a path resolves any conflict recorded for it.
     pub fn add(&mut self, entry: Entry) {
+        let entry = entry.with_stage(Stage::Resolved);
         Self::populate_parents_for(&mut self.parents, &entry);
         self.discard_conflicts_with(&entry.path);
-        self.index.entries.insert(entry.key().to_owned(), entry);
+        self.index.conflicts.remove(entry.key());
+        self.entries_mut().insert(entry.key().to_owned(), entry);
+    }
+
+    /// Record an unmerged path, replacing any resolved entry for it. The
+    /// stage of each entry is set according to the side it's given as.
+    pub fn add_conflict(&mut self, path: &WsPath, conflict: Conflict) {
+        self.remove(path);
+
+        let Conflict { base, ours, theirs } = conflict;
+        let mut staged = Conflict::default();
+        for (entry, stage) in [
+            (base, Stage::Base),
+            (ours, Stage::Ours),
+            (theirs, Stage::Theirs),
+        ] {
+            if let Some(entry) = entry {
+                staged.insert(entry.with_stage(stage));
+            }
+        }
+
+        if staged.entries().next().is_some() {
+            self.index.conflicts.insert(path.to_bstring(), staged);
+        }
+    }
+
+    /// Copied out of the map when the index was opened for modification
+    fn entries_mut(&mut self) -> &mut EntriesMap {
+        self.index.entries.to_mut()
+    }
+
+    /// All entries, including conflict stages, in the order they're stored.
+    fn all_entries(&mut self) -> Vec<&Entry> {
+        let Index {
+            entries, conflicts, ..
+        } = &mut *self.index;
+        let mut all = entries
+            .to_mut()
+            .values()
+            .chain(conflicts.values().flat_map(Conflict::entries))
+            .collect::<Vec<_>>();
+        all.sort_by(|a, b| (a.key(), a.stage()).cmp(&(b.key(), b.stage())));
+        all
     }
 
     fn populate_parents_for(parents: &mut ParentsMap, entry: &Entry) {
         for parent in entry.path.parents() {
             parents
                 .entry(parent.as_bstr().to_owned())
-                .or_insert_with(BTreeSet::new)
+                .or_default()
                 .insert(entry.path.to_bstring());
         }
     }
@@ -169,7 +397,8 @@ impl<'i> IndexMut<'i> {
     fn discard_conflicts_with(&mut self, path: &WsPath) {
         // If the new entry is lib/index/foo, remove lib and index.
         for parent in path.parents() {
-            self.index.entries.remove(parent.as_bstr());
+            self.entries_mut().remove(parent.as_bstr());
+            self.index.conflicts.remove(parent.as_bstr());
         }
 
         // If the new entry is lib, remove lib/index/foo and lib/index
@@ -188,22 +417,36 @@ impl<'i> IndexMut<'i> {
         }
     }
 
+    pub fn set_skip_worktree(
+        &mut self,
+        path: &WsPath,
+        skip_worktree: bool,
+    ) -> Result<(), NonexistentEntryError> {
+        let entry = self
+            .entries_mut()
+            .get_mut(path.as_bstr())
+            .ok_or(NonexistentEntryError)?;
+        entry.set_skip_worktree(skip_worktree);
+        Ok(())
+    }
+
     pub fn update_stat(
         &mut self,
         path: &WsPath,
         stat: Stat,
     ) -> Result<Stat, NonexistentEntryError> {
         let entry = self
-            .index
-            .entries
+            .entries_mut()
             .get_mut(path.as_bstr())
             .ok_or(NonexistentEntryError)?;
         let old = entry.update_stat(stat);
         Ok(old)
     }
 
+    /// Also discards any conflict recorded for the path.
     pub fn remove(&mut self, path: &WsPath) -> Option<Entry> {
-        if let Some(entry) = self.index.entries.remove(path.as_bstr()) {
+        self.index.conflicts.remove(path.as_bstr());
+        if let Some(entry) = self.entries_mut().remove(path.as_bstr()) {
             for parent in path.parents() {
                 if let Some(children) = self.parents.get_mut(parent.as_bstr()) {
                     children.remove(path.as_bstr());
@@ -219,26 +462,41 @@ impl<'i> IndexMut<'i> {
         }
     }
 
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(level = "trace", skip(self), fields(path = ?self.index.path))
+    )]
     pub fn commit(mut self) -> Result<(), CommitError> {
         let mut lock = self.lock.take().expect("Has a lock");
+        let mut buffered = BufWriter::new(&mut lock);
+
+        let mut out = WithDigest::new(&SHA1, &mut buffered);
 
-        let mut out = WithDigest::new(&SHA1, &mut lock);
+        let entries = self.all_entries();
+        let version = if entries.iter().any(|entry| entry.is_extended()) {
+            Index::EXTENDED_VERSION
+        } else {
+            Index::VERSION
+        };
 
         out.write_all(Index::SIG)?; // offset 0
-        out.write_u32::<NetworkEndian>(Index::VERSION)?; // offset 4
+        out.write_u32::<NetworkEndian>(version)?; // offset 4
 
-        let size = self.index.entries.len().try_into().expect("Len overflowed");
+        let size = entries.len().try_into().expect("Len overflowed");
         out.write_u32::<NetworkEndian>(size)?; // offset 8
 
-        for entry in self.entries() {
+        for entry in entries {
             entry.write_to_index(&mut out)?;
         }
 
         let hash = out.finish();
-        lock.write_all(hash.as_ref())?; // offset
+        buffered.write_all(hash.as_ref())?; // offset
+        buffered.flush()?;
+        drop(buffered);
 
         lock.commit()?;
 
+        trace!(entries = size, "Committed index");
         Ok(())
     }
 }
@@ -247,7 +505,7 @@ impl Deref for IndexMut<'_> {
     type Target = Index;
 
     fn deref(&self) -> &Self::Target {
-        &self.index
+        self.index
     }
 }
 
@@ -264,7 +522,7 @@ impl PartialEq for IndexMut<'_> {
 pub enum LoadError {
     /// Failed to read index file, corrupt
     Corrupt(#[from] CorruptError),
-    /// Only version 2 of the index file is supported, but index is version {0}
+    /// Only versions 2 and 3 of the index file are supported, but index is version {0}
     UnsupportedVersion(u32),
     /// Performing IO
     Io(#[from] io::Error),
@@ -294,6 +552,20 @@ pub struct NonexistentEntryError;
 /// Failed to commit index
 pub struct CommitError(#[from] io::Error);
 
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum RefreshError {
+    /// Failed to load index
+    Load(#[from] LoadError),
+    /// Failed to open index for modifications
+    Open(#[from] OpenForModificationsError),
+    /// Failed to load attributes
+    Attributes(#[from] attributes::LoadError),
+    /// Failed to check file
+    Check(#[from] IsUnchangedError),
+    /// Failed to commit changes
+    Commit(#[from] CommitError),
+}
+
 #[derive(Debug, displaydoc::Display, thiserror::Error)]
 /// Corrupt index
 pub enum CorruptError {
@@ -301,17 +573,26 @@ pub enum CorruptError {
     MissingSignature,
     /// Failed checksum validation
     IncorrectChecksum,
+    /// Index has extension {0}, which isn't supported
+    UnsupportedExtension(BString),
+    /// Index extension is truncated
+    TruncatedExtension,
+    /// Index has an entry without a path
+    EmptyPath,
+    /// Index has an invalid path
+    InvalidPath(#[source] InvalidPathError),
 }
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::{fs, path::PathBuf};
 
     use super::*;
     use crate::{
         core::{Oid, WsPath},
         test_support::init,
     };
+    use bstr::ByteSlice;
     use insta::assert_debug_snapshot;
     use pretty_assertions::assert_eq;
 
@@ -320,18 +601,32 @@ mod tests {
         init();
 
         let sample = hex::decode(SAMPLE_INDEX)?;
-        let actual = Index::load_entries_from(&*sample)?;
+        let (actual, _conflicts) = Index::load_entries_from(&sample)?;
 
         assert_debug_snapshot!(actual);
 
         Ok(())
     }
 
+    #[test]
+    fn rejects_paths_outside_workspace() -> eyre::Result<()> {
+        let mut data = Vec::new();
+        entry_fixture("../escape.txt").write_to_index(&mut data)?;
+        assert!(matches!(
+            Entry::parse_from_index(&mut &*data),
+            Err(LoadError::Corrupt(CorruptError::InvalidPath(_)))
+        ));
+        Ok(())
+    }
+
     fn index_fixture() -> eyre::Result<(tempfile::NamedTempFile, Index)> {
         let file = tempfile::NamedTempFile::new()?;
         let index = Index {
-            entries: BTreeMap::<BString, Entry>::new(),
+            entries: Entries::Owned(BTreeMap::new()),
+            conflicts: BTreeMap::new(),
             path: file.path().to_owned(),
+            fsync: false,
+            lock_options: LockOptions::default(),
         };
 
         Ok((file, index))
@@ -367,12 +662,9 @@ mod tests {
 
         index.add(entry_fixture("alice.txt/nested.txt"));
 
-        let actual = index.entries().map(|e| e.path.clone()).collect::<Vec<_>>();
+        let actual = index.entries().map(|e| e.key()).collect::<Vec<_>>();
 
-        let expected = vec![
-            WsPath::new_unchecked("alice.txt/nested.txt"),
-            WsPath::new_unchecked("bob.txt"),
-        ];
+        let expected = vec!["alice.txt/nested.txt", "bob.txt"];
 
         assert_eq!(expected, actual);
 
@@ -391,12 +683,9 @@ mod tests {
 
         index.add(entry_fixture("nested"));
 
-        let actual = index.entries().map(|e| e.path.clone()).collect::<Vec<_>>();
+        let actual = index.entries().map(|e| e.key()).collect::<Vec<_>>();
 
-        let expected = vec![
-            WsPath::new_unchecked("alice.txt"),
-            WsPath::new_unchecked("nested"),
-        ];
+        let expected = vec!["alice.txt", "nested"];
 
         assert_eq!(expected, actual);
 
@@ -416,18 +705,105 @@ mod tests {
 
         index.add(entry_fixture("nested"));
 
-        let actual = index.entries().map(|e| e.path.clone()).collect::<Vec<_>>();
+        let actual = index.entries().map(|e| e.key()).collect::<Vec<_>>();
 
-        let expected = vec![
-            WsPath::new_unchecked("alice.txt"),
-            WsPath::new_unchecked("nested"),
-        ];
+        let expected = vec!["alice.txt", "nested"];
 
         assert_eq!(expected, actual);
 
         Ok(())
     }
 
+    #[test]
+    fn round_trips_conflicts() -> eyre::Result<()> {
+        init();
+
+        let (file, mut index) = index_fixture()?;
+        let mut index_mut = index.modify()?;
+
+        index_mut.add(entry_fixture("a.txt"));
+        index_mut.add(entry_fixture("c.txt"));
+        index_mut.add(entry_fixture("b.txt"));
+        let conflict = Conflict {
+            base: Some(entry_fixture("b.txt")),
+            ours: None,
+            theirs: Some(entry_fixture("b.txt")),
+        };
+        index_mut.add_conflict(&WsPath::new_unchecked("b.txt"), conflict);
+        index_mut.commit()?;
+
+        let (entries, conflicts) = Index::load_entries_from(&fs::read(file.path())?)?;
+        let actual = entries.keys().collect::<Vec<_>>();
+        assert_eq!(vec!["a.txt", "c.txt"], actual);
+
+        let conflict = &conflicts[b"b.txt".as_bstr()];
+        assert_eq!(Some(Stage::Base), conflict.base.as_ref().map(Entry::stage));
+        assert_eq!(None, conflict.ours);
+        assert_eq!(
+            Some(Stage::Theirs),
+            conflict.theirs.as_ref().map(Entry::stage)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_skip_worktree() -> eyre::Result<()> {
+        init();
+
+        let (file, mut index) = index_fixture()?;
+        let mut index_mut = index.modify()?;
+
+        index_mut.add(entry_fixture("a.txt"));
+        index_mut.add(entry_fixture("some/longer/b.txt"));
+        index_mut.set_skip_worktree(&WsPath::new_unchecked("some/longer/b.txt"), true)?;
+        index_mut.commit()?;
+
+        let bytes = fs::read(file.path())?;
+        assert_eq!(3_u32.to_be_bytes(), bytes[4..8]);
+
+        let (entries, _conflicts) = Index::load_entries_from(&bytes)?;
+        let actual = entries
+            .values()
+            .map(|entry| (entry.key(), entry.skip_worktree()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (b"a.txt".as_bstr(), false),
+                (b"some/longer/b.txt".as_bstr(), true)
+            ],
+            actual
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn adding_resolves_conflict() -> eyre::Result<()> {
+        init();
+
+        let (_file, mut index) = index_fixture()?;
+        let mut index = index.modify()?;
+        let path = WsPath::new_unchecked("a.txt");
+
+        let conflict = Conflict {
+            ours: Some(entry_fixture("a.txt")),
+            ..Conflict::default()
+        };
+        index.add_conflict(&path, conflict);
+        assert!(index.conflict(&path).is_some());
+        assert!(index.is_tracked_file(&path));
+
+        index.add(entry_fixture("a.txt"));
+        assert!(index.conflict(&path).is_none());
+        assert_eq!(
+            Some(Stage::Resolved),
+            index.entry(&path).map(|entry| entry.stage())
+        );
+
+        Ok(())
+    }
+
     const SAMPLE_INDEX: &str = "\
 4449524300000002000000036084db442e8f6d7c6084db442e8f6d7c0000\
 fd0100a421bd000081a4000003e8000003e800000000e69de29bb2d1d643\
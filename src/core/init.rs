@@ -0,0 +1,183 @@
+//! Creating repositories, like `git init`
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use bstr::ByteSlice;
+use tracing::instrument;
+
+use super::{
+    platform,
+    refs::{self, Refs},
+    repo::InitError,
+    Config, Repo, Workspace,
+};
+
+/// The branch HEAD points to if no other is given
+pub const DEFAULT_BRANCH: &str = "master";
+
+/// How a repository is created, by default with a workspace in the
+/// directory and the git directory in its `.git`. Set up with the methods
+/// and finish with [`Builder::init`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Builder {
+    dir: PathBuf,
+    bare: bool,
+    initial_branch: Option<String>,
+    template: Option<PathBuf>,
+    separate_git_dir: Option<PathBuf>,
+    object_format: ObjectFormat,
+}
+
+/// How objects are named, like `git init --object-format`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ObjectFormat {
+    #[default]
+    Sha1,
+    /// Not supported yet
+    Sha256,
+}
+
+impl fmt::Display for ObjectFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+        })
+    }
+}
+
+impl Builder {
+    /// The workspace, or the repository itself if it's bare
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            bare: false,
+            initial_branch: None,
+            template: None,
+            separate_git_dir: None,
+            object_format: ObjectFormat::default(),
+        }
+    }
+
+    /// Without a workspace, with `core.bare` set
+    #[must_use]
+    pub fn bare(mut self, bare: bool) -> Self {
+        self.bare = bare;
+        self
+    }
+
+    /// The branch HEAD points to, by default [`DEFAULT_BRANCH`]
+    #[must_use]
+    pub fn initial_branch(mut self, name: impl Into<String>) -> Self {
+        self.initial_branch = Some(name.into());
+        self
+    }
+
+    /// A directory whose files are copied into the git directory, like
+    /// hooks or `info/exclude`. A `config` in it is kept, with the core
+    /// settings we need set over it.
+    #[must_use]
+    pub fn template(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.template = Some(dir.into());
+        self
+    }
+
+    /// Where the git directory goes instead of the workspace's `.git`, which
+    /// becomes a file pointing to it
+    #[must_use]
+    pub fn separate_git_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.separate_git_dir = Some(dir.into());
+        self
+    }
+
+    #[must_use]
+    pub fn object_format(mut self, format: ObjectFormat) -> Self {
+        self.object_format = format;
+        self
+    }
+
+    #[instrument(err)]
+    pub fn init(self) -> Result<Repo, InitError> {
+        if self.object_format != ObjectFormat::Sha1 {
+            return Err(InitError::UnsupportedObjectFormat(self.object_format));
+        }
+        let branch = self.initial_branch.as_deref().unwrap_or(DEFAULT_BRANCH);
+        let head = format!("refs/heads/{branch}");
+        if !refs::is_valid_name(head.as_bytes().as_bstr()) {
+            return Err(InitError::InvalidBranch(branch.to_owned()));
+        }
+
+        let (git_dir, workspace_dir) = if self.bare {
+            if self.separate_git_dir.is_some() {
+                return Err(InitError::BareSeparate);
+            }
+            (self.dir, None)
+        } else {
+            fs::create_dir_all(&self.dir)
+                .map_err(|e| InitError::CreateWorkspace(self.dir.clone(), e))?;
+            let dot_git = self.dir.join(".git");
+            if dot_git
+                .try_exists()
+                .map_err(|e| InitError::Open(dot_git.clone(), e))?
+            {
+                return Err(InitError::Exists(dot_git));
+            }
+            let git_dir = self.separate_git_dir.clone().unwrap_or(dot_git);
+            (git_dir, Some(self.dir))
+        };
+        if Repo::is_git_dir(&git_dir) {
+            return Err(InitError::Exists(git_dir));
+        }
+        Repo::init_git_dir(&git_dir)?;
+        if let Some(template) = &self.template {
+            copy_template(template, &git_dir)?;
+        }
+
+        if let (Some(dir), Some(_)) = (&workspace_dir, &self.separate_git_dir) {
+            let target = git_dir
+                .canonicalize()
+                .map_err(|e| InitError::Open(git_dir.clone(), e))?;
+            let contents = [b"gitdir: ", &platform::into_bytes(target)[..], b"\n"].concat();
+            let git_file = dir.join(".git");
+            fs::write(&git_file, contents).map_err(|e| InitError::Write(git_file, e))?;
+        }
+
+        let config_path = git_dir.join("config");
+        let mut config = Config::load(&config_path)?;
+        let bare = if self.bare { "true" } else { "false" };
+        let file_mode = if cfg!(unix) { "true" } else { "false" };
+        for (name, value) in [
+            ("core.repositoryformatversion", "0"),
+            ("core.filemode", file_mode),
+            ("core.bare", bare),
+        ] {
+            config.set(name, value).expect("Valid name");
+        }
+        config.save(&config_path)?;
+
+        Refs::new(&git_dir).update_symbolic_ref(b"HEAD".as_bstr(), head.as_bytes().as_bstr())?;
+
+        Repo::init_with(git_dir, workspace_dir.map(Workspace::new), config)
+    }
+}
+
+/// Copies what isn't in `git_dir` yet
+fn copy_template(template: &Path, git_dir: &Path) -> Result<(), InitError> {
+    for entry in walkdir::WalkDir::new(template).min_depth(1) {
+        let entry = entry.map_err(|e| InitError::Template(template.to_owned(), e.into()))?;
+        let rel = entry
+            .path()
+            .strip_prefix(template)
+            .expect("Within template");
+        let dst = git_dir.join(rel);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dst).map_err(|e| InitError::Write(dst, e))?;
+        } else if !dst.exists() {
+            fs::copy(entry.path(), &dst).map_err(|e| InitError::Write(dst, e))?;
+        }
+    }
+    Ok(())
+}
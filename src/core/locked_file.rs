@@ -1,9 +1,13 @@
 use std::{
     fs,
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant, SystemTime},
 };
-use tracing::{error, info};
+use tracing::{debug, error, info, trace, warn};
+
+use super::{fsync, platform};
 
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum Error {
@@ -15,6 +19,31 @@ pub enum Error {
     NotFound(#[source] io::Error),
 }
 
+/// How hard to try for a lock someone else holds. The default gives up at
+/// once and never breaks locks, like git.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LockOptions {
+    /// Keep retrying a contested lock for this long
+    pub timeout: Duration,
+    /// The wait before the first retry, doubled after each (up to a second)
+    pub backoff: Duration,
+    /// Break locks at least this old, unless the process that took them is
+    /// still running. See [`is_stale`].
+    pub stale_after: Option<Duration>,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::ZERO,
+            backoff: Duration::from_millis(10),
+            stale_after: None,
+        }
+    }
+}
+
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub struct LockedFile {
     path: PathBuf,
@@ -23,25 +52,62 @@ pub struct LockedFile {
     lock: Option<fs::File>,
     /// May not exist
     protected: Option<fs::File>,
+    /// Sync to disk on commit, see [`Self::set_fsync`]
+    fsync: bool,
+    /// Whether we recorded ourselves as the owner, see [`owner`]
+    owner_recorded: bool,
 }
 
 impl LockedFile {
     pub fn acquire<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
+        Self::acquire_with(path, &LockOptions::default())
+    }
+
+    /// Like [`Self::acquire`], but retrying and breaking stale locks as the
+    /// options say
+    pub fn acquire_with<P: Into<PathBuf>>(path: P, options: &LockOptions) -> Result<Self, Error> {
         let path = path.into();
-        let lock_path = path.with_extension("lock");
+        let start = Instant::now();
+        let mut backoff = options.backoff;
+        loop {
+            match Self::try_acquire(path.clone(), options.stale_after.is_some()) {
+                Err(Error::Contested(err)) => {
+                    if let Some(stale_after) = options.stale_after {
+                        if break_stale_lock(&path, stale_after)? {
+                            continue;
+                        }
+                    }
 
-        let lock = match fs::File::with_options()
+                    let elapsed = start.elapsed();
+                    if elapsed >= options.timeout {
+                        return Err(Error::Contested(err));
+                    }
+                    thread::sleep(backoff.min(options.timeout.saturating_sub(elapsed)));
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The owner is only recorded if it's asked for, as only those breaking
+    /// stale locks read it and it's another file written for every lock
+    fn try_acquire(path: PathBuf, record_owner: bool) -> Result<Self, Error> {
+        let lock_path = lock_path(&path);
+
+        let lock = match fs::File::options()
             .write(true)
             .create_new(true)
             .open(&lock_path)
         {
             Ok(lock) => lock,
             Err(err) => {
+                trace!(?lock_path, %err, "Failed to acquire lock");
                 return match err.kind() {
                     io::ErrorKind::AlreadyExists => Err(Error::Contested(err)),
                     io::ErrorKind::NotFound => Err(Error::NotFound(err)),
                     _ => Err(Error::Io(err)),
-                }
+                };
             }
         };
 
@@ -54,11 +120,23 @@ impl LockedFile {
             },
         }?;
 
+        let owner_recorded = record_owner
+            && match fs::write(owner_path(&path), format!("{}\n", process::id())) {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!(?lock_path, %err, "Failed to record lock owner");
+                    false
+                }
+            };
+
+        trace!(?lock_path, "Acquired lock");
         Ok(Self {
             path,
             lock_path,
             lock: Some(lock),
             protected,
+            fsync: false,
+            owner_recorded,
         })
     }
 
@@ -68,27 +146,46 @@ impl LockedFile {
         self.protected.as_ref()
     }
 
+    /// Whether committing syncs the new contents, and then the directory
+    /// they're renamed into, to disk. See [`fsync`].
+    pub fn set_fsync(&mut self, fsync: bool) {
+        self.fsync = fsync;
+    }
+
     pub fn commit(mut self) -> io::Result<()> {
         let mut lock = self.lock.take().unwrap();
         lock.flush()?;
+        fsync::file(&lock, self.fsync)?;
         drop(lock);
 
         if let Some(protected) = self.protected.take() {
             drop(protected);
         }
 
+        // Once the lock is renamed someone else can take it and record
+        // themselves as its owner
+        if self.owner_recorded {
+            remove_owner(&self.path)?;
+        }
         fs::rename(&self.lock_path, &self.path)?;
+        if let Some(dir) = self.path.parent().filter(|dir| dir != &Path::new("")) {
+            fsync::dir(dir, self.fsync)?;
+        }
 
+        trace!(path = ?self.path, "Committed lock");
         Ok(())
     }
 
     pub fn rollback(mut self) -> io::Result<()> {
-        self._rollback()
+        self.remove_lock()
     }
 
-    fn _rollback(&mut self) -> io::Result<()> {
+    fn remove_lock(&mut self) -> io::Result<()> {
         let lock = self.lock.take().unwrap();
         drop(lock);
+        if self.owner_recorded {
+            remove_owner(&self.path)?;
+        }
         fs::remove_file(&self.lock_path)
     }
 
@@ -97,6 +194,123 @@ impl LockedFile {
     }
 }
 
+/// The process that took the lock on `path`, if it's held and the owner was
+/// recorded. Owners are only recorded by those that break stale locks, see
+/// [`LockOptions::stale_after`].
+pub fn owner(path: impl AsRef<Path>) -> io::Result<Option<u32>> {
+    match fs::read_to_string(owner_path(path.as_ref())) {
+        Ok(owner) => Ok(owner.trim().parse().ok()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether the lock on `path` is at least `stale_after` old and its owner
+/// isn't running. Locks with no recorded owner (like git's) go by age alone,
+/// but where we can't tell if the owner is running it might be, so they're
+/// never stale.
+pub fn is_stale(path: impl AsRef<Path>, stale_after: Duration) -> io::Result<bool> {
+    Ok(stale_lock(path.as_ref(), stale_after)?.is_some())
+}
+
+/// The lock file on `path` if it's stale
+fn stale_lock(path: &Path, stale_after: Duration) -> io::Result<Option<platform::Metadata>> {
+    let meta = match fs::symlink_metadata(lock_path(path)) {
+        Ok(meta) => platform::metadata(&meta),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let age = SystemTime::now()
+        .duration_since(meta.mtime)
+        .unwrap_or_default();
+    if age < stale_after {
+        return Ok(None);
+    }
+    let stale = match owner(path)? {
+        Some(pid) => platform::process_running(pid) == Some(false),
+        None => true,
+    };
+    Ok(stale.then_some(meta))
+}
+
+/// Breaks the lock on `path` if it's stale, see [`is_stale`]. Returns whether
+/// it was broken.
+///
+/// Another process could break the same lock and take its own in between,
+/// so rather than removing whatever is there the lock is renamed aside, and
+/// only removed if it's still the one found stale. Otherwise it's put back.
+pub fn break_stale_lock(path: impl AsRef<Path>, stale_after: Duration) -> io::Result<bool> {
+    let path = path.as_ref();
+    match stale_lock(path, stale_after)? {
+        Some(stale) => break_if_unchanged(path, &stale),
+        None => Ok(false),
+    }
+}
+
+fn break_if_unchanged(path: &Path, stale: &platform::Metadata) -> io::Result<bool> {
+    let lock = lock_path(path);
+    // Ends in `.lock` like the owner file, and is ours alone
+    let aside = path.with_extension(format!("{}.stale.lock", process::id()));
+    match fs::rename(&lock, &aside) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    }
+
+    let moved = platform::metadata(&fs::symlink_metadata(&aside)?);
+    // The ctime changes with the rename
+    let identity = |meta: &platform::Metadata| (meta.dev, meta.ino, meta.mtime, meta.size);
+    if identity(&moved) != identity(stale) {
+        // Linking fails rather than replacing a lock taken since
+        match fs::hard_link(&aside, &lock) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+            _ => {}
+        }
+        fs::remove_file(&aside)?;
+        debug!(?path, "Lock was replaced before it could be broken");
+        return Ok(false);
+    }
+
+    fs::remove_file(&aside)?;
+    remove_owner(path)?;
+    warn!(?path, "Broke stale lock");
+    Ok(true)
+}
+
+/// Remove the lock on `path`, whoever holds it. Only safe if they're gone,
+/// see [`is_stale`]. Returns whether there was a lock.
+pub fn break_lock(path: impl AsRef<Path>) -> io::Result<bool> {
+    let path = path.as_ref();
+    let existed = match fs::remove_file(lock_path(path)) {
+        Ok(()) => true,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+        Err(err) => return Err(err),
+    };
+    remove_owner(path)?;
+    info!(?path, existed, "Broke lock");
+    Ok(existed)
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    path.with_extension("lock")
+}
+
+/// Beside the lock, as the lock itself holds the new contents. Appended to
+/// the lock's name so each lock has its own, and skipped under `refs/` (see
+/// [`is_valid_name`](super::refs::is_valid_name)).
+fn owner_path(path: &Path) -> PathBuf {
+    let mut name = lock_path(path).into_os_string();
+    name.push(".owner");
+    PathBuf::from(name)
+}
+
+fn remove_owner(path: &Path) -> io::Result<()> {
+    match fs::remove_file(owner_path(path)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
 impl Write for LockedFile {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.expect_lock().write(buf)
@@ -111,9 +325,96 @@ impl Drop for LockedFile {
     fn drop(&mut self) {
         if self.lock.is_some() {
             info!(path=?self.path, "LockedFile never committed, cancelling");
-            if let Err(err) = self._rollback() {
+            if let Err(err) = self.remove_lock() {
                 error!("Failed to remove lock: {:?}", err);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn retries_then_times_out() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("index");
+        let held = LockedFile::acquire(&path)?;
+        assert_eq!(None, owner(&path)?);
+
+        let options = LockOptions {
+            timeout: Duration::from_millis(50),
+            ..LockOptions::default()
+        };
+        let start = Instant::now();
+        let err = LockedFile::acquire_with(&path, &options).unwrap_err();
+        assert!(matches!(err, Error::Contested(_)));
+        assert!(start.elapsed() >= options.timeout);
+
+        held.rollback()?;
+        assert_eq!(None, owner(&path)?);
+        LockedFile::acquire_with(&path, &options)?.commit()?;
+        assert!(!dir.path().join("index.lock.owner").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn breaks_stale_locks() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("index");
+        let lock = dir.path().join("index.lock");
+        fs::write(&lock, "left behind")?;
+        let hour_ago = SystemTime::now() - Duration::from_hours(1);
+        filetime::set_file_mtime(&lock, hour_ago.into())?;
+
+        let options = LockOptions {
+            stale_after: Some(Duration::from_hours(2)),
+            ..LockOptions::default()
+        };
+        assert!(!is_stale(&path, Duration::from_hours(2))?);
+        assert!(LockedFile::acquire_with(&path, &options).is_err());
+
+        // Our own process is still running, so its locks are never stale
+        let owner_file = dir.path().join("index.lock.owner");
+        fs::write(&owner_file, process::id().to_string())?;
+        assert!(!is_stale(&path, Duration::from_mins(1))?);
+        fs::remove_file(&owner_file)?;
+
+        let options = LockOptions {
+            stale_after: Some(Duration::from_mins(1)),
+            ..LockOptions::default()
+        };
+        let held = LockedFile::acquire_with(&path, &options)?;
+        assert_eq!(Some(process::id()), owner(&path)?);
+        held.rollback()?;
+        assert!(!lock.exists());
+        assert!(!owner_file.exists());
+        assert!(!break_lock(&path)?);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_locks_taken_since_they_were_found_stale() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("index");
+        let lock = dir.path().join("index.lock");
+        fs::write(&lock, "left behind")?;
+        let hour_ago = SystemTime::now() - Duration::from_hours(1);
+        filetime::set_file_mtime(&lock, hour_ago.into())?;
+        let stale = stale_lock(&path, Duration::from_mins(1))?.expect("Stale");
+
+        // Someone else breaks it and takes the lock first
+        fs::remove_file(&lock)?;
+        let theirs = LockedFile::acquire(&path)?;
+        assert!(!break_if_unchanged(&path, &stale)?);
+        assert_eq!("", fs::read_to_string(&lock)?);
+        let aside = format!("index.{}.stale.lock", process::id());
+        assert!(!dir.path().join(aside).exists());
+
+        theirs.rollback()?;
+        assert_eq!(0, fs::read_dir(dir.path())?.count());
+        Ok(())
+    }
+}
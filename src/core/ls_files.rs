@@ -0,0 +1,165 @@
+//! Listing the index and workspace like `git ls-files`, as typed records
+//! rather than lines for scripts to parse
+
+use tracing::instrument;
+
+use crate::core::{
+    cancel::CancelToken,
+    db::Blob,
+    index::{self, Stage},
+    repo::StatusError,
+    stat::Mode,
+    MappedIndex, Oid, Pathspecs, Repo, Status, StatusOptions, WsPath,
+};
+
+/// What to list. Files in the index are listed if nothing else is asked for,
+/// like git.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[allow(clippy::struct_excessive_bools)] // Flags, like git ls-files'
+pub struct LsFilesOptions {
+    /// Files in the index, like `--cached`
+    pub cached: bool,
+    /// Tracked files missing from the workspace, like `--deleted`
+    pub deleted: bool,
+    /// Tracked files that differ from the index, including deleted ones, like
+    /// `--modified`
+    pub modified: bool,
+    /// Untracked files, like `--others --exclude-standard`
+    pub others: bool,
+    /// With `others`, list the untracked files that are ignored instead of
+    /// those that aren't, like `--ignored`
+    pub ignored: bool,
+    /// List every stage of unmerged paths, like `--stage`, rather than each
+    /// path once by its first stage. Implies `cached`.
+    pub stage: bool,
+    /// Checked while the workspace is compared
+    pub cancel: CancelToken,
+}
+
+/// Files of the same path are listed as [`Self::Other`], then
+/// [`Self::Cached`], then [`Self::Deleted`], then [`Self::Modified`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ListedFile {
+    Cached(CachedFile),
+    Deleted(WsPath),
+    Modified(WsPath),
+    Other(WsPath),
+}
+
+/// An entry of the index
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CachedFile {
+    pub path: WsPath,
+    pub mode: Mode,
+    pub oid: Oid<Blob>,
+    pub stage: Stage,
+}
+
+impl ListedFile {
+    pub fn path(&self) -> &WsPath {
+        match self {
+            Self::Cached(file) => &file.path,
+            Self::Deleted(path) | Self::Modified(path) | Self::Other(path) => path,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Other(_) => 0,
+            Self::Cached(_) => 1,
+            Self::Deleted(_) => 2,
+            Self::Modified(_) => 3,
+        }
+    }
+}
+
+impl Repo {
+    /// Every file of the kinds asked for, sorted by path. See
+    /// [`Self::ls_files_matching`].
+    pub fn ls_files(
+        &mut self,
+        options: &LsFilesOptions,
+    ) -> Result<impl Iterator<Item = ListedFile>, LsFilesError> {
+        self.ls_files_matching(&Pathspecs::new(Vec::new()), options)
+    }
+
+    /// Files in the index are read straight from it, so listing only those
+    /// works in bare repositories too. Anything else compares the workspace
+    /// as [`Self::status_matching`] does.
+    #[instrument(err)]
+    pub fn ls_files_matching(
+        &mut self,
+        pathspecs: &Pathspecs,
+        options: &LsFilesOptions,
+    ) -> Result<impl Iterator<Item = ListedFile>, LsFilesError> {
+        let compare = options.deleted || options.modified || options.others;
+        let cached = options.cached || options.stage || !compare;
+        let mut listed = Vec::new();
+
+        if cached {
+            let index = MappedIndex::open_file(self.index.path())?;
+            let mut last = None;
+            for entry in index.entries() {
+                if !options.stage && last == Some(entry.key()) {
+                    continue;
+                }
+                last = Some(entry.key());
+                let path = WsPath::new_unchecked_bytes(entry.key());
+                if pathspecs.matches(&path) {
+                    listed.push(ListedFile::Cached(CachedFile {
+                        path,
+                        mode: entry.mode(),
+                        oid: entry.oid,
+                        stage: entry.stage(),
+                    }));
+                }
+            }
+        }
+
+        if compare {
+            let status_options = StatusOptions {
+                ignored: options.others && options.ignored,
+                cancel: options.cancel.clone(),
+            };
+            for (path, status) in self.status_matching(pathspecs, &status_options)? {
+                match status.workspace {
+                    Status::Untracked if options.others && !options.ignored => {
+                        listed.push(ListedFile::Other(path));
+                    }
+                    Status::Ignored if options.others => listed.push(ListedFile::Other(path)),
+                    // Paths removed from the index aren't tracked anymore
+                    Status::Deleted if status.index != Status::Deleted => {
+                        if options.deleted {
+                            listed.push(ListedFile::Deleted(path.clone()));
+                        }
+                        if options.modified {
+                            listed.push(ListedFile::Modified(path));
+                        }
+                    }
+                    Status::Modified | Status::TypeChanged if options.modified => {
+                        listed.push(ListedFile::Modified(path));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Stable, so the stages of a path stay in order
+        listed.sort_by(|a, b| a.path().cmp(b.path()).then(a.rank().cmp(&b.rank())));
+        Ok(listed.into_iter())
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum LsFilesError {
+    /// Failed to load index
+    LoadIndex(#[from] index::LoadError),
+    /// Failed to compare workspace
+    Status(#[from] StatusError),
+}
@@ -0,0 +1,169 @@
+//! Listing the contents of a tree like `git ls-tree`, as typed records rather
+//! than lines for scripts to parse
+
+use bstr::{BStr, ByteSlice};
+use tracing::instrument;
+
+use crate::core::{
+    db::{self, tree, Blob, CommitRef, TagRef, Tree, UntypedOid},
+    pack::ObjectType,
+    stat::Mode,
+    Db, Oid, Pathspecs, Repo, WsPath,
+};
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct LsTreeOptions {
+    /// List the files in subtrees instead of the subtrees themselves, like
+    /// `-r`
+    pub recursive: bool,
+    /// Only list trees, like `-d`. With `recursive`, every tree is listed.
+    pub trees_only: bool,
+    /// Include the size of blobs, like `--long`
+    pub long: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ListedNode {
+    /// `None` for trees
+    pub mode: Option<Mode>,
+    /// A blob, a tree, or a commit for a submodule
+    pub object_type: ObjectType,
+    pub oid: UntypedOid,
+    /// The size of blobs, with [`LsTreeOptions::long`]
+    pub size: Option<usize>,
+    pub path: WsPath,
+}
+
+impl ListedNode {
+    /// The mode as git shows it, like `100644`, or `040000` for trees
+    pub fn mode_base8(&self) -> &'static BStr {
+        match self.mode {
+            Some(mode) => mode.as_base8(),
+            None => b"040000".as_bstr(),
+        }
+    }
+}
+
+impl Repo {
+    /// The nodes of the tree of `treeish` (a tree, or a commit or tag that
+    /// leads to one) that match the pathspecs, in order. Like git, trees on
+    /// the way to a pathspec are descended into even if they don't match it
+    /// themselves, so `dir/file` lists just that file.
+    #[instrument(err)]
+    pub fn ls_tree(
+        &mut self,
+        treeish: UntypedOid,
+        pathspecs: &Pathspecs,
+        options: &LsTreeOptions,
+    ) -> Result<impl Iterator<Item = ListedNode>, LsTreeError> {
+        let tree = self.peel_to_tree(treeish)?;
+        let mut listed = Vec::new();
+        let walk = Walk {
+            pathspecs,
+            prefixes: pathspecs.prefixes(),
+            options,
+        };
+        walk.tree(&mut self.db, &mut listed, &WsPath::root(), tree)?;
+        Ok(listed.into_iter())
+    }
+
+    fn peel_to_tree(&self, mut oid: UntypedOid) -> Result<Oid<Tree>, LsTreeError> {
+        loop {
+            let source = self.db.replaced(&oid);
+            let (ty, data) = self
+                .db
+                .load_raw(&source)?
+                .ok_or(LsTreeError::Missing(oid))?;
+            let corrupt = || LsTreeError::Corrupt(oid);
+            oid = match ty.as_bytes() {
+                b"tree" => return Ok(oid.to_typed()),
+                b"commit" => CommitRef::parse(&data)
+                    .map_err(|_| corrupt())?
+                    .tree
+                    .into_untyped(),
+                b"tag" => TagRef::parse(&data).map_err(|_| corrupt())?.object,
+                _ => return Err(LsTreeError::NotTree(oid)),
+            };
+        }
+    }
+}
+
+struct Walk<'a> {
+    pathspecs: &'a Pathspecs,
+    prefixes: Vec<WsPath>,
+    options: &'a LsTreeOptions,
+}
+
+impl Walk<'_> {
+    fn tree(
+        &self,
+        db: &mut Db,
+        listed: &mut Vec<ListedNode>,
+        root: &WsPath,
+        tree: Oid<Tree>,
+    ) -> Result<(), LsTreeError> {
+        for node in db.load(tree)?.direct_children() {
+            let path = root.join_bytes(node.name());
+            match node {
+                tree::Node::Tree { oid, .. } => {
+                    let options = self.options;
+                    let show = !options.recursive || options.trees_only;
+                    if show && self.pathspecs.matches(&path) {
+                        listed.push(ListedNode {
+                            mode: None,
+                            object_type: ObjectType::Tree,
+                            oid: oid.into_untyped(),
+                            size: None,
+                            path: path.clone(),
+                        });
+                    }
+
+                    let leads_to_match = self
+                        .prefixes
+                        .iter()
+                        .any(|prefix| prefix != &path && prefix.is_within(&path));
+                    let within = self.prefixes.iter().any(|prefix| path.is_within(prefix));
+                    if leads_to_match || (options.recursive && within) {
+                        self.tree(db, listed, &path, *oid)?;
+                    }
+                }
+                tree::Node::File(file) => {
+                    if self.options.trees_only || !self.pathspecs.matches(&path) {
+                        continue;
+                    }
+                    let (object_type, size) = if file.mode == Mode::Gitlink {
+                        (ObjectType::Commit, None)
+                    } else if self.options.long {
+                        (ObjectType::Blob, Some(db.open_blob(file.oid)?.len()))
+                    } else {
+                        (ObjectType::Blob, None)
+                    };
+                    listed.push(ListedNode {
+                        mode: Some(file.mode),
+                        object_type,
+                        oid: file.oid.into_untyped(),
+                        size,
+                        path,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum LsTreeError {
+    /// {0} not found
+    Missing(UntypedOid),
+    /// {0} is not a tree, commit or tag
+    NotTree(UntypedOid),
+    /// {0} is corrupt
+    Corrupt(UntypedOid),
+    /// Failed to load object
+    LoadRaw(#[from] db::LoadRawError),
+    /// Failed to load tree
+    LoadTree(#[from] db::LoadError<Tree>),
+    /// Failed to read size of blob
+    LoadBlob(#[from] db::LoadBytesError<Blob>),
+}
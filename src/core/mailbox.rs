@@ -0,0 +1,1042 @@
+//! Mailing commits as patches, like `git format-patch`, and committing the
+//! patches of a mailbox, like `git am`
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead},
+};
+
+use bstr::{BString, ByteSlice, ByteVec};
+use chrono::DateTime;
+use tracing::{debug, instrument};
+
+use crate::core::{
+    commit_msg::Cleanup,
+    db::{self, blob, signature, tree::FileNode, Blob, Commit, Signature, Tree},
+    index,
+    merge::{diff, FileMergeOptions, MergeError},
+    refs,
+    rev_parse::RevRange,
+    revwalk::RevWalkError,
+    stat::Mode,
+    ObjectBuilder, Oid, Repo, WsPath,
+};
+
+type Files = BTreeMap<WsPath, FileNode>;
+
+/// Lines of context around each change
+const CONTEXT: usize = 3;
+/// How wide the stat of a patch is kept, as git keeps it for mail
+const STAT_WIDTH: usize = 72;
+/// How long the subject in a patch's file name can be, like git
+const NAME_MAX: usize = 64;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FormatPatchOptions {
+    /// In place of `PATCH` in subjects like `[PATCH 1/2]`
+    pub subject_prefix: Option<String>,
+    /// Number patches like `[PATCH 1/1]` even if there's only one, like
+    /// `--numbered`
+    pub numbered: bool,
+}
+
+/// A commit as a mail
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Patch {
+    pub commit: Oid<Commit>,
+    /// Like `0001-Fix-the-thing.patch`
+    pub file_name: String,
+    pub mail: BString,
+}
+
+/// What a commit changed in a file
+struct FileChange {
+    path: WsPath,
+    old: Option<FileNode>,
+    new: Option<FileNode>,
+    old_data: BString,
+    new_data: BString,
+}
+
+/// A mail of a mailbox, parsed
+struct Mail {
+    subject: String,
+    author: Signature,
+    msg: String,
+    files: Vec<FilePatch>,
+}
+
+/// The changes a patch makes to a file
+#[derive(Debug, Default)]
+struct FilePatch {
+    path: Option<WsPath>,
+    created: bool,
+    deleted: bool,
+    new_mode: Option<Mode>,
+    hunks: Vec<PatchHunk>,
+}
+
+#[derive(Debug)]
+struct PatchHunk {
+    /// Where it applies in the old file, counting from 0
+    old_start: usize,
+    lines: Vec<(u8, Vec<u8>)>,
+}
+
+impl Repo {
+    /// Each commit of the range as a mail, oldest first, like `git
+    /// format-patch --base`: with the author in `From` and `Date`, the
+    /// message's subject in `Subject`, its body, a stat of the files changed
+    /// after `---`, and the patch. The first patch ends with the commit the
+    /// series applies to, if there is one. Merges are left out.
+    ///
+    /// Joined, the mails make up a mailbox (see [`Self::am`]).
+    #[instrument(err)]
+    pub fn format_patch(
+        &mut self,
+        range: &RevRange,
+        options: &FormatPatchOptions,
+    ) -> Result<Vec<Patch>, FormatPatchError> {
+        let mut commits = Vec::new();
+        for walked in range.walk(&self.db)? {
+            let walked = walked?;
+            if walked.parents.len() <= 1 {
+                commits.push(walked.oid.to_typed::<Commit>());
+            }
+        }
+        commits.reverse();
+
+        let total = commits.len();
+        let prefix = options.subject_prefix.as_deref().unwrap_or("PATCH");
+        let mut patches = Vec::with_capacity(total);
+        for (n, &oid) in commits.iter().enumerate() {
+            let commit = self.db.load(oid)?;
+            let old = match commit.parent {
+                Some(parent) => {
+                    let tree = self.db.load(parent)?.tree;
+                    self.db.load_tree_files(&WsPath::root(), tree)?
+                }
+                None => Files::new(),
+            };
+            let new = self.db.load_tree_files(&WsPath::root(), commit.tree)?;
+            let changes = self.file_changes(&old, &new)?;
+
+            let msg = commit.msg.to_str_lossy();
+            let (subject, body) = split_message(&msg);
+            let number = if options.numbered || total > 1 {
+                format!("{prefix} {}/{total}", n + 1)
+            } else {
+                prefix.to_owned()
+            };
+            let author = &commit.author;
+
+            let mut mail =
+                BString::from(format!("From {} Mon Sep 17 00:00:00 2001\n", oid.to_hex()));
+            mail.push_str("From: ");
+            mail.push_str(encode_header(&author.name().to_str_lossy()));
+            mail.push_str(format!(" <{}>\n", author.email()));
+            mail.push_str(format!("Date: {}\n", author.time().to_rfc2822()));
+            mail.push_str(format!("Subject: [{number}] {}\n", encode_header(&subject)));
+            if !msg.is_ascii() {
+                mail.push_str(
+                    "MIME-Version: 1.0\n\
+                     Content-Type: text/plain; charset=UTF-8\n\
+                     Content-Transfer-Encoding: 8bit\n",
+                );
+            }
+            mail.push_str(format!("\n{body}---\n"));
+            mail.push_str(diff_stat(&changes));
+            mail.push_str("\n");
+            for change in &changes {
+                mail.push_str(patch_of(change));
+            }
+            if let (0, Some(base)) = (n, commit.parent) {
+                mail.push_str(format!("\nbase-commit: {}\n", base.to_hex()));
+            }
+            mail.push_str(format!("-- \nwrit {}\n\n", env!("CARGO_PKG_VERSION")));
+
+            patches.push(Patch {
+                commit: oid,
+                file_name: format!("{:04}-{}.patch", n + 1, file_name_of(&subject)),
+                mail,
+            });
+        }
+        Ok(patches)
+    }
+
+    /// Commits the patch of each mail of the mailbox onto HEAD, with the
+    /// mail's author, and its subject and body as the message, like `git am`.
+    /// The index has to match HEAD, and the files a patch changes are
+    /// updated in the workspace as it's applied.
+    ///
+    /// Patches have to apply exactly, although their hunks can have moved.
+    /// Binary patches, renames and copies aren't supported. If a patch
+    /// doesn't apply, the commits made before it are kept.
+    #[instrument(err, skip(mbox))]
+    pub fn am(&mut self, mut mbox: impl BufRead) -> Result<Vec<Oid<Commit>>, AmError> {
+        let mut data = Vec::new();
+        mbox.read_to_end(&mut data).map_err(AmError::Read)?;
+
+        let mut made = Vec::new();
+        for mail in split_mbox(&data) {
+            let mail = parse_mail(mail)?;
+            debug!(subject = %mail.subject, "Applying");
+            made.push(self.apply_mail(mail)?);
+        }
+        Ok(made)
+    }
+
+    fn apply_mail(&mut self, mail: Mail) -> Result<Oid<Commit>, AmError> {
+        if mail.files.is_empty() {
+            return Err(AmError::Empty(mail.subject));
+        }
+        let head = self.refs.head()?.ok_or(AmError::NoHead)?;
+        let tree = self.db.load(head)?.tree;
+        let ours = self.db.load_tree_files(&WsPath::root(), tree)?;
+        self.index.reload()?;
+        if self.index.has_conflicts() {
+            return Err(AmError::Unmerged);
+        }
+        if self.has_staged(&ours) {
+            return Err(AmError::Staged);
+        }
+
+        let mut patched = ours.clone();
+        for file in &mail.files {
+            let path = file.path.clone().expect("Parsed with a path");
+            let fail = || AmError::DoesNotApply {
+                subject: mail.subject.clone(),
+                path: path.clone(),
+            };
+            let existing = patched.get(&path).cloned();
+            if file.created == existing.is_some() {
+                return Err(fail());
+            }
+            let old_data = match &existing {
+                Some(existing) => self.db.load(existing.oid)?.bytes,
+                None => BString::default(),
+            };
+            let new_data = apply_hunks(&old_data, &file.hunks).ok_or_else(fail)?;
+            if file.deleted {
+                if !new_data.is_empty() {
+                    return Err(fail());
+                }
+                patched.remove(&path);
+                continue;
+            }
+            let node = FileNode {
+                oid: blob::Builder::new(new_data).store(&self.db)?,
+                name: path.file_name().unwrap_or_default().to_owned(),
+                mode: file
+                    .new_mode
+                    .or_else(|| existing.map(|existing| existing.mode))
+                    .unwrap_or(Mode::Regular),
+            };
+            patched.insert(path, node);
+        }
+
+        // Merging from HEAD to HEAD and the patched files is just the
+        // patched files, with the workspace and index updated to them
+        self.merge_trees(&ours, &ours, &patched, &FileMergeOptions::default())?;
+        let entries = patched
+            .into_iter()
+            .map(|(path, file)| db::tree::EntryBuilder {
+                oid: file.oid,
+                path,
+                mode: file.mode,
+            });
+        let tree = db::tree::Builder::new().entries(entries).store(&self.db)?;
+        let committer = self.signature(signature::Role::Committer)?;
+        let made = db::commit::Builder::new(Some(head), tree, mail.author, committer, mail.msg)
+            .store(&self.db)?;
+        self.refs.update_head(&made)?;
+        Ok(made)
+    }
+
+    /// The files that differ between the trees, with their contents
+    fn file_changes(
+        &mut self,
+        old: &Files,
+        new: &Files,
+    ) -> Result<Vec<FileChange>, FormatPatchError> {
+        let mut paths = old.keys().chain(new.keys()).collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+
+        let mut changes = Vec::new();
+        for path in paths {
+            let (old, new) = (old.get(path).cloned(), new.get(path).cloned());
+            let same = match (&old, &new) {
+                (Some(old), Some(new)) => old.oid == new.oid && old.mode == new.mode,
+                _ => false,
+            };
+            if same {
+                continue;
+            }
+            changes.push(FileChange {
+                path: path.clone(),
+                old_data: self.diffed_contents(old.as_ref())?,
+                new_data: self.diffed_contents(new.as_ref())?,
+                old,
+                new,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// What a patch shows of a file, with submodules shown by their commit
+    fn diffed_contents(&mut self, node: Option<&FileNode>) -> Result<BString, FormatPatchError> {
+        Ok(match node {
+            None => BString::default(),
+            Some(node) if node.mode == Mode::Gitlink => {
+                format!("Subproject commit {}\n", node.oid.to_hex()).into()
+            }
+            Some(node) => self.db.load(node.oid)?.bytes,
+        })
+    }
+}
+
+impl FileChange {
+    fn is_binary(&self) -> bool {
+        let binary = |data: &[u8]| data[..data.len().min(8000)].contains(&0);
+        binary(&self.old_data) || binary(&self.new_data)
+    }
+
+    fn hunks(&self) -> Vec<diff::Hunk<'_>> {
+        let (old, new) = (diff::lines(&self.old_data), diff::lines(&self.new_data));
+        diff::hunks(&old, &new, CONTEXT)
+    }
+}
+
+/// The subject, as the first paragraph of the message on one line, and the
+/// body after it, ending in a newline unless it's empty
+fn split_message(msg: &str) -> (String, String) {
+    let msg = msg.trim_start_matches('\n');
+    let (subject, body) = msg.split_once("\n\n").unwrap_or((msg, ""));
+    let subject = subject.lines().map(str::trim).collect::<Vec<_>>().join(" ");
+    let mut body = body.trim_start_matches('\n').trim_end().to_owned();
+    if !body.is_empty() {
+        body.push('\n');
+    }
+    (subject, body)
+}
+
+/// The subject with what doesn't belong in a file name replaced by `-`
+fn file_name_of(subject: &str) -> String {
+    let mut name = String::new();
+    for c in subject.chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+        if name.len() >= NAME_MAX {
+            break;
+        }
+    }
+    name.trim_end_matches(['-', '.']).to_owned()
+}
+
+/// Like ` a.txt | 3 ++-`, for each change, then a summary
+fn diff_stat(changes: &[FileChange]) -> String {
+    let counts = changes
+        .iter()
+        .map(|change| {
+            let (mut added, mut removed) = (0, 0);
+            if !change.is_binary() {
+                for hunk in change.hunks() {
+                    for (op, _) in hunk.lines {
+                        match op {
+                            b'+' => added += 1,
+                            b'-' => removed += 1,
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            (added, removed)
+        })
+        .collect::<Vec<_>>();
+    let names = changes
+        .iter()
+        .map(|change| change.path.to_string())
+        .collect::<Vec<_>>();
+    let name_width = names
+        .iter()
+        .map(|name| name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let max_change = counts.iter().map(|(a, r)| a + r).max().unwrap_or(0);
+    let number_width = max_change.to_string().len();
+    let graph_width = STAT_WIDTH
+        .saturating_sub(name_width + number_width + 6)
+        .max(6);
+    let scale = |n: usize| {
+        if max_change <= graph_width || n == 0 {
+            n
+        } else {
+            1 + n * (graph_width - 1) / max_change
+        }
+    };
+
+    let mut stat = String::new();
+    for ((change, name), (added, removed)) in changes.iter().zip(&names).zip(&counts) {
+        let padding = " ".repeat(name_width - name.chars().count());
+        if change.is_binary() {
+            let (old, new) = (change.old_data.len(), change.new_data.len());
+            writeln!(stat, " {name}{padding} | Bin {old} -> {new} bytes")
+                .expect("Writing to a string");
+            continue;
+        }
+        let graph = format!(
+            "{}{}",
+            "+".repeat(scale(*added)),
+            "-".repeat(scale(*removed))
+        );
+        let count = added + removed;
+        let line = format!(" {name}{padding} | {count:>number_width$} {graph}");
+        stat.push_str(line.trim_end());
+        stat.push('\n');
+    }
+    stat.push_str(&stat_summary(changes, &counts));
+    stat
+}
+
+/// Like ` 2 files changed, 1 insertion(+)`, then the files created, deleted
+/// or with their mode changed
+fn stat_summary(changes: &[FileChange], counts: &[(usize, usize)]) -> String {
+    let plural =
+        |n: usize, one: &str, many: &str| format!("{n} {}", if n == 1 { one } else { many });
+    let (added, removed) = counts
+        .iter()
+        .fold((0, 0), |(a, r), (added, removed)| (a + added, r + removed));
+    let mut summary = format!(
+        " {}",
+        plural(changes.len(), "file changed", "files changed")
+    );
+    if added > 0 || removed == 0 {
+        summary.push_str(", ");
+        summary.push_str(&plural(added, "insertion(+)", "insertions(+)"));
+    }
+    if removed > 0 || added == 0 {
+        summary.push_str(", ");
+        summary.push_str(&plural(removed, "deletion(-)", "deletions(-)"));
+    }
+    summary.push('\n');
+
+    for change in changes {
+        let path = &change.path;
+        let line = match (&change.old, &change.new) {
+            (None, Some(new)) => format!(" create mode {} {path}\n", new.mode.as_base8()),
+            (Some(old), None) => format!(" delete mode {} {path}\n", old.mode.as_base8()),
+            (Some(old), Some(new)) if old.mode != new.mode => format!(
+                " mode change {} => {} {path}\n",
+                old.mode.as_base8(),
+                new.mode.as_base8()
+            ),
+            _ => continue,
+        };
+        summary.push_str(&line);
+    }
+    summary
+}
+
+/// The change as a patch like `git diff` shows it, starting `diff --git`
+fn patch_of(change: &FileChange) -> BString {
+    let name = change.path.as_bstr();
+    let mut patch = BString::from(format!("diff --git a/{name} b/{name}\n"));
+    let short = |node: &Option<FileNode>| {
+        let hex = node
+            .as_ref()
+            .map_or_else(|| "0".repeat(40), |node| node.oid.to_hex());
+        hex[..7].to_owned()
+    };
+    match (&change.old, &change.new) {
+        (None, Some(new)) => patch.push_str(format!("new file mode {}\n", new.mode.as_base8())),
+        (Some(old), None) => {
+            patch.push_str(format!("deleted file mode {}\n", old.mode.as_base8()));
+        }
+        (Some(old), Some(new)) if old.mode != new.mode => patch.push_str(format!(
+            "old mode {}\nnew mode {}\n",
+            old.mode.as_base8(),
+            new.mode.as_base8()
+        )),
+        _ => {}
+    }
+    let same_oid = matches!(
+        (&change.old, &change.new),
+        (Some(old), Some(new)) if old.oid == new.oid
+    );
+    if !same_oid {
+        patch.push_str(format!(
+            "index {}..{}",
+            short(&change.old),
+            short(&change.new)
+        ));
+        match (&change.old, &change.new) {
+            (Some(old), Some(new)) if old.mode == new.mode => {
+                patch.push_str(format!(" {}", old.mode.as_base8()));
+            }
+            _ => {}
+        }
+        patch.push_str("\n");
+    }
+
+    let old_name = match change.old {
+        Some(_) => format!("a/{name}"),
+        None => "/dev/null".to_owned(),
+    };
+    let new_name = match change.new {
+        Some(_) => format!("b/{name}"),
+        None => "/dev/null".to_owned(),
+    };
+    if change.is_binary() {
+        patch.push_str(format!("Binary files {old_name} and {new_name} differ\n"));
+        return patch;
+    }
+    let hunks = change.hunks();
+    if hunks.is_empty() {
+        return patch;
+    }
+    patch.push_str(format!("--- {old_name}\n+++ {new_name}\n"));
+    for hunk in hunks {
+        patch.push_str(hunk.header());
+        patch.push_str("\n");
+        for (op, line) in hunk.lines {
+            patch.push_byte(op);
+            patch.push_str(line);
+            if !line.ends_with(b"\n") {
+                patch.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    patch
+}
+
+/// The mails of a mailbox, each starting with a line like `From <oid>
+/// <date>` followed by headers. A mailbox without such a line is one mail.
+fn split_mbox(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    let mut after_blank = true;
+    let mut lines = data.lines_with_terminator().peekable();
+    while let Some(line) = lines.next() {
+        let next_is_header = lines.peek().and_then(|next| next.find_byte(b':')).is_some();
+        if after_blank && line.starts_with(b"From ") && next_is_header {
+            starts.push(offset);
+        }
+        after_blank = line.trim().is_empty();
+        offset += line.len();
+    }
+    if starts.first() != Some(&0) {
+        starts.insert(0, 0);
+    }
+    starts
+        .iter()
+        .zip(starts.iter().skip(1).chain([&data.len()]))
+        .map(|(&start, &end)| &data[start..end])
+        .filter(|mail| !mail.trim().is_empty())
+        .collect()
+}
+
+fn parse_mail(mail: &[u8]) -> Result<Mail, AmError> {
+    let mut lines = mail.lines_with_terminator().peekable();
+    if lines.peek().is_some_and(|line| line.starts_with(b"From ")) {
+        lines.next();
+    }
+
+    // Folded headers are unfolded
+    let mut headers = BTreeMap::<String, String>::new();
+    let mut last = None;
+    for line in lines.by_ref() {
+        let line = line.to_str_lossy();
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some(value) = last.as_ref().and_then(|name| headers.get_mut(name)) {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim().to_ascii_lowercase();
+            headers.insert(name.clone(), value.trim().to_owned());
+            last = Some(name);
+        }
+    }
+
+    let subject = clean_subject(&decode_header(
+        headers.get("subject").map_or("", String::as_str),
+    ));
+    let invalid = |reason| AmError::Invalid {
+        subject: subject.clone(),
+        reason,
+    };
+    let from = decode_header(headers.get("from").ok_or_else(|| invalid("no From"))?);
+    let (name, email) = match from.rsplit_once('<') {
+        Some((name, email)) => (
+            name.trim().trim_matches('"').to_owned(),
+            email.trim_end().trim_end_matches('>').to_owned(),
+        ),
+        None => (String::new(), from.trim().to_owned()),
+    };
+    let date = headers.get("date").ok_or_else(|| invalid("no Date"))?;
+    let date = DateTime::parse_from_rfc2822(date).map_err(|_| invalid("an invalid Date"))?;
+
+    // The body goes until `---` or the patch, whichever comes first
+    let mut body = String::new();
+    let mut patch = Vec::new();
+    let mut in_patch = false;
+    for line in lines {
+        if !in_patch && line.starts_with(b"diff --git ") {
+            in_patch = true;
+        }
+        if in_patch {
+            patch.push(line);
+        } else if line.trim_end() == b"---" {
+            in_patch = true;
+            patch.clear();
+        } else {
+            body.push_str(&line.to_str_lossy());
+        }
+    }
+
+    let msg = Cleanup::Whitespace.apply(&format!("{subject}\n\n{body}"), '#');
+    Ok(Mail {
+        files: parse_patch(&patch).map_err(invalid)?,
+        author: Signature::new(name, email, date),
+        msg,
+        subject,
+    })
+}
+
+/// Takes `Re:` and tags like `[PATCH 1/2]` off the start of a subject
+fn clean_subject(subject: &str) -> String {
+    let mut subject = subject.trim();
+    loop {
+        if let Some(rest) = subject
+            .get(..3)
+            .filter(|re| re.eq_ignore_ascii_case("re:"))
+            .map(|_| &subject[3..])
+        {
+            subject = rest.trim_start();
+        } else if let Some(end) = subject.strip_prefix('[').and_then(|rest| rest.find(']')) {
+            subject = subject[end + 2..].trim_start();
+        } else {
+            return subject.to_owned();
+        }
+    }
+}
+
+/// The files of a patch, from its lines. Lines outside the hunks of a file
+/// that aren't headers are skipped.
+fn parse_patch(lines: &[&[u8]]) -> Result<Vec<FilePatch>, &'static str> {
+    let mut files = Vec::new();
+    let mut lines = lines.iter().copied().peekable();
+    while let Some(line) = lines.next() {
+        let Some(names) = line.strip_prefix(b"diff --git ") else {
+            continue;
+        };
+        let mut file = FilePatch {
+            path: diff_git_path(names.trim_end()),
+            ..FilePatch::default()
+        };
+        while let Some(&line) = lines.peek() {
+            let header = line.trim_end_with(|c| c == '\n' || c == '\r');
+            let mode = |prefix: &[u8]| parse_mode(&header[prefix.len()..]).ok_or("an invalid mode");
+            if header.starts_with(b"new file mode ") {
+                file.created = true;
+                file.new_mode = Some(mode(b"new file mode ")?);
+            } else if header.starts_with(b"deleted file mode ") {
+                file.deleted = true;
+            } else if header.starts_with(b"new mode ") {
+                file.new_mode = Some(mode(b"new mode ")?);
+            } else if header.starts_with(b"+++ ") {
+                if let Some(path) = header.strip_prefix(b"+++ b/") {
+                    file.path = path_of(path);
+                }
+            } else if header.starts_with(b"--- ") {
+                if let (None, Some(path)) = (&file.path, header.strip_prefix(b"--- a/")) {
+                    file.path = path_of(path);
+                }
+            } else if header.starts_with(b"@@ -") {
+                lines.next();
+                file.hunks.push(parse_hunk(header, &mut lines)?);
+                continue;
+            } else if header.starts_with(b"Binary files ") || header == b"GIT binary patch" {
+                return Err("a binary patch, which isn't supported");
+            } else if header.starts_with(b"rename from ") || header.starts_with(b"copy from ") {
+                return Err("a rename or copy, which isn't supported");
+            } else if !(header.starts_with(b"old mode ")
+                || header.starts_with(b"index ")
+                || header.starts_with(b"similarity index "))
+            {
+                break;
+            }
+            lines.next();
+        }
+        if file.path.is_none() {
+            return Err("a patch without a path");
+        }
+        files.push(file);
+    }
+    Ok(files)
+}
+
+/// The path of `a/<path> b/<path>`, which is ambiguous unless both are the
+/// same
+fn diff_git_path(names: &[u8]) -> Option<WsPath> {
+    let len = names.len().checked_sub(5)? / 2;
+    let (a, b) = (names.get(..len + 2)?, names.get(len + 2..)?);
+    let path = a.strip_prefix(b"a/")?;
+    (b.strip_prefix(b" b/")? == path).then(|| path_of(path))?
+}
+
+fn path_of(path: &[u8]) -> Option<WsPath> {
+    WsPath::new_checked_bytes(path.to_owned()).ok()
+}
+
+fn parse_mode(mode: &[u8]) -> Option<Mode> {
+    match mode {
+        b"100644" => Some(Mode::Regular),
+        b"100755" => Some(Mode::Executable),
+        b"120000" => Some(Mode::Symlink),
+        b"160000" => Some(Mode::Gitlink),
+        _ => None,
+    }
+}
+
+/// A hunk from its header like `@@ -1,3 +1,4 @@` and the lines after it
+fn parse_hunk<'a>(
+    header: &[u8],
+    lines: &mut std::iter::Peekable<impl Iterator<Item = &'a [u8]>>,
+) -> Result<PatchHunk, &'static str> {
+    let invalid = "an invalid hunk header";
+    let ranges = header[4..].split_str(" @@").next().ok_or(invalid)?;
+    let mut ranges = ranges.split_str(" +");
+    let range = |range: Option<&[u8]>| -> Option<(usize, usize)> {
+        let range = range?.to_str().ok()?;
+        Some(match range.split_once(',') {
+            Some((start, len)) => (start.parse().ok()?, len.parse().ok()?),
+            None => (range.parse().ok()?, 1),
+        })
+    };
+    let (old_start, mut old_len) = range(ranges.next()).ok_or(invalid)?;
+    let (_, mut new_len) = range(ranges.next()).ok_or(invalid)?;
+
+    let mut hunk = PatchHunk {
+        old_start: if old_len == 0 {
+            old_start
+        } else {
+            old_start.saturating_sub(1)
+        },
+        lines: Vec::new(),
+    };
+    while old_len > 0 || new_len > 0 {
+        let line = lines.next().ok_or("a hunk that ends early")?;
+        // Mail can lose the space of empty context lines
+        let (op, content) = match line.split_first() {
+            Some((&b'\n', _)) | None => (b' ', &b"\n"[..]),
+            Some((&op, content)) => (op, content),
+        };
+        match op {
+            b' ' => (old_len, new_len) = (old_len.saturating_sub(1), new_len.saturating_sub(1)),
+            b'-' => old_len = old_len.saturating_sub(1),
+            b'+' => new_len = new_len.saturating_sub(1),
+            b'\\' => {}
+            _ => return Err("an invalid hunk line"),
+        }
+        if op == b'\\' {
+            no_newline(&mut hunk);
+        } else {
+            hunk.lines.push((op, content.to_vec()));
+        }
+    }
+    if lines.peek().is_some_and(|line| line.starts_with(b"\\")) {
+        lines.next();
+        no_newline(&mut hunk);
+    }
+    Ok(hunk)
+}
+
+/// After `\ No newline at end of file`, the line before has no newline
+fn no_newline(hunk: &mut PatchHunk) {
+    if let Some((_, line)) = hunk.lines.last_mut() {
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+    }
+}
+
+/// The data with the hunks applied, or `None` if one doesn't apply. Each
+/// hunk is looked for where it says it goes, then further and further away.
+fn apply_hunks(old: &[u8], hunks: &[PatchHunk]) -> Option<BString> {
+    let old = diff::lines(old);
+    let mut patched = BString::default();
+    let mut cursor = 0;
+    for hunk in hunks {
+        let side = |keep: u8| {
+            hunk.lines
+                .iter()
+                .filter(move |(op, _)| *op == b' ' || *op == keep)
+                .map(|(_, line)| line.as_slice())
+                .collect::<Vec<_>>()
+        };
+        let (before, after) = (side(b'-'), side(b'+'));
+        let last = old.len().checked_sub(before.len())?;
+        let fits =
+            |at: usize| at >= cursor && at <= last && old[at..at + before.len()] == before[..];
+        let at = (0..=old.len())
+            .flat_map(|distance| {
+                [
+                    hunk.old_start.checked_add(distance),
+                    hunk.old_start.checked_sub(distance),
+                ]
+            })
+            .flatten()
+            .find(|&at| fits(at))?;
+
+        patched.extend(old[cursor..at].concat());
+        patched.extend(after.concat());
+        cursor = at + before.len();
+    }
+    patched.extend(old[cursor..].concat());
+    Some(patched)
+}
+
+/// Encodes a header's text like `=?UTF-8?q?...?=` if it isn't ASCII
+fn encode_header(text: &str) -> String {
+    if text.is_ascii() {
+        return text.to_owned();
+    }
+    let mut encoded = String::from("=?UTF-8?q?");
+    for b in text.bytes() {
+        match b {
+            b' ' => encoded.push('_'),
+            b if b.is_ascii_alphanumeric() || b"!*+-/".contains(&b) => encoded.push(char::from(b)),
+            b => write!(encoded, "={b:02X}").expect("Writing to a string"),
+        }
+    }
+    encoded.push_str("?=");
+    encoded
+}
+
+/// Decodes the words of a header encoded like `=?UTF-8?q?...?=` or
+/// `=?UTF-8?b?...?=`, joining them where they're next to each other
+fn decode_header(value: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, word) = rest.split_at(start);
+        if let Some((text, len)) = decode_word(word) {
+            if !(after_word && before.trim().is_empty()) {
+                decoded.push_str(before);
+            }
+            decoded.push_str(&text);
+            rest = &word[len..];
+            after_word = true;
+        } else {
+            decoded.push_str(before);
+            decoded.push_str("=?");
+            rest = &word[2..];
+            after_word = false;
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The text of an encoded word at the start of `word`, and how long the
+/// word was. Whatever the charset, the text is taken as UTF-8.
+fn decode_word(word: &str) -> Option<(String, usize)> {
+    let (charset, rest) = word.strip_prefix("=?")?.split_once('?')?;
+    let (encoding, rest) = rest.split_once('?')?;
+    let end = rest.find("?=")?;
+    let text = &rest.as_bytes()[..end];
+    let bytes = match encoding {
+        "q" | "Q" => {
+            let mut bytes = Vec::new();
+            let mut i = 0;
+            while i < text.len() {
+                match text[i] {
+                    b'_' => bytes.push(b' '),
+                    b'=' => {
+                        let hex = text.get(i + 1..i + 3)?.to_str().ok()?;
+                        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                        i += 2;
+                    }
+                    b => bytes.push(b),
+                }
+                i += 1;
+            }
+            bytes
+        }
+        "b" | "B" => decode_base64(text)?,
+        _ => return None,
+    };
+    let len = 2 + charset.len() + 1 + encoding.len() + 1 + end + 2;
+    Some((String::from_utf8_lossy(&bytes).into_owned(), len))
+}
+
+fn decode_base64(text: &[u8]) -> Option<Vec<u8>> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+    let mut bytes = Vec::new();
+    let (mut bits, mut len) = (0_u32, 0);
+    for &c in text.iter().filter(|&&c| c != b'=') {
+        bits = (bits << 6) | u32::from(value(c)?);
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            bytes.push((bits >> len).to_le_bytes()[0]);
+        }
+    }
+    Some(bytes)
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum FormatPatchError {
+    /// Failed to walk commits
+    RevWalk(#[from] RevWalkError),
+    /// Failed to load commit
+    LoadCommit(#[from] db::LoadError<Commit>),
+    /// Failed to load tree
+    LoadTree(#[from] db::LoadError<Tree>),
+    /// Failed to load blob
+    LoadBlob(#[from] db::LoadError<Blob>),
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum AmError {
+    /// Failed to read mailbox
+    Read(#[source] io::Error),
+    /// Mail {subject:?} has {reason}
+    Invalid {
+        subject: String,
+        reason: &'static str,
+    },
+    /// Patch {0:?} is empty
+    Empty(String),
+    /// Patch {subject:?} does not apply to {path}
+    DoesNotApply { subject: String, path: WsPath },
+    /// Cannot apply patches without a commit to apply them to
+    NoHead,
+    /// Cannot apply patches with unmerged files
+    Unmerged,
+    /// Cannot apply patches with changes staged
+    Staged,
+    /// Failed to merge
+    MergeTrees(#[from] MergeError),
+    /// Failed to determine committer
+    Identity(#[from] signature::IdentityError),
+    /// Failed to load commit
+    LoadCommit(#[from] db::LoadError<Commit>),
+    /// Failed to load tree
+    LoadTree(#[from] db::LoadError<Tree>),
+    /// Failed to load blob
+    LoadBlob(#[from] db::LoadError<Blob>),
+    /// Failed to store blob
+    StoreBlob(#[from] db::StoreError<Blob>),
+    /// Failed to store tree
+    StoreTree(#[from] db::StoreError<Tree>),
+    /// Failed to store commit
+    StoreCommit(#[from] db::StoreError<Commit>),
+    /// Failed to reload index
+    ReloadIndex(#[from] index::LoadError),
+    /// Failed to read ref
+    ReadRef(#[from] refs::ReadError),
+    /// Failed to update ref
+    UpdateRef(#[from] refs::UpdateError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn encodes_and_decodes_headers() {
+        assert_eq!("Plain", encode_header("Plain"));
+        assert_eq!(
+            "=?UTF-8?q?J=C3=B6rg_M=C3=BCller?=",
+            encode_header("Jörg Müller")
+        );
+        assert_eq!("Jörg Müller", decode_header(&encode_header("Jörg Müller")));
+        assert_eq!(
+            "[PATCH] Füße und Hände",
+            decode_header("[PATCH] =?UTF-8?q?F=C3=BC=C3=9Fe_und?= =?UTF-8?b?IEjDpG5kZQ==?=")
+        );
+        assert_eq!("a =?broken", decode_header("a =?broken"));
+    }
+
+    #[test]
+    fn cleans_subjects() {
+        assert_eq!("Fix it", clean_subject("[PATCH 2/3] Fix it"));
+        assert_eq!("Fix it", clean_subject("Re: [RFC] [PATCH v2] Fix it"));
+        assert_eq!("Fix [it]", clean_subject("Fix [it]"));
+    }
+
+    #[test]
+    fn names_files_after_subjects() {
+        assert_eq!("Fix-the-thing-s-bug", file_name_of("Fix the thing's bug!"));
+        assert_eq!("v1.2_release", file_name_of("v1.2_release."));
+        assert_eq!(NAME_MAX, file_name_of(&"a".repeat(100)).len());
+    }
+
+    #[test]
+    fn applies_moved_hunks() {
+        let hunk = PatchHunk {
+            old_start: 0,
+            lines: vec![
+                (b' ', b"a\n".to_vec()),
+                (b'-', b"b\n".to_vec()),
+                (b'+', b"B\n".to_vec()),
+            ],
+        };
+        assert_eq!(
+            Some(BString::from("x\ny\na\nB\nc\n")),
+            apply_hunks(b"x\ny\na\nb\nc\n", &[hunk])
+        );
+        let hunk = PatchHunk {
+            old_start: 0,
+            lines: vec![(b'-', b"missing\n".to_vec())],
+        };
+        assert_eq!(None, apply_hunks(b"a\n", &[hunk]));
+    }
+
+    #[test]
+    fn parses_hunks_without_newlines() -> Result<(), &'static str> {
+        let patch: &[&[u8]] = &[
+            b"diff --git a/dir/f b/dir/f\n",
+            b"index 1234567..89abcde 100644\n",
+            b"--- a/dir/f\n",
+            b"+++ b/dir/f\n",
+            b"@@ -1,2 +1,2 @@\n",
+            b" a\n",
+            b"-b\n",
+            b"\\ No newline at end of file\n",
+            b"+c\n",
+            b"\\ No newline at end of file\n",
+            b"-- \n",
+        ];
+        let files = parse_patch(patch)?;
+        assert_eq!(1, files.len());
+        assert_eq!(Some(WsPath::new_unchecked("dir/f")), files[0].path);
+        assert_eq!(
+            Some(BString::from("a\nc")),
+            apply_hunks(b"a\nb", &files[0].hunks)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+//! Keeping the repository quick to read and no bigger than it needs to be,
+//! like `git gc`: everything reachable is repacked into one pack, old reflog
+//! entries are dropped, and loose objects are pruned once they're packed or
+//! have been unreachable for long enough.
+
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bstr::ByteSlice;
+use tempfile::NamedTempFile;
+use tracing::{debug, instrument};
+
+use crate::core::{
+    config,
+    db::{CommitRef, LoadRawError, StoreRawError, TagRef, TreeRef, UntypedOid},
+    fsync,
+    index::{self, Entry},
+    locked_file,
+    pack::{
+        self,
+        file::{LoadPackedError, OpenPackError},
+        PackIndex, PackOptions, UnpackError,
+    },
+    refs,
+    stat::Mode,
+    LockedFile, Oid, Repo,
+};
+
+/// Loose objects there can be before automatic maintenance
+const DEFAULT_AUTO: i64 = 6700;
+/// Packs there can be before automatic maintenance
+const DEFAULT_AUTO_PACK_LIMIT: i64 = 50;
+const DEFAULT_PRUNE_EXPIRE: &str = "2.weeks.ago";
+const DEFAULT_REFLOG_EXPIRE: &str = "90.days.ago";
+
+/// What [`Repo::maintenance_run`] did
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Maintained {
+    /// The pack everything reachable is in, or `None` if nothing is
+    pub pack: Option<PathBuf>,
+    /// Older than `gc.reflogExpire`
+    pub expired_reflog_entries: usize,
+    /// Loose objects removed, as they're packed or have been unreachable for
+    /// longer than `gc.pruneExpire`
+    pub pruned: usize,
+}
+
+impl Repo {
+    /// Repacks, expires reflogs and prunes, like `git gc`. If `auto`, this is
+    /// only done if [`Self::needs_maintenance`], returning `None` otherwise,
+    /// so that it can be called after each commit or fetch.
+    ///
+    /// Unreachable objects that were packed are kept loose until they're old
+    /// enough to prune. How old that is is `gc.pruneExpire` (like
+    /// `2.weeks.ago`, the default, or `now` or `never`), and likewise
+    /// `gc.reflogExpire` for reflog entries (`90.days.ago` by default).
+    #[instrument(err)]
+    pub fn maintenance_run(&mut self, auto: bool) -> Result<Option<Maintained>, MaintenanceError> {
+        if auto && !self.needs_maintenance()? {
+            return Ok(None);
+        }
+        let prune_cutoff = expiry(&self.config, "gc.pruneExpire", DEFAULT_PRUNE_EXPIRE)?;
+        let reflog_cutoff = expiry(&self.config, "gc.reflogExpire", DEFAULT_REFLOG_EXPIRE)?;
+
+        let (expired_reflog_entries, mut roots) = self.expire_reflogs(reflog_cutoff)?;
+        roots.extend(self.roots()?);
+        let reachable = self.reachable(roots)?;
+        let pack = self.repack_all(&reachable)?;
+        let pruned = self.prune_objects(&reachable.into_iter().collect(), prune_cutoff)?;
+        Ok(Some(Maintained {
+            pack,
+            expired_reflog_entries,
+            pruned,
+        }))
+    }
+
+    /// Whether there are more loose objects than `gc.auto` (6700 by default)
+    /// or more packs than `gc.autoPackLimit` (50 by default). Either can be 0
+    /// to not count, though `gc.auto` being 0 turns automatic maintenance off
+    /// entirely, like git. Like git, the loose objects are estimated from how
+    /// many start with `17`, as they're spread evenly.
+    pub fn needs_maintenance(&self) -> Result<bool, MaintenanceError> {
+        let auto = self.config.get_int("gc.auto")?.unwrap_or(DEFAULT_AUTO);
+        if auto <= 0 {
+            return Ok(false);
+        }
+        let pack_limit = self
+            .config
+            .get_int("gc.autoPackLimit")?
+            .unwrap_or(DEFAULT_AUTO_PACK_LIMIT);
+        let packs = i64::try_from(self.db.packs().len()).unwrap_or(i64::MAX);
+        if pack_limit > 0 && packs > pack_limit {
+            debug!(packs, pack_limit, "Too many packs");
+            return Ok(true);
+        }
+
+        let dir = self.db.objects_dir().join("17");
+        let loose = loose_in(&dir)?.len();
+        let threshold = (auto + 255) / 256;
+        debug!(loose, threshold, "Counted loose objects starting with 17");
+        Ok(i64::try_from(loose).unwrap_or(i64::MAX) > threshold)
+    }
+
+    /// What's pointed to by refs, HEAD and the index
+    fn roots(&mut self) -> Result<Vec<UntypedOid>, MaintenanceError> {
+        let mut roots = self
+            .refs
+            .list(b"refs/".as_bstr())?
+            .into_iter()
+            .map(|(_, oid)| oid.into_untyped())
+            .collect::<Vec<_>>();
+        roots.extend(self.refs.head()?.map(Oid::into_untyped));
+        if self.workspace.is_some() {
+            self.index.reload()?;
+            let conflicted = self.index.conflicts().flat_map(|(_, conflict)| {
+                conflict
+                    .base
+                    .iter()
+                    .chain(&conflict.ours)
+                    .chain(&conflict.theirs)
+                    .map(Entry::as_entry_ref)
+            });
+            roots.extend(
+                self.index
+                    .entries()
+                    .chain(conflicted)
+                    .filter(|entry| entry.mode() != Mode::Gitlink)
+                    .map(|entry| entry.oid.into_untyped()),
+            );
+        }
+        Ok(roots)
+    }
+
+    /// Everything reachable from the roots that we have, roots first
+    fn reachable(&self, roots: Vec<UntypedOid>) -> Result<Vec<UntypedOid>, MaintenanceError> {
+        let mut seen = BTreeSet::new();
+        let mut found = Vec::new();
+        let mut pending = roots;
+        pending.reverse();
+        while let Some(oid) = pending.pop() {
+            if !seen.insert(oid) {
+                continue;
+            }
+            let Some((ty, data)) = self.db.load_raw(&oid)? else {
+                debug!(?oid, "Missing reachable object");
+                continue;
+            };
+            found.push(oid);
+            let corrupt = || MaintenanceError::Corrupt(oid);
+            match ty.as_bytes() {
+                b"commit" => {
+                    let commit = CommitRef::parse(&data).map_err(|_| corrupt())?;
+                    pending.push(commit.tree.into_untyped());
+                    if !self.db.is_shallow(&oid) {
+                        pending.extend(commit.parents().map(Oid::into_untyped));
+                    }
+                }
+                b"tag" => pending.push(TagRef::parse(&data).map_err(|_| corrupt())?.object),
+                b"tree" => pending.extend(
+                    TreeRef::parse(&data)
+                        .map_err(|_| corrupt())?
+                        .nodes()
+                        .filter(|node| !node.is_gitlink())
+                        .map(|node| node.untyped_oid()),
+                ),
+                _ => {}
+            }
+        }
+        Ok(found)
+    }
+
+    /// Puts the objects in one pack, replacing every other. Objects that were
+    /// packed but unreachable are kept loose.
+    fn repack_all(&mut self, objects: &[UntypedOid]) -> Result<Option<PathBuf>, MaintenanceError> {
+        let old = self.db.packs().to_vec();
+        let reachable = objects.iter().collect::<BTreeSet<_>>();
+        for pack in &old {
+            for entry in pack.index().entries() {
+                if !reachable.contains(&entry.oid) && !self.db.oid_path(&entry.oid).exists() {
+                    let (ty, data) = pack.load_at(entry.offset, &self.db)?;
+                    self.db.store_raw(ty.name(), &data)?;
+                }
+            }
+        }
+
+        let path = if objects.is_empty() {
+            None
+        } else {
+            let options = PackOptions::from_config(&self.config)?;
+            let mut data = Vec::new();
+            pack::write(&self.db, objects, &options, &mut data, &mut ())?;
+            let index = PackIndex::build(&data, &mut ())?;
+            let dir = self.db.pack_dir();
+            fs::create_dir_all(&dir).map_err(|e| MaintenanceError::Write(dir.clone(), e))?;
+            let path = dir.join(format!("pack-{}.pack", hex::encode(index.checksum())));
+            let mut idx = Vec::new();
+            index.write(&mut idx).expect("Writing to vec");
+            // The index last, so that the pack is never found without it
+            let sync = self.db.fsync().packs;
+            write_atomic(&path, &data, sync)?;
+            write_atomic(&path.with_extension("idx"), &idx, sync)?;
+            Some(path)
+        };
+
+        for pack in old {
+            if Some(pack.path()) != path.as_deref() {
+                debug!(path = ?pack.path(), "Removing repacked");
+                for file in [pack.path().with_extension("idx"), pack.path().to_owned()] {
+                    fs::remove_file(&file).map_err(|e| MaintenanceError::Remove(file, e))?;
+                }
+            }
+        }
+        self.db.load_packs()?;
+        Ok(path)
+    }
+
+    /// Removes loose objects that are packed, or that are unreachable and
+    /// haven't been modified since `cutoff`
+    fn prune_objects(
+        &self,
+        reachable: &BTreeSet<UntypedOid>,
+        cutoff: Option<SystemTime>,
+    ) -> Result<usize, MaintenanceError> {
+        let objects = self.db.objects_dir();
+        let dirs = fs::read_dir(objects).map_err(|e| MaintenanceError::List(objects.into(), e))?;
+        let mut pruned = 0;
+        for dir in dirs {
+            let dir = dir
+                .map_err(|e| MaintenanceError::List(objects.into(), e))?
+                .path();
+            if !is_loose_dir(&dir) {
+                continue;
+            }
+            for (oid, path) in loose_in(&dir)? {
+                let packed = self.db.packs().iter().any(|pack| pack.contains(&oid));
+                let expired = !reachable.contains(&oid)
+                    && match cutoff {
+                        Some(cutoff) => modified(&path)? <= cutoff,
+                        None => false,
+                    };
+                if packed || expired {
+                    debug!(?oid, packed, "Pruning");
+                    fs::remove_file(&path).map_err(|e| MaintenanceError::Remove(path, e))?;
+                    pruned += 1;
+                }
+            }
+            // Which fails if anything's left
+            if fs::remove_dir(&dir).is_ok() {
+                debug!(?dir, "Removed empty");
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Drops reflog entries from up to `cutoff`, returning how many and the
+    /// objects the rest point to, which are kept
+    fn expire_reflogs(
+        &self,
+        cutoff: Option<SystemTime>,
+    ) -> Result<(usize, Vec<UntypedOid>), MaintenanceError> {
+        let cutoff = cutoff.map(|cutoff| {
+            let since_epoch = cutoff.duration_since(UNIX_EPOCH).unwrap_or_default();
+            i64::try_from(since_epoch.as_secs()).unwrap_or(i64::MAX)
+        });
+        let logs = self.git_dir().join("logs");
+        let mut expired = 0;
+        let mut kept_objects = Vec::new();
+        for entry in walkdir::WalkDir::new(&logs).sort_by_file_name() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err)
+                    if err.io_error().map(io::Error::kind) == Some(io::ErrorKind::NotFound) =>
+                {
+                    continue
+                }
+                Err(err) => return Err(MaintenanceError::ListReflogs(err)),
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let log = fs::read(path).map_err(|e| MaintenanceError::Read(path.into(), e))?;
+            let mut kept = Vec::new();
+            for line in log.lines_with_terminator() {
+                let parsed = reflog_entry(line);
+                match (parsed, cutoff) {
+                    (Some((_, _, time)), Some(cutoff)) if time <= cutoff => expired += 1,
+                    _ => {
+                        kept.extend_from_slice(line);
+                        if let Some((old, new, _)) = parsed {
+                            kept_objects.extend(
+                                [old, new]
+                                    .iter()
+                                    .copied()
+                                    .filter(|oid| *oid != UntypedOid::zero()),
+                            );
+                        }
+                    }
+                }
+            }
+            if kept.len() != log.len() {
+                let mut lock = LockedFile::acquire(path).map_err(MaintenanceError::Lock)?;
+                lock.write_all(&kept)
+                    .map_err(|e| MaintenanceError::Write(path.into(), e))?;
+                lock.commit()
+                    .map_err(|e| MaintenanceError::Write(path.into(), e))?;
+            }
+        }
+        Ok((expired, kept_objects))
+    }
+}
+
+/// Whether it's one of the directories of loose objects, like `17`, rather
+/// than `pack` or `info`
+fn is_loose_dir(dir: &Path) -> bool {
+    let name = dir.file_name().and_then(|name| name.to_str());
+    name.is_some_and(|name| name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit()))
+        && dir.is_dir()
+}
+
+/// The loose objects in one of the directories of the database, like `17`
+fn loose_in(dir: &Path) -> Result<Vec<(UntypedOid, PathBuf)>, MaintenanceError> {
+    let prefix = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(MaintenanceError::List(dir.into(), err)),
+    };
+    let mut objects = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| MaintenanceError::List(dir.into(), e))?
+            .path();
+        let rest = path.file_name().and_then(|name| name.to_str());
+        if let Some(Ok(oid)) = rest.map(|rest| UntypedOid::parse(format!("{prefix}{rest}"))) {
+            objects.push((oid, path));
+        }
+    }
+    Ok(objects)
+}
+
+fn modified(path: &Path) -> Result<SystemTime, MaintenanceError> {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .map_err(|e| MaintenanceError::Read(path.into(), e))
+}
+
+/// In the same directory, so that it can be renamed into place
+fn write_atomic(path: &Path, data: &[u8], sync: bool) -> Result<(), MaintenanceError> {
+    let err = |e| MaintenanceError::Write(path.into(), e);
+    let dir = path.parent().expect("In the pack directory");
+    let mut temp = NamedTempFile::new_in(dir).map_err(err)?;
+    temp.write_all(data).map_err(err)?;
+    fsync::file(temp.as_file(), sync).map_err(err)?;
+    temp.persist(path).map_err(|e| err(e.error))?;
+    fsync::dir(dir, sync).map_err(err)?;
+    Ok(())
+}
+
+/// Like `<old> <new> Name <email> 1234567890 +0000\tmessage`
+fn reflog_entry(line: &[u8]) -> Option<(UntypedOid, UntypedOid, i64)> {
+    let header = line.split_str("\t").next()?;
+    let mut fields = header.splitn_str(3, " ");
+    let old = UntypedOid::parse(fields.next()?).ok()?;
+    let new = UntypedOid::parse(fields.next()?).ok()?;
+    let mut signature = fields.next()?.trim_end().rsplitn_str(3, " ");
+    let _offset = signature.next()?;
+    let time = signature.next()?.to_str().ok()?.parse().ok()?;
+    Some((old, new, time))
+}
+
+/// The expiry date in the config, or `None` for `never`
+fn expiry(
+    config: &config::Config,
+    name: &str,
+    default: &str,
+) -> Result<Option<SystemTime>, MaintenanceError> {
+    let value = config.get(name).unwrap_or(default);
+    let expiry =
+        parse_expiry(value, SystemTime::now()).ok_or_else(|| MaintenanceError::Expiry {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        })?;
+    Ok(match expiry {
+        Expiry::Never => None,
+        Expiry::Before(cutoff) => Some(cutoff),
+    })
+}
+
+/// When things like unreachable objects expire
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Expiry {
+    Never,
+    /// Those from this time or before
+    Before(SystemTime),
+}
+
+/// Like `now`, `never`, `2.weeks.ago` or `3 days ago`
+fn parse_expiry(value: &str, now: SystemTime) -> Option<Expiry> {
+    match value {
+        "never" | "false" => return Some(Expiry::Never),
+        "now" | "all" => return Some(Expiry::Before(now)),
+        _ => {}
+    }
+    let words = value
+        .split(|c: char| c == '.' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    let [count, unit, "ago"] = words[..] else {
+        return None;
+    };
+    let count: u64 = count.parse().ok()?;
+    let seconds = match unit.trim_end_matches('s') {
+        "second" => 1,
+        "minute" => 60,
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        "month" => 30 * 24 * 60 * 60,
+        "year" => 365 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let ago = Duration::from_secs(count.checked_mul(seconds)?);
+    Some(Expiry::Before(now.checked_sub(ago).unwrap_or(UNIX_EPOCH)))
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum MaintenanceError {
+    /// Invalid config
+    Config(#[from] config::ValueError),
+    /// Invalid {name}: {value:?}
+    Expiry { name: String, value: String },
+    /// Failed to read refs
+    ReadRefs(#[from] refs::ReadError),
+    /// Failed to load index
+    LoadIndex(#[from] index::LoadError),
+    /// Failed to load object
+    Load(#[from] LoadRawError),
+    /// Object {0:?} is corrupt
+    Corrupt(UntypedOid),
+    /// Failed to read packed object
+    LoadPacked(#[from] LoadPackedError),
+    /// Failed to store object from repacked pack
+    Store(#[from] StoreRawError),
+    /// Failed to write pack
+    WritePack(#[from] pack::WriteError),
+    /// Failed to index pack
+    IndexPack(#[from] UnpackError),
+    /// Failed to read packs
+    LoadPacks(#[from] OpenPackError),
+    /// Failed to list {0:?}
+    List(PathBuf, #[source] io::Error),
+    /// Failed to read {0:?}
+    Read(PathBuf, #[source] io::Error),
+    /// Failed to write {0:?}
+    Write(PathBuf, #[source] io::Error),
+    /// Failed to remove {0:?}
+    Remove(PathBuf, #[source] io::Error),
+    /// Failed to list reflogs
+    ListReflogs(#[source] walkdir::Error),
+    /// Failed to lock reflog
+    Lock(#[source] locked_file::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_expiry_dates() {
+        let now = UNIX_EPOCH + Duration::from_hours(30 * 24);
+        let days_ago = |days: u64| {
+            Some(Expiry::Before(
+                now - Duration::from_secs(days * 24 * 60 * 60),
+            ))
+        };
+        assert_eq!(Some(Expiry::Never), parse_expiry("never", now));
+        assert_eq!(Some(Expiry::Before(now)), parse_expiry("now", now));
+        assert_eq!(days_ago(14), parse_expiry("2.weeks.ago", now));
+        assert_eq!(days_ago(3), parse_expiry("3 days ago", now));
+        assert_eq!(days_ago(1), parse_expiry("1.day.ago", now));
+        assert_eq!(None, parse_expiry("2.fortnights.ago", now));
+        assert_eq!(None, parse_expiry("yesterday", now));
+    }
+
+    #[test]
+    fn parses_reflog_entries() {
+        let (old, new) = ("1".repeat(40), "2".repeat(40));
+        let line = format!("{old} {new} A U Thor <a@b.c> 1234567890 +0100\tcommit: Msg\n");
+        assert_eq!(
+            Some((
+                UntypedOid::parse(&old).unwrap(),
+                UntypedOid::parse(&new).unwrap(),
+                1_234_567_890
+            )),
+            reflog_entry(line.as_bytes())
+        );
+        assert_eq!(None, reflog_entry(b"garbage\n"));
+    }
+}
@@ -0,0 +1,396 @@
+//! Merging another commit into HEAD, like `git merge`
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs, io,
+};
+
+use bstr::{BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use super::{
+    driver::{Drivers, MergeFileError},
+    file::FileMergeOptions,
+};
+use crate::core::{
+    commit_msg, config,
+    db::{self, signature, tree::FileNode, Blob, Commit, Tree},
+    fetch, hook,
+    index::{self, Conflict, Entry},
+    migration::{self, Migration},
+    refs::{self, Refs},
+    repo::{BareError, CheckoutError, CheckoutOptions},
+    revwalk::{self, RevWalkError},
+    sparse::{self, Cone},
+    stat::Mode,
+    ws::{self, attributes, Attributes, IgnoreRules},
+    Config, ObjectBuilder, Oid, Repo, Stat, WsPath,
+};
+
+type Files = BTreeMap<WsPath, FileNode>;
+
+/// The message to commit a merge with once its conflicts are resolved
+pub(crate) const MERGE_MSG: &str = "MERGE_MSG";
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MergeOptions {
+    pub strategy: Strategy,
+    /// How files both sides changed are merged
+    pub file: FileMergeOptions,
+    /// Of the merge commit, by default `Merge commit '<oid>'`
+    pub message: Option<String>,
+    /// Commit a merge even if HEAD could be fast-forwarded, like `git merge
+    /// --no-ff`
+    pub no_ff: bool,
+}
+
+impl MergeOptions {
+    /// With files merged as configured, see [`FileMergeOptions::from_config`]
+    pub fn from_config(config: &Config) -> Result<Self, config::ValueError> {
+        Ok(Self {
+            file: FileMergeOptions::from_config(config)?,
+            ..Self::default()
+        })
+    }
+}
+
+/// Like `git merge -s <strategy>`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Strategy {
+    /// Merges the trees three ways from their merge base, file by file.
+    /// Renames aren't detected.
+    #[default]
+    Resolve,
+    /// Records the merge but keeps the tree of HEAD, discarding what the
+    /// other side changed
+    Ours,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Merged {
+    /// HEAD already has what was merged
+    UpToDate,
+    /// What was merged descends from HEAD, which was moved to it
+    FastForward(Oid<Commit>),
+    Commit(Oid<Commit>),
+    /// Nothing was committed, and the index and workspace have the conflicts
+    /// to be resolved
+    Conflicts(Vec<WsPath>),
+}
+
+/// The sides of a path that conflicted
+type Sides = (Option<FileNode>, Option<FileNode>, Option<FileNode>);
+
+impl Repo {
+    /// Merges `theirs` into HEAD with the strategy of the options, and
+    /// commits the merge unless it conflicts. Nothing may be staged, and
+    /// files the merge changes mustn't have local changes. Once a merge
+    /// has updated the workspace the `post-merge` hook is run, which can
+    /// fail but not undo the merge.
+    #[instrument(err)]
+    pub fn merge(
+        &mut self,
+        theirs: Oid<Commit>,
+        options: &MergeOptions,
+    ) -> Result<Merged, MergeError> {
+        let head = self.refs.head()?.ok_or(MergeError::NoHead)?;
+        self.index.reload()?;
+        if self.index.has_conflicts() {
+            return Err(MergeError::Unmerged);
+        }
+        if self.refs.read_ref(Refs::MERGE_HEAD.as_bstr())?.is_some() {
+            return Err(MergeError::InProgress);
+        }
+        let (head_oid, theirs_oid) = (head.into_untyped(), theirs.into_untyped());
+        if revwalk::is_ancestor(&self.db, theirs_oid, head_oid)? {
+            return Ok(Merged::UpToDate);
+        }
+
+        let ours_tree = self.db.load(head)?.tree;
+        let ours = self.db.load_tree_files(&WsPath::root(), ours_tree)?;
+        if self.has_staged(&ours) {
+            return Err(MergeError::Staged);
+        }
+        self.refs.update_ref(Refs::ORIG_HEAD.as_bstr(), &head)?;
+
+        if options.strategy == Strategy::Resolve
+            && !options.no_ff
+            && revwalk::is_ancestor(&self.db, head_oid, theirs_oid)?
+        {
+            debug!("Fast-forwarding");
+            self.migrate_to(theirs, &CheckoutOptions::default())
+                .map_err(Box::new)?;
+            self.refs.update_head(&theirs)?;
+            self.run_post_merge()?;
+            return Ok(Merged::FastForward(theirs));
+        }
+
+        let tree = match options.strategy {
+            Strategy::Ours => ours_tree,
+            Strategy::Resolve => {
+                let base = match revwalk::merge_base(&self.db, head_oid, theirs_oid)? {
+                    Some(base) => {
+                        let tree = self.db.load(base.to_typed::<Commit>())?.tree;
+                        self.db.load_tree_files(&WsPath::root(), tree)?
+                    }
+                    None => BTreeMap::new(),
+                };
+                let theirs_tree = self.db.load(theirs)?.tree;
+                let theirs_files = self.db.load_tree_files(&WsPath::root(), theirs_tree)?;
+
+                let (merged, conflicts) =
+                    self.merge_trees(&base, &ours, &theirs_files, &options.file)?;
+                if !conflicts.is_empty() {
+                    // Committing once they're resolved concludes the merge
+                    self.refs.update_ref(Refs::MERGE_HEAD.as_bstr(), &theirs)?;
+                    let msg = commit_msg::with_conflicts(
+                        &merge_message(options, theirs),
+                        &conflicts,
+                        commit_msg::comment_char(&self.config),
+                    );
+                    fs::write(self.git_dir().join(MERGE_MSG), msg).map_err(MergeError::Message)?;
+                    return Ok(Merged::Conflicts(conflicts));
+                }
+                let entries = merged
+                    .into_iter()
+                    .map(|(path, file)| db::tree::EntryBuilder {
+                        oid: file.oid,
+                        path,
+                        mode: file.mode,
+                    });
+                db::tree::Builder::new().entries(entries).store(&self.db)?
+            }
+        };
+
+        let msg = merge_message(options, theirs);
+        let author = self.signature(signature::Role::Author)?;
+        let committer = self.signature(signature::Role::Committer)?;
+        let commit = db::commit::Builder {
+            merged: vec![theirs],
+            ..db::commit::Builder::new(Some(head), tree, author, committer, msg)
+        }
+        .store(&self.db)?;
+        self.refs.update_head(&commit)?;
+        self.run_post_merge()?;
+        Ok(Merged::Commit(commit))
+    }
+
+    /// Its argument is whether the merge was squashed, which ours never are
+    fn run_post_merge(&self) -> Result<(), hook::HookError> {
+        self.run_hook("post-merge", &[OsStr::new("0")])?;
+        Ok(())
+    }
+
+    /// Whether the index differs from the files of HEAD
+    pub(crate) fn has_staged(&self, ours: &Files) -> bool {
+        self.index.entries().count() != ours.len()
+            || self
+                .index
+                .entries()
+                .any(|entry| match ours.get(&entry.to_path()) {
+                    Some(file) => file.oid != entry.oid || file.mode != entry.mode(),
+                    None => true,
+                })
+    }
+
+    /// Updates the index and workspace from `ours` to the merge, returning
+    /// it and the paths that conflicted, which are left unmerged in the
+    /// index. Conflicting files are left in the workspace with conflict
+    /// markers, or as the side that has them if the other deleted them.
+    pub(crate) fn merge_trees(
+        &mut self,
+        base: &Files,
+        ours: &Files,
+        theirs: &Files,
+        options: &FileMergeOptions,
+    ) -> Result<(Files, Vec<WsPath>), MergeError> {
+        let work = Self::workspace_of(self.workspace.as_ref(), self.git_dir())?;
+        let mut attrs = Attributes::new(self.common_dir())?;
+        let drivers = Drivers::from_config(&self.config);
+
+        let mut merged = ours.clone();
+        let mut conflicts = BTreeMap::<WsPath, Sides>::new();
+        let paths = base
+            .keys()
+            .chain(ours.keys())
+            .chain(theirs.keys())
+            .collect::<BTreeSet<_>>();
+        for path in paths {
+            let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+            if same(o, t) || same(b, t) {
+                continue;
+            }
+            if same(b, o) {
+                match t {
+                    Some(t) => merged.insert(path.clone(), t.clone()),
+                    None => merged.remove(path),
+                };
+                continue;
+            }
+
+            let conflicted = match (o, t) {
+                (Some(o), Some(t)) if is_file(o.mode) && is_file(t.mode) => {
+                    let mode = match b.map(|b| b.mode) {
+                        _ if o.mode == t.mode => Some(o.mode),
+                        Some(mode) if mode == o.mode => Some(t.mode),
+                        Some(mode) if mode == t.mode => Some(o.mode),
+                        _ => None,
+                    };
+                    let base_data = match b {
+                        Some(b) if is_file(b.mode) => self.db.load(b.oid)?.bytes,
+                        // Both added it
+                        _ => BString::default(),
+                    };
+                    let ours_data = self.db.load(o.oid)?.bytes;
+                    let theirs_data = self.db.load(t.oid)?.bytes;
+
+                    attrs.load_parents(work, path)?;
+                    let file = drivers.merge(
+                        path,
+                        &attrs,
+                        work.path(),
+                        &base_data,
+                        &ours_data,
+                        &theirs_data,
+                        options,
+                    )?;
+                    let clean = file.is_clean();
+                    let oid = db::blob::Builder::new(file.data).store(&self.db)?;
+                    let node = FileNode {
+                        oid,
+                        name: o.name.clone(),
+                        mode: mode.unwrap_or(o.mode),
+                    };
+                    merged.insert(path.clone(), node);
+                    !clean || mode.is_none()
+                }
+                (None, Some(t)) => {
+                    merged.insert(path.clone(), t.clone());
+                    true
+                }
+                _ => true,
+            };
+            if conflicted {
+                debug!("{path} conflicted");
+                conflicts.insert(path.clone(), (b.cloned(), o.cloned(), t.cloned()));
+            }
+        }
+
+        let migration = Migration::new(ours, &merged);
+        let cone = Cone::load(self.git_dir())?;
+        self.fetch_missing_blobs(migration.written_blobs(cone.as_ref()))?;
+        for path in migration.paths() {
+            attrs.load_parents(work, path)?;
+        }
+        let mut ignores = IgnoreRules::new(self.common_dir())?;
+        let mut index = self.index.modify()?;
+        migration.check(work, &index, &attrs, &mut ignores)?;
+        migration.apply(work, &mut self.db, &mut index, &attrs, cone.as_ref())?;
+
+        for (path, (b, o, t)) in &conflicts {
+            let entry = |file: &Option<FileNode>| {
+                file.as_ref().map(|file| {
+                    let stat = Stat {
+                        mode: file.mode,
+                        ..Stat::zeroed()
+                    };
+                    Entry::new(path.clone(), file.oid, stat)
+                })
+            };
+            let conflict = Conflict {
+                base: entry(b),
+                ours: entry(o),
+                theirs: entry(t),
+            };
+            index.add_conflict(path, conflict);
+        }
+        index.commit()?;
+
+        Ok((merged, conflicts.into_keys().collect()))
+    }
+}
+
+/// Of the merge commit, ending in a newline
+fn merge_message(options: &MergeOptions, theirs: Oid<Commit>) -> String {
+    let mut msg = options
+        .message
+        .clone()
+        .unwrap_or_else(|| format!("Merge commit '{}'", theirs.to_hex()));
+    if !msg.ends_with('\n') {
+        msg.push('\n');
+    }
+    msg
+}
+
+fn same(a: Option<&FileNode>, b: Option<&FileNode>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.oid == b.oid && a.mode == b.mode,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn is_file(mode: Mode) -> bool {
+    matches!(mode, Mode::Regular | Mode::Executable)
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum MergeError {
+    /// {0}
+    Bare(#[from] BareError),
+    /// Cannot merge without a commit to merge into
+    NoHead,
+    /// Cannot merge with unmerged paths in the index
+    Unmerged,
+    /// Cannot merge with changes staged
+    Staged,
+    /// Cannot merge while another merge is in progress, commit it first
+    InProgress,
+    /// Failed to read ref
+    ReadRef(#[from] refs::ReadError),
+    /// Failed to walk history
+    RevWalk(#[from] RevWalkError),
+    /// Failed to load commit
+    LoadCommit(#[from] db::LoadError<Commit>),
+    /// Failed to load tree
+    LoadTree(#[from] db::LoadError<Tree>),
+    /// Failed to load blob
+    LoadBlob(#[from] db::LoadError<Blob>),
+    /// Failed to fast-forward
+    FastForward(#[from] Box<CheckoutError>),
+    /// Failed to reload index
+    ReloadIndex(#[from] index::LoadError),
+    /// Failed to open index of modifications
+    OpenIndex(#[from] index::OpenForModificationsError),
+    /// Failed to load attributes
+    LoadAttributes(#[from] attributes::LoadError),
+    /// Failed to load ignore rules
+    LoadIgnores(#[from] ws::ignore::LoadError),
+    /// Failed to load sparse checkout patterns
+    LoadSparse(#[from] sparse::LoadError),
+    /// Failed to merge file
+    File(#[from] MergeFileError),
+    /// {0}
+    Check(#[from] migration::CheckError),
+    /// Failed to fetch missing blobs
+    FetchPromised(#[from] fetch::FetchError),
+    /// Failed to update workspace
+    Apply(#[from] migration::ApplyError),
+    /// Failed to commit changes to index
+    CommitIndex(#[from] index::CommitError),
+    /// Failed to store blob
+    StoreBlob(#[from] db::StoreError<Blob>),
+    /// Failed to store tree
+    StoreTree(#[from] db::StoreError<Tree>),
+    /// Failed to store commit
+    StoreCommit(#[from] db::StoreError<Commit>),
+    /// {0}
+    Identity(#[from] signature::IdentityError),
+    /// Failed to update ref
+    UpdateRef(#[from] refs::UpdateError),
+    /// Failed to write `MERGE_MSG`
+    Message(#[source] io::Error),
+    /// {0}
+    Hook(#[from] hook::HookError),
+}
@@ -0,0 +1,219 @@
+//! Finding what's the same in two sequences, with [Myers' algorithm][myers]
+//!
+//! [myers]: http://www.xmailserver.org/diff2.pdf
+
+use std::convert::TryFrom;
+
+use bstr::ByteSlice;
+
+/// Each line, with its terminator
+pub fn lines(data: &[u8]) -> Vec<&[u8]> {
+    data.lines_with_terminator().collect()
+}
+
+/// The indexes of the items of `a` and `b` that are the same in a shortest
+/// edit from `a` to `b`, in order
+pub fn matching<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let middle = myers(&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut matches = (0..prefix).map(|i| (i, i)).collect::<Vec<_>>();
+    matches.extend(middle.into_iter().map(|(i, j)| (prefix + i, prefix + j)));
+    let (a_start, b_start) = (a.len() - suffix, b.len() - suffix);
+    matches.extend((0..suffix).map(|i| (a_start + i, b_start + i)));
+    matches
+}
+
+/// Lines that differ with the lines of context around them, as a unified
+/// diff shows them
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Hunk<'a> {
+    /// Where the hunk starts in each, counting from 0
+    pub old_start: usize,
+    pub new_start: usize,
+    /// Each line with its terminator, after ` ` if it's context, `-` if it
+    /// was removed, or `+` if it was added
+    pub lines: Vec<(u8, &'a [u8])>,
+}
+
+impl Hunk<'_> {
+    pub fn old_len(&self) -> usize {
+        self.lines.iter().filter(|(op, _)| *op != b'+').count()
+    }
+
+    pub fn new_len(&self) -> usize {
+        self.lines.iter().filter(|(op, _)| *op != b'-').count()
+    }
+
+    /// Like `@@ -1,3 +1,4 @@`
+    pub fn header(&self) -> String {
+        let range = |start: usize, len: usize| match len {
+            0 => format!("{start},0"),
+            1 => format!("{}", start + 1),
+            _ => format!("{},{len}", start + 1),
+        };
+        format!(
+            "@@ -{} +{} @@",
+            range(self.old_start, self.old_len()),
+            range(self.new_start, self.new_len())
+        )
+    }
+}
+
+/// The hunks of a shortest edit from `old` to `new`, with up to `context`
+/// lines around each change. Hunks that would overlap or touch are joined,
+/// like git does.
+pub fn hunks<'a>(old: &[&'a [u8]], new: &[&'a [u8]], context: usize) -> Vec<Hunk<'a>> {
+    // Each line with where it is in each
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for (next_i, next_j) in matching(old, new)
+        .into_iter()
+        .chain([(old.len(), new.len())])
+    {
+        ops.extend((i..next_i).map(|i| (b'-', old[i], i, j)));
+        ops.extend((j..next_j).map(|j| (b'+', new[j], next_i, j)));
+        if next_i < old.len() {
+            ops.push((b' ', old[next_i], next_i, next_j));
+        }
+        (i, j) = (next_i + 1, next_j + 1);
+    }
+
+    let is_context = |k: usize| ops[k].0 == b' ';
+    let mut hunks = Vec::new();
+    let mut k = 0;
+    while let Some(first) = (k..ops.len()).find(|&k| !is_context(k)) {
+        let start = first.saturating_sub(context);
+        let mut end = first;
+        loop {
+            while end < ops.len() && !is_context(end) {
+                end += 1;
+            }
+            let gap = (end..ops.len()).take_while(|&k| is_context(k)).count();
+            if end + gap < ops.len() && gap <= 2 * context {
+                end += gap;
+                continue;
+            }
+            end += gap.min(context);
+            break;
+        }
+        let (_, _, old_start, new_start) = ops[start];
+        hunks.push(Hunk {
+            old_start,
+            new_start,
+            lines: ops[start..end]
+                .iter()
+                .map(|&(op, line, _, _)| (op, line))
+                .collect(),
+        });
+        k = end;
+    }
+    hunks
+}
+
+#[allow(clippy::many_single_char_names)] // As in the paper
+fn myers<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let to_isize = |len| isize::try_from(len).expect("Fits in memory");
+    let as_index = |i| usize::try_from(i).expect("In range");
+    let (n, m) = (to_isize(a.len()), to_isize(b.len()));
+    let max = n + m;
+    // Of each diagonal k, with diagonal -max at 0
+    let at = |k: isize| usize::try_from(k + max).expect("Diagonal in range");
+    let mut furthest = vec![0_isize; at(max) + 2];
+    let mut trace = Vec::new();
+    'search: for d in 0..=max {
+        trace.push(furthest.clone());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && furthest[at(k - 1)] < furthest[at(k + 1)]) {
+                furthest[at(k + 1)]
+            } else {
+                furthest[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[as_index(x)] == b[as_index(y)] {
+                x += 1;
+                y += 1;
+            }
+            furthest[at(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut matches = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, furthest) in trace.iter().enumerate().rev() {
+        let d = to_isize(d);
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && furthest[at(k - 1)] < furthest[at(k + 1)]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = furthest[at(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            matches.push((as_index(x), as_index(y)));
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    matches.reverse();
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn finds_shortest_edits() {
+        let a = b"ABCABBA";
+        let b = b"CBABAC";
+        let matches = matching(a, b);
+        assert_eq!(4, matches.len(), "Myers' example has 5 edits");
+        for &(i, j) in &matches {
+            assert_eq!(a[i], b[j]);
+        }
+        assert!(matches
+            .windows(2)
+            .all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+
+        assert_eq!(vec![(0, 0), (2, 1)], matching(b"abc", b"ac"));
+        assert_eq!(Vec::<(usize, usize)>::new(), matching(b"", b"abc"));
+        assert_eq!(Vec::<(usize, usize)>::new(), matching(b"abc", b"xyz"));
+        assert_eq!(
+            vec![(0, 0), (1, 1)],
+            matching(&lines(b"a\nb\n"), &lines(b"a\nb\nc"))
+        );
+    }
+
+    #[test]
+    fn groups_changes_into_hunks() {
+        let old = lines(b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n");
+        let new = lines(b"1\nTWO\n3\n4\n5\n6\n7\n8\n9\n10\nELEVEN\n12\n");
+        let hunks = hunks(&old, &new, 3);
+        assert_eq!(2, hunks.len());
+        assert_eq!("@@ -1,5 +1,5 @@", hunks[0].header());
+        assert_eq!("@@ -8,5 +8,5 @@", hunks[1].header());
+        assert_eq!(
+            vec![(b'-', b"11\n".as_ref()), (b'+', b"ELEVEN\n".as_ref())],
+            hunks[1].lines[3..5]
+        );
+        assert_eq!(1, super::hunks(&old, &new, 4).len(), "Joined");
+
+        let added = super::hunks(&[], &new[..1], 3);
+        assert_eq!("@@ -0,0 +1 @@", added[0].header());
+        assert!(super::hunks(&old, &old, 3).is_empty());
+    }
+}
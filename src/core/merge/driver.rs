@@ -0,0 +1,291 @@
+//! Merge drivers, configured with `merge.<driver>.driver` and chosen by the
+//! `merge` attribute, so files like changelogs or lockfiles can be merged by
+//! commands that know how, rather than conflicting line by line.
+//!
+//! A driver's command is run through the shell with `%O`, `%A` and `%B`
+//! replaced by files holding the base, ours and theirs, `%L` by the conflict
+//! marker size and `%P` by the path being merged. It leaves the result in
+//! `%A`, and exits with a non-zero status if it conflicted.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+    process::Command,
+};
+
+use tempfile::NamedTempFile;
+use tracing::{debug, instrument};
+
+use super::file::{self, Favor, FileMergeOptions, MergedFile};
+use crate::core::{
+    ws::attributes::{self, Attributes, State},
+    Config, Repo, WsPath,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Drivers {
+    drivers: BTreeMap<String, Driver>,
+    /// `merge.default`, for paths without the `merge` attribute
+    default: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Driver {
+    /// Shown to humans
+    pub name: Option<String>,
+    pub driver: Option<String>,
+}
+
+impl Drivers {
+    pub fn from_config(config: &Config) -> Self {
+        let mut drivers = BTreeMap::<String, Driver>::new();
+        for entry in config.entries() {
+            let (Some(name), "merge") = (&entry.subsection, entry.section.as_str()) else {
+                continue;
+            };
+            let driver = drivers.entry(name.clone()).or_default();
+            let value = entry.value.clone();
+            match entry.key.as_str() {
+                "name" => driver.name = value,
+                "driver" => driver.driver = value,
+                _ => {}
+            }
+        }
+        Self {
+            drivers,
+            default: config.get("merge.default").map(str::to_owned),
+        }
+    }
+
+    pub fn driver(&self, name: &str) -> Option<&Driver> {
+        self.drivers.get(name)
+    }
+
+    /// Merges the file at `path` by its `merge` attribute: a configured
+    /// driver is run from `cwd`, `-merge` (or `binary`) keeps ours as a
+    /// conflict unless a side is favored, `union` keeps both sides of each
+    /// conflict, and anything else is merged as text. If only one side
+    /// changed, that side is taken without running anything.
+    #[allow(clippy::too_many_arguments)]
+    pub fn merge(
+        &self,
+        path: &WsPath,
+        attrs: &Attributes,
+        cwd: &Path,
+        base: &[u8],
+        ours: &[u8],
+        theirs: &[u8],
+        options: &FileMergeOptions,
+    ) -> Result<MergedFile, MergeFileError> {
+        let taken = |side: &[u8]| MergedFile {
+            data: side.to_owned(),
+            conflicts: 0,
+        };
+        if ours == theirs || base == theirs {
+            return Ok(taken(ours));
+        } else if base == ours {
+            return Ok(taken(theirs));
+        }
+
+        let mut options = options.clone();
+        if let Some(State::Value(size)) = attrs.get(path, "conflict-marker-size") {
+            match size.to_string().parse() {
+                Ok(size) => options.marker_size = size,
+                Err(err) => debug!(%err, "Ignoring invalid conflict-marker-size"),
+            }
+        }
+
+        let name = match attrs.get(path, "merge") {
+            Some(State::Unset) => Some("binary".to_owned()),
+            Some(State::Set) => None,
+            Some(State::Value(name)) => Some(name.to_string()),
+            Some(State::Unspecified) | None => self.default.clone(),
+        };
+        match name.as_deref() {
+            None | Some("text") => Ok(file::merge(base, ours, theirs, &options)),
+            Some("union") => {
+                options.favor = Some(Favor::Union);
+                Ok(file::merge(base, ours, theirs, &options))
+            }
+            Some("binary") => Ok(match options.favor {
+                Some(Favor::Theirs) => taken(theirs),
+                Some(Favor::Ours) => taken(ours),
+                Some(Favor::Union) | None => MergedFile {
+                    data: ours.to_owned(),
+                    conflicts: 1,
+                },
+            }),
+            Some(name) => {
+                if let Some(command) = self.driver(name).and_then(|d| d.driver.as_ref()) {
+                    return run_driver(cwd, command, path, base, ours, theirs, &options);
+                }
+                debug!(name, "Merging as text, as merge driver isn't configured");
+                Ok(file::merge(base, ours, theirs, &options))
+            }
+        }
+    }
+}
+
+impl Repo {
+    /// Merges what `ours` and `theirs` changed from `base` in the file at
+    /// `path`, with the driver its attributes choose. See
+    /// [`Drivers::merge`].
+    #[instrument(err, skip(base, ours, theirs))]
+    pub fn merge_file(
+        &self,
+        path: &WsPath,
+        base: &[u8],
+        ours: &[u8],
+        theirs: &[u8],
+        options: &FileMergeOptions,
+    ) -> Result<MergedFile, MergeFileError> {
+        let mut attrs = Attributes::new(self.common_dir())?;
+        let cwd = match &self.workspace {
+            Some(workspace) => {
+                attrs.load_parents(workspace, path)?;
+                workspace.path()
+            }
+            None => self.git_dir(),
+        };
+        Drivers::from_config(&self.config).merge(path, &attrs, cwd, base, ours, theirs, options)
+    }
+}
+
+fn run_driver(
+    cwd: &Path,
+    command: &str,
+    path: &WsPath,
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    options: &FileMergeOptions,
+) -> Result<MergedFile, MergeFileError> {
+    let temp = |data: &[u8]| -> Result<NamedTempFile, MergeFileError> {
+        let mut file = NamedTempFile::new().map_err(MergeFileError::Temp)?;
+        file.write_all(data).map_err(MergeFileError::Temp)?;
+        Ok(file)
+    };
+    let (base_file, ours_file, theirs_file) = (temp(base)?, temp(ours)?, temp(theirs)?);
+
+    let mut expanded = String::new();
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        let quoted = |file: &NamedTempFile| shell_quote(&file.path().to_string_lossy());
+        match chars.next() {
+            Some('O') => expanded.push_str(&quoted(&base_file)),
+            Some('A') => expanded.push_str(&quoted(&ours_file)),
+            Some('B') => expanded.push_str(&quoted(&theirs_file)),
+            Some('L') => expanded.push_str(&options.marker_size.to_string()),
+            Some('P') => expanded.push_str(&shell_quote(&path.to_string())),
+            Some(other) => {
+                expanded.push('%');
+                if other != '%' {
+                    expanded.push(other);
+                }
+            }
+            None => expanded.push('%'),
+        }
+    }
+    debug!(command = %expanded, "Running merge driver");
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(&expanded)
+        .current_dir(cwd)
+        .status()
+        .map_err(|e| MergeFileError::Start(expanded.clone(), e))?;
+    let data = fs::read(ours_file.path()).map_err(|e| MergeFileError::Read(expanded, e))?;
+    Ok(MergedFile {
+        data,
+        conflicts: usize::from(!status.success()),
+    })
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum MergeFileError {
+    /// Failed to load attributes
+    Attributes(#[from] attributes::LoadError),
+    /// Failed to write the sides to merge for the merge driver
+    Temp(#[source] io::Error),
+    /// Failed to start merge driver `{0}`
+    Start(String, #[source] io::Error),
+    /// Failed to read what merge driver `{0}` merged
+    Read(String, #[source] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bstr::ByteSlice;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn merge_with(attrs: &str, config: &str) -> eyre::Result<MergedFile> {
+        let dir = tempdir()?;
+        let drivers = Drivers::from_config(&Config::parse(config)?);
+        let attrs = Attributes::parse_for_test(attrs);
+        let path = WsPath::new_unchecked("CHANGELOG");
+        let merged = drivers.merge(
+            &path,
+            &attrs,
+            dir.path(),
+            b"base\n",
+            b"ours\n",
+            b"theirs\n",
+            &FileMergeOptions::default(),
+        )?;
+        Ok(merged)
+    }
+
+    #[test]
+    fn chooses_drivers_by_attribute() -> eyre::Result<()> {
+        let config = "[merge \"cat\"]\n\tdriver = cat %B %O >>%A && echo %L %P >>%A\n";
+        let merged = merge_with("CHANGELOG merge=cat", config)?;
+        assert!(merged.is_clean());
+        assert_eq!("ours\ntheirs\nbase\n7 CHANGELOG\n", merged.data.to_str()?);
+
+        let merged = merge_with("CHANGELOG merge=cat conflict-marker-size=3", config)?;
+        assert!(merged.data.ends_with(b"3 CHANGELOG\n"));
+
+        let merged = merge_with("*", &format!("{config}[merge]\n\tdefault = cat\n"))?;
+        assert!(merged.is_clean());
+
+        let merged = merge_with("CHANGELOG -merge", config)?;
+        assert_eq!(
+            ("ours\n".as_bytes(), 1),
+            (&merged.data[..], merged.conflicts)
+        );
+
+        let merged = merge_with("CHANGELOG merge=union", config)?;
+        assert_eq!(
+            ("ours\ntheirs\n".as_bytes(), 0),
+            (&merged.data[..], merged.conflicts)
+        );
+
+        let merged = merge_with("CHANGELOG merge=missing", config)?;
+        assert_eq!(1, merged.conflicts);
+        assert!(merged.data.starts_with(b"<<<<<<< ours\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn conflicts_if_the_driver_fails() -> eyre::Result<()> {
+        let config = "[merge \"fail\"]\n\tdriver = \"echo partial >%A; false\"\n";
+        let merged = merge_with("CHANGELOG merge=fail", config)?;
+        assert_eq!(
+            ("partial\n".as_bytes(), 1),
+            (&merged.data[..], merged.conflicts)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+//! Merging text line by line, combining what each side changed from their
+//! base
+
+use super::diff;
+use crate::core::{config, Config};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileMergeOptions {
+    /// After `<<<<<<<`
+    pub ours_label: String,
+    /// After `>>>>>>>`
+    pub theirs_label: String,
+    /// After `|||||||`, with the `diff3` and `zdiff3` styles
+    pub base_label: String,
+    /// Of each conflict marker
+    pub marker_size: usize,
+    /// How to resolve conflicts rather than marking them
+    pub favor: Option<Favor>,
+    pub style: ConflictStyle,
+}
+
+/// How to resolve conflicting hunks, like `git merge-file --ours`,
+/// `--theirs` and `--union`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Favor {
+    Ours,
+    Theirs,
+    /// Both, ours first
+    Union,
+}
+
+/// `merge.conflictStyle`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ConflictStyle {
+    /// Ours and theirs, without what they have in common
+    #[default]
+    Merge,
+    /// Ours, the base and theirs, all as they are
+    Diff3,
+    /// Like `Diff3`, but what ours and theirs have in common at the start and
+    /// end of the conflict is left out of it
+    Zdiff3,
+}
+
+impl Default for FileMergeOptions {
+    fn default() -> Self {
+        Self {
+            ours_label: "ours".into(),
+            theirs_label: "theirs".into(),
+            base_label: "base".into(),
+            marker_size: 7,
+            favor: None,
+            style: ConflictStyle::default(),
+        }
+    }
+}
+
+impl FileMergeOptions {
+    pub fn from_config(config: &Config) -> Result<Self, config::ValueError> {
+        let style = match config
+            .get("merge.conflictstyle")
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("merge") | None => ConflictStyle::Merge,
+            Some("diff3") => ConflictStyle::Diff3,
+            Some("zdiff3") => ConflictStyle::Zdiff3,
+            Some(value) => {
+                return Err(config::ValueError::Invalid(
+                    "merge.conflictStyle".to_owned(),
+                    value.to_owned(),
+                ))
+            }
+        };
+        Ok(Self {
+            style,
+            ..Self::default()
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MergedFile {
+    pub data: Vec<u8>,
+    /// How many hunks conflicted, which are between conflict markers in
+    /// `data`
+    pub conflicts: usize,
+}
+
+impl MergedFile {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts == 0
+    }
+}
+
+/// Merges the lines `ours` and `theirs` changed from `base`. Where both
+/// changed the same lines differently, what they have in common is kept and
+/// the rest is left between conflict markers, unless another side is
+/// favored.
+pub fn merge(base: &[u8], ours: &[u8], theirs: &[u8], options: &FileMergeOptions) -> MergedFile {
+    let (base, ours, theirs) = (diff::lines(base), diff::lines(ours), diff::lines(theirs));
+    let to_ours = matches_of(&base, &ours);
+    let to_theirs = matches_of(&base, &theirs);
+
+    let mut merged = MergedFile::default();
+    let (mut i, mut a, mut b) = (0, 0, 0);
+    while i < base.len() || a < ours.len() || b < theirs.len() {
+        if i < base.len() && to_ours[i] == Some(a) && to_theirs[i] == Some(b) {
+            merged.data.extend_from_slice(base[i]);
+            i += 1;
+            a += 1;
+            b += 1;
+            continue;
+        }
+
+        // Up to the next line neither side changed
+        let (j, a_end, b_end) = (i..base.len())
+            .find_map(|j| Some((j, to_ours[j]?, to_theirs[j]?)))
+            .unwrap_or((base.len(), ours.len(), theirs.len()));
+        merged.hunk(&base[i..j], &ours[a..a_end], &theirs[b..b_end], options);
+        i = j;
+        a = a_end;
+        b = b_end;
+    }
+    merged
+}
+
+/// The line of `to` each line of `from` is the same as
+fn matches_of(from: &[&[u8]], to: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; from.len()];
+    for (i, j) in diff::matching(from, to) {
+        matches[i] = Some(j);
+    }
+    matches
+}
+
+impl MergedFile {
+    fn hunk(
+        &mut self,
+        base: &[&[u8]],
+        ours: &[&[u8]],
+        theirs: &[&[u8]],
+        options: &FileMergeOptions,
+    ) {
+        if ours == base {
+            self.push_lines(theirs);
+        } else if theirs == base || ours == theirs {
+            self.push_lines(ours);
+        } else if options.style == ConflictStyle::Diff3 {
+            self.conflict(base, ours, theirs, options);
+        } else {
+            let prefix = ours.iter().zip(theirs).take_while(|(x, y)| x == y).count();
+            self.push_lines(&ours[..prefix]);
+            let (ours, theirs) = (&ours[prefix..], &theirs[prefix..]);
+            let suffix = ours
+                .iter()
+                .rev()
+                .zip(theirs.iter().rev())
+                .take_while(|(x, y)| x == y)
+                .count();
+
+            self.conflict(
+                base,
+                &ours[..ours.len() - suffix],
+                &theirs[..theirs.len() - suffix],
+                options,
+            );
+            self.push_lines(&ours[ours.len() - suffix..]);
+        }
+    }
+
+    fn conflict(
+        &mut self,
+        base: &[&[u8]],
+        ours: &[&[u8]],
+        theirs: &[&[u8]],
+        options: &FileMergeOptions,
+    ) {
+        match options.favor {
+            Some(Favor::Ours) => return self.push_lines(ours),
+            Some(Favor::Theirs) => return self.push_lines(theirs),
+            Some(Favor::Union) => {
+                self.push_lines(ours);
+                return self.push_lines(theirs);
+            }
+            None => {}
+        }
+        self.conflicts += 1;
+        self.marker(b'<', &options.ours_label, options);
+        self.push_lines(ours);
+        if options.style != ConflictStyle::Merge {
+            self.marker(b'|', &options.base_label, options);
+            self.push_lines(base);
+        }
+        self.marker(b'=', "", options);
+        self.push_lines(theirs);
+        self.marker(b'>', &options.theirs_label, options);
+    }
+
+    fn marker(&mut self, c: u8, label: &str, options: &FileMergeOptions) {
+        // What's before may be the end of a file without a final newline
+        if !self.data.is_empty() && !self.data.ends_with(b"\n") {
+            self.data.push(b'\n');
+        }
+        self.data.resize(self.data.len() + options.marker_size, c);
+        if !label.is_empty() {
+            self.data.push(b' ');
+            self.data.extend_from_slice(label.as_bytes());
+        }
+        self.data.push(b'\n');
+    }
+
+    fn push_lines(&mut self, lines: &[&[u8]]) {
+        for line in lines {
+            self.data.extend_from_slice(line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bstr::ByteSlice;
+    use pretty_assertions::assert_eq;
+
+    fn merge_str(base: &str, ours: &str, theirs: &str) -> (String, usize) {
+        let merged = merge(
+            base.as_bytes(),
+            ours.as_bytes(),
+            theirs.as_bytes(),
+            &FileMergeOptions::default(),
+        );
+        (merged.data.to_str().unwrap().to_owned(), merged.conflicts)
+    }
+
+    #[test]
+    fn combines_changes_to_different_lines() {
+        assert_eq!(
+            ("A\nb\nc\nd\nE\n".to_owned(), 0),
+            merge_str("a\nb\nc\nd\ne\n", "A\nb\nc\nd\ne\n", "a\nb\nc\nd\nE\n")
+        );
+        assert_eq!(
+            ("new\na\nb\nend\n".to_owned(), 0),
+            merge_str("a\nb\n", "new\na\nb\n", "a\nb\nend\n")
+        );
+        assert_eq!(("b\n".to_owned(), 0), merge_str("a\nb\n", "b\n", "b\n"));
+    }
+
+    #[test]
+    fn marks_conflicts() {
+        assert_eq!(
+            (
+                "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\n".to_owned(),
+                1
+            ),
+            merge_str("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\n")
+        );
+    }
+
+    #[test]
+    fn keeps_what_both_sides_changed_the_same_out_of_conflicts() {
+        assert_eq!(
+            (
+                "same\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\nalso same\n".to_owned(),
+                1
+            ),
+            merge_str("base\n", "same\nx\nalso same\n", "same\ny\nalso same\n")
+        );
+    }
+
+    #[test]
+    fn ends_unterminated_lines_before_markers() {
+        let options = FileMergeOptions {
+            ours_label: "HEAD".into(),
+            theirs_label: "topic".into(),
+            marker_size: 3,
+            ..FileMergeOptions::default()
+        };
+        let merged = merge(b"a", b"b", b"c", &options);
+        assert_eq!(
+            "<<< HEAD\nb\n===\nc\n>>> topic\n",
+            merged.data.to_str().unwrap()
+        );
+        assert!(!merged.is_clean());
+    }
+
+    #[test]
+    fn resolves_conflicts_by_favor() {
+        let merged = |favor| {
+            let options = FileMergeOptions {
+                favor: Some(favor),
+                ..FileMergeOptions::default()
+            };
+            let merged = merge(b"a\nb\nc\n", b"a\nours\nc\n", b"a\ntheirs\nc\n", &options);
+            assert!(merged.is_clean());
+            String::from_utf8(merged.data).unwrap()
+        };
+        assert_eq!("a\nours\nc\n", merged(Favor::Ours));
+        assert_eq!("a\ntheirs\nc\n", merged(Favor::Theirs));
+        assert_eq!("a\nours\ntheirs\nc\n", merged(Favor::Union));
+    }
+
+    #[test]
+    fn shows_the_base_in_diff3_styles() {
+        let merged = |style| {
+            let options = FileMergeOptions {
+                style,
+                ..FileMergeOptions::default()
+            };
+            let merged = merge(b"a\nb\nc\n", b"a\nx\ny\nc\n", b"a\nx\nz\nc\n", &options);
+            assert_eq!(1, merged.conflicts);
+            String::from_utf8(merged.data).unwrap()
+        };
+        assert_eq!(
+            "a\n<<<<<<< ours\nx\ny\n||||||| base\nb\n=======\nx\nz\n>>>>>>> theirs\nc\n",
+            merged(ConflictStyle::Diff3)
+        );
+        assert_eq!(
+            "a\nx\n<<<<<<< ours\ny\n||||||| base\nb\n=======\nz\n>>>>>>> theirs\nc\n",
+            merged(ConflictStyle::Zdiff3)
+        );
+    }
+
+    #[test]
+    fn configures_from_config() -> eyre::Result<()> {
+        let style = |config: &str| -> eyre::Result<_> {
+            Ok(FileMergeOptions::from_config(&Config::parse(config)?)?.style)
+        };
+        assert_eq!(ConflictStyle::Merge, style("")?);
+        assert_eq!(
+            ConflictStyle::Zdiff3,
+            style("[merge]\n\tconflictStyle = zdiff3\n")?
+        );
+        assert!(style("[merge]\n\tconflictStyle = other\n").is_err());
+        Ok(())
+    }
+}
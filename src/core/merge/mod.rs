@@ -0,0 +1,13 @@
+//! Merging three ways: what each side changed from their base is combined,
+//! and where both changed the same lines differently conflict markers are
+//! left. How a file is merged depends on its `merge` attribute, see
+//! [`Repo::merge_file`](crate::core::Repo::merge_file).
+
+pub mod commits;
+pub mod diff;
+pub mod driver;
+pub mod file;
+
+pub use commits::{MergeError, MergeOptions, Merged, Strategy};
+pub use driver::{Driver, Drivers, MergeFileError};
+pub use file::{ConflictStyle, Favor, FileMergeOptions, MergedFile};
@@ -0,0 +1,321 @@
+//! Moving the index and workspace from one tree to another, as for checkout.
+//! Files with changes that would be lost are detected before anything is
+//! touched.
+
+use std::{collections::BTreeMap, fmt};
+
+use tracing::debug;
+
+use crate::core::{
+    db::{self, tree::FileNode, Blob},
+    index::entry::{self, Entry, EntryRef, StatusChatty},
+    sparse::Cone,
+    stat::Mode,
+    ws::{self, Attributes, IgnoreRules, ListFilesError, StatFileError},
+    Db, Index, IndexMut, Oid, Stat, Workspace, WsPath,
+};
+
+type Files = BTreeMap<WsPath, FileNode>;
+
+#[derive(Debug, Clone)]
+pub struct Migration {
+    /// By path, the old file (if any) and the new file (if any)
+    changes: BTreeMap<WsPath, (Option<FileNode>, Option<FileNode>)>,
+}
+
+/// Paths whose contents would be lost, grouped like git's "would be
+/// overwritten" errors
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Clobbered {
+    /// Tracked files with local changes
+    pub local_changes: Vec<WsPath>,
+    /// Untracked files where a tracked file would be written
+    pub untracked_overwritten: Vec<WsPath>,
+    /// Untracked files within a directory that would be replaced by a file,
+    /// or occupying a path a file would be deleted from
+    pub untracked_removed: Vec<WsPath>,
+}
+
+impl Migration {
+    pub fn new(old: &Files, new: &Files) -> Self {
+        let mut changes = BTreeMap::new();
+        for (path, old_file) in old {
+            match new.get(path) {
+                Some(new_file) if Self::same(old_file, new_file) => {}
+                new_file => {
+                    changes.insert(path.clone(), (Some(old_file.clone()), new_file.cloned()));
+                }
+            }
+        }
+        for (path, new_file) in new {
+            if !old.contains_key(path) {
+                changes.insert(path.clone(), (None, Some(new_file.clone())));
+            }
+        }
+        Self { changes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Paths that will be written or deleted
+    pub fn paths(&self) -> impl Iterator<Item = &WsPath> {
+        self.changes.keys()
+    }
+
+    /// The blobs [`Self::apply`] will write
+    pub fn written_blobs<'a>(
+        &'a self,
+        cone: Option<&'a Cone>,
+    ) -> impl Iterator<Item = Oid<Blob>> + 'a {
+        self.changes
+            .iter()
+            .filter(move |(path, _)| cone.is_none_or(|cone| cone.contains(path)))
+            .filter_map(|(_, (_, new))| new.as_ref())
+            .filter(|new| new.mode != Mode::Gitlink)
+            .map(|new| new.oid)
+    }
+
+    fn same(a: &FileNode, b: &FileNode) -> bool {
+        a.oid == b.oid && a.mode == b.mode
+    }
+
+    fn matches(entry: Option<EntryRef>, file: Option<&FileNode>) -> bool {
+        match (entry, file) {
+            (None, None) => true,
+            (Some(entry), Some(file)) => entry.oid == file.oid && entry.mode() == file.mode,
+            _ => false,
+        }
+    }
+
+    /// Check nothing would be lost by applying the migration. The attributes
+    /// for each changed path must already be loaded.
+    pub fn check(
+        &self,
+        work: &Workspace,
+        index: &Index,
+        attrs: &Attributes,
+        ignores: &mut IgnoreRules,
+    ) -> Result<(), CheckError> {
+        let mut clobbered = Clobbered::default();
+
+        for (path, (old, new)) in &self.changes {
+            let entry = index.entry(path);
+            // The index may already have the new version, say after a failed
+            // checkout, which is fine.
+            if !Self::matches(entry, old.as_ref()) && !Self::matches(entry, new.as_ref()) {
+                debug!("{path} changed in index");
+                clobbered.local_changes.push(path.clone());
+                continue;
+            }
+            if entry.is_some_and(|entry| entry.skip_worktree()) {
+                continue;
+            }
+
+            let stat = match work.stat(path) {
+                Ok(stat) => stat,
+                Err(err) if err.is_not_found() => {
+                    if Self::has_untracked_parent(work, index, path)? {
+                        debug!("{path} has an untracked file as a parent");
+                        if old.is_some() {
+                            clobbered.untracked_removed.push(path.clone());
+                        } else {
+                            clobbered.untracked_overwritten.push(path.clone());
+                        }
+                    }
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            let is_dir = stat.mode == Mode::Gitlink;
+            let expecting_dir = entry.is_some_and(|entry| entry.mode() == Mode::Gitlink);
+            if is_dir && !expecting_dir {
+                let listing = work.list_files_under(std::slice::from_ref(path), ignores)?;
+                let untracked = listing
+                    .files
+                    .into_iter()
+                    .filter(|file| !index.is_tracked_file(file))
+                    .collect::<Vec<_>>();
+                if !untracked.is_empty() {
+                    debug!("{path} is a directory with untracked files");
+                    clobbered.untracked_removed.extend(untracked);
+                }
+                continue;
+            }
+
+            let Some(entry) = entry else {
+                debug!("{path} is untracked");
+                clobbered.untracked_overwritten.push(path.clone());
+                continue;
+            };
+            if let StatusChatty::Modified | StatusChatty::TypeChanged =
+                entry.index_status_chatty(work, attrs)?
+            {
+                debug!("{path} changed in workspace");
+                clobbered.local_changes.push(path.clone());
+            }
+        }
+
+        if clobbered.is_empty() {
+            Ok(())
+        } else {
+            clobbered.untracked_removed.sort();
+            clobbered.untracked_removed.dedup();
+            Err(CheckError::Clobbered(clobbered))
+        }
+    }
+
+    fn has_untracked_parent(
+        work: &Workspace,
+        index: &Index,
+        path: &WsPath,
+    ) -> Result<bool, StatFileError> {
+        for parent in path.parents() {
+            match work.stat(&parent) {
+                Ok(stat) if stat.mode != Mode::Gitlink => {
+                    return Ok(!index.is_tracked_file(&parent));
+                }
+                Ok(_) => {}
+                Err(err) if err.is_not_found() => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Update the workspace and index. Deletions are done first, so that a
+    /// file can replace a directory and vice versa. Files outside the cone
+    /// (if given) are only recorded in the index, as skip-worktree.
+    ///
+    /// The workspace is updated all at once (see [`ws::Transaction`]), so if
+    /// this fails it's left as it was.
+    pub fn apply(
+        &self,
+        work: &Workspace,
+        db: &mut Db,
+        index: &mut IndexMut,
+        attrs: &Attributes,
+        cone: Option<&Cone>,
+    ) -> Result<(), ApplyError> {
+        let in_cone = |path: &WsPath| cone.is_none_or(|cone| cone.contains(path));
+        let mut transaction = work.transaction()?;
+
+        for (path, (old, new)) in self.changes.iter().rev() {
+            if old.is_some()
+                && new.is_none()
+                && !index.entry(path).is_some_and(|entry| entry.skip_worktree())
+            {
+                debug!("Deleting {path}");
+                transaction.remove(path.clone());
+            }
+        }
+        for (path, (_, new)) in &self.changes {
+            match new {
+                Some(new) if in_cone(path) => {
+                    debug!("Writing {path}");
+                    let blob = db.open_blob(new.oid)?;
+                    transaction.write_from_git_reader(path.clone(), new.mode, blob, attrs)?;
+                }
+                Some(_) => debug!("Skipping {path}, outside sparse checkout"),
+                None => {}
+            }
+        }
+        transaction.commit()?;
+
+        for (path, (_, new)) in &self.changes {
+            let Some(new) = new else {
+                index.remove(path);
+                continue;
+            };
+            let entry = if in_cone(path) {
+                let stat = work.stat_tracked(path, new.mode)?;
+                Entry::new(path.clone(), new.oid, stat)
+            } else {
+                let stat = Stat {
+                    mode: new.mode,
+                    ..Stat::zeroed()
+                };
+                let mut entry = Entry::new(path.clone(), new.oid, stat);
+                entry.set_skip_worktree(true);
+                entry
+            };
+            index.add(entry);
+        }
+
+        Ok(())
+    }
+}
+
+impl Clobbered {
+    pub fn is_empty(&self) -> bool {
+        self.local_changes.is_empty()
+            && self.untracked_overwritten.is_empty()
+            && self.untracked_removed.is_empty()
+    }
+}
+
+impl fmt::Display for Clobbered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let groups = [
+            (
+                &self.local_changes,
+                "Your local changes to the following files would be overwritten by checkout:",
+                "Please commit your changes or stash them before you switch branches.",
+            ),
+            (
+                &self.untracked_overwritten,
+                "The following untracked working tree files would be overwritten by checkout:",
+                "Please move or remove them before you switch branches.",
+            ),
+            (
+                &self.untracked_removed,
+                "The following untracked working tree files would be removed by checkout:",
+                "Please move or remove them before you switch branches.",
+            ),
+        ];
+        let mut first = true;
+        for (paths, header, footer) in groups {
+            if paths.is_empty() {
+                continue;
+            }
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            writeln!(f, "{header}")?;
+            for path in paths {
+                writeln!(f, "\t{path}")?;
+            }
+            write!(f, "{footer}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum CheckError {
+    /// {0}
+    Clobbered(Clobbered),
+    /// Failed to stat file
+    Stat(#[from] StatFileError),
+    /// Failed to list files in directory
+    ListFiles(#[from] ListFilesError),
+    /// Failed to check if file unchanged
+    IsUnchanged(#[from] entry::IsUnchangedError),
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ApplyError {
+    /// Failed to open blob
+    OpenBlob(#[from] db::LoadBytesError<Blob>),
+    /// Failed to create scratch directory
+    Scratch(#[from] ws::transaction::ScratchError),
+    /// Failed to write file
+    Write(#[from] ws::WriteFromGitError),
+    /// Failed to update workspace
+    Commit(#[from] ws::WriteFileError),
+    /// Failed to stat file
+    Stat(#[from] StatFileError),
+}
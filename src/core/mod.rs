@@ -1,20 +1,85 @@
+pub mod cancel;
+pub mod check_attr;
+pub mod check_ignore;
+pub mod clone;
+pub mod commit_msg;
+pub mod config;
 pub mod db;
+pub mod discover;
+pub mod fast_export;
+pub mod fast_import;
+pub mod fetch;
+pub mod fsync;
+pub mod hook;
 pub mod index;
+pub mod init;
 pub mod locked_file;
+pub mod ls_files;
+pub mod ls_tree;
+pub mod mailbox;
+pub mod maintenance;
+pub mod merge;
+pub mod migration;
+pub mod negotiate;
+pub mod notes;
+pub mod pack;
+pub mod patch_id;
+pub mod pathspec;
+mod platform;
+pub mod progress;
+pub mod push;
 pub mod refs;
+pub mod refspec;
+pub mod replace;
 pub mod repo;
+pub mod rerere;
+pub mod rev_parse;
+pub mod revwalk;
+mod safe_directory;
+pub mod sequencer;
+#[cfg(feature = "serde")]
+mod serialize;
+pub mod serve;
+pub mod sparse;
 pub mod stat;
 pub mod status;
+pub mod submodule;
+pub mod trailers;
+pub mod transport;
+pub mod update_index;
+pub mod verify;
+#[cfg(feature = "watch")]
+pub mod watch;
 pub mod with_digest;
 pub mod ws;
 
+pub use cancel::CancelToken;
+pub use clone::CloneOptions;
+pub use commit_msg::Cleanup;
+pub use config::Config;
 pub use db::{Db, Object, ObjectBuilder, Oid};
-pub use index::{Index, IndexMut};
+pub use discover::{DiscoverOptions, OpenOptions};
+pub use fast_import::FastImportOptions;
+pub use fetch::{FetchOptions, Fetched, Tags};
+pub use fsync::Fsync;
+pub use index::{Index, IndexMut, MappedIndex};
 pub use locked_file::LockedFile;
+pub use ls_files::{ListedFile, LsFilesOptions};
+pub use ls_tree::{ListedNode, LsTreeOptions};
+pub use mailbox::{FormatPatchOptions, Patch};
+pub use patch_id::Cherry;
+pub use pathspec::{Pathspec, Pathspecs};
+pub use progress::Progress;
+pub use push::{Lease, PushOptions, PushStatus, PushUpdate, Pushed};
 pub use refs::Refs;
-pub use repo::Repo;
+pub use refspec::Refspec;
+pub use repo::{CheckoutOptions, CommitOptions, Repo};
+pub use rev_parse::RevRange;
+pub use sequencer::Sequenced;
 pub use stat::Stat;
-pub use status::{FileStatus, Status};
+pub use status::{FileStatus, Status, StatusOptions};
+pub use trailers::{Trailer, TrailerOptions, Trailers};
+pub use update_index::CacheInfo;
 pub use with_digest::WithDigest;
 pub use ws::Workspace;
 pub use ws::WsPath;
@@ -0,0 +1,272 @@
+//! Finding the commits we have in common with a remote, so that it only
+//! sends what we're missing. We send our commits newest first in rounds of
+//! haves, and the remote acknowledges the ones it has, which it does for
+//! each (like `multi_ack_detailed`) as protocol v2 always does.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use tracing::{debug, instrument};
+
+use crate::core::{
+    db::UntypedOid,
+    progress::Progress,
+    revwalk::{RevWalk, RevWalkError},
+    transport::{
+        upload_pack::{FetchResponse, UploadPackError},
+        UploadPack,
+    },
+    Db,
+};
+
+/// Haves to send in the first round, which doubles each round up to
+/// [`MAX_ROUND`]
+const INITIAL_ROUND: usize = 16;
+const MAX_ROUND: usize = 1024;
+/// How many haves without a new common commit before we stop looking, and
+/// take a bigger pack instead
+const MAX_IN_VAIN: usize = 256;
+
+/// What the remote sent once we were done
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Negotiated {
+    pub pack: Vec<u8>,
+    /// Commits sent without their parents
+    pub shallow: Vec<UntypedOid>,
+    /// Shallow commits whose parents were sent
+    pub unshallow: Vec<UntypedOid>,
+}
+
+/// A pack of `wants` and everything they need that can't be reached from
+/// `common` or what the remote finds it has of `tips` and their history
+#[instrument(err, skip(db, upload_pack, common, progress))]
+pub fn negotiate(
+    db: &Db,
+    upload_pack: &mut UploadPack,
+    wants: &[UntypedOid],
+    tips: &[UntypedOid],
+    common: impl IntoIterator<Item = UntypedOid>,
+    progress: &mut dyn Progress,
+) -> Result<Negotiated, NegotiateError> {
+    let mut negotiator = Negotiator {
+        common: common.into_iter().collect(),
+        skipped: BTreeSet::new(),
+        walked: BTreeMap::new(),
+    };
+    let mut walk = RevWalk::new(db);
+    walk.ignore_replacements();
+    for &tip in tips {
+        walk.push(tip)?;
+    }
+
+    let mut round = INITIAL_ROUND;
+    let mut in_vain = 0;
+    loop {
+        let mut haves = Vec::new();
+        while haves.len() < round {
+            match walk.next().transpose()? {
+                Some(commit) => haves.extend(negotiator.walked(commit.oid, commit.parents)),
+                None => break,
+            }
+        }
+        if haves.is_empty() {
+            break;
+        }
+        in_vain += haves.len();
+
+        let mut sent = negotiator.common.iter().copied().collect::<Vec<_>>();
+        sent.extend(haves);
+        let response = upload_pack.fetch(wants, &sent, false, progress)?;
+        for &oid in &response.common {
+            if negotiator.mark_common(oid) {
+                in_vain = 0;
+            }
+        }
+        if response.pack.is_some() {
+            return Ok(negotiated(response)?);
+        }
+        if in_vain >= MAX_IN_VAIN {
+            debug!(in_vain, "Giving up on finding more in common");
+            break;
+        }
+        round = (round * 2).min(MAX_ROUND);
+    }
+
+    let common = negotiator.common.into_iter().collect::<Vec<_>>();
+    debug!(common = common.len(), "Done");
+    let response = upload_pack.fetch(wants, &common, true, progress)?;
+    Ok(negotiated(response)?)
+}
+
+fn negotiated(response: FetchResponse) -> Result<Negotiated, UploadPackError> {
+    Ok(Negotiated {
+        pack: response.pack.ok_or(UploadPackError::NoPack)?,
+        shallow: response.shallow,
+        unshallow: response.unshallow,
+    })
+}
+
+#[derive(Debug)]
+struct Negotiator {
+    /// What the remote has, which we keep sending as the remote forgets
+    /// between rounds
+    common: BTreeSet<UntypedOid>,
+    /// Ancestors of common commits, which the remote has too, so aren't worth
+    /// sending
+    skipped: BTreeSet<UntypedOid>,
+    /// The parents of the commits we've walked
+    walked: BTreeMap<UntypedOid, Vec<UntypedOid>>,
+}
+
+impl Negotiator {
+    /// The commit if it's worth sending
+    fn walked(&mut self, oid: UntypedOid, parents: Vec<UntypedOid>) -> Option<UntypedOid> {
+        let skip = self.common.contains(&oid) || self.skipped.contains(&oid);
+        if skip {
+            self.skipped.extend(parents.iter().copied());
+        }
+        self.walked.insert(oid, parents);
+        if skip {
+            None
+        } else {
+            Some(oid)
+        }
+    }
+
+    /// Whether the commit wasn't known to be common
+    fn mark_common(&mut self, oid: UntypedOid) -> bool {
+        if !self.common.insert(oid) {
+            return false;
+        }
+        let mut pending = vec![oid];
+        while let Some(oid) = pending.pop() {
+            for &parent in self.walked.get(&oid).into_iter().flatten() {
+                // Commits we haven't walked are marked when we do
+                if self.skipped.insert(parent) && self.walked.contains_key(&parent) {
+                    pending.push(parent);
+                }
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum NegotiateError {
+    /// Failed to walk our commits
+    Walk(#[from] RevWalkError),
+    /// Failed to fetch from remote
+    UploadPack(#[from] UploadPackError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::transport::canned::{packets, Canned};
+    use bstr::{BString, ByteSlice};
+    use pretty_assertions::assert_eq;
+    use std::rc::Rc;
+    use tempfile::tempdir;
+
+    /// The oldest first
+    fn history(db: &Db, len: i64) -> Vec<UntypedOid> {
+        let mut history: Vec<UntypedOid> = Vec::new();
+        for time in 0..len {
+            let parent = history
+                .last()
+                .map(|parent| format!("parent {}\n", parent.to_hex()))
+                .unwrap_or_default();
+            let data = format!(
+                "tree {}\n{parent}author A <a@b> {time} +0000\ncommitter C <c@d> {time} +0000\n\n{time}\n",
+                UntypedOid::zero().to_hex()
+            );
+            history.push(db.store_raw(b"commit", data.as_bytes()).unwrap());
+        }
+        history
+    }
+
+    /// The haves and whether we're done
+    fn haves(request: &BString) -> (Vec<UntypedOid>, bool) {
+        let haves = request
+            .lines()
+            .filter_map(|line| line.get(4..)?.strip_prefix(b"have "))
+            .map(|oid| UntypedOid::parse(oid).unwrap())
+            .collect();
+        (haves, request.find("done\n").is_some())
+    }
+
+    #[test]
+    fn skips_what_the_remote_has() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        std::fs::create_dir(dir.path().join("objects"))?;
+        let db = Db::new(dir.path());
+        let history = history(&db, 40);
+        let want = UntypedOid::parse("7".repeat(40))?;
+
+        let requests = Rc::default();
+        let transport = Canned {
+            advertisement: packets(&["version 2\n", "fetch\n", "0000"]),
+            responses: vec![
+                packets(&[
+                    "acknowledgments\n",
+                    &format!("ACK {}\n", history[30].to_hex()),
+                    "0000",
+                ]),
+                packets(&["packfile\n", "\x01PACK", "0000"]),
+            ],
+            requests: Rc::clone(&requests),
+        };
+        let mut upload_pack = UploadPack::connect(Box::new(transport))?;
+
+        let negotiated = negotiate(
+            &db,
+            &mut upload_pack,
+            &[want],
+            &[history[39]],
+            None,
+            &mut (),
+        )?;
+        assert_eq!(b"PACK".to_vec(), negotiated.pack);
+
+        let requests = requests.borrow();
+        let newest = history.iter().rev().copied();
+        assert_eq!(
+            vec![
+                (newest.take(INITIAL_ROUND).collect(), false),
+                // Everything older than 30 is common too
+                (vec![history[30]], true),
+            ],
+            requests.iter().map(haves).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stops_once_the_remote_is_ready() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        std::fs::create_dir(dir.path().join("objects"))?;
+        let db = Db::new(dir.path());
+        let history = history(&db, 10);
+        let want = UntypedOid::parse("7".repeat(40))?;
+
+        let requests = Rc::default();
+        let transport = Canned {
+            advertisement: packets(&["version 2\n", "fetch\n", "0000"]),
+            responses: vec![packets(&[
+                "acknowledgments\n",
+                &format!("ACK {}\n", history[8].to_hex()),
+                "ready\n",
+                "0001",
+                "packfile\n",
+                "\x01PACK",
+                "0000",
+            ])],
+            requests: Rc::clone(&requests),
+        };
+        let mut upload_pack = UploadPack::connect(Box::new(transport))?;
+
+        let negotiated = negotiate(&db, &mut upload_pack, &[want], &[history[9]], None, &mut ())?;
+        assert_eq!(b"PACK".to_vec(), negotiated.pack);
+        assert_eq!(1, requests.borrow().len());
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+//! Notes, messages attached to commits without changing them, like `git
+//! notes`. The commits of a notes ref (`refs/notes/commits` by default) have
+//! a blob for each note, named by the hex of the commit it's for. Once there
+//! are many, they're split into directories by the first bytes (the fan-out)
+//! like `ab/cdef...`.
+
+use std::collections::BTreeMap;
+
+use bstr::{BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use crate::core::{
+    db::{self, blob, commit, signature, tree, Blob, Commit, Tree},
+    refs, stat, ObjectBuilder, Oid, Repo, WsPath,
+};
+
+pub const DEFAULT_REF: &str = "refs/notes/commits";
+/// More notes than this and they're split by another byte
+const FANOUT_THRESHOLD: usize = 256;
+
+impl Repo {
+    /// `core.notesRef`, or else [`DEFAULT_REF`]
+    pub fn notes_ref(&self) -> BString {
+        self.config
+            .get("core.notesRef")
+            .unwrap_or(DEFAULT_REF)
+            .into()
+    }
+
+    /// Every note, by the commit it's for
+    #[instrument(err)]
+    pub fn notes(&mut self) -> Result<BTreeMap<Oid<Commit>, BString>, NotesError> {
+        let notes = self.load_notes()?.notes;
+        notes
+            .into_iter()
+            .map(|(commit, blob)| Ok((commit, self.db.load(blob)?.bytes)))
+            .collect()
+    }
+
+    #[instrument(err)]
+    pub fn note(&mut self, commit: Oid<Commit>) -> Result<Option<BString>, NotesError> {
+        match self.load_notes()?.notes.get(&commit) {
+            Some(&blob) => Ok(Some(self.db.load(blob)?.bytes)),
+            None => Ok(None),
+        }
+    }
+
+    /// Fails if the commit has a note, unless `force`
+    #[instrument(err)]
+    pub fn add_note(
+        &mut self,
+        commit: Oid<Commit>,
+        note: impl Into<BString> + std::fmt::Debug,
+        force: bool,
+    ) -> Result<(), NotesError> {
+        let mut notes = self.load_notes()?;
+        if !force && notes.notes.contains_key(&commit) {
+            return Err(NotesError::Exists(commit));
+        }
+        let blob = blob::Builder::new(with_newline(note.into())).store(&self.db)?;
+        notes.notes.insert(commit, blob);
+        self.store_notes(notes, "Notes added by 'git notes add'\n")
+    }
+
+    /// Adds to the end of the commit's note after a blank line, or adds one
+    /// if it hasn't one
+    #[instrument(err)]
+    pub fn append_note(
+        &mut self,
+        commit: Oid<Commit>,
+        note: impl Into<BString> + std::fmt::Debug,
+    ) -> Result<(), NotesError> {
+        let mut notes = self.load_notes()?;
+        let mut appended = match notes.notes.get(&commit) {
+            Some(&blob) => {
+                let mut existing = self.db.load(blob)?.bytes;
+                existing.push(b'\n');
+                existing
+            }
+            None => BString::from(Vec::new()),
+        };
+        appended.extend_from_slice(&note.into());
+        let blob = blob::Builder::new(with_newline(appended)).store(&self.db)?;
+        notes.notes.insert(commit, blob);
+        self.store_notes(notes, "Notes added by 'git notes append'\n")
+    }
+
+    /// Fails if the commit hasn't a note
+    #[instrument(err)]
+    pub fn remove_note(&mut self, commit: Oid<Commit>) -> Result<(), NotesError> {
+        let mut notes = self.load_notes()?;
+        if notes.notes.remove(&commit).is_none() {
+            return Err(NotesError::Missing(commit));
+        }
+        self.store_notes(notes, "Notes removed by 'git notes remove'\n")
+    }
+
+    /// Gives `to` the note of `from`, failing if `from` hasn't one or `to`
+    /// already has one, unless `force`
+    #[instrument(err)]
+    pub fn copy_note(
+        &mut self,
+        from: Oid<Commit>,
+        to: Oid<Commit>,
+        force: bool,
+    ) -> Result<(), NotesError> {
+        let mut notes = self.load_notes()?;
+        let blob = *notes.notes.get(&from).ok_or(NotesError::Missing(from))?;
+        if !force && notes.notes.contains_key(&to) {
+            return Err(NotesError::Exists(to));
+        }
+        notes.notes.insert(to, blob);
+        self.store_notes(notes, "Notes added by 'git notes copy'\n")
+    }
+
+    fn load_notes(&mut self) -> Result<Loaded, NotesError> {
+        let notes_ref = self.notes_ref();
+        let mut notes = Loaded {
+            commit: self.refs.read_ref(notes_ref.as_bstr())?,
+            notes: BTreeMap::new(),
+            other: BTreeMap::new(),
+        };
+        let Some(commit) = notes.commit else {
+            return Ok(notes);
+        };
+        let tree = self.db.load(commit)?.tree;
+        for (path, file) in self.db.load_tree_files(&WsPath::root(), tree)? {
+            if let Some(commit) = note_commit(&path) {
+                notes.notes.insert(commit, file.oid);
+            } else {
+                debug!(%path, "Keeping file that isn't a note");
+                notes.other.insert(path, file);
+            }
+        }
+        Ok(notes)
+    }
+
+    fn store_notes(&mut self, notes: Loaded, msg: &str) -> Result<(), NotesError> {
+        let fanout = fanout(notes.notes.len());
+        let entries = notes.notes.iter().map(|(commit, &oid)| {
+            let hex = commit.to_hex();
+            let (dirs, rest) = hex.split_at(fanout * 2);
+            let mut path = WsPath::root();
+            for dir in dirs.as_bytes().chunks(2) {
+                path = path.join_bytes(dir.as_bstr());
+            }
+            tree::EntryBuilder {
+                oid,
+                path: path.join_bytes(rest.as_bytes().as_bstr()),
+                mode: stat::Mode::Regular,
+            }
+        });
+        let others = notes
+            .other
+            .into_iter()
+            .map(|(path, file)| tree::EntryBuilder {
+                oid: file.oid,
+                path,
+                mode: file.mode,
+            });
+        let tree = tree::Builder::new()
+            .entries(entries.chain(others))
+            .store(&self.db)?;
+
+        let author = self.signature(signature::Role::Author)?;
+        let committer = self.signature(signature::Role::Committer)?;
+        let commit =
+            commit::Builder::new(notes.commit, tree, author, committer, msg).store(&self.db)?;
+        let mut transaction = self.refs.transaction();
+        transaction.update(self.notes_ref(), notes.commit, Some(commit));
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+/// What's in the tree of a notes commit
+#[derive(Debug)]
+struct Loaded {
+    /// `None` if there aren't any notes yet
+    commit: Option<Oid<Commit>>,
+    notes: BTreeMap<Oid<Commit>, Oid<Blob>>,
+    /// Anything else, which is kept as is
+    other: BTreeMap<WsPath, tree::FileNode>,
+}
+
+/// The commit a note is for, if the path is one, like `ab/cdef...`
+fn note_commit(path: &WsPath) -> Option<Oid<Commit>> {
+    let components = path
+        .components()
+        .map(|name| name.as_bytes())
+        .collect::<Vec<_>>();
+    let (_, dirs) = components.split_last()?;
+    if dirs.iter().any(|dir| dir.len() != 2) {
+        return None;
+    }
+    Oid::parse(components.concat()).ok()
+}
+
+/// How many bytes of the commit the directories of notes are named by, which
+/// grows as they don't fit in one directory like git
+fn fanout(notes: usize) -> usize {
+    let mut fanout = 0;
+    let mut per_dir = notes;
+    while per_dir > FANOUT_THRESHOLD {
+        per_dir /= FANOUT_THRESHOLD;
+        fanout += 1;
+    }
+    fanout
+}
+
+fn with_newline(mut note: BString) -> BString {
+    if !note.ends_with(b"\n") {
+        note.push(b'\n');
+    }
+    note
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum NotesError {
+    /// {0} already has a note
+    Exists(Oid<Commit>),
+    /// {0} has no note
+    Missing(Oid<Commit>),
+    /// Failed to read notes ref
+    ReadRef(#[from] refs::ReadError),
+    /// Failed to load notes commit
+    LoadCommit(#[from] db::LoadError<Commit>),
+    /// Failed to load notes tree
+    LoadTree(#[from] db::LoadError<Tree>),
+    /// Failed to load note
+    LoadBlob(#[from] db::LoadError<Blob>),
+    /// Failed to store note
+    StoreBlob(#[from] db::StoreError<Blob>),
+    /// Failed to store notes tree
+    StoreTree(#[from] db::StoreError<Tree>),
+    /// Failed to store notes commit
+    StoreCommit(#[from] db::StoreError<Commit>),
+    /// {0}
+    Identity(#[from] signature::IdentityError),
+    /// Failed to update notes ref
+    UpdateRef(#[from] refs::TransactionError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fans_out_as_notes_grow() {
+        assert_eq!(0, fanout(0));
+        assert_eq!(0, fanout(256));
+        assert_eq!(1, fanout(257));
+        assert_eq!(1, fanout(256 * 256));
+        assert_eq!(2, fanout(256 * 256 + 256));
+
+        let hex = "ab".repeat(20);
+        let commit = Oid::parse(&hex).ok();
+        assert_eq!(commit, note_commit(&WsPath::new_unchecked(&hex)));
+        let fanned_out = format!("ab/ab/{}", &hex[4..]);
+        assert_eq!(commit, note_commit(&WsPath::new_unchecked(fanned_out)));
+        assert_eq!(
+            None,
+            note_commit(&WsPath::new_unchecked(format!("abab/{}", &hex[4..])))
+        );
+        assert_eq!(None, note_commit(&WsPath::new_unchecked("README")));
+    }
+}
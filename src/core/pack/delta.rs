@@ -0,0 +1,216 @@
+//! Deltas, which describe an object as instructions to copy ranges of a base
+//! object and insert new data
+
+use std::collections::BTreeMap;
+
+/// Matches are looked for by blocks of this many bytes of the base
+const BLOCK: usize = 16;
+/// The most a copy can take, as larger sizes need more than the three bytes
+/// older versions of git read
+const MAX_COPY: usize = 0x10000;
+/// The most an insert can take, as its size is in the seven low bits
+const MAX_INSERT: usize = 0x7f;
+
+/// A delta from `base` to `target`, which copies what they share and inserts
+/// everything else
+pub fn create(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut blocks = BTreeMap::new();
+    for offset in (0..=base.len().saturating_sub(BLOCK)).step_by(BLOCK) {
+        if let Some(block) = base.get(offset..offset + BLOCK) {
+            blocks.entry(block).or_insert(offset);
+        }
+    }
+
+    let mut delta = Vec::new();
+    write_size(&mut delta, base.len());
+    write_size(&mut delta, target.len());
+    let mut inserted = 0;
+    let mut pos = 0;
+    while pos + BLOCK <= target.len() {
+        let Some(&(mut start)) = blocks.get(&target[pos..pos + BLOCK]) else {
+            pos += 1;
+            continue;
+        };
+        // What comes before the block may match too
+        while start > 0 && pos > inserted && base[start - 1] == target[pos - 1] {
+            start -= 1;
+            pos -= 1;
+        }
+        let len = base[start..]
+            .iter()
+            .zip(&target[pos..])
+            .take_while(|(base, target)| base == target)
+            .count();
+        insert(&mut delta, &target[inserted..pos]);
+        copy(&mut delta, start, len);
+        pos += len;
+        inserted = pos;
+    }
+    insert(&mut delta, &target[inserted..]);
+    delta
+}
+
+#[allow(clippy::cast_possible_truncation)] // Masked
+fn write_size(delta: &mut Vec<u8>, mut size: usize) {
+    while size >= 0x80 {
+        delta.push((size & 0x7f) as u8 | 0x80);
+        size >>= 7;
+    }
+    delta.push(size as u8);
+}
+
+#[allow(clippy::cast_possible_truncation)] // Less than `MAX_INSERT`
+fn insert(delta: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(MAX_INSERT) {
+        delta.push(chunk.len() as u8);
+        delta.extend_from_slice(chunk);
+    }
+}
+
+/// Each byte of the offset and size is only written if it isn't zero, with
+/// a bit of the op saying it's there
+#[allow(clippy::cast_possible_truncation)] // Masked
+fn copy(delta: &mut Vec<u8>, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(MAX_COPY);
+        let op = delta.len();
+        delta.push(0x80);
+        for i in 0..4 {
+            let byte = (offset >> (8 * i)) as u8;
+            if byte != 0 {
+                delta[op] |= 1 << i;
+                delta.push(byte);
+            }
+        }
+        // Which is zero for `MAX_COPY`
+        for i in 0..3 {
+            let byte = (size >> (8 * i)) as u8;
+            if byte != 0 {
+                delta[op] |= 0x10 << i;
+                delta.push(byte);
+            }
+        }
+        offset += size;
+        len -= size;
+    }
+}
+
+/// The target of the delta, or `None` if it doesn't apply to the base
+pub fn apply(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut delta = delta.iter().copied();
+    let base_size = read_size(&mut delta)?;
+    let target_size = read_size(&mut delta)?;
+    if base_size != base.len() {
+        return None;
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    while let Some(op) = delta.next() {
+        if op & 0x80 == 0 {
+            // Insert the next `op` bytes
+            if op == 0 {
+                return None;
+            }
+            for _ in 0..op {
+                target.push(delta.next()?);
+            }
+            continue;
+        }
+
+        // Copy, where each bit says whether a byte of the offset or size is
+        // present
+        let mut read_bytes = |bits: u8, count: u32| {
+            let mut value = 0_usize;
+            for i in 0..count {
+                if op & (bits << i) != 0 {
+                    value |= usize::from(delta.next()?) << (8 * i);
+                }
+            }
+            Some(value)
+        };
+        let offset = read_bytes(0x01, 4)?;
+        let size = match read_bytes(0x10, 3)? {
+            0 => 0x10000,
+            size => size,
+        };
+        target.extend_from_slice(base.get(offset..offset.checked_add(size)?)?);
+    }
+
+    (target.len() == target_size).then_some(target)
+}
+
+/// The size of the target of the delta, without applying it
+pub fn target_size(delta: &[u8]) -> Option<usize> {
+    let mut delta = delta.iter().copied();
+    read_size(&mut delta)?;
+    read_size(&mut delta)
+}
+
+fn read_size(delta: &mut impl Iterator<Item = u8>) -> Option<usize> {
+    let mut size = 0_usize;
+    for shift in (0..).step_by(7) {
+        let byte = delta.next()?;
+        size |= usize::from(byte & 0x7f).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(size);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn copies_and_inserts() {
+        let base = b"hello, world";
+        let delta = [
+            12, // Base size
+            14, // Target size
+            0x91, 7, 5, // Copy "world"
+            4, b' ', b'a', b'n', b'd', // Insert " and"
+            0x90, 5, // Copy "hello"
+        ];
+        assert_eq!(Some(b"world andhello".to_vec()), apply(base, &delta));
+    }
+
+    #[test]
+    fn creates_deltas_that_apply() {
+        let base = (0..2000)
+            .map(|line| format!("line {line}\n"))
+            .collect::<Vec<_>>()
+            .concat();
+        let target = base.replace("line 1000\n", "changed\n") + "added";
+        let delta = create(base.as_bytes(), target.as_bytes());
+        assert_eq!(
+            Some(target.as_bytes().to_vec()),
+            apply(base.as_bytes(), &delta)
+        );
+        assert!(delta.len() < 100, "{}", delta.len());
+
+        // Copies larger than `MAX_COPY`, at offsets with zero bytes
+        let base = [b"x".repeat(0x100), b"y".repeat(3 * MAX_COPY)].concat();
+        let target = base[0x100..].to_vec();
+        let delta = create(&base, &target);
+        assert_eq!(Some(target), apply(&base, &delta));
+
+        for (base, target) in [(&b""[..], &b"new"[..]), (b"old", b""), (b"short", b"short")] {
+            assert_eq!(Some(target.to_vec()), apply(base, &create(base, target)));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_deltas() {
+        let base = b"hello";
+        // Wrong base size
+        assert_eq!(None, apply(base, &[4, 1, 1, b'x']));
+        // Wrong target size
+        assert_eq!(None, apply(base, &[5, 2, 1, b'x']));
+        // Copy past the end of the base
+        assert_eq!(None, apply(base, &[5, 5, 0x91, 3, 5]));
+        // Truncated insert
+        assert_eq!(None, apply(base, &[5, 2, 2, b'x']));
+    }
+}
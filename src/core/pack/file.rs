@@ -0,0 +1,269 @@
+//! Packs kept in the database (in `objects/pack`), next to their indexes
+
+use std::{
+    convert::TryFrom,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use lru::LruCache;
+use memmap2::Mmap;
+
+use super::{delta, index::IndexError, read_entry, Kind, ObjectType, PackIndex, UnpackError};
+use crate::core::{
+    db::{object::OID_SIZE, LoadRawError, UntypedOid},
+    Db,
+};
+
+/// Deltas of deltas can't go deeper than this, so that a pack of deltas of
+/// each other by oid can't loop forever
+const MAX_CHAIN: usize = 10_000;
+
+#[derive(Debug)]
+pub struct PackFile {
+    path: PathBuf,
+    /// Mapped rather than read, as packs can be far larger than the objects
+    /// loaded from them
+    data: Mmap,
+    index: PackIndex,
+    bases: Mutex<BaseCache>,
+}
+
+/// Recently reconstructed bases of deltas, by their offset in the pack, so
+/// that loading objects near each other in a long chain of deltas (as
+/// checkout and log do) doesn't rebuild the chain from the start each time.
+/// Bounded by the bytes of the bases, like git's `core.deltaBaseCacheLimit`.
+struct BaseCache {
+    bases: LruCache<usize, (ObjectType, Vec<u8>)>,
+    bytes: usize,
+    limit: usize,
+}
+
+impl PackFile {
+    /// Maps the `.pack` at `path` and reads the `.idx` next to it
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, OpenPackError> {
+        let path = path.into();
+        let read_err = |e| OpenPackError::Read(path.clone(), e);
+        let file = fs::File::open(&path).map_err(read_err)?;
+        // Safety: Packs are written under a temporary name and renamed into
+        // place once complete (by us and by git), never changed in place.
+        // Removing one, as repacking does, leaves what we've mapped intact.
+        let data = unsafe { Mmap::map(&file) }.map_err(read_err)?;
+        let idx_path = path.with_extension("idx");
+        let idx = fs::read(&idx_path).map_err(|e| OpenPackError::Read(idx_path.clone(), e))?;
+        let index =
+            PackIndex::parse(&idx).map_err(|e| OpenPackError::Index(idx_path.clone(), e))?;
+        let checksum = data.len().checked_sub(OID_SIZE).map(|start| &data[start..]);
+        if checksum != Some(&index.checksum()[..]) {
+            return Err(OpenPackError::Mismatch(idx_path));
+        }
+        Ok(Self {
+            path,
+            data,
+            index,
+            bases: Mutex::new(BaseCache::new(BaseCache::DEFAULT_LIMIT)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn index(&self) -> &PackIndex {
+        &self.index
+    }
+
+    /// The whole pack, checksum included
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn contains(&self, oid: &UntypedOid) -> bool {
+        self.index.offset(oid).is_some()
+    }
+
+    /// The type and contents of the object, or `None` if it isn't in this
+    /// pack. Bases of deltas that aren't in the pack are loaded from `db`.
+    pub fn load(
+        &self,
+        oid: &UntypedOid,
+        db: &Db,
+    ) -> Result<Option<(ObjectType, Vec<u8>)>, LoadPackedError> {
+        match self.index.offset(oid) {
+            Some(offset) => self.load_at(offset, db).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The type and contents of the object at `offset`
+    pub fn load_at(&self, offset: u64, db: &Db) -> Result<(ObjectType, Vec<u8>), LoadPackedError> {
+        let mut offset = usize::try_from(offset).map_err(|_| UnpackError::Truncated)?;
+        let mut deltas = Vec::new();
+        let (ty, mut data, mut base_offset) = loop {
+            if deltas.len() > MAX_CHAIN {
+                return Err(UnpackError::CorruptObject(offset).into());
+            }
+            if let Some((ty, data)) = self.cached_base(offset) {
+                break (ty, data, Some(offset));
+            }
+            let (entry, _) = read_entry(&self.data, offset)?;
+            match entry.kind {
+                Kind::Object(ty) => break (ty, entry.data, Some(offset)),
+                Kind::OffsetDelta(base) => {
+                    deltas.push((entry.offset, entry.data));
+                    offset = base;
+                }
+                Kind::RefDelta(base) => {
+                    deltas.push((entry.offset, entry.data));
+                    if let Some(base) = self.index.offset(&base) {
+                        offset = usize::try_from(base).map_err(|_| UnpackError::Truncated)?;
+                    } else {
+                        let (ty, data) = Self::load_external(base, db)?;
+                        break (ty, data, None);
+                    }
+                }
+            }
+        };
+        while let Some((offset, delta)) = deltas.pop() {
+            if let Some(base_offset) = base_offset {
+                self.cache_base(base_offset, ty, &data);
+            }
+            data = delta::apply(&data, &delta).ok_or(UnpackError::InvalidDelta(offset))?;
+            base_offset = Some(offset);
+        }
+        Ok((ty, data))
+    }
+
+    /// The type and size of the object at `offset`, which for a delta is
+    /// the size of its target, so that only the bases' headers are read
+    pub fn header_at(&self, offset: u64, db: &Db) -> Result<(ObjectType, usize), LoadPackedError> {
+        let mut offset = usize::try_from(offset).map_err(|_| UnpackError::Truncated)?;
+        let mut size = None;
+        for _ in 0..=MAX_CHAIN {
+            let (entry, _) = read_entry(&self.data, offset)?;
+            if size.is_none() && !matches!(entry.kind, Kind::Object(_)) {
+                let target = delta::target_size(&entry.data)
+                    .ok_or(UnpackError::InvalidDelta(entry.offset))?;
+                size = Some(target);
+            }
+            match entry.kind {
+                Kind::Object(ty) => return Ok((ty, size.unwrap_or(entry.data.len()))),
+                Kind::OffsetDelta(base) => offset = base,
+                Kind::RefDelta(base) => {
+                    if let Some(base) = self.index.offset(&base) {
+                        offset = usize::try_from(base).map_err(|_| UnpackError::Truncated)?;
+                    } else {
+                        let (ty, data) = Self::load_external(base, db)?;
+                        return Ok((ty, size.unwrap_or(data.len())));
+                    }
+                }
+            }
+        }
+        Err(UnpackError::CorruptObject(offset).into())
+    }
+
+    fn cached_base(&self, offset: usize) -> Option<(ObjectType, Vec<u8>)> {
+        let mut bases = self.bases.lock().expect("Not poisoned");
+        bases.get(offset).map(|(ty, data)| (ty, data.to_vec()))
+    }
+
+    fn cache_base(&self, offset: usize, ty: ObjectType, data: &[u8]) {
+        let mut bases = self.bases.lock().expect("Not poisoned");
+        bases.insert(offset, ty, data);
+    }
+
+    fn load_external(oid: UntypedOid, db: &Db) -> Result<(ObjectType, Vec<u8>), LoadPackedError> {
+        let missing = || LoadPackedError::from(UnpackError::MissingBase(oid));
+        let (ty, data) = db
+            .load_raw(&oid)
+            .map_err(LoadPackedError::LoadBase)?
+            .ok_or_else(missing)?;
+        Ok((ObjectType::from_name(&ty).ok_or_else(missing)?, data))
+    }
+}
+
+impl BaseCache {
+    /// Git's default
+    const DEFAULT_LIMIT: usize = 96 * 1024 * 1024;
+
+    fn new(limit: usize) -> Self {
+        Self {
+            bases: LruCache::unbounded(),
+            bytes: 0,
+            limit,
+        }
+    }
+
+    fn get(&mut self, offset: usize) -> Option<(ObjectType, &[u8])> {
+        self.bases.get(&offset).map(|(ty, data)| (*ty, &data[..]))
+    }
+
+    /// Bases bigger than the limit aren't kept
+    fn insert(&mut self, offset: usize, ty: ObjectType, data: &[u8]) {
+        if data.len() > self.limit || self.bases.contains(&offset) {
+            return;
+        }
+        self.bytes += data.len();
+        self.bases.put(offset, (ty, data.to_vec()));
+        while self.bytes > self.limit {
+            match self.bases.pop_lru() {
+                Some((_, (_, evicted))) => self.bytes -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for BaseCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BaseCache")
+            .field("len", &self.bases.len())
+            .field("bytes", &self.bytes)
+            .field("limit", &self.limit)
+            .finish()
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum OpenPackError {
+    /// Failed to list packs in {0:?}
+    List(PathBuf, #[source] io::Error),
+    /// Failed to read {0:?}
+    Read(PathBuf, #[source] io::Error),
+    /// Invalid pack index {0:?}
+    Index(PathBuf, #[source] IndexError),
+    /// Pack index {0:?} is for another pack
+    Mismatch(PathBuf),
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum LoadPackedError {
+    /// Failed to read object from pack
+    Unpack(#[from] UnpackError),
+    /// Failed to load the base of a delta
+    LoadBase(#[source] LoadRawError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn evicts_bases_past_limit() {
+        let mut cache = BaseCache::new(10);
+        cache.insert(0, ObjectType::Blob, b"12345");
+        cache.insert(1, ObjectType::Blob, b"1234");
+        cache.insert(2, ObjectType::Blob, b"much too big");
+        assert_eq!(9, cache.bytes);
+        assert!(cache.get(2).is_none());
+
+        // Using the first makes the second least recently used
+        assert_eq!(Some((ObjectType::Blob, &b"12345"[..])), cache.get(0));
+        cache.insert(3, ObjectType::Tree, b"123");
+        assert_eq!(8, cache.bytes);
+        assert!(cache.get(1).is_none());
+        assert_eq!(Some((ObjectType::Tree, &b"123"[..])), cache.get(3));
+    }
+}
@@ -0,0 +1,264 @@
+//! Pack indexes (`.idx` files, version 2), which say where each object is in
+//! a pack so that it can be read without reading the rest
+
+use std::{
+    convert::TryFrom,
+    io::{self, Write},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY as SHA1};
+
+use super::{hash, resolve, UnpackError};
+use crate::core::{
+    db::{object::OID_SIZE, UntypedOid},
+    progress::Progress,
+    WithDigest,
+};
+
+const SIGNATURE: &[u8] = b"\xfftOc";
+const VERSION: u32 = 2;
+const FANOUT_LEN: usize = 256 * 4;
+/// Offsets with this bit set are indexes into the table of large offsets
+const LARGE_OFFSET: u32 = 0x8000_0000;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PackIndex {
+    /// Sorted by oid
+    entries: Vec<IndexEntry>,
+    /// Of the pack
+    checksum: [u8; OID_SIZE],
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IndexEntry {
+    pub oid: UntypedOid,
+    pub offset: u64,
+    /// Of the entry as it's stored in the pack
+    pub crc: u32,
+}
+
+impl PackIndex {
+    /// Indexes the objects in the pack like `git index-pack`, which can't be
+    /// thin
+    pub fn build(pack: &[u8], progress: &mut dyn Progress) -> Result<Self, UnpackError> {
+        let external = |oid| Err(UnpackError::MissingBase(oid));
+        let mut entries = resolve(pack, progress, external, hash)?
+            .into_iter()
+            .map(|resolved| IndexEntry {
+                oid: resolved.oid,
+                offset: resolved.offset as u64,
+                crc: crc32fast::hash(&pack[resolved.offset..resolved.offset + resolved.len]),
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.oid);
+
+        let mut checksum = [0; OID_SIZE];
+        checksum.copy_from_slice(&pack[pack.len() - OID_SIZE..]);
+        Ok(Self { entries, checksum })
+    }
+
+    pub fn parse(idx: &[u8]) -> Result<Self, IndexError> {
+        let header_len = SIGNATURE.len() + 4;
+        let body_len = idx
+            .len()
+            .checked_sub(OID_SIZE)
+            .filter(|&len| len >= header_len + FANOUT_LEN + OID_SIZE)
+            .ok_or(IndexError::Truncated)?;
+        let (body, checksum) = idx.split_at(body_len);
+        if digest(&SHA1, body).as_ref() != checksum {
+            return Err(IndexError::ChecksumMismatch);
+        }
+        if &body[..SIGNATURE.len()] != SIGNATURE {
+            return Err(IndexError::InvalidSignature);
+        }
+        let version = BigEndian::read_u32(&body[SIGNATURE.len()..header_len]);
+        if version != VERSION {
+            return Err(IndexError::UnsupportedVersion(version));
+        }
+
+        let fanout = &body[header_len..header_len + FANOUT_LEN];
+        let count = BigEndian::read_u32(&fanout[FANOUT_LEN - 4..]) as usize;
+        let oids_start = header_len + FANOUT_LEN;
+        let crcs_start = oids_start + count * OID_SIZE;
+        let offsets_start = crcs_start + count * 4;
+        let large_start = offsets_start + count * 4;
+        let large = body
+            .get(large_start..body.len() - OID_SIZE)
+            .ok_or(IndexError::Truncated)?;
+
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut oid = [0; OID_SIZE];
+            oid.copy_from_slice(&body[oids_start + i * OID_SIZE..oids_start + (i + 1) * OID_SIZE]);
+            let crc = BigEndian::read_u32(&body[crcs_start + i * 4..]);
+            let offset = BigEndian::read_u32(&body[offsets_start + i * 4..]);
+            let offset = if offset & LARGE_OFFSET == 0 {
+                u64::from(offset)
+            } else {
+                let at = (offset & !LARGE_OFFSET) as usize * 8;
+                let large = large.get(at..at + 8).ok_or(IndexError::Truncated)?;
+                BigEndian::read_u64(large)
+            };
+            entries.push(IndexEntry {
+                oid: UntypedOid::new(oid),
+                offset,
+                crc,
+            });
+        }
+        if entries.windows(2).any(|pair| pair[0].oid >= pair[1].oid) {
+            return Err(IndexError::Unsorted);
+        }
+
+        let mut pack_checksum = [0; OID_SIZE];
+        pack_checksum.copy_from_slice(&body[body.len() - OID_SIZE..]);
+        Ok(Self {
+            entries,
+            checksum: pack_checksum,
+        })
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut hashed = WithDigest::new(&SHA1, &mut *out);
+        hashed.write_all(SIGNATURE)?;
+        hashed.write_all(&VERSION.to_be_bytes())?;
+
+        let mut fanout = [0_u32; 256];
+        for entry in &self.entries {
+            fanout[usize::from(entry.oid.as_bytes()[0])] += 1;
+        }
+        let mut total = 0;
+        for count in fanout {
+            total += count;
+            hashed.write_all(&total.to_be_bytes())?;
+        }
+        for entry in &self.entries {
+            hashed.write_all(entry.oid.as_bytes())?;
+        }
+        for entry in &self.entries {
+            hashed.write_all(&entry.crc.to_be_bytes())?;
+        }
+        let mut large = Vec::new();
+        for entry in &self.entries {
+            let offset = match u32::try_from(entry.offset) {
+                Ok(offset) if offset & LARGE_OFFSET == 0 => offset,
+                _ => {
+                    large.push(entry.offset);
+                    let index = u32::try_from(large.len() - 1).expect("Fewer than objects");
+                    LARGE_OFFSET | index
+                }
+            };
+            hashed.write_all(&offset.to_be_bytes())?;
+        }
+        for offset in large {
+            hashed.write_all(&offset.to_be_bytes())?;
+        }
+        hashed.write_all(&self.checksum)?;
+
+        let checksum = hashed.finish();
+        out.write_all(checksum.as_ref())
+    }
+
+    /// Where the object is in the pack
+    pub fn offset(&self, oid: &UntypedOid) -> Option<u64> {
+        self.entries
+            .binary_search_by_key(oid, |entry| entry.oid)
+            .ok()
+            .map(|i| self.entries[i].offset)
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// The checksum of the pack, which names it
+    pub fn checksum(&self) -> &[u8; OID_SIZE] {
+        &self.checksum
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum IndexError {
+    /// Pack index is truncated
+    Truncated,
+    /// Pack index checksum doesn't match its contents
+    ChecksumMismatch,
+    /// Pack index doesn't start with the index signature
+    InvalidSignature,
+    /// Unsupported pack index version {0}
+    UnsupportedVersion(u32),
+    /// Pack index isn't sorted
+    Unsorted,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    use crate::core::{
+        pack::{self, PackOptions},
+        Db,
+    };
+
+    #[test]
+    fn parses_what_it_writes() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        std::fs::create_dir(dir.path().join("objects"))?;
+        let db = Db::new(dir.path());
+        let big = "big ".repeat(100);
+        let objects = vec![
+            db.store_raw(b"blob", big.as_bytes())?,
+            db.store_raw(b"blob", format!("{big}!").as_bytes())?,
+            db.store_raw(b"blob", b"small")?,
+        ];
+        let mut pack = Vec::new();
+        pack::write(&db, &objects, &PackOptions::default(), &mut pack, &mut ())?;
+
+        let index = PackIndex::build(&pack, &mut ())?;
+        let mut sorted = objects.clone();
+        sorted.sort();
+        assert_eq!(
+            sorted,
+            index.entries().iter().map(|e| e.oid).collect::<Vec<_>>()
+        );
+        // The biggest is first
+        assert_eq!(Some(12), index.offset(&objects[1]));
+        assert_eq!(None, index.offset(&UntypedOid::zero()));
+
+        let mut idx = Vec::new();
+        index.write(&mut idx)?;
+        assert_eq!(index, PackIndex::parse(&idx)?);
+
+        idx[10] ^= 1;
+        assert!(matches!(
+            PackIndex::parse(&idx),
+            Err(IndexError::ChecksumMismatch)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn stores_large_offsets_apart() -> eyre::Result<()> {
+        let index = PackIndex {
+            entries: vec![
+                IndexEntry {
+                    oid: UntypedOid::new([1; OID_SIZE]),
+                    offset: 12,
+                    crc: 1,
+                },
+                IndexEntry {
+                    oid: UntypedOid::new([2; OID_SIZE]),
+                    offset: 1 << 33,
+                    crc: 2,
+                },
+            ],
+            checksum: [3; OID_SIZE],
+        };
+        let mut idx = Vec::new();
+        index.write(&mut idx)?;
+        assert_eq!(index, PackIndex::parse(&idx)?);
+        Ok(())
+    }
+}
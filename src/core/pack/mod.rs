@@ -0,0 +1,559 @@
+//! Packs, which hold many objects compressed together, some as deltas of
+//! others. See <https://git-scm.com/docs/pack-format>.
+
+pub mod delta;
+pub mod file;
+pub mod index;
+pub mod verify;
+pub mod write;
+
+pub use file::PackFile;
+pub use index::PackIndex;
+pub use verify::PackedObject;
+pub use write::{write, write_thin, PackOptions, WriteError};
+
+use std::{
+    collections::{btree_map, BTreeMap},
+    convert::TryFrom,
+    io::{self, BufRead},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use flate2::{Decompress, FlushDecompress, Status};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY as SHA1};
+use tracing::{debug, instrument};
+
+use crate::core::{
+    db::{
+        object::{CollisionError, OID_SIZE},
+        LoadRawError, StoreRawError, UntypedOid,
+    },
+    progress::{Progress, Reporter, Stage},
+    Db,
+};
+
+const SIGNATURE: &[u8] = b"PACK";
+const HEADER_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ObjectType {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+}
+
+#[derive(Debug)]
+struct Entry {
+    offset: usize,
+    kind: Kind,
+    /// Inflated
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Object(ObjectType),
+    /// A delta of the entry at the offset
+    OffsetDelta(usize),
+    /// A delta of the object, which may not be in the pack if it's thin
+    RefDelta(UntypedOid),
+}
+
+impl ObjectType {
+    pub fn name(self) -> &'static [u8] {
+        match self {
+            Self::Commit => b"commit",
+            Self::Tree => b"tree",
+            Self::Blob => b"blob",
+            Self::Tag => b"tag",
+        }
+    }
+
+    pub fn from_name(name: &[u8]) -> Option<Self> {
+        [Self::Commit, Self::Tree, Self::Blob, Self::Tag]
+            .iter()
+            .copied()
+            .find(|ty| ty.name() == name)
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Self::Commit => 1,
+            Self::Tree => 2,
+            Self::Blob => 3,
+            Self::Tag => 4,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Self::Commit),
+            2 => Some(Self::Tree),
+            3 => Some(Self::Blob),
+            4 => Some(Self::Tag),
+            _ => None,
+        }
+    }
+}
+
+/// Like `git index-pack` followed by `git unpack-objects`: every object in
+/// the pack is stored loose. Bases of deltas that aren't in the pack (because
+/// it's thin) are loaded from the database.
+///
+/// Returns what was stored, in the order the objects were resolved.
+#[instrument(err, skip(db, pack, progress), fields(len = pack.len()))]
+pub fn unpack(
+    db: &Db,
+    pack: &[u8],
+    progress: &mut dyn Progress,
+) -> Result<Vec<UntypedOid>, UnpackError> {
+    let external = |oid| {
+        let (ty, data) = db.load_raw(&oid)?.ok_or(UnpackError::MissingBase(oid))?;
+        let ty = ObjectType::from_name(&ty).ok_or(UnpackError::MissingBase(oid))?;
+        Ok((ty, data))
+    };
+    let store = |ty: ObjectType, data: &[u8]| Ok(db.store_raw(ty.name(), data)?);
+    let resolved = resolve(pack, progress, external, store)?;
+    Ok(resolved.into_iter().map(|object| object.oid).collect())
+}
+
+/// An object found in a pack
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Resolved {
+    pub oid: UntypedOid,
+    pub ty: ObjectType,
+    pub offset: usize,
+    /// Of the entry in the pack
+    pub len: usize,
+}
+
+/// Checks the pack and resolves each of its objects, giving them to `store`
+/// for their oid. Bases of deltas that aren't in the pack come from
+/// `external`.
+fn resolve(
+    pack: &[u8],
+    progress: &mut dyn Progress,
+    mut external: impl FnMut(UntypedOid) -> Result<(ObjectType, Vec<u8>), UnpackError>,
+    mut store: impl FnMut(ObjectType, &[u8]) -> Result<UntypedOid, UnpackError>,
+) -> Result<Vec<Resolved>, UnpackError> {
+    let (body, count) = check(pack)?;
+    debug!(count);
+
+    let mut reporter = Reporter::new(progress, Stage::Resolving, usize::try_from(count).ok());
+    let mut offset = HEADER_LEN;
+    let mut deltas = Vec::new();
+    let mut resolver = Resolver::default();
+    for _ in 0..count {
+        let (entry, next) = read_entry(body, offset)?;
+        let len = next - offset;
+        offset = next;
+        match entry.kind {
+            Kind::Object(ty) => {
+                let oid = store(ty, &entry.data)?;
+                resolver.insert(oid, entry.offset, len, ty, entry.data);
+                reporter.add(1, len as u64);
+            }
+            _ => deltas.push((entry, len)),
+        }
+    }
+    if offset != body.len() {
+        return Err(UnpackError::CorruptObject(offset));
+    }
+
+    // Bases can come after their deltas, so we keep going until we're stuck
+    let mut loaded_external = false;
+    while !deltas.is_empty() {
+        let before = deltas.len();
+        let mut unresolved = Vec::new();
+        for (entry, len) in deltas {
+            let base = match &entry.kind {
+                Kind::OffsetDelta(base) => resolver.by_offset.get(base),
+                Kind::RefDelta(base) => resolver.by_oid(base),
+                Kind::Object(_) => unreachable!("Stored already"),
+            };
+            if let Some((ty, base)) = base {
+                let ty = *ty;
+                let data = delta::apply(base, &entry.data)
+                    .ok_or(UnpackError::InvalidDelta(entry.offset))?;
+                let oid = store(ty, &data)?;
+                resolver.insert(oid, entry.offset, len, ty, data);
+                reporter.add(1, len as u64);
+            } else {
+                unresolved.push((entry, len));
+            }
+        }
+        deltas = unresolved;
+
+        if deltas.len() == before {
+            if loaded_external {
+                let (entry, _) = &deltas[0];
+                return Err(match entry.kind {
+                    Kind::RefDelta(base) => UnpackError::MissingBase(base),
+                    _ => UnpackError::CorruptObject(entry.offset),
+                });
+            }
+            for (entry, _) in &deltas {
+                if let Kind::RefDelta(base) = entry.kind {
+                    if let btree_map::Entry::Vacant(vacant) = resolver.external.entry(base) {
+                        vacant.insert(external(base)?);
+                    }
+                }
+            }
+            loaded_external = true;
+        }
+    }
+
+    Ok(resolver.resolved)
+}
+
+/// The oid of an object, refusing it if it looks like part of a collision
+/// attack, for indexing packs rather than storing what's in them
+fn hash(ty: ObjectType, data: &[u8]) -> Result<UntypedOid, UnpackError> {
+    let len = data.len().to_string();
+    let header = [ty.name(), b" ", len.as_bytes(), b"\0"].concat();
+    Ok(UntypedOid::try_for_parts(&[&header, data])?)
+}
+
+/// The pack without its checksum, once that and the header are checked, and
+/// how many objects it has
+fn check(pack: &[u8]) -> Result<(&[u8], u32), UnpackError> {
+    let body_len = pack
+        .len()
+        .checked_sub(OID_SIZE)
+        .filter(|&len| len >= HEADER_LEN)
+        .ok_or(UnpackError::Truncated)?;
+    let (body, checksum) = pack.split_at(body_len);
+    if digest(&SHA1, body).as_ref() != checksum {
+        return Err(UnpackError::ChecksumMismatch);
+    }
+
+    if &body[0..4] != SIGNATURE {
+        return Err(UnpackError::InvalidSignature);
+    }
+    let version = BigEndian::read_u32(&body[4..8]);
+    if version != 2 && version != 3 {
+        return Err(UnpackError::UnsupportedVersion(version));
+    }
+    Ok((body, BigEndian::read_u32(&body[8..12])))
+}
+
+/// Reads a pack up to the end of its checksum, leaving anything after, for
+/// when the end of the stream isn't the end of the pack (as when a client
+/// keeps the connection open for our reply). Only as much is checked as is
+/// needed to find the end.
+#[instrument(err, skip(input))]
+pub fn read(input: &mut impl BufRead) -> Result<Vec<u8>, UnpackError> {
+    let mut pack = vec![0; HEADER_LEN];
+    read_exact(input, &mut pack)?;
+    if &pack[0..4] != SIGNATURE {
+        return Err(UnpackError::InvalidSignature);
+    }
+    let count = BigEndian::read_u32(&pack[8..12]);
+
+    for _ in 0..count {
+        let offset = pack.len();
+        // The type and size, then a delta's base, end with a byte without
+        // the high bit set
+        let first = read_byte(input, &mut pack)?;
+        let mut byte = first;
+        while byte & 0x80 != 0 {
+            byte = read_byte(input, &mut pack)?;
+        }
+        match (first >> 4) & 0x7 {
+            6 => {
+                byte = read_byte(input, &mut pack)?;
+                while byte & 0x80 != 0 {
+                    byte = read_byte(input, &mut pack)?;
+                }
+            }
+            7 => {
+                let mut base = [0; OID_SIZE];
+                read_exact(input, &mut base)?;
+                pack.extend_from_slice(&base);
+            }
+            _ => {}
+        }
+
+        // Inflated only to find where the data ends
+        let mut inflater = Decompress::new(true);
+        let mut scratch = [0; 4096];
+        loop {
+            let available = input.fill_buf()?;
+            if available.is_empty() {
+                return Err(UnpackError::Truncated);
+            }
+            let before = inflater.total_in();
+            let status = inflater
+                .decompress(available, &mut scratch, FlushDecompress::None)
+                .map_err(|_| UnpackError::CorruptObject(offset))?;
+            let consumed = usize::try_from(inflater.total_in() - before).expect("Within input");
+            pack.extend_from_slice(&available[..consumed]);
+            input.consume(consumed);
+            if status == Status::StreamEnd {
+                break;
+            }
+        }
+    }
+
+    let mut checksum = [0; OID_SIZE];
+    read_exact(input, &mut checksum)?;
+    pack.extend_from_slice(&checksum);
+    Ok(pack)
+}
+
+fn read_byte(input: &mut impl BufRead, pack: &mut Vec<u8>) -> Result<u8, UnpackError> {
+    let mut byte = [0];
+    read_exact(input, &mut byte)?;
+    pack.push(byte[0]);
+    Ok(byte[0])
+}
+
+fn read_exact(input: &mut impl BufRead, buf: &mut [u8]) -> Result<(), UnpackError> {
+    input.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => UnpackError::Truncated,
+        _ => UnpackError::Read(err),
+    })
+}
+
+#[derive(Debug, Default)]
+struct Resolver {
+    by_offset: BTreeMap<usize, (ObjectType, Vec<u8>)>,
+    offsets: BTreeMap<UntypedOid, usize>,
+    /// Bases from outside the pack
+    external: BTreeMap<UntypedOid, (ObjectType, Vec<u8>)>,
+    resolved: Vec<Resolved>,
+}
+
+impl Resolver {
+    fn insert(
+        &mut self,
+        oid: UntypedOid,
+        offset: usize,
+        len: usize,
+        ty: ObjectType,
+        data: Vec<u8>,
+    ) {
+        self.offsets.insert(oid, offset);
+        self.by_offset.insert(offset, (ty, data));
+        self.resolved.push(Resolved {
+            oid,
+            ty,
+            offset,
+            len,
+        });
+    }
+
+    fn by_oid(&self, oid: &UntypedOid) -> Option<&(ObjectType, Vec<u8>)> {
+        match self.offsets.get(oid) {
+            Some(offset) => self.by_offset.get(offset),
+            None => self.external.get(oid),
+        }
+    }
+}
+
+/// The entry at `offset`, and the offset after it
+fn read_entry(body: &[u8], offset: usize) -> Result<(Entry, usize), UnpackError> {
+    let corrupt = || UnpackError::CorruptObject(offset);
+    let mut bytes = body.get(offset..).ok_or_else(corrupt)?.iter().copied();
+    let mut pos = offset;
+    let mut next = || {
+        pos += 1;
+        bytes.next().ok_or_else(corrupt)
+    };
+
+    // The type, then the inflated size
+    let mut byte = next()?;
+    let code = (byte >> 4) & 0x7;
+    let mut size = usize::from(byte & 0x0f);
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = next()?;
+        size |= usize::from(byte & 0x7f)
+            .checked_shl(shift)
+            .ok_or_else(corrupt)?;
+        shift += 7;
+    }
+
+    let kind = match code {
+        6 => {
+            // The distance back to the base, where each continuation adds
+            // one so that there's only one encoding of each distance
+            byte = next()?;
+            let mut distance = usize::from(byte & 0x7f);
+            while byte & 0x80 != 0 {
+                byte = next()?;
+                distance = distance
+                    .checked_add(1)
+                    .and_then(|d| d.checked_mul(1 << 7))
+                    .ok_or_else(corrupt)?
+                    | usize::from(byte & 0x7f);
+            }
+            Kind::OffsetDelta(offset.checked_sub(distance).ok_or_else(corrupt)?)
+        }
+        7 => {
+            let mut oid = [0; OID_SIZE];
+            for byte in &mut oid {
+                *byte = next()?;
+            }
+            Kind::RefDelta(UntypedOid::new(oid))
+        }
+        code => Kind::Object(ObjectType::from_code(code).ok_or_else(corrupt)?),
+    };
+
+    let (data, consumed) = inflate(&body[pos..], size).ok_or_else(corrupt)?;
+    Ok((Entry { offset, kind, data }, pos + consumed))
+}
+
+/// The data, and how much of the input it took
+fn inflate(input: &[u8], size: usize) -> Option<(Vec<u8>, usize)> {
+    // With room to spare, so that too much data isn't cut off
+    let mut data = Vec::with_capacity(size + 1);
+    let mut inflater = Decompress::new(true);
+    match inflater.decompress_vec(input, &mut data, FlushDecompress::Finish) {
+        Ok(Status::StreamEnd) if data.len() == size => {
+            let consumed = usize::try_from(inflater.total_in()).ok()?;
+            Some((data, consumed))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum UnpackError {
+    /// Pack is truncated
+    Truncated,
+    /// Pack checksum doesn't match its contents
+    ChecksumMismatch,
+    /// Pack doesn't start with `PACK`
+    InvalidSignature,
+    /// Unsupported pack version {0}
+    UnsupportedVersion(u32),
+    /// Entry at offset {0} in pack is corrupt
+    CorruptObject(usize),
+    /// Delta at offset {0} in pack doesn't apply to its base
+    InvalidDelta(usize),
+    /// Base {0:?} of a delta isn't in the pack or the database
+    MissingBase(UntypedOid),
+    /// Failed to load the base of a delta
+    LoadBase(#[from] LoadRawError),
+    /// Failed to store object from pack
+    Store(#[from] StoreRawError),
+    /// Refusing object from pack
+    Collision(#[from] CollisionError),
+    /// Failed to read pack
+    Read(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression};
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    /// An entry with its type code, anything between the header and the
+    /// data (like a delta's base), and its inflated data
+    fn entry(code: u8, base: &[u8], data: &[u8]) -> Vec<u8> {
+        // Sizes here fit in the first byte
+        let mut entry = vec![code << 4 | u8::try_from(data.len()).unwrap()];
+        assert!(data.len() < 16);
+        entry.extend_from_slice(base);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        entry.extend(encoder.finish().unwrap());
+        entry
+    }
+
+    fn pack(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut pack = SIGNATURE.to_vec();
+        pack.extend_from_slice(&2_u32.to_be_bytes());
+        pack.extend_from_slice(&u32::try_from(entries.len()).unwrap().to_be_bytes());
+        pack.extend(entries.concat());
+        let checksum = digest(&SHA1, &pack);
+        pack.extend_from_slice(checksum.as_ref());
+        pack
+    }
+
+    #[test]
+    fn resolves_deltas_against_pack_and_db() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        std::fs::create_dir(dir.path().join("objects"))?;
+        let db = Db::new(dir.path());
+        let external = db.store_raw(b"blob", b"hello")?;
+
+        let in_pack = b"blob 5\0world";
+        let in_pack_oid = UntypedOid::for_bytes(in_pack);
+        let blob = entry(3, &[], b"world");
+        let distance = u8::try_from(blob.len()).unwrap();
+        let pack = pack(&[
+            // "hello!", from the database
+            entry(7, external.as_bytes(), &[5, 6, 0x90, 5, 1, b'!']),
+            // "world?", from the next entry
+            entry(7, in_pack_oid.as_bytes(), &[5, 6, 0x90, 5, 1, b'?']),
+            blob,
+            // "worl", from the entry before
+            entry(6, &[distance], &[5, 4, 0x90, 4]),
+        ]);
+
+        let stored = unpack(&db, &pack, &mut ())?;
+        assert_eq!(4, stored.len());
+        let mut contents = stored
+            .iter()
+            .map(|oid| Ok(db.load_raw(oid)?.expect("Stored").1.into()))
+            .collect::<eyre::Result<Vec<bstr::BString>>>()?;
+        contents.sort();
+        assert_eq!(vec!["hello!", "worl", "world", "world?"], contents);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_packs_from_streams() -> eyre::Result<()> {
+        let base = UntypedOid::for_bytes(b"base");
+        let pack = pack(&[
+            entry(3, &[], b"world"),
+            entry(7, base.as_bytes(), &[5, 6, 0x90, 5, 1, b'?']),
+            entry(6, &[200, 1], &[5, 4, 0x90, 4]),
+        ]);
+        let mut stream = [pack.as_slice(), b"after"].concat();
+        let mut input = stream.as_slice();
+        assert_eq!(pack, read(&mut input)?);
+        assert_eq!(b"after", input);
+
+        stream.truncate(pack.len() - 1);
+        assert!(matches!(
+            read(&mut stream.as_slice()),
+            Err(UnpackError::Truncated)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_bad_packs() {
+        let dir = tempdir().unwrap();
+        let db = Db::new(dir.path());
+
+        let mut corrupted = pack(&[entry(3, &[], b"data")]);
+        corrupted[13] ^= 1;
+        assert!(matches!(
+            unpack(&db, &corrupted, &mut ()),
+            Err(UnpackError::ChecksumMismatch)
+        ));
+
+        let missing = UntypedOid::for_bytes(b"missing");
+        assert!(matches!(
+            unpack(
+                &db,
+                &pack(&[entry(7, missing.as_bytes(), &[1, 1, 1, b'x'])]),
+                &mut ()
+            ),
+            Err(UnpackError::MissingBase(oid)) if oid == missing
+        ));
+
+        assert!(matches!(
+            unpack(&db, &pack(&[entry(3, &[], b"data")])[..20], &mut ()),
+            Err(UnpackError::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,196 @@
+//! Checking packs in the database thoroughly, like `git verify-pack`
+
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use tracing::instrument;
+
+use super::{
+    hash, index::IndexError, read_entry, resolve, Kind, ObjectType, PackIndex, UnpackError,
+    HEADER_LEN,
+};
+use crate::core::{
+    db::{object::OID_SIZE, UntypedOid},
+    Db,
+};
+
+/// What `git verify-pack --verbose` says of an object
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PackedObject {
+    pub oid: UntypedOid,
+    /// Of the object, which for deltas is the type of their base
+    pub object_type: ObjectType,
+    /// Of the entry's data once inflated, which for deltas is the size of
+    /// the delta
+    pub size: usize,
+    /// Of the entry in the pack, header included
+    pub packed_size: usize,
+    pub offset: u64,
+    /// How many deltas lead to a whole object, `0` if it isn't a delta
+    pub depth: usize,
+    /// The object a delta applies to
+    pub base: Option<UntypedOid>,
+}
+
+impl Db {
+    /// Checks the pack at `path` (relative to `objects/pack`, unless
+    /// absolute) and its index: the checksums of both, that every entry
+    /// inflates and every delta applies, and that the index has every object
+    /// at its offset with the CRC of its entry. Returns every object in the
+    /// order they're in the pack.
+    #[instrument(err, skip(self))]
+    pub fn verify_pack(
+        &self,
+        path: impl AsRef<Path> + fmt::Debug,
+    ) -> Result<Vec<PackedObject>, VerifyPackError> {
+        let path = self.pack_dir().join(path);
+        let pack = fs::read(&path).map_err(|e| VerifyPackError::Read(path.clone(), e))?;
+        let idx_path = path.with_extension("idx");
+        let idx = fs::read(&idx_path).map_err(|e| VerifyPackError::Read(idx_path.clone(), e))?;
+        let index =
+            PackIndex::parse(&idx).map_err(|e| VerifyPackError::Index(idx_path.clone(), e))?;
+
+        // Checks the checksum and that every object can be resolved, without
+        // looking outside the pack
+        let external = |oid| Err(UnpackError::MissingBase(oid));
+        let resolved = resolve(&pack, &mut (), external, hash)?;
+        if pack[pack.len() - OID_SIZE..] != index.checksum()[..] {
+            return Err(VerifyPackError::Mismatch(idx_path));
+        }
+        if resolved.len() != index.entries().len() {
+            return Err(VerifyPackError::Count {
+                pack: resolved.len(),
+                index: index.entries().len(),
+            });
+        }
+
+        let body = &pack[..pack.len() - OID_SIZE];
+        let by_offset = resolved
+            .iter()
+            .map(|object| (object.offset, object))
+            .collect::<BTreeMap<_, _>>();
+        for entry in index.entries() {
+            let object = usize::try_from(entry.offset)
+                .ok()
+                .and_then(|offset| by_offset.get(&offset))
+                .filter(|object| object.oid == entry.oid)
+                .ok_or(VerifyPackError::WrongOffset(entry.oid, entry.offset))?;
+            let crc = crc32fast::hash(&body[object.offset..object.offset + object.len]);
+            if crc != entry.crc {
+                return Err(VerifyPackError::Crc(entry.oid));
+            }
+        }
+
+        // Entries were read as they were resolved, but only now that they're
+        // known to be good do we look at the chains of deltas
+        let mut bases = BTreeMap::new();
+        let mut sizes = BTreeMap::new();
+        let mut offset = HEADER_LEN;
+        while offset < body.len() {
+            let (entry, next) = read_entry(body, offset)?;
+            let base = match entry.kind {
+                Kind::Object(_) => None,
+                Kind::OffsetDelta(base) => Some(base),
+                Kind::RefDelta(base) => Some(by_oid(&index, &base)?),
+            };
+            bases.insert(offset, base);
+            sizes.insert(offset, entry.data.len());
+            offset = next;
+        }
+
+        let mut depths = BTreeMap::new();
+        let mut objects = Vec::with_capacity(resolved.len());
+        for (&offset, object) in &by_offset {
+            let base = bases[&offset];
+            objects.push(PackedObject {
+                oid: object.oid,
+                object_type: object.ty,
+                size: sizes[&offset],
+                packed_size: object.len,
+                offset: offset as u64,
+                depth: depth(&bases, &mut depths, offset),
+                base: base.map(|base| by_offset[&base].oid),
+            });
+        }
+        Ok(objects)
+    }
+}
+
+fn by_oid(index: &PackIndex, oid: &UntypedOid) -> Result<usize, VerifyPackError> {
+    index
+        .offset(oid)
+        .and_then(|offset| usize::try_from(offset).ok())
+        .ok_or(VerifyPackError::Unpack(UnpackError::MissingBase(*oid)))
+}
+
+/// Following the chain until an object whose depth is known, as chains can
+/// be too long to recurse down
+fn depth(
+    bases: &BTreeMap<usize, Option<usize>>,
+    depths: &mut BTreeMap<usize, usize>,
+    offset: usize,
+) -> usize {
+    let mut chain = Vec::new();
+    let mut next = Some(offset);
+    let mut depth = 0;
+    while let Some(offset) = next {
+        if let Some(&known) = depths.get(&offset) {
+            depth = known;
+            break;
+        }
+        chain.push(offset);
+        next = bases[&offset];
+    }
+    // The last in the chain is a whole object, unless its depth was known
+    if next.is_none() {
+        depth = 0;
+        if let Some(whole) = chain.pop() {
+            depths.insert(whole, 0);
+        }
+    }
+    for offset in chain.into_iter().rev() {
+        depth += 1;
+        depths.insert(offset, depth);
+    }
+    depths[&offset]
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum VerifyPackError {
+    /// Failed to read {0:?}
+    Read(PathBuf, #[source] io::Error),
+    /// Invalid pack index {0:?}
+    Index(PathBuf, #[source] IndexError),
+    /// Pack index {0:?} is for another pack
+    Mismatch(PathBuf),
+    /// Pack has {pack} objects, but its index has {index}
+    Count { pack: usize, index: usize },
+    /// Object {0} isn't at offset {1} in the pack, as its index says
+    WrongOffset(UntypedOid, u64),
+    /// CRC of the entry of {0} in the pack doesn't match its index
+    Crc(UntypedOid),
+    /// {0}
+    Unpack(#[from] UnpackError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn follows_chains_for_depth() {
+        let bases = vec![(10, None), (20, Some(10)), (30, Some(20)), (40, Some(10))]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let mut depths = BTreeMap::new();
+        assert_eq!(2, depth(&bases, &mut depths, 30));
+        assert_eq!(1, depth(&bases, &mut depths, 40));
+        assert_eq!(0, depth(&bases, &mut depths, 10));
+        assert_eq!(1, depth(&bases, &mut depths, 20));
+    }
+}
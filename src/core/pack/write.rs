@@ -0,0 +1,551 @@
+//! Writing packs
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    io::{self, BufWriter, Write},
+};
+
+use flate2::{write::ZlibEncoder, Compression};
+use rayon::prelude::*;
+use ring::digest::SHA1_FOR_LEGACY_USE_ONLY as SHA1;
+use tracing::instrument;
+
+use super::{delta, ObjectType, SIGNATURE};
+use crate::core::{
+    cancel::{CancelToken, Cancelled},
+    config::{self, Config},
+    db::{LoadRawError, TreeRef, UntypedOid},
+    progress::{Progress, Reporter, Stage},
+    Db, WithDigest,
+};
+
+const OFFSET_DELTA: u8 = 6;
+const REF_DELTA: u8 = 7;
+
+/// How deltas are searched for, like `git pack-objects`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PackOptions {
+    /// How many of the objects before one (by type, name and size) are tried
+    /// as the base of its delta
+    pub window: usize,
+    /// The longest chain of deltas, so that resolving one stays quick
+    pub depth: usize,
+    /// How many threads search for deltas, or 0 for one per CPU. Objects are
+    /// split between them, and only have bases among the objects of their
+    /// thread.
+    pub threads: usize,
+    /// Checked as objects are loaded, searched for deltas and written
+    pub cancel: CancelToken,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        Self {
+            window: 10,
+            depth: 50,
+            threads: 0,
+            cancel: CancelToken::default(),
+        }
+    }
+}
+
+impl PackOptions {
+    /// From `pack.window`, `pack.depth` and `pack.threads`
+    pub fn from_config(config: &Config) -> Result<Self, config::ValueError> {
+        let default = Self::default();
+        let get = |name: &str, default: usize| match config.get_int(name)? {
+            Some(value) => usize::try_from(value)
+                .map_err(|_| config::ValueError::Invalid(name.to_owned(), value.to_string())),
+            None => Ok(default),
+        };
+        Ok(Self {
+            window: get("pack.window", default.window)?,
+            depth: get("pack.depth", default.depth)?,
+            threads: get("pack.threads", default.threads)?,
+            cancel: default.cancel,
+        })
+    }
+}
+
+/// A pack of the objects, where some may be deltas of others
+pub fn write(
+    db: &Db,
+    objects: &[UntypedOid],
+    options: &PackOptions,
+    out: &mut impl Write,
+    progress: &mut dyn Progress,
+) -> Result<(), WriteError> {
+    write_thin(db, objects, &[], true, options, out, progress)
+}
+
+/// A pack of the objects, where some may be deltas of others or of `bases`,
+/// which aren't in the pack (making it thin) so the reader has to have them.
+/// Bases in the pack are referred to by their offset if `ofs_delta`, which the
+/// reader has to support, or else by oid.
+#[instrument(err, skip(db, objects, bases, out, progress), fields(count = objects.len(), bases = bases.len()))]
+pub fn write_thin(
+    db: &Db,
+    objects: &[UntypedOid],
+    bases: &[UntypedOid],
+    ofs_delta: bool,
+    options: &PackOptions,
+    out: &mut impl Write,
+    progress: &mut dyn Progress,
+) -> Result<(), WriteError> {
+    let count = u32::try_from(objects.len()).map_err(|_| WriteError::TooMany(objects.len()))?;
+    let in_pack = objects.iter().collect::<BTreeSet<_>>();
+    let mut candidates = Vec::new();
+    for oid in objects
+        .iter()
+        .chain(bases.iter().filter(|oid| !in_pack.contains(oid)))
+    {
+        options.cancel.check()?;
+        let (ty, data) = db.load_raw(oid)?.ok_or(WriteError::NotFound(*oid))?;
+        let ty = ObjectType::from_name(&ty).ok_or(WriteError::NotFound(*oid))?;
+        candidates.push(Candidate {
+            oid: *oid,
+            ty,
+            data,
+            in_pack: candidates.len() < objects.len(),
+        });
+    }
+    // Like git, objects with similar names next to each other, and bigger
+    // objects first, as deltas that remove are smaller
+    let names = name_hashes(&candidates);
+    let mut order = (0..candidates.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| {
+        let candidate = &candidates[i];
+        let name = names.get(&candidate.oid).copied().unwrap_or_default();
+        (candidate.ty.code(), name, Reverse(candidate.data.len()))
+    });
+
+    let threads = match options.threads {
+        0 => rayon::current_num_threads(),
+        threads => threads,
+    };
+    // Small enough chunks would hardly find any deltas
+    let chunk_len = order.len().div_ceil(threads).max(options.window * 2).max(1);
+    let mut deltas = BTreeMap::new();
+    for found in order
+        .par_chunks(chunk_len)
+        .map(|chunk| find_deltas(&candidates, chunk, options))
+        .collect::<Result<Vec<_>, _>>()?
+    {
+        deltas.extend(found);
+    }
+
+    let mut buffered = BufWriter::new(&mut *out);
+    let mut hashed = WithDigest::new(&SHA1, &mut buffered);
+    hashed.write_all(SIGNATURE)?;
+    hashed.write_all(&2_u32.to_be_bytes())?;
+    hashed.write_all(&count.to_be_bytes())?;
+
+    let mut reporter = Reporter::new(progress, Stage::Writing, Some(objects.len()));
+    let mut offsets = BTreeMap::new();
+    // Bases are before their deltas in this order
+    for &i in order.iter().filter(|&&i| candidates[i].in_pack) {
+        options.cancel.check()?;
+        let offset = hashed.bytes();
+        let candidate = &candidates[i];
+        let delta = deltas.get(&i);
+        let header = match delta {
+            Some((base, delta)) => match offsets.get(base) {
+                Some(base_offset) if ofs_delta => {
+                    let mut header = entry_header(OFFSET_DELTA, delta.len());
+                    header.extend(offset_encoding(offset - base_offset));
+                    header
+                }
+                _ => {
+                    let mut header = entry_header(REF_DELTA, delta.len());
+                    header.extend_from_slice(candidates[*base].oid.as_bytes());
+                    header
+                }
+            },
+            None => entry_header(candidate.ty.code(), candidate.data.len()),
+        };
+        let data = delta.map_or(&candidate.data, |(_, delta)| delta);
+        hashed.write_all(&header)?;
+        let mut encoder = ZlibEncoder::new(&mut hashed, Compression::default());
+        encoder.write_all(data)?;
+        encoder.finish()?;
+        offsets.insert(i, offset);
+        reporter.add(1, hashed.bytes() - offset);
+    }
+
+    let checksum = hashed.finish();
+    buffered.write_all(checksum.as_ref())?;
+    buffered.flush()?;
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Candidate {
+    oid: UntypedOid,
+    ty: ObjectType,
+    data: Vec<u8>,
+    /// Or else only a base
+    in_pack: bool,
+}
+
+/// The hash of the name each blob and tree has in the trees among the
+/// candidates, like git's, which mostly depends on the last characters so
+/// that files of the same kind sort together
+fn name_hashes(candidates: &[Candidate]) -> BTreeMap<UntypedOid, u32> {
+    let mut names = BTreeMap::new();
+    for candidate in candidates.iter().filter(|c| c.ty == ObjectType::Tree) {
+        let Ok(tree) = TreeRef::parse(&candidate.data) else {
+            continue;
+        };
+        for node in tree.nodes() {
+            names.entry(node.untyped_oid()).or_insert_with(|| {
+                node.name()
+                    .iter()
+                    .filter(|c| !c.is_ascii_whitespace())
+                    .fold(0_u32, |hash, &c| (hash >> 2) + (u32::from(c) << 24))
+            });
+        }
+    }
+    names
+}
+
+/// Which candidate is stored as a delta, and its base and the delta
+type FoundDelta = (usize, (usize, Vec<u8>));
+
+/// The base and delta of each candidate in the chunk that's stored as a
+/// delta, with bases from the window before it in the chunk
+fn find_deltas(
+    candidates: &[Candidate],
+    chunk: &[usize],
+    options: &PackOptions,
+) -> Result<Vec<FoundDelta>, Cancelled> {
+    let mut depths = vec![0; chunk.len()];
+    let mut found = Vec::new();
+    for (pos, &i) in chunk.iter().enumerate() {
+        options.cancel.check()?;
+        if !candidates[i].in_pack {
+            continue;
+        }
+        let start = pos.saturating_sub(options.window);
+        let window = &chunk[start..pos];
+        if let Some((at, delta)) = best_delta(candidates, window, &depths[start..pos], i, options) {
+            depths[pos] = depths[start + at] + 1;
+            found.push((i, (window[at], delta)));
+        }
+    }
+    Ok(found)
+}
+
+/// Where the base of the smallest delta of the candidate is in the window,
+/// and the delta, if any is less than half its size
+fn best_delta(
+    candidates: &[Candidate],
+    window: &[usize],
+    depths: &[usize],
+    i: usize,
+    options: &PackOptions,
+) -> Option<(usize, Vec<u8>)> {
+    let target = &candidates[i];
+    let mut best: Option<(usize, Vec<u8>)> = None;
+    for (at, &j) in window.iter().enumerate().rev() {
+        let base = &candidates[j];
+        if base.ty != target.ty || depths[at] >= options.depth {
+            continue;
+        }
+        let delta = delta::create(&base.data, &target.data);
+        let limit = best
+            .as_ref()
+            .map_or(target.data.len() / 2, |(_, best)| best.len());
+        if delta.len() < limit {
+            best = Some((at, delta));
+        }
+    }
+    best
+}
+
+/// The type code, then the size in little-endian groups of seven bits, the
+/// first of which only has four
+#[allow(clippy::cast_possible_truncation)] // Masked
+fn entry_header(code: u8, size: usize) -> Vec<u8> {
+    let mut header = vec![code << 4 | (size & 0x0f) as u8];
+    let mut rest = size >> 4;
+    while rest != 0 {
+        *header.last_mut().expect("Not empty") |= 0x80;
+        header.push((rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    header
+}
+
+/// The distance back to a delta's base in big-endian groups of seven bits,
+/// where each continuation adds one
+#[allow(clippy::cast_possible_truncation)] // Masked
+fn offset_encoding(mut distance: u64) -> Vec<u8> {
+    let mut encoded = vec![(distance & 0x7f) as u8];
+    distance >>= 7;
+    while distance != 0 {
+        distance -= 1;
+        encoded.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    encoded.reverse();
+    encoded
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum WriteError {
+    /// Too many objects for a pack: {0}
+    TooMany(usize),
+    /// Object {0:?} to pack isn't in the database
+    NotFound(UntypedOid),
+    /// Failed to load object to pack
+    Load(#[from] LoadRawError),
+    /// Failed to write pack
+    Io(#[from] io::Error),
+    /// {0}
+    Cancelled(#[from] Cancelled),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    use crate::core::{
+        pack::{read_entry, unpack, Kind, HEADER_LEN},
+        progress::Update,
+    };
+
+    #[derive(Default)]
+    struct Recorded(Vec<Update>);
+
+    impl Progress for Recorded {
+        fn update(&mut self, update: &Update) {
+            self.0.push(*update);
+        }
+    }
+
+    #[test]
+    fn unpacks_what_it_writes() -> eyre::Result<()> {
+        let src = tempdir()?;
+        std::fs::create_dir(src.path().join("objects"))?;
+        let src = Db::new(src.path());
+        // Big enough that the size doesn't fit in the first byte
+        let big = "big ".repeat(100);
+        let objects = vec![
+            src.store_raw(b"blob", b"small")?,
+            src.store_raw(b"blob", big.as_bytes())?,
+            src.store_raw(b"blob", b"")?,
+        ];
+        let mut pack = Vec::new();
+        let mut written = Recorded::default();
+        write(
+            &src,
+            &objects,
+            &PackOptions::default(),
+            &mut pack,
+            &mut written,
+        )?;
+        let last = written.0.last().expect("Reported");
+        assert_eq!(
+            (Stage::Writing, 3, Some(3)),
+            (last.stage, last.objects, last.total)
+        );
+        // Less the header and checksum
+        assert_eq!(pack.len() as u64 - 12 - 20, last.bytes);
+
+        let dst = tempdir()?;
+        std::fs::create_dir(dst.path().join("objects"))?;
+        let dst = Db::new(dst.path());
+        let mut resolved = Recorded::default();
+        let mut unpacked = unpack(&dst, &pack, &mut resolved)?;
+        let last = resolved.0.last().expect("Reported");
+        assert_eq!(
+            (Stage::Resolving, 3, Some(3)),
+            (last.stage, last.objects, last.total)
+        );
+        assert_eq!(pack.len() as u64 - 12 - 20, last.bytes);
+        unpacked.sort();
+        let mut expected = objects.clone();
+        expected.sort();
+        assert_eq!(expected, unpacked);
+        assert_eq!(src.load_raw(&objects[1])?, dst.load_raw(&objects[1])?);
+
+        assert!(matches!(
+            write(
+                &src,
+                &[UntypedOid::zero()],
+                &PackOptions::default(),
+                &mut Vec::new(),
+                &mut ()
+            ),
+            Err(WriteError::NotFound(_))
+        ));
+
+        let cancelled = PackOptions::default();
+        cancelled.cancel.cancel();
+        assert!(matches!(
+            write(&src, &objects, &cancelled, &mut Vec::new(), &mut ()),
+            Err(WriteError::Cancelled(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn writes_deltas() -> eyre::Result<()> {
+        let src = tempdir()?;
+        std::fs::create_dir(src.path().join("objects"))?;
+        let src = Db::new(src.path());
+        let lines = (0..1000)
+            .map(|line| format!("line {line}\n"))
+            .collect::<Vec<_>>()
+            .concat();
+        let base = src.store_raw(b"blob", lines.as_bytes())?;
+        let changed =
+            |from: &str, to: &str| src.store_raw(b"blob", lines.replace(from, to).as_bytes());
+        let objects = vec![
+            changed("line 10\n", "first\n")?,
+            changed("line 500\n", "second\n")?,
+        ];
+
+        let options = PackOptions::default();
+        for ofs_delta in [true, false] {
+            let mut full = Vec::new();
+            write_thin(&src, &objects, &[], ofs_delta, &options, &mut full, &mut ())?;
+            let mut thin = Vec::new();
+            write_thin(
+                &src,
+                &objects,
+                &[base],
+                ofs_delta,
+                &options,
+                &mut thin,
+                &mut (),
+            )?;
+            let kinds = |pack: &[u8]| -> eyre::Result<Vec<Kind>> {
+                let (first, next) = read_entry(pack, HEADER_LEN)?;
+                let (second, _) = read_entry(pack, next)?;
+                Ok(vec![first.kind, second.kind])
+            };
+            // The other is a delta of the one written whole
+            let full_kinds = kinds(&full)?;
+            assert!(matches!(full_kinds[0], Kind::Object(ObjectType::Blob)));
+            if ofs_delta {
+                assert!(matches!(full_kinds[1], Kind::OffsetDelta(HEADER_LEN)));
+            } else {
+                assert!(matches!(full_kinds[1], Kind::RefDelta(_)));
+            }
+            for kind in kinds(&thin)? {
+                assert!(matches!(kind, Kind::RefDelta(oid) if oid == base));
+            }
+
+            let dst = tempdir()?;
+            std::fs::create_dir(dst.path().join("objects"))?;
+            let dst = Db::new(dst.path());
+            let mut unpacked = unpack(&dst, &full, &mut ())?;
+            unpacked.sort();
+            let mut expected = objects.clone();
+            expected.sort();
+            assert_eq!(expected, unpacked);
+
+            let dst = tempdir()?;
+            std::fs::create_dir(dst.path().join("objects"))?;
+            let dst = Db::new(dst.path());
+            assert!(unpack(&dst, &thin, &mut ()).is_err());
+            dst.store_raw(b"blob", lines.as_bytes())?;
+            unpack(&dst, &thin, &mut ())?;
+            assert_eq!(src.load_raw(&objects[1])?, dst.load_raw(&objects[1])?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn follows_window_depth_and_threads() -> eyre::Result<()> {
+        let src = tempdir()?;
+        std::fs::create_dir(src.path().join("objects"))?;
+        let src = Db::new(src.path());
+        let lines = (0..200)
+            .map(|line| format!("line {line}\n"))
+            .collect::<Vec<_>>()
+            .concat();
+        let mut objects = Vec::new();
+        for version in 0..20 {
+            let contents = format!("{lines}version {version}\n");
+            objects.push(src.store_raw(b"blob", contents.as_bytes())?);
+        }
+        let kinds = |options: &PackOptions| -> eyre::Result<Vec<(usize, Kind)>> {
+            let mut pack = Vec::new();
+            write(&src, &objects, options, &mut pack, &mut ())?;
+            let dst = tempdir()?;
+            std::fs::create_dir(dst.path().join("objects"))?;
+            let dst = Db::new(dst.path());
+            assert_eq!(objects.len(), unpack(&dst, &pack, &mut ())?.len());
+
+            let mut kinds = Vec::new();
+            let mut offset = HEADER_LEN;
+            for _ in &objects {
+                let (entry, next) = read_entry(&pack, offset)?;
+                kinds.push((entry.offset, entry.kind));
+                offset = next;
+            }
+            Ok(kinds)
+        };
+        let deltas = |kinds: &[(usize, Kind)]| {
+            kinds
+                .iter()
+                .filter(|(_, kind)| matches!(kind, Kind::OffsetDelta(_)))
+                .count()
+        };
+
+        let whole = PackOptions {
+            window: 0,
+            ..PackOptions::default()
+        };
+        assert_eq!(0, deltas(&kinds(&whole)?));
+        assert_eq!(19, deltas(&kinds(&PackOptions::default())?));
+
+        let shallow = PackOptions {
+            depth: 1,
+            ..PackOptions::default()
+        };
+        let shallow = kinds(&shallow)?;
+        for (_, kind) in &shallow {
+            if let Kind::OffsetDelta(base) = kind {
+                let (_, base) = shallow.iter().find(|(offset, _)| offset == base).unwrap();
+                assert!(matches!(base, Kind::Object(_)), "Only one delta deep");
+            }
+        }
+
+        // Split in four, each first of which is whole
+        let threaded = PackOptions {
+            window: 2,
+            threads: 4,
+            ..PackOptions::default()
+        };
+        assert_eq!(16, deltas(&kinds(&threaded)?));
+        Ok(())
+    }
+
+    #[test]
+    fn configures_from_config() -> eyre::Result<()> {
+        let mut config = Config::default();
+        assert_eq!(PackOptions::default(), PackOptions::from_config(&config)?);
+        config.set("pack.window", "20")?;
+        config.set("pack.depth", "10")?;
+        config.set("pack.threads", "1")?;
+        assert_eq!(
+            PackOptions {
+                window: 20,
+                depth: 10,
+                threads: 1,
+                ..PackOptions::default()
+            },
+            PackOptions::from_config(&config)?
+        );
+        config.set("pack.window", "-1")?;
+        assert!(PackOptions::from_config(&config).is_err());
+        Ok(())
+    }
+}
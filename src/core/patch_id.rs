@@ -0,0 +1,234 @@
+//! Identifying commits by the changes they make rather than by their oid,
+//! like `git patch-id --stable` and `git cherry`, so that a change can be
+//! recognized after it's been cherry-picked or rebased
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bstr::ByteSlice;
+use tracing::instrument;
+
+use crate::core::{
+    db::{self, tree::FileNode, Blob, Commit, Tree, UntypedOid},
+    merge::diff,
+    rev_parse::RevRange,
+    revwalk::RevWalkError,
+    stat::Mode,
+    Oid, Repo, WsPath,
+};
+
+type Files = BTreeMap<WsPath, FileNode>;
+
+/// Lines of context around each change, as in a patch
+const CONTEXT: usize = 3;
+
+/// A commit `git cherry` lists
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Cherry {
+    pub commit: Oid<Commit>,
+    /// An equivalent change is upstream, which `git cherry` marks with `-`
+    pub upstream: bool,
+}
+
+impl Repo {
+    /// The patch id of what the commit changed from its first parent, the
+    /// same as `git show <commit> | git patch-id --stable` gives. Whitespace
+    /// and line numbers don't count, and neither does the order of the files
+    /// changed. `None` for merges, which have no single change.
+    #[instrument(err)]
+    pub fn patch_id(&mut self, commit: Oid<Commit>) -> Result<Option<UntypedOid>, PatchIdError> {
+        let commit = self.db.load(commit)?;
+        if !commit.merged.is_empty() {
+            return Ok(None);
+        }
+        let old = match commit.parent {
+            Some(parent) => {
+                let parent = self.db.load(parent)?;
+                self.db.load_tree_files(&WsPath::root(), parent.tree)?
+            }
+            None => Files::new(),
+        };
+        let new = self.db.load_tree_files(&WsPath::root(), commit.tree)?;
+
+        let mut paths = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+        paths.retain(|path| {
+            let (old, new) = (old.get(*path), new.get(*path));
+            old.map(|old| (old.oid, old.mode)) != new.map(|new| (new.oid, new.mode))
+        });
+
+        // Each file is hashed alone and the hashes summed, so their order
+        // doesn't matter
+        let mut sum = [0_u8; 20];
+        for path in paths {
+            let (old, new) = (old.get(path), new.get(path));
+            let hashed = self.file_patch_id(path, old, new)?;
+            let mut carry = 0_u16;
+            for (sum, byte) in sum.iter_mut().zip(hashed.as_bytes()) {
+                carry += u16::from(*sum) + u16::from(*byte);
+                *sum = carry.to_le_bytes()[0];
+                carry >>= 8;
+            }
+        }
+        Ok(Some(UntypedOid::new(sum)))
+    }
+
+    /// The commits in `head` that aren't in `upstream`, oldest first, with
+    /// whether an equivalent change is, like `git cherry <upstream> <head>`.
+    /// Merges are left out.
+    #[instrument(err)]
+    pub fn cherry(
+        &mut self,
+        upstream: UntypedOid,
+        head: UntypedOid,
+    ) -> Result<Vec<Cherry>, PatchIdError> {
+        let mut upstream_ids = BTreeSet::new();
+        for commit in self.walk_only(upstream, head)? {
+            if let Some(id) = self.patch_id(commit)? {
+                upstream_ids.insert(id);
+            }
+        }
+
+        let mut cherries = Vec::new();
+        for commit in self.walk_only(head, upstream)? {
+            if let Some(id) = self.patch_id(commit)? {
+                cherries.push(Cherry {
+                    commit,
+                    upstream: upstream_ids.contains(&id),
+                });
+            }
+        }
+        cherries.reverse();
+        Ok(cherries)
+    }
+
+    /// The commits reachable from `include` and not from `exclude`, newest
+    /// first
+    fn walk_only(
+        &self,
+        include: UntypedOid,
+        exclude: UntypedOid,
+    ) -> Result<Vec<Oid<Commit>>, PatchIdError> {
+        let range = RevRange {
+            include: vec![include],
+            exclude: vec![exclude],
+        };
+        let mut commits = Vec::new();
+        for walked in range.walk(&self.db)? {
+            commits.push(walked?.oid.to_typed());
+        }
+        Ok(commits)
+    }
+
+    /// Hashes the change to a file like git does, from the lines of its
+    /// patch with whitespace removed, leaving out hunk headers and the
+    /// `index` line
+    fn file_patch_id(
+        &mut self,
+        path: &WsPath,
+        old: Option<&FileNode>,
+        new: Option<&FileNode>,
+    ) -> Result<UntypedOid, PatchIdError> {
+        let path = path.to_string();
+        let path = path.as_bytes();
+        let mut hashed = Vec::new();
+        let mut add = |parts: &[&[u8]]| {
+            for part in parts {
+                hashed.extend(part.iter().filter(|b| !b.is_ascii_whitespace()));
+            }
+        };
+
+        add(&[b"diff--gita/", path, b"b/", path]);
+        let mode = |node: &FileNode| node.mode.as_base8().as_bytes();
+        match (old, new) {
+            (None, Some(new)) => add(&[b"newfilemode", mode(new)]),
+            (Some(old), None) => add(&[b"deletedfilemode", mode(old)]),
+            (Some(old), Some(new)) if old.mode != new.mode => {
+                add(&[b"oldmode", mode(old), b"newmode", mode(new)]);
+            }
+            _ => {}
+        }
+
+        let old_data = self.contents(old)?;
+        let new_data = self.contents(new)?;
+        if is_binary(&old_data) || is_binary(&new_data) {
+            let hex = |node: Option<&FileNode>| {
+                node.map_or_else(|| UntypedOid::zero().to_hex(), |node| node.oid.to_hex())
+            };
+            add(&[hex(old).as_bytes(), hex(new).as_bytes()]);
+        } else {
+            match (old, new) {
+                (None, _) => add(&[b"---/dev/null+++b/", path]),
+                (_, None) => add(&[b"---a/", path, b"+++/dev/null"]),
+                _ => add(&[b"---a/", path, b"+++b/", path]),
+            }
+            add(&[hunk_lines(&old_data, &new_data).as_slice()]);
+        }
+        Ok(UntypedOid::for_bytes(hashed))
+    }
+
+    /// What a submodule's diff shows for a gitlink
+    fn contents(&mut self, node: Option<&FileNode>) -> Result<Vec<u8>, PatchIdError> {
+        Ok(match node {
+            None => Vec::new(),
+            Some(node) if node.mode == Mode::Gitlink => {
+                format!("Subproject commit {}\n", node.oid.to_hex()).into_bytes()
+            }
+            Some(node) => self.db.load(node.oid)?.bytes.into(),
+        })
+    }
+}
+
+/// Like git, data with a NUL in its first 8000 bytes is binary
+fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(8000)].contains(&0)
+}
+
+/// The lines of the hunks of a patch from `old` to `new`, each starting
+/// with ` `, `-` or `+`, without hunk headers
+fn hunk_lines(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let (old, new) = (diff::lines(old), diff::lines(new));
+    let mut lines = Vec::new();
+    for hunk in diff::hunks(&old, &new, CONTEXT) {
+        for (op, line) in hunk.lines {
+            lines.push(op);
+            lines.extend_from_slice(line);
+        }
+    }
+    lines
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum PatchIdError {
+    /// Failed to walk commits
+    RevWalk(#[from] RevWalkError),
+    /// Failed to load commit
+    LoadCommit(#[from] db::LoadError<Commit>),
+    /// Failed to load tree
+    LoadTree(#[from] db::LoadError<Tree>),
+    /// Failed to load blob
+    LoadBlob(#[from] db::LoadError<Blob>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn keeps_three_lines_of_context() {
+        let old = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = b"1\nTWO\n3\n4\n5\n6\n7\n8\n9\n10\nELEVEN\n12\n";
+        assert_eq!(
+            " 1\n-2\n+TWO\n 3\n 4\n 5\n 8\n 9\n 10\n-11\n+ELEVEN\n 12\n",
+            hunk_lines(old, new).to_str_lossy()
+        );
+        assert_eq!("+a\n", hunk_lines(b"", b"a\n").to_str_lossy());
+    }
+
+    #[test]
+    fn detects_binary_like_git() {
+        assert!(is_binary(b"a\0b"));
+        assert!(!is_binary(b"text\n"));
+        let late = [vec![b'a'; 8000], vec![0]].concat();
+        assert!(!is_binary(&late));
+    }
+}
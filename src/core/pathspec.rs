@@ -0,0 +1,423 @@
+//! Pathspecs, which select paths the way the path arguments of git's commands
+//! do, like `src`, `*.rs`, `:(icase)readme*` or `:!vendor`
+
+use std::fmt;
+
+use bstr::{BStr, BString, ByteSlice};
+use regex::bytes::{Regex, RegexBuilder};
+
+use crate::core::{ws::ignore::Pattern, WsPath};
+
+#[derive(Debug, Clone)]
+pub struct Pathspec {
+    /// As given, including any magic
+    text: String,
+    pub magic: Magic,
+    /// Relative to the workspace root
+    pattern: BString,
+    /// `None` if the pattern is matched literally
+    regex: Option<Regex>,
+    /// Every path the pathspec matches is within this
+    prefix: WsPath,
+}
+
+/// Modifiers written before the pattern, either long like `:(top,icase)` or
+/// short like `:/` and `:!`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[allow(clippy::struct_excessive_bools)] // One per magic word
+pub struct Magic {
+    /// Relative to the workspace root rather than the current directory,
+    /// written `:(top)` or `:/`
+    pub top: bool,
+    /// Wildcards are matched as themselves
+    pub literal: bool,
+    /// Wildcards are matched like in `.gitignore`, so `*` doesn't match `/`
+    /// and `**` matches any number of directories
+    pub glob: bool,
+    pub icase: bool,
+    /// Paths that match are left out, written `:(exclude)`, `:!` or `:^`
+    pub exclude: bool,
+}
+
+/// Selects the paths that match any included pathspec and no excluded one.
+/// If there are only excluded pathspecs, everything else is included, and if
+/// there are none at all, everything is.
+#[derive(Debug, Clone, Default)]
+pub struct Pathspecs(Vec<Pathspec>);
+
+impl Pathspec {
+    /// Like [`Self::parse_in`] from the workspace root
+    pub fn parse(spec: &str) -> Result<Self, ParseError> {
+        Self::parse_in(spec, &WsPath::root())
+    }
+
+    /// Unless it has the `top` magic the pattern is relative to `cwd`, the
+    /// current directory within the workspace
+    pub fn parse_in(spec: &str, cwd: &WsPath) -> Result<Self, ParseError> {
+        let invalid = || ParseError::Invalid(spec.to_owned());
+        let (magic, pattern) = Magic::parse(spec)?;
+
+        let pattern = if magic.top {
+            WsPath::new_normalized(pattern)
+        } else {
+            WsPath::new_normalized(cwd.as_path().join(pattern))
+        }
+        .map_err(|_| invalid())?;
+        let pattern = pattern.to_bstring();
+
+        let regex = if !magic.literal && pattern.iter().any(|&b| Self::is_wildcard(b)) {
+            let re = if magic.glob {
+                Pattern::to_regex(&pattern, true)
+            } else {
+                Self::to_regex(&pattern)
+            };
+            let regex = RegexBuilder::new(&re)
+                .case_insensitive(magic.icase)
+                .build()
+                .map_err(|_| invalid())?;
+            Some(regex)
+        } else {
+            None
+        };
+
+        let prefix = if magic.icase {
+            WsPath::root()
+        } else if regex.is_some() {
+            let dirs = pattern
+                .split_str("/")
+                .take_while(|component| !component.iter().any(|&b| Self::is_wildcard(b)));
+            WsPath::new_unchecked_bytes(bstr::join("/", dirs))
+        } else {
+            WsPath::new_unchecked_bytes(pattern.clone())
+        };
+
+        Ok(Self {
+            text: spec.to_owned(),
+            magic,
+            pattern,
+            regex,
+            prefix,
+        })
+    }
+
+    /// Matches exactly the path, and everything within it if it's a
+    /// directory
+    pub fn literal(path: &WsPath) -> Self {
+        Self {
+            text: format!(":(literal,top){path}"),
+            magic: Magic {
+                top: true,
+                literal: true,
+                ..Magic::default()
+            },
+            pattern: path.to_bstring(),
+            regex: None,
+            prefix: path.clone(),
+        }
+    }
+
+    /// A pattern without wildcards matches the path it names and everything
+    /// within it. A pattern with wildcards must match the whole path.
+    pub fn matches(&self, path: &WsPath) -> bool {
+        let path = path.as_bstr();
+        if let Some(regex) = &self.regex {
+            return regex.is_match(path);
+        }
+        if self.pattern.is_empty() {
+            return true;
+        }
+        let len = self.pattern.len();
+        let prefix_matches = if self.magic.icase {
+            path.len() >= len && path[..len].eq_ignore_ascii_case(&self.pattern)
+        } else {
+            path.starts_with(&self.pattern)
+        };
+        prefix_matches && (path.len() == len || path[len] == b'/')
+    }
+
+    /// Every path the pathspec matches is within this, so only it needs to
+    /// be searched
+    pub fn prefix(&self) -> &WsPath {
+        &self.prefix
+    }
+
+    /// Whether it names a single path (and everything within it), having no
+    /// wildcards to match
+    pub fn names_path(&self) -> bool {
+        self.regex.is_none() && !self.magic.icase
+    }
+
+    /// The pattern relative to the workspace root, without magic
+    pub fn pattern(&self) -> &BStr {
+        self.pattern.as_bstr()
+    }
+
+    fn is_wildcard(byte: u8) -> bool {
+        matches!(byte, b'*' | b'?' | b'[' | b'\\')
+    }
+
+    /// Like `fnmatch` without `FNM_PATHNAME`, so wildcards match `/` too
+    fn to_regex(pattern: &[u8]) -> String {
+        let mut re = String::from("(?s-u)^");
+        let mut i = 0;
+        while i < pattern.len() {
+            let rest = &pattern[i..];
+            match rest[0] {
+                b'*' => re.push_str(".*"),
+                b'?' => re.push('.'),
+                b'[' => {
+                    if let Some(len) = Pattern::push_class(&mut re, rest) {
+                        i += len;
+                        continue;
+                    }
+                    re.push_str(r"\[");
+                }
+                b'\\' if rest.len() > 1 => {
+                    Pattern::push_literal(&mut re, rest[1]);
+                    i += 1;
+                }
+                byte => Pattern::push_literal(&mut re, byte),
+            }
+            i += 1;
+        }
+        re.push('$');
+        re
+    }
+}
+
+impl Magic {
+    /// Returns the magic and the rest of the pathspec
+    fn parse(spec: &str) -> Result<(Self, &str), ParseError> {
+        let mut magic = Self::default();
+        let rest = if let Some(rest) = spec.strip_prefix(":(") {
+            let (words, rest) = rest
+                .split_once(')')
+                .ok_or_else(|| ParseError::Invalid(spec.to_owned()))?;
+            for word in words.split(',').filter(|word| !word.is_empty()) {
+                match word {
+                    "top" => magic.top = true,
+                    "literal" => magic.literal = true,
+                    "glob" => magic.glob = true,
+                    "icase" => magic.icase = true,
+                    "exclude" => magic.exclude = true,
+                    _ => return Err(ParseError::UnknownMagic(word.to_owned())),
+                }
+            }
+            rest
+        } else if let Some(mut rest) = spec.strip_prefix(':') {
+            loop {
+                match rest.as_bytes().first() {
+                    Some(b'/') => magic.top = true,
+                    Some(b'!' | b'^') => magic.exclude = true,
+                    Some(b':') => {
+                        rest = &rest[1..];
+                        break;
+                    }
+                    _ => break,
+                }
+                rest = &rest[1..];
+            }
+            rest
+        } else {
+            spec
+        };
+
+        if magic.literal && magic.glob {
+            return Err(ParseError::LiteralGlob(spec.to_owned()));
+        }
+        Ok((magic, rest))
+    }
+}
+
+impl Pathspecs {
+    pub fn new(pathspecs: Vec<Pathspec>) -> Self {
+        Self(pathspecs)
+    }
+
+    /// Like [`Self::parse_in`] from the workspace root
+    pub fn parse<I, S>(specs: I) -> Result<Self, ParseError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self::parse_in(specs, &WsPath::root())
+    }
+
+    pub fn parse_in<I, S>(specs: I, cwd: &WsPath) -> Result<Self, ParseError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        specs
+            .into_iter()
+            .map(|spec| Pathspec::parse_in(spec.as_ref(), cwd))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// Matches exactly the paths, and everything within them
+    pub fn literal<'p>(paths: impl IntoIterator<Item = &'p WsPath>) -> Self {
+        Self(paths.into_iter().map(Pathspec::literal).collect())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Pathspec> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, path: &WsPath) -> bool {
+        let mut included = self.included().peekable();
+        let is_included = included.peek().is_none() || included.any(|spec| spec.matches(path));
+        is_included && !self.excluded().any(|spec| spec.matches(path))
+    }
+
+    /// Every path that matches is within one of these
+    pub fn prefixes(&self) -> Vec<WsPath> {
+        let prefixes = self
+            .included()
+            .map(|spec| spec.prefix.clone())
+            .collect::<Vec<_>>();
+        if prefixes.is_empty() {
+            vec![WsPath::root()]
+        } else {
+            WsPath::minimal_prefixes(&prefixes)
+                .into_iter()
+                .cloned()
+                .collect()
+        }
+    }
+
+    pub(crate) fn included(&self) -> impl Iterator<Item = &Pathspec> {
+        self.0.iter().filter(|spec| !spec.magic.exclude)
+    }
+
+    fn excluded(&self) -> impl Iterator<Item = &Pathspec> {
+        self.0.iter().filter(|spec| spec.magic.exclude)
+    }
+}
+
+impl fmt::Display for Pathspec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl PartialEq for Pathspec {
+    fn eq(&self, other: &Self) -> bool {
+        self.magic == other.magic && self.pattern == other.pattern
+    }
+}
+
+impl Eq for Pathspec {}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ParseError {
+    /// Invalid pathspec {0:?}
+    Invalid(String),
+    /// Unknown pathspec magic {0:?}
+    UnknownMagic(String),
+    /// Pathspec {0:?} can't be both literal and glob
+    LiteralGlob(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn matches(spec: &str, path: &str) -> bool {
+        Pathspec::parse(spec)
+            .unwrap()
+            .matches(&WsPath::new_unchecked(path))
+    }
+
+    #[test]
+    fn paths_match_themselves_and_their_contents() {
+        assert!(matches("src", "src"));
+        assert!(matches("src", "src/main.rs"));
+        assert!(matches("./src/", "src/main.rs"));
+        assert!(!matches("src", "srcs/main.rs"));
+        assert!(matches(".", "anything/at/all"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", "src/core/mod.rs"));
+        assert!(!matches("*.rs", "main.rsx"));
+        assert!(matches("src/?ain.[rR]s", "src/main.Rs"));
+
+        assert!(!matches(":(glob)*.rs", "src/core/mod.rs"));
+        assert!(matches(":(glob)src/**/*.rs", "src/core/mod.rs"));
+
+        assert!(matches(":(literal)*.rs", "*.rs"));
+        assert!(!matches(":(literal)*.rs", "main.rs"));
+    }
+
+    #[test]
+    fn icase() {
+        assert!(matches(":(icase)README", "readme"));
+        assert!(matches(":(icase)Doc", "doc/readme"));
+        assert!(matches(":(icase)*.MD", "doc/readme.md"));
+        assert!(!matches("README", "readme"));
+    }
+
+    #[test]
+    fn relative_to_cwd_unless_top() -> eyre::Result<()> {
+        let cwd = WsPath::new_unchecked("src");
+        assert_eq!(
+            "src/main.rs",
+            Pathspec::parse_in("main.rs", &cwd)?.pattern()
+        );
+        assert_eq!("lib", Pathspec::parse_in("../lib", &cwd)?.pattern());
+        assert_eq!("main.rs", Pathspec::parse_in(":/main.rs", &cwd)?.pattern());
+        assert_eq!(
+            "main.rs",
+            Pathspec::parse_in(":(top)main.rs", &cwd)?.pattern()
+        );
+        assert!(Pathspec::parse_in("../../x", &cwd).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_magic() -> eyre::Result<()> {
+        let spec = Pathspec::parse(":!/:a")?;
+        assert!(spec.magic.exclude && spec.magic.top);
+        assert_eq!("a", spec.pattern());
+        assert!(Pathspec::parse(":^a")?.magic.exclude);
+        assert!(Pathspec::parse(":(exclude,icase)a")?.magic.icase);
+        assert!(Pathspec::parse(":(bogus)a").is_err());
+        assert!(Pathspec::parse(":(literal,glob)a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn excludes() -> eyre::Result<()> {
+        let path = |path: &str| WsPath::new_unchecked(path);
+
+        let specs = Pathspecs::parse(["src", ":!src/vendor"])?;
+        assert!(specs.matches(&path("src/main.rs")));
+        assert!(!specs.matches(&path("src/vendor/lib.rs")));
+        assert!(!specs.matches(&path("README")));
+
+        let specs = Pathspecs::parse([":(exclude)*.log"])?;
+        assert!(specs.matches(&path("README")));
+        assert!(!specs.matches(&path("logs/a.log")));
+        assert_eq!(vec![WsPath::root()], specs.prefixes());
+        Ok(())
+    }
+
+    #[test]
+    fn prefixes_stop_at_wildcards() -> eyre::Result<()> {
+        let prefix = |spec| Pathspec::parse(spec).map(|spec| spec.prefix().clone());
+        assert_eq!(WsPath::new_unchecked("src/core"), prefix("src/core")?);
+        assert_eq!(WsPath::new_unchecked("src"), prefix("src/*.rs")?);
+        assert_eq!(WsPath::new_unchecked("src"), prefix("src/c*/mod.rs")?);
+        assert_eq!(WsPath::root(), prefix("*.rs")?);
+        assert_eq!(WsPath::root(), prefix(":(icase)src")?);
+        Ok(())
+    }
+}
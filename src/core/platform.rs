@@ -0,0 +1,264 @@
+//! The few things that differ between unix and Windows.
+//!
+//! Like git, we treat paths as bytes. On unix that's what they are. On
+//! Windows they're WTF-8 internally, so paths that aren't valid unicode are
+//! converted lossily, and separators are normalized to `/`, before they're
+//! used as a [`WsPath`](crate::core::WsPath).
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// What we need from [`fs::Metadata`], with placeholders for what the
+/// platform doesn't have
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Metadata {
+    pub ctime: SystemTime,
+    pub mtime: SystemTime,
+    pub dev: u64,
+    pub ino: u64,
+    /// Unix style, including the file type
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        ffi::{OsStr, OsString},
+        fs, io,
+        os::unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::{MetadataExt, PermissionsExt},
+        },
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    };
+
+    pub fn normalize(path: PathBuf) -> PathBuf {
+        path
+    }
+
+    pub fn as_bytes(path: &Path) -> &[u8] {
+        path.as_os_str().as_bytes()
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+        OsString::from_vec(bytes).into()
+    }
+
+    pub fn into_bytes(path: PathBuf) -> Vec<u8> {
+        path.into_os_string().into_vec()
+    }
+
+    /// Times before the epoch have negative seconds, with the nanoseconds
+    /// still counting forwards
+    #[allow(clippy::cast_sign_loss)]
+    fn time(secs: i64, nanos: i64) -> SystemTime {
+        let nanos = Duration::from_nanos(nanos as u64);
+        if secs < 0 {
+            SystemTime::UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nanos
+        } else {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64) + nanos
+        }
+    }
+
+    pub fn metadata(meta: &fs::Metadata) -> super::Metadata {
+        super::Metadata {
+            ctime: time(meta.ctime(), meta.ctime_nsec()),
+            mtime: time(meta.mtime(), meta.mtime_nsec()),
+            dev: meta.dev(),
+            ino: meta.ino(),
+            mode: meta.mode(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size: meta.size(),
+        }
+    }
+
+    pub fn symlink(target: &[u8], link: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(OsStr::from_bytes(target), link)
+    }
+
+    pub fn set_executable(path: &Path, executable: bool) -> io::Result<()> {
+        let mode = if executable { 0o755 } else { 0o644 };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    pub fn is_executable(meta: &fs::Metadata) -> bool {
+        meta.permissions().mode() & 0o111 != 0
+    }
+    /// Root acting for someone with `sudo` is them, as with git
+    #[allow(clippy::unnecessary_wraps)] // Not on Windows
+    pub fn current_owner() -> Option<u32> {
+        // SAFETY: Always succeeds
+        let euid = unsafe { libc::geteuid() };
+        if euid == 0 {
+            if let Some(uid) = std::env::var("SUDO_UID")
+                .ok()
+                .and_then(|uid| uid.parse().ok())
+            {
+                return Some(uid);
+            }
+        }
+        Some(euid)
+    }
+
+    /// So that entries renamed into it survive a crash
+    pub fn sync_dir(dir: &Path) -> io::Result<()> {
+        fs::File::open(dir)?.sync_all()
+    }
+
+    /// Only known where there's a `/proc`
+    pub fn process_running(pid: u32) -> Option<bool> {
+        let proc = Path::new("/proc");
+        if proc.join("self").exists() {
+            Some(proc.join(pid.to_string()).exists())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::{
+        fs, io,
+        path::{Path, PathBuf},
+        time::SystemTime,
+    };
+
+    pub fn normalize(path: PathBuf) -> PathBuf {
+        match path.to_str() {
+            Some(path) if !path.contains('\\') => path.into(),
+            _ => path.to_string_lossy().replace('\\', "/").into(),
+        }
+    }
+
+    /// The path must have been normalized
+    pub fn as_bytes(path: &Path) -> &[u8] {
+        path.to_str().expect("Path was normalized").as_bytes()
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+        match String::from_utf8(bytes) {
+            Ok(path) => path.into(),
+            Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned().into(),
+        }
+    }
+
+    pub fn into_bytes(path: PathBuf) -> Vec<u8> {
+        normalize(path)
+            .into_os_string()
+            .into_string()
+            .expect("Path was normalized")
+            .into_bytes()
+    }
+
+    pub fn metadata(meta: &fs::Metadata) -> super::Metadata {
+        let mode = if meta.file_type().is_symlink() {
+            0o120_000
+        } else if meta.is_dir() {
+            0o040_000
+        } else {
+            0o100_644
+        };
+        super::Metadata {
+            ctime: meta.created().unwrap_or(SystemTime::UNIX_EPOCH),
+            mtime: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: meta.len(),
+        }
+    }
+
+    pub fn symlink(target: &[u8], link: &Path) -> io::Result<()> {
+        let target = from_bytes(target.to_vec());
+        let target = target.to_string_lossy().replace('/', "\\");
+        std::os::windows::fs::symlink_file(target, link)
+    }
+
+    /// There's no executable bit
+    pub fn set_executable(_path: &Path, _executable: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Everything is, as there's no executable bit
+    pub fn is_executable(_meta: &fs::Metadata) -> bool {
+        true
+    }
+    /// Files don't have a [`super::Metadata::uid`] to compare with
+    pub fn current_owner() -> Option<u32> {
+        None
+    }
+
+    /// Directories can't be opened to sync them, and renames are
+    /// journaled with the file
+    pub fn sync_dir(_dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn process_running(_pid: u32) -> Option<bool> {
+        None
+    }
+}
+
+/// Make a path safe to use with [`as_bytes`]
+pub fn normalize(path: PathBuf) -> PathBuf {
+    imp::normalize(path)
+}
+
+/// The path must have come from [`normalize`] (or a function that
+/// normalizes) on Windows.
+pub fn as_bytes(path: &Path) -> &[u8] {
+    imp::as_bytes(path)
+}
+
+pub fn from_bytes(bytes: impl Into<Vec<u8>>) -> PathBuf {
+    imp::from_bytes(bytes.into())
+}
+
+/// Any path
+pub fn into_bytes(path: PathBuf) -> Vec<u8> {
+    imp::into_bytes(path)
+}
+
+pub fn metadata(meta: &fs::Metadata) -> Metadata {
+    imp::metadata(meta)
+}
+
+pub fn symlink(target: &[u8], link: &Path) -> io::Result<()> {
+    imp::symlink(target, link)
+}
+
+/// Does nothing where there's no executable bit
+pub fn set_executable(path: &Path, executable: bool) -> io::Result<()> {
+    imp::set_executable(path, executable)
+}
+
+pub fn is_executable(meta: &fs::Metadata) -> bool {
+    imp::is_executable(meta)
+}
+
+/// Whether a process with this id is running, if the platform can tell us
+pub fn process_running(pid: u32) -> Option<bool> {
+    imp::process_running(pid)
+}
+
+/// The user we act as, to compare with [`Metadata::uid`], if the platform
+/// has owners
+pub fn current_owner() -> Option<u32> {
+    imp::current_owner()
+}
+
+/// Does nothing where directories can't be synced
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    imp::sync_dir(dir)
+}
@@ -0,0 +1,90 @@
+//! Reporting how far along network and pack operations are, so that callers
+//! can show progress bars
+
+use std::{convert::TryFrom, time::Instant};
+
+use bstr::BStr;
+
+/// What's being done, like the stages git reports
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Stage {
+    /// Finding the objects to send
+    Counting,
+    /// Writing the pack to send
+    Writing,
+    /// Downloading a pack
+    Receiving,
+    /// Storing the objects of a pack we received, resolving its deltas
+    Resolving,
+    /// Copying objects from the source of a local clone
+    Copying,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Update {
+    pub stage: Stage,
+    /// Objects done so far in this stage
+    pub objects: usize,
+    /// How many objects there are, if known
+    pub total: Option<usize>,
+    /// Bytes done so far in this stage
+    pub bytes: u64,
+    /// Bytes per second since the stage started
+    pub throughput: u64,
+}
+
+/// Called as operations make progress. Both methods do nothing by default,
+/// and `()` ignores everything.
+pub trait Progress {
+    fn update(&mut self, _update: &Update) {}
+
+    /// A message from the remote (on sideband 2), like
+    /// `Counting objects: 50% (1/2)`
+    fn remote(&mut self, _message: &BStr) {}
+}
+
+impl Progress for () {}
+
+/// Reports the updates of one stage
+pub(crate) struct Reporter<'a> {
+    progress: &'a mut dyn Progress,
+    started: Instant,
+    update: Update,
+}
+
+impl<'a> Reporter<'a> {
+    pub fn new(progress: &'a mut dyn Progress, stage: Stage, total: Option<usize>) -> Self {
+        Self {
+            progress,
+            started: Instant::now(),
+            update: Update {
+                stage,
+                objects: 0,
+                total,
+                bytes: 0,
+                throughput: 0,
+            },
+        }
+    }
+
+    pub fn add(&mut self, objects: usize, bytes: u64) {
+        self.update.objects += objects;
+        self.update.bytes += bytes;
+        let millis = u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX);
+        self.update.throughput = self.update.bytes.saturating_mul(1000) / millis.max(1);
+        self.progress.update(&self.update);
+    }
+
+    pub fn remote(&mut self, message: &BStr) {
+        self.progress.remote(message);
+    }
+}
+
+/// Only passes on messages from the remote
+pub(crate) struct RemoteOnly<'a>(pub &'a mut dyn Progress);
+
+impl Progress for RemoteOnly<'_> {
+    fn remote(&mut self, message: &BStr) {
+        self.0.remote(message);
+    }
+}
@@ -0,0 +1,618 @@
+//! Pushing to a remote repository over the network
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+};
+
+use bstr::{BStr, BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use crate::core::{
+    config,
+    db::{tree::NodeRef, Commit, CommitRef, LoadRawError, TagRef, TreeRef, UntypedOid},
+    hook,
+    pack::{self, PackOptions},
+    progress::{Progress, Reporter, Stage},
+    refs,
+    refspec::{self, Refspec},
+    revwalk::{self, RevWalkError},
+    transport::{
+        self,
+        receive_pack::{ReceivePackError, RefUpdate},
+        Capabilities, ReceivePack, Service, TransportError,
+    },
+    Db, Oid, Repo,
+};
+
+/// A ref to change on the remote
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PushUpdate {
+    /// What's pushed, like `refs/heads/main`, if it's a ref. Only told to the
+    /// `pre-push` hook.
+    pub local_ref: Option<BString>,
+    /// Like `refs/heads/main`
+    pub remote_ref: BString,
+    /// What to set the ref to, or `None` to delete it
+    pub new: Option<Oid<Commit>>,
+    /// Update the ref even if that loses commits
+    pub force: bool,
+    /// Update the ref even if that loses commits, but only if the remote has
+    /// what we expect, like `git push --force-with-lease`
+    pub lease: Option<Lease>,
+}
+
+/// What we expect a remote ref to be before forcing an update
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Lease {
+    /// What its remote-tracking branch is, or missing if there isn't one
+    Tracking,
+    /// This, or missing if `None`
+    Oid(Option<Oid<Commit>>),
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PushOptions {
+    /// Either every ref is updated or none are, like `git push --atomic`.
+    /// The remote has to support it.
+    pub atomic: bool,
+    /// Don't run the `pre-push` hook, like `git push --no-verify`
+    pub no_verify: bool,
+}
+
+/// What [`Repo::push`] did with a [`PushUpdate`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Pushed {
+    pub remote_ref: BString,
+    /// What the remote had
+    pub old: Option<Oid<Commit>>,
+    pub new: Option<Oid<Commit>>,
+    pub status: PushStatus,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PushStatus {
+    Updated,
+    UpToDate,
+    /// Not sent, as the remote ref has commits that the new value doesn't
+    /// (or that we don't have) and the update wasn't forced
+    NonFastForward,
+    /// Not sent, as the remote ref isn't what the lease expected
+    Stale,
+    /// Refused by the remote, for the reason it gave
+    Rejected(BString),
+}
+
+impl Repo {
+    /// Like `git push <remote>`, where `remote` is either the name of a
+    /// remote configured with `remote.<name>.url` or a url. Only the objects
+    /// the remote doesn't have are sent. Pushing to a named remote updates
+    /// its remote-tracking branches to match.
+    pub fn push(&self, remote: &str, updates: &[PushUpdate]) -> Result<Vec<Pushed>, PushError> {
+        self.push_with(remote, updates, &PushOptions::default(), &mut ())
+    }
+
+    /// An atomic push fails with [`PushError::Atomic`] if any update would
+    /// be left out or is refused, without updating any.
+    ///
+    /// `progress` hears how the objects to send are counted and packed, and
+    /// what the remote says while taking them.
+    #[instrument(err, skip(progress))]
+    pub fn push_with(
+        &self,
+        remote: &str,
+        updates: &[PushUpdate],
+        options: &PushOptions,
+        progress: &mut dyn Progress,
+    ) -> Result<Vec<Pushed>, PushError> {
+        let (name, url) = self.remote_url(remote);
+        let transport = transport::connect(url, Service::ReceivePack, &self.config)?;
+        let mut receive_pack = ReceivePack::connect(transport)?;
+        receive_pack.set_atomic(options.atomic)?;
+        let remote_refs = receive_pack
+            .refs()
+            .iter()
+            .map(|remote_ref| (remote_ref.name.clone(), remote_ref.oid))
+            .collect::<BTreeMap<_, _>>();
+
+        let (mut pushed, commands) = self.negotiate_updates(name, &remote_refs, updates)?;
+        if options.atomic {
+            let left_out = pushed.iter().find(|pushed| {
+                matches!(
+                    pushed.status,
+                    PushStatus::NonFastForward | PushStatus::Stale
+                )
+            });
+            if let Some(left_out) = left_out {
+                return Err(atomic_error(left_out));
+            }
+        }
+        if commands.is_empty() {
+            return Ok(pushed);
+        }
+        let pushing = pushed
+            .iter()
+            .filter(|pushed| pushed.status == PushStatus::Updated)
+            .cloned()
+            .collect::<Vec<_>>();
+        if !options.no_verify {
+            let input = pre_push_input(updates, &pushed);
+            let args = [OsStr::new(name.unwrap_or(url)), OsStr::new(url)];
+            self.run_hook_with("pre-push", &args, &input)?;
+        }
+        for hooks in &self.hooks {
+            hooks.pre_push(name.unwrap_or(url), url, &pushing)?;
+        }
+
+        let pack = self.pack_for(
+            &commands,
+            &remote_refs,
+            receive_pack.capabilities(),
+            progress,
+        )?;
+        let report = receive_pack.push(&commands, pack.as_deref(), progress)?;
+
+        for pushed in &mut pushed {
+            if pushed.status != PushStatus::Updated {
+                continue;
+            }
+            if let Some(err) = &report.unpack_error {
+                pushed.status = PushStatus::Rejected(format!("unpack failed: {err}").into());
+            } else if let Some(reason) = report.rejected.get(&pushed.remote_ref) {
+                pushed.status = PushStatus::Rejected(reason.clone());
+            }
+        }
+        if options.atomic {
+            let refused = pushed
+                .iter()
+                .filter(|pushed| matches!(pushed.status, PushStatus::Rejected(_)))
+                .collect::<Vec<_>>();
+            // The others are only refused because of this one
+            let failed = refused
+                .iter()
+                .find(|pushed| pushed.status != PushStatus::Rejected(ATOMIC_FAILURE.into()))
+                .or_else(|| refused.first());
+            if let Some(failed) = failed {
+                return Err(atomic_error(failed));
+            }
+        }
+        if let Some(name) = name {
+            for pushed in &pushed {
+                if pushed.status == PushStatus::Updated {
+                    self.update_tracking(name, pushed)?;
+                }
+            }
+        }
+        Ok(pushed)
+    }
+
+    /// What becomes of each update given what the remote has, and the
+    /// commands to send for those that go ahead
+    fn negotiate_updates(
+        &self,
+        name: Option<&str>,
+        remote_refs: &BTreeMap<BString, UntypedOid>,
+        updates: &[PushUpdate],
+    ) -> Result<(Vec<Pushed>, Vec<RefUpdate>), PushError> {
+        let mut pushed = Vec::new();
+        let mut commands = Vec::new();
+        for update in updates {
+            let old = remote_refs.get(&update.remote_ref).copied();
+            let new = update.new.map(Oid::into_untyped);
+            let expected = match update.lease {
+                Some(lease) => Some(self.leased(name, update.remote_ref.as_bstr(), lease)?),
+                None => None,
+            };
+            let status = match (old, new) {
+                _ if old == new => PushStatus::UpToDate,
+                _ if expected.is_some_and(|expected| expected != old) => PushStatus::Stale,
+                (Some(old), Some(new))
+                    if !update.force
+                        && expected.is_none()
+                        && !revwalk::is_ancestor(&self.db, old, new)? =>
+                {
+                    PushStatus::NonFastForward
+                }
+                // The remote only applies this while the ref is still `old`, so
+                // leases hold against pushes since it listed its refs too
+                _ => {
+                    commands.push(RefUpdate {
+                        name: update.remote_ref.clone(),
+                        old: old.unwrap_or_else(UntypedOid::zero),
+                        new: new.unwrap_or_else(UntypedOid::zero),
+                    });
+                    PushStatus::Updated
+                }
+            };
+            pushed.push(Pushed {
+                remote_ref: update.remote_ref.clone(),
+                old: old.map(UntypedOid::to_typed),
+                new: update.new,
+                status,
+            });
+        }
+        Ok((pushed, commands))
+    }
+
+    /// The objects the commands need that the remote doesn't have, or `None`
+    /// if they only delete refs
+    fn pack_for(
+        &self,
+        commands: &[RefUpdate],
+        remote_refs: &BTreeMap<BString, UntypedOid>,
+        capabilities: &Capabilities,
+        progress: &mut dyn Progress,
+    ) -> Result<Option<Vec<u8>>, PushError> {
+        let tips = commands
+            .iter()
+            .map(|command| command.new)
+            .filter(|&new| new != UntypedOid::zero())
+            .collect::<Vec<_>>();
+        if tips.is_empty() {
+            return Ok(None);
+        }
+        let remote = remote_refs.values().copied();
+        let objects = missing_objects(&self.db, &tips, remote, progress)?;
+        let bases = if capabilities.get("no-thin").is_some() {
+            Vec::new()
+        } else {
+            thin_bases(&self.db, &tips, &objects)?
+        };
+        let ofs_delta = capabilities.get("ofs-delta").is_some();
+        debug!(objects = objects.len(), bases = bases.len(), "Packing");
+        let options = PackOptions::from_config(&self.config)?;
+        let mut pack = Vec::new();
+        pack::write_thin(
+            &self.db, &objects, &bases, ofs_delta, &options, &mut pack, progress,
+        )?;
+        Ok(Some(pack))
+    }
+
+    /// What `refspecs` select to push from our refs, like `git push <remote>
+    /// <refspec>...`, with short names (like `main`) for branches or tags.
+    /// Without any, `remote.<name>.push` is used, or else the current branch
+    /// is pushed to the branch of the same name.
+    pub fn push_updates(
+        &self,
+        remote: &str,
+        refspecs: &[Refspec],
+    ) -> Result<Vec<PushUpdate>, PushError> {
+        let configured;
+        let refspecs = if refspecs.is_empty() {
+            configured = self
+                .config
+                .get_all(&format!("remote.{remote}.push"))
+                .map(Refspec::parse)
+                .collect::<Result<Vec<_>, _>>()?;
+            &configured
+        } else {
+            refspecs
+        };
+
+        let mut updates = Vec::new();
+        if refspecs.is_empty() {
+            let (remote_ref, new) = self.resolve_push_src(b"HEAD".as_bstr())?;
+            updates.push(PushUpdate {
+                local_ref: Some(remote_ref.clone()),
+                remote_ref,
+                new: Some(new),
+                force: false,
+                lease: None,
+            });
+        }
+        for refspec in refspecs.iter().filter(|refspec| !refspec.negative) {
+            if refspec.src.is_empty() {
+                updates.push(PushUpdate {
+                    local_ref: None,
+                    remote_ref: refspec.dst.clone().expect("Parsed with a destination"),
+                    new: None,
+                    force: refspec.force,
+                    lease: None,
+                });
+            } else if refspec.is_wildcard() {
+                for (name, oid) in self.refs.list(refspec.prefix())? {
+                    if let Some(remote_ref) = refspec.map(name.as_bstr()) {
+                        if !refspec::excluded(refspecs, name.as_bstr()) {
+                            updates.push(PushUpdate {
+                                local_ref: Some(name.clone()),
+                                remote_ref,
+                                new: Some(oid),
+                                force: refspec.force,
+                                lease: None,
+                            });
+                        }
+                    }
+                }
+            } else {
+                let (name, oid) = self.resolve_push_src(refspec.src.as_bstr())?;
+                if refspec::excluded(refspecs, name.as_bstr()) {
+                    continue;
+                }
+                let remote_ref = match &refspec.dst {
+                    Some(dst) if dst.starts_with(b"refs/") => dst.clone(),
+                    // Like the source, a branch unless it's a tag
+                    Some(dst) => {
+                        let namespace = if name.starts_with(b"refs/tags/") {
+                            "refs/tags/"
+                        } else {
+                            "refs/heads/"
+                        };
+                        BString::from(format!("{namespace}{dst}"))
+                    }
+                    None => name.clone(),
+                };
+                updates.push(PushUpdate {
+                    local_ref: Some(name),
+                    remote_ref,
+                    new: Some(oid),
+                    force: refspec.force,
+                    lease: None,
+                });
+            }
+        }
+        Ok(updates)
+    }
+
+    /// The full name of the ref and what it points to, where HEAD is the
+    /// current branch
+    fn resolve_push_src(&self, src: &BStr) -> Result<(BString, Oid<Commit>), PushError> {
+        let unknown = || PushError::UnknownRef(src.to_owned());
+        let name = if src == "HEAD" {
+            let branch = self.refs.current_branch()?.ok_or(PushError::NoBranch)?;
+            BString::from([b"refs/heads/", branch.as_bytes()].concat())
+        } else if src.starts_with(b"refs/") {
+            src.to_owned()
+        } else {
+            let mut found = None;
+            for namespace in [&b"refs/heads/"[..], b"refs/tags/"] {
+                let name = BString::from([namespace, src.as_bytes()].concat());
+                if self.refs.read_ref(name.as_bstr())?.is_some() {
+                    found = Some(name);
+                    break;
+                }
+            }
+            found.ok_or_else(unknown)?
+        };
+        let oid = self.refs.read_ref(name.as_bstr())?.ok_or_else(unknown)?;
+        Ok((name, oid))
+    }
+
+    /// What the remote ref is expected to be, by its remote-tracking branch
+    /// if the remote is named
+    fn leased(
+        &self,
+        remote: Option<&str>,
+        remote_ref: &BStr,
+        lease: Lease,
+    ) -> Result<Option<UntypedOid>, refs::ReadError> {
+        let expected = match (lease, remote, remote_ref.strip_prefix(b"refs/heads/")) {
+            (Lease::Oid(oid), _, _) => oid,
+            (Lease::Tracking, Some(remote), Some(branch)) => {
+                let tracking = format!("refs/remotes/{remote}/{}", branch.as_bstr());
+                self.refs.read_ref(tracking.as_bytes().as_bstr())?
+            }
+            (Lease::Tracking, _, _) => None,
+        };
+        Ok(expected.map(Oid::into_untyped))
+    }
+
+    fn update_tracking(&self, remote: &str, pushed: &Pushed) -> Result<(), refs::UpdateError> {
+        let branch = match pushed.remote_ref.strip_prefix(b"refs/heads/") {
+            Some(branch) => branch.as_bstr(),
+            None => return Ok(()),
+        };
+        let tracking = BString::from(format!("refs/remotes/{remote}/{branch}"));
+        match &pushed.new {
+            Some(new) => self.refs.update_ref(tracking.as_bstr(), new),
+            None => self.refs.delete_ref(tracking.as_bstr()),
+        }
+    }
+}
+
+/// Why a remote refuses the updates of an atomic push besides the one that
+/// failed
+pub(crate) const ATOMIC_FAILURE: &str = "atomic push failure";
+
+fn atomic_error(pushed: &Pushed) -> PushError {
+    PushError::Atomic(pushed.remote_ref.clone(), pushed.status.clone())
+}
+
+/// What the `pre-push` hook reads, a line like `<local ref> <local oid>
+/// <remote ref> <remote oid>` for each update that's sent
+fn pre_push_input(updates: &[PushUpdate], pushed: &[Pushed]) -> Vec<u8> {
+    let hex = |oid: Option<Oid<Commit>>| {
+        oid.map_or_else(|| UntypedOid::zero().to_hex(), |oid| oid.to_hex())
+    };
+    updates
+        .iter()
+        .zip(pushed)
+        .filter(|(_, pushed)| pushed.status == PushStatus::Updated)
+        .map(|(update, pushed)| {
+            let new = hex(update.new);
+            let local = match (&update.local_ref, update.new) {
+                (_, None) => "(delete)".to_owned(),
+                (Some(local), _) => local.to_string(),
+                (None, Some(_)) => new.clone(),
+            };
+            format!("{local} {new} {} {}\n", pushed.remote_ref, hex(pushed.old))
+        })
+        .collect::<Vec<_>>()
+        .concat()
+        .into_bytes()
+}
+
+/// Everything reachable from `tips` that isn't reachable from what the
+/// remote has. Of what the remote has, we only walk what we have too.
+pub(crate) fn missing_objects(
+    db: &Db,
+    tips: &[UntypedOid],
+    remote: impl IntoIterator<Item = UntypedOid>,
+    progress: &mut dyn Progress,
+) -> Result<Vec<UntypedOid>, PushError> {
+    let mut seen = BTreeSet::new();
+    let mut excluded = Vec::new();
+    walk(
+        db,
+        remote.into_iter().filter(|oid| db.contains(oid)),
+        &mut seen,
+        &mut excluded,
+        &mut (),
+    )?;
+
+    let mut missing = Vec::new();
+    walk(db, tips.iter().copied(), &mut seen, &mut missing, progress)?;
+    Ok(missing)
+}
+
+/// Adds everything reachable from `start` that hasn't been seen to `found`,
+/// counting each
+fn walk(
+    db: &Db,
+    start: impl IntoIterator<Item = UntypedOid>,
+    seen: &mut BTreeSet<UntypedOid>,
+    found: &mut Vec<UntypedOid>,
+    progress: &mut dyn Progress,
+) -> Result<(), PushError> {
+    let mut reporter = Reporter::new(progress, Stage::Counting, None);
+    let mut pending = start.into_iter().collect::<Vec<_>>();
+    while let Some(oid) = pending.pop() {
+        if !seen.insert(oid) {
+            continue;
+        }
+        let (ty, data) = db.load_raw(&oid)?.ok_or(PushError::MissingObject(oid))?;
+        found.push(oid);
+        reporter.add(1, 0);
+        let corrupt = || PushError::Corrupt(oid);
+        match ty.as_bytes() {
+            b"commit" => {
+                let commit = CommitRef::parse(&data).map_err(|_| corrupt())?;
+                pending.push(commit.tree.into_untyped());
+                if !db.is_shallow(&oid) {
+                    pending.extend(commit.parents().map(Oid::into_untyped));
+                }
+            }
+            b"tag" => pending.push(TagRef::parse(&data).map_err(|_| corrupt())?.object),
+            // Gitlinks are left out, as they're in other repositories
+            b"tree" => pending.extend(
+                TreeRef::parse(&data)
+                    .map_err(|_| corrupt())?
+                    .nodes()
+                    .filter(|node| !node.is_gitlink())
+                    .map(|node| node.untyped_oid()),
+            ),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// What the remote has at the same paths in the parents of the commits we
+/// send as the objects we send, which they're likely to be deltas of
+fn thin_bases(
+    db: &Db,
+    tips: &[UntypedOid],
+    objects: &[UntypedOid],
+) -> Result<Vec<UntypedOid>, PushError> {
+    let sending = objects.iter().copied().collect::<BTreeSet<_>>();
+    let load = |oid| db.load_raw(&oid)?.ok_or(PushError::MissingObject(oid));
+    let tree_of = |oid: UntypedOid| -> Result<UntypedOid, PushError> {
+        let (_, data) = load(oid)?;
+        let commit = CommitRef::parse(&data).map_err(|_| PushError::Corrupt(oid))?;
+        Ok(commit.tree.into_untyped())
+    };
+
+    let mut seen = BTreeSet::new();
+    let mut pairs = Vec::new();
+    let mut pending = tips.to_vec();
+    while let Some(oid) = pending.pop() {
+        if !sending.contains(&oid) || !seen.insert(oid) || db.is_shallow(&oid) {
+            continue;
+        }
+        let (ty, data) = load(oid)?;
+        let corrupt = || PushError::Corrupt(oid);
+        let commit = match ty.as_bytes() {
+            b"commit" => CommitRef::parse(&data).map_err(|_| corrupt())?,
+            b"tag" => {
+                pending.push(TagRef::parse(&data).map_err(|_| corrupt())?.object);
+                continue;
+            }
+            _ => continue,
+        };
+        for parent in commit.parents().map(Oid::into_untyped) {
+            if sending.contains(&parent) {
+                pending.push(parent);
+            } else if db.contains(&parent) {
+                // Which the remote has, as we'd send it otherwise
+                pairs.push((commit.tree.into_untyped(), tree_of(parent)?));
+            }
+        }
+    }
+
+    let is_blob = |node: &NodeRef| matches!(node, NodeRef::File { .. }) && !node.is_gitlink();
+    let mut bases = BTreeSet::new();
+    while let Some((new, old)) = pairs.pop() {
+        if new == old || !sending.contains(&new) || !bases.insert(old) {
+            continue;
+        }
+        let (_, new_data) = load(new)?;
+        let (_, old_data) = load(old)?;
+        let new_tree = TreeRef::parse(&new_data).map_err(|_| PushError::Corrupt(new))?;
+        let old_tree = TreeRef::parse(&old_data).map_err(|_| PushError::Corrupt(old))?;
+        let old_nodes = old_tree
+            .nodes()
+            .map(|node| (node.name(), node))
+            .collect::<BTreeMap<_, _>>();
+        for node in new_tree.nodes() {
+            let Some(old) = old_nodes.get(node.name()) else {
+                continue;
+            };
+            let (new_oid, old_oid) = (node.untyped_oid(), old.untyped_oid());
+            if let (NodeRef::Tree { .. }, NodeRef::Tree { .. }) = (node, old) {
+                pairs.push((new_oid, old_oid));
+            } else if is_blob(&node)
+                && is_blob(old)
+                && new_oid != old_oid
+                && sending.contains(&new_oid)
+            {
+                bases.insert(old_oid);
+            }
+        }
+    }
+    Ok(bases.into_iter().collect())
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum PushError {
+    /// Failed to connect to remote
+    Connect(#[from] TransportError),
+    /// Failed to push to remote
+    ReceivePack(#[from] ReceivePackError),
+    /// Failed to load object to push
+    Load(#[from] LoadRawError),
+    /// Object {0:?} to push isn't in the database
+    MissingObject(UntypedOid),
+    /// Object {0:?} to push is corrupt
+    Corrupt(UntypedOid),
+    /// Failed to walk commits to push
+    Walk(#[from] RevWalkError),
+    /// Invalid pack config
+    Config(#[from] config::ValueError),
+    /// Failed to write pack to push
+    WritePack(#[from] pack::WriteError),
+    /// Failed to update remote-tracking branches
+    UpdateRefs(#[from] refs::UpdateError),
+    /// Invalid push refspec
+    Refspec(#[from] refspec::ParseError),
+    /// Failed to read refs to push
+    ReadRefs(#[from] refs::ReadError),
+    /// No ref {0:?} to push
+    UnknownRef(BString),
+    /// Not on a branch to push
+    NoBranch,
+    /// Atomic push failed, as {0} wasn't updated: {1:?}
+    Atomic(BString, PushStatus),
+    /// {0}
+    Hook(#[from] hook::HookError),
+    /// {0}
+    Rejected(#[from] hook::Rejected),
+}
@@ -1,35 +1,124 @@
 use bstr::{BStr, BString, ByteSlice};
 
+use tracing::debug;
+
 use crate::core::{
     db::{object::ParseOidError, Commit},
-    locked_file, LockedFile, Oid,
+    hook::{Hooks, Rejected},
+    locked_file::{self, LockOptions},
+    platform, LockedFile, Oid,
 };
 use std::{
-    ffi::OsStr,
     fs,
     io::{self, Write},
-    os::unix::prelude::OsStrExt,
     path::PathBuf,
+    sync::Arc,
 };
 
 #[derive(Debug, Clone)]
 pub struct Refs {
     path: PathBuf,
+    /// Where refs shared by linked worktrees are, see [`Self::with_common_dir`]
+    common: PathBuf,
+    /// Told about transactions
+    hooks: Vec<Arc<dyn Hooks>>,
+    /// Sync writes to disk, see [`Self::set_fsync`]
+    fsync: bool,
+    /// See [`Self::set_lock_options`]
+    lock_options: LockOptions,
 }
 
 impl Refs {
     const HEAD: &'static [u8] = b"HEAD";
+    /// Where HEAD was before the last merge moved it
+    pub const ORIG_HEAD: &'static [u8] = b"ORIG_HEAD";
+    /// What's being merged while the conflicts of a merge are resolved. The
+    /// merge is committed with it as a parent.
+    pub const MERGE_HEAD: &'static [u8] = b"MERGE_HEAD";
+    /// The commit being cherry-picked while its conflicts are resolved
+    pub const CHERRY_PICK_HEAD: &'static [u8] = b"CHERRY_PICK_HEAD";
+    /// The commit being reverted while its conflicts are resolved
+    pub const REVERT_HEAD: &'static [u8] = b"REVERT_HEAD";
+    /// What the last fetch fetched, see [`Self::update_fetch_head`]
+    pub const FETCH_HEAD: &'static [u8] = b"FETCH_HEAD";
+    const SYMBOLIC_PREFIX: &'static [u8] = b"ref: ";
+    /// Symbolic refs are followed at most this many times, so that a cycle
+    /// is an error
+    const MAX_SYMBOLIC_DEPTH: usize = 5;
 
     pub fn new<P: Into<PathBuf>>(path: P) -> Self {
-        Self { path: path.into() }
+        let path = path.into();
+        Self::with_common_dir(path.clone(), path)
+    }
+
+    /// For a linked worktree, whose `HEAD`, like other refs outside `refs/`
+    /// and those under `refs/bisect/`, `refs/worktree/` and
+    /// `refs/rewritten/`, are its own
+    pub fn with_common_dir(path: impl Into<PathBuf>, common: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            common: common.into(),
+            hooks: Vec::new(),
+            fsync: false,
+            lock_options: LockOptions::default(),
+        }
     }
 
+    pub(crate) fn register_hooks(&mut self, hooks: Arc<dyn Hooks>) {
+        self.hooks.push(hooks);
+    }
+
+    /// Whether refs are synced to disk when they're written, like the
+    /// `reference` component of [`Fsync`](crate::core::Fsync)
+    pub fn set_fsync(&mut self, fsync: bool) {
+        self.fsync = fsync;
+    }
+
+    /// How hard to try for the lock on a ref someone else is updating
+    pub fn set_lock_options(&mut self, options: LockOptions) {
+        self.lock_options = options;
+    }
+
+    /// Parent directories are created as needed
     pub fn update_ref(&self, ref_name: &BStr, oid: &Oid<Commit>) -> Result<(), UpdateError> {
+        self.write_ref(ref_name, oid.to_hex().as_bytes())
+    }
+
+    /// Point a ref at another ref, as HEAD points at a branch
+    pub fn update_symbolic_ref(&self, ref_name: &BStr, target: &BStr) -> Result<(), UpdateError> {
+        self.write_ref(
+            ref_name,
+            &[Self::SYMBOLIC_PREFIX, target.as_bytes()].concat(),
+        )
+    }
+
+    /// Does nothing if the ref doesn't exist
+    pub fn delete_ref(&self, ref_name: &BStr) -> Result<(), UpdateError> {
+        match fs::remove_file(self.ref_path(ref_name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(UpdateError::Write(ref_name.to_owned(), err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Replaces `FETCH_HEAD` with a line per ref fetched, each `<oid> TAB
+    /// [not-for-merge] TAB <description>` as git writes them. Read as a ref,
+    /// it's the oid of the first line.
+    pub fn update_fetch_head(&self, lines: &[BString]) -> Result<(), UpdateError> {
+        self.write_ref(Self::FETCH_HEAD.as_bstr(), &bstr::join("\n", lines))
+    }
+
+    fn write_ref(&self, ref_name: &BStr, contents: &[u8]) -> Result<(), UpdateError> {
         let path = self.ref_path(ref_name);
-        let mut lock =
-            LockedFile::acquire(path).map_err(|e| UpdateError::Lock(ref_name.to_owned(), e))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| UpdateError::Write(ref_name.to_owned(), e))?;
+        }
+        let mut lock = LockedFile::acquire_with(path, &self.lock_options)
+            .map_err(|e| UpdateError::Lock(ref_name.to_owned(), e))?;
+        lock.set_fsync(self.fsync);
 
-        lock.write_all(oid.to_hex().as_bytes())
+        lock.write_all(contents)
             .map_err(|e| UpdateError::Write(ref_name.to_owned(), e))?;
         lock.write_all(b"\n")
             .map_err(|e| UpdateError::Write(ref_name.to_owned(), e))?;
@@ -39,17 +128,61 @@ impl Refs {
         Ok(())
     }
 
+    /// If HEAD points at a branch, the branch is updated instead, as when
+    /// committing or merging. To move HEAD itself see [`Self::detach_head`]
+    /// and [`Self::switch_head`].
     pub fn update_head(&self, oid: &Oid<Commit>) -> Result<(), UpdateError> {
+        let head = Self::HEAD.as_bstr();
+        match self.read_symbolic(head) {
+            Ok(Some(target)) => self.update_ref(target.as_bstr(), oid),
+            Ok(None) => self.update_ref(head, oid),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Point HEAD straight at a commit, leaving the branch it was on alone
+    pub fn detach_head(&self, oid: &Oid<Commit>) -> Result<(), UpdateError> {
         self.update_ref(Self::HEAD.as_bstr(), oid)
     }
 
+    /// Point HEAD at a branch, given in full like `refs/heads/main`
+    pub fn switch_head(&self, branch: &BStr) -> Result<(), UpdateError> {
+        self.update_symbolic_ref(Self::HEAD.as_bstr(), branch)
+    }
+
+    /// Symbolic refs are followed. `None` if the ref (or the ref it points
+    /// to) doesn't exist.
     pub fn read_ref(&self, ref_name: &BStr) -> Result<Option<Oid<Commit>>, ReadError> {
-        match fs::read(self.ref_path(ref_name)) {
-            Ok(bytes) => {
-                let oid = bytes.trim();
-                let oid = Oid::parse(oid).map_err(|e| ReadError::Parse(ref_name.to_owned(), e))?;
-                Ok(Some(oid))
+        let mut ref_name = ref_name.to_owned();
+        for _ in 0..=Self::MAX_SYMBOLIC_DEPTH {
+            let Some(contents) = self.read_raw(ref_name.as_bstr())? else {
+                return Ok(None);
+            };
+            if let Some(target) = contents.strip_prefix(Self::SYMBOLIC_PREFIX) {
+                ref_name = target.trim().into();
+                continue;
             }
+            // Only the first oid counts, as in `FETCH_HEAD`
+            let oid = contents.fields().next().unwrap_or_default();
+            let oid = Oid::parse(oid).map_err(|e| ReadError::Parse(ref_name.clone(), e))?;
+            return Ok(Some(oid));
+        }
+        Err(ReadError::Cycle(ref_name))
+    }
+
+    /// The ref a symbolic ref points at, or `None` if it isn't symbolic or
+    /// doesn't exist
+    pub fn read_symbolic(&self, ref_name: &BStr) -> Result<Option<BString>, ReadError> {
+        Ok(self.read_raw(ref_name)?.and_then(|contents| {
+            contents
+                .strip_prefix(Self::SYMBOLIC_PREFIX)
+                .map(|target| target.trim().into())
+        }))
+    }
+
+    fn read_raw(&self, ref_name: &BStr) -> Result<Option<Vec<u8>>, ReadError> {
+        match fs::read(self.ref_path(ref_name)) {
+            Ok(contents) => Ok(Some(contents)),
             Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
             Err(err) => Err(ReadError::Io(ref_name.to_owned(), err)),
         }
@@ -59,8 +192,201 @@ impl Refs {
         self.read_ref(Self::HEAD.as_bstr())
     }
 
+    /// The refs under the prefix (like `refs/heads/`) and what they point
+    /// to, sorted by name
+    pub fn list(&self, prefix: &BStr) -> Result<Vec<(BString, Oid<Commit>)>, ReadError> {
+        let dir = self.ref_path(prefix);
+        let mut refs = Vec::new();
+        for entry in walkdir::WalkDir::new(&dir).sort_by_file_name() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err)
+                    if err.io_error().map(io::Error::kind) == Some(io::ErrorKind::NotFound) =>
+                {
+                    continue
+                }
+                Err(err) => return Err(ReadError::List(prefix.to_owned(), err)),
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let rel = entry
+                .path()
+                .strip_prefix(&self.path)
+                .or_else(|_| entry.path().strip_prefix(&self.common))
+                .expect("Within refs");
+            let name = BString::from(platform::into_bytes(rel.to_owned()));
+            if !is_valid_name(name.as_bstr()) {
+                continue;
+            }
+            if let Some(oid) = self.read_ref(name.as_bstr())? {
+                refs.push((name, oid));
+            }
+        }
+        Ok(refs)
+    }
+
+    /// The short name of the branch HEAD points to, or `None` if HEAD is
+    /// detached or missing
+    pub fn current_branch(&self) -> Result<Option<BString>, ReadError> {
+        Ok(self
+            .read_symbolic(Self::HEAD.as_bstr())?
+            .and_then(|target| {
+                target
+                    .strip_prefix(b"refs/heads/".as_ref())
+                    .map(BString::from)
+            }))
+    }
+
+    /// Updates to make together, see [`Transaction`]
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            refs: self,
+            updates: Vec::new(),
+        }
+    }
+
     fn ref_path(&self, ref_name: &BStr) -> PathBuf {
-        self.path.join(OsStr::from_bytes(ref_name.as_bytes()))
+        let per_worktree = !ref_name.starts_with(b"refs/")
+            || [&b"refs/bisect/"[..], b"refs/worktree/", b"refs/rewritten/"]
+                .iter()
+                .any(|prefix| ref_name.starts_with(prefix));
+        let dir = if per_worktree {
+            &self.path
+        } else {
+            &self.common
+        };
+        dir.join(platform::from_bytes(ref_name.as_bytes()))
+    }
+}
+
+/// Under `refs/`, with no empty or hidden components, nothing that looks
+/// like a lock or a lock's owner, and none of the characters git gives
+/// meaning to in revisions
+pub(crate) fn is_valid_name(name: &BStr) -> bool {
+    let components_valid = name.split_str("/").all(|component| {
+        !component.is_empty()
+            && !component.starts_with(b".")
+            && !component.ends_with(b".lock")
+            && !component.ends_with(b".lock.owner")
+    });
+    let chars_valid = name
+        .iter()
+        .all(|&byte| byte > b' ' && byte != 0x7f && !b"~^:?*[\\".contains(&byte));
+    name.starts_with(b"refs/") && components_valid && chars_valid && name.find("..").is_none()
+}
+
+/// Ref updates made only if every ref is what we expect, like `git update-ref
+/// --stdin`. Every ref is locked before any is checked, so others can't
+/// change them between the check and the update.
+#[derive(Debug)]
+pub struct Transaction<'r> {
+    refs: &'r Refs,
+    updates: Vec<Update>,
+}
+
+/// A change to a ref in a [`Transaction`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Update {
+    pub name: BString,
+    /// `None` if the ref must be missing
+    pub old: Option<Oid<Commit>>,
+    /// `None` to delete the ref
+    pub new: Option<Oid<Commit>>,
+}
+
+/// How far along a [`Transaction`] is, for
+/// [`Hooks::reference_transaction`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TransactionState {
+    /// Every ref is locked and checked, but none are changed
+    Prepared,
+    /// A hook rejected the prepared transaction, so nothing was changed
+    Aborted,
+    /// Every ref is changed
+    Committed,
+}
+
+impl Transaction<'_> {
+    /// Sets the ref to `new` (or deletes it if `None`) as long as it's `old`
+    /// (or missing if `None`)
+    pub fn update(
+        &mut self,
+        name: impl Into<BString>,
+        old: Option<Oid<Commit>>,
+        new: Option<Oid<Commit>>,
+    ) -> &mut Self {
+        self.updates.push(Update {
+            name: name.into(),
+            old,
+            new,
+        });
+        self
+    }
+
+    /// If a ref isn't what we expect, none are changed. Otherwise if writing
+    /// fails part way through, the refs before are changed.
+    pub fn commit(mut self) -> Result<(), TransactionError> {
+        // Locked in order, so that transactions don't deadlock each other
+        self.updates.sort_by(|a, b| a.name.cmp(&b.name));
+        if let Some(pair) = self
+            .updates
+            .windows(2)
+            .find(|pair| pair[0].name == pair[1].name)
+        {
+            return Err(TransactionError::Duplicate(pair[0].name.clone()));
+        }
+
+        let mut locks = Vec::new();
+        for update in &self.updates {
+            let name = update.name.as_bstr();
+            let path = self.refs.ref_path(name);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| UpdateError::Write(name.to_owned(), e))?;
+            }
+            let mut lock = LockedFile::acquire_with(path, &self.refs.lock_options)
+                .map_err(|e| UpdateError::Lock(name.to_owned(), e))?;
+            lock.set_fsync(self.refs.fsync);
+            if self.refs.read_ref(name)? != update.old {
+                return Err(TransactionError::Stale(name.to_owned()));
+            }
+            locks.push(lock);
+        }
+
+        if let Err(rejected) = self.prepare() {
+            self.notify(TransactionState::Aborted);
+            return Err(rejected.into());
+        }
+        for (update, mut lock) in self.updates.iter().zip(locks) {
+            let name = update.name.as_bstr();
+            let write_err = |e| UpdateError::Write(name.to_owned(), e);
+            if let Some(new) = update.new {
+                writeln!(lock, "{}", new.to_hex()).map_err(write_err)?;
+                lock.commit().map_err(write_err)?;
+            } else {
+                self.refs.delete_ref(name)?;
+                lock.rollback().map_err(write_err)?;
+            }
+        }
+        self.notify(TransactionState::Committed);
+        Ok(())
+    }
+
+    /// Stops at the first hook to reject the transaction
+    fn prepare(&self) -> Result<(), Rejected> {
+        for hooks in &self.refs.hooks {
+            hooks.reference_transaction(TransactionState::Prepared, &self.updates)?;
+        }
+        Ok(())
+    }
+
+    /// Too late for hooks to reject it
+    fn notify(&self, state: TransactionState) {
+        for hooks in &self.refs.hooks {
+            if let Err(err) = hooks.reference_transaction(state, &self.updates) {
+                debug!(%err, ?state, "Ignoring hook");
+            }
+        }
     }
 }
 
@@ -70,6 +396,10 @@ pub enum ReadError {
     Io(BString, #[source] io::Error),
     /// Failed to parse Oid of ref {0}
     Parse(BString, #[source] ParseOidError),
+    /// Symbolic refs nested too deeply at {0}, there may be a cycle
+    Cycle(BString),
+    /// Failed to list refs under {0}
+    List(BString, #[source] walkdir::Error),
 }
 
 #[derive(Debug, displaydoc::Display, thiserror::Error)]
@@ -78,4 +408,152 @@ pub enum UpdateError {
     Write(BString, #[source] io::Error),
     /// Error locking ref {0} for writing
     Lock(BString, #[source] locked_file::Error),
+    /// Failed to read HEAD to update it
+    Read(#[from] ReadError),
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum TransactionError {
+    /// Ref {0} is updated more than once
+    Duplicate(BString),
+    /// Ref {0} isn't what was expected
+    Stale(BString),
+    /// Failed to read ref to check it
+    Read(#[from] ReadError),
+    /// Failed to update ref
+    Update(#[from] UpdateError),
+    /// {0}
+    Rejected(#[from] Rejected),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    #[test]
+    fn transactions_change_all_refs_or_none() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let refs = Refs::new(dir.path());
+        let a = Oid::parse("a".repeat(40))?;
+        let b = Oid::parse("b".repeat(40))?;
+        let name = |name: &'static str| name.as_bytes().as_bstr();
+        refs.update_ref(name("refs/heads/main"), &a)?;
+        refs.update_ref(name("refs/heads/gone"), &a)?;
+
+        let mut transaction = refs.transaction();
+        transaction
+            .update("refs/heads/main", Some(a), Some(b))
+            .update("refs/heads/new", None, Some(b))
+            .update("refs/heads/gone", Some(b), None);
+        assert!(matches!(
+            transaction.commit(),
+            Err(TransactionError::Stale(name)) if name == "refs/heads/gone"
+        ));
+        assert_eq!(Some(a), refs.read_ref(name("refs/heads/main"))?);
+        assert_eq!(None, refs.read_ref(name("refs/heads/new"))?);
+
+        let mut transaction = refs.transaction();
+        transaction
+            .update("refs/heads/main", Some(a), Some(b))
+            .update("refs/heads/new", None, Some(b))
+            .update("refs/heads/gone", Some(a), None);
+        transaction.commit()?;
+        assert_eq!(Some(b), refs.read_ref(name("refs/heads/main"))?);
+        assert_eq!(Some(b), refs.read_ref(name("refs/heads/new"))?);
+        assert_eq!(None, refs.read_ref(name("refs/heads/gone"))?);
+        // Nothing is left locked
+        assert!(!dir.path().join("refs/heads/main.lock").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn lists_no_locks_or_their_owners() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let refs = Refs::new(dir.path());
+        let a = Oid::parse("a".repeat(40))?;
+        refs.update_ref(b"refs/heads/main".as_bstr(), &a)?;
+
+        let options = LockOptions {
+            stale_after: Some(std::time::Duration::from_hours(1)),
+            ..LockOptions::default()
+        };
+        let _held = LockedFile::acquire_with(dir.path().join("refs/heads/main"), &options)?;
+        assert!(dir.path().join("refs/heads/main.lock.owner").exists());
+        assert_eq!(
+            vec![(BString::from("refs/heads/main"), a)],
+            refs.list(b"refs/heads/".as_bstr())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn detaching_head_leaves_branch_alone() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let refs = Refs::new(dir.path());
+        let a = Oid::parse("a".repeat(40))?;
+        let b = Oid::parse("b".repeat(40))?;
+        let main = b"refs/heads/main".as_bstr();
+        refs.update_ref(main, &a)?;
+        refs.switch_head(main)?;
+
+        refs.update_head(&b)?;
+        assert_eq!(Some(b), refs.read_ref(main)?);
+        assert_eq!(Some(BString::from("main")), refs.current_branch()?);
+
+        refs.detach_head(&a)?;
+        assert_eq!(Some(b), refs.read_ref(main)?);
+        assert_eq!(Some(a), refs.head()?);
+        assert_eq!(None, refs.current_branch()?);
+        Ok(())
+    }
+
+    #[test]
+    fn tells_hooks_about_transactions() -> eyre::Result<()> {
+        use std::sync::Mutex;
+        use TransactionState::*;
+
+        /// Rejects deleting refs
+        #[derive(Debug, Default)]
+        struct Recorder(Mutex<Vec<(TransactionState, usize)>>);
+
+        impl Hooks for Recorder {
+            fn reference_transaction(
+                &self,
+                state: TransactionState,
+                updates: &[Update],
+            ) -> Result<(), Rejected> {
+                self.0.lock().unwrap().push((state, updates.len()));
+                if updates.iter().any(|update| update.new.is_none()) {
+                    return Err(Rejected("No deleting".into()));
+                }
+                Ok(())
+            }
+        }
+
+        let dir = tempdir()?;
+        let mut refs = Refs::new(dir.path());
+        let recorder = Arc::new(Recorder::default());
+        refs.register_hooks(Arc::clone(&recorder) as Arc<dyn Hooks>);
+        let a = Oid::parse("a".repeat(40))?;
+        let main = b"refs/heads/main".as_bstr();
+
+        let mut transaction = refs.transaction();
+        transaction.update(main, None, Some(a));
+        transaction.commit()?;
+        let mut transaction = refs.transaction();
+        transaction.update(main, Some(a), None);
+        assert!(matches!(
+            transaction.commit(),
+            Err(TransactionError::Rejected(_))
+        ));
+        assert_eq!(Some(a), refs.read_ref(main)?);
+        assert!(!dir.path().join("refs/heads/main.lock").exists());
+        assert_eq!(
+            vec![(Prepared, 1), (Committed, 1), (Prepared, 1), (Aborted, 1)],
+            *recorder.0.lock().unwrap()
+        );
+        Ok(())
+    }
 }
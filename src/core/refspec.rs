@@ -0,0 +1,240 @@
+//! Refspecs, which say which refs to fetch or push and where to put them,
+//! like `+refs/heads/*:refs/remotes/origin/*`
+
+use std::fmt;
+
+use bstr::{BStr, BString, ByteSlice};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Refspec {
+    /// Like `refs/heads/*`. Empty when pushing to delete `dst`.
+    pub src: BString,
+    /// Like `refs/remotes/origin/*`, or `None` if the refs aren't stored
+    pub dst: Option<BString>,
+    /// Update `dst` even if that loses commits, written with a leading `+`
+    pub force: bool,
+    /// Leave out the refs matching `src`, written with a leading `^`
+    pub negative: bool,
+}
+
+impl Refspec {
+    /// Either side may have one `*`, which matches anything, as long as the
+    /// other side has one too
+    pub fn parse(spec: &str) -> Result<Self, ParseError> {
+        let invalid = || ParseError(spec.to_owned());
+        let (negative, rest) = match spec.strip_prefix('^') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let (force, rest) = match rest.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let (src, dst) = match rest.split_once(':') {
+            Some((src, dst)) => (src, Some(dst)),
+            None => (rest, None),
+        };
+
+        let wildcards = |side: &str| side.matches('*').count();
+        let valid = match dst {
+            _ if src.contains(char::is_whitespace) => false,
+            _ if negative => !force && dst.is_none() && !src.is_empty(),
+            None => !src.is_empty() && wildcards(src) <= 1,
+            Some(dst) => {
+                (!src.is_empty() || !dst.is_empty())
+                    && wildcards(src) <= 1
+                    && (dst.is_empty() || wildcards(src) == wildcards(dst))
+            }
+        };
+        if !valid {
+            return Err(invalid());
+        }
+        Ok(Self {
+            src: src.into(),
+            dst: dst.filter(|dst| !dst.is_empty()).map(Into::into),
+            force,
+            negative,
+        })
+    }
+
+    pub fn is_wildcard(&self) -> bool {
+        self.src.contains(&b'*')
+    }
+
+    /// What the refs matching `src` start with, like `refs/heads/`
+    pub fn prefix(&self) -> &BStr {
+        let end = self.src.find_byte(b'*').unwrap_or(self.src.len());
+        self.src[..end].as_bstr()
+    }
+
+    pub fn matches(&self, name: &BStr) -> bool {
+        self.capture(name).is_some()
+    }
+
+    /// Where `name` goes, if it matches `src` and there's a `dst`
+    pub fn map(&self, name: &BStr) -> Option<BString> {
+        let capture = self.capture(name)?;
+        let dst = self.dst.as_ref()?;
+        Some(dst.replacen("*", capture, 1).into())
+    }
+
+    /// The refspec taking `dst` back to `src`, if there's a `dst`
+    pub fn reversed(&self) -> Option<Self> {
+        Some(Self {
+            src: self.dst.clone()?,
+            dst: Some(self.src.clone()),
+            force: self.force,
+            negative: false,
+        })
+    }
+
+    /// What the wildcard matched, or empty if there isn't one
+    fn capture<'a>(&self, name: &'a BStr) -> Option<&'a BStr> {
+        match self.src.find_byte(b'*') {
+            Some(star) => {
+                let (prefix, suffix) = (&self.src[..star], &self.src[star + 1..]);
+                let fits = name.len() >= prefix.len() + suffix.len();
+                (fits && name.starts_with(prefix) && name.ends_with(suffix))
+                    .then(|| &name[prefix.len()..name.len() - suffix.len()])
+            }
+            None => (name == self.src).then(|| &name[..0]),
+        }
+    }
+}
+
+/// Everywhere `name` goes by each refspec that maps it, and whether that's
+/// forced. Nothing matched by a negative refspec goes anywhere.
+pub fn map(refspecs: &[Refspec], name: &BStr) -> Vec<(BString, bool)> {
+    if excluded(refspecs, name) {
+        return Vec::new();
+    }
+    refspecs
+        .iter()
+        .filter(|refspec| !refspec.negative)
+        .filter_map(|refspec| Some((refspec.map(name)?, refspec.force)))
+        .collect()
+}
+
+/// Whether a negative refspec matches `name`
+pub fn excluded(refspecs: &[Refspec], name: &BStr) -> bool {
+    refspecs
+        .iter()
+        .any(|refspec| refspec.negative && refspec.matches(name))
+}
+
+impl fmt::Display for Refspec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negative {
+            write!(f, "^")?;
+        }
+        if self.force {
+            write!(f, "+")?;
+        }
+        write!(f, "{}", self.src)?;
+        match &self.dst {
+            Some(dst) => write!(f, ":{dst}"),
+            None if self.src.is_empty() => write!(f, ":"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+/// Invalid refspec {0:?}
+pub struct ParseError(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn name(name: &str) -> &BStr {
+        name.as_bytes().as_bstr()
+    }
+
+    #[test]
+    fn parses_refspecs() -> eyre::Result<()> {
+        assert_eq!(
+            Refspec {
+                src: "refs/heads/*".into(),
+                dst: Some("refs/remotes/origin/*".into()),
+                force: true,
+                negative: false,
+            },
+            Refspec::parse("+refs/heads/*:refs/remotes/origin/*")?
+        );
+        assert_eq!(
+            Refspec {
+                src: "refs/heads/wip/*".into(),
+                dst: None,
+                force: false,
+                negative: true,
+            },
+            Refspec::parse("^refs/heads/wip/*")?
+        );
+        assert_eq!(
+            Refspec {
+                src: "".into(),
+                dst: Some("refs/heads/gone".into()),
+                force: false,
+                negative: false,
+            },
+            Refspec::parse(":refs/heads/gone")?
+        );
+        for spec in [
+            "+refs/heads/*:refs/remotes/origin/*",
+            "^refs/heads/wip/*",
+            "main",
+            ":refs/heads/gone",
+        ] {
+            assert_eq!(spec, Refspec::parse(spec)?.to_string());
+        }
+
+        for invalid in [
+            "",
+            ":",
+            "refs/heads/*:refs/remotes/origin/main",
+            "refs/*/*:refs/*/*",
+            "^+refs/heads/main",
+            "^refs/heads/main:refs/heads/other",
+            "refs/heads/a b",
+        ] {
+            assert!(Refspec::parse(invalid).is_err(), "{:?}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn maps_refs() -> eyre::Result<()> {
+        let refspecs = [
+            Refspec::parse("^refs/heads/wip/*")?,
+            Refspec::parse("refs/heads/main:refs/remotes/origin/trunk")?,
+            Refspec::parse("+refs/heads/*:refs/remotes/origin/*")?,
+            Refspec::parse("refs/tags/v*-rc")?,
+        ];
+        assert_eq!(
+            vec![
+                ("refs/remotes/origin/trunk".into(), false),
+                ("refs/remotes/origin/main".into(), true),
+            ],
+            map(&refspecs, name("refs/heads/main"))
+        );
+        assert_eq!(
+            vec![("refs/remotes/origin/feature/x".into(), true)],
+            map(&refspecs, name("refs/heads/feature/x"))
+        );
+        assert!(map(&refspecs, name("refs/heads/wip/x")).is_empty());
+        assert!(refspecs[3].matches(name("refs/tags/v1-rc")));
+        assert!(!refspecs[3].matches(name("refs/tags/v1")));
+        assert!(map(&refspecs, name("refs/tags/v1-rc")).is_empty());
+        assert_eq!(name("refs/tags/v"), refspecs[3].prefix());
+
+        let reversed = refspecs[2].reversed().expect("Has dst");
+        assert_eq!(
+            Some(BString::from("refs/heads/x")),
+            reversed.map(name("refs/remotes/origin/x"))
+        );
+        assert_eq!(None, refspecs[3].reversed());
+        Ok(())
+    }
+}
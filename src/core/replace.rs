@@ -0,0 +1,187 @@
+//! Replacements, objects loaded in place of others without rewriting what
+//! links to them, like `git replace`. Each is a ref like
+//! `refs/replace/<hex of the original>` pointing to its replacement, so they
+//! can be fetched and pushed like any other ref.
+
+use std::{collections::BTreeMap, env, fmt::Write};
+
+use bstr::ByteSlice;
+use tracing::instrument;
+
+use crate::core::{
+    config::{self, Config},
+    db::{LoadRawError, StoreRawError, UntypedOid},
+    refs, Refs, Repo,
+};
+
+pub const PREFIX: &str = "refs/replace/";
+
+impl Repo {
+    /// Every replacement, by the object it replaces, even if they aren't
+    /// used
+    #[instrument(err)]
+    pub fn replacements(&self) -> Result<BTreeMap<UntypedOid, UntypedOid>, ReplaceError> {
+        list(&self.refs)
+    }
+
+    /// Loads `replacement` in place of `original` from now on. They must be
+    /// the same type of object, and `original` mustn't already be replaced
+    /// unless `force`.
+    #[instrument(err)]
+    pub fn replace(
+        &mut self,
+        original: UntypedOid,
+        replacement: UntypedOid,
+        force: bool,
+    ) -> Result<(), ReplaceError> {
+        if original == replacement {
+            return Err(ReplaceError::Itself(original));
+        }
+        let expected = self.object_type(original)?;
+        let actual = self.object_type(replacement)?;
+        if expected != actual {
+            return Err(ReplaceError::WrongType {
+                replacement,
+                expected,
+                actual,
+            });
+        }
+
+        let name = ref_name(original);
+        let old = self.refs.read_ref(name.as_bytes().as_bstr())?;
+        if old.is_some() && !force {
+            return Err(ReplaceError::Exists(original));
+        }
+        let mut transaction = self.refs.transaction();
+        transaction.update(name, old, Some(replacement.to_typed()));
+        transaction.commit()?;
+        self.reload_replacements()
+    }
+
+    /// Replaces `commit` with a copy that has other parents, like `git
+    /// replace --graft`, returning the copy. This can cut history short or
+    /// join histories that were stored apart.
+    #[instrument(err)]
+    pub fn graft(
+        &mut self,
+        commit: UntypedOid,
+        parents: &[UntypedOid],
+        force: bool,
+    ) -> Result<UntypedOid, ReplaceError> {
+        let (ty, data) = self
+            .db
+            .load_raw(&commit)?
+            .ok_or(ReplaceError::Missing(commit))?;
+        if ty != "commit" {
+            return Err(ReplaceError::NotCommit(commit));
+        }
+        let (headers, message) = data
+            .find(b"\n\n")
+            .map_or((&data[..], &b""[..]), |end| data.split_at(end + 1));
+
+        let mut grafted = Vec::new();
+        let mut lines = headers.lines_with_terminator().peekable();
+        if let Some(tree) = lines.next_if(|line| line.starts_with(b"tree ")) {
+            grafted.extend_from_slice(tree);
+        }
+        let mut parent_lines = String::new();
+        for parent in parents {
+            writeln!(parent_lines, "parent {}", parent.to_hex()).expect("Writing to string");
+        }
+        grafted.extend_from_slice(parent_lines.as_bytes());
+        for line in lines.filter(|line| !line.starts_with(b"parent ")) {
+            grafted.extend_from_slice(line);
+        }
+        grafted.extend_from_slice(message);
+
+        let replacement = self.db.store_raw(b"commit", &grafted)?;
+        self.replace(commit, replacement, force)?;
+        Ok(replacement)
+    }
+
+    /// Loads `original` itself again
+    #[instrument(err)]
+    pub fn remove_replacement(&mut self, original: UntypedOid) -> Result<(), ReplaceError> {
+        let name = ref_name(original);
+        let old = self
+            .refs
+            .read_ref(name.as_bytes().as_bstr())?
+            .ok_or(ReplaceError::NotReplaced(original))?;
+        let mut transaction = self.refs.transaction();
+        transaction.update(name, Some(old), None);
+        transaction.commit()?;
+        self.reload_replacements()
+    }
+
+    fn reload_replacements(&mut self) -> Result<(), ReplaceError> {
+        let replacements = load(&self.refs, &self.config)?;
+        self.db.set_replacements(replacements);
+        Ok(())
+    }
+
+    fn object_type(&self, oid: UntypedOid) -> Result<String, ReplaceError> {
+        let (ty, _) = self.db.load_raw(&oid)?.ok_or(ReplaceError::Missing(oid))?;
+        Ok(ty.to_str_lossy().into_owned())
+    }
+}
+
+/// The replacements to use, which are none if `GIT_NO_REPLACE_OBJECTS` is
+/// set or `core.useReplaceRefs` is false
+pub(crate) fn load(
+    refs: &Refs,
+    config: &Config,
+) -> Result<BTreeMap<UntypedOid, UntypedOid>, ReplaceError> {
+    let disabled = env::var_os("GIT_NO_REPLACE_OBJECTS").is_some()
+        || config.get_bool("core.useReplaceRefs")? == Some(false);
+    if disabled {
+        return Ok(BTreeMap::new());
+    }
+    list(refs)
+}
+
+fn list(refs: &Refs) -> Result<BTreeMap<UntypedOid, UntypedOid>, ReplaceError> {
+    let mut replacements = BTreeMap::new();
+    for (name, replacement) in refs.list(PREFIX.as_bytes().as_bstr())? {
+        let hex = &name[PREFIX.len()..];
+        let original =
+            UntypedOid::parse(hex).map_err(|_| ReplaceError::InvalidRef(name.clone()))?;
+        replacements.insert(original, replacement.into_untyped());
+    }
+    Ok(replacements)
+}
+
+fn ref_name(original: UntypedOid) -> String {
+    format!("{PREFIX}{}", original.to_hex())
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ReplaceError {
+    /// {0:?} can't replace itself
+    Itself(UntypedOid),
+    /// {0:?} is already replaced
+    Exists(UntypedOid),
+    /// {0:?} isn't replaced
+    NotReplaced(UntypedOid),
+    /// {0:?} not found in database
+    Missing(UntypedOid),
+    /// {0:?} isn't a commit
+    NotCommit(UntypedOid),
+    /// Expected replacement {replacement:?} to have type {expected}, got {actual}
+    WrongType {
+        replacement: UntypedOid,
+        expected: String,
+        actual: String,
+    },
+    /// Replace ref {0} isn't named by an oid
+    InvalidRef(bstr::BString),
+    /// Invalid config
+    Config(#[from] config::ValueError),
+    /// Failed to load object
+    Load(#[from] LoadRawError),
+    /// Failed to store grafted commit
+    Store(#[from] StoreRawError),
+    /// Failed to read replace refs
+    ReadRef(#[from] refs::ReadError),
+    /// Failed to update replace ref
+    UpdateRef(#[from] refs::TransactionError),
+}
@@ -1,119 +1,424 @@
 use std::{
-    collections::BTreeMap,
-    env, fmt, fs,
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    env,
+    ffi::OsStr,
+    fmt, fs,
     io::{self},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
+use std::sync::Arc;
+#[cfg(feature = "watch")]
+use std::sync::Mutex;
+
+use bstr::{BStr, BString, ByteSlice};
+
 use crate::core::{
-    db::{self, object, tree, Blob, Commit, Tree},
+    cancel::{CancelToken, Cancelled},
+    commit_msg::{self, Cleanup},
+    config::{self, Config},
+    db::{self, object, signature, tree, Blob, Commit, Tree, UntypedOid},
+    discover::OpenOptions,
+    fetch,
+    hook::{self, Hooks},
     index::{
         self,
         entry::{self, Entry, StatusChatty},
     },
-    refs,
-    ws::{self, ListFilesError, ReadFileError, StatFileError},
-    Db, FileStatus, Index, IndexMut, ObjectBuilder, Refs, Status, Workspace, WsPath,
+    init,
+    locked_file::LockOptions,
+    merge,
+    migration::{self, Migration},
+    pack,
+    pathspec::{self, Pathspec, Pathspecs},
+    refs, replace, rerere, safe_directory,
+    sparse::{self, Cone},
+    stat::Mode,
+    ws::{
+        self, attributes, Attributes, IgnoreRules, ListFilesError, ReadForGitError, StatFileError,
+        Walked, WriteFileError, WriteFromGitError,
+    },
+    Db, FileStatus, Fsync, Index, IndexMut, ObjectBuilder, Oid, Refs, Stat, Status, StatusOptions,
+    Workspace, WsPath,
 };
+
+#[cfg(feature = "watch")]
+use crate::core::watch::{self, Changes, Stamp, Watch};
 use chrono::Local;
-use tracing::{debug, instrument};
+use rayon::prelude::*;
+use tracing::{debug, instrument, warn};
 
+/// Repos are `Send + Sync`. To work on one from several threads at once,
+/// give each thread a clone, which is cheap: packs are shared, and the object
+/// cache of each clone starts empty.
 #[derive(Debug, Clone)]
 pub struct Repo {
     git_dir: PathBuf,
-    pub workspace: Workspace,
+    /// See [`Self::common_dir`]
+    common_dir: PathBuf,
+    /// `None` if the repository is bare
+    pub workspace: Option<Workspace>,
     pub db: Db,
     pub refs: Refs,
     pub index: Index,
+    pub config: Config,
+    /// Registered with [`Self::register_hooks`]
+    pub(crate) hooks: Vec<Arc<dyn Hooks>>,
+    /// Started by the first call to [`Self::status_cached`]
+    #[cfg(feature = "watch")]
+    watch: Option<Arc<Mutex<Watch>>>,
 }
 
+// Fails to compile if a field stops being thread-safe
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Repo>();
+};
+
 impl Repo {
+    /// Opens either a workspace containing a `.git` directory (or a `.git`
+    /// file pointing at one, as submodules and linked worktrees have), or a
+    /// git directory itself. The workspace can be moved elsewhere with
+    /// `core.worktree`, and there's no workspace if `core.bare` is set or a
+    /// git directory was opened without `core.worktree`.
     #[instrument(err)]
-    pub fn new(workspace: impl Into<PathBuf> + fmt::Debug) -> Result<Self, ReadError> {
-        let workspace_dir = workspace.into();
-        let workspace_dir = workspace_dir
-            .canonicalize()
-            .map_err(|e| ReadError::Io(workspace_dir, e))?;
+    pub fn new(dir: impl Into<PathBuf> + fmt::Debug) -> Result<Self, ReadError> {
+        let (git_dir, default_workspace) = Self::find_git_dir(dir.into())?;
+        Self::open_git_dir(git_dir, default_workspace, &OpenOptions::default())
+    }
 
-        let git_dir = workspace_dir.join(".git");
+    /// The git directory of `dir` and the workspace it has unless
+    /// configured otherwise, as described in [`Self::new`]
+    pub(crate) fn find_git_dir(dir: PathBuf) -> Result<(PathBuf, Option<PathBuf>), ReadError> {
+        let dir = dir.canonicalize().map_err(|e| ReadError::Io(dir, e))?;
 
-        if !git_dir
+        let dot_git = dir.join(".git");
+        let (git_dir, default_workspace) = if dot_git.is_file() {
+            (Self::read_git_file(&dot_git)?, Some(dir))
+        } else if dot_git
             .try_exists()
-            .map_err(|e| ReadError::Io(git_dir.clone(), e))?
+            .map_err(|e| ReadError::Io(dot_git.clone(), e))?
         {
-            return Err(ReadError::NotRepo(workspace_dir));
-        }
+            (dot_git, Some(dir))
+        } else if Self::is_git_dir(&dir) {
+            (dir, None)
+        } else {
+            return Err(ReadError::NotRepo(dir));
+        };
+        Ok((git_dir, default_workspace))
+    }
+
+    /// Only the locations in `options` are used, the git directory is
+    /// already known
+    pub(crate) fn open_git_dir(
+        git_dir: PathBuf,
+        default_workspace: Option<PathBuf>,
+        options: &OpenOptions,
+    ) -> Result<Self, ReadError> {
+        safe_directory::check(default_workspace.as_ref().unwrap_or(&git_dir), &git_dir)?;
+        let common_dir = Self::read_common_dir(&git_dir)?;
+        let linked = common_dir != git_dir;
+        let mut refs = Refs::with_common_dir(&git_dir, &common_dir);
+        let conditions = config::Conditions {
+            git_dir: Some(git_dir.clone()),
+            branch: refs
+                .current_branch()?
+                .map(|branch| branch.to_str_lossy().into_owned()),
+        };
+        let config = Config::load_with(common_dir.join("config"), &conditions)?;
+
+        let workspace_dir = if let Some(work_tree) = &options.work_tree {
+            let work_tree = work_tree
+                .canonicalize()
+                .map_err(|e| ReadError::Io(work_tree.clone(), e))?;
+            Some(work_tree)
+        } else if linked {
+            // The shared config is the main worktree's
+            default_workspace
+        } else if config.get_bool("core.bare")?.unwrap_or(false) {
+            None
+        } else if let Some(worktree) = config.get_path("core.worktree")? {
+            let worktree = git_dir.join(worktree);
+            let worktree = worktree
+                .canonicalize()
+                .map_err(|e| ReadError::Io(worktree, e))?;
+            Some(worktree)
+        } else {
+            default_workspace
+        };
+        let workspace = match workspace_dir {
+            Some(dir) => {
+                let mut workspace = Workspace::new(dir);
+                Self::configure_workspace(&mut workspace, &config)?;
+                Some(workspace)
+            }
+            None => None,
+        };
 
-        let workspace = Workspace::new(workspace_dir);
-        let db = Db::new(&git_dir);
-        let refs = Refs::new(&git_dir);
-        let index = Index::load(&git_dir)?;
+        let mut db = match &options.objects_dir {
+            Some(dir) => Db::with_objects_dir(dir, &common_dir),
+            None => Db::new(&common_dir),
+        };
+        db.load_shallow()?;
+        db.load_packs()?;
+        db.set_replacements(replace::load(&refs, &config)?);
+        let mut index = match &options.index_file {
+            Some(path) => Index::load_file(path)?,
+            None => Index::load(&git_dir)?,
+        };
+        Self::configure_fsync(&mut db, &mut refs, &mut index, &config)?;
+        Self::configure_locks(&mut refs, &config)?;
 
         Ok(Self {
             git_dir,
+            common_dir,
             workspace,
             db,
             refs,
             index,
+            config,
+            hooks: Vec::new(),
+            #[cfg(feature = "watch")]
+            watch: None,
         })
     }
 
+    /// Where a `.git` file points, like `gitdir: ../.git/modules/lib` in a
+    /// submodule, relative to the directory it's in
+    pub(crate) fn read_git_file(path: &Path) -> Result<PathBuf, ReadError> {
+        let contents = fs::read_to_string(path).map_err(|e| ReadError::Io(path.to_owned(), e))?;
+        let target = contents
+            .strip_prefix("gitdir: ")
+            .map(str::trim_end)
+            .ok_or_else(|| ReadError::GitFile(path.to_owned()))?;
+        let git_dir = path.parent().expect("Has a file name").join(target);
+        git_dir
+            .canonicalize()
+            .map_err(|e| ReadError::Io(git_dir, e))
+    }
+
+    /// Where a linked worktree's `commondir` file points, relative to its
+    /// git directory, or the git directory itself
+    fn read_common_dir(git_dir: &Path) -> Result<PathBuf, ReadError> {
+        let path = git_dir.join("commondir");
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(git_dir.to_owned()),
+            Err(err) => return Err(ReadError::Io(path, err)),
+        };
+        let common_dir = git_dir.join(contents.trim_end());
+        common_dir
+            .canonicalize()
+            .map_err(|e| ReadError::Io(common_dir, e))
+    }
+
+    pub(crate) fn is_git_dir(dir: &Path) -> bool {
+        dir.join("objects").is_dir() && dir.join("refs").is_dir()
+    }
+
+    /// The `.git` directory, or the repository itself if it's bare
+    pub fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    /// Where objects, refs under `refs/`, config and hooks are, which
+    /// linked worktrees share with the main one. Otherwise the same as
+    /// [`Self::git_dir`].
+    pub fn common_dir(&self) -> &Path {
+        &self.common_dir
+    }
+
+    pub fn is_bare(&self) -> bool {
+        self.workspace.is_none()
+    }
+
+    /// Takes the fields rather than `self`, so that others can be borrowed
+    /// mutably alongside
+    pub(crate) fn workspace_of<'a>(
+        workspace: Option<&'a Workspace>,
+        git_dir: &Path,
+    ) -> Result<&'a Workspace, BareError> {
+        workspace.ok_or_else(|| BareError(git_dir.to_owned()))
+    }
+
+    fn configure_workspace(
+        workspace: &mut Workspace,
+        config: &Config,
+    ) -> Result<(), config::ValueError> {
+        workspace.set_file_mode(config.get_bool("core.filemode")?.unwrap_or(true));
+        workspace.set_symlinks(config.get_bool("core.symlinks")?.unwrap_or(true));
+        workspace
+            .set_precompose_unicode(config.get_bool("core.precomposeunicode")?.unwrap_or(false));
+        workspace.set_eol(ws::eol::Settings::from_config(config)?);
+        workspace.set_filters(ws::filter::Filters::from_config(config));
+        Ok(())
+    }
+
+    /// See [`Fsync::from_config`]
+    fn configure_fsync(
+        db: &mut Db,
+        refs: &mut Refs,
+        index: &mut Index,
+        config: &Config,
+    ) -> Result<(), config::ValueError> {
+        let fsync = Fsync::from_config(config)?;
+        db.set_fsync(fsync);
+        refs.set_fsync(fsync.refs);
+        index.set_fsync(fsync.index);
+        Ok(())
+    }
+
+    /// Like git, locks on refs are retried for `core.filesRefLockTimeout`
+    /// milliseconds, 100 by default or forever if it's negative
+    fn configure_locks(refs: &mut Refs, config: &Config) -> Result<(), config::ValueError> {
+        let timeout = config.get_int("core.filesRefLockTimeout")?.unwrap_or(100);
+        let timeout = u64::try_from(timeout).map_or(Duration::MAX, Duration::from_millis);
+        refs.set_lock_options(LockOptions {
+            timeout,
+            ..LockOptions::default()
+        });
+        Ok(())
+    }
+
+    /// How hard the index and refs try for locks someone else holds, instead
+    /// of what's configured. Stale locks are only broken if the options say
+    /// to.
+    pub fn set_lock_options(&mut self, options: LockOptions) {
+        self.refs.set_lock_options(options);
+        self.index.set_lock_options(options);
+    }
+
+    /// Write [`Self::config`] back to `.git/config`, after editing it
+    pub fn save_config(&self) -> Result<(), config::SaveError> {
+        self.config.save(self.common_dir.join("config"))
+    }
+
     pub fn for_current_dir() -> Result<Self, ForCurrentDirError> {
         let dir = env::current_dir()?;
         Ok(Self::new(dir)?)
     }
 
-    #[instrument(err)]
+    /// A workspace with the git directory in its `.git`, see
+    /// [`init::Builder`] for other layouts
     pub fn init(workspace: impl Into<PathBuf> + fmt::Debug) -> Result<Self, InitError> {
-        let workspace_dir = workspace.into();
-
-        fs::create_dir_all(&workspace_dir)
-            .map_err(|e| InitError::CreateWorkspace(workspace_dir.clone(), e))?;
+        init::Builder::new(workspace).init()
+    }
 
-        let git_dir = workspace_dir.join(".git");
-        if git_dir
-            .try_exists()
-            .map_err(|e| InitError::Open(git_dir.clone(), e))?
-        {
-            return Err(InitError::Exists(git_dir));
-        }
+    /// A repository without a workspace, with `core.bare` set
+    pub fn init_bare(git_dir: impl Into<PathBuf> + fmt::Debug) -> Result<Self, InitError> {
+        init::Builder::new(git_dir).bare(true).init()
+    }
 
+    pub(crate) fn init_git_dir(git_dir: &Path) -> Result<(), InitError> {
         for child in &["objects", "refs"] {
             let child = git_dir.join(child);
             fs::create_dir_all(&child).map_err(|e| InitError::Write(child, e))?;
         }
+        Ok(())
+    }
 
-        let workspace = Workspace::new(workspace_dir);
-        let db = Db::new(&git_dir);
-        let refs = Refs::new(&git_dir);
-        let index = Index::load(&git_dir)?;
+    pub(crate) fn init_with(
+        git_dir: PathBuf,
+        workspace: Option<Workspace>,
+        config: Config,
+    ) -> Result<Self, InitError> {
+        let mut db = Db::new(&git_dir);
+        let mut refs = Refs::new(&git_dir);
+        let mut index = Index::load(&git_dir)?;
+        Self::configure_fsync(&mut db, &mut refs, &mut index, &config)?;
+        Self::configure_locks(&mut refs, &config)?;
 
         Ok(Self {
+            common_dir: git_dir.clone(),
             git_dir,
             workspace,
             db,
             refs,
             index,
+            config,
+            hooks: Vec::new(),
+            #[cfg(feature = "watch")]
+            watch: None,
         })
     }
 
+    /// Adds the files matching the pathspecs (relative to the workspace
+    /// root). See [`Self::add_matching`].
     #[instrument(err)]
-    pub fn add<I, P>(&mut self, files: I) -> Result<Vec<WsPath>, AddError>
+    pub fn add<I, S>(&mut self, pathspecs: I) -> Result<Vec<WsPath>, AddError>
     where
-        I: IntoIterator<Item = P> + fmt::Debug,
-        P: AsRef<Path>,
+        I: IntoIterator<Item = S> + fmt::Debug,
+        S: AsRef<str>,
     {
-        let workspace = &self.workspace;
+        let pathspecs = Pathspecs::parse(pathspecs)?;
+        self.add_matching(&pathspecs)
+    }
+
+    /// A pathspec without wildcards must name a file or directory that
+    /// exists, and every pathspec that isn't excluded must match a file,
+    /// unless it's within `.git`.
+    /// Ignored files are skipped unless a pathspec names them (or a
+    /// directory they're in) exactly.
+    /// Nothing is added if there are no pathspecs.
+    #[instrument(err)]
+    pub fn add_matching(&mut self, pathspecs: &Pathspecs) -> Result<Vec<WsPath>, AddError> {
+        let workspace = Self::workspace_of(self.workspace.as_ref(), &self.git_dir)?;
+        if pathspecs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let named = pathspecs
+            .included()
+            .filter(|spec| spec.names_path())
+            .map(Pathspec::prefix)
+            .collect::<BTreeSet<_>>();
+        for path in &named {
+            workspace.check_exists(path)?;
+        }
+
+        let mut ignores = IgnoreRules::new(&self.common_dir)?;
+        let mut files = BTreeSet::new();
+        for walked in workspace.walk(&pathspecs.prefixes(), &mut ignores) {
+            match walked? {
+                Walked::File(path) if Self::is_nested_repo(workspace, &path) => {
+                    debug!("Not adding nested repository {path}");
+                }
+                Walked::File(path) => {
+                    files.insert(path);
+                }
+                Walked::Ignored(path) if named.contains(&path) => {
+                    files.extend(workspace.find_files([path.as_path()])?);
+                }
+                Walked::Ignored(path) => debug!("Not adding {path}, as it's ignored"),
+            }
+        }
+        files.retain(|file| pathspecs.matches(file));
+        // Like git, pathspecs within `.git` quietly match nothing
+        let git_dir = WsPath::new_unchecked(".git");
+        if let Some(spec) = pathspecs.included().find(|spec| {
+            !spec.prefix().is_within(&git_dir) && !files.iter().any(|file| spec.matches(file))
+        }) {
+            return Err(AddError::NoMatch(spec.to_string()));
+        }
+
         let db = &self.db;
         self.index.reload()?;
         let mut index = self.index.modify()?;
 
+        let mut attrs = Attributes::new(&self.common_dir)?;
+
         let mut added = Vec::new();
-        for file in workspace.find_files(files)? {
-            let data = workspace.read_file(&file)?;
-            let stat = workspace.stat(&file)?;
+        for file in files {
+            attrs.load_parents(workspace, &file)?;
+            let data = workspace.read_for_git(&file, &attrs)?;
+            let stat = match index.entry(&file) {
+                Some(entry) => workspace.stat_tracked(&file, entry.mode())?,
+                None => workspace.stat(&file)?,
+            };
 
-            let oid = db::blob::Builder::new(data).store(&db)?;
+            let oid = db::blob::Builder::new(data).store(db)?;
             let entry = Entry::new(file.clone(), oid, stat);
 
             debug!("Adding {:?}", entry);
@@ -126,6 +431,14 @@ impl Repo {
         Ok(added)
     }
 
+    /// The walk lists directories that contain a `.git`, which we can't add
+    /// as gitlinks
+    fn is_nested_repo(workspace: &Workspace, path: &WsPath) -> bool {
+        path.to_absolute(workspace)
+            .is_ok_and(|abs_path| abs_path.is_dir())
+    }
+
+    /// Commit as the given name and email, for both author and committer
     #[instrument(err)]
     pub fn commit(
         &mut self,
@@ -133,16 +446,120 @@ impl Repo {
         email: impl Into<String> + fmt::Debug,
         msg: impl Into<String> + fmt::Debug,
     ) -> Result<(), CommitError> {
+        let signature = db::Signature::new_local(name.into(), email.into(), Local::now());
+        self.commit_as(signature.clone(), signature, msg)
+    }
+
+    /// As the author and committer from the environment or config, like
+    /// `git commit`. See [`Self::signature`] and [`Self::commit_as_with`].
+    #[instrument(err)]
+    pub fn commit_with(
+        &mut self,
+        msg: impl Into<String> + fmt::Debug,
+        options: &CommitOptions,
+    ) -> Result<(), CommitError> {
+        let author = self.signature(signature::Role::Author)?;
+        let committer = self.signature(signature::Role::Committer)?;
+        self.commit_as_with(author, committer, msg, options)
+    }
+
+    /// Who to commit as, from the environment or config. See
+    /// [`db::Signature::resolve`].
+    pub fn signature(
+        &self,
+        role: signature::Role,
+    ) -> Result<db::Signature, signature::IdentityError> {
+        db::Signature::resolve(role, &self.config)
+    }
+
+    pub fn commit_as(
+        &mut self,
+        author: db::Signature,
+        committer: db::Signature,
+        msg: impl Into<String> + fmt::Debug,
+    ) -> Result<(), CommitError> {
+        self.commit_as_with(author, committer, msg, &CommitOptions::default())
+    }
+
+    /// Unless `options.no_verify`, the `pre-commit` hook is run first, then
+    /// the `commit-msg` hook with the message in `.git/COMMIT_EDITMSG`, which
+    /// it may change. The commit is aborted if either fails. The message is
+    /// edited between the two if `options.edit`, and then cleaned up, see
+    /// [`CommitOptions::cleanup`]. If a merge is in progress, it's concluded
+    /// by committing `MERGE_HEAD` as a parent, and a cherry-pick or revert
+    /// that stopped for conflicts is concluded too. See
+    /// [`CommitOptions::amend`] for replacing HEAD instead.
+    #[instrument(err)]
+    pub fn commit_as_with(
+        &mut self,
+        author: db::Signature,
+        committer: db::Signature,
+        msg: impl Into<String> + fmt::Debug,
+        options: &CommitOptions,
+    ) -> Result<(), CommitError> {
+        let amended = if options.amend {
+            let head = self.refs.head()?.ok_or(CommitError::NothingToAmend)?;
+            if self.refs.read_ref(Refs::MERGE_HEAD.as_bstr())?.is_some() {
+                return Err(CommitError::AmendMerge);
+            }
+            Some(self.db.load(head)?)
+        } else {
+            None
+        };
         let mut msg = msg.into();
-        if msg.is_empty() {
+        if let Some(amended) = amended.as_ref().filter(|_| msg.is_empty()) {
+            msg = String::from_utf8_lossy(&amended.msg).into_owned();
+        }
+        // Editing can start from nothing
+        if msg.is_empty() && !options.edit {
             return Err(CommitError::EmptyMessage);
         }
-        if !msg.ends_with('\n') {
+        if !msg.is_empty() && !msg.ends_with('\n') {
             msg.push('\n');
         }
+        let cleanup = match options.cleanup {
+            Some(cleanup) => cleanup,
+            None => Cleanup::from_config(&self.config)?.unwrap_or(if options.edit {
+                Cleanup::Strip
+            } else {
+                Cleanup::Whitespace
+            }),
+        };
 
-        let name = name.into();
-        let email = email.into();
+        if self.index.has_conflicts() {
+            return Err(CommitError::Unmerged);
+        }
+        if self.workspace.is_some() {
+            // Records how conflicts were resolved
+            self.rerere()?;
+        }
+        // It may have staged changes
+        if !options.no_verify && self.run_hook("pre-commit", &[])? {
+            self.index.reload()?;
+        }
+        if options.edit {
+            msg = self.edit_message_file(&msg, cleanup)?;
+        }
+        if !options.no_verify {
+            let msg_file = self.git_dir.join("COMMIT_EDITMSG");
+            fs::write(&msg_file, &msg).map_err(CommitError::MessageFile)?;
+            if self.run_hook("commit-msg", &[msg_file.as_os_str()])? {
+                msg = fs::read_to_string(&msg_file).map_err(CommitError::MessageFile)?;
+                if msg.trim().is_empty() {
+                    return Err(CommitError::EmptyMessage);
+                }
+                if !msg.ends_with('\n') {
+                    msg.push('\n');
+                }
+            }
+        }
+        let msg = cleanup.apply(&msg, commit_msg::comment_char(&self.config));
+        if msg.trim().is_empty() {
+            return Err(CommitError::EmptyMessage);
+        }
+        for hooks in self.hooks.clone() {
+            hooks.pre_commit(self)?;
+        }
 
         let db = &self.db;
         let refs = &self.refs;
@@ -150,27 +567,343 @@ impl Repo {
 
         let entries = index.entries().map(|entry| db::tree::EntryBuilder {
             oid: entry.oid,
-            path: entry.path.clone(),
+            path: entry.to_path(),
             mode: entry.mode(),
         });
 
-        let root = db::tree::Builder::new().entries(entries).store(&db)?;
+        let root = db::tree::Builder::new().entries(entries).store(db)?;
 
-        let parent = refs.head()?;
-        let author = db::Author::new_local(name, email, Local::now());
-        let commit = db::commit::Builder::new(parent, root, author, msg).store(db)?;
+        // What's amended keeps its parents, and its author unless that's
+        // reset
+        let (parent, merged, author) = match amended {
+            Some(amended) if options.reset_author => (amended.parent, amended.merged, author),
+            Some(amended) => (amended.parent, amended.merged, amended.author),
+            None => {
+                let merged = refs.read_ref(Refs::MERGE_HEAD.as_bstr())?;
+                (refs.head()?, merged.into_iter().collect(), author)
+            }
+        };
+        let commit = db::commit::Builder {
+            merged,
+            ..db::commit::Builder::new(parent, root, author, committer, msg)
+        }
+        .store(db)?;
         refs.update_head(&commit)?;
+        // Whatever was in progress is concluded
+        for pseudo in [Refs::MERGE_HEAD, Refs::CHERRY_PICK_HEAD, Refs::REVERT_HEAD] {
+            refs.delete_ref(pseudo.as_bstr())?;
+        }
+        match fs::remove_file(self.git_dir.join(merge::commits::MERGE_MSG)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(CommitError::MergeMessage(err))
+            }
+            _ => {}
+        }
+
+        for hooks in &self.hooks {
+            hooks.post_commit(self, commit);
+        }
+        Ok(())
+    }
+
+    /// Check out only the given directories (and the files directly within
+    /// their parents and the root), recording them as cone mode patterns.
+    /// Files outside the cone are removed and marked skip-worktree in the
+    /// index, unless they've been modified. Files brought back into the cone
+    /// are checked out.
+    #[instrument(err)]
+    pub fn sparse_checkout_set<I, P>(&mut self, dirs: I) -> Result<Cone, SparseCheckoutError>
+    where
+        I: IntoIterator<Item = P> + fmt::Debug,
+        P: AsRef<Path>,
+    {
+        let dirs = dirs
+            .into_iter()
+            .map(WsPath::new_normalized)
+            .collect::<Result<Vec<_>, _>>()?;
+        let cone = Cone::new(dirs);
+        cone.save(&self.git_dir)?;
+
+        self.index.reload()?;
+        self.fetch_missing_blobs(
+            self.index
+                .entries()
+                .filter(|entry| entry.skip_worktree() && cone.contains(&entry.to_path()))
+                .filter(|entry| entry.mode() != Mode::Gitlink)
+                .map(|entry| entry.oid),
+        )?;
+
+        let work = Self::workspace_of(self.workspace.as_ref(), &self.git_dir)?;
+        let db = &mut self.db;
+        let mut index = self.index.modify()?;
+        let mut attrs = Attributes::new(&self.common_dir)?;
+
+        let entries = index
+            .entries()
+            .map(|entry| entry.to_entry())
+            .collect::<Vec<_>>();
+        for entry in entries {
+            let path = &entry.path;
+            match (cone.contains(path), entry.skip_worktree()) {
+                (true, true) => {
+                    debug!("Checking out {path}, now in sparse checkout");
+                    attrs.load_parents(work, path)?;
+                    let blob = db.load(entry.oid)?;
+                    work.write_from_git(path, entry.mode(), &blob.bytes, &attrs)?;
+                    let stat = work.stat_tracked(path, entry.mode())?;
+                    index.update_stat(path, stat).expect("Entry exists");
+                    index.set_skip_worktree(path, false).expect("Entry exists");
+                }
+                (false, false) => {
+                    attrs.load_parents(work, path)?;
+                    match entry.as_entry_ref().index_status_chatty(work, &attrs)? {
+                        StatusChatty::Unmodified
+                        | StatusChatty::UnmodifiedButNewStat(_)
+                        | StatusChatty::Deleted => {}
+                        StatusChatty::Modified | StatusChatty::TypeChanged => {
+                            warn!("Not removing {path}, outside sparse checkout, as it's modified");
+                            continue;
+                        }
+                    }
+                    debug!("Removing {path}, outside sparse checkout");
+                    work.remove_entry(path)?;
+                    index.set_skip_worktree(path, true).expect("Entry exists");
+                }
+                _ => {}
+            }
+        }
+
+        index.commit()?;
+        Ok(cone)
+    }
+
+    /// Switch HEAD, the index and the workspace to the given commit. Nothing is
+    /// changed if a file that would be written or deleted has changes that
+    /// would be lost, see [`migration::Clobbered`]. Then the `post-checkout`
+    /// hook is run, which can fail but not undo the checkout.
+    pub fn checkout(&mut self, target: Oid<Commit>) -> Result<(), CheckoutError> {
+        self.checkout_with(target, &CheckoutOptions::default())
+    }
+
+    /// Cancelling stops the checkout before the workspace is changed, so
+    /// nothing is left half done. Once files are being written it runs to
+    /// the end.
+    #[instrument(err)]
+    pub fn checkout_with(
+        &mut self,
+        target: Oid<Commit>,
+        options: &CheckoutOptions,
+    ) -> Result<(), CheckoutError> {
+        let head = self.migrate_to(target, options)?;
+        self.refs.detach_head(&target)?;
+        self.run_post_checkout(head, target, false)
+    }
+
+    /// Switch to a branch, given by its short name like `main`. As for
+    /// [`Self::checkout`], except HEAD is pointed at the branch.
+    pub fn checkout_branch(&mut self, branch: &BStr) -> Result<(), CheckoutError> {
+        self.checkout_branch_with(branch, &CheckoutOptions::default())
+    }
+
+    #[instrument(err)]
+    pub fn checkout_branch_with(
+        &mut self,
+        branch: &BStr,
+        options: &CheckoutOptions,
+    ) -> Result<(), CheckoutError> {
+        let name = BString::from([b"refs/heads/".as_ref(), branch.as_bytes()].concat());
+        let target = self
+            .refs
+            .read_ref(name.as_bstr())?
+            .ok_or_else(|| CheckoutError::NoBranch(branch.to_owned()))?;
+        let head = self.migrate_to(target, options)?;
+        self.refs.switch_head(name.as_bstr())?;
+        self.run_post_checkout(head, target, true)
+    }
 
+    /// The hook is told where HEAD was and is, and whether a branch was
+    /// switched to
+    fn run_post_checkout(
+        &self,
+        old: Option<Oid<Commit>>,
+        new: Oid<Commit>,
+        branch: bool,
+    ) -> Result<(), CheckoutError> {
+        let old = old.map_or_else(UntypedOid::zero, Oid::into_untyped);
+        let branch = if branch { "1" } else { "0" };
+        let args = [old.to_hex(), new.to_hex(), branch.to_owned()];
+        let args = args.iter().map(OsStr::new).collect::<Vec<_>>();
+        self.run_hook("post-checkout", &args)?;
         Ok(())
     }
 
+    /// Moves the index and workspace from HEAD's tree to the target's,
+    /// leaving refs to the caller. Returns where HEAD is.
+    pub(crate) fn migrate_to(
+        &mut self,
+        target: Oid<Commit>,
+        options: &CheckoutOptions,
+    ) -> Result<Option<Oid<Commit>>, CheckoutError> {
+        let head = self.refs.head()?;
+        let old = match head {
+            Some(head) => {
+                let tree = self.db.load(head)?.tree;
+                self.db.load_tree_files(&WsPath::root(), tree)?
+            }
+            None => BTreeMap::new(),
+        };
+        let tree = self.db.load(target)?.tree;
+        let new = self.db.load_tree_files(&WsPath::root(), tree)?;
+        let migration = Migration::new(&old, &new);
+        let cone = Cone::load(&self.git_dir)?;
+        options.cancel.check()?;
+        self.fetch_missing_blobs(migration.written_blobs(cone.as_ref()))?;
+        options.cancel.check()?;
+
+        let work = Self::workspace_of(self.workspace.as_ref(), &self.git_dir)?;
+        self.index.reload()?;
+        let mut index = self.index.modify()?;
+        if index.has_conflicts() {
+            return Err(CheckoutError::Unmerged);
+        }
+
+        let mut attrs = Attributes::new(&self.common_dir)?;
+        for path in migration.paths() {
+            attrs.load_parents(work, path)?;
+        }
+        let mut ignores = IgnoreRules::new(&self.common_dir)?;
+        migration.check(work, &index, &attrs, &mut ignores)?;
+
+        options.cancel.check()?;
+        migration.apply(work, &mut self.db, &mut index, &attrs, cone.as_ref())?;
+
+        index.commit()?;
+        Ok(head)
+    }
+
+    /// Fetches the blobs we don't have, as they were left out of a partial
+    /// clone
+    pub(crate) fn fetch_missing_blobs(
+        &self,
+        blobs: impl IntoIterator<Item = Oid<Blob>>,
+    ) -> Result<(), fetch::FetchError> {
+        let missing = blobs
+            .into_iter()
+            .map(Oid::into_untyped)
+            .filter(|oid| !self.db.contains(oid))
+            .collect::<BTreeSet<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        self.fetch_promised(&missing.into_iter().collect::<Vec<_>>())
+    }
+
     /// Unlike git, this lists files only. Children of untracked directories are
     /// reported instead of reporting the directory itself.
     #[instrument(err)]
     pub fn status(&mut self) -> Result<BTreeMap<WsPath, FileStatus>, StatusError> {
+        self.status_of(["."])
+    }
+
+    /// Whether the index matches HEAD and the workspace matches the index,
+    /// stopping at the first difference rather than finding them all like
+    /// [`Self::status`]. Untracked files don't count, as with `git diff
+    /// --quiet HEAD`, but unresolved conflicts do. The index isn't written,
+    /// so stat changes found along the way aren't recorded.
+    #[instrument(err)]
+    pub fn is_clean(&mut self) -> Result<bool, StatusError> {
+        self.index.reload()?;
+        if self.index.has_conflicts() {
+            return Ok(false);
+        }
+        let head = match self.refs.head()? {
+            Some(head) => {
+                let tree = self.db.load(head)?.tree;
+                self.db.load_tree_files(&WsPath::root(), tree)?
+            }
+            None => BTreeMap::new(),
+        };
+        let index_matches_head = head.len() == self.index.entries().count()
+            && self.index.entries().all(|entry| {
+                head.get(&entry.to_path())
+                    .is_some_and(|file| file.mode == entry.mode() && file.oid == entry.oid)
+            });
+        if !index_matches_head {
+            return Ok(false);
+        }
+
+        let work = Self::workspace_of(self.workspace.as_ref(), &self.git_dir)?;
+        let entries = self
+            .index
+            .entries()
+            .filter(|entry| !entry.skip_worktree())
+            .collect::<Vec<_>>();
+        let mut attrs = Attributes::new(&self.common_dir)?;
+        for entry in &entries {
+            attrs.load_parents(work, &entry.to_path())?;
+        }
+        let first_changed = entries
+            .into_par_iter()
+            .map(|entry| {
+                let status = entry.index_status_chatty(work, &attrs)?;
+                Ok(!matches!(
+                    status,
+                    StatusChatty::Unmodified | StatusChatty::UnmodifiedButNewStat(_)
+                ))
+            })
+            .find_any(|changed: &Result<bool, StatusError>| !matches!(changed, Ok(false)));
+        match first_changed {
+            Some(Err(err)) => Err(err),
+            Some(Ok(_)) => Ok(false),
+            None => Ok(true),
+        }
+    }
+
+    /// Like [`Self::status`], but only reports files matching the pathspecs
+    /// (relative to the workspace root). Only directories of the workspace
+    /// and head tree that can contain matches are traversed.
+    #[instrument(err)]
+    pub fn status_of<I, S>(
+        &mut self,
+        pathspecs: I,
+    ) -> Result<BTreeMap<WsPath, FileStatus>, StatusError>
+    where
+        I: IntoIterator<Item = S> + fmt::Debug,
+        S: AsRef<str>,
+    {
+        self.status_with(pathspecs, &StatusOptions::default())
+    }
+
+    #[instrument(err)]
+    pub fn status_with<I, S>(
+        &mut self,
+        pathspecs: I,
+        options: &StatusOptions,
+    ) -> Result<BTreeMap<WsPath, FileStatus>, StatusError>
+    where
+        I: IntoIterator<Item = S> + fmt::Debug,
+        S: AsRef<str>,
+    {
+        let pathspecs = Pathspecs::parse(pathspecs)?;
+        self.status_matching(&pathspecs, options)
+    }
+
+    #[instrument(err)]
+    #[allow(clippy::too_many_lines)]
+    pub fn status_matching(
+        &mut self,
+        pathspecs: &Pathspecs,
+        options: &StatusOptions,
+    ) -> Result<BTreeMap<WsPath, FileStatus>, StatusError> {
+        let prefixes = pathspecs.prefixes();
+        let in_pathspecs = |path: &WsPath| pathspecs.matches(path);
+
         let head = if let Some(head) = self.refs.head()? {
             let tree = self.db.load(head)?.tree;
-            self.db.load_tree_files(&WsPath::root(), tree)?
+            options.cancel.check()?;
+            let mut files = self.db.load_tree_files_under(tree, &prefixes)?;
+            files.retain(|path, _| in_pathspecs(path));
+            files
         } else {
             BTreeMap::new()
         };
@@ -181,24 +914,70 @@ impl Repo {
             .modify()
             .map_err(|e| StatusError::UpdateIndex(e.into()))?;
 
-        let work = &self.workspace;
+        let work = Self::workspace_of(self.workspace.as_ref(), &self.git_dir)?;
 
         let mut ws_statuses = BTreeMap::new();
         let mut index_statuses = BTreeMap::new();
 
-        for path in self.workspace.list_files()? {
-            let ws_status = Self::workspace_status_of(work, &mut index, &path)?;
-            let index_status = Self::index_status_of(&index, &head, &path)?;
+        let mut ignore_rules = IgnoreRules::new(&self.common_dir)?;
+        let mut listing = work.list_files_under(&prefixes, &mut ignore_rules)?;
+        listing.files.retain(|path| in_pathspecs(path));
+        listing.ignored.retain(|path| in_pathspecs(path));
+        options.cancel.check()?;
+
+        let mut attrs = Attributes::new(&self.common_dir)?;
+        for path in &listing.files {
+            attrs.load_parents(work, path)?;
+        }
+
+        // Stat-ing and hashing is the slow part, so we check files in
+        // parallel and only update the index once we're done
+        let checked = {
+            let index: &Index = &index;
+            let attrs = &attrs;
+            listing
+                .files
+                .into_par_iter()
+                .map(|path| {
+                    options.cancel.check()?;
+                    let (ws_status, new_stat) =
+                        Self::check_workspace_file(work, index, attrs, &path)?;
+                    let index_status = Self::index_status_of(index, &head, &path)?;
+                    Ok((path, ws_status, new_stat, index_status))
+                })
+                .collect::<Result<Vec<_>, StatusError>>()?
+        };
+        for (path, ws_status, new_stat, index_status) in checked {
             debug!("{path} in workspace, so ws: {ws_status:?}, idx: {index_status:?}");
+            if let Some(new_stat) = new_stat {
+                index.update_stat(&path, new_stat).expect("Entry exists");
+            }
             ws_statuses.insert(path.clone(), ws_status);
             index_statuses.insert(path, index_status);
         }
 
-        for entry in index.entries() {
-            if !ws_statuses.contains_key(&entry.path) {
-                debug!("{} in idx but not ws, so ws: Status::Deleted", entry.path);
-                ws_statuses.insert(entry.path.clone(), Status::Deleted);
+        let ignored = if options.ignored {
+            Self::untracked_ignored(work, &index, listing.ignored)?
+        } else {
+            Vec::new()
+        };
+
+        // Files we didn't see might be deleted or might be in an ignored dir
+        let unseen = index
+            .entries()
+            .map(|entry| entry.to_path())
+            .filter(|path| in_pathspecs(path) && !ws_statuses.contains_key(path))
+            .collect::<Vec<_>>();
+        for path in unseen {
+            options.cancel.check()?;
+            attrs.load_parents(work, &path)?;
+            let ws_status = Self::workspace_status_of(work, &mut index, &attrs, &path)?;
+            debug!("{path} in idx but not seen in ws, so ws: {ws_status:?}");
+            if ws_status != Status::Deleted {
+                let index_status = Self::index_status_of(&index, &head, &path)?;
+                index_statuses.insert(path.clone(), index_status);
             }
+            ws_statuses.insert(path, ws_status);
         }
 
         for (path, _file) in head {
@@ -208,6 +987,15 @@ impl Repo {
             }
         }
 
+        let conflicts = index
+            .conflicts()
+            .map(|(path, conflict)| {
+                let path = WsPath::new_unchecked_bytes(path);
+                (path, Self::conflict_status_of(conflict))
+            })
+            .filter(|(path, _)| in_pathspecs(path))
+            .collect::<Vec<_>>();
+
         index
             .commit()
             .map_err(|e| StatusError::UpdateIndex(e.into()))?;
@@ -237,28 +1025,164 @@ impl Repo {
             );
         }
 
+        for path in ignored {
+            statuses.insert(
+                path.clone(),
+                FileStatus {
+                    path,
+                    workspace: Status::Ignored,
+                    index: Status::Untracked,
+                },
+            );
+        }
+
+        for (path, status) in conflicts {
+            debug!("{path} is unmerged, so {status:?}");
+            statuses.insert(
+                path.clone(),
+                FileStatus {
+                    path,
+                    workspace: status,
+                    index: status,
+                },
+            );
+        }
+
         Ok(statuses)
     }
 
+    /// Like [`Self::status`], but the first call starts watching the
+    /// workspace and later calls only check the paths that changed since.
+    /// Changes are seen once the watcher is told about them, which is usually
+    /// very soon after they happen.
+    #[cfg(feature = "watch")]
+    #[instrument(err)]
+    pub fn status_cached(&mut self) -> Result<BTreeMap<WsPath, FileStatus>, StatusCachedError> {
+        let watch = if let Some(watch) = &self.watch {
+            Arc::clone(watch)
+        } else {
+            let work = Self::workspace_of(self.workspace.as_ref(), &self.git_dir)?;
+            let watch = Arc::new(Mutex::new(Watch::new(work)?));
+            self.watch = Some(Arc::clone(&watch));
+            watch
+        };
+        let mut watch = watch.lock().expect("Not poisoned");
+
+        let changes = watch.take_changes();
+        let cached = watch.cached(&self.status_stamp()?).cloned();
+        let statuses = match (cached, changes) {
+            (Some(cached), Changes::Paths(paths)) if paths.is_empty() => return Ok(cached),
+            (Some(mut statuses), Changes::Paths(paths)) => {
+                debug!("Checking changed paths {paths:?}");
+                statuses.retain(|path, _| !paths.iter().any(|changed| path.is_within(changed)));
+                let pathspecs = Pathspecs::literal(&paths);
+                statuses.extend(self.status_matching(&pathspecs, &StatusOptions::default())?);
+                statuses
+            }
+            _ => {
+                debug!("Checking everything");
+                self.status()?
+            }
+        };
+
+        // Taken again since checking may have updated the index
+        watch.set_cached(self.status_stamp()?, statuses.clone());
+        Ok(statuses)
+    }
+
+    #[cfg(feature = "watch")]
+    fn status_stamp(&self) -> Result<Stamp, StatusCachedError> {
+        let path = self.index.path();
+        let index = match fs::symlink_metadata(path) {
+            Ok(meta) => Some(Stat::from(&meta)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(StatusCachedError::StatIndex(path.to_owned(), err)),
+        };
+        Ok(Stamp {
+            head: self.refs.head()?,
+            index,
+        })
+    }
+
+    /// Tracked files in ignored directories are reported normally, so we
+    /// list the rest of the files in those directories individually.
+    fn untracked_ignored(
+        work: &Workspace,
+        index: &Index,
+        ignored: Vec<WsPath>,
+    ) -> Result<Vec<WsPath>, StatusError> {
+        let mut untracked = Vec::new();
+        for path in ignored {
+            if index
+                .entries()
+                .any(|entry| entry.to_path().is_within(&path))
+            {
+                let within = work.list_files_under(&[path], &mut IgnoreRules::none())?;
+                untracked.extend(
+                    within
+                        .files
+                        .into_iter()
+                        .filter(|path| !index.is_tracked_file(path)),
+                );
+            } else {
+                untracked.push(path);
+            }
+        }
+        Ok(untracked)
+    }
+
+    fn conflict_status_of(conflict: &index::Conflict) -> Status {
+        let oid = |entry: &Option<Entry>| entry.as_ref().map(|entry| entry.oid);
+        Status::Conflicted {
+            base: oid(&conflict.base),
+            ours: oid(&conflict.ours),
+            theirs: oid(&conflict.theirs),
+        }
+    }
+
     pub fn workspace_status_of(
         work: &Workspace,
         index: &mut IndexMut,
+        attrs: &Attributes,
         path: &WsPath,
     ) -> Result<Status, StatusError> {
-        let status = if let Some(entry) = index.entry(path) {
-            match entry.index_status_chatty(work)? {
-                StatusChatty::Unmodified => Status::Unmodified,
+        let (status, new_stat) = Self::check_workspace_file(work, index, attrs, path)?;
+        if let Some(new_stat) = new_stat {
+            index.update_stat(path, new_stat).expect("Entry exists");
+        }
+        Ok(status)
+    }
+
+    /// Like [`Self::workspace_status_of`], but returns the new stat to record
+    /// instead of updating the index, so it can be run in parallel.
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(level = "trace", skip(work, index, attrs))
+    )]
+    fn check_workspace_file(
+        work: &Workspace,
+        index: &Index,
+        attrs: &Attributes,
+        path: &WsPath,
+    ) -> Result<(Status, Option<Stat>), StatusError> {
+        let checked = if let Some(entry) = index.entry(path) {
+            if entry.skip_worktree() {
+                // Outside the sparse checkout, so whatever is there isn't ours
+                return Ok((Status::Unmodified, None));
+            }
+            match entry.index_status_chatty(work, attrs)? {
+                StatusChatty::Unmodified => (Status::Unmodified, None),
                 StatusChatty::UnmodifiedButNewStat(new_stat) => {
-                    index.update_stat(path, new_stat).expect("Entry exists");
-                    Status::Unmodified
+                    (Status::Unmodified, Some(new_stat))
                 }
-                StatusChatty::Modified => Status::Modified,
-                StatusChatty::Deleted => Status::Deleted,
+                StatusChatty::Modified => (Status::Modified, None),
+                StatusChatty::TypeChanged => (Status::TypeChanged, None),
+                StatusChatty::Deleted => (Status::Deleted, None),
             }
         } else {
-            Status::Untracked
+            (Status::Untracked, None)
         };
-        Ok(status)
+        Ok(checked)
     }
 
     #[allow(clippy::option_if_let_else)]
@@ -267,14 +1191,14 @@ impl Repo {
         head: &BTreeMap<WsPath, tree::FileNode>,
         path: &WsPath,
     ) -> Result<Status, StatusError> {
-        let index_entry = if let Some(index_entry) = index.entry(path) {
-            index_entry
-        } else {
+        let Some(index_entry) = index.entry(path) else {
             return Ok(Status::Untracked);
         };
 
         let status = if let Some(head_file) = head.get(path) {
-            if head_file.mode == index_entry.mode() && head_file.oid == index_entry.oid {
+            if !head_file.mode.is_same_type(index_entry.mode()) {
+                Status::TypeChanged
+            } else if head_file.mode == index_entry.mode() && head_file.oid == index_entry.oid {
                 Status::Unmodified
             } else {
                 Status::Modified
@@ -300,8 +1224,28 @@ pub enum InitError {
     Write(PathBuf, #[source] io::Error),
     /// Failed to open index
     OpenIndex(#[from] index::LoadError),
+    /// Failed to write config
+    SaveConfig(#[from] config::SaveError),
+    /// Failed to read config from template
+    LoadConfig(#[from] config::LoadError),
+    /// Invalid config
+    InvalidConfig(#[from] config::ValueError),
+    /// Failed to copy template {0:?}
+    Template(PathBuf, #[source] io::Error),
+    /// Failed to write HEAD
+    WriteHead(#[from] refs::UpdateError),
+    /// Invalid initial branch name {0:?}
+    InvalidBranch(String),
+    /// A bare repository can't have a separate git directory
+    BareSeparate,
+    /// Object format {0} isn't supported
+    UnsupportedObjectFormat(init::ObjectFormat),
 }
 
+/// {0:?} is a bare repository, which has no workspace
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub struct BareError(PathBuf);
+
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 /// Failed to read a directory as a git repository.
 pub enum ReadError {
@@ -309,8 +1253,26 @@ pub enum ReadError {
     NotRepo(PathBuf),
     /// IO error while checking if directory {0:?} is a git repository
     Io(PathBuf, #[source] io::Error),
+    /// Invalid .git file {0:?}, which should be like `gitdir: <path>`
+    GitFile(PathBuf),
+    /// {0:?} is owned by someone else, so its config and hooks can't be
+    /// trusted. To open it anyway, run `git config --global --add
+    /// safe.directory {0:?}`
+    Unsafe(PathBuf),
     /// Failed to open index
     OpenIndex(#[from] index::LoadError),
+    /// Failed to read the current branch
+    ReadHead(#[from] refs::ReadError),
+    /// Failed to load config
+    LoadConfig(#[from] config::LoadError),
+    /// Invalid config
+    InvalidConfig(#[from] config::ValueError),
+    /// Failed to read shallow commits
+    LoadShallow(#[from] db::ShallowError),
+    /// Failed to read packs
+    LoadPacks(#[from] pack::file::OpenPackError),
+    /// Failed to read replacements
+    LoadReplacements(#[from] replace::ReplaceError),
 }
 
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
@@ -324,26 +1286,79 @@ pub enum ForCurrentDirError {
 
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum AddError {
+    /// {0}
+    Bare(#[from] BareError),
+    /// {0}
+    Pathspec(#[from] pathspec::ParseError),
+    /// Pathspec {0:?} did not match any files
+    NoMatch(String),
     /// Failed to reload index
     ReloadIndex(#[from] index::LoadError),
     /// Failed to open index of modifications
     OpenIndex(#[from] index::OpenForModificationsError),
     /// Failed to find files provided in repository
     FindFiles(#[from] ws::ListFilesError),
+    /// Failed to load ignore rules
+    LoadIgnores(#[from] ws::ignore::LoadError),
     /// Failed to stat file
     Stat(#[from] StatFileError),
     /// Failed to read file
-    Read(#[from] ReadFileError),
+    Read(#[from] ReadForGitError),
+    /// Failed to load attributes
+    LoadAttributes(#[from] attributes::LoadError),
     /// Failed to store file
     StoreBlob(#[from] db::StoreError<Blob>),
     /// Failed to commit changes to index
     CommitIndex(#[from] index::CommitError),
 }
 
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[allow(clippy::struct_excessive_bools)] // Flags, like git commit's
+pub struct CommitOptions {
+    /// Skip the `pre-commit` and `commit-msg` hooks, like `git commit
+    /// --no-verify`
+    pub no_verify: bool,
+    /// Have the user edit the message first, like `git commit -e`, see
+    /// [`Repo::edit_commit_message`]
+    pub edit: bool,
+    /// How the message is cleaned up. If `None`, `commit.cleanup` decides,
+    /// and by default whitespace is cleaned up, as are comments if the
+    /// message was edited.
+    pub cleanup: Option<Cleanup>,
+    /// Replace HEAD instead of adding to it, like `git commit --amend`. The
+    /// new commit has HEAD's parents, and keeps HEAD's author rather than
+    /// the one given unless [`Self::reset_author`]. An empty message keeps
+    /// HEAD's too.
+    pub amend: bool,
+    /// Take the author given when amending, like `--reset-author`
+    pub reset_author: bool,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CheckoutOptions {
+    pub cancel: CancelToken,
+}
+
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum CommitError {
     /// Empty commit message
     EmptyMessage,
+    /// There's no commit to amend
+    NothingToAmend,
+    /// Cannot amend in the middle of a merge
+    AmendMerge,
+    /// Cannot commit with unmerged paths in the index
+    Unmerged,
+    /// {0}
+    Hook(#[from] hook::HookError),
+    /// {0}
+    Rejected(#[from] hook::Rejected),
+    /// Failed to pass commit message to hook
+    MessageFile(#[source] io::Error),
+    /// Failed to record conflict resolutions
+    Rerere(#[from] rerere::RerereError),
+    /// Failed to reload index
+    ReloadIndex(#[from] index::LoadError),
     /// Failed to load index
     LoadIndex(#[from] index::OpenForModificationsError),
     /// Failed to store tree
@@ -356,10 +1371,107 @@ pub enum CommitError {
     ParseParentOid(#[from] object::ParseOidError),
     /// Failed to update ref
     UpdateRef(#[from] refs::UpdateError),
+    /// Failed to remove `MERGE_MSG`
+    MergeMessage(#[source] io::Error),
+    /// Invalid config
+    Config(#[from] config::ValueError),
+    /// Failed to edit message
+    Editor(#[from] commit_msg::EditorError),
+    /// Failed to load the commit to amend
+    LoadCommit(#[from] db::LoadError<Commit>),
+    /// {0}
+    Identity(#[from] signature::IdentityError),
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum CheckoutError {
+    /// {0}
+    Bare(#[from] BareError),
+    /// {0}
+    Hook(#[from] hook::HookError),
+    /// Cannot checkout with unmerged paths in the index
+    Unmerged,
+    /// No branch named {0}
+    NoBranch(BString),
+    /// Failed to read ref
+    ReadRef(#[from] refs::ReadError),
+    /// Failed to load commit
+    LoadCommit(#[from] db::LoadError<Commit>),
+    /// Failed to load tree
+    LoadTree(#[from] db::LoadError<Tree>),
+    /// Failed to reload index
+    ReloadIndex(#[from] index::LoadError),
+    /// Failed to open index of modifications
+    OpenIndex(#[from] index::OpenForModificationsError),
+    /// Failed to load attributes
+    LoadAttributes(#[from] attributes::LoadError),
+    /// Failed to load ignore rules
+    LoadIgnores(#[from] ws::ignore::LoadError),
+    /// Failed to load sparse checkout patterns
+    LoadSparse(#[from] sparse::LoadError),
+    /// {0}
+    Check(#[from] migration::CheckError),
+    /// Failed to fetch missing blobs
+    FetchPromised(#[from] fetch::FetchError),
+    /// Failed to update workspace
+    Apply(#[from] migration::ApplyError),
+    /// Failed to commit changes to index
+    CommitIndex(#[from] index::CommitError),
+    /// Failed to update ref
+    UpdateRef(#[from] refs::UpdateError),
+    /// {0}
+    Cancelled(#[from] Cancelled),
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum SparseCheckoutError {
+    /// {0}
+    Bare(#[from] BareError),
+    /// Invalid directory
+    Pathspec(#[from] ws::path::NormalizeError),
+    /// Failed to save sparse checkout patterns
+    SavePatterns(#[from] sparse::SaveError),
+    /// Failed to reload index
+    ReloadIndex(#[from] index::LoadError),
+    /// Failed to open index of modifications
+    OpenIndex(#[from] index::OpenForModificationsError),
+    /// Failed to load attributes
+    LoadAttributes(#[from] attributes::LoadError),
+    /// Failed to check if file unchanged
+    IsUnchanged(#[from] entry::IsUnchangedError),
+    /// Failed to fetch missing blobs
+    FetchPromised(#[from] fetch::FetchError),
+    /// Failed to load blob
+    LoadBlob(#[from] db::LoadError<Blob>),
+    /// Failed to check out file
+    Checkout(#[from] WriteFromGitError),
+    /// Failed to stat file
+    Stat(#[from] StatFileError),
+    /// Failed to remove file
+    Remove(#[from] WriteFileError),
+    /// Failed to commit changes to index
+    CommitIndex(#[from] index::CommitError),
+}
+
+#[cfg(feature = "watch")]
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum StatusCachedError {
+    /// {0}
+    Bare(#[from] BareError),
+    /// Failed to watch workspace
+    Watch(#[from] watch::Error),
+    /// Failed to get head oid
+    GetHeadOid(#[from] refs::ReadError),
+    /// Failed to stat index at {0:?}
+    StatIndex(PathBuf, #[source] io::Error),
+    /// Failed to get status
+    Status(#[from] StatusError),
 }
 
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum StatusError {
+    /// {0}
+    Bare(#[from] BareError),
     /// Failed to reload index
     ReloadIndex(#[from] index::LoadError),
     /// Failed to get head oid
@@ -368,10 +1480,18 @@ pub enum StatusError {
     LoadHeadCommit(#[from] db::LoadError<db::Commit>),
     /// Failed to load of tree from head
     LoadHeadTree(#[from] db::LoadError<db::Tree>),
+    /// {0}
+    Pathspec(#[from] pathspec::ParseError),
+    /// Failed to load ignore rules
+    LoadIgnores(#[from] ws::ignore::LoadError),
+    /// Failed to load attributes
+    LoadAttributes(#[from] attributes::LoadError),
     /// Failed to list files
     ListFiles(#[from] ListFilesError),
     /// Failed to check if file unchanged
     IsUnchanged(#[from] entry::IsUnchangedError),
     /// Failed to update index with new stat
     UpdateIndex(#[from] index::ModifyError),
+    /// {0}
+    Cancelled(#[from] Cancelled),
 }
@@ -0,0 +1,441 @@
+//! Reusing recorded resolutions of conflicts, like `git rerere`. When a file
+//! first has conflicts its hunks are recorded in `.git/rr-cache/<id>` as the
+//! preimage, where the ID is the hash of the hunks, and once it's resolved the
+//! file is recorded as the postimage. When the same hunks conflict again
+//! they're resolved the way they were, even if what's around them changed.
+//! Files being resolved are listed in `.git/MERGE_RR` until they are.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bstr::ByteSlice;
+use ring::digest::{Context, SHA1_FOR_LEGACY_USE_ONLY as SHA1};
+use tracing::{debug, instrument};
+
+use crate::core::{config, index, repo::BareError, Repo, WsPath};
+
+/// Like git's default `conflict-marker-size`
+const MARKER_SIZE: usize = 7;
+
+/// What [`Repo::rerere`] did, by path
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Rerere {
+    /// Conflicts seen for the first time, whose resolutions will be recorded
+    pub recorded: Vec<WsPath>,
+    /// Conflicts resolved the way they were before
+    pub resolved: Vec<WsPath>,
+    /// Resolutions recorded
+    pub remembered: Vec<WsPath>,
+}
+
+impl Repo {
+    /// Whether resolutions are recorded and reused, which is `rerere.enabled`,
+    /// or else whether there's a `.git/rr-cache` like git
+    pub fn rerere_enabled(&self) -> Result<bool, RerereError> {
+        Ok(match self.config.get_bool("rerere.enabled")? {
+            Some(enabled) => enabled,
+            None => self.rr_cache().is_dir(),
+        })
+    }
+
+    /// Records the conflicts in the workspace, resolves those that have been
+    /// before and records how those that are now resolved were, like `git
+    /// rerere`. Call this after a merge has left conflicts, and again once
+    /// they're resolved (committing does). Resolved files aren't staged.
+    #[instrument(err)]
+    pub fn rerere(&mut self) -> Result<Rerere, RerereError> {
+        let mut done = Rerere::default();
+        if !self.rerere_enabled()? {
+            return Ok(done);
+        }
+        let work = Self::workspace_of(self.workspace.as_ref(), self.git_dir())?
+            .path()
+            .to_owned();
+        let cache = self.rr_cache();
+        let merge_rr_path = self.git_dir().join("MERGE_RR");
+        let mut merge_rr = read_merge_rr(&merge_rr_path)?;
+
+        self.index.reload()?;
+        let conflicted = self
+            .index
+            .conflicts()
+            .filter(|(_, conflict)| conflict.ours.is_some() && conflict.theirs.is_some())
+            .map(|(path, _)| WsPath::new_unchecked_bytes(path))
+            .collect::<Vec<_>>();
+        for path in conflicted {
+            if merge_rr.contains_key(&path) {
+                continue;
+            }
+            let file = work.join(&path);
+            let data = read(&file)?;
+            let Ok(current) = Conflicts::parse(&data) else {
+                debug!(%path, "Conflict markers are malformed");
+                continue;
+            };
+            if current.hunks() == 0 {
+                continue;
+            }
+            let id = current.id();
+            let dir = cache.join(&id);
+            let postimage = dir.join("postimage");
+            merge_rr.insert(path.clone(), id);
+            if postimage.exists() {
+                let preimage = read(&dir.join("preimage"))?;
+                let postimage = read(&postimage)?;
+                if let Some(resolved) = current.resolve(&preimage, &postimage) {
+                    debug!(%path, "Resolving as before");
+                    write(&file, &resolved)?;
+                    done.resolved.push(path);
+                    continue;
+                }
+            }
+            write(&dir.join("preimage"), &current.normalized())?;
+            done.recorded.push(path);
+        }
+
+        let mut pending = BTreeMap::new();
+        for (path, id) in merge_rr {
+            let data = read(&work.join(&path))?;
+            let unresolved = Conflicts::parse(&data).map_or(true, |c| c.hunks() > 0);
+            if unresolved {
+                pending.insert(path, id);
+                continue;
+            }
+            let postimage = cache.join(&id).join("postimage");
+            if !postimage.exists() {
+                write(&postimage, &data)?;
+                done.remembered.push(path);
+            }
+        }
+        write_merge_rr(&merge_rr_path, &pending)?;
+        Ok(done)
+    }
+
+    fn rr_cache(&self) -> PathBuf {
+        self.common_dir().join("rr-cache")
+    }
+}
+
+/// A file with conflict markers, split into what's between the hunks and the
+/// hunks
+#[derive(Debug)]
+struct Conflicts<'a> {
+    segments: Vec<Segment<'a>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Segment<'a> {
+    Context(&'a [u8]),
+    /// With the sides in order, so that which side is ours doesn't matter
+    Hunk(&'a [u8], &'a [u8]),
+}
+
+#[derive(Debug)]
+struct Malformed;
+
+impl<'a> Conflicts<'a> {
+    /// Any common ancestor in a hunk (after `|||||||`) is left out
+    fn parse(data: &'a [u8]) -> Result<Self, Malformed> {
+        #[derive(PartialEq)]
+        enum State {
+            Context,
+            Ours,
+            Base,
+            Theirs,
+        }
+        let mut segments = Vec::new();
+        let mut state = State::Context;
+        let (mut start, mut pos) = (0, 0);
+        let mut ours = 0..0;
+        for line in data.lines_with_terminator() {
+            let end = pos + line.len();
+            match state {
+                State::Context if is_marker(line, b'<') => {
+                    if start < pos {
+                        segments.push(Segment::Context(&data[start..pos]));
+                    }
+                    state = State::Ours;
+                    start = end;
+                }
+                State::Ours if is_marker(line, b'|') => {
+                    ours = start..pos;
+                    state = State::Base;
+                }
+                State::Ours if is_marker(line, b'=') => {
+                    ours = start..pos;
+                    state = State::Theirs;
+                    start = end;
+                }
+                State::Base if is_marker(line, b'=') => {
+                    state = State::Theirs;
+                    start = end;
+                }
+                State::Theirs if is_marker(line, b'>') => {
+                    let (ours, theirs) = (&data[ours.clone()], &data[start..pos]);
+                    segments.push(if ours <= theirs {
+                        Segment::Hunk(ours, theirs)
+                    } else {
+                        Segment::Hunk(theirs, ours)
+                    });
+                    state = State::Context;
+                    start = end;
+                }
+                State::Context => {}
+                _ if is_marker(line, b'<') => return Err(Malformed),
+                _ => {}
+            }
+            pos = end;
+        }
+        if state != State::Context {
+            return Err(Malformed);
+        }
+        if start < data.len() {
+            segments.push(Segment::Context(&data[start..]));
+        }
+        Ok(Self { segments })
+    }
+
+    fn hunks(&self) -> usize {
+        self.hunk_sides().count()
+    }
+
+    fn hunk_sides(&self) -> impl Iterator<Item = (&'a [u8], &'a [u8])> + '_ {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Hunk(one, two) => Some((*one, *two)),
+            Segment::Context(_) => None,
+        })
+    }
+
+    /// The hex of the hash of each side of each hunk, like git's
+    fn id(&self) -> String {
+        let mut hash = Context::new(&SHA1);
+        for (one, two) in self.hunk_sides() {
+            hash.update(one);
+            hash.update(b"\0");
+            hash.update(two);
+            hash.update(b"\0");
+        }
+        hex::encode(hash.finish())
+    }
+
+    /// With bare markers and the sides in order
+    fn normalized(&self) -> Vec<u8> {
+        let mut normalized = Vec::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Context(context) => normalized.extend_from_slice(context),
+                Segment::Hunk(one, two) => {
+                    normalized.extend_from_slice(b"<<<<<<<\n");
+                    normalized.extend_from_slice(one);
+                    normalized.extend_from_slice(b"=======\n");
+                    normalized.extend_from_slice(two);
+                    normalized.extend_from_slice(b">>>>>>>\n");
+                }
+            }
+        }
+        normalized
+    }
+
+    /// These conflicts resolved like `preimage` was resolved as `postimage`.
+    /// Each hunk is replaced with what replaced it in the postimage, which is
+    /// found by what was around it, so changes around the hunks are kept. If
+    /// that can't be found, only conflicts just like the preimage's are
+    /// resolved (as the postimage).
+    fn resolve(&self, preimage: &[u8], postimage: &[u8]) -> Option<Vec<u8>> {
+        let recorded = Conflicts::parse(preimage).ok()?;
+        match recorded.resolutions(postimage) {
+            Some(resolutions) if resolutions.len() == self.hunks() => {
+                let mut resolutions = resolutions.into_iter();
+                let mut resolved = Vec::new();
+                for segment in &self.segments {
+                    match segment {
+                        Segment::Context(context) => resolved.extend_from_slice(context),
+                        Segment::Hunk(..) => resolved.extend_from_slice(resolutions.next()?),
+                    }
+                }
+                Some(resolved)
+            }
+            _ => (self.normalized() == preimage).then(|| postimage.to_vec()),
+        }
+    }
+
+    /// What replaced each hunk in `postimage`, if what was between them is
+    /// still there (and isn't empty, which would be ambiguous)
+    fn resolutions<'p>(&self, postimage: &'p [u8]) -> Option<Vec<&'p [u8]>> {
+        let mut contexts = vec![&b""[..]];
+        for segment in &self.segments {
+            match segment {
+                Segment::Context(context) => *contexts.last_mut().expect("Not empty") = context,
+                Segment::Hunk(..) => contexts.push(b""),
+            }
+        }
+        let (first, rest) = contexts.split_first().expect("Not empty");
+        let (last, between) = rest.split_last()?;
+
+        let mut rest = postimage.strip_prefix(*first)?;
+        let mut resolutions = Vec::new();
+        for context in between {
+            if context.is_empty() {
+                return None;
+            }
+            let at = rest.find(context)?;
+            resolutions.push(&rest[..at]);
+            rest = &rest[at + context.len()..];
+        }
+        resolutions.push(rest.strip_suffix(*last)?);
+        Some(resolutions)
+    }
+}
+
+fn is_marker(line: &[u8], marker: u8) -> bool {
+    line.len() >= MARKER_SIZE
+        && line[..MARKER_SIZE].iter().all(|&byte| byte == marker)
+        && matches!(line.get(MARKER_SIZE), None | Some(b' ' | b'\n' | b'\r'))
+}
+
+/// Each line is the ID, a tab and the path, ending in a NUL like git's
+fn read_merge_rr(path: &Path) -> Result<BTreeMap<WsPath, String>, RerereError> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(RerereError::Read(path.into(), err)),
+    };
+    let mut merge_rr = BTreeMap::new();
+    for record in data.split_str("\0").filter(|record| !record.is_empty()) {
+        let invalid = || RerereError::InvalidMergeRr(record.into());
+        let mut fields = record.splitn_str(2, "\t");
+        let (Some(id), Some(path)) = (fields.next(), fields.next()) else {
+            return Err(invalid());
+        };
+        let id = id.to_str().map_err(|_| invalid())?;
+        merge_rr.insert(WsPath::new_unchecked_bytes(path), id.to_owned());
+    }
+    Ok(merge_rr)
+}
+
+fn write_merge_rr(path: &Path, merge_rr: &BTreeMap<WsPath, String>) -> Result<(), RerereError> {
+    if merge_rr.is_empty() {
+        return match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(RerereError::Write(path.into(), err))
+            }
+            _ => Ok(()),
+        };
+    }
+    let mut data = Vec::new();
+    for (file, id) in merge_rr {
+        data.extend_from_slice(id.as_bytes());
+        data.push(b'\t');
+        data.extend_from_slice(file.as_bstr());
+        data.push(b'\0');
+    }
+    write(path, &data)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, RerereError> {
+    fs::read(path).map_err(|e| RerereError::Read(path.into(), e))
+}
+
+fn write(path: &Path, data: &[u8]) -> Result<(), RerereError> {
+    let write_err = |e| RerereError::Write(path.into(), e);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(write_err)?;
+    }
+    fs::write(path, data).map_err(write_err)
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum RerereError {
+    /// {0}
+    Bare(#[from] BareError),
+    /// Invalid `rerere.enabled`
+    Config(#[from] config::ValueError),
+    /// Failed to load index
+    LoadIndex(#[from] index::LoadError),
+    /// Failed to read {0:?}
+    Read(PathBuf, #[source] io::Error),
+    /// Failed to write {0:?}
+    Write(PathBuf, #[source] io::Error),
+    /// Invalid entry in `MERGE_RR`: {0}
+    InvalidMergeRr(bstr::BString),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const CONFLICTED: &[u8] = b"top\n\
+        <<<<<<< HEAD\n\
+        ours\n\
+        ||||||| base\n\
+        base\n\
+        =======\n\
+        theirs\n\
+        >>>>>>> other\n\
+        middle\n\
+        <<<<<<< HEAD\n\
+        b\n\
+        =======\n\
+        a\n\
+        >>>>>>> other\n\
+        bottom\n";
+
+    #[test]
+    fn normalizes_conflicts() {
+        let conflicts = Conflicts::parse(CONFLICTED).unwrap();
+        assert_eq!(2, conflicts.hunks());
+        assert_eq!(
+            b"top\n<<<<<<<\nours\n=======\ntheirs\n>>>>>>>\n\
+            middle\n<<<<<<<\na\n=======\nb\n>>>>>>>\nbottom\n"
+                .as_bstr(),
+            conflicts.normalized().as_bstr()
+        );
+
+        // The same whichever side is ours
+        let swapped = CONFLICTED
+            .replace("ours", "OURS")
+            .replace("theirs", "ours")
+            .replace("OURS", "theirs");
+        assert_eq!(conflicts.id(), Conflicts::parse(&swapped).unwrap().id());
+        let other = CONFLICTED.replace("bottom", "changed");
+        assert_eq!(conflicts.id(), Conflicts::parse(&other).unwrap().id());
+        let different = CONFLICTED.replace("theirs", "else");
+        assert_ne!(conflicts.id(), Conflicts::parse(&different).unwrap().id());
+
+        assert!(Conflicts::parse(b"<<<<<<< HEAD\nunfinished\n").is_err());
+        assert_eq!(
+            0,
+            Conflicts::parse(b"<<<<<<<< not a marker\n")
+                .unwrap()
+                .hunks()
+        );
+    }
+
+    #[test]
+    fn resolves_hunks_like_before() {
+        let preimage = Conflicts::parse(CONFLICTED).unwrap().normalized();
+        let postimage = b"top\nresolved\nmiddle\nab\nbottom\n";
+
+        let moved = CONFLICTED
+            .replace("top\n", "new top\n")
+            .replace("bottom", "new bottom");
+        let conflicts = Conflicts::parse(&moved).unwrap();
+        assert_eq!(
+            Some(b"new top\nresolved\nmiddle\nab\nnew bottom\n".as_bstr()),
+            conflicts
+                .resolve(&preimage, postimage)
+                .as_ref()
+                .map(|resolved| resolved.as_bstr())
+        );
+
+        // Without any context to go by, only the same conflicts are resolved
+        let preimage = b"<<<<<<<\na\n=======\nb\n>>>>>>>\n<<<<<<<\nc\n=======\nd\n>>>>>>>\n";
+        let postimage = b"resolved\n";
+        let same = Conflicts::parse(b"<<<<<<< HEAD\nb\n=======\na\n>>>>>>> other\n<<<<<<< HEAD\nc\n=======\nd\n>>>>>>> other\n").unwrap();
+        assert_eq!(Some(postimage.to_vec()), same.resolve(preimage, postimage));
+    }
+}
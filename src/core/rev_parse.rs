@@ -0,0 +1,241 @@
+//! Resolving revisions like `main~2` and ranges like `main..topic` or
+//! `^main`, like `git rev-parse`
+
+use std::fmt;
+
+use bstr::{BString, ByteSlice};
+use tracing::instrument;
+
+use crate::core::{
+    db::{CommitRef, LoadRawError, Oid, TagRef, UntypedOid},
+    refs::{self, is_valid_name},
+    revwalk::{self, RevWalk, RevWalkError},
+    Db, Repo,
+};
+
+/// The commits to walk from and the commits whose history is left out, from
+/// revisions and ranges
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct RevRange {
+    pub include: Vec<UntypedOid>,
+    pub exclude: Vec<UntypedOid>,
+}
+
+impl RevRange {
+    /// Walks the commits reachable from those included but not from those
+    /// excluded, like `git rev-list`
+    pub fn walk<'a>(&self, db: &'a Db) -> Result<RevWalk<'a>, RevWalkError> {
+        let mut walk = RevWalk::new(db);
+        for &oid in &self.include {
+            walk.push(oid)?;
+        }
+        for &oid in &self.exclude {
+            walk.hide(oid)?;
+        }
+        Ok(walk)
+    }
+}
+
+impl Repo {
+    /// The object a revision names. A revision is a full oid, `HEAD`, or a
+    /// ref (looked for under `refs/`, `refs/tags/`, `refs/heads/` and
+    /// `refs/remotes/` like git), followed by any of
+    ///
+    /// - `~n` for the nth first-parent ancestor (`~` is `~1`)
+    /// - `^n` for the nth parent (`^` is `^1`, `^0` the commit itself)
+    /// - `^{}` to peel tags, or `^{commit}`, `^{tree}` and so on to peel to
+    ///   an object of that type
+    #[instrument(err)]
+    pub fn rev_parse(&self, rev: &str) -> Result<UntypedOid, RevParseError> {
+        let end = rev.find(['~', '^']).unwrap_or(rev.len());
+        let (name, mut suffixes) = rev.split_at(end);
+        let mut oid = self.resolve_name(rev, name)?;
+
+        while !suffixes.is_empty() {
+            let (op, rest) = suffixes.split_at(1);
+            if op != "~" && op != "^" {
+                return Err(invalid(rev));
+            }
+            if op == "^" && rest.starts_with('{') {
+                let close = rest.find('}').ok_or_else(|| invalid(rev))?;
+                let ty = &rest[1..close];
+                oid = match ty {
+                    "" => self.peel(rev, oid, None)?,
+                    "commit" | "tree" | "blob" | "tag" => self.peel(rev, oid, Some(ty))?,
+                    _ => return Err(invalid(rev)),
+                };
+                suffixes = &rest[close + 1..];
+                continue;
+            }
+
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let n = match &rest[..digits] {
+                "" => 1,
+                n => n.parse::<usize>().map_err(|_| invalid(rev))?,
+            };
+            suffixes = &rest[digits..];
+            oid = if op == "~" {
+                (0..n).try_fold(oid, |oid, _| self.parent(rev, oid, 1))?
+            } else if n == 0 {
+                self.peel(rev, oid, Some("commit"))?
+            } else {
+                self.parent(rev, oid, n)?
+            };
+        }
+        Ok(oid)
+    }
+
+    /// Resolves revisions and ranges like `git rev-list` takes them:
+    ///
+    /// - `rev` includes it
+    /// - `^rev` excludes it
+    /// - `a..b` includes `b` and excludes `a`
+    /// - `a...b` includes both and excludes their merge base
+    ///
+    /// Either side of a range can be left out to mean `HEAD`.
+    #[instrument(err)]
+    pub fn rev_range<I, S>(&self, revs: I) -> Result<RevRange, RevParseError>
+    where
+        I: IntoIterator<Item = S> + fmt::Debug,
+        S: AsRef<str>,
+    {
+        let side = |rev: &str| {
+            if rev.is_empty() {
+                self.rev_parse("HEAD")
+            } else {
+                self.rev_parse(rev)
+            }
+        };
+
+        let mut range = RevRange::default();
+        for rev in revs {
+            let rev = rev.as_ref();
+            if let Some((a, b)) = rev.split_once("...") {
+                let (a, b) = (side(a)?, side(b)?);
+                range.include.extend([a, b]);
+                if let Some(base) = revwalk::merge_base(&self.db, a, b)? {
+                    range.exclude.push(base);
+                }
+            } else if let Some((a, b)) = rev.split_once("..") {
+                range.exclude.push(side(a)?);
+                range.include.push(side(b)?);
+            } else if let Some(rev) = rev.strip_prefix('^') {
+                range.exclude.push(self.rev_parse(rev)?);
+            } else {
+                range.include.push(self.rev_parse(rev)?);
+            }
+        }
+        Ok(range)
+    }
+
+    fn resolve_name(&self, rev: &str, name: &str) -> Result<UntypedOid, RevParseError> {
+        let name = if name == "@" { "HEAD" } else { name };
+        if name.len() == 40 {
+            if let Ok(oid) = UntypedOid::parse(name) {
+                return Ok(oid);
+            }
+        }
+
+        // Like `HEAD` or `ORIG_HEAD`
+        let is_pseudo = |name: &str| name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_');
+        let candidates = [
+            name.to_owned(),
+            format!("refs/{name}"),
+            format!("refs/tags/{name}"),
+            format!("refs/heads/{name}"),
+            format!("refs/remotes/{name}"),
+            format!("refs/remotes/{name}/HEAD"),
+        ];
+        for candidate in &candidates {
+            let allowed = (candidate == name && is_pseudo(name))
+                || is_valid_name(candidate.as_bytes().as_bstr());
+            if !allowed {
+                continue;
+            }
+            if let Some(oid) = self.refs.read_ref(candidate.as_bytes().as_bstr())? {
+                return Ok(oid.into_untyped());
+            }
+        }
+        Err(RevParseError::NotFound(rev.to_owned()))
+    }
+
+    /// The raw type and data of the object, with any replacement
+    fn load_rev(&self, rev: &str, oid: UntypedOid) -> Result<(BString, Vec<u8>), RevParseError> {
+        self.db
+            .load_raw(&self.db.replaced(&oid))?
+            .ok_or_else(|| RevParseError::NotFound(rev.to_owned()))
+    }
+
+    /// Follows tags until an object of type `ty`, or until an object that
+    /// isn't a tag if `None`. Commits peel to their trees.
+    fn peel(
+        &self,
+        rev: &str,
+        mut oid: UntypedOid,
+        ty: Option<&str>,
+    ) -> Result<UntypedOid, RevParseError> {
+        loop {
+            let (actual, data) = self.load_rev(rev, oid)?;
+            if Some(actual.as_bytes()) == ty.map(str::as_bytes) {
+                return Ok(oid);
+            }
+            let corrupt = || RevParseError::Corrupt(oid);
+            oid = match (actual.as_bytes(), ty) {
+                (b"tag", _) => TagRef::parse(&data).map_err(|_| corrupt())?.object,
+                (_, None) => return Ok(oid),
+                (b"commit", Some("tree")) => CommitRef::parse(&data)
+                    .map_err(|_| corrupt())?
+                    .tree
+                    .into_untyped(),
+                (_, Some(ty)) => {
+                    return Err(RevParseError::WrongType {
+                        rev: rev.to_owned(),
+                        expected: ty.to_owned(),
+                    })
+                }
+            };
+        }
+    }
+
+    /// The nth parent of the commit, counting from 1
+    fn parent(&self, rev: &str, oid: UntypedOid, n: usize) -> Result<UntypedOid, RevParseError> {
+        let commit = self.peel(rev, oid, Some("commit"))?;
+        if self.db.is_shallow(&commit) {
+            return Err(RevParseError::NoParent(rev.to_owned()));
+        }
+        let (_, data) = self.load_rev(rev, commit)?;
+        let parent = CommitRef::parse(&data)
+            .map_err(|_| RevParseError::Corrupt(commit))?
+            .parents()
+            .nth(n - 1);
+        parent
+            .map(Oid::into_untyped)
+            .ok_or_else(|| RevParseError::NoParent(rev.to_owned()))
+    }
+}
+
+fn invalid(rev: &str) -> RevParseError {
+    RevParseError::Invalid(rev.to_owned())
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum RevParseError {
+    /// Invalid revision {0:?}
+    Invalid(String),
+    /// Unknown revision {0:?}
+    NotFound(String),
+    /// Revision {0:?} goes past the commits it has parents for
+    NoParent(String),
+    /// Revision {rev:?} doesn't peel to a {expected}
+    WrongType { rev: String, expected: String },
+    /// Object {0} is corrupt
+    Corrupt(UntypedOid),
+    /// Failed to read ref
+    ReadRef(#[from] refs::ReadError),
+    /// Failed to load object
+    LoadRaw(#[from] LoadRawError),
+    /// Failed to find merge base
+    MergeBase(#[from] RevWalkError),
+}
@@ -0,0 +1,458 @@
+//! Walking the history of commits, newest first
+
+use std::collections::{BTreeSet, BinaryHeap, VecDeque};
+
+use bstr::{BString, ByteSlice};
+
+use crate::core::{
+    db::{tree::NodeRef, CommitRef, LoadRawError, Oid, TagRef, TreeRef, UntypedOid},
+    pack::ObjectType,
+    Db,
+};
+
+/// Yields each commit reachable from the ones pushed once, in order of
+/// committer time, like `git rev-list`. Commits we don't have are left out,
+/// and shallow commits have no parents, so the walk stops where our history
+/// does. Replaced commits have the parents of their replacements. Commits
+/// reachable from hidden ones are left out, like `^rev`.
+#[derive(Debug)]
+pub struct RevWalk<'a> {
+    db: &'a Db,
+    /// Newest first, and hidden first among commits made at the same time,
+    /// so that their parents are hidden before they're walked
+    queue: BinaryHeap<(i64, bool, Walked)>,
+    seen: BTreeSet<UntypedOid>,
+    /// Reachable from a hidden commit, as far as the walk has found
+    hidden: BTreeSet<UntypedOid>,
+    replace: bool,
+}
+
+/// Ordered by time
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Walked {
+    /// Seconds since the epoch
+    pub time: i64,
+    pub oid: UntypedOid,
+    pub parents: Vec<UntypedOid>,
+    pub tree: UntypedOid,
+}
+
+/// Yields the commits of a [`RevWalk`], then every tree and blob reachable
+/// from them once each, like `git rev-list --objects`. Trees and blobs in
+/// the trees of the hidden commits where the walk stopped are left out, as
+/// the other side of a range has them. Blobs aren't loaded, so they may be
+/// missing, as in a partial clone, but trees are. Submodule commits are left
+/// out.
+#[derive(Debug)]
+pub struct ObjectWalk<'a> {
+    commits: RevWalk<'a>,
+    /// The trees of the commits walked
+    trees: VecDeque<UntypedOid>,
+    /// Of the commits walked, to find the hidden ones where the walk stopped
+    parents: BTreeSet<UntypedOid>,
+    /// Objects to yield next, depth first, with their paths
+    pending: Vec<(UntypedOid, ObjectType, BString)>,
+    seen: BTreeSet<UntypedOid>,
+    commits_done: bool,
+}
+
+/// A commit, tree or blob found by an [`ObjectWalk`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WalkedObject {
+    pub oid: UntypedOid,
+    pub object_type: ObjectType,
+    /// Where it was first found, like `dir/file.txt`, or empty for the root
+    /// tree of a commit. `None` for commits.
+    pub path: Option<BString>,
+}
+
+impl<'a> RevWalk<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self {
+            db,
+            queue: BinaryHeap::new(),
+            seen: BTreeSet::new(),
+            hidden: BTreeSet::new(),
+            replace: true,
+        }
+    }
+
+    /// Walks the history as it's stored, as needed when telling a remote
+    /// what we have
+    pub fn ignore_replacements(&mut self) -> &mut Self {
+        self.replace = false;
+        self
+    }
+
+    /// Starts walking from `oid`. Tags are peeled, and anything else that
+    /// isn't a commit is ignored.
+    pub fn push(&mut self, oid: UntypedOid) -> Result<(), RevWalkError> {
+        self.push_as(oid, false)
+    }
+
+    /// Leaves out `oid` and every commit reachable from it, like `^oid`.
+    /// Tags are peeled, and anything else that isn't a commit is ignored.
+    pub fn hide(&mut self, oid: UntypedOid) -> Result<(), RevWalkError> {
+        self.push_as(oid, true)
+    }
+
+    fn push_as(&mut self, mut oid: UntypedOid, hidden: bool) -> Result<(), RevWalkError> {
+        loop {
+            if self.seen.contains(&oid) {
+                if hidden && self.hidden.insert(oid) {
+                    // Queued again to be walked before shown commits made at
+                    // the same time, the stale entry is skipped as hidden
+                    let queued = self.queue.iter().find(|(_, _, commit)| commit.oid == oid);
+                    if let Some((time, _, commit)) = queued.cloned() {
+                        self.queue.push((time, true, commit));
+                    }
+                }
+                return Ok(());
+            }
+            let source = if self.replace {
+                self.db.replaced(&oid)
+            } else {
+                oid
+            };
+            // Missing objects are left out
+            let (ty, data) = self.db.load_raw(&source)?.unwrap_or_default();
+            let corrupt = || RevWalkError::Corrupt(oid);
+            match ty.as_bytes() {
+                b"tag" => oid = TagRef::parse(&data).map_err(|_| corrupt())?.object,
+                b"commit" => {
+                    let commit = CommitRef::parse(&data).map_err(|_| corrupt())?;
+                    self.seen.insert(oid);
+                    if hidden {
+                        self.hidden.insert(oid);
+                    }
+                    let parents = if self.db.is_shallow(&oid) {
+                        Vec::new()
+                    } else {
+                        commit.parents().map(Oid::into_untyped).collect()
+                    };
+                    let time = commit.committer.time.timestamp();
+                    let walked = Walked {
+                        time,
+                        oid,
+                        parents,
+                        tree: commit.tree.into_untyped(),
+                    };
+                    self.queue.push((time, hidden, walked));
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Also yields every tree and blob reachable from the commits
+    pub fn objects(self) -> ObjectWalk<'a> {
+        ObjectWalk {
+            commits: self,
+            trees: VecDeque::new(),
+            parents: BTreeSet::new(),
+            pending: Vec::new(),
+            seen: BTreeSet::new(),
+            commits_done: false,
+        }
+    }
+
+    /// Hidden commits are walked too, to hide their parents, until only
+    /// hidden commits are left
+    fn walk_next(&mut self) -> Result<Option<Walked>, RevWalkError> {
+        loop {
+            if self
+                .queue
+                .iter()
+                .all(|(_, _, commit)| self.hidden.contains(&commit.oid))
+            {
+                return Ok(None);
+            }
+            let (_, _, commit) = self.queue.pop().expect("Not empty");
+            let hidden = self.hidden.contains(&commit.oid);
+            for &parent in &commit.parents {
+                self.push_as(parent, hidden)?;
+            }
+            if !hidden {
+                return Ok(Some(commit));
+            }
+        }
+    }
+}
+
+impl Iterator for RevWalk<'_> {
+    type Item = Result<Walked, RevWalkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walk_next().transpose()
+    }
+}
+
+impl ObjectWalk<'_> {
+    fn walk_next(&mut self) -> Result<Option<WalkedObject>, RevWalkError> {
+        if !self.commits_done {
+            if let Some(commit) = self.commits.walk_next()? {
+                self.parents.extend(&commit.parents);
+                self.trees.push_back(commit.tree);
+                return Ok(Some(WalkedObject {
+                    oid: commit.oid,
+                    object_type: ObjectType::Commit,
+                    path: None,
+                }));
+            }
+            self.commits_done = true;
+            let parents = std::mem::take(&mut self.parents);
+            for parent in parents {
+                if self.commits.hidden.contains(&parent) {
+                    self.hide_tree_of(parent)?;
+                }
+            }
+        }
+
+        loop {
+            if self.pending.is_empty() {
+                match self.trees.pop_front() {
+                    Some(tree) => self
+                        .pending
+                        .push((tree, ObjectType::Tree, BString::from(""))),
+                    None => return Ok(None),
+                }
+            }
+            let (oid, object_type, path) = self.pending.pop().expect("Not empty");
+            if !self.seen.insert(oid) {
+                continue;
+            }
+            if object_type == ObjectType::Tree {
+                let children = self.children(oid, &path)?;
+                self.pending.extend(children.into_iter().rev());
+            }
+            return Ok(Some(WalkedObject {
+                oid,
+                object_type,
+                path: Some(path),
+            }));
+        }
+    }
+
+    /// The trees and blobs in a tree, with their paths
+    fn children(
+        &self,
+        tree: UntypedOid,
+        path: &[u8],
+    ) -> Result<Vec<(UntypedOid, ObjectType, BString)>, RevWalkError> {
+        let (_, data) = self
+            .commits
+            .db
+            .load_raw(&tree)?
+            .ok_or(RevWalkError::Missing(tree))?;
+        let nodes = TreeRef::parse(&data).map_err(|_| RevWalkError::Corrupt(tree))?;
+        Ok(nodes
+            .nodes()
+            .filter(|node| !node.is_gitlink())
+            .map(|node| {
+                let ty = match node {
+                    NodeRef::Tree { .. } => ObjectType::Tree,
+                    NodeRef::File { .. } => ObjectType::Blob,
+                };
+                let path = if path.is_empty() {
+                    BString::from(node.name().as_bytes())
+                } else {
+                    BString::from([path, b"/", node.name().as_bytes()].concat())
+                };
+                (node.untyped_oid(), ty, path)
+            })
+            .collect())
+    }
+
+    /// Marks everything in the tree of the commit as seen, so it isn't yielded
+    fn hide_tree_of(&mut self, commit: UntypedOid) -> Result<(), RevWalkError> {
+        let db = self.commits.db;
+        let source = if self.commits.replace {
+            db.replaced(&commit)
+        } else {
+            commit
+        };
+        let Some((_, data)) = db.load_raw(&source)? else {
+            return Ok(());
+        };
+        let tree = CommitRef::parse(&data)
+            .map_err(|_| RevWalkError::Corrupt(commit))?
+            .tree;
+        let mut pending = vec![tree.into_untyped()];
+        while let Some(oid) = pending.pop() {
+            if !self.seen.insert(oid) {
+                continue;
+            }
+            // What the other side has may not all be here
+            let Some((_, data)) = db.load_raw(&oid)? else {
+                continue;
+            };
+            let tree = TreeRef::parse(&data).map_err(|_| RevWalkError::Corrupt(oid))?;
+            for node in tree.nodes().filter(|node| !node.is_gitlink()) {
+                match node {
+                    NodeRef::Tree { oid, .. } => pending.push(oid.into_untyped()),
+                    NodeRef::File { oid, .. } => {
+                        self.seen.insert(oid.into_untyped());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for ObjectWalk<'_> {
+    type Item = Result<WalkedObject, RevWalkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walk_next().transpose()
+    }
+}
+
+/// Whether `ancestor` can be reached from `descendant`, as far as we know
+pub fn is_ancestor(
+    db: &Db,
+    ancestor: UntypedOid,
+    descendant: UntypedOid,
+) -> Result<bool, RevWalkError> {
+    let mut walk = RevWalk::new(db);
+    walk.push(descendant)?;
+    for commit in walk {
+        if commit?.oid == ancestor {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The newest commit both `a` and `b` descend from, which is the best base to
+/// merge them from unless their history is criss-crossed
+pub fn merge_base(
+    db: &Db,
+    a: UntypedOid,
+    b: UntypedOid,
+) -> Result<Option<UntypedOid>, RevWalkError> {
+    let mut walk = RevWalk::new(db);
+    walk.push(a)?;
+    let ancestors = walk
+        .map(|commit| commit.map(|commit| commit.oid))
+        .collect::<Result<BTreeSet<_>, _>>()?;
+    let mut walk = RevWalk::new(db);
+    walk.push(b)?;
+    for commit in walk {
+        let oid = commit?.oid;
+        if ancestors.contains(&oid) {
+            return Ok(Some(oid));
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum RevWalkError {
+    /// Failed to load commit
+    Load(#[from] LoadRawError),
+    /// Object {0:?} is corrupt
+    Corrupt(UntypedOid),
+    /// Tree {0:?} is missing
+    Missing(UntypedOid),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fmt::Write;
+    use tempfile::tempdir;
+
+    fn commit(db: &Db, time: i64, parents: &[UntypedOid]) -> UntypedOid {
+        let mut data = format!("tree {}\n", UntypedOid::zero().to_hex());
+        for parent in parents {
+            writeln!(data, "parent {}", parent.to_hex()).unwrap();
+        }
+        write!(
+            data,
+            "author A <a@b> {time} +0000\ncommitter C <c@d> {time} -0100\n\nMsg\n"
+        )
+        .unwrap();
+        db.store_raw(b"commit", data.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn walks_newest_first() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        std::fs::create_dir(dir.path().join("objects"))?;
+        let db = Db::new(dir.path());
+
+        let root = commit(&db, 1, &[]);
+        let left = commit(&db, 2, &[root]);
+        let right = commit(&db, 4, &[root]);
+        let merge = commit(&db, 5, &[left, right]);
+        let missing_parent = commit(&db, 3, &[UntypedOid::parse("7".repeat(40))?]);
+        let tag = db.store_raw(
+            b"tag",
+            format!("object {}\ntype commit\ntag v1\n\nMsg\n", merge.to_hex()).as_bytes(),
+        )?;
+
+        let mut walk = RevWalk::new(&db);
+        walk.push(tag)?;
+        walk.push(missing_parent)?;
+        walk.push(left)?;
+        let walked = walk
+            .map(|c| c.map(|c| c.oid))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(vec![merge, right, missing_parent, left, root], walked);
+
+        assert!(is_ancestor(&db, root, merge)?);
+        assert!(!is_ancestor(&db, merge, root)?);
+        assert_eq!(Some(root), merge_base(&db, left, right)?);
+        assert_eq!(Some(right), merge_base(&db, merge, right)?);
+        assert_eq!(None, merge_base(&db, left, missing_parent)?);
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_out_hidden_history() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        std::fs::create_dir(dir.path().join("objects"))?;
+        let db = Db::new(dir.path());
+
+        let root = commit(&db, 1, &[]);
+        let left = commit(&db, 2, &[root]);
+        let right = commit(&db, 3, &[root]);
+        let merge = commit(&db, 4, &[left, right]);
+        let after = commit(&db, 5, &[merge]);
+
+        let walk = |push: &[UntypedOid], hide: &[UntypedOid]| -> eyre::Result<_> {
+            let mut walk = RevWalk::new(&db);
+            for &oid in push {
+                walk.push(oid)?;
+            }
+            for &oid in hide {
+                walk.hide(oid)?;
+            }
+            Ok(walk
+                .map(|c| c.map(|c| c.oid))
+                .collect::<Result<Vec<_>, _>>()?)
+        };
+        assert_eq!(vec![after, merge, left], walk(&[after], &[right])?);
+        assert_eq!(vec![after], walk(&[after], &[merge])?);
+        assert_eq!(Vec::<UntypedOid>::new(), walk(&[left], &[after])?);
+        assert_eq!(vec![right], walk(&[right, left], &[left])?);
+
+        // Made at the same time as the hidden commit and their parent
+        let parent = commit(&db, 6, &[after]);
+        let hidden = commit(&db, 6, &[parent, root]);
+        let shown = commit(&db, 6, &[parent]);
+        assert_eq!(vec![shown], walk(&[shown], &[hidden])?);
+        // Even when it's also pushed, as with `shown...hidden`, however
+        // their ids happen to order them
+        for time in 7..20 {
+            let parent = commit(&db, time, &[after]);
+            let hidden = commit(&db, time, &[parent]);
+            let shown = commit(&db, time, &[parent, root]);
+            assert_eq!(vec![shown], walk(&[shown, hidden], &[hidden])?);
+        }
+        Ok(())
+    }
+}
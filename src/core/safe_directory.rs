@@ -0,0 +1,110 @@
+//! Refusing to open repositories owned by someone else, like git does since
+//! CVE-2022-24765. On a shared machine, their config and hooks could run
+//! commands as us, unless we've trusted them with `safe.directory`.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use tracing::debug;
+
+use crate::core::{config, platform, repo::ReadError, Config};
+
+/// Errors unless we own both `dir`, the workspace or the repository itself if
+/// it's bare, and `git_dir`, or `dir` is in `safe.directory`
+pub(crate) fn check(dir: &Path, git_dir: &Path) -> Result<(), ReadError> {
+    let Some(owner) = platform::current_owner() else {
+        return Ok(());
+    };
+    for path in [dir, git_dir] {
+        let meta = fs::metadata(path).map_err(|e| ReadError::Io(path.to_owned(), e))?;
+        let uid = platform::metadata(&meta).uid;
+        if uid != owner {
+            debug!(?path, uid, owner, "Owned by someone else");
+            let values = trusted_values()?;
+            if trusts(values.iter().map(String::as_str), dir) {
+                return Ok(());
+            }
+            return Err(ReadError::Unsafe(dir.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+/// The `safe.directory` values of the system and global config, as a
+/// repository's own config can't vouch for it
+fn trusted_values() -> Result<Vec<String>, config::LoadError> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let mut paths = Vec::new();
+    if env::var_os("GIT_CONFIG_NOSYSTEM").is_none() {
+        paths.push(
+            env::var_os("GIT_CONFIG_SYSTEM")
+                .map_or_else(|| PathBuf::from("/etc/gitconfig"), PathBuf::from),
+        );
+    }
+    if let Some(global) = env::var_os("GIT_CONFIG_GLOBAL") {
+        paths.push(global.into());
+    } else {
+        let xdg = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home.as_ref().map(|home| home.join(".config")));
+        paths.extend(xdg.map(|dir| dir.join("git/config")));
+        paths.extend(home.map(|home| home.join(".gitconfig")));
+    }
+
+    let mut values = Vec::new();
+    for path in paths {
+        let config = Config::load(path)?;
+        values.extend(config.get_all("safe.directory").map(str::to_owned));
+    }
+    Ok(values)
+}
+
+/// `*` trusts everything, a path ending in `/*` everything under it, and an
+/// empty value forgets those before it
+fn trusts<'a>(values: impl IntoIterator<Item = &'a str>, dir: &Path) -> bool {
+    let values = values.into_iter().collect::<Vec<_>>();
+    let start = values
+        .iter()
+        .rposition(|value| value.is_empty())
+        .map_or(0, |i| i + 1);
+    values[start..].iter().any(|&value| {
+        if value == "*" {
+            return true;
+        }
+        let (value, under) = match value.strip_suffix("/*") {
+            Some(prefix) => (prefix, true),
+            None => (value, false),
+        };
+        let Some(path) = config::expand_home(value) else {
+            return false;
+        };
+        let path = path.canonicalize().unwrap_or(path);
+        if under {
+            dir.starts_with(&path) && dir != path
+        } else {
+            dir == path
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusts_listed_directories() {
+        let dir = Path::new("/srv/shared/repo");
+        assert!(!trusts([], dir));
+        assert!(trusts(["/srv/shared/repo"], dir));
+        assert!(trusts(["/srv/shared/repo/"], dir));
+        assert!(!trusts(["/srv/shared"], dir));
+        assert!(trusts(["/srv/shared/*"], dir));
+        assert!(!trusts(["/srv/shared/repo/*"], dir));
+        assert!(trusts(["/elsewhere", "*"], dir));
+        // An empty value forgets those before it
+        assert!(!trusts(["*", ""], dir));
+        assert!(trusts(["", "/srv/shared/repo"], dir));
+    }
+}
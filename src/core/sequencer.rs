@@ -0,0 +1,475 @@
+//! Cherry-picking and reverting series of commits, like `git cherry-pick` and
+//! `git revert`. The commits left to apply are kept in `.git/sequencer`, so
+//! that a series stopped by conflicts can be continued once they're resolved,
+//! skipped past, or aborted.
+
+use std::{collections::BTreeMap, fs, io, path::PathBuf};
+
+use bstr::{BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use crate::core::{
+    commit_msg::{self, Cleanup},
+    config,
+    db::{self, signature, tree::FileNode, Commit, Tree, UntypedOid},
+    fetch, index,
+    merge::{commits::MERGE_MSG, FileMergeOptions, MergeError},
+    migration::{self, Migration},
+    refs::{self, Refs},
+    repo::{BareError, CommitError},
+    rev_parse::RevRange,
+    revwalk::RevWalkError,
+    sparse::{self, Cone},
+    ws::{attributes, Attributes},
+    CommitOptions, ObjectBuilder, Oid, Repo, WsPath,
+};
+
+type Files = BTreeMap<WsPath, FileNode>;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Action {
+    /// Applies what a commit changed from its parent
+    Pick,
+    /// Undoes what a commit changed from its parent
+    Revert,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Sequenced {
+    /// Every commit was applied, with the commits made in order. Commits
+    /// that changed nothing once applied are dropped.
+    Done(Vec<Oid<Commit>>),
+    /// Applying the commit conflicted, and the series stopped with the
+    /// paths left unmerged. Once they're resolved, see
+    /// [`Repo::continue_sequence`].
+    Conflicts {
+        commit: Oid<Commit>,
+        paths: Vec<WsPath>,
+    },
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Pick => "pick",
+            Self::Revert => "revert",
+        }
+    }
+
+    /// Where the commit is kept while its conflicts are resolved
+    fn pseudo_ref(self) -> &'static [u8] {
+        match self {
+            Self::Pick => Refs::CHERRY_PICK_HEAD,
+            Self::Revert => Refs::REVERT_HEAD,
+        }
+    }
+}
+
+impl Repo {
+    /// Applies each commit of the range onto HEAD, committing it with its
+    /// author and message. A range that excludes nothing is just the commits
+    /// given, in order, like `git cherry-pick a b`, while `a..b` picks the
+    /// commits of the range oldest first. Merges can't be picked.
+    #[instrument(err)]
+    pub fn cherry_pick(&mut self, range: &RevRange) -> Result<Sequenced, SequencerError> {
+        let mut commits = self.sequence_commits(range)?;
+        if !range.exclude.is_empty() {
+            commits.reverse();
+        }
+        self.start_sequence(Action::Pick, commits)
+    }
+
+    /// Commits undoing each commit of the range, newest first for a range
+    /// like `a..b`, with messages like `Revert "<subject>"`
+    #[instrument(err)]
+    pub fn revert(&mut self, range: &RevRange) -> Result<Sequenced, SequencerError> {
+        let commits = self.sequence_commits(range)?;
+        self.start_sequence(Action::Revert, commits)
+    }
+
+    /// Commits the resolution of the commit that conflicted, with the message
+    /// in `MERGE_MSG`, then applies the rest, like `git cherry-pick
+    /// --continue`. If the resolution was already committed, only the rest
+    /// are applied.
+    #[instrument(err)]
+    pub fn continue_sequence(&mut self) -> Result<Sequenced, SequencerError> {
+        if !self.sequencer_dir().exists() {
+            return Err(SequencerError::NotInProgress);
+        }
+        self.index.reload()?;
+        if self.index.has_conflicts() {
+            return Err(SequencerError::Unmerged);
+        }
+
+        let mut stopped = None;
+        for action in [Action::Pick, Action::Revert] {
+            if let Some(commit) = self.refs.read_ref(action.pseudo_ref().as_bstr())? {
+                stopped = Some((action, commit));
+            }
+        }
+        let mut made = Vec::new();
+        if let Some((action, commit)) = stopped {
+            let msg = match self.initial_commit_message()? {
+                Some(msg) => msg,
+                None => String::from_utf8_lossy(&self.db.load(commit)?.msg).into_owned(),
+            };
+            let author = match action {
+                Action::Pick => self.db.load(commit)?.author,
+                Action::Revert => self.signature(signature::Role::Author)?,
+            };
+            let committer = self.signature(signature::Role::Committer)?;
+            let options = CommitOptions {
+                cleanup: Some(Cleanup::Strip),
+                ..CommitOptions::default()
+            };
+            self.commit_as_with(author, committer, msg, &options)?;
+            made.extend(self.refs.head()?);
+        }
+        self.run_sequence(made)
+    }
+
+    /// Drops the commit that conflicted, putting back the index and files it
+    /// changed, and applies the rest, like `git cherry-pick --skip`
+    #[instrument(err)]
+    pub fn skip_sequence(&mut self) -> Result<Sequenced, SequencerError> {
+        if !self.sequencer_dir().exists() {
+            return Err(SequencerError::NotInProgress);
+        }
+        let head = self.refs.head()?.ok_or(SequencerError::NoHead)?;
+        self.reset_merge(head)?;
+        self.run_sequence(Vec::new())
+    }
+
+    /// Stops the series, putting HEAD, the index and the files changed back
+    /// as they were before it started, like `git cherry-pick --abort`
+    #[instrument(err)]
+    pub fn abort_sequence(&mut self) -> Result<(), SequencerError> {
+        let head_file = self.sequencer_dir().join("head");
+        let head = match fs::read_to_string(&head_file) {
+            Ok(head) => head,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(SequencerError::NotInProgress)
+            }
+            Err(err) => return Err(SequencerError::Read(head_file, err)),
+        };
+        let head =
+            Oid::parse(head.trim()).map_err(|_| SequencerError::Corrupt(head_file.clone()))?;
+        self.reset_merge(head)?;
+        self.refs.update_head(&head)?;
+        self.end_sequence()
+    }
+
+    /// The commits of the range, newest first
+    fn sequence_commits(&self, range: &RevRange) -> Result<Vec<Oid<Commit>>, SequencerError> {
+        if range.exclude.is_empty() {
+            return Ok(range
+                .include
+                .iter()
+                .copied()
+                .map(UntypedOid::to_typed)
+                .collect());
+        }
+        let mut commits = Vec::new();
+        for walked in range.walk(&self.db)? {
+            commits.push(walked?.oid.to_typed());
+        }
+        Ok(commits)
+    }
+
+    fn start_sequence(
+        &mut self,
+        action: Action,
+        commits: Vec<Oid<Commit>>,
+    ) -> Result<Sequenced, SequencerError> {
+        let dir = self.sequencer_dir();
+        if dir.exists() {
+            return Err(SequencerError::InProgress);
+        }
+        let head = self.refs.head()?.ok_or(SequencerError::NoHead)?;
+        fs::create_dir_all(&dir).map_err(|e| SequencerError::Write(dir.clone(), e))?;
+        let head_file = dir.join("head");
+        fs::write(&head_file, format!("{}\n", head.to_hex()))
+            .map_err(|e| SequencerError::Write(head_file, e))?;
+        let todo = commits
+            .into_iter()
+            .map(|commit| (action, commit))
+            .collect::<Vec<_>>();
+        self.write_todo(&todo)?;
+        self.run_sequence(Vec::new())
+    }
+
+    /// Applies what's left to do, after the commits already made
+    fn run_sequence(&mut self, mut made: Vec<Oid<Commit>>) -> Result<Sequenced, SequencerError> {
+        loop {
+            let todo = self.read_todo()?;
+            let Some((&(action, commit), rest)) = todo.split_first() else {
+                self.end_sequence()?;
+                return Ok(Sequenced::Done(made));
+            };
+            debug!(action = action.name(), %commit, "Applying");
+            let applied = self.apply_commit(action, commit)?;
+            self.write_todo(rest)?;
+            match applied {
+                Applied::Commit(oid) => made.push(oid),
+                Applied::Empty => debug!(%commit, "Dropped, as it changed nothing"),
+                Applied::Conflicts(paths) => return Ok(Sequenced::Conflicts { commit, paths }),
+            }
+        }
+    }
+
+    fn apply_commit(
+        &mut self,
+        action: Action,
+        commit: Oid<Commit>,
+    ) -> Result<Applied, SequencerError> {
+        let head = self.refs.head()?.ok_or(SequencerError::NoHead)?;
+        let applied = self.db.load(commit)?;
+        if !applied.merged.is_empty() {
+            return Err(SequencerError::Merge(commit));
+        }
+        let parent = match applied.parent {
+            Some(parent) => {
+                let tree = self.db.load(parent)?.tree;
+                self.db.load_tree_files(&WsPath::root(), tree)?
+            }
+            None => Files::new(),
+        };
+        let changed = self.db.load_tree_files(&WsPath::root(), applied.tree)?;
+        let (base, theirs) = match action {
+            Action::Pick => (parent, changed),
+            Action::Revert => (changed, parent),
+        };
+        let ours_tree = self.db.load(head)?.tree;
+        let ours = self.db.load_tree_files(&WsPath::root(), ours_tree)?;
+        self.index.reload()?;
+        if self.index.has_conflicts() {
+            return Err(SequencerError::Unmerged);
+        }
+        if self.has_staged(&ours) {
+            return Err(SequencerError::Staged);
+        }
+
+        let subject = applied
+            .msg
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_str_lossy();
+        let hex = commit.to_hex();
+        let short = &hex[..7];
+        let options = FileMergeOptions {
+            ours_label: "HEAD".into(),
+            theirs_label: match action {
+                Action::Pick => format!("{short} ({subject})"),
+                Action::Revert => format!("parent of {short} ({subject})"),
+            },
+            ..FileMergeOptions::from_config(&self.config)?
+        };
+        let (merged, conflicts) = self.merge_trees(&base, &ours, &theirs, &options)?;
+        let msg = match action {
+            Action::Pick => String::from_utf8_lossy(&applied.msg).into_owned(),
+            Action::Revert => format!("Revert \"{subject}\"\n\nThis reverts commit {hex}.\n"),
+        };
+        if !conflicts.is_empty() {
+            self.refs
+                .update_ref(action.pseudo_ref().as_bstr(), &commit)?;
+            let comment_char = commit_msg::comment_char(&self.config);
+            let msg = commit_msg::with_conflicts(&msg, &conflicts, comment_char);
+            let msg_file = self.git_dir().join(MERGE_MSG);
+            fs::write(&msg_file, msg).map_err(|e| SequencerError::Write(msg_file, e))?;
+            return Ok(Applied::Conflicts(conflicts));
+        }
+        if merged == ours {
+            return Ok(Applied::Empty);
+        }
+
+        let entries = merged
+            .into_iter()
+            .map(|(path, file)| db::tree::EntryBuilder {
+                oid: file.oid,
+                path,
+                mode: file.mode,
+            });
+        let tree = db::tree::Builder::new().entries(entries).store(&self.db)?;
+        let author = match action {
+            Action::Pick => applied.author,
+            Action::Revert => self.signature(signature::Role::Author)?,
+        };
+        let committer = self.signature(signature::Role::Committer)?;
+        let made =
+            db::commit::Builder::new(Some(head), tree, author, committer, msg).store(&self.db)?;
+        self.refs.update_head(&made)?;
+        Ok(Applied::Commit(made))
+    }
+
+    /// Puts the index and the files that differ from the commit back as
+    /// they are in it, including those left unmerged, like `git reset
+    /// --merge`. Other local changes are kept. Anything in progress is
+    /// forgotten.
+    fn reset_merge(&mut self, target: Oid<Commit>) -> Result<(), SequencerError> {
+        let tree = self.db.load(target)?.tree;
+        let target = self.db.load_tree_files(&WsPath::root(), tree)?;
+        let work = Self::workspace_of(self.workspace.as_ref(), self.git_dir())?;
+        self.index.reload()?;
+        let mut current = self
+            .index
+            .entries()
+            .map(|entry| {
+                let node = FileNode {
+                    oid: entry.oid,
+                    name: entry.filename().unwrap_or_default().to_owned(),
+                    mode: entry.mode(),
+                };
+                (entry.to_path(), node)
+            })
+            .collect::<Files>();
+        // Unmerged files have conflict markers, so they're always rewritten
+        for (path, conflict) in self.index.conflicts() {
+            let Some(entry) = conflict.entries().next() else {
+                continue;
+            };
+            let node = FileNode {
+                oid: UntypedOid::zero().to_typed(),
+                name: entry.filename().unwrap_or_default().to_owned(),
+                mode: entry.mode(),
+            };
+            current.insert(WsPath::new_unchecked_bytes(path.to_owned()), node);
+        }
+        let migration = Migration::new(&current, &target);
+
+        let cone = Cone::load(self.git_dir())?;
+        self.fetch_missing_blobs(migration.written_blobs(cone.as_ref()))?;
+        let mut attrs = Attributes::new(self.common_dir())?;
+        for path in migration.paths() {
+            attrs.load_parents(work, path)?;
+        }
+        let mut index = self.index.modify()?;
+        migration.apply(work, &mut self.db, &mut index, &attrs, cone.as_ref())?;
+        index.commit()?;
+
+        for action in [Action::Pick, Action::Revert] {
+            self.refs.delete_ref(action.pseudo_ref().as_bstr())?;
+        }
+        let msg_file = self.git_dir().join(MERGE_MSG);
+        match fs::remove_file(&msg_file) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(SequencerError::Write(msg_file, err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn sequencer_dir(&self) -> PathBuf {
+        self.git_dir().join("sequencer")
+    }
+
+    /// Each line is like `pick <oid> <subject>`, as git writes them
+    fn read_todo(&self) -> Result<Vec<(Action, Oid<Commit>)>, SequencerError> {
+        let path = self.sequencer_dir().join("todo");
+        let todo = fs::read(&path).map_err(|e| SequencerError::Read(path.clone(), e))?;
+        todo.lines()
+            .filter(|line| !line.is_empty() && !line.starts_with(b"#"))
+            .map(|line| {
+                let mut fields = line.fields();
+                let action = match fields.next() {
+                    Some(b"pick" | b"p") => Action::Pick,
+                    Some(b"revert") => Action::Revert,
+                    _ => return Err(SequencerError::Corrupt(path.clone())),
+                };
+                let commit = fields
+                    .next()
+                    .and_then(|oid| Oid::parse(oid).ok())
+                    .ok_or_else(|| SequencerError::Corrupt(path.clone()))?;
+                Ok((action, commit))
+            })
+            .collect()
+    }
+
+    fn write_todo(&mut self, todo: &[(Action, Oid<Commit>)]) -> Result<(), SequencerError> {
+        let mut contents = BString::default();
+        for &(action, commit) in todo {
+            let subject = self.db.load(commit)?.msg;
+            let subject = subject.lines().next().unwrap_or_default();
+            contents
+                .extend_from_slice(format!("{} {} ", action.name(), commit.to_hex()).as_bytes());
+            contents.extend_from_slice(subject);
+            contents.push(b'\n');
+        }
+        let path = self.sequencer_dir().join("todo");
+        fs::write(&path, contents).map_err(|e| SequencerError::Write(path, e))
+    }
+
+    fn end_sequence(&self) -> Result<(), SequencerError> {
+        let dir = self.sequencer_dir();
+        fs::remove_dir_all(&dir).map_err(|e| SequencerError::Write(dir, e))
+    }
+}
+
+enum Applied {
+    Commit(Oid<Commit>),
+    /// It changed nothing
+    Empty,
+    Conflicts(Vec<WsPath>),
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum SequencerError {
+    /// {0}
+    Bare(#[from] BareError),
+    /// Cannot cherry-pick or revert without a commit to apply onto
+    NoHead,
+    /// A cherry-pick or revert is already in progress
+    InProgress,
+    /// No cherry-pick or revert is in progress
+    NotInProgress,
+    /// Cannot cherry-pick or revert with unmerged paths in the index
+    Unmerged,
+    /// Cannot cherry-pick or revert with changes staged
+    Staged,
+    /// Commit {0} is a merge, which can't be cherry-picked or reverted
+    Merge(Oid<Commit>),
+    /// Failed to read {0:?}
+    Read(PathBuf, #[source] io::Error),
+    /// Failed to write {0:?}
+    Write(PathBuf, #[source] io::Error),
+    /// Sequencer state {0:?} is corrupt
+    Corrupt(PathBuf),
+    /// Failed to walk commits
+    RevWalk(#[from] RevWalkError),
+    /// Failed to read ref
+    ReadRef(#[from] refs::ReadError),
+    /// Failed to update ref
+    UpdateRef(#[from] refs::UpdateError),
+    /// Failed to load commit
+    LoadCommit(#[from] db::LoadError<Commit>),
+    /// Failed to load tree
+    LoadTree(#[from] db::LoadError<Tree>),
+    /// Failed to store tree
+    StoreTree(#[from] db::StoreError<Tree>),
+    /// Failed to store commit
+    StoreCommit(#[from] db::StoreError<Commit>),
+    /// Invalid config
+    Config(#[from] config::ValueError),
+    /// {0}
+    Identity(#[from] signature::IdentityError),
+    /// Failed to merge
+    MergeTrees(#[from] MergeError),
+    /// Failed to read commit message
+    Message(#[from] commit_msg::CommitMessageError),
+    /// Failed to commit resolution
+    Commit(#[from] CommitError),
+    /// Failed to reload index
+    ReloadIndex(#[from] index::LoadError),
+    /// Failed to open index for modifications
+    OpenIndex(#[from] index::OpenForModificationsError),
+    /// Failed to write index
+    CommitIndex(#[from] index::CommitError),
+    /// Failed to load attributes
+    LoadAttributes(#[from] attributes::LoadError),
+    /// Failed to load sparse checkout patterns
+    LoadSparse(#[from] sparse::LoadError),
+    /// Failed to fetch missing blobs
+    FetchPromised(#[from] fetch::FetchError),
+    /// Failed to update workspace
+    Apply(#[from] migration::ApplyError),
+}
@@ -0,0 +1,132 @@
+//! Helpers for the `serde` feature, for fields whose types don't serialize
+//! the way we want, used with `#[serde(with = "..")]`
+
+/// Bytes that are usually text, like paths and messages. Human-readable
+/// formats get a string, lossily converted if the bytes aren't UTF-8, and
+/// binary formats get the bytes as they are. Either is accepted back.
+pub(crate) mod bytes {
+    use std::fmt;
+
+    use bstr::{BString, ByteSlice};
+    use serde::{de, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        bytes: impl AsRef<[u8]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let bytes = bytes.as_ref();
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&bytes.to_str_lossy())
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>, T: From<BString>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        deserializer.deserialize_byte_buf(Visitor).map(T::from)
+    }
+
+    struct Visitor;
+
+    impl<'de> de::Visitor<'de> for Visitor {
+        type Value = BString;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(v.into())
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(v.into())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v.into())
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes.into())
+        }
+    }
+}
+
+/// Times in git's internal format, `<seconds> <offset>`, which keeps the
+/// offset the time was recorded in
+pub(crate) mod time {
+    use chrono::{DateTime, FixedOffset};
+    use serde::{de, Deserializer, Serializer};
+
+    const FORMAT: &str = "%s %z";
+
+    pub(crate) fn serialize<S: Serializer>(
+        time: &DateTime<FixedOffset>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&time.format(FORMAT))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<FixedOffset>, D::Error> {
+        let time: String = serde::Deserialize::deserialize(deserializer)?;
+        DateTime::parse_from_str(&time, FORMAT).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::core::{
+        db::{Commit, Signature},
+        index::Entry,
+        Oid, Stat, WsPath,
+    };
+
+    #[test]
+    fn paths_are_lossy_strings() -> eyre::Result<()> {
+        let path = WsPath::new_unchecked_bytes(&b"dir/caf\xe9"[..]);
+        let json = serde_json::to_string(&path)?;
+        assert_eq!("\"dir/caf\u{fffd}\"", json);
+        let parsed: WsPath = serde_json::from_str("\"dir/a.txt\"")?;
+        assert_eq!(WsPath::new_unchecked("dir/a.txt"), parsed);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_entries_and_commits() -> eyre::Result<()> {
+        let oid = Oid::parse("ce013625030ba8dba906f756967f9e9ca394464a")?;
+        let entry = Entry::new(WsPath::new_unchecked("a.txt"), oid, Stat::zeroed());
+        let json = serde_json::to_string(&entry)?;
+        assert!(json.contains(r#""oid":"ce013625030ba8dba906f756967f9e9ca394464a""#));
+        let parsed: Entry = serde_json::from_str(&json)?;
+        assert_eq!(entry, parsed);
+
+        let time = chrono::DateTime::parse_from_rfc3339("2021-05-01T12:00:00+02:00")?;
+        let signature = Signature::new("A", "a@example.com", time);
+        let commit = Commit {
+            oid: Oid::parse("4b825dc642cb6eb9a060e54bf8d69288fbee4904")?,
+            parent: None,
+            merged: Vec::new(),
+            tree: Oid::parse("4b825dc642cb6eb9a060e54bf8d69288fbee4904")?,
+            author: signature.clone(),
+            committer: signature,
+            msg: "Message\n".into(),
+        };
+        let json = serde_json::to_string(&commit)?;
+        assert!(json.contains(r#""time":"1619863200 +0200""#));
+        assert!(json.contains(r#""msg":"Message\n""#));
+        let parsed: Commit = serde_json::from_str(&json)?;
+        assert_eq!(commit, parsed);
+        Ok(())
+    }
+}
@@ -0,0 +1,443 @@
+//! Serving pushes to clients, as `git receive-pack` does, see
+//! <https://git-scm.com/docs/pack-protocol#_pushing_data_to_a_server>
+
+use std::io::{self, BufRead, Write};
+
+use bstr::{BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use crate::core::{
+    config,
+    db::{Commit, UntypedOid},
+    pack, push, refs,
+    revwalk::{self, RevWalkError},
+    transport::{
+        pkt_line::{self, Packet, MAX_DATA_LEN},
+        receive_pack::RefUpdate,
+        split_once, AGENT,
+    },
+    Oid, Repo,
+};
+
+/// What we can do for clients, advertised after the first ref
+const CAPABILITIES: &str = "report-status delete-refs side-band-64k ofs-delta atomic";
+
+impl Repo {
+    /// Like the first reply of `git receive-pack`: all our refs, with our
+    /// capabilities
+    #[instrument(err, skip(out))]
+    pub fn advertise_receive_pack(&self, out: &mut impl Write) -> Result<(), ServeError> {
+        let refs = self.refs.list(b"refs/".as_bstr())?;
+        let caps = format!("{CAPABILITIES} agent={AGENT}");
+        let mut writer = pkt_line::Writer::new(out);
+        if refs.is_empty() {
+            let zero = UntypedOid::zero().to_hex();
+            writer.write_line(format!("{zero} capabilities^{{}}\0{caps}"))?;
+        }
+        for (i, (name, oid)) in refs.iter().enumerate() {
+            let mut line = format!("{} {name}", oid.to_hex()).into_bytes();
+            if i == 0 {
+                line.push(b'\0');
+                line.extend_from_slice(caps.as_bytes());
+            }
+            writer.write_line(line)?;
+        }
+        writer.write_flush()?;
+        Ok(())
+    }
+
+    /// Applies a push sent after the advertisement: commands to change refs,
+    /// then a pack if any are set. Each ref is only changed if it's still
+    /// what the client saw and we have everything it needs, and the client is
+    /// told which were if it asks for a report. If it asks for an atomic
+    /// push, either every ref is changed or none are.
+    #[instrument(err, skip(input, out))]
+    pub fn receive_pack(
+        &self,
+        input: &mut impl BufRead,
+        out: &mut impl Write,
+    ) -> Result<(), ServeError> {
+        let (lines, end) = pkt_line::Reader::new(&mut *input).read_lines()?;
+        if end != Packet::Flush {
+            return Err(pkt_line::ReadError::UnexpectedPacket(end).into());
+        }
+        let (updates, caps) = parse_commands(&lines)?;
+        // The client had nothing to push
+        if updates.is_empty() {
+            return Ok(());
+        }
+        let existing = self
+            .refs
+            .list(b"refs/".as_bstr())?
+            .into_iter()
+            .map(|(_, oid)| oid.into_untyped())
+            .collect::<Vec<_>>();
+
+        let unpacked = if updates
+            .iter()
+            .all(|update| update.new == UntypedOid::zero())
+        {
+            Ok(())
+        } else {
+            pack::read(input)
+                .and_then(|pack| pack::unpack(&self.db, &pack, &mut ()))
+                .map(|stored| debug!(stored = stored.len(), "Unpacked"))
+        };
+
+        let mut report = pkt_line::Writer::new(Vec::new());
+        match &unpacked {
+            Ok(()) => report.write_line("unpack ok")?,
+            Err(err) => report.write_line(format!("unpack {err}"))?,
+        }
+        let refused = match &unpacked {
+            Ok(()) if caps.iter().any(|cap| cap == "atomic") => {
+                self.receive_atomic(&updates, &existing)?
+            }
+            Ok(()) => updates
+                .iter()
+                .map(|update| self.receive_update(update, &existing))
+                .collect::<Result<_, _>>()?,
+            Err(_) => vec![Some("unpacker error"); updates.len()],
+        };
+        for (update, refused) in updates.iter().zip(refused) {
+            match refused {
+                Some(reason) => report.write_line(format!("ng {} {reason}", update.name))?,
+                None => report.write_line(format!("ok {}", update.name))?,
+            }
+        }
+        report.write_flush()?;
+
+        let sideband = caps.iter().any(|cap| cap == "side-band-64k");
+        let report = if caps.iter().any(|cap| cap == "report-status") {
+            report.into_inner()
+        } else {
+            Vec::new()
+        };
+        if sideband {
+            // The report goes in band 1, as packets of its own
+            let mut writer = pkt_line::Writer::new(&mut *out);
+            for chunk in report.chunks(MAX_DATA_LEN - 1) {
+                writer.write_data(&[&[1], chunk].concat())?;
+            }
+            writer.write_flush()?;
+        } else {
+            out.write_all(&report)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Both halves, over a connection that stays open (like `git
+    /// receive-pack` run over SSH)
+    pub fn serve_receive_pack(
+        &self,
+        input: &mut impl BufRead,
+        out: &mut impl Write,
+    ) -> Result<(), ServeError> {
+        self.advertise_receive_pack(out)?;
+        out.flush()?;
+        self.receive_pack(input, out)
+    }
+
+    /// Why the update is refused, if it is. Otherwise it's made.
+    fn receive_update(
+        &self,
+        update: &RefUpdate,
+        existing: &[UntypedOid],
+    ) -> Result<Option<&'static str>, ServeError> {
+        if let Some(reason) = self.check_update(update, existing)? {
+            return Ok(Some(reason));
+        }
+        let mut transaction = self.refs.transaction();
+        let (old, new) = values(update);
+        transaction.update(update.name.as_bstr(), old, new);
+        match transaction.commit() {
+            Ok(()) => Ok(None),
+            Err(err) => {
+                debug!(%err, "Failed to update");
+                Ok(Some("failed to update ref"))
+            }
+        }
+    }
+
+    /// Why each update is refused, if any are, in which case none are made
+    fn receive_atomic(
+        &self,
+        updates: &[RefUpdate],
+        existing: &[UntypedOid],
+    ) -> Result<Vec<Option<&'static str>>, ServeError> {
+        let mut refused = updates
+            .iter()
+            .map(|update| self.check_update(update, existing))
+            .collect::<Result<Vec<_>, _>>()?;
+        if refused.iter().any(Option::is_some) {
+            for refused in &mut refused {
+                refused.get_or_insert(push::ATOMIC_FAILURE);
+            }
+            return Ok(refused);
+        }
+
+        let mut transaction = self.refs.transaction();
+        for update in updates {
+            let (old, new) = values(update);
+            transaction.update(update.name.as_bstr(), old, new);
+        }
+        if let Err(err) = transaction.commit() {
+            // Like git, we don't say which ref it was
+            debug!(%err, "Failed to update");
+            return Ok(vec![Some("atomic transaction failed"); updates.len()]);
+        }
+        Ok(refused)
+    }
+
+    /// Why the update would be refused, if it would be
+    fn check_update(
+        &self,
+        update: &RefUpdate,
+        existing: &[UntypedOid],
+    ) -> Result<Option<&'static str>, ServeError> {
+        let name = update.name.as_bstr();
+        let old = Some(update.old).filter(|&old| old != UntypedOid::zero());
+        let new = Some(update.new).filter(|&new| new != UntypedOid::zero());
+
+        if !refs::is_valid_name(name) {
+            return Ok(Some("funny refname"));
+        }
+        if new.is_none() && self.config.get_bool("receive.denyDeletes")? == Some(true) {
+            return Ok(Some("deletion prohibited"));
+        }
+        let current = self.refs.read_symbolic(b"HEAD".as_bstr())?;
+        if !self.is_bare()
+            && current.as_ref().is_some_and(|current| current == name)
+            && self.denies_current_branch()
+        {
+            return Ok(Some("branch is currently checked out"));
+        }
+        if let Some(new) = new {
+            let missing =
+                push::missing_objects(&self.db, &[new], existing.iter().copied(), &mut ());
+            if let Err(err) = missing {
+                debug!(%err, "Not connected");
+                return Ok(Some("missing necessary objects"));
+            }
+        }
+        if let (Some(old), Some(new)) = (old, new) {
+            let deny = self.config.get_bool("receive.denyNonFastForwards")? == Some(true);
+            if deny && !revwalk::is_ancestor(&self.db, old, new)? {
+                return Ok(Some("non-fast-forward"));
+            }
+        }
+        Ok(None)
+    }
+
+    /// `receive.denyCurrentBranch`, which is `refuse` by default. We can't
+    /// `updateInstead`, so we refuse then too.
+    fn denies_current_branch(&self) -> bool {
+        !matches!(
+            self.config.get("receive.denyCurrentBranch"),
+            Some("ignore" | "warn" | "false" | "no" | "off" | "0")
+        )
+    }
+}
+
+/// The updates and the client's capabilities, which follow a NUL in the
+/// first, like `<old> <new> refs/heads/main\0report-status`
+fn parse_commands(lines: &[BString]) -> Result<(Vec<RefUpdate>, Vec<BString>), ServeError> {
+    let mut updates = Vec::new();
+    let mut caps = Vec::new();
+    for line in lines {
+        // Sent by shallow clients, but we have everything they do
+        if line.starts_with(b"shallow ") {
+            continue;
+        }
+        let line = match split_once(line, b'\0') {
+            Some((line, line_caps)) => {
+                caps = line_caps.fields().map(BString::from).collect();
+                line
+            }
+            None => line.as_bytes(),
+        };
+        let invalid = || ServeError::InvalidCommand(line.as_bstr().to_owned());
+        let mut fields = line.splitn_str(3, " ");
+        let mut oid = || UntypedOid::parse(fields.next()?).ok();
+        let (old, new) = oid().zip(oid()).ok_or_else(invalid)?;
+        let name = fields.next().ok_or_else(invalid)?;
+        updates.push(RefUpdate {
+            name: name.into(),
+            old,
+            new,
+        });
+    }
+    Ok((updates, caps))
+}
+
+/// What the ref is expected to be and what it's set to, where `None` is
+/// missing
+fn values(update: &RefUpdate) -> (Option<Oid<Commit>>, Option<Oid<Commit>>) {
+    let value = |oid: UntypedOid| (oid != UntypedOid::zero()).then(|| oid.to_typed());
+    (value(update.old), value(update.new))
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ServeError {
+    /// Failed to read refs
+    ReadRefs(#[from] refs::ReadError),
+    /// Invalid request from client
+    Read(#[from] pkt_line::ReadError),
+    /// Invalid command from client: {0:?}
+    InvalidCommand(BString),
+    /// Invalid config
+    Config(#[from] config::ValueError),
+    /// Failed to check history
+    Walk(#[from] RevWalkError),
+    /// Failed to reply to client
+    Write(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{db::Db, transport::canned::packets};
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn commit(db: &Db, parent: Option<UntypedOid>) -> eyre::Result<UntypedOid> {
+        let tree = db.store_raw(b"tree", b"")?;
+        let parent = parent
+            .map(|parent| format!("parent {}\n", parent.to_hex()))
+            .unwrap_or_default();
+        let data = format!(
+            "tree {}\n{parent}author A <a@b> 0 +0000\ncommitter C <c@d> 0 +0000\n\nMsg\n",
+            tree.to_hex()
+        );
+        Ok(db.store_raw(b"commit", data.as_bytes())?)
+    }
+
+    #[test]
+    fn receives_pushes() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let repo = Repo::init_bare(dir.path().join("dst"))?;
+        let src = tempdir()?;
+        std::fs::create_dir(src.path().join("objects"))?;
+        let src = Db::new(src.path());
+        let root = commit(&src, None)?;
+        let child = commit(&src, Some(root))?;
+
+        let mut advertisement = Vec::new();
+        repo.advertise_receive_pack(&mut advertisement)?;
+        let (lines, _) = pkt_line::Reader::new(advertisement.as_slice()).read_lines()?;
+        assert!(lines[0].starts_with(b"0000000000000000000000000000000000000000 capabilities^{}\0"));
+
+        let zero = UntypedOid::zero().to_hex();
+        let (root_hex, child_hex) = (root.to_hex(), child.to_hex());
+        let request = packets(&[
+            &format!("{zero} {root_hex} refs/heads/main\0report-status side-band-64k"),
+            &format!("{zero} {child_hex} refs/heads/../main"),
+            // Stale, as it doesn't exist yet
+            &format!("{root_hex} {child_hex} refs/heads/other"),
+            "0000",
+        ]);
+        let objects = push::missing_objects(&src, &[child], None, &mut ())?;
+        let request = [request, pack_of(&src, &objects)?].concat();
+
+        let mut response = Vec::new();
+        repo.receive_pack(&mut request.as_slice(), &mut response)?;
+        let mut report = Vec::new();
+        pkt_line::Reader::new(response.as_slice()).demux(&mut report, &mut ())?;
+        let (report, _) = pkt_line::Reader::new(report.as_slice()).read_lines()?;
+        assert_eq!(
+            vec![
+                BString::from("unpack ok"),
+                BString::from("ok refs/heads/main"),
+                BString::from("ng refs/heads/../main funny refname"),
+                BString::from("ng refs/heads/other failed to update ref"),
+            ],
+            report
+        );
+        assert_eq!(
+            Some(root.to_typed()),
+            repo.refs.read_ref(b"refs/heads/main".as_bstr())?
+        );
+        assert_eq!(None, repo.refs.read_ref(b"refs/heads/other".as_bstr())?);
+
+        // Without the objects it needs
+        let missing = UntypedOid::parse("7".repeat(40))?;
+        let request = packets(&[
+            &format!(
+                "{root_hex} {} refs/heads/main\0report-status",
+                missing.to_hex()
+            ),
+            "0000",
+        ]);
+        let request = [request, pack_of(&src, &[])?].concat();
+        let mut response = Vec::new();
+        repo.receive_pack(&mut request.as_slice(), &mut response)?;
+        let (report, _) = pkt_line::Reader::new(response.as_slice()).read_lines()?;
+        assert_eq!(
+            BString::from("ng refs/heads/main missing necessary objects"),
+            report[1]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn receives_atomic_pushes() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let repo = Repo::init_bare(dir.path().join("dst"))?;
+        let src = tempdir()?;
+        std::fs::create_dir(src.path().join("objects"))?;
+        let src = Db::new(src.path());
+        let root = commit(&src, None)?;
+        let objects = push::missing_objects(&src, &[root], None, &mut ())?;
+
+        let zero = UntypedOid::zero().to_hex();
+        let root_hex = root.to_hex();
+        let request = packets(&[
+            &format!("{zero} {root_hex} refs/heads/main\0report-status atomic"),
+            &format!("{zero} {root_hex} refs/heads/../main"),
+            "0000",
+        ]);
+        let request = [request, pack_of(&src, &objects)?].concat();
+        let mut response = Vec::new();
+        repo.receive_pack(&mut request.as_slice(), &mut response)?;
+        let (report, _) = pkt_line::Reader::new(response.as_slice()).read_lines()?;
+        assert_eq!(
+            vec![
+                BString::from("unpack ok"),
+                BString::from("ng refs/heads/main atomic push failure"),
+                BString::from("ng refs/heads/../main funny refname"),
+            ],
+            report
+        );
+        assert_eq!(None, repo.refs.read_ref(b"refs/heads/main".as_bstr())?);
+
+        // Stale, as it doesn't exist
+        let request = packets(&[
+            &format!("{zero} {root_hex} refs/heads/main\0report-status atomic"),
+            &format!("{root_hex} {zero} refs/heads/other"),
+            "0000",
+        ]);
+        let request = [request, pack_of(&src, &[])?].concat();
+        let mut response = Vec::new();
+        repo.receive_pack(&mut request.as_slice(), &mut response)?;
+        let (report, _) = pkt_line::Reader::new(response.as_slice()).read_lines()?;
+        assert_eq!(
+            BString::from("ng refs/heads/main atomic transaction failed"),
+            report[1]
+        );
+        assert_eq!(None, repo.refs.read_ref(b"refs/heads/main".as_bstr())?);
+        Ok(())
+    }
+
+    fn pack_of(db: &Db, objects: &[UntypedOid]) -> eyre::Result<Vec<u8>> {
+        let mut pack = Vec::new();
+        pack::write(
+            db,
+            objects,
+            &pack::PackOptions::default(),
+            &mut pack,
+            &mut (),
+        )?;
+        Ok(pack)
+    }
+}
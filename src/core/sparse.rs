@@ -0,0 +1,230 @@
+//! Cone mode sparse checkout patterns, as stored in
+//! `.git/info/sparse-checkout`.
+
+use std::{
+    collections::BTreeSet,
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::core::{locked_file, LockedFile, WsPath};
+
+/// The directories to check out recursively. Files directly within the root
+/// or any parent of one of the directories are checked out too.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Cone {
+    dirs: BTreeSet<WsPath>,
+}
+
+impl Cone {
+    /// Directories within another directory given are dropped
+    pub fn new(dirs: impl IntoIterator<Item = WsPath>) -> Self {
+        let dirs = dirs.into_iter().collect::<Vec<_>>();
+        let dirs = WsPath::minimal_prefixes(&dirs)
+            .into_iter()
+            .filter(|dir| **dir != WsPath::root())
+            .cloned()
+            .collect();
+        Self { dirs }
+    }
+
+    pub fn dirs(&self) -> impl Iterator<Item = &WsPath> {
+        self.dirs.iter()
+    }
+
+    /// `None` if the file doesn't exist
+    pub fn load(git_dir: impl AsRef<Path>) -> Result<Option<Self>, LoadError> {
+        let path = Self::file_path(git_dir);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(LoadError::Read(path, err)),
+        };
+        Ok(Some(Self::parse(&contents)?))
+    }
+
+    pub fn save(&self, git_dir: impl AsRef<Path>) -> Result<(), SaveError> {
+        let path = Self::file_path(git_dir);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| SaveError::Io(path.clone(), e))?;
+        }
+        let mut lock = LockedFile::acquire(&path)?;
+        lock.write_all(self.to_patterns().as_bytes())
+            .map_err(|e| SaveError::Io(path.clone(), e))?;
+        lock.commit().map_err(|e| SaveError::Io(path, e))?;
+        Ok(())
+    }
+
+    pub fn contains(&self, path: &WsPath) -> bool {
+        let dir = path.parent();
+        dir == WsPath::root()
+            || self
+                .dirs
+                .iter()
+                .any(|cone| dir.is_within(cone) || cone.is_within(&dir))
+    }
+
+    /// Every directory whose immediate files are included (but not its
+    /// subdirectories), excluding the root
+    fn parents(&self) -> BTreeSet<WsPath> {
+        self.dirs
+            .iter()
+            .flat_map(WsPath::parents)
+            .filter(|parent| !self.dirs.contains(parent))
+            .collect()
+    }
+
+    fn to_patterns(&self) -> String {
+        let mut out = String::from("/*\n!/*/\n");
+        let parents = self.parents();
+        let all = parents
+            .iter()
+            .chain(self.dirs.iter())
+            .collect::<BTreeSet<_>>();
+        for dir in all {
+            let escaped = escape(&dir.to_string());
+            writeln!(out, "/{escaped}/").expect("Writing to a string");
+            if parents.contains(dir) {
+                writeln!(out, "!/{escaped}/*/").expect("Writing to a string");
+            }
+        }
+        out
+    }
+
+    fn parse(contents: &str) -> Result<Self, ParseError> {
+        let mut included = BTreeSet::new();
+        let mut parents = BTreeSet::new();
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') || line == "/*" || line == "!/*/" {
+                continue;
+            }
+            let dir = if let Some(parent) = line
+                .strip_prefix("!/")
+                .and_then(|line| line.strip_suffix("/*/"))
+            {
+                parents.insert(unescape(parent, n + 1)?);
+                continue;
+            } else if let Some(dir) = line
+                .strip_prefix('/')
+                .and_then(|line| line.strip_suffix('/'))
+            {
+                dir
+            } else {
+                return Err(ParseError::NotCone(n + 1));
+            };
+            included.insert(unescape(dir, n + 1)?);
+        }
+
+        let dirs = included
+            .into_iter()
+            .filter(|dir| !parents.contains(dir))
+            .map(WsPath::new_unchecked);
+        Ok(Self::new(dirs))
+    }
+
+    fn file_path(git_dir: impl AsRef<Path>) -> PathBuf {
+        git_dir.as_ref().join("info/sparse-checkout")
+    }
+}
+
+/// Git treats the patterns as globs even in cone mode
+fn escape(dir: &str) -> String {
+    let mut out = String::with_capacity(dir.len());
+    for c in dir.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn unescape(pattern: &str, line: usize) -> Result<String, ParseError> {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push(chars.next().ok_or(ParseError::NotCone(line))?),
+            '*' | '?' | '[' => return Err(ParseError::NotCone(line)),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum LoadError {
+    /// Failed to read sparse checkout patterns from {0:?}
+    Read(PathBuf, #[source] io::Error),
+    /// Failed to parse sparse checkout patterns
+    Parse(#[from] ParseError),
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error, Eq, PartialEq)]
+pub enum ParseError {
+    /// Line {0} isn't a cone mode pattern
+    NotCone(usize),
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum SaveError {
+    /// Failed to lock sparse checkout patterns
+    Lock(#[from] locked_file::Error),
+    /// Failed to write sparse checkout patterns to {0:?}
+    Io(PathBuf, #[source] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn cone(dirs: &[&str]) -> Cone {
+        Cone::new(dirs.iter().copied().map(WsPath::new_unchecked))
+    }
+
+    fn contains(cone: &Cone, path: &str) -> bool {
+        cone.contains(&WsPath::new_unchecked(path))
+    }
+
+    #[test]
+    fn includes_cone_and_parent_files() {
+        let cone = cone(&["a/b", "a/b/c", "d"]);
+        assert!(contains(&cone, "top.txt"));
+        assert!(contains(&cone, "a/file.txt"));
+        assert!(contains(&cone, "a/b/file.txt"));
+        assert!(contains(&cone, "a/b/deep/er/file.txt"));
+        assert!(contains(&cone, "d/e/file.txt"));
+        assert!(!contains(&cone, "a/other/file.txt"));
+        assert!(!contains(&cone, "ab/file.txt"));
+        assert!(!contains(&cone, "e/file.txt"));
+    }
+
+    #[test]
+    fn writes_git_patterns() {
+        let cone = cone(&["a/b", "a/b/c", "d", "x*"]);
+        assert_eq!(
+            "/*\n!/*/\n/a/\n!/a/*/\n/a/b/\n/d/\n/x\\*/\n",
+            cone.to_patterns()
+        );
+    }
+
+    #[test]
+    fn round_trips_patterns() -> eyre::Result<()> {
+        let cone = cone(&["a/b/c", "d", "x*", "a/e"]);
+        assert_eq!(cone, Cone::parse(&cone.to_patterns())?);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_non_cone_patterns() {
+        assert_eq!(
+            Err(ParseError::NotCone(3)),
+            Cone::parse("/*\n!/*/\n*.txt\n")
+        );
+        assert_eq!(Err(ParseError::NotCone(1)), Cone::parse("/a*/\n"));
+    }
+}
@@ -1,14 +1,15 @@
 use std::{
-    convert::TryInto,
     fs,
-    os::linux::fs::MetadataExt,
     time::{Duration, SystemTime},
 };
 use tracing::warn;
 
 use bstr::{BStr, ByteSlice};
 
+use crate::core::platform;
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Stat {
     pub ctime: SystemTime,
     pub mtime: SystemTime,
@@ -21,27 +22,36 @@ pub struct Stat {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum Mode {
     Regular,
     Executable,
+    Symlink,
+    /// A commit in another repository, used for submodules
+    Gitlink,
 }
 
 impl Stat {
-    #[allow(
-        clippy::cast_possible_truncation,
-        clippy::cast_sign_loss,
-        clippy::similar_names
-    )]
+    /// Like git, fields too big for the index are truncated, except for
+    /// times, which are only truncated as the index is written (see
+    /// [`Self::mtime_epoch`]). On Windows there's no `dev`, `ino`, `uid` or
+    /// `gid`, so they're zero.
+    #[allow(clippy::cast_possible_truncation)]
     pub fn from(meta: &fs::Metadata) -> Self {
+        let meta = platform::metadata(meta);
         Self {
-            ctime: Self::systemtime_from_epoch(meta.st_ctime() as u32, meta.st_ctime_nsec() as u32),
-            mtime: Self::systemtime_from_epoch(meta.st_mtime() as u32, meta.st_mtime_nsec() as u32),
-            dev: meta.st_dev() as u32,
-            ino: meta.st_ino() as u32,
-            mode: Mode::from_u32(meta.st_mode()),
-            uid: meta.st_uid(),
-            gid: meta.st_gid(),
-            size: meta.st_size() as u32,
+            ctime: meta.ctime,
+            mtime: meta.mtime,
+            dev: meta.dev as u32,
+            ino: meta.ino as u32,
+            mode: Mode::from_u32(meta.mode),
+            uid: meta.uid,
+            gid: meta.gid,
+            size: meta.size as u32,
         }
     }
 
@@ -58,22 +68,40 @@ impl Stat {
         }
     }
 
+    /// Seconds and nanoseconds since the epoch, as the index stores them.
+    /// Like git, the seconds are truncated to 32 bits, so times before 1970
+    /// or after 2106 wrap around.
     pub fn ctime_epoch(&self) -> (u32, u32) {
         Self::systemtime_to_epoch(self.ctime)
     }
 
+    /// Like [`Self::ctime_epoch`]
     pub fn mtime_epoch(&self) -> (u32, u32) {
         Self::systemtime_to_epoch(self.mtime)
     }
 
-    fn systemtime_to_epoch(time: SystemTime) -> (u32, u32) {
-        let dur = time
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("Not before epoch");
-
-        let secs: u32 = dur.as_secs().try_into().expect("Time overflowed");
+    /// Whether the times are the same once truncated for the index, as one
+    /// of the stats usually came from it
+    pub fn times_match(&self, other: &Self) -> bool {
+        self.mtime_epoch() == other.mtime_epoch() && self.ctime_epoch() == other.ctime_epoch()
+    }
 
-        (secs, dur.subsec_nanos())
+    #[allow(clippy::cast_possible_truncation)]
+    fn systemtime_to_epoch(time: SystemTime) -> (u32, u32) {
+        match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(dur) => (dur.as_secs() as u32, dur.subsec_nanos()),
+            // Negative seconds, with nanoseconds still counting forwards
+            Err(err) => {
+                let before = err.duration();
+                let mut secs = 0_u64.wrapping_sub(before.as_secs());
+                let mut nanos = before.subsec_nanos();
+                if nanos > 0 {
+                    secs = secs.wrapping_sub(1);
+                    nanos = 1_000_000_000 - nanos;
+                }
+                (secs as u32, nanos)
+            }
+        }
     }
 
     pub(crate) fn systemtime_from_epoch(secs: u32, nanos: u32) -> SystemTime {
@@ -84,14 +112,21 @@ impl Stat {
 impl Mode {
     const EXECUTABLE: u32 = 0o10_07_55;
     const REGULAR: u32 = 0o10_06_44;
+    const SYMLINK: u32 = 0o12_00_00;
+    const GITLINK: u32 = 0o16_00_00;
+    const TYPE_MASK: u32 = 0o17_00_00;
 
     const REGULAR_S: &'static [u8] = b"100644";
     const EXECUTABLE_S: &'static [u8] = b"100755";
+    const SYMLINK_S: &'static [u8] = b"120000";
+    const GITLINK_S: &'static [u8] = b"160000";
 
     pub fn as_base8(self) -> &'static BStr {
         match self {
             Self::Regular => Self::REGULAR_S.as_bstr(),
             Self::Executable => Self::EXECUTABLE_S.as_bstr(),
+            Self::Symlink => Self::SYMLINK_S.as_bstr(),
+            Self::Gitlink => Self::GITLINK_S.as_bstr(),
         }
     }
 
@@ -99,15 +134,17 @@ impl Mode {
         match self {
             Self::Regular => Self::REGULAR,
             Self::Executable => Self::EXECUTABLE,
+            Self::Symlink => Self::SYMLINK,
+            Self::Gitlink => Self::GITLINK,
         }
     }
 
     pub fn from_u32(val: u32) -> Self {
-        let is_executable = val & 0o111 != 0;
-        if is_executable {
-            Self::Executable
-        } else {
-            Self::Regular
+        match val & Self::TYPE_MASK {
+            Self::SYMLINK => Self::Symlink,
+            Self::GITLINK => Self::Gitlink,
+            _ if val & 0o111 != 0 => Self::Executable,
+            _ => Self::Regular,
         }
     }
 
@@ -116,10 +153,72 @@ impl Mode {
         match bytes.as_bytes() {
             Self::REGULAR_S => Self::Regular,
             Self::EXECUTABLE_S => Self::Executable,
+            Self::SYMLINK_S => Self::Symlink,
+            Self::GITLINK_S => Self::Gitlink,
             _ => {
                 warn!("Assuming unrecognized mode {} to be regular", bytes);
                 Self::Regular
             }
         }
     }
+
+    /// If both are regular files (executable or not), symlinks, or gitlinks.
+    /// A change between types is reported as a typechange rather than a
+    /// modification.
+    pub fn is_same_type(self, other: Self) -> bool {
+        let is_file = |mode| matches!(mode, Self::Regular | Self::Executable);
+        self == other || (is_file(self) && is_file(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn mode_round_trips() {
+        for mode in [
+            Mode::Regular,
+            Mode::Executable,
+            Mode::Symlink,
+            Mode::Gitlink,
+        ] {
+            assert_eq!(mode, Mode::from_u32(mode.as_u32()));
+            assert_eq!(mode, Mode::from_base8(mode.as_base8()));
+        }
+    }
+
+    #[test]
+    fn mode_from_st_mode() {
+        assert_eq!(Mode::Regular, Mode::from_u32(0o100_664));
+        assert_eq!(Mode::Executable, Mode::from_u32(0o100_700));
+        assert_eq!(Mode::Symlink, Mode::from_u32(0o120_777));
+    }
+
+    #[test]
+    fn mode_types() {
+        assert!(Mode::Regular.is_same_type(Mode::Executable));
+        assert!(Mode::Symlink.is_same_type(Mode::Symlink));
+        assert!(!Mode::Regular.is_same_type(Mode::Symlink));
+        assert!(!Mode::Executable.is_same_type(Mode::Gitlink));
+    }
+
+    #[test]
+    fn times_outside_index_range_are_truncated() {
+        let mut stat = Stat::zeroed();
+        assert_eq!((0, 0), stat.mtime_epoch());
+        stat.mtime = SystemTime::UNIX_EPOCH - Duration::from_millis(1500);
+        assert_eq!((u32::MAX - 1, 500_000_000), stat.mtime_epoch());
+        stat.ctime = SystemTime::UNIX_EPOCH + Duration::from_secs((1 << 32) + 5);
+        assert_eq!((5, 0), stat.ctime_epoch());
+
+        let (secs, nanos) = stat.ctime_epoch();
+        let read = Stat {
+            ctime: Stat::systemtime_from_epoch(secs, nanos),
+            ..stat
+        };
+        assert!(stat.times_match(&read));
+        assert_ne!(stat, read);
+    }
 }
@@ -1,6 +1,7 @@
-use crate::core::WsPath;
+use crate::core::{cancel::CancelToken, db::Blob, Oid, WsPath};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[allow(clippy::module_name_repetitions)]
 pub struct FileStatus {
     pub path: WsPath,
@@ -8,23 +9,155 @@ pub struct FileStatus {
     pub workspace: Status,
 }
 
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[allow(clippy::module_name_repetitions)]
+pub struct StatusOptions {
+    /// Also report untracked files and directories that are ignored, as
+    /// [`Status::Ignored`]. Ignored directories are reported as the directory
+    /// itself, without listing their contents (like git's
+    /// `--ignored=matching`).
+    pub ignored: bool,
+    /// Checked as files are listed and compared
+    pub cancel: CancelToken,
+}
+
+/// With the `serde` feature this serializes as the snake case name of the
+/// variant (e.g. `"untracked"`), except for `Conflicted`, which is
+/// `{"conflicted": {"base": .., "ours": .., "theirs": ..}}` with each side a
+/// hex oid or `null`.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum Status {
     Untracked,
+    /// Only reported when requested with [`StatusOptions::ignored`]
+    Ignored,
     Modified,
+    /// The path changed between a regular file, a symlink, and a gitlink
+    TypeChanged,
     Unmodified,
     Deleted,
     Added,
+    /// The path is unmerged. Each side is the blob recorded in the index for
+    /// that stage, if the side has the path. Both the index and workspace
+    /// status of an unmerged path are the same `Conflicted`.
+    Conflicted {
+        base: Option<Oid<Blob>>,
+        ours: Option<Oid<Blob>>,
+        theirs: Option<Oid<Blob>>,
+    },
+}
+
+impl FileStatus {
+    /// The two-letter code used by `git status --short`
+    pub fn short_code(&self) -> String {
+        if let Some(code) = self.index.conflict_code() {
+            return code.to_owned();
+        }
+        if self.workspace == Status::Untracked {
+            return "??".to_owned();
+        }
+        if self.workspace == Status::Ignored {
+            return "!!".to_owned();
+        }
+        let mut code = String::with_capacity(2);
+        code.push(self.index.column_code());
+        code.push(self.workspace.column_code());
+        code
+    }
 }
 
 impl Status {
     pub fn name(self) -> &'static str {
         match self {
             Status::Untracked => "untracked",
+            Status::Ignored => "ignored",
             Status::Modified => "modified",
+            Status::TypeChanged => "typechange",
             Status::Unmodified => "unmodified",
             Status::Deleted => "deleted",
             Status::Added => "added",
+            Status::Conflicted { base, ours, theirs } => {
+                match (base.is_some(), ours.is_some(), theirs.is_some()) {
+                    (true, true, true) => "both modified",
+                    (false, true, true) => "both added",
+                    (true, false, true) => "deleted by us",
+                    (true, true, false) => "deleted by them",
+                    (false, true, false) => "added by us",
+                    (false, false, true) => "added by them",
+                    (_, false, false) => "both deleted",
+                }
+            }
+        }
+    }
+
+    pub fn is_conflicted(self) -> bool {
+        matches!(self, Status::Conflicted { .. })
+    }
+
+    /// The two-letter code for an unmerged path, or `None` if not conflicted
+    pub fn conflict_code(self) -> Option<&'static str> {
+        if let Status::Conflicted { base, ours, theirs } = self {
+            let code = match (base.is_some(), ours.is_some(), theirs.is_some()) {
+                (true, true, true) => "UU",
+                (false, true, true) => "AA",
+                (true, false, true) => "DU",
+                (true, true, false) => "UD",
+                (false, true, false) => "AU",
+                (false, false, true) => "UA",
+                (_, false, false) => "DD",
+            };
+            Some(code)
+        } else {
+            None
+        }
+    }
+
+    fn column_code(self) -> char {
+        match self {
+            Status::Untracked => '?',
+            Status::Ignored => '!',
+            Status::Modified => 'M',
+            Status::TypeChanged => 'T',
+            Status::Unmodified => ' ',
+            Status::Deleted => 'D',
+            Status::Added => 'A',
+            Status::Conflicted { .. } => 'U',
         }
     }
 }
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn serializes_to_stable_schema() -> eyre::Result<()> {
+        let status = FileStatus {
+            path: WsPath::new_unchecked("dir/a.txt"),
+            index: Status::Added,
+            workspace: Status::Modified,
+        };
+        assert_eq!(
+            r#"{"path":"dir/a.txt","index":"added","workspace":"modified"}"#,
+            serde_json::to_string(&status)?
+        );
+
+        let ours = Oid::<Blob>::parse("ce013625030ba8dba906f756967f9e9ca394464a")?;
+        let conflicted = Status::Conflicted {
+            base: None,
+            ours: Some(ours),
+            theirs: None,
+        };
+        assert_eq!(
+            r#"{"conflicted":{"base":null,"ours":"ce013625030ba8dba906f756967f9e9ca394464a","theirs":null}}"#,
+            serde_json::to_string(&conflicted)?
+        );
+
+        Ok(())
+    }
+}
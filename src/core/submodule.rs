@@ -0,0 +1,313 @@
+//! Submodules, other repositories checked out within the workspace at the
+//! commits the index has for them (gitlinks), like `git submodule`. Their
+//! names, paths and URLs are in `.gitmodules`, and their git directories are
+//! kept in `.git/modules/<name>`, which their `.git` files point to.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bstr::ByteSlice;
+use tracing::{debug, instrument};
+
+use crate::core::{
+    config::{self, Config},
+    db::Commit,
+    fetch::{self, FetchOptions},
+    index::{self, Entry},
+    progress::Progress,
+    refs,
+    repo::{BareError, CheckoutError, InitError, ReadError},
+    stat::Mode,
+    ws::{self, StatFileError},
+    Oid, Repo, WsPath,
+};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Submodule {
+    /// Like `lib`, which names its git directory in `.git/modules`
+    pub name: String,
+    pub path: WsPath,
+    /// From `.git/config` once initialized, or else from `.gitmodules`
+    pub url: Option<String>,
+    /// What the index has for it
+    pub commit: Option<Oid<Commit>>,
+}
+
+/// How a submodule's checkout compares to what the index has, like `git
+/// submodule status`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SubmoduleStatus {
+    /// Not checked out, shown with a leading `-`
+    Uninitialized,
+    /// At the commit the index has
+    UpToDate,
+    /// At another commit, shown with a leading `+`
+    Modified(Oid<Commit>),
+}
+
+impl Repo {
+    /// The submodules in `.gitmodules`, in the order they're listed
+    #[instrument(err)]
+    pub fn submodules(&mut self) -> Result<Vec<Submodule>, SubmoduleError> {
+        let work = Self::workspace_of(self.workspace.as_ref(), self.git_dir())?;
+        let gitmodules = Config::load(work.path().join(".gitmodules"))?;
+        self.index.reload()?;
+
+        let mut submodules = Vec::new();
+        for entry in gitmodules.entries() {
+            let (Some(name), Some(path)) = (&entry.subsection, &entry.value) else {
+                continue;
+            };
+            if entry.section != "submodule" || entry.key != "path" {
+                continue;
+            }
+            let url_name = format!("submodule.{name}.url");
+            let url = self
+                .config
+                .get(&url_name)
+                .or_else(|| gitmodules.get(&url_name))
+                .map(str::to_owned);
+            let path = WsPath::new_normalized(path)?;
+            let commit = self
+                .index
+                .entry(&path)
+                .filter(|entry| entry.mode() == Mode::Gitlink)
+                .map(|entry| entry.oid.into_untyped().to_typed());
+            submodules.push(Submodule {
+                name: name.clone(),
+                path,
+                url,
+                commit,
+            });
+        }
+        Ok(submodules)
+    }
+
+    /// Copies the URLs of the submodules in `.gitmodules` that aren't
+    /// initialized to `.git/config`, like `git submodule init`, returning
+    /// their names. Relative URLs (like `../lib.git`) are taken from the URL
+    /// of `origin`, or from the workspace if there's no `origin`.
+    #[instrument(err)]
+    pub fn init_submodules(&mut self) -> Result<Vec<String>, SubmoduleError> {
+        let base = if let Some(url) = self.config.get("remote.origin.url") {
+            url.to_owned()
+        } else {
+            let work = Self::workspace_of(self.workspace.as_ref(), self.git_dir())?;
+            work.path().to_string_lossy().into_owned()
+        };
+
+        let mut initialized = Vec::new();
+        for submodule in self.submodules()? {
+            let name = format!("submodule.{}.url", submodule.name);
+            let Some(url) = submodule.url else {
+                continue;
+            };
+            if self.config.get(&name).is_some() {
+                continue;
+            }
+            self.config.set(&name, &resolve_url(&base, &url))?;
+            initialized.push(submodule.name);
+        }
+        self.save_config()?;
+        Ok(initialized)
+    }
+
+    /// Checks out each initialized submodule at the commit the index has,
+    /// detaching its HEAD, like `git submodule update`. A submodule that
+    /// isn't cloned yet is cloned into `.git/modules/<name>`, and fetched
+    /// from if it hasn't the commit. `progress` hears how each is fetched.
+    #[instrument(err, skip(progress))]
+    pub fn update_submodules(&mut self, progress: &mut dyn Progress) -> Result<(), SubmoduleError> {
+        for submodule in self.submodules()? {
+            let name = format!("submodule.{}.url", submodule.name);
+            let (Some(url), Some(commit)) = (self.config.get(&name), submodule.commit) else {
+                continue;
+            };
+            let git_dir = self.common_dir().join("modules").join(&submodule.name);
+            if !Self::is_git_dir(&git_dir) {
+                debug!(?git_dir, url, "Cloning");
+                self.create_submodule(&submodule, url, &git_dir)?;
+            }
+
+            let mut repo = Repo::new(self.submodule_dir(&submodule.path)?)?;
+            if !repo.db.contains(commit.as_untyped()) {
+                repo.fetch_with("origin", &FetchOptions::default(), progress)?;
+            }
+            if let Some(head) = repo.refs.head()? {
+                repo.refs.update_ref(b"HEAD".as_bstr(), &head)?;
+            }
+            repo.checkout(commit)?;
+        }
+        Ok(())
+    }
+
+    /// How the submodule at `path` compares to what the index has
+    #[instrument(err)]
+    pub fn submodule_status(&mut self, path: &WsPath) -> Result<SubmoduleStatus, SubmoduleError> {
+        self.index.reload()?;
+        let recorded = self
+            .index
+            .entry(path)
+            .filter(|entry| entry.mode() == Mode::Gitlink)
+            .map(|entry| entry.oid.into_untyped().to_typed())
+            .ok_or_else(|| SubmoduleError::NotSubmodule(path.clone()))?;
+        let dir = self.submodule_dir(path)?;
+        if !dir.join(".git").exists() {
+            return Ok(SubmoduleStatus::Uninitialized);
+        }
+        match Repo::new(dir)?.refs.head()? {
+            Some(head) if head == recorded => Ok(SubmoduleStatus::UpToDate),
+            Some(head) => Ok(SubmoduleStatus::Modified(head)),
+            None => Ok(SubmoduleStatus::Uninitialized),
+        }
+    }
+
+    /// Stages the commit the submodule at `path` has checked out, to be
+    /// committed to the superproject
+    #[instrument(err)]
+    pub fn record_submodule(&mut self, path: &WsPath) -> Result<Oid<Commit>, SubmoduleError> {
+        let head = Repo::new(self.submodule_dir(path)?)?
+            .refs
+            .head()?
+            .ok_or_else(|| SubmoduleError::NoCommit(path.clone()))?;
+        let work = Self::workspace_of(self.workspace.as_ref(), self.git_dir())?;
+        let stat = work.stat(path)?;
+        self.index.reload()?;
+        let mut index = self.index.modify()?;
+        index.add(Entry::new(
+            path.clone(),
+            head.into_untyped().to_typed(),
+            stat,
+        ));
+        index.commit()?;
+        Ok(head)
+    }
+
+    fn submodule_dir(&self, path: &WsPath) -> Result<PathBuf, BareError> {
+        let work = Self::workspace_of(self.workspace.as_ref(), self.git_dir())?;
+        Ok(work.path().join(path))
+    }
+
+    /// A git directory set up to fetch from `url`, with the submodule's
+    /// workspace as its worktree, both pointing to each other by relative
+    /// paths like git
+    fn create_submodule(
+        &self,
+        submodule: &Submodule,
+        url: &str,
+        git_dir: &Path,
+    ) -> Result<(), SubmoduleError> {
+        Self::init_git_dir(git_dir)?;
+        let up = |components: usize| "../".repeat(components);
+
+        let name_depth = submodule.name.split('/').count();
+        let path_depth = submodule.path.components().count();
+        let mut config = Config::default();
+        let worktree = format!("{}{}", up(name_depth + 2), submodule.path);
+        config.set("core.worktree", &worktree)?;
+        config.set("remote.origin.url", url)?;
+        config.set("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?;
+        config.save(git_dir.join("config"))?;
+
+        let dir = self.submodule_dir(&submodule.path)?;
+        fs::create_dir_all(&dir).map_err(|e| SubmoduleError::Write(dir.clone(), e))?;
+        let git_file = dir.join(".git");
+        let contents = format!(
+            "gitdir: {}.git/modules/{}\n",
+            up(path_depth),
+            submodule.name
+        );
+        fs::write(&git_file, contents).map_err(|e| SubmoduleError::Write(git_file, e))?;
+        Ok(())
+    }
+}
+
+/// `url` relative to `base` if it starts with `./` or `../`, where each `../`
+/// takes off a component of `base`
+fn resolve_url(base: &str, url: &str) -> String {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return url.to_owned();
+    }
+    let mut base = base.trim_end_matches('/').to_owned();
+    let mut rest = url;
+    loop {
+        if let Some(after) = rest.strip_prefix("./") {
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("../") {
+            rest = after;
+            base.truncate(base.rfind('/').unwrap_or(0));
+        } else {
+            break;
+        }
+    }
+    format!("{base}/{rest}")
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum SubmoduleError {
+    /// {0}
+    Bare(#[from] BareError),
+    /// Failed to load .gitmodules
+    LoadGitmodules(#[from] config::LoadError),
+    /// Invalid path in .gitmodules
+    Path(#[from] ws::path::NormalizeError),
+    /// Failed to set submodule config
+    Config(#[from] config::EditError),
+    /// Failed to save config
+    SaveConfig(#[from] config::SaveError),
+    /// Failed to load index
+    LoadIndex(#[from] index::LoadError),
+    /// Failed to open index for modifications
+    OpenIndex(#[from] index::OpenForModificationsError),
+    /// Failed to write index
+    CommitIndex(#[from] index::CommitError),
+    /// Failed to stat submodule
+    Stat(#[from] StatFileError),
+    /// Failed to create submodule git directory
+    Init(#[from] InitError),
+    /// Failed to write {0:?}
+    Write(PathBuf, #[source] io::Error),
+    /// Failed to open submodule
+    Open(#[from] ReadError),
+    /// Failed to read submodule HEAD
+    ReadRef(#[from] refs::ReadError),
+    /// Failed to detach submodule HEAD
+    UpdateRef(#[from] refs::UpdateError),
+    /// Failed to fetch submodule
+    Fetch(#[from] fetch::FetchError),
+    /// Failed to check out submodule
+    Checkout(#[from] CheckoutError),
+    /// {0} isn't a submodule in the index
+    NotSubmodule(WsPath),
+    /// Submodule {0} has no commit checked out
+    NoCommit(WsPath),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn resolves_relative_urls() {
+        let base = "https://example.com/org/app.git";
+        assert_eq!(
+            "https://example.com/org/lib.git",
+            resolve_url(base, "../lib.git")
+        );
+        assert_eq!(
+            "https://example.com/org/app.git/lib",
+            resolve_url(base, "./lib")
+        );
+        assert_eq!(
+            "https://example.com/other/lib",
+            resolve_url(base, "../../other/lib")
+        );
+        assert_eq!(
+            "git@example.com:lib",
+            resolve_url(base, "git@example.com:lib")
+        );
+    }
+}
@@ -0,0 +1,661 @@
+//! Trailers, the `Token: value` lines like `Signed-off-by:` or
+//! `Reviewed-by:` that end a message, and adding, replacing and removing
+//! them like `git interpret-trailers`.
+//!
+//! Where a trailer goes and what happens if one with its token is already
+//! there is configured with `trailer.where`, `trailer.ifExists` and
+//! `trailer.ifMissing`, or for a token with `trailer.<name>.where` and so
+//! on. `trailer.<name>.key` makes `<name>` short for a token, so
+//! `trailer.ack.key = Acked-by` adds `ack: Me` as `Acked-by: Me`.
+
+use std::{collections::BTreeMap, fmt::Write as _};
+
+use tracing::instrument;
+
+use crate::core::{commit_msg::comment_char, config, Config, Repo};
+
+/// Lines starting with these are trailers git made, and show that the
+/// paragraph they're in is a trailer block even if most of it isn't trailers
+const GIT_GENERATED: &[&str] = &["Signed-off-by: ", "(cherry picked from commit "];
+
+/// Where a trailer is added, `trailer.where`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Position {
+    /// After the last trailer
+    #[default]
+    End,
+    /// Before the first trailer
+    Start,
+    /// After the last trailer with the same token, or at the end if there
+    /// is none
+    After,
+    /// Before the first trailer with the same token, or at the start if
+    /// there is none
+    Before,
+}
+
+/// What to do when a trailer with the same token is already there,
+/// `trailer.ifExists`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum IfExists {
+    /// Add it unless the trailer it would be next to is the same
+    #[default]
+    AddIfDifferentNeighbor,
+    /// Add it unless the same trailer is anywhere
+    AddIfDifferent,
+    Add,
+    /// Add it and remove the trailer with the same token that's nearest
+    /// where it goes
+    Replace,
+    DoNothing,
+}
+
+/// What to do when there's no trailer with the same token,
+/// `trailer.ifMissing`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum IfMissing {
+    #[default]
+    Add,
+    DoNothing,
+}
+
+/// How a trailer is added
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Policy {
+    pub position: Position,
+    pub if_exists: IfExists,
+    pub if_missing: IfMissing,
+}
+
+/// Overrides config for the trailers added, like the `--where`,
+/// `--if-exists` and `--if-missing` options of `git interpret-trailers`
+#[derive(Debug, Clone, Default)]
+pub struct TrailerOptions {
+    pub position: Option<Position>,
+    pub if_exists: Option<IfExists>,
+    pub if_missing: Option<IfMissing>,
+}
+
+/// A line like `Reviewed-by: Name <email>`. The value keeps any lines it
+/// continues onto.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Trailer {
+    pub token: String,
+    pub value: String,
+}
+
+/// How trailers are found, added and removed, from `trailer.*` config
+#[derive(Debug, Clone)]
+pub struct Trailers {
+    /// `trailer.separators`, the first of which is written
+    separators: String,
+    comment_char: char,
+    default: Policy,
+    /// `trailer.<name>.*`, by name
+    configured: BTreeMap<String, Configured>,
+}
+
+/// Config for trailers with a token
+#[derive(Debug, Clone, Default)]
+struct Configured {
+    /// The token written for the name
+    key: Option<String>,
+    position: Option<Position>,
+    if_exists: Option<IfExists>,
+    if_missing: Option<IfMissing>,
+}
+
+/// A line of a trailer block
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Line {
+    Trailer(Trailer),
+    /// Kept as it is
+    Other(String),
+}
+
+/// A message split around its trailer block
+#[derive(Debug)]
+struct Split<'a> {
+    /// Up to the trailer block, including the blank line before it
+    before: &'a str,
+    /// With comments left out
+    block: Vec<Line>,
+    /// Trailing comments and blank lines, and anything after a `---`
+    /// divider, like a patch
+    after: &'a str,
+}
+
+impl Default for Trailers {
+    fn default() -> Self {
+        Self {
+            separators: ":".to_owned(),
+            comment_char: '#',
+            default: Policy::default(),
+            configured: BTreeMap::new(),
+        }
+    }
+}
+
+impl Trailers {
+    pub fn from_config(config: &Config) -> Result<Self, config::ValueError> {
+        let mut trailers = Self {
+            separators: config
+                .get("trailer.separators")
+                .filter(|separators| !separators.is_empty())
+                .unwrap_or(":")
+                .to_owned(),
+            comment_char: comment_char(config),
+            ..Self::default()
+        };
+        for entry in config.entries() {
+            if entry.section != "trailer" {
+                continue;
+            }
+            let name = match &entry.subsection {
+                Some(name) => format!("trailer.{name}.{}", entry.key),
+                None => format!("trailer.{}", entry.key),
+            };
+            let value = entry.value.as_deref().unwrap_or("");
+            let invalid = || config::ValueError::Invalid(name.clone(), value.to_owned());
+            let Some(subsection) = &entry.subsection else {
+                match entry.key.as_str() {
+                    "where" => {
+                        trailers.default.position = parse_position(value).ok_or_else(invalid)?;
+                    }
+                    "ifexists" => {
+                        trailers.default.if_exists = parse_if_exists(value).ok_or_else(invalid)?;
+                    }
+                    "ifmissing" => {
+                        trailers.default.if_missing =
+                            parse_if_missing(value).ok_or_else(invalid)?;
+                    }
+                    _ => {}
+                }
+                continue;
+            };
+            let configured = trailers.configured.entry(subsection.clone()).or_default();
+            match entry.key.as_str() {
+                "key" => configured.key = Some(value.to_owned()),
+                "where" => configured.position = Some(parse_position(value).ok_or_else(invalid)?),
+                "ifexists" => {
+                    configured.if_exists = Some(parse_if_exists(value).ok_or_else(invalid)?);
+                }
+                "ifmissing" => {
+                    configured.if_missing = Some(parse_if_missing(value).ok_or_else(invalid)?);
+                }
+                _ => {}
+            }
+        }
+        Ok(trailers)
+    }
+
+    /// The trailers ending the message, with tokens configured for a name
+    /// given as that token
+    pub fn parse(&self, msg: &str) -> Vec<Trailer> {
+        self.split(msg)
+            .block
+            .into_iter()
+            .filter_map(|line| match line {
+                Line::Trailer(trailer) => Some(trailer),
+                Line::Other(_) => None,
+            })
+            .collect()
+    }
+
+    /// Adds the trailers in order, each where and if its policy says to. A
+    /// message without trailers gets a paragraph of them, after a blank line.
+    pub fn insert(&self, msg: &str, trailers: &[Trailer], options: &TrailerOptions) -> String {
+        let mut split = self.split(msg);
+        for trailer in trailers {
+            let trailer = Trailer {
+                token: self.token_for(trailer.token.trim()),
+                value: trailer.value.trim().to_owned(),
+            };
+            let policy = self.policy(&trailer.token, options);
+            apply(&mut split.block, trailer, policy);
+        }
+        self.render(&split)
+    }
+
+    /// Removes every trailer with the token, and the paragraph of trailers
+    /// if nothing is left in it
+    pub fn remove(&self, msg: &str, token: &str) -> String {
+        let mut split = self.split(msg);
+        let token = self.token_for(token.trim());
+        let had_block = !split.block.is_empty();
+        split.block.retain(
+            |line| !matches!(line, Line::Trailer(trailer) if same_token(&trailer.token, &token)),
+        );
+        if had_block && split.block.is_empty() {
+            let kept = split.before.trim_end().len();
+            split.before = &split.before[..split.before.len().min(kept + 1)];
+        }
+        self.render(&split)
+    }
+
+    fn split<'a>(&self, msg: &'a str) -> Split<'a> {
+        let end = self.end_of_message(msg);
+        let start = self.block_start(&msg[..end]);
+
+        // Lines starting with whitespace continue the trailer before them
+        let mut lines: Vec<String> = Vec::new();
+        let mut continues = false;
+        for line in msg[start..end].split_inclusive('\n') {
+            match lines.last_mut() {
+                Some(last) if continues && line.starts_with(char::is_whitespace) => {
+                    last.push_str(line);
+                }
+                _ => {
+                    continues = self.separator_pos(line).is_some();
+                    lines.push(line.to_owned());
+                }
+            }
+        }
+
+        let block = lines
+            .into_iter()
+            .filter(|line| !line.starts_with(self.comment_char))
+            .map(|line| match self.separator_pos(&line) {
+                Some(pos) => Line::Trailer(Trailer {
+                    token: self.token_for(line[..pos].trim()),
+                    value: line[pos + 1..].trim().to_owned(),
+                }),
+                None => Line::Other(line.trim_end_matches('\n').to_owned()),
+            })
+            .collect();
+        Split {
+            before: &msg[..start],
+            block,
+            after: &msg[end..],
+        }
+    }
+
+    /// Where the message ends, before a `---` divider and any comments and
+    /// blank lines that trail it
+    fn end_of_message(&self, msg: &str) -> usize {
+        let divider = line_indices(msg)
+            .find(|(_, line)| {
+                line.strip_prefix("---")
+                    .and_then(|rest| rest.chars().next())
+                    .is_some_and(char::is_whitespace)
+            })
+            .map_or(msg.len(), |(start, _)| start);
+        line_indices(&msg[..divider])
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .take_while(|(_, line)| line.starts_with(self.comment_char) || is_blank(line))
+            .last()
+            .map_or(divider, |(start, _)| start)
+    }
+
+    /// Where the trailer block of the message starts, or its end if it has
+    /// none. The block is the last paragraph, but never the first, which is
+    /// the title. It's all trailers, or at least a quarter if it has one git
+    /// generated or one configured. Lines continuing trailers count for
+    /// them.
+    fn block_start(&self, msg: &str) -> usize {
+        let lines = line_indices(msg).collect::<Vec<_>>();
+        let end_of_title = lines
+            .iter()
+            .find(|(_, line)| !line.starts_with(self.comment_char) && is_blank(line))
+            .map_or(msg.len(), |&(start, _)| start);
+
+        let (mut trailers, mut others, mut continuations) = (0, 0, 0);
+        let mut recognized = false;
+        for &(start, line) in lines.iter().rev() {
+            if start < end_of_title {
+                break;
+            }
+            if line.starts_with(self.comment_char) {
+                others += continuations;
+                continuations = 0;
+            } else if is_blank(line) {
+                others += continuations;
+                let is_block =
+                    (recognized && trailers * 3 >= others) || (trailers > 0 && others == 0);
+                return if is_block {
+                    start + line.len()
+                } else {
+                    msg.len()
+                };
+            } else if GIT_GENERATED.iter().any(|prefix| line.starts_with(prefix)) {
+                trailers += 1;
+                continuations = 0;
+                recognized = true;
+            } else if let Some(pos) = self.separator_pos(line) {
+                trailers += 1;
+                continuations = 0;
+                recognized |= self.configured(line[..pos].trim()).is_some();
+            } else if line.starts_with(char::is_whitespace) {
+                continuations += 1;
+            } else {
+                others += 1 + continuations;
+                continuations = 0;
+            }
+        }
+        msg.len()
+    }
+
+    /// Where the separator of a trailer line is. The token before it is
+    /// letters, digits and `-`, and may be followed by whitespace.
+    fn separator_pos(&self, line: &str) -> Option<usize> {
+        let mut spaced = false;
+        for (i, c) in line.char_indices() {
+            if self.separators.contains(c) {
+                return (i > 0).then_some(i);
+            }
+            if !spaced && (c.is_ascii_alphanumeric() || c == '-') {
+                continue;
+            }
+            if i > 0 && (c == ' ' || c == '\t') {
+                spaced = true;
+                continue;
+            }
+            return None;
+        }
+        None
+    }
+
+    /// The config for the token, found by its name or key
+    fn configured(&self, token: &str) -> Option<&Configured> {
+        self.configured.iter().find_map(|(name, configured)| {
+            let key = configured.key.as_deref();
+            (same_token(name, token) || key.is_some_and(|key| same_token(key, token)))
+                .then_some(configured)
+        })
+    }
+
+    /// The key configured for the token, or the token as it is
+    fn token_for(&self, token: &str) -> String {
+        self.configured(token)
+            .and_then(|configured| configured.key.clone())
+            .unwrap_or_else(|| token.to_owned())
+    }
+
+    fn policy(&self, token: &str, options: &TrailerOptions) -> Policy {
+        let mut policy = self.default;
+        if let Some(configured) = self.configured(token) {
+            policy.position = configured.position.unwrap_or(policy.position);
+            policy.if_exists = configured.if_exists.unwrap_or(policy.if_exists);
+            policy.if_missing = configured.if_missing.unwrap_or(policy.if_missing);
+        }
+        Policy {
+            position: options.position.unwrap_or(policy.position),
+            if_exists: options.if_exists.unwrap_or(policy.if_exists),
+            if_missing: options.if_missing.unwrap_or(policy.if_missing),
+        }
+    }
+
+    fn render(&self, split: &Split<'_>) -> String {
+        let mut msg = split.before.to_owned();
+        if !split.block.is_empty() {
+            if !msg.is_empty() && !msg.ends_with('\n') {
+                msg.push('\n');
+            }
+            if !msg.lines().next_back().is_some_and(is_blank) {
+                msg.push('\n');
+            }
+        }
+        for line in &split.block {
+            match line {
+                Line::Trailer(Trailer { token, value }) => {
+                    // A key like `Bug #` is written with no separator added
+                    let last = token.trim_end().chars().next_back();
+                    if last.is_some_and(|c| self.separators.contains(c)) {
+                        writeln!(msg, "{token}{value}").expect("Writing to a string");
+                    } else {
+                        let separator = self.separators.chars().next().unwrap_or(':');
+                        writeln!(msg, "{token}{separator} {value}").expect("Writing to a string");
+                    }
+                }
+                Line::Other(line) => {
+                    msg.push_str(line);
+                    msg.push('\n');
+                }
+            }
+        }
+        msg.push_str(split.after);
+        msg
+    }
+}
+
+impl Repo {
+    /// Adds the trailers to the message as `trailer.*` config says, like
+    /// `git interpret-trailers --trailer`. See [`Trailers::insert`].
+    #[instrument(err)]
+    pub fn interpret_trailers(
+        &self,
+        msg: &str,
+        trailers: &[Trailer],
+        options: &TrailerOptions,
+    ) -> Result<String, config::ValueError> {
+        Ok(Trailers::from_config(&self.config)?.insert(msg, trailers, options))
+    }
+}
+
+/// Adds the trailer to the block where and if the policy says
+fn apply(block: &mut Vec<Line>, trailer: Trailer, policy: Policy) {
+    let is_same_token = |line: &Line| matches!(line, Line::Trailer(found) if same_token(&found.token, &trailer.token));
+    let at_end = matches!(policy.position, Position::End | Position::After);
+    let found = if at_end {
+        block.iter().rposition(is_same_token)
+    } else {
+        block.iter().position(is_same_token)
+    };
+
+    let Some(found) = found else {
+        if policy.if_missing == IfMissing::Add {
+            let at = if at_end { block.len() } else { 0 };
+            block.insert(at, Line::Trailer(trailer));
+        }
+        return;
+    };
+    let next_to = match policy.position {
+        Position::After | Position::Before => found,
+        Position::End => block.len() - 1,
+        Position::Start => 0,
+    };
+    let is_same = |line: &Line| {
+        matches!(line, Line::Trailer(found)
+            if same_token(&found.token, &trailer.token)
+                && found.value.eq_ignore_ascii_case(&trailer.value))
+    };
+    let add = match policy.if_exists {
+        IfExists::AddIfDifferentNeighbor => !is_same(&block[next_to]),
+        IfExists::AddIfDifferent => !block.iter().any(is_same),
+        IfExists::Add | IfExists::Replace => true,
+        IfExists::DoNothing => false,
+    };
+    if !add {
+        return;
+    }
+    let at = if at_end { next_to + 1 } else { next_to };
+    block.insert(at, Line::Trailer(trailer));
+    if policy.if_exists == IfExists::Replace {
+        block.remove(if at <= found { found + 1 } else { found });
+    }
+}
+
+/// Tokens are the same whatever their case and any separator they end with
+fn same_token(a: &str, b: &str) -> bool {
+    fn bare(token: &str) -> &str {
+        token.trim_end_matches(|c: char| !c.is_ascii_alphanumeric())
+    }
+    bare(a).eq_ignore_ascii_case(bare(b))
+}
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+/// Each line with where it starts, including its newline
+fn line_indices(text: &str) -> impl Iterator<Item = (usize, &str)> + '_ {
+    text.split_inclusive('\n').scan(0, |start, line| {
+        let indexed = (*start, line);
+        *start += line.len();
+        Some(indexed)
+    })
+}
+
+fn parse_position(value: &str) -> Option<Position> {
+    match value.to_ascii_lowercase().as_str() {
+        "end" => Some(Position::End),
+        "start" => Some(Position::Start),
+        "after" => Some(Position::After),
+        "before" => Some(Position::Before),
+        _ => None,
+    }
+}
+
+fn parse_if_exists(value: &str) -> Option<IfExists> {
+    match value.to_ascii_lowercase().as_str() {
+        "addifdifferentneighbor" => Some(IfExists::AddIfDifferentNeighbor),
+        "addifdifferent" => Some(IfExists::AddIfDifferent),
+        "add" => Some(IfExists::Add),
+        "replace" => Some(IfExists::Replace),
+        "donothing" => Some(IfExists::DoNothing),
+        _ => None,
+    }
+}
+
+fn parse_if_missing(value: &str) -> Option<IfMissing> {
+    match value.to_ascii_lowercase().as_str() {
+        "add" => Some(IfMissing::Add),
+        "donothing" => Some(IfMissing::DoNothing),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn trailer(token: &str, value: &str) -> Trailer {
+        Trailer {
+            token: token.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    fn insert_with(config: &str, msg: &str, trailers: &[Trailer]) -> eyre::Result<String> {
+        let trailers_config = Trailers::from_config(&Config::parse(config)?)?;
+        Ok(trailers_config.insert(msg, trailers, &TrailerOptions::default()))
+    }
+
+    #[test]
+    fn finds_the_trailer_block() {
+        let trailers = Trailers::default();
+        let parsed = |msg| trailers.parse(msg);
+
+        assert_eq!(Vec::<Trailer>::new(), parsed("Subject: not a trailer\n"));
+        assert_eq!(
+            vec![trailer("Acked-by", "A\n  continued"), trailer("Fixes", "1")],
+            parsed("Subject\n\nBody\n\nAcked-by:  A\n  continued\nFixes: 1\n\n# Comment\n")
+        );
+        // Mostly not trailers
+        assert_eq!(
+            Vec::<Trailer>::new(),
+            parsed("Subject\n\nBody\n\nFixes: 1\nSome text\n")
+        );
+        // But one git generated
+        assert_eq!(
+            vec![trailer("Signed-off-by", "Me"), trailer("Fixes", "1")],
+            parsed("Subject\n\nSigned-off-by: Me\nFixes: 1\nSome\ntext\n")
+        );
+        assert_eq!(
+            vec![trailer("Fixes", "1")],
+            parsed("Subject\n\nFixes: 1\n---\n\nNot: a trailer\n")
+        );
+    }
+
+    #[test]
+    fn places_trailers_by_policy() -> eyre::Result<()> {
+        let msg = "Subject\n\nA: 1\nB: 2\nA: 3\n";
+        let insert = |config: &str, new: Trailer| insert_with(config, msg, &[new]);
+
+        assert_eq!(
+            "Subject\n\nA: 1\nB: 2\nA: 3\nA: 4\n",
+            insert("", trailer("A", "4"))?
+        );
+        assert_eq!(
+            "Subject\n\nA: 1\nB: 2\nA: 3\n",
+            insert("", trailer("A", "3"))?,
+            "Same as its neighbor"
+        );
+        assert_eq!(
+            "Subject\n\nA: 1\nB: 2\nA: 3\nA: 1\n",
+            insert("", trailer("A", "1"))?,
+            "Different from its neighbor"
+        );
+        assert_eq!(
+            "Subject\n\nA: 1\nB: 2\nA: 3\n",
+            insert(
+                "[trailer]\n\tifExists = addIfDifferent\n",
+                trailer("A", "1")
+            )?
+        );
+        assert_eq!(
+            "Subject\n\nA: 1\nB: 2\nB: 5\nA: 3\n",
+            insert("[trailer]\n\twhere = after\n", trailer("B", "5"))?
+        );
+        assert_eq!(
+            "Subject\n\nA: 4\nA: 1\nB: 2\nA: 3\n",
+            insert("[trailer]\n\twhere = before\n", trailer("A", "4"))?
+        );
+        assert_eq!(
+            "Subject\n\nA: 1\nB: 2\nA: 4\n",
+            insert(
+                "[trailer \"a\"]\n\twhere = after\n\tifExists = replace\n",
+                trailer("A", "4")
+            )?
+        );
+        assert_eq!(
+            "Subject\n\nA: 1\nB: 2\nA: 3\n",
+            insert("[trailer]\n\tifMissing = doNothing\n", trailer("C", "4"))?
+        );
+        assert_eq!(
+            "Subject\n\nC: 4\nA: 1\nB: 2\nA: 3\n",
+            insert("[trailer]\n\twhere = start\n", trailer("C", "4"))?
+        );
+
+        // Options override config
+        let options = TrailerOptions {
+            if_exists: Some(IfExists::DoNothing),
+            ..TrailerOptions::default()
+        };
+        let config = Trailers::from_config(&Config::parse("[trailer]\n\tifExists = add\n")?)?;
+        assert_eq!(msg, config.insert(msg, &[trailer("A", "4")], &options));
+        Ok(())
+    }
+
+    #[test]
+    fn adds_trailer_blocks_with_configured_keys() -> eyre::Result<()> {
+        let config = "[trailer \"ack\"]\n\tkey = \"Acked-by: \"\n";
+        assert_eq!(
+            "Subject\n\nBody\n\nAcked-by: Me\n",
+            insert_with(config, "Subject\n\nBody\n", &[trailer("ack", "Me")])?
+        );
+        assert_eq!(
+            "Subject\n\nAcked-by: Me\n# Comment\n",
+            insert_with(config, "Subject\n# Comment\n", &[trailer("ack", "Me")])?
+        );
+        assert!(Trailers::from_config(&Config::parse("[trailer]\n\twhere = middle\n")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn removes_trailers() {
+        let trailers = Trailers::default();
+        assert_eq!(
+            "Subject\n\nB: 2\n",
+            trailers.remove("Subject\n\nA: 1\nB: 2\na: 3\n", "A")
+        );
+        assert_eq!(
+            "Subject\n\nBody\n# Comment\n",
+            trailers.remove("Subject\n\nBody\n\nA: 1\n# Comment\n", "A")
+        );
+        assert_eq!("Subject\n", trailers.remove("Subject\n", "A"));
+    }
+}
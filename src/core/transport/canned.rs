@@ -0,0 +1,45 @@
+//! A fake transport for testing the protocols spoken over transports
+
+use std::{
+    cell::RefCell,
+    io::{self, Read},
+    rc::Rc,
+};
+
+use bstr::BString;
+
+use super::{pkt_line, Transport, TransportError};
+
+/// Replies to each request with the next response, and records the
+/// requests
+#[derive(Debug)]
+pub(crate) struct Canned {
+    pub advertisement: Vec<u8>,
+    pub responses: Vec<Vec<u8>>,
+    pub requests: Rc<RefCell<Vec<BString>>>,
+}
+
+impl Transport for Canned {
+    fn advertisement(&mut self) -> Result<Box<dyn Read + '_>, TransportError> {
+        Ok(Box::new(self.advertisement.as_slice()))
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Box<dyn Read + '_>, TransportError> {
+        self.requests.borrow_mut().push(request.into());
+        Ok(Box::new(io::Cursor::new(self.responses.remove(0))))
+    }
+}
+
+/// Where `0000` is a flush and `0001` is a delimiter
+pub(crate) fn packets(lines: &[&str]) -> Vec<u8> {
+    let mut writer = pkt_line::Writer::new(Vec::new());
+    for line in lines {
+        match *line {
+            "0000" => writer.write_flush(),
+            "0001" => writer.write_delim(),
+            line => writer.write_data(line.as_bytes()),
+        }
+        .unwrap();
+    }
+    writer.into_inner()
+}
@@ -0,0 +1,127 @@
+//! The anonymous `git://` transport, where `git daemon` runs the service
+//! for us after a request naming it, over one TCP connection for every
+//! request
+
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+};
+
+use tracing::{debug, instrument};
+
+use super::{pkt_line, split_port, Service, Transport, TransportError};
+
+const DEFAULT_PORT: u16 = 9418;
+
+/// Where a url like `git://host:9418/path` points
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Address {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+#[derive(Debug)]
+pub struct Daemon {
+    stream: TcpStream,
+}
+
+impl Address {
+    /// `None` if `url` isn't for `git://`
+    pub fn parse(url: &str) -> Option<Self> {
+        let rest = url.strip_prefix("git://")?;
+        let (authority, path) = rest.split_at(rest.find('/')?);
+        let (host, port) = split_port(authority);
+        let port = match port {
+            Some(port) => port.parse().ok()?,
+            None => DEFAULT_PORT,
+        };
+        Some(Self {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Daemon {
+    /// Asks for `git-upload-pack` with protocol v2, as an extra parameter
+    /// after the host
+    #[instrument(err)]
+    pub fn connect(address: &Address, service: Service) -> Result<Self, TransportError> {
+        let authority = format!("{}:{}", address.host, address.port);
+        let mut stream = TcpStream::connect((address.host.as_str(), address.port))
+            .map_err(|e| TransportError::Connect(authority.clone(), e))?;
+
+        let host = if address.port == DEFAULT_PORT {
+            address.host.clone()
+        } else {
+            authority
+        };
+        let mut request = format!("{} {}\0host={host}\0", service.name(), address.path);
+        if service == Service::UploadPack {
+            request.push_str("\0version=2\0");
+        }
+        debug!(?request);
+        let mut writer = pkt_line::Writer::new(&mut stream);
+        writer
+            .write_data(request.as_bytes())
+            .map_err(TransportError::Send)?;
+
+        Ok(Self { stream })
+    }
+}
+
+impl Transport for Daemon {
+    fn advertisement(&mut self) -> Result<Box<dyn Read + '_>, TransportError> {
+        Ok(Box::new(&mut self.stream))
+    }
+
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(level = "trace", skip(self, request), fields(len = request.len()))
+    )]
+    fn request(&mut self, request: &[u8]) -> Result<Box<dyn Read + '_>, TransportError> {
+        self.stream
+            .write_all(request)
+            .map_err(TransportError::Send)?;
+        Ok(Box::new(&mut self.stream))
+    }
+}
+
+impl Drop for Daemon {
+    /// So that the service exits
+    fn drop(&mut self) {
+        if let Err(err) = self.stream.shutdown(Shutdown::Write) {
+            debug!(%err, "Failed to close connection");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            Some(Address {
+                host: "example.com".into(),
+                port: DEFAULT_PORT,
+                path: "/repo.git".into(),
+            }),
+            Address::parse("git://example.com/repo.git")
+        );
+        assert_eq!(
+            Some(Address {
+                host: "::1".into(),
+                port: 1234,
+                path: "/~user/repo".into(),
+            }),
+            Address::parse("git://[::1]:1234/~user/repo")
+        );
+        assert_eq!(None, Address::parse("git://example.com:port/repo"));
+        assert_eq!(None, Address::parse("ssh://example.com/repo"));
+    }
+}
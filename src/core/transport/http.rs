@@ -0,0 +1,91 @@
+//! The smart HTTP transport, which makes a `GET` request for the
+//! advertisement and a `POST` for each request
+
+use std::io::{self, Read};
+
+use bstr::ByteSlice;
+use tracing::instrument;
+
+use super::{
+    pkt_line::{self, Packet},
+    Service, Transport, TransportError,
+};
+
+#[derive(Debug)]
+pub struct Http {
+    /// Without a trailing slash
+    url: String,
+    service: Service,
+    agent: ureq::Agent,
+}
+
+impl Http {
+    pub fn new(url: &str, service: Service) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_owned(),
+            service,
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    fn with_protocol(&self, request: ureq::Request) -> ureq::Request {
+        match self.service {
+            Service::UploadPack => request.set("Git-Protocol", "version=2"),
+            Service::ReceivePack => request,
+        }
+    }
+}
+
+impl Transport for Http {
+    /// Servers speaking protocol v0 start with a `# service=` line, which we
+    /// skip so that the advertisement is the same as for other transports
+    #[instrument(err)]
+    fn advertisement(&mut self) -> Result<Box<dyn Read + '_>, TransportError> {
+        let url = format!("{}/info/refs?service={}", self.url, self.service.name());
+        let response = self
+            .with_protocol(self.agent.get(&url))
+            .call()
+            .map_err(|e| TransportError::Http(url.clone(), Box::new(e)))?;
+
+        let expected_type = format!("application/x-{}-advertisement", self.service.name());
+        if response.content_type() != expected_type {
+            return Err(TransportError::DumbHttp(self.url.clone()));
+        }
+
+        let mut body = pkt_line::Reader::new(response.into_reader());
+        match body.read()? {
+            Packet::Data(line) if line.starts_with(b"# service=") => match body.read()? {
+                Packet::Flush => Ok(Box::new(body.into_inner())),
+                packet => Err(pkt_line::ReadError::UnexpectedPacket(packet).into()),
+            },
+            Packet::Data(line) => {
+                // Put back what we read
+                let mut first = pkt_line::Writer::new(Vec::new());
+                first
+                    .write_data(line.as_bytes())
+                    .map_err(pkt_line::ReadError::Io)?;
+                let first = io::Cursor::new(first.into_inner());
+                Ok(Box::new(first.chain(body.into_inner())))
+            }
+            packet => Err(pkt_line::ReadError::UnexpectedPacket(packet).into()),
+        }
+    }
+
+    #[instrument(err, skip(request))]
+    fn request(&mut self, request: &[u8]) -> Result<Box<dyn Read + '_>, TransportError> {
+        let url = format!("{}/{}", self.url, self.service.name());
+        let response = self
+            .with_protocol(self.agent.post(&url))
+            .set(
+                "Content-Type",
+                &format!("application/x-{}-request", self.service.name()),
+            )
+            .set(
+                "Accept",
+                &format!("application/x-{}-result", self.service.name()),
+            )
+            .send_bytes(request)
+            .map_err(|e| TransportError::Http(url.clone(), Box::new(e)))?;
+        Ok(Box::new(response.into_reader()))
+    }
+}
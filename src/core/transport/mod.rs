@@ -0,0 +1,145 @@
+//! Talking to remote repositories. A [`Transport`] carries requests to one
+//! of the programs a remote runs for us (a [`Service`]), and the protocols
+//! spoken over it are built on [`pkt_line`]s.
+
+#[cfg(test)]
+pub(crate) mod canned;
+pub mod daemon;
+pub mod http;
+pub mod pkt_line;
+pub mod receive_pack;
+pub mod ssh;
+pub mod upload_pack;
+
+pub use receive_pack::ReceivePack;
+pub use upload_pack::UploadPack;
+
+use std::{
+    fmt,
+    io::{self, Read},
+};
+
+use bstr::{BStr, BString, ByteSlice};
+
+use crate::core::{db::UntypedOid, Config};
+
+/// How we introduce ourselves to servers that ask
+pub(crate) const AGENT: &str = concat!("writ/", env!("CARGO_PKG_VERSION"));
+
+/// Which program on the remote we talk to
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Service {
+    /// Sends objects, for fetching
+    UploadPack,
+    /// Receives objects, for pushing
+    ReceivePack,
+}
+
+/// Advertised by a service, like `fetch=shallow filter` or `report-status`
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Capabilities(Vec<BString>);
+
+/// A ref listed by a remote
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RemoteRef {
+    pub name: BString,
+    pub oid: UntypedOid,
+    /// What the ref points to, if it's symbolic
+    pub symref_target: Option<BString>,
+    /// What the ref's annotated tag points to
+    pub peeled: Option<UntypedOid>,
+}
+
+/// A connection to a service. Stateful transports keep one connection open
+/// for all requests, while stateless ones (like HTTP) connect for each.
+///
+/// We always ask `git-upload-pack` for protocol v2.
+pub trait Transport: fmt::Debug {
+    /// What the service says when we connect. For protocol v2 this is its
+    /// capabilities, otherwise its refs.
+    fn advertisement(&mut self) -> Result<Box<dyn Read + '_>, TransportError>;
+
+    /// Sends a complete request and returns the response
+    fn request(&mut self, request: &[u8]) -> Result<Box<dyn Read + '_>, TransportError>;
+}
+
+impl Service {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::UploadPack => "git-upload-pack",
+            Self::ReceivePack => "git-receive-pack",
+        }
+    }
+}
+
+impl Capabilities {
+    /// The value of a capability (which may be empty), or `None` if it
+    /// wasn't advertised
+    pub fn get(&self, name: &str) -> Option<&BStr> {
+        self.0.iter().find_map(|cap| {
+            let value = cap.strip_prefix(name.as_bytes())?;
+            if value.is_empty() {
+                Some(value.as_bstr())
+            } else {
+                value.strip_prefix(b"=").map(ByteSlice::as_bstr)
+            }
+        })
+    }
+}
+
+/// Around the first `sep`
+pub(crate) fn split_once(data: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let i = data.find_byte(sep)?;
+    Some((&data[..i], &data[i + 1..]))
+}
+
+/// Around the port in `host:port` or `[host]:port`
+fn split_port(authority: &str) -> (&str, Option<&str>) {
+    if let Some(rest) = authority.strip_prefix('[') {
+        if let Some(end) = rest.find(']') {
+            let port = rest[end + 1..].strip_prefix(':').filter(|p| !p.is_empty());
+            return (&rest[..end], port);
+        }
+    }
+    match authority.rfind(':') {
+        Some(i) if !authority[i + 1..].is_empty() => (&authority[..i], Some(&authority[i + 1..])),
+        Some(i) => (&authority[..i], None),
+        None => (authority, None),
+    }
+}
+
+/// Picks the transport by the scheme of the url, where `host:path` is for
+/// SSH like with scp
+pub fn connect(
+    url: &str,
+    service: Service,
+    config: &Config,
+) -> Result<Box<dyn Transport>, TransportError> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Box::new(http::Http::new(url, service)))
+    } else if let Some(address) = daemon::Address::parse(url) {
+        Ok(Box::new(daemon::Daemon::connect(&address, service)?))
+    } else if let Some(address) = ssh::Address::parse(url) {
+        Ok(Box::new(ssh::Ssh::connect(&address, service, config)?))
+    } else {
+        Err(TransportError::UnsupportedUrl(url.to_owned()))
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum TransportError {
+    /// Don't know how to connect to {0:?}
+    UnsupportedUrl(String),
+    /// Request to {0} failed
+    Http(String, #[source] Box<ureq::Error>),
+    /// {0} doesn't support the smart HTTP protocol
+    DumbHttp(String),
+    /// Failed to connect to {0}
+    Connect(String, #[source] io::Error),
+    /// Failed to run {0:?}
+    Spawn(String, #[source] io::Error),
+    /// Failed to send request to remote
+    Send(#[source] io::Error),
+    /// Invalid advertisement from remote
+    Advertisement(#[from] pkt_line::ReadError),
+}
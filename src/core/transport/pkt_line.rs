@@ -0,0 +1,302 @@
+//! The framing used by git's network protocols. Each packet is prefixed by
+//! its length (including the prefix) as four hex digits, and the lengths
+//! below four are special packets.
+
+use std::io::{self, Read, Write};
+
+use bstr::{BStr, BString, ByteSlice};
+use tracing::{debug, trace};
+
+use crate::core::progress::{Progress, Reporter, Stage};
+
+/// The most data a packet can hold
+pub const MAX_DATA_LEN: usize = 65516;
+
+const PREFIX_LEN: usize = 4;
+const ERR_PREFIX: &[u8] = b"ERR ";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Packet {
+    Data(BString),
+    /// `0000`, which ends a message
+    Flush,
+    /// `0001`, which separates the sections of a message (protocol v2 only)
+    Delim,
+    /// `0002`, which ends a response of a stateless connection (protocol v2
+    /// only)
+    ResponseEnd,
+}
+
+#[derive(Debug)]
+pub struct Reader<R> {
+    inner: R,
+}
+
+#[derive(Debug)]
+pub struct Writer<W> {
+    inner: W,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads exactly as much as each packet needs, so there's no need for `R`
+    /// to be buffered, and whatever follows can be read from `R` afterwards
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    /// Data packets starting with `ERR ` are returned as
+    /// [`ReadError::Remote`]
+    pub fn read(&mut self) -> Result<Packet, ReadError> {
+        let mut prefix = [0; PREFIX_LEN];
+        self.inner.read_exact(&mut prefix)?;
+        let len = prefix
+            .to_str()
+            .ok()
+            .and_then(|len| usize::from_str_radix(len, 16).ok())
+            .ok_or_else(|| ReadError::InvalidLength(prefix.as_bstr().to_owned()))?;
+
+        match len {
+            0 => Ok(Packet::Flush),
+            1 => Ok(Packet::Delim),
+            2 => Ok(Packet::ResponseEnd),
+            3 => Err(ReadError::InvalidLength(prefix.as_bstr().to_owned())),
+            len => {
+                trace!(len, "Receiving packet");
+                let mut data = vec![0; len - PREFIX_LEN];
+                self.inner.read_exact(&mut data)?;
+                if let Some(msg) = data.strip_prefix(ERR_PREFIX) {
+                    return Err(ReadError::Remote(trim_newline(msg).to_owned()));
+                }
+                Ok(Packet::Data(data.into()))
+            }
+        }
+    }
+
+    /// The data packets up to the next flush or delimiter (which is
+    /// returned), without trailing newlines
+    #[cfg_attr(feature = "trace", tracing::instrument(level = "trace", skip(self)))]
+    pub fn read_lines(&mut self) -> Result<(Vec<BString>, Packet), ReadError> {
+        let mut lines = Vec::new();
+        loop {
+            match self.read()? {
+                Packet::Data(data) => lines.push(trim_newline(&data).to_owned()),
+                end => return Ok((lines, end)),
+            }
+        }
+    }
+
+    /// Reads packets up to a flush, where the first byte of each is the band.
+    /// The data (band 1) is written to `out` and reported as received,
+    /// progress messages (band 2) are passed on a line at a time, and an
+    /// error (band 3) is returned as [`ReadError::Remote`].
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(level = "trace", skip(self, out, progress))
+    )]
+    pub fn demux(
+        &mut self,
+        out: &mut impl Write,
+        progress: &mut dyn Progress,
+    ) -> Result<(), ReadError> {
+        let mut reporter = Reporter::new(progress, Stage::Receiving, None);
+        // Messages can be split across packets, and progress bars end their
+        // lines with `\r` so that they're redrawn
+        let mut message = Vec::new();
+        loop {
+            let data = match self.read()? {
+                Packet::Data(data) => data,
+                Packet::Flush => {
+                    if !message.is_empty() {
+                        reporter.remote(message.as_bstr());
+                    }
+                    return Ok(());
+                }
+                packet => return Err(ReadError::UnexpectedPacket(packet)),
+            };
+            match data.split_first() {
+                Some((1, data)) => {
+                    out.write_all(data)?;
+                    reporter.add(0, data.len() as u64);
+                }
+                Some((2, msg)) => {
+                    for &byte in msg {
+                        if byte != b'\n' && byte != b'\r' {
+                            message.push(byte);
+                        } else if !message.is_empty() {
+                            debug!(msg = ?message.as_bstr(), "Remote progress");
+                            reporter.remote(message.as_bstr());
+                            message.clear();
+                        }
+                    }
+                }
+                Some((3, msg)) => return Err(ReadError::Remote(trim_newline(msg).to_owned())),
+                Some((&band, _)) => return Err(ReadError::InvalidBand(band)),
+                None => return Err(ReadError::InvalidBand(0)),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Splits data into as many packets as it needs
+    pub fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        trace!(len = data.len(), "Sending data");
+        for chunk in data.chunks(MAX_DATA_LEN) {
+            write!(self.inner, "{:04x}", chunk.len() + PREFIX_LEN)?;
+            self.inner.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// A packet of `line` followed by a newline. Lines must fit in a packet.
+    pub fn write_line(&mut self, line: impl AsRef<[u8]>) -> io::Result<()> {
+        let line = line.as_ref();
+        assert!(line.len() < MAX_DATA_LEN, "Line too long for a packet");
+        write!(self.inner, "{:04x}", line.len() + 1 + PREFIX_LEN)?;
+        self.inner.write_all(line)?;
+        self.inner.write_all(b"\n")
+    }
+
+    pub fn write_flush(&mut self) -> io::Result<()> {
+        self.inner.write_all(b"0000")
+    }
+
+    pub fn write_delim(&mut self) -> io::Result<()> {
+        self.inner.write_all(b"0001")
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+fn trim_newline(data: &[u8]) -> &BStr {
+    data.strip_suffix(b"\n").unwrap_or(data).as_bstr()
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ReadError {
+    /// Failed to read packet
+    Io(#[from] io::Error),
+    /// Invalid packet length {0:?}
+    InvalidLength(BString),
+    /// Remote error: {0}
+    Remote(BString),
+    /// Invalid sideband {0}
+    InvalidBand(u8),
+    /// Unexpected packet {0:?}
+    UnexpectedPacket(Packet),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::progress::Update;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn round_trips() -> eyre::Result<()> {
+        let mut writer = Writer::new(Vec::new());
+        writer.write_line("command=ls-refs")?;
+        writer.write_delim()?;
+        writer.write_data(b"peel")?;
+        writer.write_flush()?;
+        let written = writer.into_inner();
+        assert_eq!(
+            b"0014command=ls-refs\n00010008peel0000".as_bstr(),
+            written.as_bstr()
+        );
+
+        let mut reader = Reader::new(written.as_slice());
+        assert_eq!(
+            (vec![BString::from("command=ls-refs")], Packet::Delim),
+            reader.read_lines()?
+        );
+        assert_eq!(Packet::Data("peel".into()), reader.read()?);
+        assert_eq!(Packet::Flush, reader.read()?);
+        Ok(())
+    }
+
+    #[test]
+    fn splits_large_data() -> eyre::Result<()> {
+        let data = vec![b'x'; MAX_DATA_LEN + 1];
+        let mut writer = Writer::new(Vec::new());
+        writer.write_data(&data)?;
+        let written = writer.into_inner();
+
+        let mut reader = Reader::new(written.as_slice());
+        assert_eq!(
+            Packet::Data(vec![b'x'; MAX_DATA_LEN].into()),
+            reader.read()?
+        );
+        assert_eq!(Packet::Data("x".into()), reader.read()?);
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct Recorded {
+        received: Vec<u64>,
+        messages: Vec<BString>,
+    }
+
+    impl Progress for Recorded {
+        fn update(&mut self, update: &Update) {
+            self.received.push(update.bytes);
+        }
+
+        fn remote(&mut self, message: &BStr) {
+            self.messages.push(message.to_owned());
+        }
+    }
+
+    #[test]
+    fn demuxes_sidebands() -> eyre::Result<()> {
+        let mut writer = Writer::new(Vec::new());
+        writer.write_data(b"\x01PACK")?;
+        writer.write_data(b"\x02Counting objects: 50%\rCounting")?;
+        writer.write_data(b"\x02 objects: 100%\n")?;
+        writer.write_data(b"\x01data")?;
+        writer.write_flush()?;
+        writer.write_data(b"\x03oops\n")?;
+        writer.write_flush()?;
+        let written = writer.into_inner();
+
+        let mut reader = Reader::new(written.as_slice());
+        let mut out = Vec::new();
+        let mut progress = Recorded::default();
+        reader.demux(&mut out, &mut progress)?;
+        assert_eq!(b"PACKdata".as_bstr(), out.as_bstr());
+        assert_eq!(vec![4, 8], progress.received);
+        assert_eq!(
+            vec![
+                BString::from("Counting objects: 50%"),
+                BString::from("Counting objects: 100%")
+            ],
+            progress.messages
+        );
+
+        let err = reader.demux(&mut out, &mut ()).unwrap_err();
+        assert!(matches!(err, ReadError::Remote(msg) if msg == "oops"));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        let mut reader = Reader::new(&b"00zz"[..]);
+        assert!(matches!(reader.read(), Err(ReadError::InvalidLength(_))));
+
+        let mut reader = Reader::new(&b"000eERR denied"[..]);
+        assert!(matches!(reader.read(), Err(ReadError::Remote(msg)) if msg == "denied"));
+
+        let mut reader = Reader::new(&b"0009abc"[..]);
+        assert!(matches!(reader.read(), Err(ReadError::Io(_))));
+    }
+}
@@ -0,0 +1,259 @@
+//! The client side of `git-receive-pack`, which has no protocol v2, see
+//! <https://git-scm.com/docs/pack-protocol#_pushing_data_to_a_server>
+
+use std::{collections::BTreeMap, io};
+
+use bstr::{BStr, BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use super::{
+    pkt_line::{self, Packet},
+    split_once, Capabilities, RemoteRef, Transport, TransportError, AGENT,
+};
+use crate::core::{
+    db::UntypedOid,
+    progress::{Progress, RemoteOnly},
+};
+
+#[derive(Debug)]
+pub struct ReceivePack {
+    transport: Box<dyn Transport>,
+    refs: Vec<RemoteRef>,
+    capabilities: Capabilities,
+    atomic: bool,
+}
+
+/// A command to change a ref on the remote, where the zero oid is a missing
+/// ref. The remote only applies it if the ref is still `old`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RefUpdate {
+    pub name: BString,
+    pub old: UntypedOid,
+    pub new: UntypedOid,
+}
+
+/// What the remote did with a push
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Report {
+    /// Why the pack couldn't be unpacked, in which case no refs were updated
+    pub unpack_error: Option<BString>,
+    /// Refs the remote refused to update, and why
+    pub rejected: BTreeMap<BString, BString>,
+}
+
+impl ReceivePack {
+    /// Sent when there are no refs, so that there's a line for the
+    /// capabilities
+    const NO_REFS: &'static [u8] = b"capabilities^{}";
+    const PEELED_SUFFIX: &'static [u8] = b"^{}";
+
+    /// Reads the refs and capabilities the remote advertises, where the
+    /// capabilities follow a NUL in the first line
+    #[instrument(err)]
+    pub fn connect(mut transport: Box<dyn Transport>) -> Result<Self, ReceivePackError> {
+        let (lines, _) = pkt_line::Reader::new(transport.advertisement()?).read_lines()?;
+
+        let mut refs: Vec<RemoteRef> = Vec::new();
+        let mut capabilities = Capabilities::default();
+        for line in &lines {
+            if line.as_bytes() == b"version 1" {
+                continue;
+            }
+            let line = match split_once(line, b'\0') {
+                Some((line, caps)) => {
+                    capabilities = Capabilities(caps.fields().map(BString::from).collect());
+                    line
+                }
+                _ => line.as_bytes(),
+            };
+            let invalid = || ReceivePackError::InvalidRef(line.as_bstr().to_owned());
+            let (oid, name) = split_once(line, b' ').ok_or_else(invalid)?;
+            let oid = UntypedOid::parse(oid).map_err(|_| invalid())?;
+
+            if name == Self::NO_REFS {
+                continue;
+            }
+            if let Some(tag) = name.strip_suffix(Self::PEELED_SUFFIX) {
+                match refs.last_mut() {
+                    Some(last) if last.name == tag => last.peeled = Some(oid),
+                    _ => return Err(invalid()),
+                }
+            } else {
+                refs.push(RemoteRef {
+                    name: name.into(),
+                    oid,
+                    symref_target: None,
+                    peeled: None,
+                });
+            }
+        }
+        debug!(refs = refs.len(), ?capabilities);
+
+        Ok(Self {
+            transport,
+            refs,
+            capabilities,
+            atomic: false,
+        })
+    }
+
+    pub fn refs(&self) -> &[RemoteRef] {
+        &self.refs
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Asks the remote to make every update of later pushes or none, which it
+    /// has to support
+    pub fn set_atomic(&mut self, atomic: bool) -> Result<(), ReceivePackError> {
+        if atomic && self.capabilities.get("atomic").is_none() {
+            return Err(ReceivePackError::UnsupportedAtomic);
+        }
+        self.atomic = atomic;
+        Ok(())
+    }
+
+    /// The pack should have everything the updated refs need that the remote
+    /// doesn't have. It isn't needed if only deleting refs. If the remote
+    /// can multiplex its response, what it says (like the output of its
+    /// hooks) is passed on to `progress`.
+    #[instrument(err, skip(pack, progress), fields(pack = pack.map(<[u8]>::len)))]
+    pub fn push(
+        &mut self,
+        updates: &[RefUpdate],
+        pack: Option<&[u8]>,
+        progress: &mut dyn Progress,
+    ) -> Result<Report, ReceivePackError> {
+        let mut caps = Vec::new();
+        let report_status = self.capabilities.get("report-status").is_some();
+        if report_status {
+            caps.push("report-status".to_owned());
+        }
+        let sideband = self.capabilities.get("side-band-64k").is_some();
+        if sideband {
+            caps.push("side-band-64k".to_owned());
+        }
+        if self.atomic {
+            caps.push("atomic".to_owned());
+        }
+        if self.capabilities.get("agent").is_some() {
+            caps.push(format!("agent={AGENT}"));
+        }
+
+        let mut request = pkt_line::Writer::new(Vec::new());
+        let mut write = || -> io::Result<()> {
+            for (i, update) in updates.iter().enumerate() {
+                let mut command = format!(
+                    "{} {} {}",
+                    update.old.to_hex(),
+                    update.new.to_hex(),
+                    update.name
+                )
+                .into_bytes();
+                if i == 0 {
+                    command.push(b'\0');
+                    command.extend_from_slice(caps.join(" ").as_bytes());
+                }
+                request.write_line(command)?;
+            }
+            request.write_flush()
+        };
+        write().expect("Writing to a Vec can't fail");
+        let mut request = request.into_inner();
+        if let Some(pack) = pack {
+            request.extend_from_slice(pack);
+        }
+
+        let mut response = pkt_line::Reader::new(self.transport.request(&request)?);
+        // The report is in band 1, as packets of its own
+        let mut demuxed = Vec::new();
+        if sideband {
+            response.demux(&mut demuxed, &mut RemoteOnly(progress))?;
+        }
+        if !report_status {
+            return Ok(Report::default());
+        }
+        let (lines, end) = if sideband {
+            pkt_line::Reader::new(demuxed.as_slice()).read_lines()?
+        } else {
+            response.read_lines()?
+        };
+        if end != Packet::Flush {
+            return Err(pkt_line::ReadError::UnexpectedPacket(end).into());
+        }
+        Self::parse_report(&lines)
+    }
+
+    /// Like `unpack ok`, then `ok <ref>` or `ng <ref> <reason>` for each ref
+    fn parse_report(lines: &[BString]) -> Result<Report, ReceivePackError> {
+        let invalid = |line: &BStr| ReceivePackError::InvalidReport(line.to_owned());
+        let (unpack, statuses) = lines.split_first().ok_or_else(|| invalid(b"".as_bstr()))?;
+
+        let mut report = Report::default();
+        match unpack.strip_prefix(b"unpack ") {
+            Some(b"ok") => {}
+            Some(err) => report.unpack_error = Some(err.into()),
+            None => return Err(invalid(unpack.as_bstr())),
+        }
+        for line in statuses {
+            if line.starts_with(b"ok ") {
+                continue;
+            }
+            let rejected = line
+                .strip_prefix(b"ng ")
+                .ok_or_else(|| invalid(line.as_bstr()))?;
+            let (name, reason) = split_once(rejected, b' ').unwrap_or((rejected, b"rejected"));
+            report.rejected.insert(name.into(), reason.into());
+        }
+        Ok(report)
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ReceivePackError {
+    /// Failed to talk to remote
+    Transport(#[from] TransportError),
+    /// Invalid response from remote
+    Read(#[from] pkt_line::ReadError),
+    /// Invalid ref advertised: {0:?}
+    InvalidRef(BString),
+    /// Invalid status report from remote: {0:?}
+    InvalidReport(BString),
+    /// Remote doesn't support atomic pushes
+    UnsupportedAtomic,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_reports() -> eyre::Result<()> {
+        let lines = |lines: &[&str]| lines.iter().map(|&l| BString::from(l)).collect::<Vec<_>>();
+        assert_eq!(
+            Report::default(),
+            ReceivePack::parse_report(&lines(&["unpack ok", "ok refs/heads/main"]))?
+        );
+        assert_eq!(
+            Report {
+                unpack_error: Some("index-pack abnormal exit".into()),
+                rejected: [(
+                    BString::from("refs/heads/main"),
+                    BString::from("unpacker error")
+                )]
+                .iter()
+                .cloned()
+                .collect(),
+            },
+            ReceivePack::parse_report(&lines(&[
+                "unpack index-pack abnormal exit",
+                "ng refs/heads/main unpacker error",
+            ]))?
+        );
+        assert!(ReceivePack::parse_report(&lines(&["ok refs/heads/main"])).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! The SSH transport, which runs the service on the remote with `ssh` and
+//! talks to it over the command's stdin and stdout, keeping one connection
+//! for every request
+
+use std::{
+    env,
+    io::{Read, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+};
+
+use tracing::{debug, instrument};
+
+use super::{split_port, Service, Transport, TransportError};
+use crate::core::Config;
+
+/// Where a url like `ssh://user@host:22/path` or `user@host:path` points
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Address {
+    /// Like `user@host`
+    pub host: String,
+    pub port: Option<String>,
+    pub path: String,
+}
+
+#[derive(Debug)]
+pub struct Ssh {
+    child: Child,
+    /// Closed when we're done, so that the service exits
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl Address {
+    const SCHEMES: &'static [&'static str] = &["ssh://", "git+ssh://", "ssh+git://"];
+
+    /// `None` if `url` isn't for SSH
+    pub fn parse(url: &str) -> Option<Self> {
+        if let Some(rest) = Self::SCHEMES.iter().find_map(|s| url.strip_prefix(s)) {
+            let (authority, path) = rest.split_at(rest.find('/')?);
+            // Like `ssh://host/~user/repo` for a path relative to a home
+            let path = path.strip_prefix('/').filter(|p| p.starts_with('~'));
+            let path = path.unwrap_or(&rest[authority.len()..]);
+            let (host, port) = split_port(authority);
+            return Some(Self {
+                host: host.to_owned(),
+                port: port.map(str::to_owned),
+                path: path.to_owned(),
+            });
+        }
+
+        // The scp-like syntax, where a colon before any slash ends the host
+        if url.contains("://") {
+            return None;
+        }
+        let colon = url.find(':')?;
+        if url[..colon].contains('/') || colon == 0 {
+            return None;
+        }
+        let host = url[..colon].trim_start_matches('[').trim_end_matches(']');
+        Some(Self {
+            host: host.to_owned(),
+            port: None,
+            path: url[colon + 1..].to_owned(),
+        })
+    }
+}
+
+impl Ssh {
+    /// Runs `GIT_SSH_COMMAND` or `core.sshCommand` (with `sh`) if set, or
+    /// else `ssh`. The command is assumed to take OpenSSH's options.
+    #[instrument(err, skip(config))]
+    pub fn connect(
+        address: &Address,
+        service: Service,
+        config: &Config,
+    ) -> Result<Self, TransportError> {
+        let custom = env::var("GIT_SSH_COMMAND")
+            .ok()
+            .or_else(|| config.get("core.sshCommand").map(Into::into));
+        let mut command = match &custom {
+            // The arguments are passed on to the command, as git does
+            Some(custom) => {
+                let mut command = Command::new("sh");
+                command
+                    .arg("-c")
+                    .arg(format!("{custom} \"$@\""))
+                    .arg(custom);
+                command
+            }
+            None => Command::new("ssh"),
+        };
+
+        if service == Service::UploadPack {
+            command
+                .env("GIT_PROTOCOL", "version=2")
+                .args(["-o", "SendEnv=GIT_PROTOCOL"]);
+        }
+        if let Some(port) = &address.port {
+            command.args(["-p", port]);
+        }
+        command.arg(&address.host).arg(format!(
+            "{} {}",
+            service.name(),
+            shell_quote(&address.path)
+        ));
+        debug!(?command);
+
+        let program = custom.unwrap_or_else(|| "ssh".to_owned());
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| TransportError::Spawn(program, e))?;
+        let stdin = child.stdin.take().expect("Piped");
+        let stdout = child.stdout.take().expect("Piped");
+        Ok(Self {
+            child,
+            stdin: Some(stdin),
+            stdout,
+        })
+    }
+}
+
+/// In single quotes, for the remote shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl Transport for Ssh {
+    fn advertisement(&mut self) -> Result<Box<dyn Read + '_>, TransportError> {
+        Ok(Box::new(&mut self.stdout))
+    }
+
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(level = "trace", skip(self, request), fields(len = request.len()))
+    )]
+    fn request(&mut self, request: &[u8]) -> Result<Box<dyn Read + '_>, TransportError> {
+        let stdin = self.stdin.as_mut().expect("Only closed on drop");
+        stdin
+            .write_all(request)
+            .and_then(|()| stdin.flush())
+            .map_err(TransportError::Send)?;
+        Ok(Box::new(&mut self.stdout))
+    }
+}
+
+impl Drop for Ssh {
+    /// Waits for the service to finish, so that (for example) a push has been
+    /// applied once we're done
+    fn drop(&mut self) {
+        drop(self.stdin.take());
+        if let Err(err) = self.child.wait() {
+            debug!(%err, "Failed to wait for ssh");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn address(host: &str, port: Option<&str>, path: &str) -> Address {
+        Address {
+            host: host.to_owned(),
+            port: port.map(str::to_owned),
+            path: path.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            Some(address("git@github.com", None, "user/repo.git")),
+            Address::parse("git@github.com:user/repo.git")
+        );
+        assert_eq!(
+            Some(address("host", None, "/abs/repo")),
+            Address::parse("host:/abs/repo")
+        );
+        assert_eq!(
+            Some(address("git@host", Some("2222"), "/srv/repo")),
+            Address::parse("ssh://git@host:2222/srv/repo")
+        );
+        assert_eq!(
+            Some(address("::1", Some("22"), "/repo")),
+            Address::parse("ssh://[::1]:22/repo")
+        );
+        assert_eq!(
+            Some(address("host", None, "~user/repo")),
+            Address::parse("git+ssh://host/~user/repo")
+        );
+
+        assert_eq!(None, Address::parse("https://host/repo"));
+        assert_eq!(None, Address::parse("dir/file:name"));
+        assert_eq!(None, Address::parse("ssh://host"));
+    }
+
+    #[test]
+    fn quotes_paths() {
+        assert_eq!("'it'\\''s here'", shell_quote("it's here"));
+    }
+}
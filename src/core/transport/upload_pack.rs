@@ -0,0 +1,532 @@
+//! The client side of protocol v2 of `git-upload-pack`, see
+//! <https://git-scm.com/docs/protocol-v2>
+
+use std::{fmt, io};
+
+use bstr::{BStr, BString, ByteSlice};
+use tracing::{debug, instrument};
+
+use super::{
+    pkt_line::{self, Packet},
+    Capabilities, RemoteRef, Transport, TransportError, AGENT,
+};
+use crate::core::{db::UntypedOid, progress::Progress};
+
+#[derive(Debug)]
+pub struct UploadPack {
+    transport: Box<dyn Transport>,
+    capabilities: Capabilities,
+    /// Our shallow commits, which the remote needs to know we don't have the
+    /// parents of
+    shallow: Vec<UntypedOid>,
+    depth: Option<Depth>,
+    filter: Option<Filter>,
+    include_tag: bool,
+}
+
+/// Objects to leave out of a fetch, for a partial clone
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Filter {
+    /// `blob:none`, every blob
+    BlobNone,
+    /// `blob:limit=<n>`, blobs of at least this many bytes
+    BlobLimit(u64),
+}
+
+/// How much history to fetch
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Depth {
+    /// Like `git fetch --depth`, this many commits from the tips
+    Commits(u32),
+    /// Like `git fetch --deepen`, this many more commits than we have
+    Deepen(u32),
+}
+
+impl UploadPack {
+    #[instrument(err)]
+    pub fn connect(mut transport: Box<dyn Transport>) -> Result<Self, UploadPackError> {
+        let (mut lines, _) = pkt_line::Reader::new(transport.advertisement()?).read_lines()?;
+        if !matches!(lines.first(), Some(version) if version == "version 2") {
+            return Err(UploadPackError::UnsupportedVersion(
+                lines.into_iter().next(),
+            ));
+        }
+        let capabilities = Capabilities(lines.split_off(1));
+        debug!(?capabilities);
+        Ok(Self {
+            transport,
+            capabilities,
+            shallow: Vec::new(),
+            depth: None,
+            filter: None,
+            include_tag: false,
+        })
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// The refs starting with any of the prefixes, including symbolic refs
+    /// and what tags peel to
+    #[instrument(err)]
+    pub fn ls_refs(&mut self, prefixes: &[&str]) -> Result<Vec<RemoteRef>, UploadPackError> {
+        let mut args = vec![BString::from("symrefs"), BString::from("peel")];
+        args.extend(
+            prefixes
+                .iter()
+                .map(|prefix| BString::from(format!("ref-prefix {prefix}"))),
+        );
+        let request = self.command("ls-refs", &args);
+
+        let response = self.transport.request(&request)?;
+        let (lines, _) = pkt_line::Reader::new(response).read_lines()?;
+        lines
+            .iter()
+            .map(|line| Self::parse_ref(line.as_bstr()))
+            .collect()
+    }
+
+    /// Tells the remote about our `shallow` commits and asks for `depth` in
+    /// every later [`fetch`](Self::fetch), which the remote has to support if
+    /// there are any
+    pub fn set_shallow(
+        &mut self,
+        shallow: Vec<UntypedOid>,
+        depth: Option<Depth>,
+    ) -> Result<(), UploadPackError> {
+        if !self.supports_fetch("shallow") && (!shallow.is_empty() || depth.is_some()) {
+            return Err(UploadPackError::UnsupportedShallow);
+        }
+        self.shallow = shallow;
+        self.depth = depth;
+        Ok(())
+    }
+
+    /// Leaves out the objects matching `filter` from every later
+    /// [`fetch`](Self::fetch), which the remote has to support
+    pub fn set_filter(&mut self, filter: Option<Filter>) -> Result<(), UploadPackError> {
+        if filter.is_some() && !self.supports_fetch("filter") {
+            return Err(UploadPackError::UnsupportedFilter);
+        }
+        self.filter = filter;
+        Ok(())
+    }
+
+    /// Asks for the annotated tags pointing at what's sent to be sent too,
+    /// in every later [`fetch`](Self::fetch)
+    pub fn set_include_tag(&mut self, include_tag: bool) {
+        self.include_tag = include_tag;
+    }
+
+    /// Whether the `fetch` command has the feature
+    fn supports_fetch(&self, feature: &str) -> bool {
+        self.capabilities
+            .get("fetch")
+            .is_some_and(|features| features.split_str(" ").any(|f| f == feature.as_bytes()))
+    }
+
+    /// One round of asking for a pack of `wants` and everything they need,
+    /// leaving out what can be reached from `haves`. Until we're `done`, the
+    /// remote says which haves it has too, and only sends the pack (which
+    /// may be thin) once it's ready.
+    #[instrument(
+        err,
+        skip(wants, haves, progress),
+        fields(wants = wants.len(), haves = haves.len())
+    )]
+    pub fn fetch(
+        &mut self,
+        wants: &[UntypedOid],
+        haves: &[UntypedOid],
+        done: bool,
+        progress: &mut dyn Progress,
+    ) -> Result<FetchResponse, UploadPackError> {
+        let mut args = vec![BString::from("thin-pack"), BString::from("ofs-delta")];
+        if self.include_tag {
+            args.push("include-tag".into());
+        }
+        args.extend(wants.iter().map(|oid| BString::from(format!("want {oid}"))));
+        args.extend(haves.iter().map(|oid| BString::from(format!("have {oid}"))));
+        args.extend(
+            self.shallow
+                .iter()
+                .map(|oid| BString::from(format!("shallow {oid}"))),
+        );
+        match self.depth {
+            Some(Depth::Commits(depth)) => args.push(format!("deepen {depth}").into()),
+            Some(Depth::Deepen(depth)) => {
+                args.push(format!("deepen {depth}").into());
+                args.push("deepen-relative".into());
+            }
+            None => {}
+        }
+        if let Some(filter) = self.filter {
+            args.push(format!("filter {filter}").into());
+        }
+        if done {
+            args.push("done".into());
+        }
+        let request = self.command("fetch", &args);
+
+        let response = self.transport.request(&request)?;
+        let mut response = pkt_line::Reader::new(response);
+        let mut fetched = FetchResponse::default();
+        loop {
+            match response.read()? {
+                Packet::Data(header) if header.trim_end() == b"packfile" => break,
+                Packet::Data(header) if header.trim_end() == b"acknowledgments" => {
+                    let (lines, end) = response.read_lines()?;
+                    for line in lines {
+                        if let Some(oid) = line.strip_prefix(b"ACK ") {
+                            let oid = UntypedOid::parse(oid)
+                                .map_err(|_| UploadPackError::InvalidAck(line.clone()))?;
+                            fetched.common.push(oid);
+                        }
+                    }
+                    // Otherwise the remote is ready, and the pack follows
+                    if end == Packet::Flush && !done {
+                        return Ok(fetched);
+                    }
+                }
+                Packet::Data(header) if header.trim_end() == b"shallow-info" => {
+                    let (lines, end) = response.read_lines()?;
+                    for line in lines {
+                        let invalid = || UploadPackError::InvalidShallow(line.clone());
+                        let (kind, oid) = line.split_at(line.find_byte(b' ').ok_or_else(invalid)?);
+                        let oid = UntypedOid::parse(&oid[1..]).map_err(|_| invalid())?;
+                        match kind {
+                            b"shallow" => fetched.shallow.push(oid),
+                            b"unshallow" => fetched.unshallow.push(oid),
+                            _ => return Err(invalid()),
+                        }
+                    }
+                    if end != Packet::Delim {
+                        return Err(UploadPackError::NoPack);
+                    }
+                }
+                // Like `wanted-refs`
+                Packet::Data(header) => {
+                    debug!(?header, "Skipping section");
+                    if response.read_lines()?.1 != Packet::Delim {
+                        return Err(UploadPackError::NoPack);
+                    }
+                }
+                _ => return Err(UploadPackError::NoPack),
+            }
+        }
+        let mut pack = Vec::new();
+        response.demux(&mut pack, progress)?;
+        fetched.pack = Some(pack);
+        Ok(fetched)
+    }
+
+    fn command(&self, command: &str, args: &[BString]) -> Vec<u8> {
+        let mut request = pkt_line::Writer::new(Vec::new());
+        let mut write = || -> io::Result<()> {
+            request.write_line(format!("command={command}"))?;
+            if self.capabilities.get("agent").is_some() {
+                request.write_line(format!("agent={AGENT}"))?;
+            }
+            if self.capabilities.get("object-format").is_some() {
+                request.write_line("object-format=sha1")?;
+            }
+            request.write_delim()?;
+            for arg in args {
+                request.write_line(arg)?;
+            }
+            request.write_flush()
+        };
+        write().expect("Writing to a Vec can't fail");
+        request.into_inner()
+    }
+
+    /// Like `<oid> <name> symref-target:<target> peeled:<oid>`, where the
+    /// attributes are optional
+    fn parse_ref(line: &BStr) -> Result<RemoteRef, UploadPackError> {
+        let invalid = || UploadPackError::InvalidRef(line.to_owned());
+        let mut fields = line.split_str(" ");
+        let oid = fields.next().ok_or_else(invalid)?;
+        let oid = UntypedOid::parse(oid).map_err(|_| invalid())?;
+        let name = fields.next().ok_or_else(invalid)?.as_bstr().to_owned();
+
+        let mut remote_ref = RemoteRef {
+            name,
+            oid,
+            symref_target: None,
+            peeled: None,
+        };
+        for attribute in fields {
+            if let Some(target) = attribute.strip_prefix(b"symref-target:") {
+                remote_ref.symref_target = Some(target.as_bstr().to_owned());
+            } else if let Some(peeled) = attribute.strip_prefix(b"peeled:") {
+                remote_ref.peeled = Some(UntypedOid::parse(peeled).map_err(|_| invalid())?);
+            }
+        }
+        Ok(remote_ref)
+    }
+}
+
+impl Filter {
+    /// Like `blob:limit=1m`, `None` if it isn't a filter we support
+    pub fn parse(spec: &str) -> Option<Self> {
+        if spec == "blob:none" {
+            return Some(Self::BlobNone);
+        }
+        let limit = spec.strip_prefix("blob:limit=")?;
+        let (digits, scale) = match limit.as_bytes().last()?.to_ascii_lowercase() {
+            b'k' => (&limit[..limit.len() - 1], 1 << 10),
+            b'm' => (&limit[..limit.len() - 1], 1 << 20),
+            b'g' => (&limit[..limit.len() - 1], 1 << 30),
+            _ => (limit, 1),
+        };
+        let limit = digits.parse::<u64>().ok()?.checked_mul(scale)?;
+        Some(Self::BlobLimit(limit))
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BlobNone => write!(f, "blob:none"),
+            Self::BlobLimit(limit) => write!(f, "blob:limit={limit}"),
+        }
+    }
+}
+
+/// What the remote said to a round of [`UploadPack::fetch`]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FetchResponse {
+    /// The haves the remote has too
+    pub common: Vec<UntypedOid>,
+    /// Commits sent without their parents
+    pub shallow: Vec<UntypedOid>,
+    /// Shallow commits whose parents are now sent
+    pub unshallow: Vec<UntypedOid>,
+    pub pack: Option<Vec<u8>>,
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum UploadPackError {
+    /// Failed to talk to remote
+    Transport(#[from] TransportError),
+    /// Invalid response from remote
+    Read(#[from] pkt_line::ReadError),
+    /// Remote doesn't support protocol v2. It started with {0:?}
+    UnsupportedVersion(Option<BString>),
+    /// Invalid ref advertised: {0:?}
+    InvalidRef(BString),
+    /// Invalid acknowledgment from remote: {0:?}
+    InvalidAck(BString),
+    /// Invalid shallow commit from remote: {0:?}
+    InvalidShallow(BString),
+    /// Remote doesn't support shallow fetches
+    UnsupportedShallow,
+    /// Remote doesn't support filtering objects
+    UnsupportedFilter,
+    /// Remote didn't send a pack
+    NoPack,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::transport::canned::{packets, Canned};
+    use pretty_assertions::assert_eq;
+    use std::rc::Rc;
+
+    const OID_A: &str = "1111111111111111111111111111111111111111";
+    const OID_B: &str = "2222222222222222222222222222222222222222";
+
+    #[test]
+    fn lists_refs_and_fetches() -> eyre::Result<()> {
+        let requests = Rc::default();
+        let transport = Canned {
+            advertisement: packets(&["version 2\n", "agent=git/2.39\n", "ls-refs\n", "0000"]),
+            responses: vec![
+                packets(&[
+                    &format!("{OID_A} HEAD symref-target:refs/heads/main\n"),
+                    &format!("{OID_A} refs/heads/main\n"),
+                    &format!("{OID_B} refs/tags/v1 peeled:{OID_A}\n"),
+                    "0000",
+                ]),
+                packets(&[
+                    "shallow-info\n",
+                    &format!("shallow {OID_A}\n"),
+                    "0001",
+                    "packfile\n",
+                    "\x02Enumerating objects\n",
+                    "\x01PACK",
+                    "0000",
+                ]),
+            ],
+            requests: Rc::clone(&requests),
+        };
+
+        let mut upload_pack = UploadPack::connect(Box::new(transport))?;
+        assert_eq!(
+            Some("git/2.39".as_bytes().as_bstr()),
+            upload_pack.capabilities().get("agent")
+        );
+        assert_eq!(
+            Some("".as_bytes().as_bstr()),
+            upload_pack.capabilities().get("ls-refs")
+        );
+        assert_eq!(None, upload_pack.capabilities().get("fetch"));
+
+        let oid_a = UntypedOid::parse(OID_A)?;
+        let oid_b = UntypedOid::parse(OID_B)?;
+        assert_eq!(
+            vec![
+                RemoteRef {
+                    name: "HEAD".into(),
+                    oid: oid_a,
+                    symref_target: Some("refs/heads/main".into()),
+                    peeled: None,
+                },
+                RemoteRef {
+                    name: "refs/heads/main".into(),
+                    oid: oid_a,
+                    symref_target: None,
+                    peeled: None,
+                },
+                RemoteRef {
+                    name: "refs/tags/v1".into(),
+                    oid: oid_b,
+                    symref_target: None,
+                    peeled: Some(oid_a),
+                },
+            ],
+            upload_pack.ls_refs(&["HEAD", "refs/heads/"])?
+        );
+
+        assert_eq!(
+            FetchResponse {
+                shallow: vec![oid_a],
+                pack: Some(b"PACK".to_vec()),
+                ..FetchResponse::default()
+            },
+            upload_pack.fetch(&[oid_a], &[oid_b], true, &mut ())?
+        );
+
+        let agent = format!("agent={AGENT}\n");
+        assert_eq!(
+            vec![
+                BString::from(packets(&[
+                    "command=ls-refs\n",
+                    &agent,
+                    "0001",
+                    "symrefs\n",
+                    "peel\n",
+                    "ref-prefix HEAD\n",
+                    "ref-prefix refs/heads/\n",
+                    "0000",
+                ])),
+                BString::from(packets(&[
+                    "command=fetch\n",
+                    &agent,
+                    "0001",
+                    "thin-pack\n",
+                    "ofs-delta\n",
+                    &format!("want {OID_A}\n"),
+                    &format!("have {OID_B}\n"),
+                    "done\n",
+                    "0000",
+                ])),
+            ],
+            *requests.borrow()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn asks_for_shallow_history() -> eyre::Result<()> {
+        let requests = Rc::default();
+        let transport = Canned {
+            advertisement: packets(&["version 2\n", "fetch=shallow wait-for-done\n", "0000"]),
+            responses: vec![packets(&[
+                "shallow-info\n",
+                &format!("unshallow {OID_A}\n"),
+                "0001",
+                "packfile\n",
+                "\x01PACK",
+                "0000",
+            ])],
+            requests: Rc::clone(&requests),
+        };
+        let mut upload_pack = UploadPack::connect(Box::new(transport))?;
+
+        let oid_a = UntypedOid::parse(OID_A)?;
+        let oid_b = UntypedOid::parse(OID_B)?;
+        upload_pack.set_shallow(vec![oid_a], Some(Depth::Deepen(2)))?;
+        assert_eq!(
+            vec![oid_a],
+            upload_pack.fetch(&[oid_b], &[], true, &mut ())?.unshallow
+        );
+        assert_eq!(
+            vec![BString::from(packets(&[
+                "command=fetch\n",
+                "0001",
+                "thin-pack\n",
+                "ofs-delta\n",
+                &format!("want {OID_B}\n"),
+                &format!("shallow {OID_A}\n"),
+                "deepen 2\n",
+                "deepen-relative\n",
+                "done\n",
+                "0000",
+            ]))],
+            *requests.borrow()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn requires_fetch_features() -> eyre::Result<()> {
+        let transport = Canned {
+            advertisement: packets(&["version 2\n", "fetch\n", "0000"]),
+            responses: Vec::new(),
+            requests: Rc::default(),
+        };
+        let mut upload_pack = UploadPack::connect(Box::new(transport))?;
+        upload_pack.set_shallow(Vec::new(), None)?;
+        assert!(matches!(
+            upload_pack.set_shallow(Vec::new(), Some(Depth::Commits(1))),
+            Err(UploadPackError::UnsupportedShallow)
+        ));
+        assert!(matches!(
+            upload_pack.set_filter(Some(Filter::BlobNone)),
+            Err(UploadPackError::UnsupportedFilter)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn parses_filters() {
+        assert_eq!(Some(Filter::BlobNone), Filter::parse("blob:none"));
+        assert_eq!(
+            Some(Filter::BlobLimit(100)),
+            Filter::parse("blob:limit=100")
+        );
+        assert_eq!(
+            Some(Filter::BlobLimit(2 << 20)),
+            Filter::parse("blob:limit=2m")
+        );
+        assert_eq!(None, Filter::parse("blob:limit="));
+        assert_eq!(None, Filter::parse("tree:0"));
+        assert_eq!("blob:limit=1024", Filter::BlobLimit(1024).to_string());
+    }
+
+    #[test]
+    fn requires_protocol_v2() {
+        let transport = Canned {
+            advertisement: packets(&[&format!("{OID_A} HEAD\0multi_ack\n"), "0000"]),
+            responses: Vec::new(),
+            requests: Rc::default(),
+        };
+        assert!(matches!(
+            UploadPack::connect(Box::new(transport)),
+            Err(UploadPackError::UnsupportedVersion(Some(_)))
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+//! Putting entries in the index as they're given, without looking at the
+//! workspace, like `git update-index --cacheinfo` and `--index-info`
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead},
+};
+
+use bstr::{BString, ByteSlice};
+use tracing::instrument;
+
+use crate::core::{
+    db::Blob,
+    index::{self, entry::Stage, Conflict, Entry},
+    stat::Mode,
+    ws::{self, path::InvalidPathError},
+    Oid, Repo, Stat, WsPath,
+};
+
+/// An entry to put in the index, or with no mode one to take out of it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CacheInfo {
+    /// `None` removes the path, like a mode of `0` in `--index-info`
+    pub mode: Option<Mode>,
+    pub oid: Oid<Blob>,
+    pub path: WsPath,
+    pub stage: Stage,
+}
+
+impl CacheInfo {
+    pub fn new(mode: Mode, oid: Oid<Blob>, path: WsPath) -> Self {
+        Self {
+            mode: Some(mode),
+            oid,
+            path,
+            stage: Stage::Resolved,
+        }
+    }
+
+    /// Parses a line of `--index-info`, which is any of
+    ///
+    /// - `<mode> SP <oid> TAB <path>`
+    /// - `<mode> SP <type> SP <oid> TAB <path>`, like `git ls-tree` outputs
+    /// - `<mode> SP <oid> SP <stage> TAB <path>`, like `git ls-files -s`
+    ///
+    /// Unlike git, the path isn't unquoted.
+    pub fn parse(line: &[u8]) -> Result<Self, ParseCacheInfoError> {
+        let invalid = || ParseCacheInfoError(line.into());
+        let tab = line.find_byte(b'\t').ok_or_else(invalid)?;
+        let (fields, path) = (&line[..tab], &line[tab + 1..]);
+        let fields = fields.split_str(" ").collect::<Vec<_>>();
+
+        let (mode, oid, stage) = match fields[..] {
+            [mode, oid] => (mode, oid, None),
+            [mode, ty, oid] if ty.iter().all(u8::is_ascii_lowercase) => (mode, oid, None),
+            [mode, oid, stage] => (mode, oid, Some(stage)),
+            _ => return Err(invalid()),
+        };
+        let mode = match mode {
+            b"0" => None,
+            b"100644" | b"100755" | b"120000" | b"160000" => Some(Mode::from_base8(mode.as_bstr())),
+            _ => return Err(invalid()),
+        };
+        let stage = match stage {
+            None | Some(b"0") => Stage::Resolved,
+            Some(b"1") => Stage::Base,
+            Some(b"2") => Stage::Ours,
+            Some(b"3") => Stage::Theirs,
+            Some(_) => return Err(invalid()),
+        };
+        let oid = Oid::parse(oid).map_err(|_| invalid())?;
+        let path = WsPath::new_normalized(path.to_path().map_err(|_| invalid())?)
+            .map_err(|_| invalid())?;
+
+        Ok(Self {
+            mode,
+            oid,
+            path,
+            stage,
+        })
+    }
+
+    fn entry(&self, mode: Mode) -> Result<Entry, InvalidPathError> {
+        let stat = Stat {
+            mode,
+            ..Stat::zeroed()
+        };
+        let entry = Entry::try_new(self.path.clone(), self.oid, stat)?;
+        Ok(entry.with_stage(self.stage))
+    }
+}
+
+impl Repo {
+    /// Puts each entry in the index as given, like `git update-index
+    /// --cacheinfo`, even in a bare repository. Neither the workspace nor
+    /// the database is looked at, so the objects don't have to exist, and
+    /// the entries will be seen as modified until they're refreshed.
+    ///
+    /// An entry at stage `0` replaces the path, resolving any conflict.
+    /// Entries at other stages are added to the conflict for their path,
+    /// replacing any resolved entry for it.
+    #[instrument(err, skip(entries))]
+    pub fn update_index_cacheinfo(
+        &mut self,
+        entries: impl IntoIterator<Item = CacheInfo>,
+    ) -> Result<(), UpdateIndexError> {
+        self.index.reload()?;
+        let mut index = self.index.modify()?;
+
+        let mut conflicts = BTreeMap::<WsPath, Conflict>::new();
+        for info in entries {
+            let Some(mode) = info.mode else {
+                index.remove(&info.path);
+                conflicts.remove(&info.path);
+                continue;
+            };
+            let entry = info.entry(mode)?;
+            if info.stage == Stage::Resolved {
+                index.add(entry);
+                conflicts.remove(&info.path);
+                continue;
+            }
+
+            let conflict = conflicts
+                .entry(info.path.clone())
+                .or_insert_with(|| index.conflict(&info.path).cloned().unwrap_or_default());
+            match info.stage {
+                Stage::Base => conflict.base = Some(entry),
+                Stage::Ours => conflict.ours = Some(entry),
+                Stage::Theirs => conflict.theirs = Some(entry),
+                Stage::Resolved => unreachable!(),
+            }
+            index.add_conflict(&info.path, conflict.clone());
+        }
+
+        index.commit()?;
+        Ok(())
+    }
+
+    /// Like [`Self::update_index_cacheinfo`], reading the entries from lines
+    /// in the format of `git update-index --index-info` (see
+    /// [`CacheInfo::parse`])
+    #[instrument(err, skip(reader))]
+    pub fn update_index_info(&mut self, reader: impl BufRead) -> Result<(), UpdateIndexError> {
+        let mut entries = Vec::new();
+        for line in reader.split(b'\n') {
+            let line = line.map_err(UpdateIndexError::Read)?;
+            if !line.is_empty() {
+                entries.push(CacheInfo::parse(&line)?);
+            }
+        }
+        self.update_index_cacheinfo(entries)
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+/// Invalid index info {0:?}
+pub struct ParseCacheInfoError(BString);
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum UpdateIndexError {
+    /// Failed to read index info
+    Read(#[source] io::Error),
+    /// {0}
+    Parse(#[from] ParseCacheInfoError),
+    /// {0}
+    InvalidPath(#[from] ws::path::InvalidPathError),
+    /// Failed to reload index
+    ReloadIndex(#[from] index::LoadError),
+    /// Failed to open index for modifications
+    OpenIndex(#[from] index::OpenForModificationsError),
+    /// Failed to write index
+    CommitIndex(#[from] index::CommitError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parses_each_format() -> eyre::Result<()> {
+        let oid = Oid::<Blob>::parse("e69de29bb2d1d6434b8b29ae775ad8c2e48c5391")?;
+        let path = WsPath::new_normalized("dir/file.txt")?;
+        let hex = oid.to_hex();
+        assert_eq!(
+            CacheInfo::new(Mode::Regular, oid, path.clone()),
+            CacheInfo::parse(format!("100644 {hex}\tdir/file.txt").as_bytes())?
+        );
+        assert_eq!(
+            CacheInfo::new(Mode::Executable, oid, path.clone()),
+            CacheInfo::parse(format!("100755 blob {hex}\tdir/file.txt").as_bytes())?
+        );
+        assert_eq!(
+            CacheInfo {
+                stage: Stage::Theirs,
+                ..CacheInfo::new(Mode::Symlink, oid, path.clone())
+            },
+            CacheInfo::parse(format!("120000 {hex} 3\tdir/file.txt").as_bytes())?
+        );
+        assert_eq!(
+            None,
+            CacheInfo::parse(format!("0 {hex}\tdir/file.txt").as_bytes())?.mode
+        );
+        assert!(CacheInfo::parse(format!("040000 tree {hex}\tdir").as_bytes()).is_err());
+        assert!(CacheInfo::parse(format!("100644 {hex} 4\tdir").as_bytes()).is_err());
+        assert!(CacheInfo::parse(format!("100644 {hex}").as_bytes()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,481 @@
+//! Verifying the signatures of commits and tags, like `git verify-commit` and
+//! `git verify-tag`. Commits keep their signature in a `gpgsig` header, and
+//! tags at the end of their message. Signatures are checked by a
+//! [`Verifier`], which by default runs `gpg`, `gpgsm` or `ssh-keygen` like git.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+    thread,
+};
+
+use bstr::{BString, ByteSlice};
+use tempfile::NamedTempFile;
+use tracing::{debug, instrument};
+
+use crate::core::{
+    config::{self, Config},
+    db::{Commit, LoadRawError, UntypedOid},
+    Oid, Repo,
+};
+
+/// Checks that a signature is of the payload
+pub trait Verifier: fmt::Debug {
+    fn verify(
+        &self,
+        format: SignatureFormat,
+        payload: &[u8],
+        signature: &[u8],
+    ) -> Result<Verification, VerifyError>;
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SignatureFormat {
+    OpenPgp,
+    X509,
+    Ssh,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SignatureStatus {
+    Good,
+    /// Doesn't match what's signed
+    Bad,
+    /// Can't be checked, or is good but by a key that isn't trusted
+    UnknownKey,
+    /// Good, but the signature or key has expired
+    Expired,
+    /// Good, but the key has been revoked
+    Revoked,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Verification {
+    pub format: SignatureFormat,
+    pub status: SignatureStatus,
+    /// Like `A U Thor <author@example.com>`, or the principal for SSH
+    pub signer: Option<String>,
+    /// The ID of the key, or its fingerprint for SSH
+    pub key: Option<String>,
+}
+
+impl Repo {
+    /// The signature of the commit checked with the programs configured, or
+    /// `None` if it isn't signed
+    pub fn verify_commit(&self, oid: Oid<Commit>) -> Result<Option<Verification>, VerifyError> {
+        let programs = Programs::from_config(&self.config)?;
+        self.verify_commit_with(oid, &programs)
+    }
+
+    /// Like [`Self::verify_commit`], with a signature checked by `verifier`
+    #[instrument(err)]
+    pub fn verify_commit_with(
+        &self,
+        oid: Oid<Commit>,
+        verifier: &dyn Verifier,
+    ) -> Result<Option<Verification>, VerifyError> {
+        let data = self.load_signed(oid.into_untyped(), b"commit")?;
+        match split_commit(&data) {
+            Some((payload, signature)) => verify(verifier, &payload, &signature).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The signature of the annotated tag checked with the programs
+    /// configured, or `None` if it isn't signed
+    pub fn verify_tag(&self, oid: UntypedOid) -> Result<Option<Verification>, VerifyError> {
+        let programs = Programs::from_config(&self.config)?;
+        self.verify_tag_with(oid, &programs)
+    }
+
+    /// Like [`Self::verify_tag`], with a signature checked by `verifier`
+    #[instrument(err)]
+    pub fn verify_tag_with(
+        &self,
+        oid: UntypedOid,
+        verifier: &dyn Verifier,
+    ) -> Result<Option<Verification>, VerifyError> {
+        let data = self.load_signed(oid, b"tag")?;
+        match split_tag(&data) {
+            Some((payload, signature)) => verify(verifier, payload, signature).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// As it's stored, as replacements aren't what was signed
+    fn load_signed(&self, oid: UntypedOid, expected: &[u8]) -> Result<Vec<u8>, VerifyError> {
+        let (ty, data) = self.db.load_raw(&oid)?.ok_or(VerifyError::Missing(oid))?;
+        if ty != expected {
+            return Err(VerifyError::WrongType {
+                oid,
+                expected: expected.as_bstr().to_string(),
+                actual: ty.to_str_lossy().into_owned(),
+            });
+        }
+        Ok(data)
+    }
+}
+
+fn verify(
+    verifier: &dyn Verifier,
+    payload: &[u8],
+    signature: &[u8],
+) -> Result<Verification, VerifyError> {
+    let format = format_of(signature);
+    debug!(?format, "Verifying");
+    verifier.verify(format, payload, signature)
+}
+
+/// By the armor it starts with, where anything unknown is taken to be PGP
+/// like git
+fn format_of(signature: &[u8]) -> SignatureFormat {
+    if signature.starts_with(b"-----BEGIN SSH SIGNATURE-----") {
+        SignatureFormat::Ssh
+    } else if signature.starts_with(b"-----BEGIN SIGNED MESSAGE-----") {
+        SignatureFormat::X509
+    } else {
+        SignatureFormat::OpenPgp
+    }
+}
+
+/// The commit without its `gpgsig` header, and the signature in it
+fn split_commit(data: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let headers_len = data.find(b"\n\n").map_or(data.len(), |end| end + 1);
+    let mut payload = Vec::with_capacity(data.len());
+    let mut signature = None::<Vec<u8>>;
+    let mut in_signature = false;
+    for line in data[..headers_len].lines_with_terminator() {
+        if let Some(first) = line.strip_prefix(b"gpgsig ") {
+            signature
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(first);
+            in_signature = true;
+        } else if let (true, Some(rest)) = (in_signature, line.strip_prefix(b" ")) {
+            signature
+                .get_or_insert_with(Vec::new)
+                .extend_from_slice(rest);
+        } else {
+            in_signature = false;
+            payload.extend_from_slice(line);
+        }
+    }
+    payload.extend_from_slice(&data[headers_len..]);
+    signature.map(|signature| (payload, signature))
+}
+
+/// The tag up to the signature at the end of its message, and the signature
+fn split_tag(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let armors: [&[u8]; 4] = [
+        b"-----BEGIN PGP SIGNATURE-----",
+        b"-----BEGIN PGP MESSAGE-----",
+        b"-----BEGIN SIGNED MESSAGE-----",
+        b"-----BEGIN SSH SIGNATURE-----",
+    ];
+    let body = data.find(b"\n\n")? + 2;
+    let start = data[body..]
+        .lines_with_terminator()
+        .scan(body, |start, line| {
+            let line_start = *start;
+            *start += line.len();
+            Some((line_start, line))
+        })
+        .filter(|(_, line)| armors.iter().any(|armor| line.starts_with(armor)))
+        .map(|(start, _)| start)
+        .last()?;
+    Some(data.split_at(start))
+}
+
+/// Runs `gpg` for PGP (`gpg.openpgp.program`, or `gpg.program`), `gpgsm`
+/// for X.509 (`gpg.x509.program`) and `ssh-keygen` for SSH
+/// (`gpg.ssh.program`), like git. SSH signers are looked up in
+/// `gpg.ssh.allowedSignersFile`, and any signature of a key not in it is
+/// [`SignatureStatus::UnknownKey`] at best.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Programs {
+    pub openpgp: PathBuf,
+    pub x509: PathBuf,
+    pub ssh: PathBuf,
+    pub allowed_signers: Option<PathBuf>,
+}
+
+impl Default for Programs {
+    fn default() -> Self {
+        Self {
+            openpgp: "gpg".into(),
+            x509: "gpgsm".into(),
+            ssh: "ssh-keygen".into(),
+            allowed_signers: None,
+        }
+    }
+}
+
+impl Programs {
+    pub fn from_config(config: &Config) -> Result<Self, config::ValueError> {
+        let default = Self::default();
+        let openpgp = match config.get_path("gpg.openpgp.program")? {
+            Some(program) => Some(program),
+            None => config.get_path("gpg.program")?,
+        };
+        Ok(Self {
+            openpgp: openpgp.unwrap_or(default.openpgp),
+            x509: config.get_path("gpg.x509.program")?.unwrap_or(default.x509),
+            ssh: config.get_path("gpg.ssh.program")?.unwrap_or(default.ssh),
+            allowed_signers: config.get_path("gpg.ssh.allowedSignersFile")?,
+        })
+    }
+
+    /// Goes by the status gpg (or gpgsm) writes, whatever it exits with
+    fn verify_gpg(
+        &self,
+        format: SignatureFormat,
+        payload: &[u8],
+        signature: &[u8],
+    ) -> Result<Verification, VerifyError> {
+        let program = match format {
+            SignatureFormat::X509 => &self.x509,
+            _ => &self.openpgp,
+        };
+        let file = signature_file(signature)?;
+        let mut command = Command::new(program);
+        command
+            .args(["--status-fd=1", "--keyid-format=long", "--verify"])
+            .arg(file.path())
+            .arg("-");
+        let output = run(command, payload)?;
+        let (status, key, signer) = gpg_status(&output.stdout)
+            .ok_or_else(|| VerifyError::Failed(program.clone(), output.stderr.trim().into()))?;
+        Ok(Verification {
+            format,
+            status,
+            signer,
+            key,
+        })
+    }
+
+    /// Finds who signed it in the allowed signers, then checks it's them, like
+    /// git. Without a signer it's only checked that it's good.
+    fn verify_ssh(&self, payload: &[u8], signature: &[u8]) -> Result<Verification, VerifyError> {
+        let file = signature_file(signature)?;
+        let ssh_keygen = |args: &[&str]| {
+            let mut command = Command::new(&self.ssh);
+            command.arg("-Y").args(args);
+            command
+        };
+
+        let mut principal = None;
+        if let Some(allowed) = &self.allowed_signers {
+            let mut command = ssh_keygen(&["find-principals"]);
+            command.arg("-f").arg(allowed).arg("-s").arg(file.path());
+            let output = run(command, b"")?;
+            if output.status.success() {
+                let found = output.stdout.lines().next().map(|line| line.to_str_lossy());
+                principal = found.map(|principal| principal.trim().to_owned());
+            }
+        }
+
+        let output = if let (Some(principal), Some(allowed)) = (&principal, &self.allowed_signers) {
+            let mut command = ssh_keygen(&["verify", "-n", "git"]);
+            command
+                .arg("-f")
+                .arg(allowed)
+                .args(["-I", principal, "-s"])
+                .arg(file.path());
+            run(command, payload)?
+        } else {
+            let mut command = ssh_keygen(&["check-novalidate", "-n", "git", "-s"]);
+            command.arg(file.path());
+            run(command, payload)?
+        };
+        let status = match (output.status.success(), &principal) {
+            (false, _) => SignatureStatus::Bad,
+            (true, Some(_)) => SignatureStatus::Good,
+            (true, None) => SignatureStatus::UnknownKey,
+        };
+        let key = output
+            .stdout
+            .split_str(" ")
+            .find(|word| word.starts_with(b"SHA256:"))
+            .map(|key| key.trim().to_str_lossy().into_owned());
+        Ok(Verification {
+            format: SignatureFormat::Ssh,
+            status,
+            signer: principal,
+            key,
+        })
+    }
+}
+
+impl Verifier for Programs {
+    fn verify(
+        &self,
+        format: SignatureFormat,
+        payload: &[u8],
+        signature: &[u8],
+    ) -> Result<Verification, VerifyError> {
+        match format {
+            SignatureFormat::Ssh => self.verify_ssh(payload, signature),
+            SignatureFormat::OpenPgp | SignatureFormat::X509 => {
+                self.verify_gpg(format, payload, signature)
+            }
+        }
+    }
+}
+
+/// The status, key ID and user ID from `--status-fd` output
+fn gpg_status(output: &[u8]) -> Option<(SignatureStatus, Option<String>, Option<String>)> {
+    for line in output.lines() {
+        let Some(line) = line.strip_prefix(b"[GNUPG:] ") else {
+            continue;
+        };
+        let mut fields = line.splitn_str(3, " ");
+        let status = match fields.next()? {
+            b"GOODSIG" => SignatureStatus::Good,
+            b"BADSIG" => SignatureStatus::Bad,
+            b"EXPSIG" | b"EXPKEYSIG" => SignatureStatus::Expired,
+            b"REVKEYSIG" => SignatureStatus::Revoked,
+            b"ERRSIG" => SignatureStatus::UnknownKey,
+            _ => continue,
+        };
+        let key = fields.next().map(|key| key.to_str_lossy().into_owned());
+        let signer = match status {
+            // The rest is about the algorithms
+            SignatureStatus::UnknownKey => None,
+            _ => fields.next().map(|uid| uid.to_str_lossy().into_owned()),
+        };
+        return Some((status, key, signer));
+    }
+    None
+}
+
+/// As the programs read the signature from a file, and the payload from stdin
+fn signature_file(signature: &[u8]) -> Result<NamedTempFile, VerifyError> {
+    let mut file = NamedTempFile::new().map_err(VerifyError::SignatureFile)?;
+    file.write_all(signature)
+        .map_err(VerifyError::SignatureFile)?;
+    Ok(file)
+}
+
+fn run(mut command: Command, input: &[u8]) -> Result<Output, VerifyError> {
+    let program = PathBuf::from(command.get_program());
+    let run_err = |err| VerifyError::Run(program.clone(), err);
+    debug!(?command, "Running");
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(run_err)?;
+    // Written as it reads, so that neither waits on the other's output
+    let mut stdin = child.stdin.take().expect("Piped");
+    let input = input.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output().map_err(run_err)?;
+    match writer.join().expect("Writing doesn't panic") {
+        // It doesn't have to read everything
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => Err(run_err(err)),
+        _ => Ok(output),
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum VerifyError {
+    /// Invalid signing config
+    Config(#[from] config::ValueError),
+    /// Failed to load object to verify
+    Load(#[from] LoadRawError),
+    /// {0:?} not found in database
+    Missing(UntypedOid),
+    /// Expected {oid:?} to be a {expected}, got {actual}
+    WrongType {
+        oid: UntypedOid,
+        expected: String,
+        actual: String,
+    },
+    /// Failed to write signature to check
+    SignatureFile(#[source] io::Error),
+    /// Failed to run {0:?}
+    Run(PathBuf, #[source] io::Error),
+    /// {0:?} didn't check the signature: {1}
+    Failed(PathBuf, BString),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn splits_signatures_from_commits() {
+        let commit = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            author A <a@example.com> 1 +0000\n\
+            committer A <a@example.com> 1 +0000\n\
+            gpgsig -----BEGIN SSH SIGNATURE-----\n \
+            U1NIU0lH\n \
+            -----END SSH SIGNATURE-----\n\
+            \n\
+            Message\n \
+            indented\n";
+        let (payload, signature) = split_commit(commit).unwrap();
+        assert_eq!(
+            b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            author A <a@example.com> 1 +0000\n\
+            committer A <a@example.com> 1 +0000\n\
+            \n\
+            Message\n \
+            indented\n"
+                .as_bstr(),
+            payload.as_bstr()
+        );
+        assert_eq!(
+            b"-----BEGIN SSH SIGNATURE-----\nU1NIU0lH\n-----END SSH SIGNATURE-----\n".as_bstr(),
+            signature.as_bstr()
+        );
+        assert_eq!(SignatureFormat::Ssh, format_of(&signature));
+        assert_eq!(None, split_commit(&payload));
+    }
+
+    #[test]
+    fn splits_signatures_from_tags() {
+        let tag = b"object 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+            type commit\n\
+            tag v1\n\
+            \n\
+            Message\n\
+            -----BEGIN PGP SIGNATURE-----\n\
+            iQ\n\
+            -----END PGP SIGNATURE-----\n";
+        let (payload, signature) = split_tag(tag).unwrap();
+        assert!(payload.ends_with(b"\nMessage\n"));
+        assert!(signature.starts_with(b"-----BEGIN PGP SIGNATURE-----\n"));
+        assert_eq!(SignatureFormat::OpenPgp, format_of(signature));
+        assert_eq!(None, split_tag(payload));
+    }
+
+    #[test]
+    fn parses_gpg_status() {
+        let good = b"[GNUPG:] NEWSIG\n\
+            [GNUPG:] GOODSIG BD1A9CDDFD7D2D02 T <t@example.com>\n\
+            [GNUPG:] TRUST_ULTIMATE 0 pgp\n";
+        assert_eq!(
+            Some((
+                SignatureStatus::Good,
+                Some("BD1A9CDDFD7D2D02".to_owned()),
+                Some("T <t@example.com>".to_owned())
+            )),
+            gpg_status(good)
+        );
+        let unknown = b"[GNUPG:] ERRSIG BD1A9CDDFD7D2D02 22 8 00 1792004381 9 D7DE\n\
+            [GNUPG:] NO_PUBKEY BD1A9CDDFD7D2D02\n";
+        assert_eq!(
+            Some((
+                SignatureStatus::UnknownKey,
+                Some("BD1A9CDDFD7D2D02".to_owned()),
+                None
+            )),
+            gpg_status(unknown)
+        );
+        assert_eq!(None, gpg_status(b"gpg: no valid OpenPGP data found.\n"));
+    }
+}
@@ -0,0 +1,216 @@
+//! Keeping status up to date by watching the workspace, for callers like
+//! editors that ask for the status often. See
+//! [`Repo::status_cached`](crate::core::Repo::status_cached).
+//!
+//! Only available with the `watch` feature.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use notify::{op::Op, RawEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use tracing::debug;
+
+use crate::core::{db::Commit, FileStatus, Oid, Stat, Workspace, WsPath};
+
+type Statuses = BTreeMap<WsPath, FileStatus>;
+
+pub struct Watch {
+    /// Dropping the watcher stops the thread recording events
+    _watcher: RecommendedWatcher,
+    dirty: Arc<Mutex<Dirty>>,
+    cached: Option<(Stamp, Statuses)>,
+}
+
+#[derive(Debug, Default)]
+struct Dirty {
+    paths: BTreeSet<WsPath>,
+    everything: bool,
+}
+
+/// What changed since the last call to [`Watch::take_changes`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Changes {
+    /// Only these paths, which may be directories or may no longer exist
+    Paths(Vec<WsPath>),
+    /// Something changed that can affect any path, or events were missed
+    Everything,
+}
+
+/// What status depends on besides the workspace. If this changes the
+/// cached status is thrown out.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Stamp {
+    pub head: Option<Oid<Commit>>,
+    /// `None` if there's no index file
+    pub index: Option<Stat>,
+}
+
+/// How an event at a path affects status
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Effect {
+    None,
+    Path(WsPath),
+    Everything,
+}
+
+impl Watch {
+    /// Starts watching immediately, so nothing that happens after this
+    /// returns is missed.
+    pub fn new(workspace: &Workspace) -> Result<Self, Error> {
+        let root = workspace.path().to_owned();
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::raw_watcher(tx).map_err(|e| Error(root.clone(), e))?;
+        watcher
+            .watch(&root, RecursiveMode::Recursive)
+            .map_err(|e| Error(root.clone(), e))?;
+
+        let dirty = Arc::new(Mutex::new(Dirty::default()));
+        let recording = Arc::clone(&dirty);
+        thread::spawn(move || {
+            for event in rx {
+                Self::record(&root, &recording, event);
+            }
+            debug!("Stopped watching {root:?}");
+        });
+
+        Ok(Self {
+            _watcher: watcher,
+            dirty,
+            cached: None,
+        })
+    }
+
+    fn record(root: &Path, dirty: &Mutex<Dirty>, event: RawEvent) {
+        let mut dirty = dirty.lock().expect("Not poisoned");
+        let effect = match (event.path, event.op) {
+            (Some(path), Ok(op)) if !op.contains(Op::RESCAN) => Self::effect(root, &path),
+            (path, op) => {
+                debug!("Rescanning after watcher event {op:?} at {path:?}");
+                Effect::Everything
+            }
+        };
+        match effect {
+            Effect::None => {}
+            Effect::Path(path) => {
+                dirty.paths.insert(path);
+            }
+            Effect::Everything => dirty.everything = true,
+        }
+    }
+
+    fn effect(root: &Path, path: &Path) -> Effect {
+        let Ok(rel) = path.strip_prefix(root) else {
+            return Effect::None;
+        };
+        let path = WsPath::new_unchecked(rel);
+        if path == WsPath::root() {
+            return Effect::Everything;
+        }
+        let (first, second) = {
+            let mut components = path.components();
+            (components.next(), components.next())
+        };
+        if first.is_some_and(|c| c == ".git") {
+            // The index and head are checked with a [`Stamp`] instead, as
+            // they change whenever we update the index ourselves
+            return if second.is_some_and(|c| c == "info") {
+                Effect::Everything
+            } else {
+                Effect::None
+            };
+        }
+        let name = path.file_name().unwrap_or_default();
+        if name == ".gitignore" || name == ".gitattributes" {
+            return Effect::Everything;
+        }
+        Effect::Path(Self::outermost_repo(root, path))
+    }
+
+    /// Status treats a nested repository as a single entry, so a change
+    /// within one is a change to the repository itself.
+    fn outermost_repo(root: &Path, path: WsPath) -> WsPath {
+        let repo = path
+            .parents()
+            .filter(|parent| *parent != WsPath::root())
+            .find(|parent| root.join(parent).join(".git").exists());
+        repo.unwrap_or(path)
+    }
+
+    pub fn take_changes(&self) -> Changes {
+        let mut dirty = self.dirty.lock().expect("Not poisoned");
+        let dirty = std::mem::take(&mut *dirty);
+        if dirty.everything {
+            Changes::Everything
+        } else {
+            Changes::Paths(dirty.paths.into_iter().collect())
+        }
+    }
+
+    /// The statuses last recorded, if they were recorded with the same stamp
+    pub fn cached(&self, stamp: &Stamp) -> Option<&Statuses> {
+        self.cached
+            .as_ref()
+            .filter(|(cached, _)| cached == stamp)
+            .map(|(_, statuses)| statuses)
+    }
+
+    pub fn set_cached(&mut self, stamp: Stamp, statuses: Statuses) {
+        self.cached = Some((stamp, statuses));
+    }
+}
+
+impl fmt::Debug for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watch")
+            .field("dirty", &self.dirty)
+            .field("cached", &self.cached.as_ref().map(|(stamp, _)| stamp))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Failed to watch {0:?}
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub struct Error(PathBuf, #[source] notify::Error);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn effect(root: &Path, path: &str) -> Effect {
+        Watch::effect(root, &root.join(path))
+    }
+
+    #[test]
+    fn classifies_events() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("sub/.git"))?;
+
+        assert_eq!(
+            Effect::Path(WsPath::new_unchecked("a/b.txt")),
+            effect(root, "a/b.txt")
+        );
+        assert_eq!(Effect::None, effect(root, ".git/index"));
+        assert_eq!(Effect::None, effect(root, ".git/refs/heads/master"));
+        assert_eq!(Effect::Everything, effect(root, ".git/info/exclude"));
+        assert_eq!(Effect::Everything, effect(root, "a/.gitignore"));
+        assert_eq!(Effect::Everything, effect(root, ""));
+        assert_eq!(
+            Effect::Path(WsPath::new_unchecked("sub")),
+            effect(root, "sub/x/y.txt")
+        );
+        assert_eq!(
+            Effect::Path(WsPath::new_unchecked("sub")),
+            effect(root, "sub/.git/HEAD")
+        );
+        assert_eq!(Effect::None, Watch::effect(root, Path::new("/elsewhere")));
+        Ok(())
+    }
+}
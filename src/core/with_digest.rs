@@ -1,9 +1,33 @@
 use ring::digest::{Algorithm, Context, Digest};
 use std::{fmt, io};
 
-pub struct WithDigest<W> {
+/// Hashes what's read or written through it. It can also compute a CRC32
+/// (as pack indexes have for each entry), and call back with the bytes
+/// processed so far.
+pub struct WithDigest<W, F = fn(u64)> {
     inner: W,
     ctx: Context,
+    crc: Option<crc32fast::Hasher>,
+    bytes: u64,
+    progress: F,
+}
+
+/// Like the unstable `Write::write_all_vectored`, writing every buffer
+/// however many calls it takes
+pub(crate) fn write_all_vectored(
+    writer: &mut impl io::Write,
+    mut bufs: &mut [io::IoSlice<'_>],
+) -> io::Result<()> {
+    io::IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => io::IoSlice::advance_slices(&mut bufs, written),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
 }
 
 impl<W> WithDigest<W> {
@@ -11,7 +35,62 @@ impl<W> WithDigest<W> {
         Self {
             inner,
             ctx: Context::new(algorithm),
+            crc: None,
+            bytes: 0,
+            progress: |_| {},
+        }
+    }
+}
+
+impl<W, F: FnMut(u64)> WithDigest<W, F> {
+    /// Also compute a CRC32, see [`Self::take_crc32`]
+    #[must_use]
+    pub fn with_crc32(mut self) -> Self {
+        self.crc = Some(crc32fast::Hasher::new());
+        self
+    }
+
+    /// Called with the total bytes processed after each read or write
+    pub fn on_progress<G: FnMut(u64)>(self, progress: G) -> WithDigest<W, G> {
+        WithDigest {
+            inner: self.inner,
+            ctx: self.ctx,
+            crc: self.crc,
+            bytes: self.bytes,
+            progress,
+        }
+    }
+
+    /// Reading or writing through this skips the digest
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Include data that wasn't read or written through us
+    pub fn update(&mut self, data: &[u8]) {
+        self.hash(data);
+        (self.progress)(self.bytes);
+    }
+
+    fn hash(&mut self, data: &[u8]) {
+        self.ctx.update(data);
+        if let Some(crc) = &mut self.crc {
+            crc.update(data);
         }
+        self.bytes += data.len() as u64;
+    }
+
+    /// How many bytes have been hashed
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The CRC32 of the bytes since it was enabled or last taken, starting
+    /// over for the next bytes. `None` unless enabled with
+    /// [`Self::with_crc32`].
+    pub fn take_crc32(&mut self) -> Option<u32> {
+        let crc = self.crc.replace(crc32fast::Hasher::new())?;
+        Some(crc.finalize())
     }
 
     pub fn finish(self) -> Digest {
@@ -19,10 +98,26 @@ impl<W> WithDigest<W> {
     }
 }
 
-impl<W: io::Write> io::Write for WithDigest<W> {
+impl<W: io::Write, F: FnMut(u64)> io::Write for WithDigest<W, F> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let written = self.inner.write(buf)?;
-        self.ctx.update(&buf[0..written]);
+        self.update(&buf[0..written]);
+        Ok(written)
+    }
+
+    /// Passed on to the inner writer as is, so it can write them all at once
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let written = self.inner.write_vectored(bufs)?;
+        let mut left = written;
+        for buf in bufs {
+            let len = left.min(buf.len());
+            self.hash(&buf[..len]);
+            left -= len;
+            if left == 0 {
+                break;
+            }
+        }
+        (self.progress)(self.bytes);
         Ok(written)
     }
 
@@ -31,18 +126,68 @@ impl<W: io::Write> io::Write for WithDigest<W> {
     }
 }
 
-impl<R: io::Read> io::Read for WithDigest<R> {
+impl<R: io::Read, F: FnMut(u64)> io::Read for WithDigest<R, F> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let read = self.inner.read(buf)?;
-        self.ctx.update(&buf[0..read]);
+        self.update(&buf[0..read]);
         Ok(read)
     }
 }
 
-impl<W: fmt::Debug> fmt::Debug for WithDigest<W> {
+impl<W: fmt::Debug, F> fmt::Debug for WithDigest<W, F> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("WithDigest")
             .field("inner", &self.inner)
-            .finish()
+            .field("bytes", &self.bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY as SHA1};
+    use std::io::Write;
+
+    #[test]
+    fn computes_crc32_and_reports_progress() -> eyre::Result<()> {
+        let mut reported = Vec::new();
+        let mut out = Vec::new();
+        let mut hashed = WithDigest::new(&SHA1, &mut out)
+            .with_crc32()
+            .on_progress(|bytes| reported.push(bytes));
+        hashed.write_all(b"first")?;
+        assert_eq!(Some(crc32fast::hash(b"first")), hashed.take_crc32());
+        hashed.write_all(b"second")?;
+        assert_eq!(Some(crc32fast::hash(b"second")), hashed.take_crc32());
+        assert_eq!(11, hashed.bytes());
+        let hash = hashed.finish();
+
+        assert_eq!(digest(&SHA1, b"firstsecond").as_ref(), hash.as_ref());
+        assert_eq!(b"firstsecond", &out[..]);
+        assert_eq!(vec![5, 11], reported);
+        assert_eq!(None, WithDigest::new(&SHA1, io::sink()).take_crc32());
+        Ok(())
+    }
+
+    #[test]
+    fn hashes_what_vectored_writes_write() -> eyre::Result<()> {
+        let mut out = Vec::new();
+        let mut hashed = WithDigest::new(&SHA1, &mut out);
+        write_all_vectored(
+            &mut hashed,
+            &mut [
+                io::IoSlice::new(b"first"),
+                io::IoSlice::new(b""),
+                io::IoSlice::new(b"second"),
+            ],
+        )?;
+        assert_eq!(11, hashed.bytes());
+        let hash = hashed.finish();
+
+        assert_eq!(digest(&SHA1, b"firstsecond").as_ref(), hash.as_ref());
+        assert_eq!(b"firstsecond", &out[..]);
+        Ok(())
     }
 }
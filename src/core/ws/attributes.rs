@@ -0,0 +1,332 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bstr::{BStr, BString, ByteSlice};
+use regex::bytes::Regex;
+use tracing::{debug, warn};
+
+use super::ignore;
+use crate::core::{Workspace, WsPath};
+
+/// Attributes from `.git/info/attributes` and the `.gitattributes` files of
+/// the workspace. Like [`ignore::IgnoreRules`], per-directory files are
+/// loaded lazily, with [`Self::load_parents`].
+#[derive(Debug, Clone)]
+pub struct Attributes {
+    /// Highest precedence
+    info: Vec<Rule>,
+    /// By the directory of the `.gitattributes` file
+    dirs: BTreeMap<WsPath, Vec<Rule>>,
+    loaded_dirs: BTreeSet<WsPath>,
+    macros: BTreeMap<BString, Vec<(BString, State)>>,
+    load_dirs: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum State {
+    /// `attr`
+    Set,
+    /// `-attr`
+    Unset,
+    /// `attr=value`
+    Value(BString),
+    /// `!attr`, which resets the attribute as if no rule mentioned it
+    Unspecified,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    base: WsPath,
+    regex: Regex,
+    attrs: Vec<(BString, State)>,
+}
+
+impl Attributes {
+    const FILE_NAME: &'static str = ".gitattributes";
+
+    pub fn new(git_dir: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let mut attrs = Self::none();
+        attrs.load_dirs = true;
+        let info = git_dir.as_ref().join("info/attributes");
+        if let Some(rules) = attrs.load_file(&info, &WsPath::root())? {
+            attrs.info = rules;
+        }
+        Ok(attrs)
+    }
+
+    /// No attributes. `.gitattributes` files aren't loaded.
+    pub fn none() -> Self {
+        let mut macros = BTreeMap::new();
+        macros.insert(
+            "binary".into(),
+            vec![
+                ("diff".into(), State::Unset),
+                ("merge".into(), State::Unset),
+                ("text".into(), State::Unset),
+            ],
+        );
+        Self {
+            info: Vec::new(),
+            dirs: BTreeMap::new(),
+            loaded_dirs: BTreeSet::new(),
+            macros,
+            load_dirs: false,
+        }
+    }
+
+    /// Load the `.gitattributes` of every directory containing the path
+    pub fn load_parents(&mut self, workspace: &Workspace, path: &WsPath) -> Result<(), LoadError> {
+        self.load_dir(workspace, &WsPath::root())?;
+        for parent in path.parents() {
+            self.load_dir(workspace, &parent)?;
+        }
+        Ok(())
+    }
+
+    fn load_dir(&mut self, workspace: &Workspace, dir: &WsPath) -> Result<(), LoadError> {
+        if !self.load_dirs || self.loaded_dirs.contains(dir) {
+            return Ok(());
+        }
+        let file = dir.join(Self::FILE_NAME);
+        let file = file
+            .to_absolute(workspace)
+            .map_err(|e| LoadError(file.into_path_buf(), e.into()))?;
+        if let Some(rules) = self.load_file(&file, dir)? {
+            self.dirs.insert(dir.clone(), rules);
+        }
+        self.loaded_dirs.insert(dir.clone());
+        Ok(())
+    }
+
+    fn load_file(&mut self, file: &Path, base: &WsPath) -> Result<Option<Vec<Rule>>, LoadError> {
+        let contents = match fs::read(file) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(LoadError(file.to_owned(), err)),
+        };
+        debug!(?file, "Loading attributes");
+        Ok(Some(self.parse(contents.as_bstr(), base)))
+    }
+
+    fn parse(&mut self, contents: &BStr, base: &WsPath) -> Vec<Rule> {
+        let mut rules = Vec::new();
+        for line in contents.lines() {
+            let mut fields = line.fields_with(|c| c == ' ' || c == '\t');
+            let pattern = match fields.next() {
+                Some(pattern) if !pattern.starts_with(b"#") => pattern,
+                _ => continue,
+            };
+            let attrs = fields.map(Self::parse_attr).collect::<Vec<_>>();
+
+            if let Some(name) = pattern.strip_prefix(b"[attr]") {
+                // Macros can only be defined at the top level
+                if *base == WsPath::root() {
+                    self.macros.insert(name.into(), attrs);
+                }
+                continue;
+            }
+
+            if pattern.starts_with(b"!") {
+                warn!(pattern = %pattern.as_bstr(), "Negative patterns are ignored in attributes");
+                continue;
+            }
+            if pattern.ends_with(b"/") {
+                // Attributes don't apply to directories
+                continue;
+            }
+            let anchored = pattern.contains(&b'/');
+            let pattern = pattern.strip_prefix(b"/").unwrap_or(pattern);
+            match Regex::new(&ignore::Pattern::to_regex(pattern, anchored)) {
+                Ok(regex) => rules.push(Rule {
+                    base: base.clone(),
+                    regex,
+                    attrs,
+                }),
+                Err(err) => warn!(%err, "Skipping invalid attributes pattern"),
+            }
+        }
+        rules
+    }
+
+    #[cfg(test)]
+    pub(crate) fn parse_for_test(contents: &str) -> Self {
+        let mut attrs = Self::none();
+        let rules = attrs.parse(contents.as_bytes().as_bstr(), &WsPath::root());
+        attrs.dirs.insert(WsPath::root(), rules);
+        attrs
+    }
+
+    fn parse_attr(attr: &[u8]) -> (BString, State) {
+        if let Some(name) = attr.strip_prefix(b"-") {
+            (name.into(), State::Unset)
+        } else if let Some(name) = attr.strip_prefix(b"!") {
+            (name.into(), State::Unspecified)
+        } else if let Some(eq) = attr.find_byte(b'=') {
+            (attr[..eq].into(), State::Value(attr[eq + 1..].into()))
+        } else {
+            (attr.into(), State::Set)
+        }
+    }
+
+    /// The state of the attribute for the path, considering only files
+    /// already loaded. `None` if no rule mentions it (or it was reset with
+    /// `!attr`).
+    pub fn get(&self, path: &WsPath, name: &str) -> Option<State> {
+        match self.resolve(path).remove(name.as_bytes().as_bstr()) {
+            Some(State::Unspecified) | None => None,
+            state => state,
+        }
+    }
+
+    /// Every attribute the rules specify for the path, like `git check-attr
+    /// --all`, considering only files already loaded
+    pub fn all(&self, path: &WsPath) -> BTreeMap<BString, State> {
+        let mut states = self.resolve(path);
+        states.retain(|_, state| *state != State::Unspecified);
+        states
+    }
+
+    /// Decides attributes like git: files and the rules in them are checked
+    /// from highest precedence to lowest, and the first decision for an
+    /// attribute stands. Within a rule, later attributes take precedence.
+    fn resolve(&self, path: &WsPath) -> BTreeMap<BString, State> {
+        let mut dirs = path.parents().collect::<Vec<_>>();
+        dirs.insert(0, WsPath::root());
+
+        let files = std::iter::once(&self.info)
+            .chain(dirs.iter().rev().filter_map(|dir| self.dirs.get(dir)));
+        let mut decided = BTreeMap::new();
+        for rules in files {
+            for rule in rules.iter().rev() {
+                if rule.matches(path) {
+                    self.fill(&mut decided, &rule.attrs);
+                }
+            }
+        }
+        decided
+    }
+
+    /// Decide the attributes not yet decided. A macro that's set decides the
+    /// attributes it expands to (which may be macros too) right after itself,
+    /// so they take precedence over those earlier in the rule. As the macro
+    /// is decided before it's expanded, cycles end.
+    fn fill(&self, decided: &mut BTreeMap<BString, State>, attrs: &[(BString, State)]) {
+        for (attr, state) in attrs.iter().rev() {
+            if decided.contains_key(attr) {
+                continue;
+            }
+            decided.insert(attr.clone(), state.clone());
+            if *state == State::Set {
+                if let Some(expansion) = self.macros.get(attr) {
+                    self.fill(decided, expansion);
+                }
+            }
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, path: &WsPath) -> bool {
+        if *path == self.base || !path.is_within(&self.base) {
+            return false;
+        }
+        let rel = path.strip_prefix(&self.base).expect("Checked within base");
+        self.regex.is_match(rel.as_bstr())
+    }
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+/// Failed to read attributes file {0:?}
+pub struct LoadError(PathBuf, #[source] io::Error);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn attrs(files: &[(&str, &str)]) -> Attributes {
+        let mut attrs = Attributes::none();
+        for (dir, contents) in files {
+            let base = WsPath::new_unchecked(dir);
+            let rules = attrs.parse(contents.as_bytes().as_bstr(), &base);
+            attrs.dirs.insert(base, rules);
+        }
+        attrs
+    }
+
+    fn get(attrs: &Attributes, path: &str, name: &str) -> Option<State> {
+        attrs.get(&WsPath::new_unchecked(path), name)
+    }
+
+    #[test]
+    fn parses_states() {
+        let attrs = attrs(&[("", "*.txt text eol=crlf -diff\n*.md !text\n# *.c text")]);
+        assert_eq!(Some(State::Set), get(&attrs, "dir/a.txt", "text"));
+        assert_eq!(
+            Some(State::Value("crlf".into())),
+            get(&attrs, "a.txt", "eol")
+        );
+        assert_eq!(Some(State::Unset), get(&attrs, "a.txt", "diff"));
+        assert_eq!(None, get(&attrs, "a.md", "text"));
+        assert_eq!(None, get(&attrs, "a.c", "text"));
+    }
+
+    #[test]
+    fn later_and_deeper_rules_win() {
+        let attrs = attrs(&[("", "* text\n*.bin -text"), ("sub", "*.bin text=auto\n")]);
+        assert_eq!(Some(State::Set), get(&attrs, "a.txt", "text"));
+        assert_eq!(Some(State::Unset), get(&attrs, "a.bin", "text"));
+        assert_eq!(
+            Some(State::Value("auto".into())),
+            get(&attrs, "sub/a.bin", "text")
+        );
+    }
+
+    #[test]
+    fn first_decision_stands() {
+        let attrs = attrs(&[
+            (
+                "",
+                "[attr]lf text eol=lf\n[attr]src lf diff=rust\n* binary\n*.rs src -text\n",
+            ),
+            ("sub", "*.png -binary\n"),
+        ]);
+        // Later attributes of a rule win, even over what macros expand to
+        assert_eq!(Some(State::Unset), get(&attrs, "a.rs", "text"));
+        assert_eq!(Some(State::Value("lf".into())), get(&attrs, "a.rs", "eol"));
+        assert_eq!(
+            Some(State::Value("rust".into())),
+            get(&attrs, "a.rs", "diff")
+        );
+        // An unset macro decides nothing it would expand to, so the lower
+        // rule's expansion doesn't apply either
+        assert_eq!(Some(State::Unset), get(&attrs, "sub/a.png", "binary"));
+        assert_eq!(None, get(&attrs, "sub/a.png", "text"));
+        assert_eq!(Some(State::Unset), get(&attrs, "sub/a.txt", "text"));
+
+        let all = attrs.all(&WsPath::new_unchecked("a.rs"));
+        let names = all.keys().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            vec!["binary", "diff", "eol", "lf", "merge", "src", "text"],
+            names
+        );
+    }
+
+    #[test]
+    fn expands_macros() {
+        let attrs = attrs(&[(
+            "",
+            "[attr]crlf-text text eol=crlf\n*.png binary\n*.bat crlf-text",
+        )]);
+        assert_eq!(Some(State::Unset), get(&attrs, "a.png", "text"));
+        assert_eq!(Some(State::Unset), get(&attrs, "a.png", "diff"));
+        assert_eq!(Some(State::Set), get(&attrs, "a.bat", "text"));
+        assert_eq!(
+            Some(State::Value("crlf".into())),
+            get(&attrs, "a.bat", "eol")
+        );
+    }
+}
@@ -0,0 +1,423 @@
+//! Line ending conversion between the workspace and the database, driven by
+//! `core.autocrlf`, `core.eol`, `core.safecrlf` and the `text` and `eol`
+//! attributes.
+
+use std::borrow::Cow;
+
+use bstr::BString;
+use tracing::warn;
+
+use super::attributes::{Attributes, State};
+use crate::core::{config, Config, WsPath};
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Settings {
+    pub autocrlf: AutoCrlf,
+    pub eol: Ending,
+    pub safecrlf: SafeCrlf,
+}
+
+/// `core.autocrlf`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum AutoCrlf {
+    #[default]
+    False,
+    /// Files are stored with LF, and checked out with CRLF
+    True,
+    /// Files are stored with LF, and checked out as they are
+    Input,
+}
+
+/// `core.eol`, and the `eol` attribute
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Ending {
+    Lf,
+    Crlf,
+}
+
+/// `core.safecrlf`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum SafeCrlf {
+    False,
+    #[default]
+    Warn,
+    /// Refuse conversions that wouldn't round trip
+    True,
+}
+
+/// What to do with a file
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Action {
+    Binary,
+    /// Line endings are normalized to LF in the database, and checked out
+    /// with the ending given. If `auto` only files detected as text are
+    /// converted.
+    Text {
+        output: Ending,
+        auto: bool,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    crlf: usize,
+    lone_lf: usize,
+    lone_cr: usize,
+    nul: usize,
+}
+
+impl Default for Ending {
+    /// The native line ending
+    fn default() -> Self {
+        if cfg!(windows) {
+            Self::Crlf
+        } else {
+            Self::Lf
+        }
+    }
+}
+
+impl Settings {
+    pub fn from_config(config: &Config) -> Result<Self, config::ValueError> {
+        let autocrlf = match config.get("core.autocrlf") {
+            Some(value) if value.eq_ignore_ascii_case("input") => AutoCrlf::Input,
+            _ => match config.get_bool("core.autocrlf")? {
+                Some(true) => AutoCrlf::True,
+                Some(false) | None => AutoCrlf::False,
+            },
+        };
+
+        let eol = match config
+            .get("core.eol")
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            Some("lf") => Ending::Lf,
+            Some("crlf") => Ending::Crlf,
+            Some("native") | None => Ending::default(),
+            Some(value) => {
+                return Err(config::ValueError::Invalid(
+                    "core.eol".to_owned(),
+                    value.to_owned(),
+                ))
+            }
+        };
+
+        let safecrlf = match config.get("core.safecrlf") {
+            Some(value) if value.eq_ignore_ascii_case("warn") => SafeCrlf::Warn,
+            _ => match config.get_bool("core.safecrlf")? {
+                Some(true) => SafeCrlf::True,
+                Some(false) => SafeCrlf::False,
+                None => SafeCrlf::Warn,
+            },
+        };
+
+        Ok(Self {
+            autocrlf,
+            eol,
+            safecrlf,
+        })
+    }
+
+    fn action(self, path: &WsPath, attrs: &Attributes) -> Action {
+        let eol = match attrs.get(path, "eol") {
+            Some(State::Value(value)) if value == "lf" => Some(Ending::Lf),
+            Some(State::Value(value)) if value == "crlf" => Some(Ending::Crlf),
+            _ => None,
+        };
+
+        let auto = match attrs.get(path, "text") {
+            Some(State::Set) => false,
+            Some(State::Unset) => return Action::Binary,
+            Some(State::Value(value)) if value == "auto" => true,
+            // Setting eol implies the file is text
+            _ if eol.is_some() => false,
+            _ if self.autocrlf != AutoCrlf::False => true,
+            _ => return Action::Binary,
+        };
+
+        let output = eol.unwrap_or(match self.autocrlf {
+            AutoCrlf::True => Ending::Crlf,
+            AutoCrlf::Input => Ending::Lf,
+            AutoCrlf::False => self.eol,
+        });
+
+        Action::Text { output, auto }
+    }
+
+    /// Convert a file read from the workspace to what should be stored.
+    /// Warns (or with `core.safecrlf = true` errors) if checking the result
+    /// out again wouldn't give back the same file.
+    pub fn to_git<'a>(
+        &self,
+        path: &WsPath,
+        data: &'a [u8],
+        attrs: &Attributes,
+    ) -> Result<Cow<'a, [u8]>, UnsafeError> {
+        let Action::Text { output, auto } = self.action(path, attrs) else {
+            return Ok(Cow::Borrowed(data));
+        };
+
+        let stats = Stats::of(data);
+        if auto && stats.is_binary() {
+            return Ok(Cow::Borrowed(data));
+        }
+
+        if stats.crlf > 0 && output == Ending::Lf {
+            self.check_safe(UnsafeError::CrlfToLf(path.clone()))?;
+        } else if stats.lone_lf > 0 && output == Ending::Crlf {
+            self.check_safe(UnsafeError::LfToCrlf(path.clone()))?;
+        }
+
+        if stats.crlf == 0 {
+            return Ok(Cow::Borrowed(data));
+        }
+
+        let mut converted = Vec::with_capacity(data.len() - stats.crlf);
+        let mut bytes = data.iter().peekable();
+        while let Some(&byte) = bytes.next() {
+            if byte == b'\r' && bytes.peek() == Some(&&b'\n') {
+                continue;
+            }
+            converted.push(byte);
+        }
+        Ok(Cow::Owned(converted))
+    }
+
+    /// Whether [`Self::to_worktree`] might change the contents of the path
+    pub fn converts_to_worktree(self, path: &WsPath, attrs: &Attributes) -> bool {
+        matches!(
+            self.action(path, attrs),
+            Action::Text {
+                output: Ending::Crlf,
+                ..
+            }
+        )
+    }
+
+    /// Convert a blob from the database to what should be written to the
+    /// workspace
+    pub fn to_worktree<'a>(
+        &self,
+        path: &WsPath,
+        data: &'a [u8],
+        attrs: &Attributes,
+    ) -> Cow<'a, [u8]> {
+        let (output, auto) = match self.action(path, attrs) {
+            Action::Text { output, auto } => (output, auto),
+            Action::Binary => return Cow::Borrowed(data),
+        };
+        if output == Ending::Lf {
+            return Cow::Borrowed(data);
+        }
+
+        let stats = Stats::of(data);
+        if stats.lone_lf == 0 {
+            return Cow::Borrowed(data);
+        }
+        // If it was committed with CRs we assume they mean something
+        if auto && (stats.is_binary() || stats.crlf > 0) {
+            return Cow::Borrowed(data);
+        }
+
+        let mut converted = Vec::with_capacity(data.len() + stats.lone_lf);
+        let mut prev = None;
+        for &byte in data {
+            if byte == b'\n' && prev != Some(b'\r') {
+                converted.push(b'\r');
+            }
+            converted.push(byte);
+            prev = Some(byte);
+        }
+        Cow::Owned(converted)
+    }
+
+    fn check_safe(self, err: UnsafeError) -> Result<(), UnsafeError> {
+        match self.safecrlf {
+            SafeCrlf::False => Ok(()),
+            SafeCrlf::Warn => {
+                warn!("{}", err.warning());
+                Ok(())
+            }
+            SafeCrlf::True => Err(err),
+        }
+    }
+}
+
+impl Stats {
+    fn of(data: &[u8]) -> Self {
+        let mut stats = Self::default();
+        let mut bytes = data.iter().peekable();
+        while let Some(&byte) = bytes.next() {
+            match byte {
+                b'\r' if bytes.peek() == Some(&&b'\n') => {
+                    stats.crlf += 1;
+                    bytes.next();
+                }
+                b'\r' => stats.lone_cr += 1,
+                b'\n' => stats.lone_lf += 1,
+                0 => stats.nul += 1,
+                _ => {}
+            }
+        }
+        stats
+    }
+
+    /// Like git, we consider files with NULs or lone CRs binary
+    fn is_binary(&self) -> bool {
+        self.nul > 0 || self.lone_cr > 0
+    }
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error, Eq, PartialEq)]
+pub enum UnsafeError {
+    /// CRLF would be replaced by LF in {0}
+    CrlfToLf(WsPath),
+    /// LF would be replaced by CRLF in {0}
+    LfToCrlf(WsPath),
+}
+
+impl UnsafeError {
+    fn warning(&self) -> BString {
+        let (path, from, to) = match self {
+            Self::CrlfToLf(path) => (path, "CRLF", "LF"),
+            Self::LfToCrlf(path) => (path, "LF", "CRLF"),
+        };
+        format!(
+            "in the working copy of '{path}', {from} will be replaced by {to} the next time Git touches it"
+        )
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bstr::ByteSlice;
+    use pretty_assertions::assert_eq;
+
+    fn attrs(contents: &str) -> Attributes {
+        Attributes::parse_for_test(contents)
+    }
+
+    fn path() -> WsPath {
+        WsPath::new_unchecked("file.txt")
+    }
+
+    #[test]
+    fn no_conversion_by_default() -> eyre::Result<()> {
+        let settings = Settings::default();
+        let none = Attributes::none();
+        assert_eq!(
+            b"a\r\nb".as_ref(),
+            &*settings.to_git(&path(), b"a\r\nb", &none)?
+        );
+        assert_eq!(
+            b"a\nb".as_ref(),
+            &*settings.to_worktree(&path(), b"a\nb", &none)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn autocrlf_true() -> eyre::Result<()> {
+        let settings = Settings {
+            autocrlf: AutoCrlf::True,
+            ..Settings::default()
+        };
+        let none = Attributes::none();
+        assert_eq!(
+            b"a\nb\n".as_ref(),
+            &*settings.to_git(&path(), b"a\r\nb\r\n", &none)?
+        );
+        assert_eq!(
+            b"a\r\nb\r\n".as_ref(),
+            &*settings.to_worktree(&path(), b"a\nb\n", &none)
+        );
+        // Binary files are left alone
+        assert_eq!(
+            b"a\0\r\n".as_ref(),
+            &*settings.to_git(&path(), b"a\0\r\n", &none)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn autocrlf_input() -> eyre::Result<()> {
+        let settings = Settings {
+            autocrlf: AutoCrlf::Input,
+            ..Settings::default()
+        };
+        let none = Attributes::none();
+        assert_eq!(
+            b"a\nb".as_ref(),
+            &*settings.to_git(&path(), b"a\r\nb", &none)?
+        );
+        assert_eq!(
+            b"a\nb".as_ref(),
+            &*settings.to_worktree(&path(), b"a\nb", &none)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn attributes_override_config() -> eyre::Result<()> {
+        let settings = Settings {
+            autocrlf: AutoCrlf::True,
+            ..Settings::default()
+        };
+        let attrs = attrs("*.txt eol=lf\n*.bat text eol=crlf\n*.dat -text");
+
+        let txt = WsPath::new_unchecked("a.txt");
+        assert_eq!(
+            b"a\nb".as_ref(),
+            &*settings.to_worktree(&txt, b"a\nb", &attrs)
+        );
+
+        let bat = WsPath::new_unchecked("a.bat");
+        assert_eq!(
+            b"a\r\nb".as_ref(),
+            &*settings.to_worktree(&bat, b"a\nb", &attrs)
+        );
+        assert_eq!(
+            b"a\nb".as_ref(),
+            &*settings.to_git(&bat, b"a\r\nb", &attrs)?
+        );
+
+        let dat = WsPath::new_unchecked("a.dat");
+        assert_eq!(
+            b"a\r\nb".as_ref(),
+            &*settings.to_git(&dat, b"a\r\nb", &attrs)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn safecrlf_refuses_irreversible() {
+        let settings = Settings {
+            autocrlf: AutoCrlf::Input,
+            safecrlf: SafeCrlf::True,
+            ..Settings::default()
+        };
+        let none = Attributes::none();
+        assert_eq!(
+            Err(UnsafeError::CrlfToLf(path())),
+            settings.to_git(&path(), b"a\r\nb", &none)
+        );
+
+        let settings = Settings {
+            autocrlf: AutoCrlf::True,
+            ..settings
+        };
+        // Mixed endings come back as all CRLF
+        assert_eq!(
+            Err(UnsafeError::LfToCrlf(path())),
+            settings.to_git(&path(), b"a\r\nb\nc", &none)
+        );
+        assert!(settings.to_git(&path(), b"a\r\nb\r\n", &none).is_ok());
+        assert_eq!(
+            "in the working copy of 'file.txt', LF will be replaced by CRLF the next time Git touches it",
+            UnsafeError::LfToCrlf(path()).warning().to_str_lossy()
+        );
+    }
+}
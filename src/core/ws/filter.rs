@@ -0,0 +1,513 @@
+//! Clean and smudge filters, configured with `filter.<driver>.clean`,
+//! `.smudge`, `.process` and `.required`, and applied to paths with the
+//! `filter` attribute.
+//!
+//! Commands given with `clean` or `smudge` are run once per file, with the
+//! content streamed through them. A `process` command is started once and
+//! kept running, speaking git's long-running filter protocol (as used by Git
+//! LFS).
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::{self, BufReader, Read, Write},
+    path::Path,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use bstr::{BString, ByteSlice};
+use tracing::{debug, warn};
+
+use super::attributes::{Attributes, State};
+use crate::core::{
+    transport::pkt_line::{self, Packet},
+    Config, WsPath,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    drivers: BTreeMap<String, Driver>,
+    /// Long-running processes, started the first time they're needed
+    processes: Arc<Mutex<BTreeMap<String, Process>>>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Driver {
+    pub clean: Option<String>,
+    pub smudge: Option<String>,
+    pub process: Option<String>,
+    /// If failing to filter is an error, rather than passing the content
+    /// through unchanged
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    /// Workspace to database
+    Clean,
+    /// Database to workspace
+    Smudge,
+}
+
+struct Process {
+    child: Child,
+    /// Only `None` while dropping
+    stdin: Option<pkt_line::Writer<ChildStdin>>,
+    stdout: pkt_line::Reader<BufReader<ChildStdout>>,
+    capabilities: BTreeSet<String>,
+}
+
+impl Filters {
+    pub fn from_config(config: &Config) -> Self {
+        let mut drivers = BTreeMap::<String, Driver>::new();
+        for entry in config.entries() {
+            let (Some(name), "filter") = (&entry.subsection, entry.section.as_str()) else {
+                continue;
+            };
+            let driver = drivers.entry(name.clone()).or_default();
+            let value = entry.value.clone();
+            match entry.key.as_str() {
+                "clean" => driver.clean = value,
+                "smudge" => driver.smudge = value,
+                "process" => driver.process = value,
+                "required" => {
+                    driver.required = config
+                        .get_bool(&format!("filter.{name}.required"))
+                        .unwrap_or_else(|err| {
+                            warn!(%err, "Assuming filter not required");
+                            None
+                        })
+                        .unwrap_or(false);
+                }
+                _ => {}
+            }
+        }
+        Self {
+            drivers,
+            processes: Arc::default(),
+        }
+    }
+
+    pub fn driver(&self, name: &str) -> Option<&Driver> {
+        self.drivers.get(name)
+    }
+
+    /// The driver for the path, if it has the `filter` attribute and a driver
+    /// is configured that can filter in the direction
+    fn driver_for(
+        &self,
+        path: &WsPath,
+        attrs: &Attributes,
+        direction: Direction,
+    ) -> Option<(&str, &Driver)> {
+        let Some(State::Value(name)) = attrs.get(path, "filter") else {
+            return None;
+        };
+        let (name, driver) = self.drivers.get_key_value(name.to_str().ok()?)?;
+        let has_command = driver.process.is_some()
+            || match direction {
+                Direction::Clean => driver.clean.is_some(),
+                Direction::Smudge => driver.smudge.is_some(),
+            };
+        has_command.then_some((name.as_str(), driver))
+    }
+
+    /// Whether [`Self::clean`] would run a filter for the path
+    pub fn cleans(&self, path: &WsPath, attrs: &Attributes) -> bool {
+        self.driver_for(path, attrs, Direction::Clean).is_some()
+    }
+
+    /// Filter `input`, the contents of the file at `path`, for storing,
+    /// streaming it through the filter to `output`. Returns false if there's
+    /// no filter for the path, or if a filter that isn't required failed, in
+    /// which case anything written to `output` should be discarded in favour
+    /// of the unfiltered contents.
+    pub fn clean(
+        &self,
+        cwd: &Path,
+        path: &WsPath,
+        input: impl Read + Send,
+        output: &mut impl Write,
+        attrs: &Attributes,
+    ) -> Result<bool, FilterError> {
+        let Some((name, driver)) = self.driver_for(path, attrs, Direction::Clean) else {
+            return Ok(false);
+        };
+        let result = self.run(cwd, name, driver, Direction::Clean, path, input, output);
+        Self::handle_failure(driver, result)
+    }
+
+    /// Whether [`Self::smudge`] would run a filter for the path
+    pub fn smudges(&self, path: &WsPath, attrs: &Attributes) -> bool {
+        self.driver_for(path, attrs, Direction::Smudge).is_some()
+    }
+
+    /// Like [`Self::clean`], but filtering contents from the database for
+    /// writing to the workspace
+    pub fn smudge(
+        &self,
+        cwd: &Path,
+        path: &WsPath,
+        input: impl Read + Send,
+        output: &mut impl Write,
+        attrs: &Attributes,
+    ) -> Result<bool, FilterError> {
+        let Some((name, driver)) = self.driver_for(path, attrs, Direction::Smudge) else {
+            return Ok(false);
+        };
+        let result = self.run(cwd, name, driver, Direction::Smudge, path, input, output);
+        Self::handle_failure(driver, result)
+    }
+
+    fn handle_failure(
+        driver: &Driver,
+        result: Result<(), FilterError>,
+    ) -> Result<bool, FilterError> {
+        match result {
+            Ok(()) => Ok(true),
+            Err(err) if driver.required => Err(err),
+            Err(err) => {
+                warn!(%err, "Filter failed, using content unfiltered");
+                Ok(false)
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn run(
+        &self,
+        cwd: &Path,
+        name: &str,
+        driver: &Driver,
+        direction: Direction,
+        path: &WsPath,
+        input: impl Read + Send,
+        output: &mut impl Write,
+    ) -> Result<(), FilterError> {
+        if let Some(process) = &driver.process {
+            let mut processes = self.processes.lock().expect("Not poisoned");
+            if !processes.contains_key(name) {
+                let started = Process::start(cwd, process)?;
+                processes.insert(name.to_owned(), started);
+            }
+            let running = processes.get_mut(name).expect("Just inserted");
+            let result = running.filter(direction, path, input, output);
+            if let Err(FilterError::Io(..) | FilterError::Read(..)) = result {
+                // The process probably died, so we don't want to reuse it
+                processes.remove(name);
+            }
+            return result;
+        }
+
+        let command = match direction {
+            Direction::Clean => driver.clean.as_ref(),
+            Direction::Smudge => driver.smudge.as_ref(),
+        }
+        .expect("Checked has command");
+        run_command(cwd, command, path, input, output)
+    }
+}
+
+/// Run a one-shot filter command through the shell, streaming the input to
+/// it on another thread so neither side blocks on a full pipe.
+fn run_command(
+    cwd: &Path,
+    command: &str,
+    path: &WsPath,
+    mut input: impl Read + Send,
+    output: &mut impl Write,
+) -> Result<(), FilterError> {
+    let command = command.replace("%f", &shell_quote(&path.to_string()));
+    debug!(%command, "Running filter");
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .current_dir(cwd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| FilterError::Start(command.clone(), e))?;
+
+    let mut stdin = child.stdin.take().expect("Piped");
+    let mut stdout = child.stdout.take().expect("Piped");
+
+    thread::scope(|scope| {
+        let writer = scope.spawn(move || {
+            let result = io::copy(&mut input, &mut stdin);
+            drop(stdin);
+            result
+        });
+
+        let read = io::copy(&mut stdout, output);
+
+        // The filter may legitimately stop reading early, so a broken pipe
+        // isn't an error if it exits successfully
+        match writer.join().expect("Writer didn't panic") {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => Err(err),
+            _ => read.map(drop),
+        }
+    })
+    .map_err(|e| FilterError::Io(command.clone(), e))?;
+
+    let status = child
+        .wait()
+        .map_err(|e| FilterError::Io(command.clone(), e))?;
+    if !status.success() {
+        return Err(FilterError::Failed(command, status.code()));
+    }
+    Ok(())
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl Process {
+    fn start(cwd: &Path, command: &str) -> Result<Self, FilterError> {
+        debug!(%command, "Starting long-running filter");
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(cwd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| FilterError::Start(command.to_owned(), e))?;
+
+        let stdin = child.stdin.take().map(pkt_line::Writer::new);
+        let stdout = BufReader::new(child.stdout.take().expect("Piped"));
+        let mut process = Self {
+            child,
+            stdin,
+            stdout: pkt_line::Reader::new(stdout),
+            capabilities: BTreeSet::new(),
+        };
+        process.handshake(command)?;
+        Ok(process)
+    }
+
+    fn stdin(&mut self) -> &mut pkt_line::Writer<ChildStdin> {
+        self.stdin.as_mut().expect("Only taken when dropping")
+    }
+
+    fn handshake(&mut self, command: &str) -> Result<(), FilterError> {
+        let io_err = |e| FilterError::Io(command.to_owned(), e);
+        let read_err = |e| FilterError::Read(command.to_owned(), e);
+
+        let stdin = self.stdin();
+        stdin.write_line("git-filter-client").map_err(io_err)?;
+        stdin.write_line("version=2").map_err(io_err)?;
+        stdin.write_flush().map_err(io_err)?;
+
+        let welcome = self.read_list().map_err(read_err)?;
+        if welcome.first().map(|line| line.as_bytes()) != Some(b"git-filter-server")
+            || !welcome.iter().any(|line| line == "version=2")
+        {
+            return Err(FilterError::Handshake(command.to_owned(), welcome));
+        }
+
+        let stdin = self.stdin();
+        stdin.write_line("capability=clean").map_err(io_err)?;
+        stdin.write_line("capability=smudge").map_err(io_err)?;
+        stdin.write_flush().map_err(io_err)?;
+
+        self.capabilities = self
+            .read_list()
+            .map_err(read_err)?
+            .into_iter()
+            .filter_map(|line| {
+                let capability = line.strip_prefix(b"capability=")?;
+                Some(capability.to_str_lossy().into_owned())
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn filter(
+        &mut self,
+        direction: Direction,
+        path: &WsPath,
+        mut input: impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), FilterError> {
+        let command = match direction {
+            Direction::Clean => "clean",
+            Direction::Smudge => "smudge",
+        };
+        if !self.capabilities.contains(command) {
+            return Err(FilterError::Unsupported(command));
+        }
+        let io_err = |e| FilterError::Io(command.to_owned(), e);
+        let read_err = |e| FilterError::Read(command.to_owned(), e);
+
+        let stdin = self.stdin();
+        stdin
+            .write_line(format!("command={command}"))
+            .map_err(io_err)?;
+        stdin
+            .write_line(format!("pathname={path}"))
+            .map_err(io_err)?;
+        stdin.write_flush().map_err(io_err)?;
+
+        let mut buf = vec![0; pkt_line::MAX_DATA_LEN];
+        loop {
+            let len = input
+                .read(&mut buf)
+                .map_err(|e| FilterError::Input(path.clone(), e))?;
+            if len == 0 {
+                break;
+            }
+            stdin.write_data(&buf[..len]).map_err(io_err)?;
+        }
+        stdin.write_flush().map_err(io_err)?;
+
+        let status = self.read_list().map_err(read_err)?;
+        Self::check_status(&status)?;
+
+        loop {
+            match self.stdout.read().map_err(read_err)? {
+                Packet::Data(data) => output.write_all(&data).map_err(io_err)?,
+                Packet::Flush => break,
+                packet => return Err(read_err(pkt_line::ReadError::UnexpectedPacket(packet))),
+            }
+        }
+
+        // An empty list keeps the status we were given before the content
+        let status = self.read_list().map_err(read_err)?;
+        Self::check_status(&status)?;
+
+        Ok(())
+    }
+
+    /// The text packets up to a flush
+    fn read_list(&mut self) -> Result<Vec<BString>, pkt_line::ReadError> {
+        match self.stdout.read_lines()? {
+            (lines, Packet::Flush) => Ok(lines),
+            (_, end) => Err(pkt_line::ReadError::UnexpectedPacket(end)),
+        }
+    }
+
+    fn check_status(list: &[BString]) -> Result<(), FilterError> {
+        match list
+            .iter()
+            .rev()
+            .find_map(|line| line.strip_prefix(b"status="))
+        {
+            None | Some(b"success") => Ok(()),
+            Some(status) => Err(FilterError::Status(status.to_str_lossy().into_owned())),
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // Closing stdin tells the filter to exit
+        self.stdin.take();
+        let _ = self.child.wait();
+    }
+}
+
+impl fmt::Debug for Process {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Process")
+            .field("pid", &self.child.id())
+            .field("capabilities", &self.capabilities)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum FilterError {
+    /// Failed to start filter {0:?}
+    Start(String, #[source] io::Error),
+    /// Failed to read {0} to filter it
+    Input(WsPath, #[source] io::Error),
+    /// Failed to communicate with filter {0:?}
+    Io(String, #[source] io::Error),
+    /// Failed to read response of filter {0:?}
+    Read(String, #[source] pkt_line::ReadError),
+    /// Unexpected handshake from filter {0:?}: {1:?}
+    Handshake(String, Vec<BString>),
+    /// Filter {0:?} failed with exit code {1:?}
+    Failed(String, Option<i32>),
+    /// Filter process doesn't support {0}
+    Unsupported(&'static str),
+    /// Filter process responded with status {0:?}
+    Status(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn filters(config: &str) -> eyre::Result<Filters> {
+        Ok(Filters::from_config(&Config::parse(config)?))
+    }
+
+    #[test]
+    fn parses_drivers() -> eyre::Result<()> {
+        let filters = filters(
+            "[filter \"lfs\"]\n\tclean = git-lfs clean -- %f\n\tprocess = git-lfs filter-process\n\trequired\n",
+        )?;
+        assert_eq!(
+            Some(&Driver {
+                clean: Some("git-lfs clean -- %f".to_owned()),
+                smudge: None,
+                process: Some("git-lfs filter-process".to_owned()),
+                required: true,
+            }),
+            filters.driver("lfs")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn runs_commands() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let filters =
+            filters("[filter \"upper\"]\n\tclean = tr a-z A-Z\n\tsmudge = \"cat; echo %f\"\n")?;
+        let attrs = Attributes::parse_for_test("*.txt filter=upper");
+        let path = WsPath::new_unchecked("a.txt");
+
+        let mut cleaned = Vec::new();
+        assert!(filters.clean(dir.path(), &path, &b"hello"[..], &mut cleaned, &attrs)?);
+        assert_eq!(b"HELLO".as_bstr(), cleaned.as_bstr());
+
+        let mut smudged = Vec::new();
+        assert!(filters.smudge(dir.path(), &path, &b"hi "[..], &mut smudged, &attrs)?);
+        assert_eq!(b"hi a.txt\n".as_bstr(), smudged.as_bstr());
+
+        // More than fits in a pipe, so the filter is written to as it's read
+        let big = vec![b'a'; 1 << 20];
+        let mut cleaned = Vec::new();
+        assert!(filters.clean(dir.path(), &path, big.as_slice(), &mut cleaned, &attrs)?);
+        assert_eq!(vec![b'A'; 1 << 20], cleaned);
+
+        let other = WsPath::new_unchecked("a.md");
+        let mut smudged = Vec::new();
+        assert!(!filters.smudge(dir.path(), &other, &b""[..], &mut smudged, &attrs)?);
+        assert!(smudged.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn failures_pass_through_unless_required() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let attrs = Attributes::parse_for_test("* filter=broken");
+        let path = WsPath::new_unchecked("a");
+
+        let optional = filters("[filter \"broken\"]\n\tsmudge = exit 1\n")?;
+        assert!(!optional.smudge(dir.path(), &path, &b""[..], &mut Vec::new(), &attrs)?);
+
+        let required = filters("[filter \"broken\"]\n\tsmudge = exit 1\n\trequired = true\n")?;
+        assert!(required
+            .smudge(dir.path(), &path, &b""[..], &mut Vec::new(), &attrs)
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bstr::{BStr, BString, ByteSlice};
+use regex::bytes::Regex;
+use tracing::{debug, warn};
+
+use crate::core::{Workspace, WsPath};
+
+/// Rules from `.git/info/exclude` and the `.gitignore` files of the
+/// workspace. Per-directory files are loaded lazily, as the directories they
+/// apply to are visited.
+#[derive(Debug, Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct IgnoreRules {
+    /// In increasing order of precedence
+    patterns: Vec<Pattern>,
+    loaded_dirs: BTreeSet<WsPath>,
+    load_dirs: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    /// The pattern as written, without any trailing whitespace
+    pub text: BString,
+    pub source: Source,
+    /// The directory the pattern is relative to
+    pub base: WsPath,
+    pub negated: bool,
+    pub dir_only: bool,
+    regex: Regex,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Source {
+    /// The file the pattern was read from
+    pub file: PathBuf,
+    /// One-based
+    pub line: usize,
+}
+
+impl IgnoreRules {
+    const FILE_NAME: &'static str = ".gitignore";
+
+    pub fn new(git_dir: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let mut rules = Self::none();
+        rules.load_dirs = true;
+        let exclude = git_dir.as_ref().join("info/exclude");
+        rules.load_file(&exclude, &WsPath::root())?;
+        Ok(rules)
+    }
+
+    /// Rules that ignore nothing. `.gitignore` files aren't loaded.
+    pub fn none() -> Self {
+        Self {
+            patterns: Vec::new(),
+            loaded_dirs: BTreeSet::new(),
+            load_dirs: false,
+        }
+    }
+
+    /// Load the `.gitignore` of the directory, if it hasn't been already.
+    pub fn load_dir(&mut self, workspace: &Workspace, dir: &WsPath) -> Result<(), LoadError> {
+        if !self.load_dirs || self.loaded_dirs.contains(dir) {
+            return Ok(());
+        }
+        let file = dir.join(Self::FILE_NAME);
+        let file = file
+            .to_absolute(workspace)
+            .map_err(|e| LoadError(file.into_path_buf(), e.into()))?;
+        self.load_file(&file, dir)?;
+        self.loaded_dirs.insert(dir.clone());
+        Ok(())
+    }
+
+    fn load_file(&mut self, file: &Path, base: &WsPath) -> Result<(), LoadError> {
+        let contents = match fs::read(file) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(LoadError(file.to_owned(), err)),
+        };
+        debug!(?file, "Loading ignore rules");
+
+        for (i, line) in contents.lines().enumerate() {
+            let source = Source {
+                file: file.to_owned(),
+                line: i + 1,
+            };
+            if let Some(pattern) = Pattern::parse(line.as_bstr(), base, source) {
+                self.patterns.push(pattern);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the path or any directory containing it is ignored. Loads the
+    /// rules of every directory containing the path.
+    pub fn is_ignored(
+        &mut self,
+        workspace: &Workspace,
+        path: &WsPath,
+        is_dir: bool,
+    ) -> Result<bool, LoadError> {
+        Ok(self
+            .matching_with_parents(workspace, path, is_dir)?
+            .is_some_and(|pattern| !pattern.negated))
+    }
+
+    /// Like [`Self::is_ignored`], but returns the pattern that decided the
+    /// path, which may be a negated pattern re-including it. If a containing
+    /// directory is ignored the pattern that ignored it is returned.
+    pub fn matching_with_parents(
+        &mut self,
+        workspace: &Workspace,
+        path: &WsPath,
+        is_dir: bool,
+    ) -> Result<Option<&Pattern>, LoadError> {
+        self.load_dir(workspace, &WsPath::root())?;
+        for parent in path.parents() {
+            if self.is_ignored_loaded(&parent, true) {
+                return Ok(self.matching(&parent, true));
+            }
+            self.load_dir(workspace, &parent)?;
+        }
+        Ok(self.matching(path, is_dir))
+    }
+
+    /// Checks the path only, assuming its parents are loaded and not ignored.
+    pub fn is_ignored_loaded(&self, path: &WsPath, is_dir: bool) -> bool {
+        self.matching(path, is_dir)
+            .is_some_and(|pattern| !pattern.negated)
+    }
+
+    /// The last pattern matching the path, considering only rules already
+    /// loaded and not considering parents.
+    pub fn matching(&self, path: &WsPath, is_dir: bool) -> Option<&Pattern> {
+        if *path == WsPath::root() {
+            return None;
+        }
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(path, is_dir))
+    }
+}
+
+impl Pattern {
+    fn parse(line: &BStr, base: &WsPath, source: Source) -> Option<Self> {
+        let line = Self::trim_trailing_spaces(line);
+        if line.is_empty() || line.starts_with(b"#") {
+            return None;
+        }
+
+        let text = line.to_owned();
+        let mut pattern = line;
+
+        let negated = pattern.starts_with(b"!");
+        if negated {
+            pattern = &pattern[1..];
+        }
+        if pattern.starts_with(b"\\!") || pattern.starts_with(b"\\#") {
+            pattern = &pattern[1..];
+        }
+
+        let dir_only = pattern.ends_with(b"/");
+        if dir_only {
+            pattern = &pattern[..pattern.len() - 1];
+        }
+
+        let anchored = pattern.contains(&b'/');
+        let pattern = pattern.strip_prefix(b"/").unwrap_or(pattern);
+        if pattern.is_empty() {
+            return None;
+        }
+
+        let regex = match Regex::new(&Self::to_regex(pattern, anchored)) {
+            Ok(regex) => regex,
+            Err(err) => {
+                warn!(?source, %err, "Skipping invalid ignore pattern");
+                return None;
+            }
+        };
+
+        Some(Self {
+            text,
+            source,
+            base: base.clone(),
+            negated,
+            dir_only,
+            regex,
+        })
+    }
+
+    fn trim_trailing_spaces(line: &BStr) -> &BStr {
+        let mut end = line.len();
+        while end > 0 && line[end - 1] == b' ' && !(end > 1 && line[end - 2] == b'\\') {
+            end -= 1;
+        }
+        line[..end].as_bstr()
+    }
+
+    /// Patterns that aren't anchored match in any directory
+    pub(crate) fn to_regex(pattern: &[u8], anchored: bool) -> String {
+        let mut re = String::from("(?s-u)^");
+        if !anchored {
+            re.push_str("(?:.*/)?");
+        }
+
+        let mut i = 0;
+        while i < pattern.len() {
+            let rest = &pattern[i..];
+            if rest.starts_with(b"**/") && (i == 0 || pattern[i - 1] == b'/') {
+                re.push_str("(?:.*/)?");
+                i += 3;
+            } else if rest == b"**" && (i == 0 || pattern[i - 1] == b'/') {
+                re.push_str(".*");
+                i += 2;
+            } else {
+                match rest[0] {
+                    b'*' => re.push_str("[^/]*"),
+                    b'?' => re.push_str("[^/]"),
+                    b'[' => {
+                        if let Some(len) = Self::push_class(&mut re, rest) {
+                            i += len;
+                            continue;
+                        }
+                        re.push_str(r"\[");
+                    }
+                    b'\\' if rest.len() > 1 => {
+                        Self::push_literal(&mut re, rest[1]);
+                        i += 1;
+                    }
+                    byte => Self::push_literal(&mut re, byte),
+                }
+                i += 1;
+            }
+        }
+
+        re.push('$');
+        re
+    }
+
+    /// Returns the length of the class consumed, or `None` if unterminated
+    pub(crate) fn push_class(re: &mut String, class: &[u8]) -> Option<usize> {
+        let mut i = 1;
+        let mut out = String::from("[");
+        if matches!(class.get(i), Some(b'!' | b'^')) {
+            out.push('^');
+            i += 1;
+        }
+        if class.get(i) == Some(&b']') {
+            out.push_str(r"\]");
+            i += 1;
+        }
+        while let Some(&byte) = class.get(i) {
+            match byte {
+                b']' => {
+                    out.push(']');
+                    re.push_str(&out);
+                    return Some(i + 1);
+                }
+                b'\\' if i + 1 < class.len() => {
+                    Self::push_literal(&mut out, class[i + 1]);
+                    i += 2;
+                }
+                b'-' => {
+                    out.push('-');
+                    i += 1;
+                }
+                _ => {
+                    Self::push_literal(&mut out, byte);
+                    i += 1;
+                }
+            }
+        }
+        None
+    }
+
+    pub(crate) fn push_literal(re: &mut String, byte: u8) {
+        if byte.is_ascii_alphanumeric() {
+            re.push(byte as char);
+        } else {
+            re.push_str(r"\x");
+            re.push_str(&hex::encode([byte]));
+        }
+    }
+
+    pub fn matches(&self, path: &WsPath, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if *path == self.base || !path.is_within(&self.base) {
+            return false;
+        }
+        let rel = path.strip_prefix(&self.base).expect("Checked within base");
+        self.regex.is_match(rel.as_bstr())
+    }
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+/// Failed to read ignore file {0:?}
+pub struct LoadError(PathBuf, #[source] io::Error);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(lines: &str) -> IgnoreRules {
+        rules_in(lines, &WsPath::root())
+    }
+
+    fn rules_in(lines: &str, base: &WsPath) -> IgnoreRules {
+        let mut rules = IgnoreRules::none();
+        for (i, line) in lines.lines().enumerate() {
+            let source = Source {
+                file: PathBuf::from(".gitignore"),
+                line: i + 1,
+            };
+            if let Some(pattern) = Pattern::parse(line.as_bytes().as_bstr(), base, source) {
+                rules.patterns.push(pattern);
+            }
+        }
+        rules
+    }
+
+    fn ignored(rules: &IgnoreRules, path: &str) -> bool {
+        rules.is_ignored_loaded(&WsPath::new_unchecked(path), false)
+    }
+
+    fn ignored_dir(rules: &IgnoreRules, path: &str) -> bool {
+        rules.is_ignored_loaded(&WsPath::new_unchecked(path), true)
+    }
+
+    #[test]
+    fn basename_patterns_match_at_any_depth() {
+        let rules = rules("*.o\n# comment\n\nfoo");
+        assert!(ignored(&rules, "a.o"));
+        assert!(ignored(&rules, "dir/b.o"));
+        assert!(ignored(&rules, "dir/foo"));
+        assert!(ignored_dir(&rules, "foo"));
+        assert!(!ignored(&rules, "a.c"));
+        assert!(!ignored(&rules, "foobar"));
+        assert!(!ignored(&rules, "# comment"));
+    }
+
+    #[test]
+    fn anchored_patterns() {
+        let rules = rules("/root.txt\ndoc/*.html");
+        assert!(ignored(&rules, "root.txt"));
+        assert!(!ignored(&rules, "dir/root.txt"));
+        assert!(ignored(&rules, "doc/a.html"));
+        assert!(!ignored(&rules, "doc/sub/a.html"));
+        assert!(!ignored(&rules, "other/doc/a.html"));
+    }
+
+    #[test]
+    fn double_star() {
+        let rules = rules("**/logs\nbuild/**\na/**/z");
+        assert!(ignored_dir(&rules, "logs"));
+        assert!(ignored_dir(&rules, "x/y/logs"));
+        assert!(ignored(&rules, "build/out/bin"));
+        assert!(!ignored_dir(&rules, "build"));
+        assert!(ignored(&rules, "a/z"));
+        assert!(ignored(&rules, "a/b/c/z"));
+    }
+
+    #[test]
+    fn dir_only_and_negation() {
+        let rules = rules("target/\n*.log\n!keep.log");
+        assert!(ignored_dir(&rules, "target"));
+        assert!(!ignored(&rules, "target"));
+        assert!(ignored(&rules, "a.log"));
+        assert!(!ignored(&rules, "keep.log"));
+        assert!(rules
+            .matching(&WsPath::new_unchecked("keep.log"), false)
+            .is_some_and(|p| p.negated && p.source.line == 3));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        let rules = rules("file[0-9].txt\n[!a]*.md\n\\#hash\nspace\\ \nq?.c");
+        assert!(ignored(&rules, "file3.txt"));
+        assert!(!ignored(&rules, "filex.txt"));
+        assert!(ignored(&rules, "b.md"));
+        assert!(!ignored(&rules, "a.md"));
+        assert!(ignored(&rules, "#hash"));
+        assert!(ignored(&rules, "space "));
+        assert!(ignored(&rules, "q1.c"));
+        assert!(!ignored(&rules, "q12.c"));
+    }
+
+    #[test]
+    fn patterns_are_relative_to_their_file() {
+        let rules = rules_in("/x\n*.tmp", &WsPath::new_unchecked("sub"));
+        assert!(ignored(&rules, "sub/x"));
+        assert!(!ignored(&rules, "x"));
+        assert!(ignored(&rules, "sub/deeper/a.tmp"));
+        assert!(!ignored(&rules, "a.tmp"));
+    }
+}
@@ -1,29 +1,108 @@
+pub mod attributes;
+pub mod eol;
+pub mod filter;
+pub mod ignore;
 pub mod path;
+pub mod transaction;
+pub mod walk;
+pub use attributes::Attributes;
+pub use ignore::IgnoreRules;
 pub use path::WsPath;
+pub use transaction::Transaction;
+pub use walk::{Listing, Walk, Walked};
 
-use crate::core::Stat;
+use crate::core::{platform, stat::Mode, Stat};
 
 use bstr::BString;
 use std::{
+    borrow::Cow,
+    ffi::OsStr,
     fmt, fs, io,
     path::{Path, PathBuf},
 };
 use tracing::instrument;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
 
 #[derive(Debug, Clone)]
 pub struct Workspace {
     path: PathBuf,
+    symlinks: bool,
+    file_mode: bool,
+    precompose_unicode: bool,
+    eol: eol::Settings,
+    filters: filter::Filters,
 }
 
 impl Workspace {
     pub fn new<P: Into<PathBuf>>(path: P) -> Self {
-        Self { path: path.into() }
+        Self {
+            path: path.into(),
+            symlinks: true,
+            file_mode: true,
+            precompose_unicode: false,
+            eol: eol::Settings::default(),
+            filters: filter::Filters::default(),
+        }
     }
 
     pub fn path(&self) -> &Path {
         &self.path
     }
 
+    /// If symlinks are created as symlinks (the default), or as plain files
+    /// containing the target, for filesystems that don't support them (like
+    /// git's `core.symlinks = false`).
+    pub fn symlinks(&self) -> bool {
+        self.symlinks
+    }
+
+    pub fn set_symlinks(&mut self, symlinks: bool) {
+        self.symlinks = symlinks;
+    }
+
+    /// If the executable bit in the workspace can be trusted (the default).
+    /// If not (like git's `core.fileMode = false`), files are considered
+    /// regular unless already recorded as executable, so mode changes are
+    /// ignored.
+    pub fn file_mode(&self) -> bool {
+        self.file_mode
+    }
+
+    pub fn set_file_mode(&mut self, file_mode: bool) {
+        self.file_mode = file_mode;
+    }
+
+    /// If decomposed unicode in file names is converted to the precomposed
+    /// form when listing files (like git's `core.precomposeUnicode`). HFS+
+    /// and APFS give back file names decomposed, however they were created.
+    pub fn precompose_unicode(&self) -> bool {
+        self.precompose_unicode
+    }
+
+    pub fn set_precompose_unicode(&mut self, precompose_unicode: bool) {
+        self.precompose_unicode = precompose_unicode;
+    }
+
+    /// How line endings are converted, as well as the `text` and `eol`
+    /// attributes
+    pub fn eol(&self) -> &eol::Settings {
+        &self.eol
+    }
+
+    pub fn set_eol(&mut self, eol: eol::Settings) {
+        self.eol = eol;
+    }
+
+    /// Clean and smudge filters, applied to paths with the `filter`
+    /// attribute
+    pub fn filters(&self) -> &filter::Filters {
+        &self.filters
+    }
+
+    pub fn set_filters(&mut self, filters: filter::Filters) {
+        self.filters = filters;
+    }
+
     #[instrument(err)]
     pub fn find_files<I, P>(&self, paths: I) -> Result<Vec<WsPath>, ListFilesError>
     where
@@ -34,10 +113,7 @@ impl Workspace {
 
         for rel_path in paths {
             let rel_path = rel_path.as_ref();
-            let abs_path = self
-                .path
-                .join(rel_path)
-                .canonicalize()
+            let abs_path = Self::canonicalize_parent(&self.path.join(rel_path))
                 .map_err(|e| ListFilesError::Canonicalize(rel_path.to_owned(), e))?;
             self.list_files_in(&abs_path, &mut files)?;
         }
@@ -52,6 +128,27 @@ impl Workspace {
         Ok(files)
     }
 
+    /// Fails the way [`Self::find_files`] would if the path doesn't exist
+    pub(crate) fn check_exists(&self, path: &WsPath) -> Result<(), ListFilesError> {
+        Self::canonicalize_parent(&self.path.join(path.as_path()))
+            .map(drop)
+            .map_err(|e| ListFilesError::Canonicalize(path.as_path().to_owned(), e))
+    }
+
+    /// Canonicalize everything but the last component, so that a symlink is
+    /// kept rather than resolved to its target
+    fn canonicalize_parent(path: &Path) -> io::Result<PathBuf> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => {
+                let abs_path = parent.canonicalize()?.join(name);
+                // Surface not found errors the same as canonicalizing would
+                abs_path.symlink_metadata()?;
+                Ok(abs_path)
+            }
+            _ => path.canonicalize(),
+        }
+    }
+
     fn list_files_in(
         &self,
         abs_path: &Path,
@@ -61,12 +158,14 @@ impl Workspace {
         let rel_path = abs_path
             .strip_prefix(&self.path)
             .map_err(|_| ListFilesError::OutsideOfWorkspace(abs_path.to_owned()))?;
+        let rel_path = self.precompose(rel_path.as_os_str());
+        let rel_path = Path::new(&rel_path);
 
         let meta = abs_path
-            .metadata()
+            .symlink_metadata()
             .map_err(|e| ListFilesError::GetMetadata(abs_path.to_owned(), e))?;
 
-        if Self::is_ignored(&rel_path) {
+        if Self::is_ignored(rel_path) {
             return Ok(());
         }
 
@@ -82,7 +181,7 @@ impl Workspace {
                 .collect::<Result<Vec<_>, ListFilesError>>()?;
 
             files.extend(self.find_files(children)?);
-        } else if meta.is_file() {
+        } else if meta.is_file() || meta.file_type().is_symlink() {
             files.push(WsPath::new_unchecked(rel_path));
         } else {
             return Err(ListFilesError::InvalidFileType(rel_path.into()));
@@ -91,27 +190,245 @@ impl Workspace {
         Ok(())
     }
 
+    /// See [`Self::precompose_unicode`]. Names that aren't UTF-8 are left
+    /// alone.
+    fn precompose<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        match name.to_str() {
+            Some(name) if self.precompose_unicode && !name.is_ascii() && !is_nfc(name) => {
+                Cow::Owned(name.nfc().collect::<String>().into())
+            }
+            _ => Cow::Borrowed(name),
+        }
+    }
+
     fn is_ignored(rel_path: &Path) -> bool {
         rel_path.starts_with(".git")
     }
 
+    /// The contents of a file, or the target of a symlink, as git stores
+    /// them in a blob
     pub fn read_file(&self, path: &WsPath) -> Result<BString, ReadFileError> {
-        let bytes = fs::read(path.to_absolute(self)).map_err(|e| ReadFileError(path.clone(), e))?;
+        let abs_path = path
+            .to_absolute(self)
+            .map_err(|e| ReadFileError(path.clone(), e.into()))?;
+        let meta = abs_path
+            .symlink_metadata()
+            .map_err(|e| ReadFileError(path.clone(), e))?;
+        let bytes = if meta.file_type().is_symlink() {
+            let target = fs::read_link(&abs_path).map_err(|e| ReadFileError(path.clone(), e))?;
+            platform::into_bytes(target)
+        } else {
+            fs::read(abs_path).map_err(|e| ReadFileError(path.clone(), e))?
+        };
         Ok(bytes.into())
     }
 
+    /// Like [`Self::read_file`], but cleaned and with line endings converted
+    /// for storing in the database. Symlink targets aren't converted.
+    pub fn read_for_git(
+        &self,
+        path: &WsPath,
+        attrs: &Attributes,
+    ) -> Result<BString, ReadForGitError> {
+        self.read_for_git_with(path, attrs, self.eol)
+    }
+
+    /// Like [`Self::read_for_git`], but never warns about or refuses
+    /// conversions that wouldn't round trip, for when we only want the hash.
+    pub(crate) fn read_for_hashing(
+        &self,
+        path: &WsPath,
+        attrs: &Attributes,
+    ) -> Result<BString, ReadForGitError> {
+        let quiet = eol::Settings {
+            safecrlf: eol::SafeCrlf::False,
+            ..self.eol
+        };
+        self.read_for_git_with(path, attrs, quiet)
+    }
+
+    fn read_for_git_with(
+        &self,
+        path: &WsPath,
+        attrs: &Attributes,
+        eol: eol::Settings,
+    ) -> Result<BString, ReadForGitError> {
+        if self.stat(path)?.mode == Mode::Symlink {
+            return Ok(self.read_file(path)?);
+        }
+        let abs_path = path
+            .to_absolute(self)
+            .map_err(|e| ReadFileError(path.clone(), e.into()))?;
+        let mut cleaned = Vec::new();
+        let filtered = self.filters.cleans(path, attrs) && {
+            let input = fs::File::open(&abs_path).map_err(|e| ReadFileError(path.clone(), e))?;
+            self.filters
+                .clean(&self.path, path, input, &mut cleaned, attrs)?
+        };
+        let data = if filtered {
+            cleaned.into()
+        } else {
+            self.read_file(path)?
+        };
+        let converted = eol.to_git(path, &data, attrs)?;
+        Ok(converted.into_owned().into())
+    }
+
+    /// Like [`Self::write_entry`], but with line endings converted from how
+    /// they're stored in the database and smudged.
+    pub fn write_from_git(
+        &self,
+        path: &WsPath,
+        mode: Mode,
+        data: &[u8],
+        attrs: &Attributes,
+    ) -> Result<(), WriteFromGitError> {
+        let data = self.to_worktree(path, mode, data, attrs)?;
+        self.write_entry(path, mode, &data)?;
+        Ok(())
+    }
+
+    /// Whether [`Self::to_worktree`] might change the contents
+    fn converts_to_worktree(&self, path: &WsPath, mode: Mode, attrs: &Attributes) -> bool {
+        mode != Mode::Symlink
+            && (self.eol.converts_to_worktree(path, attrs) || self.filters.smudges(path, attrs))
+    }
+
+    /// What [`Self::write_from_git`] writes
+    fn to_worktree<'a>(
+        &self,
+        path: &WsPath,
+        mode: Mode,
+        data: &'a [u8],
+        attrs: &Attributes,
+    ) -> Result<Cow<'a, [u8]>, filter::FilterError> {
+        if mode == Mode::Symlink {
+            return Ok(Cow::Borrowed(data));
+        }
+        let converted = self.eol.to_worktree(path, data, attrs);
+        let mut smudged = Vec::new();
+        let filtered = self
+            .filters
+            .smudge(&self.path, path, &*converted, &mut smudged, attrs)?;
+        Ok(if filtered {
+            Cow::Owned(smudged)
+        } else {
+            converted
+        })
+    }
+
+    /// Like [`Self::stat`], but for a path already recorded with the given
+    /// mode. If we don't create symlinks then a recorded symlink will have
+    /// been written as a plain file, so we keep the symlink mode. Similarly if
+    /// we don't trust the executable bit we keep the recorded one.
+    pub fn stat_tracked(&self, path: &WsPath, recorded: Mode) -> Result<Stat, StatFileError> {
+        let mut stat = self.stat(path)?;
+        let is_file = stat.mode.is_same_type(Mode::Regular);
+        if !self.symlinks && recorded == Mode::Symlink && is_file {
+            stat.mode = Mode::Symlink;
+        }
+        if !self.file_mode && recorded.is_same_type(Mode::Regular) && is_file {
+            stat.mode = recorded;
+        }
+        Ok(stat)
+    }
+
+    /// Materialize a tree entry, replacing any file or symlink already at the
+    /// path. For a symlink `data` is the target, and for a gitlink an empty
+    /// directory is created (we don't populate submodules).
+    pub fn write_entry(
+        &self,
+        path: &WsPath,
+        mode: Mode,
+        data: &[u8],
+    ) -> Result<(), WriteFileError> {
+        let err = |e| WriteFileError(path.clone(), e);
+        let abs_path = path.to_absolute(self).map_err(|e| err(e.into()))?;
+
+        if let Some(parent) = abs_path.parent() {
+            fs::create_dir_all(parent).map_err(err)?;
+        }
+
+        match abs_path.symlink_metadata() {
+            Ok(meta) if meta.is_dir() && mode == Mode::Gitlink => return Ok(()),
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(&abs_path).map_err(err)?,
+            Ok(_) => fs::remove_file(&abs_path).map_err(err)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(err(e)),
+        }
+
+        match mode {
+            Mode::Symlink if self.symlinks => platform::symlink(data, &abs_path).map_err(err)?,
+            Mode::Gitlink => fs::create_dir(&abs_path).map_err(err)?,
+            Mode::Regular | Mode::Executable | Mode::Symlink => {
+                fs::write(&abs_path, data).map_err(err)?;
+                platform::set_executable(&abs_path, mode == Mode::Executable).map_err(err)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove a file or symlink, and any directories left empty. A gitlink's
+    /// directory is only removed if it's empty. It's fine if nothing is
+    /// there.
+    pub fn remove_entry(&self, path: &WsPath) -> Result<(), WriteFileError> {
+        let err = |e| WriteFileError(path.clone(), e);
+        let abs_path = path.to_absolute(self).map_err(|e| err(e.into()))?;
+
+        match abs_path.symlink_metadata() {
+            Ok(meta) if meta.is_dir() => {
+                if fs::remove_dir(&abs_path).is_err() {
+                    // A populated submodule, which we leave alone
+                    return Ok(());
+                }
+            }
+            Ok(_) => fs::remove_file(&abs_path).map_err(err)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(err(e)),
+        }
+
+        self.remove_empty_parents(path);
+        Ok(())
+    }
+
+    /// Innermost first, stopping at the first that isn't empty
+    fn remove_empty_parents(&self, path: &WsPath) {
+        let parents = path.parents().collect::<Vec<_>>();
+        for parent in parents.into_iter().rev() {
+            let removed = parent
+                .to_absolute(self)
+                .is_ok_and(|parent| fs::remove_dir(parent).is_ok());
+            if !removed {
+                // Not empty
+                break;
+            }
+        }
+    }
+
+    /// Doesn't follow symlinks, and doesn't trust the executable bit if
+    /// [`Self::file_mode`] is false. A directory is assumed to be a gitlink, as
+    /// that's the only way one can be where a file is expected.
     pub fn stat(&self, path: &WsPath) -> Result<Stat, StatFileError> {
-        self.path
+        let meta = self
+            .path
             .join(path)
-            .metadata()
-            .map(|m| Stat::from(&m))
-            .map_err(|e| StatFileError(path.clone(), e))
+            .symlink_metadata()
+            .map_err(|e| StatFileError(path.clone(), e))?;
+        let mut stat = Stat::from(&meta);
+        if meta.is_dir() {
+            stat.mode = Mode::Gitlink;
+        }
+        if !self.file_mode && stat.mode == Mode::Executable {
+            stat.mode = Mode::Regular;
+        }
+        Ok(stat)
     }
 }
 
 #[derive(Debug, displaydoc::Display, thiserror::Error)]
 /// Failed to stat file {0:?}
-pub struct StatFileError(WsPath, io::Error);
+pub struct StatFileError(WsPath, #[source] io::Error);
 
 impl StatFileError {
     pub(crate) fn is_not_found(&self) -> bool {
@@ -121,7 +438,33 @@ impl StatFileError {
 
 #[derive(Debug, displaydoc::Display, thiserror::Error)]
 /// Failed to read file {0:?}
-pub struct ReadFileError(WsPath, io::Error);
+pub struct ReadFileError(WsPath, #[source] io::Error);
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+/// Failed to write file {0:?}
+pub struct WriteFileError(WsPath, #[source] io::Error);
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum ReadForGitError {
+    /// Failed to read file
+    Read(#[from] ReadFileError),
+    /// Failed to stat file
+    Stat(#[from] StatFileError),
+    /// Refusing to convert line endings
+    Unsafe(#[from] eol::UnsafeError),
+    /// Failed to filter file
+    Filter(#[from] filter::FilterError),
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+pub enum WriteFromGitError {
+    /// Failed to read contents of {0:?} from database
+    Read(WsPath, #[source] io::Error),
+    /// Failed to write file
+    Write(#[from] WriteFileError),
+    /// Failed to filter file
+    Filter(#[from] filter::FilterError),
+}
 
 #[derive(Debug, displaydoc::Display, thiserror::Error)]
 pub enum ListFilesError {
@@ -137,6 +480,8 @@ pub enum ListFilesError {
     ReadDir(PathBuf, #[source] io::Error),
     /// Failed to read entry of directory {0:?}
     ReadDirEntry(PathBuf, #[source] io::Error),
+    /// Failed to load ignore rules
+    LoadIgnores(#[from] ignore::LoadError),
 }
 
 #[cfg(test)]
@@ -202,4 +547,80 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn precomposes_unicode_names() -> eyre::Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let decomposed = "cafe\u{301}";
+        fs::create_dir(dir.path().join(decomposed))?;
+        fs::write(dir.path().join(decomposed).join("a"), "")?;
+
+        let mut workspace = Workspace::new(dir.path());
+        let list = |workspace: &Workspace| -> eyre::Result<Vec<WsPath>> {
+            let listing =
+                workspace.list_files_under(&[WsPath::root()], &mut IgnoreRules::none())?;
+            Ok(listing.files)
+        };
+
+        assert_eq!(
+            vec![WsPath::new_unchecked("cafe\u{301}/a")],
+            list(&workspace)?
+        );
+        workspace.set_precompose_unicode(true);
+        assert_eq!(
+            vec![WsPath::new_unchecked("caf\u{e9}/a")],
+            list(&workspace)?
+        );
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_entries() -> eyre::Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let workspace = Workspace::new(dir.path());
+
+        let file = WsPath::new_unchecked("dir/file");
+        let link = WsPath::new_unchecked("dir/link");
+        workspace.write_entry(&file, Mode::Executable, b"contents")?;
+        workspace.write_entry(&link, Mode::Symlink, b"file")?;
+
+        assert_eq!(Mode::Executable, workspace.stat(&file)?.mode);
+        assert_eq!(Mode::Symlink, workspace.stat(&link)?.mode);
+        assert_eq!("contents", fs::read_to_string(dir.path().join("dir/link"))?);
+        assert_eq!(b"file".as_ref(), workspace.read_file(&link)?);
+
+        // Replaces whatever is already there
+        workspace.write_entry(&link, Mode::Regular, b"not a link")?;
+        assert_eq!(Mode::Regular, workspace.stat(&link)?.mode);
+        assert_eq!(b"not a link".as_ref(), workspace.read_file(&link)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_symlinks_as_files_if_unsupported() -> eyre::Result<()> {
+        init();
+
+        let dir = tempdir()?;
+        let mut workspace = Workspace::new(dir.path());
+        workspace.set_symlinks(false);
+
+        let link = WsPath::new_unchecked("link");
+        workspace.write_entry(&link, Mode::Symlink, b"target")?;
+
+        assert_eq!(Mode::Regular, workspace.stat(&link)?.mode);
+        assert_eq!(
+            Mode::Symlink,
+            workspace.stat_tracked(&link, Mode::Symlink)?.mode
+        );
+        assert_eq!(b"target".as_ref(), workspace.read_file(&link)?);
+
+        Ok(())
+    }
 }
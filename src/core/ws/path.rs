@@ -1,14 +1,14 @@
 use std::{
-    ffi::{OsStr, OsString},
     fmt,
-    os::unix::prelude::{OsStrExt, OsStringExt},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
 use bstr::{BStr, BString, ByteSlice};
 
-use crate::core::Workspace;
+use crate::core::{platform, Workspace};
 
+/// With the `serde` feature this serializes as a string, lossily if the path
+/// isn't UTF-8, or as bytes in binary formats
 #[derive(Debug, Clone, Eq, PartialEq)]
 #[allow(clippy::module_name_repetitions)]
 pub struct WsPath(PathBuf);
@@ -31,17 +31,65 @@ impl WsPath {
         }
     }
 
+    /// Lexically normalize a path relative to the workspace root, without
+    /// touching the filesystem. Unlike [`Self::new_canonicalized`] the path
+    /// doesn't need to exist.
+    pub fn new_normalized(path: impl AsRef<Path>) -> Result<Self, NormalizeError> {
+        let path = path.as_ref();
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !normalized.pop() {
+                        return Err(NormalizeError(path.to_owned()));
+                    }
+                }
+                Component::Normal(name) => normalized.push(name),
+                Component::RootDir | Component::Prefix(_) => {
+                    return Err(NormalizeError(path.to_owned()))
+                }
+            }
+        }
+        Ok(Self(platform::normalize(normalized)))
+    }
+
     /// Path must be in canonical form and inside the workspace you use it with
     pub fn new_unchecked(path: impl Into<PathBuf>) -> Self {
-        Self(path.into())
+        Self(platform::normalize(path.into()))
     }
 
     pub fn new_unchecked_bytes(path: impl Into<BString>) -> Self {
         let path: BString = path.into();
         let path: Vec<u8> = path.into();
-        let path = OsString::from_vec(path);
-        let path = PathBuf::from(path);
-        Self(path)
+        Self(platform::from_bytes(path))
+    }
+
+    /// Like [`Self::new_unchecked_bytes`], for paths read from files that
+    /// could have anything in them, like the index. See [`Self::check`].
+    pub fn new_checked_bytes(path: impl Into<BString>) -> Result<Self, InvalidPathError> {
+        let path = Self::new_unchecked_bytes(path);
+        path.check()?;
+        Ok(path)
+    }
+
+    /// Fails unless the path is the root or each of its components is a
+    /// name, so not empty, `.` or `..`, which also means it's relative. Only
+    /// such paths are certain to stay inside the workspace.
+    pub fn check(&self) -> Result<(), InvalidPathError> {
+        if Self::is_valid_bytes(self.as_bstr()) {
+            Ok(())
+        } else {
+            Err(InvalidPathError::new(self.clone()))
+        }
+    }
+
+    /// [`Self::check`] without making a path first
+    pub(crate) fn is_valid_bytes(path: &[u8]) -> bool {
+        path.is_empty()
+            || path
+                .split_str("/")
+                .all(|name| !name.is_empty() && name != b"." && name != b"..")
     }
 
     pub fn root() -> Self {
@@ -49,7 +97,7 @@ impl WsPath {
     }
 
     pub fn as_bstr(&self) -> &BStr {
-        self.0.as_os_str().as_bytes().as_bstr()
+        platform::as_bytes(&self.0).as_bstr()
     }
 
     pub fn to_bstring(&self) -> BString {
@@ -68,40 +116,34 @@ impl WsPath {
         &mut self.0
     }
 
-    /// Panics if self is outside of workspace
-    pub fn to_absolute(&self, workspace: &Workspace) -> PathBuf {
-        let path = workspace.path().join(&self.0);
-        if !path.starts_with(workspace.path()) {
-            panic!("Workspace path outside of workspace was created: {:?}. Refusing to make absolute. Workspace: {:?}", self, workspace);
-        }
-        path
+    /// Fails if the path might be outside the workspace, see [`Self::check`]
+    pub fn to_absolute(&self, workspace: &Workspace) -> Result<PathBuf, InvalidPathError> {
+        self.check()?;
+        Ok(workspace.path().join(&self.0))
     }
 
-    pub fn file_name(&self) -> &BStr {
-        if let Some(name) = self.0.file_name() {
-            name.as_bytes().as_bstr()
-        } else {
-            panic!(
-                "Non-normalized path was created: {:?}. Failed to get file name",
-                self,
-            )
-        }
+    /// The last component, or `None` for the root
+    pub fn file_name(&self) -> Option<&BStr> {
+        self.0
+            .file_name()
+            .map(|name| platform::as_bytes(Path::new(name)).as_bstr())
     }
 
+    #[must_use]
     pub fn parent(&self) -> WsPath {
         self.0
             .parent()
             .map_or_else(WsPath::root, |path| WsPath(path.to_owned()))
     }
 
-    pub fn parents(&self) -> Parents {
+    pub fn parents(&self) -> Parents<'_> {
         Parents::new(self)
     }
 
     pub fn components(&self) -> impl DoubleEndedIterator<Item = &BStr> {
         self.0
             .components()
-            .map(|c| c.as_os_str().as_bytes().as_bstr())
+            .map(|c| platform::as_bytes(Path::new(c.as_os_str())).as_bstr())
     }
 
     pub fn parent_components(&self) -> impl DoubleEndedIterator<Item = &BStr> {
@@ -110,17 +152,41 @@ impl WsPath {
         iter
     }
 
+    #[must_use]
     pub fn join(&self, path: impl AsRef<Path>) -> Self {
-        Self(self.0.join(path))
+        Self(platform::normalize(self.0.join(path)))
     }
 
     pub fn push(&mut self, path: impl AsRef<Path>) {
-        self.0.push(path)
+        self.0.push(path);
+        self.0 = platform::normalize(std::mem::take(&mut self.0));
     }
 
+    #[must_use]
     pub fn join_bytes(&self, path: &BStr) -> Self {
-        let path = OsStr::from_bytes(path.as_bytes());
-        Self(self.0.join(path))
+        let path = platform::from_bytes(path.as_bytes());
+        Self(platform::normalize(self.0.join(path)))
+    }
+
+    /// True if self is base or is inside the directory base. Every path is
+    /// within [`Self::root`].
+    pub fn is_within(&self, base: &Self) -> bool {
+        self.0.starts_with(&base.0)
+    }
+
+    /// Drops every path that is within another path in the list, so that
+    /// walking the result never visits a file twice.
+    pub fn minimal_prefixes(paths: &[Self]) -> Vec<&Self> {
+        let mut sorted = paths.iter().collect::<Vec<_>>();
+        sorted.sort();
+
+        let mut minimal: Vec<&Self> = Vec::new();
+        for path in sorted {
+            if !minimal.iter().any(|prefix| path.is_within(prefix)) {
+                minimal.push(path);
+            }
+        }
+        minimal
     }
 
     pub fn strip_prefix(&self, base: &Self) -> Result<Self, std::path::StripPrefixError> {
@@ -128,6 +194,21 @@ impl WsPath {
     }
 }
 
+#[cfg(feature = "serde")]
+impl serde::Serialize for WsPath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::core::serialize::bytes::serialize(self.as_bstr(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for WsPath {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        crate::core::serialize::bytes::deserialize::<_, BString>(deserializer)
+            .map(Self::new_unchecked_bytes)
+    }
+}
+
 impl fmt::Display for WsPath {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.as_bstr())
@@ -142,6 +223,27 @@ pub enum NewCanonicalizeError {
     NotInWorkspace(PathBuf),
 }
 
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+/// Path {0:?} is outside the workspace
+pub struct NormalizeError(PathBuf);
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+/// Invalid workspace path {0:?}
+pub struct InvalidPathError(PathBuf);
+
+impl InvalidPathError {
+    pub(crate) fn new(path: WsPath) -> Self {
+        Self(path.0)
+    }
+}
+
+/// For the many places that fail with IO errors for a path
+impl From<InvalidPathError> for std::io::Error {
+    fn from(err: InvalidPathError) -> Self {
+        Self::new(std::io::ErrorKind::InvalidInput, err)
+    }
+}
+
 impl From<WsPath> for PathBuf {
     fn from(path: WsPath) -> Self {
         path.0
@@ -168,7 +270,7 @@ impl From<WsPath> for BString {
 
 impl PartialOrd for WsPath {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.as_bstr().partial_cmp(other.as_bstr())
+        Some(self.cmp(other))
     }
 }
 
@@ -223,7 +325,7 @@ impl<'p> Parents<'p> {
     }
 }
 
-impl<'p> Iterator for Parents<'p> {
+impl Iterator for Parents<'_> {
     type Item = WsPath;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -261,4 +363,44 @@ mod tests {
         ];
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn new_normalized() -> eyre::Result<()> {
+        assert_eq!(WsPath::root(), WsPath::new_normalized(".")?);
+        assert_eq!(
+            WsPath::new_unchecked("a/c"),
+            WsPath::new_normalized("./a/b/../c/")?
+        );
+        assert!(WsPath::new_normalized("a/../..").is_err());
+        assert!(WsPath::new_normalized("/a").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn checked() {
+        assert!(WsPath::new_checked_bytes("a/b.txt").is_ok());
+        assert!(WsPath::new_checked_bytes("").is_ok());
+        for path in ["../a", "a/../b", "a/./b", "/etc/passwd", "a//b", "a/"] {
+            assert!(WsPath::new_checked_bytes(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn file_name() {
+        assert_eq!(
+            Some(b"c.txt".as_bstr()),
+            WsPath::new_unchecked("a/b/c.txt").file_name()
+        );
+        assert_eq!(None, WsPath::root().file_name());
+    }
+
+    #[test]
+    fn is_within() {
+        let path = WsPath::new_unchecked("src/core/mod.rs");
+        assert!(path.is_within(&WsPath::root()));
+        assert!(path.is_within(&WsPath::new_unchecked("src")));
+        assert!(path.is_within(&path));
+        assert!(!path.is_within(&WsPath::new_unchecked("sr")));
+        assert!(!path.is_within(&WsPath::new_unchecked("src/core/mod.rs/x")));
+    }
 }
@@ -0,0 +1,356 @@
+//! Changing many paths in the workspace so that either every change is made
+//! or none are. Contents are written to a scratch directory first, and only
+//! renamed into place once they've all been written. Anything replaced or
+//! removed is moved aside rather than deleted, so that it can be put back if
+//! a later change fails.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use tempfile::TempDir;
+use tracing::{debug, warn};
+
+use super::{Attributes, WriteFileError, WriteFromGitError};
+use crate::core::{platform, stat::Mode, Workspace, WsPath};
+
+pub struct Transaction<'w> {
+    workspace: &'w Workspace,
+    /// Within `.git`, so that it's on the same filesystem as the workspace
+    scratch: TempDir,
+    removals: Vec<WsPath>,
+    writes: Vec<(WsPath, Staged)>,
+    scratch_files: usize,
+}
+
+#[derive(Debug)]
+enum Staged {
+    /// Ready to be renamed into place
+    Entry(PathBuf),
+    Gitlink,
+}
+
+/// How to revert a change made by [`Transaction::commit`]
+#[derive(Debug)]
+enum Undo {
+    /// Move what was at the path back from the scratch directory
+    Restore(WsPath, PathBuf),
+    /// Remove what we put at the path
+    Remove(WsPath),
+    /// Recreate an empty directory we removed
+    CreateDir(WsPath),
+}
+
+impl Workspace {
+    pub fn transaction(&self) -> Result<Transaction<'_>, ScratchError> {
+        // Where a submodule's `.git` file is instead, it's in the workspace
+        let git_dir = self.path.join(".git");
+        let dir = if git_dir.is_dir() {
+            git_dir
+        } else {
+            self.path.clone()
+        };
+        let scratch = tempfile::Builder::new()
+            .prefix("writ-transaction")
+            .tempdir_in(&dir)
+            .map_err(|e| ScratchError(dir, e))?;
+        Ok(Transaction {
+            workspace: self,
+            scratch,
+            removals: Vec::new(),
+            writes: Vec::new(),
+            scratch_files: 0,
+        })
+    }
+}
+
+impl Transaction<'_> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    /// Like [`Workspace::remove_entry`]. Removals are made in the order
+    /// they're given, before any writes.
+    pub fn remove(&mut self, path: WsPath) {
+        self.removals.push(path);
+    }
+
+    /// Like [`Workspace::write_from_git`], but only written to the scratch
+    /// directory for now. Writes are made in the order they're given.
+    pub fn write_from_git(
+        &mut self,
+        path: WsPath,
+        mode: Mode,
+        data: &[u8],
+        attrs: &Attributes,
+    ) -> Result<(), WriteFromGitError> {
+        let data = self.workspace.to_worktree(&path, mode, data, attrs)?;
+        let err = |e| WriteFileError(path.clone(), e);
+
+        let staged = if mode == Mode::Gitlink {
+            Staged::Gitlink
+        } else {
+            let scratch = self.scratch_path("new");
+            if mode == Mode::Symlink && self.workspace.symlinks {
+                platform::symlink(&data, &scratch).map_err(err)?;
+            } else {
+                fs::write(&scratch, &data).map_err(err)?;
+                platform::set_executable(&scratch, mode == Mode::Executable).map_err(err)?;
+            }
+            Staged::Entry(scratch)
+        };
+        self.writes.push((path, staged));
+        Ok(())
+    }
+
+    /// Like [`Self::write_from_git`], but where the contents don't need to be
+    /// converted they're copied to the scratch directory a chunk at a time,
+    /// so that large files needn't fit in memory.
+    pub fn write_from_git_reader(
+        &mut self,
+        path: WsPath,
+        mode: Mode,
+        mut data: impl Read,
+        attrs: &Attributes,
+    ) -> Result<(), WriteFromGitError> {
+        let read_err = |e| WriteFromGitError::Read(path.clone(), e);
+        if matches!(mode, Mode::Symlink | Mode::Gitlink)
+            || self.workspace.converts_to_worktree(&path, mode, attrs)
+        {
+            let mut buf = Vec::new();
+            data.read_to_end(&mut buf).map_err(read_err)?;
+            return self.write_from_git(path, mode, &buf, attrs);
+        }
+
+        let err = |e| WriteFileError(path.clone(), e);
+        let scratch = self.scratch_path("new");
+        let mut file = File::create(&scratch).map_err(err)?;
+        let mut chunk = vec![0; Self::CHUNK_SIZE];
+        loop {
+            let len = match data.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(read_err(e)),
+            };
+            file.write_all(&chunk[..len]).map_err(err)?;
+        }
+        drop(file);
+        platform::set_executable(&scratch, mode == Mode::Executable).map_err(err)?;
+
+        self.writes.push((path, Staged::Entry(scratch)));
+        Ok(())
+    }
+
+    /// If anything fails, whatever was already done is undone (as far as
+    /// possible) before returning the error.
+    pub fn commit(mut self) -> Result<(), WriteFileError> {
+        let mut done = Vec::new();
+        let result = self.apply(&mut done);
+        if result.is_err() {
+            debug!("Rolling back {} changes", done.len());
+            for undo in done.into_iter().rev() {
+                self.undo(&undo);
+            }
+        }
+        result
+    }
+
+    fn apply(&mut self, done: &mut Vec<Undo>) -> Result<(), WriteFileError> {
+        let work = self.workspace;
+
+        for path in std::mem::take(&mut self.removals) {
+            let err = |e| WriteFileError(path.clone(), e);
+            let abs_path = path.to_absolute(work).map_err(|e| err(e.into()))?;
+            match abs_path.symlink_metadata() {
+                Ok(meta) if meta.is_dir() => {
+                    // Otherwise it's a populated submodule, which we leave alone
+                    if fs::remove_dir(&abs_path).is_ok() {
+                        done.push(Undo::CreateDir(path.clone()));
+                    }
+                }
+                Ok(_) => {
+                    let backup = self.move_aside(&abs_path).map_err(err)?;
+                    done.push(Undo::Restore(path.clone(), backup));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(err(e)),
+            }
+            // Restoring a file recreates its parents, so this needn't be undone
+            work.remove_empty_parents(&path);
+        }
+
+        for (path, staged) in std::mem::take(&mut self.writes) {
+            let err = |e| WriteFileError(path.clone(), e);
+            let abs_path = path.to_absolute(work).map_err(|e| err(e.into()))?;
+            if let Some(parent) = abs_path.parent() {
+                fs::create_dir_all(parent).map_err(err)?;
+            }
+
+            match abs_path.symlink_metadata() {
+                Ok(meta) if meta.is_dir() && matches!(staged, Staged::Gitlink) => continue,
+                Ok(_) => {
+                    let backup = self.move_aside(&abs_path).map_err(err)?;
+                    done.push(Undo::Restore(path.clone(), backup));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(err(e)),
+            }
+
+            match staged {
+                Staged::Entry(scratch) => fs::rename(scratch, &abs_path).map_err(err)?,
+                Staged::Gitlink => fs::create_dir(&abs_path).map_err(err)?,
+            }
+            done.push(Undo::Remove(path));
+        }
+
+        Ok(())
+    }
+
+    fn undo(&self, undo: &Undo) {
+        if let Err(err) = self.try_undo(undo) {
+            warn!(%err, "Failed to roll back {undo:?}");
+        }
+    }
+
+    fn try_undo(&self, undo: &Undo) -> io::Result<()> {
+        let work = self.workspace;
+        match undo {
+            Undo::Restore(path, backup) => {
+                let abs_path = path.to_absolute(work)?;
+                if let Some(parent) = abs_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(backup, &abs_path)
+            }
+            Undo::Remove(path) => {
+                let abs_path = path.to_absolute(work)?;
+                let removed = match abs_path.symlink_metadata() {
+                    Ok(meta) if meta.is_dir() => fs::remove_dir(&abs_path),
+                    Ok(_) => fs::remove_file(&abs_path),
+                    Err(e) => Err(e),
+                };
+                work.remove_empty_parents(path);
+                removed
+            }
+            Undo::CreateDir(path) => fs::create_dir_all(path.to_absolute(work)?),
+        }
+    }
+
+    fn move_aside(&mut self, abs_path: &Path) -> io::Result<PathBuf> {
+        let backup = self.scratch_path("old");
+        fs::rename(abs_path, &backup)?;
+        Ok(backup)
+    }
+
+    fn scratch_path(&mut self, prefix: &str) -> PathBuf {
+        self.scratch_files += 1;
+        self.scratch
+            .path()
+            .join(format!("{prefix}-{}", self.scratch_files))
+    }
+}
+
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+/// Failed to create scratch directory in {0:?}
+pub struct ScratchError(PathBuf, #[source] io::Error);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn write(work: &Workspace, path: &str, data: &str) -> eyre::Result<()> {
+        let path = work.path().join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    fn read(work: &Workspace, path: &str) -> Option<String> {
+        fs::read_to_string(work.path().join(path)).ok()
+    }
+
+    #[test]
+    fn makes_every_change() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let work = Workspace::new(dir.path());
+        fs::create_dir(dir.path().join(".git"))?;
+        write(&work, "a.txt", "old")?;
+        write(&work, "dir/b.txt", "b")?;
+        let attrs = Attributes::new(dir.path().join(".git"))?;
+
+        let mut transaction = work.transaction()?;
+        transaction.remove(WsPath::new_unchecked("dir/b.txt"));
+        transaction.write_from_git(
+            WsPath::new_unchecked("a.txt"),
+            Mode::Regular,
+            b"new",
+            &attrs,
+        )?;
+        transaction.write_from_git(WsPath::new_unchecked("dir"), Mode::Regular, b"dir", &attrs)?;
+        assert_eq!(Some("old".to_owned()), read(&work, "a.txt"));
+        transaction.commit()?;
+
+        assert_eq!(Some("new".to_owned()), read(&work, "a.txt"));
+        assert_eq!(Some("dir".to_owned()), read(&work, "dir"));
+        assert_eq!(0, fs::read_dir(dir.path().join(".git"))?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn streams_unconverted_contents() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let work = Workspace::new(dir.path());
+        fs::create_dir(dir.path().join(".git"))?;
+        fs::create_dir(dir.path().join(".git/info"))?;
+        fs::write(dir.path().join(".git/info/attributes"), "*.txt eol=crlf\n")?;
+        let mut attrs = Attributes::new(dir.path().join(".git"))?;
+        let big = WsPath::new_unchecked("big.bin");
+        let text = WsPath::new_unchecked("a.txt");
+        attrs.load_parents(&work, &big)?;
+
+        let contents = "line\n".repeat(50_000);
+        let mut transaction = work.transaction()?;
+        transaction.write_from_git_reader(big, Mode::Regular, contents.as_bytes(), &attrs)?;
+        transaction.write_from_git_reader(text, Mode::Regular, &b"a\nb\n"[..], &attrs)?;
+        transaction.commit()?;
+
+        assert_eq!(Some(contents), read(&work, "big.bin"));
+        assert_eq!(Some("a\r\nb\r\n".to_owned()), read(&work, "a.txt"));
+        Ok(())
+    }
+
+    #[test]
+    fn rolls_back_on_failure() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        let work = Workspace::new(dir.path());
+        fs::create_dir(dir.path().join(".git"))?;
+        write(&work, "a.txt", "old")?;
+        write(&work, "gone/b.txt", "b")?;
+        write(&work, "file", "in the way")?;
+        let attrs = Attributes::new(dir.path().join(".git"))?;
+
+        let mut transaction = work.transaction()?;
+        transaction.remove(WsPath::new_unchecked("gone/b.txt"));
+        transaction.write_from_git(
+            WsPath::new_unchecked("a.txt"),
+            Mode::Regular,
+            b"new",
+            &attrs,
+        )?;
+        transaction.write_from_git(WsPath::new_unchecked("c.txt"), Mode::Regular, b"c", &attrs)?;
+        // Fails, as the parent is a file
+        transaction.write_from_git(WsPath::new_unchecked("file/d"), Mode::Regular, b"d", &attrs)?;
+        assert!(transaction.commit().is_err());
+
+        assert_eq!(Some("old".to_owned()), read(&work, "a.txt"));
+        assert_eq!(Some("b".to_owned()), read(&work, "gone/b.txt"));
+        assert_eq!(None, read(&work, "c.txt"));
+        assert_eq!(Some("in the way".to_owned()), read(&work, "file"));
+        // The scratch directory is cleaned up
+        assert_eq!(0, fs::read_dir(dir.path().join(".git"))?.count());
+        Ok(())
+    }
+}
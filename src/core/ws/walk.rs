@@ -0,0 +1,321 @@
+//! Walking the workspace, skipping ignored directories and directories no
+//! pathspec can match without reading them.
+
+use std::path::PathBuf;
+
+use rayon::prelude::*;
+use tracing::{instrument, trace};
+
+use super::{IgnoreRules, ListFilesError, Workspace, WsPath};
+
+/// What [`Workspace::walk`] finds
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Walked {
+    /// A file, symlink, or directory containing a `.git` (a potential
+    /// gitlink)
+    File(WsPath),
+    /// An ignored file, or an ignored directory (which isn't descended into)
+    Ignored(WsPath),
+}
+
+/// Files found by [`Workspace::list_files_under`]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Listing {
+    pub files: Vec<WsPath>,
+    /// Ignored files, and ignored directories (which aren't descended into)
+    pub ignored: Vec<WsPath>,
+}
+
+/// See [`Workspace::walk`]
+#[derive(Debug)]
+pub struct Walk<'w> {
+    workspace: &'w Workspace,
+    pathspecs: Vec<WsPath>,
+    ignores: &'w mut IgnoreRules,
+    /// Directories still to read
+    pending: Vec<Dir>,
+    /// Found in the last directory read, but not yet returned
+    found: Vec<Walked>,
+}
+
+#[derive(Debug, Clone)]
+struct Dir {
+    path: WsPath,
+    /// Where the directory is on disk, which may not be the same as the path
+    /// if it was precomposed
+    abs_path: PathBuf,
+}
+
+enum Step {
+    Found(Walked),
+    Descend(Dir),
+}
+
+impl Listing {
+    fn extend(&mut self, other: Self) {
+        self.files.extend(other.files);
+        self.ignored.extend(other.ignored);
+    }
+
+    fn push(&mut self, walked: Walked) {
+        match walked {
+            Walked::File(path) => self.files.push(path),
+            Walked::Ignored(path) => self.ignored.push(path),
+        }
+    }
+}
+
+impl Workspace {
+    /// Everything within the pathspecs, in no particular order. Only
+    /// directories that are, contain, or are within a pathspec are read, and
+    /// ignored directories aren't descended into. Pathspecs that don't exist
+    /// are skipped rather than treated as errors, as they may have been
+    /// deleted.
+    ///
+    /// Symlinks are listed rather than followed.
+    pub fn walk<'w>(&'w self, pathspecs: &[WsPath], ignores: &'w mut IgnoreRules) -> Walk<'w> {
+        Walk {
+            workspace: self,
+            pathspecs: WsPath::minimal_prefixes(pathspecs)
+                .into_iter()
+                .cloned()
+                .collect(),
+            ignores,
+            pending: vec![self.root_dir()],
+            found: Vec::new(),
+        }
+    }
+
+    /// Like [`Self::walk`], but sorted and with directories read in parallel
+    #[instrument(err, skip(ignores))]
+    pub fn list_files_under(
+        &self,
+        pathspecs: &[WsPath],
+        ignores: &mut IgnoreRules,
+    ) -> Result<Listing, ListFilesError> {
+        let pathspecs = WsPath::minimal_prefixes(pathspecs)
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut listing = if pathspecs.is_empty() {
+            Listing::default()
+        } else {
+            self.walk_par(&self.root_dir(), &pathspecs, ignores.clone())?
+        };
+        listing.files.sort();
+        listing.ignored.sort();
+        Ok(listing)
+    }
+
+    fn root_dir(&self) -> Dir {
+        Dir {
+            path: WsPath::root(),
+            abs_path: self.path.clone(),
+        }
+    }
+
+    /// Each branch gets its own copy of the ignore rules
+    fn walk_par(
+        &self,
+        dir: &Dir,
+        pathspecs: &[WsPath],
+        mut ignores: IgnoreRules,
+    ) -> Result<Listing, ListFilesError> {
+        let steps = self.read_walked_dir(dir, pathspecs, &mut ignores)?;
+        steps
+            .into_par_iter()
+            .map(|step| match step {
+                Step::Found(walked) => {
+                    let mut listing = Listing::default();
+                    listing.push(walked);
+                    Ok(listing)
+                }
+                Step::Descend(dir) => self.walk_par(&dir, pathspecs, ignores.clone()),
+            })
+            .try_reduce(Listing::default, |mut acc, listing| {
+                acc.extend(listing);
+                Ok(acc)
+            })
+    }
+
+    /// What to do with each child of a directory that isn't ignored and is
+    /// within or contains a pathspec. The rules of the directory are loaded.
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(
+            level = "trace",
+            skip(self, dir, pathspecs, ignores),
+            fields(dir = %dir.path)
+        )
+    )]
+    fn read_walked_dir(
+        &self,
+        dir: &Dir,
+        pathspecs: &[WsPath],
+        ignores: &mut IgnoreRules,
+    ) -> Result<Vec<Step>, ListFilesError> {
+        ignores.load_dir(self, &dir.path)?;
+
+        let mut entries = match dir.abs_path.read_dir() {
+            Ok(entries) => entries
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| ListFilesError::ReadDirEntry(dir.abs_path.clone(), e))?,
+            // Deleted since we saw it
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(ListFilesError::ReadDir(dir.abs_path.clone(), err)),
+        };
+        entries.sort_by_key(std::fs::DirEntry::file_name);
+
+        let mut steps = Vec::new();
+        for entry in entries {
+            let path = dir.path.join(self.precompose(&entry.file_name()));
+            if Self::is_ignored(path.as_path()) {
+                continue;
+            }
+            let in_spec = pathspecs.iter().any(|spec| path.is_within(spec));
+            let above_spec = pathspecs.iter().any(|spec| spec.is_within(&path));
+            if !in_spec && !above_spec {
+                continue;
+            }
+
+            let file_type = entry
+                .file_type()
+                .map_err(|e| ListFilesError::GetMetadata(entry.path(), e))?;
+            let is_dir = file_type.is_dir();
+
+            if ignores.is_ignored_loaded(&path, is_dir) {
+                if in_spec {
+                    steps.push(Step::Found(Walked::Ignored(path)));
+                } else {
+                    // The pathspecs within are ignored along with it
+                    for spec in pathspecs.iter().filter(|spec| spec.is_within(&path)) {
+                        let exists = spec
+                            .to_absolute(self)
+                            .is_ok_and(|abs| abs.symlink_metadata().is_ok());
+                        if exists {
+                            steps.push(Step::Found(Walked::Ignored(spec.clone())));
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let abs_path = entry.path();
+            if is_dir && !abs_path.join(".git").exists() {
+                steps.push(Step::Descend(Dir { path, abs_path }));
+            } else if in_spec {
+                if !is_dir && !file_type.is_file() && !file_type.is_symlink() {
+                    return Err(ListFilesError::InvalidFileType(path.into()));
+                }
+                steps.push(Step::Found(Walked::File(path)));
+            }
+        }
+        trace!(steps = steps.len(), "Read directory");
+        Ok(steps)
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = Result<Walked, ListFilesError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pathspecs.is_empty() {
+            return None;
+        }
+        loop {
+            if let Some(walked) = self.found.pop() {
+                return Some(Ok(walked));
+            }
+            let dir = self.pending.pop()?;
+            let steps = match self
+                .workspace
+                .read_walked_dir(&dir, &self.pathspecs, self.ignores)
+            {
+                Ok(steps) => steps,
+                Err(err) => return Some(Err(err)),
+            };
+            // Reversed so that we pop them in order
+            for step in steps.into_iter().rev() {
+                match step {
+                    Step::Found(walked) => self.found.push(walked),
+                    Step::Descend(dir) => self.pending.push(dir),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::tempdir;
+
+    fn paths(specs: &[&str]) -> Vec<WsPath> {
+        specs.iter().copied().map(WsPath::new_unchecked).collect()
+    }
+
+    #[test]
+    fn prunes_ignored_and_unmatched_dirs() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        for file in &[
+            ".gitignore",
+            "a/1.txt",
+            "a/b/2.txt",
+            "a/target/3.txt",
+            "a/c.log",
+            "other/4.txt",
+        ] {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, "")?;
+        }
+        std::fs::write(dir.path().join(".gitignore"), "target/\n*.log\n")?;
+        std::fs::create_dir_all(dir.path().join(".git"))?;
+
+        let work = Workspace::new(dir.path());
+        let mut ignores = IgnoreRules::new(dir.path().join(".git"))?;
+        let mut walked = work
+            .walk(&paths(&["a", "missing"]), &mut ignores)
+            .collect::<Result<Vec<_>, _>>()?;
+        walked.sort_by(|a, b| format!("{a:?}").cmp(&format!("{b:?}")));
+
+        assert_eq!(
+            vec![
+                Walked::File(WsPath::new_unchecked("a/1.txt")),
+                Walked::File(WsPath::new_unchecked("a/b/2.txt")),
+                Walked::Ignored(WsPath::new_unchecked("a/c.log")),
+                Walked::Ignored(WsPath::new_unchecked("a/target")),
+            ],
+            walked
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn walk_matches_listing() -> eyre::Result<()> {
+        let dir = tempdir()?;
+        for file in &["a/1.txt", "a/b/2.txt", "c/3.txt", "x.txt", "target/y"] {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, "")?;
+        }
+        std::fs::write(dir.path().join(".gitignore"), "target\n")?;
+
+        let work = Workspace::new(dir.path());
+        let specs = paths(&["a/b", "c/3.txt", "target/y", "x.txt"]);
+        let mut ignores = IgnoreRules::new(dir.path().join(".git"))?;
+        let listing = work.list_files_under(&specs, &mut ignores)?;
+
+        let mut walked = Listing::default();
+        for found in work.walk(&specs, &mut ignores) {
+            walked.push(found?);
+        }
+        walked.files.sort();
+
+        assert_eq!(paths(&["a/b/2.txt", "c/3.txt", "x.txt"]), listing.files);
+        assert_eq!(paths(&["target/y"]), listing.ignored);
+        assert_eq!(listing, walked);
+        Ok(())
+    }
+}
@@ -0,0 +1,573 @@
+//! One error type for all of [`crate::core`], for applications that would
+//! rather handle kinds of failures than each module's errors
+
+use std::{error::Error as StdError, fmt, io};
+
+use crate::core::{
+    cancel, check_attr, check_ignore, clone, commit_msg, config,
+    db::{self, commit, object, signature, tree, Blob, Commit, Object, Tree},
+    discover, fast_export, fast_import, fetch, hook, index, locked_file, ls_files, ls_tree,
+    mailbox, maintenance, merge, migration, negotiate, notes, pack, patch_id, pathspec, push, refs,
+    refspec, replace, repo, rerere, rev_parse, revwalk, sequencer, serve, sparse, submodule,
+    transport::{self, pkt_line, receive_pack, upload_pack},
+    update_index, verify, ws,
+};
+
+/// Any error from [`crate::core`], displayed as the error it came from
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    inner: Box<dyn StdError + Send + Sync>,
+}
+
+/// What went wrong, which stays the same for a failure across versions even
+/// as the errors of modules change. More kinds may be added.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// An object, ref, file or repository doesn't exist
+    NotFound,
+    /// What would be created already exists
+    AlreadyExists,
+    /// Local changes or unmerged paths are in the way, or a ref isn't what
+    /// it was expected to be
+    Conflict,
+    /// Another process holds a lock
+    Locked,
+    /// Something in the repository is malformed
+    Corrupt,
+    /// HEAD is on a branch without commits
+    Unborn,
+    /// The repository has no workspace
+    Bare,
+    /// Refused by a hook, the remote, or a safety check
+    Rejected,
+    /// Config is malformed or missing values
+    Config,
+    /// A name, path or option given is invalid
+    InvalidInput,
+    /// Not supported by us or the remote
+    Unsupported,
+    /// Talking to the remote failed
+    Remote,
+    /// Stopped by a [`crate::core::CancelToken`]
+    Cancelled,
+    PermissionDenied,
+    /// Any other IO error
+    Io,
+    Other,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The error this came from
+    pub fn get_ref(&self) -> &(dyn StdError + Send + Sync + 'static) {
+        &*self.inner
+    }
+
+    pub fn downcast_ref<E: StdError + 'static>(&self) -> Option<&E> {
+        self.inner.downcast_ref()
+    }
+
+    pub fn into_inner(self) -> Box<dyn StdError + Send + Sync> {
+        self.inner
+    }
+
+    fn new(inner: impl StdError + Send + Sync + 'static) -> Self {
+        Self {
+            kind: ErrorKind::of(&inner),
+            inner: Box::new(inner),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.inner.source()
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotFound => "not found",
+            Self::AlreadyExists => "already exists",
+            Self::Conflict => "conflict",
+            Self::Locked => "locked",
+            Self::Corrupt => "corrupt",
+            Self::Unborn => "unborn branch",
+            Self::Bare => "bare repository",
+            Self::Rejected => "rejected",
+            Self::Config => "invalid config",
+            Self::InvalidInput => "invalid input",
+            Self::Unsupported => "unsupported",
+            Self::Remote => "remote error",
+            Self::Cancelled => "cancelled",
+            Self::PermissionDenied => "permission denied",
+            Self::Io => "IO error",
+            Self::Other => "other error",
+        })
+    }
+}
+
+/// Returns the kind of the error if it's one of the types, by the first of
+/// its patterns that matches
+macro_rules! kinds {
+    ($err:ident, $($ty:ty { $($($pat:pat)|+ $(if $guard:expr)? => $kind:ident),+ $(,)? })*) => {
+        $(
+            if let Some(err) = $err.downcast_ref::<$ty>() {
+                return match err {
+                    $($($pat)|+ $(if $guard)? => Some(Self::$kind),)+
+                    #[allow(unreachable_patterns)]
+                    _ => None,
+                };
+            }
+        )*
+    };
+}
+
+impl ErrorKind {
+    /// The kind of the first error in the chain of sources that has one
+    fn of(err: &(dyn StdError + 'static)) -> Self {
+        let mut next = Some(err);
+        while let Some(err) = next {
+            if let Some(kind) = Self::of_one(err) {
+                return kind;
+            }
+            next = err.source();
+        }
+        Self::Other
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn of_one(err: &(dyn StdError + 'static)) -> Option<Self> {
+        if let Some(kind) = Self::of_objects::<Blob>(err)
+            .or_else(|| Self::of_objects::<Tree>(err))
+            .or_else(|| Self::of_objects::<Commit>(err))
+        {
+            return Some(kind);
+        }
+
+        kinds! { err,
+            io::Error {
+                e if e.kind() == io::ErrorKind::NotFound => NotFound,
+                e if e.kind() == io::ErrorKind::AlreadyExists => AlreadyExists,
+                e if e.kind() == io::ErrorKind::PermissionDenied => PermissionDenied,
+                _ => Io,
+            }
+            locked_file::Error {
+                locked_file::Error::Contested(_) => Locked,
+                locked_file::Error::NotFound(_) => NotFound,
+            }
+            cancel::Cancelled { _ => Cancelled }
+            config::ParseError { _ => Config }
+            config::ValueError { _ => Config }
+            config::EditError { config::EditError::InvalidName(_) => InvalidInput }
+            repo::InitError {
+                repo::InitError::Exists(_) => AlreadyExists,
+                repo::InitError::InvalidBranch(_) | repo::InitError::BareSeparate => InvalidInput,
+                repo::InitError::UnsupportedObjectFormat(_) => Unsupported,
+            }
+            repo::AddError { repo::AddError::NoMatch(_) => NotFound }
+            repo::BareError { _ => Bare }
+            repo::ReadError {
+                repo::ReadError::NotRepo(_) => NotFound,
+                repo::ReadError::GitFile(_) => Corrupt,
+                repo::ReadError::Unsafe(_) => Rejected,
+            }
+            discover::DiscoverError {
+                discover::DiscoverError::NotFound(_)
+                | discover::DiscoverError::FilesystemBoundary { .. } => NotFound,
+            }
+            repo::CommitError {
+                repo::CommitError::EmptyMessage => InvalidInput,
+                repo::CommitError::Unmerged => Conflict,
+            }
+            repo::CheckoutError { repo::CheckoutError::Unmerged => Conflict }
+            merge::MergeError {
+                merge::MergeError::NoHead => Unborn,
+                merge::MergeError::Unmerged
+                | merge::MergeError::Staged
+                | merge::MergeError::InProgress => Conflict,
+            }
+            sequencer::SequencerError {
+                sequencer::SequencerError::NoHead => Unborn,
+                sequencer::SequencerError::InProgress
+                | sequencer::SequencerError::Unmerged
+                | sequencer::SequencerError::Staged => Conflict,
+                sequencer::SequencerError::NotInProgress => NotFound,
+                sequencer::SequencerError::Merge(_) => Unsupported,
+                sequencer::SequencerError::Corrupt(_) => Corrupt,
+            }
+            commit_msg::EditorError {
+                commit_msg::EditorError::NoEditor => Config,
+                commit_msg::EditorError::Failed(_) => Rejected,
+            }
+            migration::CheckError { migration::CheckError::Clobbered(_) => Conflict }
+            hook::HookError { hook::HookError::Failed(..) => Rejected }
+            hook::Rejected { _ => Rejected }
+            ws::eol::UnsafeError { _ => Rejected }
+            ws::filter::FilterError { ws::filter::FilterError::Unsupported(_) => Unsupported }
+            ws::path::NormalizeError { _ => InvalidInput }
+            ws::path::InvalidPathError { _ => InvalidInput }
+            object::CollisionError { _ => Rejected }
+            ws::path::NewCanonicalizeError {
+                ws::path::NewCanonicalizeError::NotInWorkspace(_) => InvalidInput,
+            }
+            ws::ListFilesError {
+                ws::ListFilesError::InvalidFileType(_) => Unsupported,
+                ws::ListFilesError::OutsideOfWorkspace(_) => InvalidInput,
+            }
+            refs::ReadError {
+                refs::ReadError::Parse(..) | refs::ReadError::Cycle(_) => Corrupt,
+            }
+            refs::TransactionError {
+                refs::TransactionError::Duplicate(_) => InvalidInput,
+                refs::TransactionError::Stale(_) => Conflict,
+            }
+            refspec::ParseError { _ => InvalidInput }
+            pathspec::ParseError { _ => InvalidInput }
+            object::ParseOidError { _ => InvalidInput }
+            object::ParseSizedOidError { _ => InvalidInput }
+            commit::DeserializeError { _ => Corrupt }
+            tree::DeserializeError { _ => Corrupt }
+            signature::ParseError { _ => Corrupt }
+            signature::IdentityError {
+                signature::IdentityError::MissingName(_)
+                | signature::IdentityError::MissingEmail(_) => Config,
+                signature::IdentityError::InvalidDate(..) => InvalidInput,
+            }
+            db::batch::BatchError { db::batch::BatchError::Corrupt(_) => Corrupt }
+            db::hash_object::HashObjectError {
+                db::hash_object::HashObjectError::UnknownType(_)
+                | db::hash_object::HashObjectError::InvalidCommit(_)
+                | db::hash_object::HashObjectError::InvalidTree(_)
+                | db::hash_object::HashObjectError::InvalidTag(_) => InvalidInput,
+            }
+            db::LoadRawError { db::LoadRawError::Corrupt(_) => Corrupt }
+            db::ShallowError { db::ShallowError::Parse(_) => Corrupt }
+            index::LoadError { index::LoadError::UnsupportedVersion(_) => Unsupported }
+            index::CorruptError {
+                index::CorruptError::UnsupportedExtension(_) => Unsupported,
+                _ => Corrupt,
+            }
+            index::NonexistentEntryError { _ => NotFound }
+            pack::index::IndexError {
+                pack::index::IndexError::UnsupportedVersion(_) => Unsupported,
+                _ => Corrupt,
+            }
+            pack::file::OpenPackError { pack::file::OpenPackError::Mismatch(_) => Corrupt }
+            pack::UnpackError {
+                pack::UnpackError::UnsupportedVersion(_) => Unsupported,
+                pack::UnpackError::Truncated
+                | pack::UnpackError::ChecksumMismatch
+                | pack::UnpackError::InvalidSignature
+                | pack::UnpackError::CorruptObject(_)
+                | pack::UnpackError::InvalidDelta(_)
+                | pack::UnpackError::MissingBase(_) => Corrupt,
+            }
+            pack::WriteError { pack::WriteError::NotFound(_) => NotFound }
+            pack::verify::VerifyPackError {
+                pack::verify::VerifyPackError::Mismatch(_)
+                | pack::verify::VerifyPackError::Count { .. }
+                | pack::verify::VerifyPackError::WrongOffset(..)
+                | pack::verify::VerifyPackError::Crc(_) => Corrupt,
+            }
+            sparse::ParseError { _ => Corrupt }
+            update_index::ParseCacheInfoError { _ => InvalidInput }
+            rev_parse::RevParseError {
+                rev_parse::RevParseError::Invalid(_)
+                | rev_parse::RevParseError::NoParent(_)
+                | rev_parse::RevParseError::WrongType { .. } => InvalidInput,
+                rev_parse::RevParseError::NotFound(_) => NotFound,
+                rev_parse::RevParseError::Corrupt(_) => Corrupt,
+            }
+            revwalk::RevWalkError {
+                revwalk::RevWalkError::Corrupt(_) => Corrupt,
+                revwalk::RevWalkError::Missing(_) => NotFound,
+            }
+            maintenance::MaintenanceError {
+                maintenance::MaintenanceError::Corrupt(_) => Corrupt,
+                maintenance::MaintenanceError::Expiry { .. } => Config,
+            }
+            rerere::RerereError { rerere::RerereError::InvalidMergeRr(_) => Corrupt }
+            replace::ReplaceError {
+                replace::ReplaceError::Exists(_) => AlreadyExists,
+                replace::ReplaceError::Missing(_) | replace::ReplaceError::NotReplaced(_) => NotFound,
+                replace::ReplaceError::Itself(_)
+                | replace::ReplaceError::NotCommit(_)
+                | replace::ReplaceError::WrongType { .. } => InvalidInput,
+                replace::ReplaceError::InvalidRef(_) => Corrupt,
+            }
+            notes::NotesError {
+                notes::NotesError::Exists(_) => AlreadyExists,
+                notes::NotesError::Missing(_) => NotFound,
+            }
+            verify::VerifyError {
+                verify::VerifyError::Missing(_) => NotFound,
+                verify::VerifyError::WrongType { .. } => InvalidInput,
+            }
+            ls_tree::LsTreeError {
+                ls_tree::LsTreeError::Missing(_) => NotFound,
+                ls_tree::LsTreeError::NotTree(_) => InvalidInput,
+                ls_tree::LsTreeError::Corrupt(_) => Corrupt,
+            }
+            submodule::SubmoduleError {
+                submodule::SubmoduleError::NotSubmodule(_) => InvalidInput,
+                submodule::SubmoduleError::NoCommit(_) => NotFound,
+            }
+            fast_export::FastExportError {
+                fast_export::FastExportError::Corrupt(_) => Corrupt,
+            }
+            fast_import::FastImportError {
+                fast_import::FastImportError::Invalid { .. }
+                | fast_import::FastImportError::Signature(..)
+                | fast_import::FastImportError::UnknownMark(_) => InvalidInput,
+                fast_import::FastImportError::Unsupported(_) => Unsupported,
+                fast_import::FastImportError::NotFastForward(_) => Rejected,
+            }
+            mailbox::AmError {
+                mailbox::AmError::Invalid { .. } | mailbox::AmError::Empty(_) => InvalidInput,
+                mailbox::AmError::DoesNotApply { .. }
+                | mailbox::AmError::Unmerged
+                | mailbox::AmError::Staged => Conflict,
+                mailbox::AmError::NoHead => Unborn,
+            }
+            fetch::FetchError {
+                fetch::FetchError::FilterWithoutRemote
+                | fetch::FetchError::InvalidFilter(_)
+                | fetch::FetchError::InvalidTagOpt(_) => InvalidInput,
+                fetch::FetchError::NoPromisor => Config,
+                fetch::FetchError::NotPromised(_) => NotFound,
+            }
+            push::PushError {
+                push::PushError::MissingObject(_) | push::PushError::UnknownRef(_) => NotFound,
+                push::PushError::Corrupt(_) => Corrupt,
+                push::PushError::NoBranch => InvalidInput,
+                push::PushError::Atomic(..) => Rejected,
+            }
+            transport::TransportError {
+                transport::TransportError::UnsupportedUrl(_) => Unsupported,
+                _ => Remote,
+            }
+            upload_pack::UploadPackError {
+                upload_pack::UploadPackError::UnsupportedVersion(_)
+                | upload_pack::UploadPackError::UnsupportedShallow
+                | upload_pack::UploadPackError::UnsupportedFilter => Unsupported,
+                upload_pack::UploadPackError::InvalidRef(_)
+                | upload_pack::UploadPackError::InvalidAck(_)
+                | upload_pack::UploadPackError::InvalidShallow(_)
+                | upload_pack::UploadPackError::NoPack => Remote,
+            }
+            receive_pack::ReceivePackError {
+                receive_pack::ReceivePackError::UnsupportedAtomic => Unsupported,
+                receive_pack::ReceivePackError::InvalidRef(_)
+                | receive_pack::ReceivePackError::InvalidReport(_) => Remote,
+            }
+            pkt_line::ReadError { _ => Remote }
+            serve::ServeError { serve::ServeError::InvalidCommand(_) => Remote }
+        }
+        None
+    }
+
+    fn of_objects<O: Object + 'static>(err: &(dyn StdError + 'static)) -> Option<Self> {
+        kinds! { err,
+            db::LoadError<O> { db::LoadError::Deserialize(..) => Corrupt }
+            db::LoadBytesError<O> {
+                db::LoadBytesError::NotFound(_) => NotFound,
+                db::LoadBytesError::Corrupt(_)
+                | db::LoadBytesError::WrongType { .. }
+                | db::LoadBytesError::ParseLenToBytes(..)
+                | db::LoadBytesError::ParseLenToInt(..) => Corrupt,
+            }
+        }
+        None
+    }
+}
+
+macro_rules! from {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl From<$ty> for Error {
+                fn from(err: $ty) -> Self {
+                    Self::new(err)
+                }
+            }
+        )*
+    };
+}
+
+from! {
+    cancel::Cancelled,
+    check_attr::CheckAttrError,
+    check_ignore::CheckIgnoreError,
+    clone::CloneError,
+    commit_msg::CommitMessageError,
+    commit_msg::EditorError,
+    config::EditError,
+    config::LoadError,
+    config::ParseError,
+    config::SaveError,
+    config::ValueError,
+    commit::DeserializeError,
+    db::batch::BatchError,
+    db::hash_object::HashObjectError,
+    db::LoadRawError,
+    db::ShallowError,
+    db::StoreRawError,
+    discover::DiscoverError,
+    fast_export::FastExportError,
+    fast_import::FastImportError,
+    fetch::FetchError,
+    hook::HookError,
+    hook::Rejected,
+    index::CommitError,
+    index::CorruptError,
+    index::LoadError,
+    index::ModifyError,
+    index::NonexistentEntryError,
+    index::OpenForModificationsError,
+    index::RefreshError,
+    index::entry::IsUnchangedError,
+    locked_file::Error,
+    ls_files::LsFilesError,
+    ls_tree::LsTreeError,
+    mailbox::AmError,
+    mailbox::FormatPatchError,
+    maintenance::MaintenanceError,
+    merge::MergeError,
+    merge::MergeFileError,
+    migration::ApplyError,
+    migration::CheckError,
+    negotiate::NegotiateError,
+    notes::NotesError,
+    object::CollisionError,
+    object::ParseOidError,
+    object::ParseSizedOidError,
+    pack::UnpackError,
+    pack::WriteError,
+    pack::file::LoadPackedError,
+    pack::file::OpenPackError,
+    pack::index::IndexError,
+    pack::verify::VerifyPackError,
+    patch_id::PatchIdError,
+    pathspec::ParseError,
+    pkt_line::ReadError,
+    push::PushError,
+    receive_pack::ReceivePackError,
+    refs::ReadError,
+    refs::TransactionError,
+    refs::UpdateError,
+    refspec::ParseError,
+    replace::ReplaceError,
+    repo::AddError,
+    repo::BareError,
+    repo::CheckoutError,
+    repo::CommitError,
+    repo::ForCurrentDirError,
+    repo::InitError,
+    repo::ReadError,
+    repo::SparseCheckoutError,
+    repo::StatusError,
+    rerere::RerereError,
+    rev_parse::RevParseError,
+    revwalk::RevWalkError,
+    sequencer::SequencerError,
+    serve::ServeError,
+    signature::IdentityError,
+    signature::ParseError,
+    sparse::LoadError,
+    sparse::ParseError,
+    sparse::SaveError,
+    submodule::SubmoduleError,
+    transport::TransportError,
+    tree::DeserializeError,
+    update_index::ParseCacheInfoError,
+    update_index::UpdateIndexError,
+    upload_pack::UploadPackError,
+    verify::VerifyError,
+    ws::ListFilesError,
+    ws::ReadFileError,
+    ws::ReadForGitError,
+    ws::StatFileError,
+    ws::WriteFileError,
+    ws::WriteFromGitError,
+    ws::attributes::LoadError,
+    ws::eol::UnsafeError,
+    ws::filter::FilterError,
+    ws::ignore::LoadError,
+    ws::path::InvalidPathError,
+    ws::path::NewCanonicalizeError,
+    ws::path::NormalizeError,
+    ws::transaction::ScratchError,
+}
+
+#[cfg(feature = "watch")]
+from! { crate::core::watch::Error, repo::StatusCachedError }
+
+impl<O: Object + 'static> From<db::StoreError<O>> for Error
+where
+    db::StoreError<O>: Send + Sync,
+{
+    fn from(err: db::StoreError<O>) -> Self {
+        Self::new(err)
+    }
+}
+
+impl<O: Object + 'static> From<db::LoadError<O>> for Error
+where
+    db::LoadError<O>: Send + Sync,
+{
+    fn from(err: db::LoadError<O>) -> Self {
+        Self::new(err)
+    }
+}
+
+impl<O: Object + 'static> From<db::LoadBytesError<O>> for Error
+where
+    db::LoadBytesError<O>: Send + Sync,
+{
+    fn from(err: db::LoadBytesError<O>) -> Self {
+        Self::new(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn kinds_come_from_the_error() {
+        let err = Error::from(repo::CommitError::EmptyMessage);
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert_eq!("Empty commit message", err.to_string());
+        assert!(err.downcast_ref::<repo::CommitError>().is_some());
+
+        let err = Error::from(merge::MergeError::NoHead);
+        assert_eq!(ErrorKind::Unborn, err.kind());
+
+        let err = Error::from(repo::StatusError::Cancelled(cancel::Cancelled));
+        assert_eq!(ErrorKind::Cancelled, err.kind());
+    }
+
+    #[test]
+    fn kinds_come_from_sources_when_the_error_has_none() {
+        let io_err = io::Error::new(io::ErrorKind::NotFound, "missing");
+        let err = Error::from(locked_file::Error::Io(io_err));
+        assert_eq!(ErrorKind::NotFound, err.kind());
+
+        let io_err = io::Error::other("busy");
+        let err = Error::from(locked_file::Error::Contested(io_err));
+        assert_eq!(ErrorKind::Locked, err.kind());
+    }
+}
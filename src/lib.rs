@@ -1,12 +1,3 @@
-#![feature(
-    path_try_exists,
-    with_options,
-    associated_type_defaults,
-    map_first_last,
-    const_fn,
-    debug_non_exhaustive,
-    format_args_capture
-)]
 // TODO: Warn clippy::cargo
 #![warn(clippy::all, clippy::pedantic)]
 #![allow(
@@ -16,7 +7,10 @@
 )]
 
 pub mod core;
+pub mod error;
 pub mod ui;
 
+pub use error::{Error, ErrorKind};
+
 #[cfg(test)]
 pub use test_support;
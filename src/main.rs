@@ -1,6 +1,7 @@
 use std::{
     borrow::Cow,
     env::{self, VarError},
+    io,
 };
 
 use structopt::StructOpt;
@@ -13,8 +14,10 @@ fn main() -> eyre::Result<()> {
         Err(err) => return Err(err.into()),
     };
     let filter = tracing_subscriber::EnvFilter::try_new(filter)?;
+    // Stdout is spoken over by `receive-pack`
     tracing_subscriber::fmt()
         .with_env_filter(filter)
+        .with_writer(io::stderr)
         .pretty()
         .init();
 
@@ -1,7 +1,4 @@
-use std::{
-    fmt,
-    path::{Path, PathBuf},
-};
+use std::{fmt, io, path::PathBuf};
 
 use console::style;
 use eyre::eyre;
@@ -9,6 +6,7 @@ use structopt::StructOpt;
 use tracing::debug;
 
 use bstr::ByteSlice;
+use chrono::Local;
 
 use crate::core;
 
@@ -17,19 +15,45 @@ pub enum Opt {
     Init {
         #[structopt(default_value = ".")]
         dir: PathBuf,
+        #[structopt(long)]
+        bare: bool,
+        #[structopt(short = "b", long)]
+        initial_branch: Option<String>,
+        #[structopt(long)]
+        template: Option<PathBuf>,
+        #[structopt(long)]
+        separate_git_dir: Option<PathBuf>,
     },
     Add {
-        files: Vec<PathBuf>,
+        /// Pathspecs, like `src`, `*.rs` or `:!vendor`
+        pathspecs: Vec<String>,
     },
     Commit {
-        #[structopt(long)]
-        name: String,
-        #[structopt(long)]
-        email: String,
+        /// Defaults to the author from the environment or config
+        #[structopt(long, requires = "email")]
+        name: Option<String>,
+        #[structopt(long, requires = "name")]
+        email: Option<String>,
         #[structopt(long, short)]
         message: String,
+        /// Skip the pre-commit and commit-msg hooks
+        #[structopt(long)]
+        no_verify: bool,
+        /// Replace the last commit, keeping its author
+        #[structopt(long)]
+        amend: bool,
+    },
+    Status {
+        /// Also list ignored files
+        #[structopt(long)]
+        ignored: bool,
+        pathspecs: Vec<String>,
+    },
+    /// Receive a push on stdin, replying on stdout, for `git push
+    /// --receive-pack`
+    ReceivePack {
+        dir: PathBuf,
     },
-    Status,
     Plumb(PlumbOpt),
 }
 
@@ -59,25 +83,23 @@ impl Ui {
         Ok(Self::new(repo))
     }
 
-    pub fn init(workspace: impl Into<PathBuf>) -> eyre::Result<Self> {
-        let workspace = workspace.into();
-
-        let workspace_name = workspace.clone();
-        let workspace_name = workspace_name.to_string_lossy();
+    pub fn init(builder: core::init::Builder) -> eyre::Result<Self> {
+        let repo = builder.init()?;
 
-        let repo = core::Repo::init(workspace)?;
-
-        println!("Initialized repository in {}", workspace_name);
+        println!(
+            "Initialized repository in {}",
+            repo.git_dir().to_string_lossy()
+        );
 
         Ok(Self::new(repo))
     }
 
-    pub fn add<I, P>(&mut self, files: I) -> eyre::Result<()>
+    pub fn add<I, S>(&mut self, pathspecs: I) -> eyre::Result<()>
     where
-        I: IntoIterator<Item = P> + fmt::Debug,
-        P: AsRef<Path>,
+        I: IntoIterator<Item = S> + fmt::Debug,
+        S: AsRef<str>,
     {
-        let added = self.repo.add(files)?;
+        let added = self.repo.add(pathspecs)?;
 
         if added.is_empty() {
             return Err(eyre!("No files match paths specified"));
@@ -94,24 +116,41 @@ impl Ui {
 
     pub fn commit(
         &mut self,
-        name: impl Into<String> + fmt::Debug,
-        email: impl Into<String> + fmt::Debug,
+        identity: Option<(String, String)>,
         msg: impl Into<String> + fmt::Debug,
+        options: &core::CommitOptions,
     ) -> eyre::Result<()> {
-        self.repo.commit(name, email, msg)?;
+        if let Some((name, email)) = identity {
+            let signature = core::db::Signature::new_local(name, email, Local::now());
+            self.repo
+                .commit_as_with(signature.clone(), signature, msg, options)?;
+        } else {
+            self.repo.commit_with(msg, options)?;
+        }
         println_style!("Committed".green().bold());
         Ok(())
     }
 
-    pub fn status(&mut self) -> eyre::Result<()> {
-        let status = self.repo.status()?;
+    pub fn status(
+        &mut self,
+        pathspecs: &[String],
+        options: &core::StatusOptions,
+    ) -> eyre::Result<()> {
+        let pathspecs = core::Pathspecs::parse(pathspecs)?;
+        let status = self.repo.status_matching(&pathspecs, options)?;
 
         let mut to_commit = Vec::new();
+        let mut unmerged = Vec::new();
         let mut not_staged = Vec::new();
         let mut untracked = Vec::new();
+        let mut ignored = Vec::new();
 
         for (path, status) in status {
-            if status.workspace == core::Status::Untracked {
+            if status.index.is_conflicted() {
+                unmerged.push((path, status.index));
+            } else if status.workspace == core::Status::Ignored {
+                ignored.push(path);
+            } else if status.workspace == core::Status::Untracked {
                 untracked.push(path);
             } else {
                 let index = status.index;
@@ -135,6 +174,15 @@ impl Ui {
             println!();
         }
 
+        if !unmerged.is_empty() {
+            println!("Unmerged paths:");
+            for (path, status) in unmerged {
+                let status = status.name();
+                println_style!("    {status}: {path}".red());
+            }
+            println!();
+        }
+
         if !not_staged.is_empty() {
             println!("Changes not staged for commit:");
             for (path, status) in not_staged {
@@ -152,6 +200,14 @@ impl Ui {
             println!();
         }
 
+        if !ignored.is_empty() {
+            println!("Ignored files:");
+            for path in ignored {
+                println_style!("    {path}".red());
+            }
+            println!();
+        }
+
         Ok(())
     }
 
@@ -162,7 +218,7 @@ impl Ui {
             .head()?
             .ok_or_else(|| eyre::eyre!("No HEAD"))?;
         let commit = self.repo.db.load::<core::db::Commit>(head)?;
-        eprintln!("HEAD: {}\n", head);
+        eprintln!("HEAD: {head}\n");
         self.plumb_print_tree(commit.tree, 0)?;
         Ok(())
     }
@@ -177,10 +233,10 @@ impl Ui {
         for node in tree.direct_children() {
             match node {
                 core::db::tree::Node::File(core::db::tree::FileNode { name, mode, oid }) => {
-                    println!("{}{} {} ({:?})", level_prefix, oid, name, mode)
+                    println!("{level_prefix}{oid} {name} ({mode:?})");
                 }
                 core::db::tree::Node::Tree { name, oid } => {
-                    println!("{}{} {}/", level_prefix, oid, name);
+                    println!("{level_prefix}{oid} {name}/");
                     self.plumb_print_tree(*oid, level + 1)?;
                 }
             }
@@ -193,16 +249,51 @@ pub fn run_command(opt: Opt) -> eyre::Result<()> {
     debug!("Got opt {:#?}", opt);
 
     match opt {
-        Opt::Init { dir } => {
-            Ui::init(dir)?;
+        Opt::Init {
+            dir,
+            bare,
+            initial_branch,
+            template,
+            separate_git_dir,
+        } => {
+            let mut builder = core::init::Builder::new(dir).bare(bare);
+            if let Some(branch) = initial_branch {
+                builder = builder.initial_branch(branch);
+            }
+            if let Some(template) = template {
+                builder = builder.template(template);
+            }
+            if let Some(git_dir) = separate_git_dir {
+                builder = builder.separate_git_dir(git_dir);
+            }
+            Ui::init(builder)?;
         }
-        Opt::Add { files } => Ui::for_current_dir()?.add(files)?,
+        Opt::Add { pathspecs } => Ui::for_current_dir()?.add(pathspecs)?,
         Opt::Commit {
             name,
             email,
             message,
-        } => Ui::for_current_dir()?.commit(name, email, message)?,
-        Opt::Status => Ui::for_current_dir()?.status()?,
+            no_verify,
+            amend,
+        } => {
+            let options = core::CommitOptions {
+                no_verify,
+                amend,
+                ..core::CommitOptions::default()
+            };
+            Ui::for_current_dir()?.commit(name.zip(email), message, &options)?;
+        }
+        Opt::Status { ignored, pathspecs } => {
+            let options = core::StatusOptions {
+                ignored,
+                ..core::StatusOptions::default()
+            };
+            Ui::for_current_dir()?.status(&pathspecs, &options)?;
+        }
+        Opt::ReceivePack { dir } => {
+            let repo = core::Repo::new(dir)?;
+            repo.serve_receive_pack(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+        }
         Opt::Plumb(plumb) => run_plumb_command(plumb)?,
     }
 
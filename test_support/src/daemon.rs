@@ -0,0 +1,71 @@
+//! `git daemon` on a local port, so that the `git://` transport can be
+//! tested against real git without the network
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+};
+
+/// Serves the repositories under `root` on a free local port until the
+/// tests exit, returning the url of `root`. Each connection is handed to
+/// `git daemon --inetd`. Pushing is allowed.
+pub fn serve_git_daemon(root: &Path) -> eyre::Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("git://{}", listener.local_addr()?);
+    let root = root.to_owned();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let root = root.clone();
+            thread::spawn(move || {
+                if let Err(err) = stream.map_err(eyre::Report::from).and_then(|s| handle(s, &root)) {
+                    eprintln!("git daemon connection failed: {err:?}");
+                }
+            });
+        }
+    });
+    Ok(url)
+}
+
+fn handle(stream: TcpStream, root: &Path) -> eyre::Result<()> {
+    let mut base_path = std::ffi::OsString::from("--base-path=");
+    base_path.push(root);
+    let mut child = Command::new("git")
+        .args(["daemon", "--inetd", "--export-all", "--enable=receive-pack"])
+        .arg(base_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("Piped");
+    let mut stdout = child.stdout.take().expect("Piped");
+    let mut from_client = stream.try_clone()?;
+    let to_client = thread::spawn({
+        let mut stream = stream.try_clone()?;
+        move || -> io::Result<()> {
+            copy(&mut stdout, &mut stream)?;
+            stream.shutdown(Shutdown::Write)
+        }
+    });
+    // The client may close its side before we're done copying
+    let _ = copy(&mut from_client, &mut stdin);
+    drop(stdin);
+
+    to_client.join().expect("Copying to client panicked")?;
+    child.wait()?;
+    Ok(())
+}
+
+/// Like [`io::copy`], but passing on each read as it comes, which
+/// `io::copy` doesn't when it splices from a socket into a pipe
+fn copy(from: &mut impl Read, to: &mut impl Write) -> io::Result<()> {
+    let mut buf = [0; 8192];
+    loop {
+        match from.read(&mut buf)? {
+            0 => return Ok(()),
+            n => to.write_all(&buf[..n])?,
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! A tiny HTTP server in front of `git http-backend`, so that the smart HTTP
+//! transport can be tested against real git without the network
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+};
+
+/// Serves the repositories under `root` on a free local port until the
+/// tests exit, returning the url of `root`. Pushing is allowed.
+pub fn serve_git_http(root: &Path) -> eyre::Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}", listener.local_addr()?);
+    let root = root.to_owned();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let root = root.clone();
+            thread::spawn(move || {
+                if let Err(err) = stream.map_err(eyre::Report::from).and_then(|s| handle(s, &root)) {
+                    eprintln!("git http-backend request failed: {err:?}");
+                }
+            });
+        }
+    });
+    Ok(url)
+}
+
+fn handle(stream: TcpStream, root: &Path) -> eyre::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut request = line.split_whitespace();
+    let method = request.next().unwrap_or_default().to_owned();
+    let target = request.next().unwrap_or_default().to_owned();
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => headers.push((name.to_ascii_lowercase(), value.trim().to_owned())),
+            None => break,
+        }
+    }
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.clone())
+    };
+
+    let mut body = Vec::new();
+    if let Some(len) = header("content-length") {
+        body.resize(len.parse()?, 0);
+        reader.read_exact(&mut body)?;
+    } else if header("transfer-encoding").as_deref() == Some("chunked") {
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let len = usize::from_str_radix(line.trim(), 16)?;
+            let mut chunk = vec![0; len + 2];
+            reader.read_exact(&mut chunk)?;
+            if len == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..len]);
+        }
+    }
+
+    let mut backend = Command::new("git")
+        .arg("http-backend")
+        .env("GIT_PROJECT_ROOT", root)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("GIT_CONFIG_COUNT", "1")
+        .env("GIT_CONFIG_KEY_0", "http.receivepack")
+        .env("GIT_CONFIG_VALUE_0", "true")
+        .env("REQUEST_METHOD", method)
+        .env("PATH_INFO", path)
+        .env("QUERY_STRING", query)
+        .env("CONTENT_TYPE", header("content-type").unwrap_or_default())
+        .env("CONTENT_LENGTH", body.len().to_string())
+        .env("HTTP_GIT_PROTOCOL", header("git-protocol").unwrap_or_default())
+        .env("REMOTE_ADDR", "127.0.0.1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    backend.stdin.take().expect("Piped").write_all(&body)?;
+    let output = backend.wait_with_output()?;
+
+    // CGI headers, then the body
+    let split = output
+        .stdout
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| eyre::eyre!("No headers from git http-backend"))?;
+    let (cgi_headers, response) = (&output.stdout[..split], &output.stdout[split + 4..]);
+    let cgi_headers = String::from_utf8_lossy(cgi_headers);
+
+    let mut status = "200 OK".to_owned();
+    let mut out = Vec::new();
+    for line in cgi_headers.lines() {
+        match line.strip_prefix("Status: ") {
+            Some(s) => status = s.to_owned(),
+            None => write!(out, "{line}\r\n")?,
+        }
+    }
+
+    let mut stream = stream;
+    write!(stream, "HTTP/1.1 {status}\r\n")?;
+    stream.write_all(&out)?;
+    write!(
+        stream,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.len()
+    )?;
+    stream.write_all(response)?;
+    Ok(())
+}
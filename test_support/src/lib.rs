@@ -4,10 +4,22 @@ use std::{
     sync::Once,
 };
 
+use bstr::{BStr, BString};
+use writ::core::{
+    progress::{Stage, Update},
+    Progress,
+};
+
+mod daemon;
+mod http;
+
 pub use cmd_lib::run_fun;
+pub use daemon::serve_git_daemon;
+pub use http::serve_git_http;
 pub use insta::assert_debug_snapshot;
 pub use pretty_assertions::assert_eq;
 pub use std::fs;
+#[cfg(unix)]
 pub use std::os::unix::prelude::MetadataExt;
 pub use tempfile::{tempdir, TempDir};
 pub use writ::core::Repo;
@@ -121,6 +133,30 @@ pub fn repo_fixture() -> eyre::Result<(TempDir, Repo)> {
     Ok((dir, repo))
 }
 
+/// Everything a [`Progress`] hears
+#[derive(Debug, Default)]
+pub struct RecordedProgress {
+    pub updates: Vec<Update>,
+    pub messages: Vec<BString>,
+}
+
+impl RecordedProgress {
+    /// The last update of the stage
+    pub fn last(&self, stage: Stage) -> Option<&Update> {
+        self.updates.iter().rev().find(|update| update.stage == stage)
+    }
+}
+
+impl Progress for RecordedProgress {
+    fn update(&mut self, update: &Update) {
+        self.updates.push(*update);
+    }
+
+    fn remote(&mut self, message: &BStr) {
+        self.messages.push(message.to_owned());
+    }
+}
+
 pub fn write_to(path: impl AsRef<Path>, data: impl AsRef<[u8]>) -> Result {
     let path = path.as_ref();
     if let Some(parent) = path.parent() {
@@ -130,6 +166,16 @@ pub fn write_to(path: impl AsRef<Path>, data: impl AsRef<[u8]>) -> Result {
     Ok(())
 }
 
+/// An executable hook in the repository's `.git/hooks`
+#[cfg(unix)]
+pub fn write_hook(dir: &Path, name: &str, script: &str) -> Result {
+    use std::os::unix::fs::PermissionsExt;
+    let hook = dir.join(".git/hooks").join(name);
+    write_to(&hook, script)?;
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
 /// Assert that each item in the list is matched by at least one predicate. Predicates cannot be re-used.
 pub fn assert_contains_unordered<Item, List, Preds, P>(list: List, preds: Preds)
 where
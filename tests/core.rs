@@ -1,10 +1,68 @@
-#![feature(map_into_keys_values, path_try_exists)]
-
 #[path = "core/add.rs"]
 mod add;
+#[path = "core/check_attr.rs"]
+mod check_attr;
+#[path = "core/check_ignore.rs"]
+mod check_ignore;
+#[path = "core/checkout.rs"]
+mod checkout;
+#[path = "core/clone.rs"]
+mod clone;
 #[path = "core/commit.rs"]
 mod commit;
+#[path = "core/discover.rs"]
+mod discover;
+#[path = "core/fast_export.rs"]
+mod fast_export;
+#[path = "core/fast_import.rs"]
+mod fast_import;
+#[path = "core/fetch.rs"]
+mod fetch;
+#[path = "core/hash_object.rs"]
+mod hash_object;
+#[path = "core/ls_files.rs"]
+mod ls_files;
+#[path = "core/ls_tree.rs"]
+mod ls_tree;
+#[path = "core/mailbox.rs"]
+mod mailbox;
+#[path = "core/maintenance.rs"]
+mod maintenance;
+#[path = "core/merge.rs"]
+mod merge;
+#[path = "core/notes.rs"]
+mod notes;
+#[path = "core/patch_id.rs"]
+mod patch_id;
+#[path = "core/push.rs"]
+mod push;
+#[path = "core/replace.rs"]
+mod replace;
 #[path = "core/repo_init.rs"]
 mod repo_init;
+#[path = "core/rerere.rs"]
+mod rerere;
+#[path = "core/rev_parse.rs"]
+mod rev_parse;
+#[path = "core/sequencer.rs"]
+mod sequencer;
+#[path = "core/serve.rs"]
+mod serve;
+#[path = "core/sparse_checkout.rs"]
+mod sparse_checkout;
+#[cfg(unix)]
+#[path = "core/ssh.rs"]
+mod ssh;
 #[path = "core/status.rs"]
 mod status;
+#[path = "core/submodule.rs"]
+mod submodule;
+#[path = "core/trailers.rs"]
+mod trailers;
+#[path = "core/update_index.rs"]
+mod update_index;
+#[path = "core/verify.rs"]
+mod verify;
+#[cfg(feature = "watch")]
+#[path = "core/watch.rs"]
+mod watch;
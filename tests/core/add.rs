@@ -33,6 +33,7 @@ fn can_basic_add() -> Result {
 }
 
 #[ignore] // TODO: Figure out why this test is flaky
+#[cfg(unix)]
 #[test]
 fn can_add_executable() -> Result {
     init();
@@ -88,7 +89,7 @@ fn can_nested_add() -> Result {
 
     create_nested_files(dir)?;
 
-    let files = all_files(&dir)?;
+    let files = all_files(dir)?;
     repo.add(files)?;
     let actual = fs::read(dir.join(".git/index"))?;
 
@@ -98,7 +99,7 @@ fn can_nested_add() -> Result {
         cd $dir_s;
         rm .git/index;
     })?;
-    for file in all_files(&dir)? {
+    for file in all_files(dir)? {
         (run_fun! {
             cd $dir_s;
             git add $file;
@@ -131,7 +132,7 @@ fn can_duplicate_add() -> Result {
         cd $dir_s;
         rm .git/index;
     })?;
-    for file in all_files(&dir)? {
+    for file in all_files(dir)? {
         (run_fun! {
             cd $dir_s;
             git add $file;
@@ -154,6 +155,7 @@ fn nonexistent_add_fails() -> Result {
     Ok(())
 }
 
+#[cfg(unix)]
 #[test]
 fn unreadable_add_fails() -> Result {
     init();
@@ -238,3 +240,151 @@ fn can_add_file_where_name_is_multiple_of_padding_size() -> Result {
     repo.status()?;
     Ok(())
 }
+
+fn added_blob(repo: &mut Repo, path: &str) -> eyre::Result<Vec<u8>> {
+    let entry = repo
+        .index
+        .entry(&writ::core::WsPath::new_unchecked(path))
+        .expect("Added");
+    let oid = entry.oid;
+    Ok(repo.db.load(oid)?.bytes.into())
+}
+
+#[test]
+fn add_converts_crlf_with_autocrlf() -> Result {
+    init();
+    let (dir, _repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join(".git/config"), "[core]\n\tautocrlf = true\n")?;
+    write_to(dir.join("file.txt"), b"a\r\nb\r\n")?;
+    write_to(dir.join("binary"), b"a\0\r\n")?;
+
+    let mut repo = Repo::new(dir)?;
+    repo.add(["."])?;
+
+    assert_eq!(b"a\nb\n".to_vec(), added_blob(&mut repo, "file.txt")?);
+    assert_eq!(b"a\0\r\n".to_vec(), added_blob(&mut repo, "binary")?);
+
+    let status = repo.status()?;
+    assert!(status
+        .values()
+        .all(|s| s.workspace == writ::core::Status::Unmodified));
+
+    Ok(())
+}
+
+#[test]
+fn add_converts_text_attribute() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join(".gitattributes"), "*.txt text\n")?;
+    write_to(dir.join("file.txt"), b"a\r\nb")?;
+    write_to(dir.join("other"), b"a\r\nb")?;
+
+    repo.add(["."])?;
+
+    assert_eq!(b"a\nb".to_vec(), added_blob(&mut repo, "file.txt")?);
+    assert_eq!(b"a\r\nb".to_vec(), added_blob(&mut repo, "other")?);
+
+    Ok(())
+}
+
+#[test]
+fn add_refuses_unsafe_crlf() -> Result {
+    init();
+    let (dir, _repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    write_to(
+        dir.join(".git/config"),
+        "[core]\n\tautocrlf = input\n\tsafecrlf = true\n",
+    )?;
+    write_to(dir.join("file.txt"), b"a\r\nb")?;
+
+    let mut repo = Repo::new(dir)?;
+    assert!(repo.add(["file.txt"]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn add_applies_clean_filter() -> Result {
+    init();
+    let (dir, _repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    write_to(
+        dir.join(".git/config"),
+        "[filter \"upper\"]\n\tclean = tr a-z A-Z\n",
+    )?;
+    write_to(dir.join(".gitattributes"), "*.txt filter=upper\n")?;
+    write_to(dir.join("file.txt"), b"contents")?;
+
+    let mut repo = Repo::new(dir)?;
+    repo.add(["file.txt"])?;
+
+    assert_eq!(b"CONTENTS".to_vec(), added_blob(&mut repo, "file.txt")?);
+
+    let status = repo.status_of(["file.txt"])?;
+    assert!(status
+        .values()
+        .all(|s| s.workspace == writ::core::Status::Unmodified));
+
+    Ok(())
+}
+
+#[test]
+fn add_matches_pathspecs() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    create_nested_files(dir)?;
+    write_to(dir.join("dir_1/notes.md"), "notes")?;
+
+    let added = repo.add(["dir_1/*", ":!dir_1/dir_a", ":(icase)*.MD"])?;
+    assert_eq!(
+        vec!["dir_1/f", "dir_1/f2", "dir_1/notes.md"],
+        added.iter().map(ToString::to_string).collect::<Vec<_>>()
+    );
+
+    assert!(repo.add(["*.nothing"]).is_err());
+
+    let status = repo.status_of([":(glob)dir_1/*"])?;
+    assert_eq!(
+        vec!["dir_1/f", "dir_1/f2", "dir_1/notes.md"],
+        status.keys().map(ToString::to_string).collect::<Vec<_>>()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn add_skips_ignored_files() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join(".gitignore"), "*.log\ntarget/\n")?;
+    write_to(dir.join("src/main.rs"), "fn main() {}")?;
+    write_to(dir.join("src/debug.log"), "debug")?;
+    write_to(dir.join("target/out"), "built")?;
+
+    let added = repo.add(["."])?;
+    assert_eq!(
+        vec![".gitignore", "src/main.rs"],
+        added.iter().map(ToString::to_string).collect::<Vec<_>>()
+    );
+
+    // Unless they're named
+    let added = repo.add(["src/debug.log", "target"])?;
+    assert_eq!(
+        vec!["src/debug.log", "target/out"],
+        added.iter().map(ToString::to_string).collect::<Vec<_>>()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::ws::attributes::State;
+
+#[test]
+fn reports_like_git() -> Result {
+    init();
+    let (dir, repo) = repo_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+
+    write_to(
+        dir.path().join(".gitattributes"),
+        "[attr]lf text eol=lf\n* binary\n*.rs lf diff=rust -merge\ndocs/* !diff\n",
+    )?;
+    write_to(
+        dir.path().join("docs/.gitattributes"),
+        "*.md export-ignore\n",
+    )?;
+    write_to(dir.path().join(".git/info/attributes"), "secret.rs -text\n")?;
+
+    let format = |state: &Option<State>| match state {
+        None => "unspecified".to_string(),
+        Some(State::Set) => "set".to_string(),
+        Some(State::Unset) => "unset".to_string(),
+        Some(State::Value(value)) => value.to_string(),
+        Some(State::Unspecified) => unreachable!(),
+    };
+
+    let paths = ["a.rs", "secret.rs", "docs/a.md", "docs/b.rs"];
+    let attrs = ["text", "eol", "diff", "merge", "export-ignore"];
+    let checks = repo
+        .check_attr(paths, &attrs)?
+        .iter()
+        .map(|check| format!("{}: {}: {}", check.path, check.attr, format(&check.state)))
+        .collect::<Vec<_>>();
+    let expected = run_fun!(cd $dir_s; git check-attr $[attrs] -- $[paths])?;
+    assert_eq!(expected.lines().collect::<Vec<_>>(), checks);
+
+    let all = repo
+        .check_attr(["docs/b.rs"], &[])?
+        .iter()
+        .map(|check| format!("{}: {}: {}", check.path, check.attr, format(&check.state)))
+        .collect::<Vec<_>>();
+    let expected = run_fun!(cd $dir_s; git check-attr -a docs/b.rs)?;
+    let mut expected = expected.lines().collect::<Vec<_>>();
+    expected.sort_unstable();
+    assert_eq!(expected, all);
+
+    assert_eq!(
+        Some(State::Unset),
+        repo.check_attr(["secret.rs"], &["text"])?[0].state
+    );
+
+    Ok(())
+}
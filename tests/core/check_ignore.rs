@@ -0,0 +1,69 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use bstr::ByteSlice;
+
+#[test]
+fn reports_deciding_pattern() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join(".gitignore"), "# Logs\n*.log\n!keep.log\nbuild/\n")?;
+    write_to(dir.join(".git/info/exclude"), "secret.txt\n")?;
+    write_to(dir.join("tracked.log"), "")?;
+    repo.add(["tracked.log"])?;
+    write_to(dir.join("build/out.txt"), "")?;
+
+    let checks = repo.check_ignore([
+        "debug.log",
+        "keep.log",
+        "build/out.txt",
+        "secret.txt",
+        "tracked.log",
+        "src/main.rs",
+    ])?;
+    let root = dir.canonicalize()?;
+    let summary = checks
+        .iter()
+        .map(|check| {
+            let source = check.pattern.as_ref().map(|pattern| {
+                let file = pattern.source.file.strip_prefix(&root).unwrap();
+                format!(
+                    "{}:{}:{}",
+                    file.display(),
+                    pattern.source.line,
+                    pattern.text.as_bstr()
+                )
+            });
+            (check.path.to_string(), check.ignored, source)
+        })
+        .collect::<Vec<_>>();
+
+    let source = |s: &str| Some(s.to_string());
+    assert_eq!(
+        vec![
+            ("debug.log".to_string(), true, source(".gitignore:2:*.log")),
+            (
+                "keep.log".to_string(),
+                false,
+                source(".gitignore:3:!keep.log")
+            ),
+            (
+                "build/out.txt".to_string(),
+                true,
+                source(".gitignore:4:build/")
+            ),
+            (
+                "secret.txt".to_string(),
+                true,
+                source(".git/info/exclude:1:secret.txt")
+            ),
+            ("tracked.log".to_string(), false, None),
+            ("src/main.rs".to_string(), false, None),
+        ],
+        summary
+    );
+
+    Ok(())
+}
@@ -0,0 +1,279 @@
+use bstr::ByteSlice;
+
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{
+    db::Commit,
+    migration::{CheckError, Clobbered},
+    repo::CheckoutError,
+    FileStatus, Oid, Status, WsPath,
+};
+
+/// Two commits, returning the first. The second is checked out.
+fn two_commits_fixture() -> eyre::Result<(TempDir, Repo, Oid<Commit>)> {
+    let (dir, mut repo) = repo_fixture()?;
+    let path = dir.path();
+
+    write_to(path.join("a.txt"), "first")?;
+    write_to(path.join("same.txt"), "same")?;
+    write_to(path.join("dir/b.txt"), "b")?;
+    repo.add(["."])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let first = repo.refs.head()?.unwrap();
+
+    write_to(path.join("a.txt"), "second")?;
+    fs::remove_file(path.join("dir/b.txt"))?;
+    write_to(path.join("c.txt"), "c")?;
+    let mut index = repo.index.modify()?;
+    index.remove(&WsPath::new_unchecked("dir/b.txt"));
+    index.commit()?;
+    repo.add(["a.txt", "c.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+
+    Ok((dir, repo, first))
+}
+
+fn clobbered(result: std::result::Result<(), CheckoutError>) -> Clobbered {
+    match result {
+        Err(CheckoutError::Check(CheckError::Clobbered(clobbered))) => clobbered,
+        other => panic!("Expected clobbered error, got {:?}", other),
+    }
+}
+
+#[test]
+fn switches_workspace_and_index() -> Result {
+    init();
+    let (dir, mut repo, first) = two_commits_fixture()?;
+    let dir = dir.path();
+
+    repo.checkout(first)?;
+
+    assert_eq!("first", fs::read_to_string(dir.join("a.txt"))?);
+    assert_eq!("b", fs::read_to_string(dir.join("dir/b.txt"))?);
+    assert!(!dir.join("c.txt").exists());
+    assert_eq!(Some(first), repo.refs.head()?);
+
+    let status = repo.status()?;
+    assert_eq!(3, status.len());
+    assert!(status.values().all(|s: &FileStatus| {
+        s.workspace == Status::Unmodified && s.index == Status::Unmodified
+    }));
+
+    Ok(())
+}
+
+#[test]
+fn detaches_head_from_branch() -> Result {
+    init();
+    let (_dir, mut repo, first) = two_commits_fixture()?;
+    let branch = repo.refs.current_branch()?.expect("On a branch");
+    let branch = format!("refs/heads/{branch}");
+    let second = repo.refs.head()?.unwrap();
+
+    repo.checkout(first)?;
+
+    assert_eq!(
+        Some(second),
+        repo.refs.read_ref(branch.as_bytes().as_bstr())?
+    );
+    assert_eq!(None, repo.refs.current_branch()?);
+    assert_eq!(Some(first), repo.refs.head()?);
+    // Only history moving operations like merge keep where HEAD was
+    assert_eq!(None, repo.refs.read_ref(b"ORIG_HEAD".as_bstr())?);
+    Ok(())
+}
+
+#[test]
+fn switches_branches() -> Result {
+    init();
+    let (dir, mut repo, first) = two_commits_fixture()?;
+    let dir = dir.path();
+    let main = repo.refs.current_branch()?.expect("On a branch");
+    let second = repo.refs.head()?.unwrap();
+    repo.refs.update_ref(b"refs/heads/old".as_bstr(), &first)?;
+
+    repo.checkout_branch(b"old".as_bstr())?;
+    assert_eq!("first", fs::read_to_string(dir.join("a.txt"))?);
+    assert_eq!(Some("old".into()), repo.refs.current_branch()?);
+    assert_eq!(Some(first), repo.refs.head()?);
+
+    repo.checkout_branch(main.as_bstr())?;
+    assert_eq!("second", fs::read_to_string(dir.join("a.txt"))?);
+    assert_eq!(Some(main), repo.refs.current_branch()?);
+    assert_eq!(Some(second), repo.refs.head()?);
+
+    assert!(matches!(
+        repo.checkout_branch(b"missing".as_bstr()),
+        Err(CheckoutError::NoBranch(name)) if name == "missing"
+    ));
+    Ok(())
+}
+
+#[test]
+fn keeps_changes_to_unaffected_files() -> Result {
+    init();
+    let (dir, mut repo, first) = two_commits_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join("same.txt"), "changed")?;
+    repo.checkout(first)?;
+
+    assert_eq!("changed", fs::read_to_string(dir.join("same.txt"))?);
+
+    Ok(())
+}
+
+#[test]
+fn refuses_to_lose_local_changes() -> Result {
+    init();
+    let (dir, mut repo, first) = two_commits_fixture()?;
+    let dir = dir.path();
+    let head = repo.refs.head()?;
+
+    write_to(dir.join("a.txt"), "changed")?;
+    write_to(dir.join("c.txt"), "also changed")?;
+
+    let clobbered = clobbered(repo.checkout(first));
+    assert_eq!(
+        vec![
+            WsPath::new_unchecked("a.txt"),
+            WsPath::new_unchecked("c.txt")
+        ],
+        clobbered.local_changes
+    );
+    assert_eq!(
+        "Your local changes to the following files would be overwritten by checkout:\n\
+        \ta.txt\n\
+        \tc.txt\n\
+        Please commit your changes or stash them before you switch branches.",
+        clobbered.to_string()
+    );
+
+    // Nothing was touched
+    assert_eq!(head, repo.refs.head()?);
+    assert_eq!("changed", fs::read_to_string(dir.join("a.txt"))?);
+    assert!(!dir.join("dir/b.txt").exists());
+
+    Ok(())
+}
+
+#[test]
+fn refuses_to_lose_staged_changes() -> Result {
+    init();
+    let (dir, mut repo, first) = two_commits_fixture()?;
+
+    write_to(dir.path().join("a.txt"), "staged")?;
+    repo.add(["a.txt"])?;
+
+    let clobbered = clobbered(repo.checkout(first));
+    assert_eq!(
+        vec![WsPath::new_unchecked("a.txt")],
+        clobbered.local_changes
+    );
+
+    Ok(())
+}
+
+#[test]
+fn refuses_to_overwrite_untracked() -> Result {
+    init();
+    let (dir, mut repo, first) = two_commits_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join("dir/b.txt"), "untracked")?;
+
+    let clobbered = clobbered(repo.checkout(first));
+    assert_eq!(
+        vec![WsPath::new_unchecked("dir/b.txt")],
+        clobbered.untracked_overwritten
+    );
+    assert_eq!("untracked", fs::read_to_string(dir.join("dir/b.txt"))?);
+
+    Ok(())
+}
+
+#[test]
+fn refuses_to_remove_untracked_in_replaced_dir() -> Result {
+    init();
+    let (dir, mut repo, first) = two_commits_fixture()?;
+    let dir = dir.path();
+
+    // c.txt is deleted by the checkout, but something untracked is in the way
+    fs::remove_file(dir.join("c.txt"))?;
+    write_to(dir.join("c.txt/nested.txt"), "untracked")?;
+
+    let clobbered = clobbered(repo.checkout(first));
+    assert_eq!(
+        vec![WsPath::new_unchecked("c.txt/nested.txt")],
+        clobbered.untracked_removed
+    );
+
+    Ok(())
+}
+
+#[test]
+fn leaves_workspace_alone_if_a_write_fails() -> Result {
+    init();
+    let (dir, repo, first) = two_commits_fixture()?;
+    let dir = dir.path();
+    let second = repo.refs.head()?.unwrap();
+    drop(repo);
+    write_to(
+        dir.join(".git/info/attributes"),
+        "dir/b.txt filter=broken\n",
+    )?;
+    let mut config = fs::read_to_string(dir.join(".git/config")).unwrap_or_default();
+    config.push_str("[filter \"broken\"]\n\tsmudge = false\n\trequired = true\n");
+    write_to(dir.join(".git/config"), config)?;
+    let mut repo = Repo::new(dir)?;
+
+    let result = repo.checkout(first);
+    assert!(
+        matches!(result, Err(CheckoutError::Apply(_))),
+        "Expected apply error, got {:?}",
+        result
+    );
+
+    assert_eq!("second", fs::read_to_string(dir.join("a.txt"))?);
+    assert_eq!("c", fs::read_to_string(dir.join("c.txt"))?);
+    assert!(!dir.join("dir/b.txt").exists());
+    assert_eq!(Some(second), repo.refs.head()?);
+    assert!(repo.status()?.values().all(|s: &FileStatus| {
+        s.workspace == Status::Unmodified && s.index == Status::Unmodified
+    }));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn runs_post_checkout_hook() -> Result {
+    init();
+    let (dir, mut repo, first) = two_commits_fixture()?;
+    let dir = dir.path();
+    let second = repo.refs.head()?.unwrap();
+    write_hook(
+        dir,
+        "post-checkout",
+        "#!/bin/sh\necho \"$@\" > checked-out\nexit 1\n",
+    )?;
+
+    // The checkout is done even though the hook fails
+    let err = repo.checkout(first).unwrap_err();
+    assert!(matches!(err, CheckoutError::Hook(_)), "{:?}", err);
+    assert_eq!(Some(first), repo.refs.head()?);
+    assert_eq!(
+        format!("{} {} 0\n", second.to_hex(), first.to_hex()),
+        fs::read_to_string(dir.join("checked-out"))?
+    );
+
+    // Only switching branches sets the flag
+    repo.refs.update_ref(b"refs/heads/old".as_bstr(), &second)?;
+    let err = repo.checkout_branch(b"old".as_bstr()).unwrap_err();
+    assert!(matches!(err, CheckoutError::Hook(_)), "{:?}", err);
+    assert_eq!(
+        format!("{} {} 1\n", first.to_hex(), second.to_hex()),
+        fs::read_to_string(dir.join("checked-out"))?
+    );
+    Ok(())
+}
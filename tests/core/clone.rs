@@ -0,0 +1,109 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{progress::Stage, CloneOptions, Status};
+
+fn git_source() -> eyre::Result<TempDir> {
+    let src = tempdir()?;
+    let src_s = src.path().to_str().unwrap();
+    write_to(src.path().join("a.txt"), "a\n")?;
+    write_to(src.path().join("dir/b.txt"), "b\n")?;
+    run_fun! {
+        cd $src_s;
+        git init -q -b trunk;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git add .;
+        git commit -q -m $MSG;
+        git branch other;
+        git tag v1;
+    }?;
+    Ok(src)
+}
+
+#[test]
+fn clones_local_repo() -> Result {
+    init();
+    let src = git_source()?;
+    let src_s = src.path().to_str().unwrap();
+    let dst = tempdir()?;
+    let dst_path = dst.path().join("clone");
+    let dst_s = dst_path.to_str().unwrap();
+
+    let mut progress = RecordedProgress::default();
+    let mut repo = Repo::clone_local(
+        src.path(),
+        &dst_path,
+        &CloneOptions::default(),
+        &mut progress,
+    )?;
+    let copied = progress.last(Stage::Copying).expect("Copied");
+    // A commit, two trees and two blobs
+    assert_eq!((5, Some(5)), (copied.objects, copied.total));
+
+    assert_eq!("b\n", fs::read_to_string(dst_path.join("dir/b.txt"))?);
+    assert!(repo
+        .status()?
+        .values()
+        .all(|s| s.workspace == Status::Unmodified && s.index == Status::Unmodified));
+
+    let git = |args: &[&str]| run_fun!(cd $dst_s; git $[args]);
+    let head = run_fun!(cd $src_s; git rev-parse HEAD)?;
+    for rev in &[
+        "HEAD",
+        "trunk",
+        "origin/trunk",
+        "origin/other",
+        "origin/HEAD",
+        "v1",
+    ] {
+        assert_eq!(head, git(&["rev-parse", rev])?);
+    }
+    assert_eq!("refs/heads/trunk", git(&["symbolic-ref", "HEAD"])?);
+    assert_eq!("origin", git(&["config", "branch.trunk.remote"])?);
+    assert_eq!("refs/heads/trunk", git(&["config", "branch.trunk.merge"])?);
+
+    assert_eq!(
+        src.path().canonicalize()?.to_str().unwrap(),
+        git(&["config", "remote.origin.url"])?
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn hardlinks_objects_unless_asked_not_to() -> Result {
+    init();
+    let src = git_source()?;
+    let dst = tempdir()?;
+
+    let linked = Repo::clone_local(
+        src.path(),
+        dst.path().join("linked"),
+        &CloneOptions::default(),
+        &mut (),
+    )?;
+    let copied = Repo::clone_local(
+        src.path(),
+        dst.path().join("copied"),
+        &CloneOptions {
+            no_hardlinks: true,
+            no_checkout: true,
+        },
+        &mut (),
+    )?;
+
+    let head = linked.refs.head()?.expect("Cloned");
+    let object = |repo: &Repo| {
+        let hex = head.to_hex();
+        repo.git_dir()
+            .join("objects")
+            .join(&hex[..2])
+            .join(&hex[2..])
+    };
+    assert_eq!(2, fs::metadata(object(&linked))?.nlink());
+    assert_eq!(1, fs::metadata(object(&copied))?.nlink());
+    assert_eq!(Some(head), copied.refs.head()?);
+    assert!(!dst.path().join("copied/a.txt").exists());
+    Ok(())
+}
@@ -1,5 +1,33 @@
 use test_support::assert_eq;
 use test_support::*;
+use writ::core::{
+    db::{Commit, Signature},
+    repo::CommitError,
+    CommitOptions,
+};
+
+#[test]
+fn clones_load_commits_on_other_threads() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    write_to(dir.path().join("file.txt"), "File contents\n")?;
+    repo.add(vec!["file.txt"])?;
+    repo.commit(NAME.to_string(), EMAIL.to_string(), MSG.to_string())?;
+    let head = repo.refs.head()?.expect("Committed");
+    let commit = repo.db.load(head)?;
+
+    let threads = (0..4)
+        .map(|_| {
+            let mut repo = repo.clone();
+            std::thread::spawn(move || repo.db.load(head))
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        assert_eq!(commit, thread.join().expect("Didn't panic")?);
+    }
+
+    Ok(())
+}
 
 #[test]
 fn can_basic_commit() -> Result {
@@ -72,3 +100,347 @@ fn can_commit_with_nested_files() -> Result {
 
     Ok(())
 }
+
+#[test]
+fn records_author_and_committer() -> Result {
+    init();
+
+    let (dir, mut repo) = repo_fixture()?;
+    write_to(dir.path().join("file.txt"), "File contents\n")?;
+    repo.add(vec!["file.txt"])?;
+
+    let time = |secs| chrono::DateTime::parse_from_str(secs, "%s %z").unwrap();
+    let author = Signature::new(NAME, EMAIL, time("1500000000 +0200"));
+    let committer = Signature::new(
+        "Committer",
+        "committer@example.com",
+        time("1600000000 -0500"),
+    );
+    repo.commit_as(author.clone(), committer.clone(), MSG)?;
+
+    let head = repo.refs.head()?.expect("Committed");
+    let commit = repo.db.load::<Commit>(head)?;
+    assert_eq!(author, commit.author);
+    assert_eq!(committer, commit.committer);
+
+    Ok(())
+}
+
+#[test]
+fn amends_keeping_the_author() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+    let amend = CommitOptions {
+        amend: true,
+        ..CommitOptions::default()
+    };
+    let time = |secs| chrono::DateTime::parse_from_str(secs, "%s %z").unwrap();
+    let author = Signature::new(NAME, EMAIL, time("1500000000 +0200"));
+    let committer = Signature::new("Other", "other@example.com", time("1600000000 -0500"));
+    let err = repo.commit_as_with(author.clone(), committer.clone(), MSG, &amend);
+    assert!(matches!(err, Err(CommitError::NothingToAmend)));
+
+    write_to(dir.path().join("file.txt"), "contents")?;
+    repo.add(["file.txt"])?;
+    repo.commit_as(author.clone(), author.clone(), MSG)?;
+    write_to(dir.path().join("other.txt"), "contents")?;
+    repo.add(["other.txt"])?;
+    repo.commit_as_with(committer.clone(), committer.clone(), "", &amend)?;
+
+    let commit = repo.db.load::<Commit>(repo.refs.head()?.unwrap())?;
+    assert_eq!(None, commit.parent);
+    assert_eq!(author, commit.author);
+    assert_eq!(committer, commit.committer);
+    assert_eq!(MSG, commit.msg);
+    assert_eq!(
+        "file.txt\nother.txt",
+        run_fun!(cd $dir_s; git ls-tree --name-only HEAD)?
+    );
+    assert_eq!("1", run_fun!(cd $dir_s; git rev-list --count HEAD)?);
+
+    let reset = CommitOptions {
+        reset_author: true,
+        ..amend
+    };
+    repo.commit_as_with(committer.clone(), committer.clone(), MSG, &reset)?;
+    let commit = repo.db.load::<Commit>(repo.refs.head()?.unwrap())?;
+    assert_eq!(committer, commit.author);
+
+    // Otherwise the author and committer come from the config
+    run_fun! {
+        cd $dir_s;
+        git config user.name Configured;
+        git config user.email configured@example.com;
+        git config committer.name Committer;
+    }?;
+    let mut repo = Repo::new(dir.path())?;
+    repo.commit_with(MSG, &amend)?;
+    assert_eq!(
+        "Other other@example.com Committer configured@example.com",
+        run_fun!(cd $dir_s; git log -1 "--format=%an %ae %cn %ce")?
+    );
+    run_fun!(cd $dir_s; git fsck --strict)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn runs_commit_hooks() -> Result {
+    use writ::core::CommitOptions;
+    init();
+
+    let (dir, mut repo) = repo_fixture()?;
+    write_to(dir.path().join("file.txt"), "File contents\n")?;
+    repo.add(vec!["file.txt"])?;
+
+    write_hook(
+        dir.path(),
+        "pre-commit",
+        "#!/bin/sh\necho Not today >&2\nexit 1\n",
+    )?;
+    let err = repo.commit(NAME, EMAIL, MSG).unwrap_err();
+    assert_eq!("pre-commit hook failed: Not today", err.to_string());
+    assert_eq!(None, repo.refs.head()?);
+
+    let signature = Signature::new_local(NAME, EMAIL, chrono::Local::now());
+    let options = CommitOptions {
+        no_verify: true,
+        ..CommitOptions::default()
+    };
+    repo.commit_as_with(signature.clone(), signature, MSG, &options)?;
+    let skipped = repo.refs.head()?.expect("Committed");
+
+    // It can change the message
+    fs::remove_file(dir.path().join(".git/hooks/pre-commit"))?;
+    write_hook(
+        dir.path(),
+        "commit-msg",
+        "#!/bin/sh\necho Signed-off-by: Me >> \"$1\"\n",
+    )?;
+    write_to(dir.path().join("file.txt"), "Changed\n")?;
+    repo.add(vec!["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let head = repo.refs.head()?.expect("Committed");
+    let commit = repo.db.load::<Commit>(head)?;
+    assert_eq!(Some(skipped), commit.parent);
+    assert_eq!(format!("{MSG}Signed-off-by: Me\n"), commit.msg);
+
+    Ok(())
+}
+
+#[test]
+fn runs_registered_hooks() -> Result {
+    use std::sync::{Arc, Mutex};
+    use writ::core::{
+        hook::{Hooks, Rejected},
+        Oid, Repo,
+    };
+
+    #[derive(Debug, Default)]
+    struct NoSecrets {
+        committed: Arc<Mutex<Vec<Oid<Commit>>>>,
+    }
+
+    impl Hooks for NoSecrets {
+        fn pre_commit(&self, repo: &Repo) -> std::result::Result<(), Rejected> {
+            let secret = repo.index.entries().any(|entry| entry.path == "secret.txt");
+            if secret {
+                return Err(Rejected("secret.txt is staged".into()));
+            }
+            Ok(())
+        }
+
+        fn post_commit(&self, _repo: &Repo, commit: Oid<Commit>) {
+            self.committed.lock().unwrap().push(commit);
+        }
+    }
+
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let hooks = NoSecrets::default();
+    let committed = Arc::clone(&hooks.committed);
+    repo.register_hooks(hooks);
+
+    write_to(dir.path().join("file.txt"), "File contents\n")?;
+    repo.add(vec!["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let head = repo.refs.head()?.expect("Committed");
+    assert_eq!(vec![head], *committed.lock().unwrap());
+
+    write_to(dir.path().join("secret.txt"), "Hunter2\n")?;
+    repo.add(vec!["secret.txt"])?;
+    let err = repo.commit(NAME, EMAIL, MSG).unwrap_err();
+    assert_eq!("Rejected by hook: secret.txt is staged", err.to_string());
+    assert_eq!(Some(head), repo.refs.head()?);
+    assert_eq!(1, committed.lock().unwrap().len());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn runs_post_merge_hook() -> Result {
+    use writ::core::merge::{MergeError, MergeOptions, Merged};
+    init();
+
+    let (dir, mut repo) = repo_fixture()?;
+    repo.config.set("user.name", NAME)?;
+    repo.config.set("user.email", EMAIL)?;
+    write_to(dir.path().join("file.txt"), "File contents\n")?;
+    repo.add(vec!["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let first = repo.refs.head()?.expect("Committed");
+    write_to(dir.path().join("file.txt"), "Changed\n")?;
+    repo.add(vec!["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let second = repo.refs.head()?.expect("Committed");
+    repo.checkout(first)?;
+
+    // The merge is done even though the hook fails
+    write_hook(
+        dir.path(),
+        "post-merge",
+        "#!/bin/sh\necho \"$@\" >> merged\nexit 1\n",
+    )?;
+    let err = repo.merge(second, &MergeOptions::default()).unwrap_err();
+    assert!(matches!(err, MergeError::Hook(_)), "{:?}", err);
+    assert_eq!(Some(second), repo.refs.head()?);
+
+    repo.checkout(first)?;
+    write_hook(
+        dir.path(),
+        "post-merge",
+        "#!/bin/sh\necho \"$@\" >> merged\n",
+    )?;
+    let options = MergeOptions {
+        no_ff: true,
+        ..MergeOptions::default()
+    };
+    let merged = repo.merge(second, &options)?;
+    assert!(matches!(merged, Merged::Commit(_)), "{:?}", merged);
+    assert_eq!("0\n0\n", fs::read_to_string(dir.path().join("merged"))?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn uses_hooks_path() -> Result {
+    use std::os::unix::fs::PermissionsExt;
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let failing = |hooks: &std::path::Path| -> Result {
+        let hook = hooks.join("pre-commit");
+        write_to(
+            &hook,
+            format!("#!/bin/sh\necho {} >&2\nexit 1\n", hooks.display()),
+        )?;
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    };
+    write_to(dir.path().join("file.txt"), "File contents\n")?;
+    repo.add(vec!["file.txt"])?;
+    write_hook(dir.path(), "pre-commit", "#!/bin/sh\nexit 1\n")?;
+
+    // Relative to the root of the workspace
+    failing(&dir.path().join(".husky"))?;
+    repo.config.set("core.hooksPath", ".husky")?;
+    let err = repo.commit(NAME, EMAIL, MSG).unwrap_err();
+    assert!(err.to_string().ends_with(".husky"), "{}", err);
+
+    let elsewhere = tempdir()?;
+    failing(elsewhere.path())?;
+    repo.config
+        .set("core.hooksPath", elsewhere.path().to_str().unwrap())?;
+    let err = repo.commit(NAME, EMAIL, MSG).unwrap_err();
+    assert_eq!(
+        format!("pre-commit hook failed: {}", elsewhere.path().display()),
+        err.to_string()
+    );
+
+    // Without a hook there, the one in .git/hooks isn't used
+    repo.config.set("core.hooksPath", "missing")?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    Ok(())
+}
+
+#[test]
+fn cleans_up_messages() -> Result {
+    use writ::core::{Cleanup, CommitOptions};
+    init();
+
+    let (dir, mut repo) = repo_fixture()?;
+    let template_path = dir.path().join(".git/template.txt");
+    write_to(&template_path, "\n# Say why\n")?;
+    repo.config
+        .set("commit.template", template_path.to_str().unwrap())?;
+    write_to(dir.path().join("file.txt"), "File contents\n")?;
+    repo.add(vec!["file.txt"])?;
+    let template = repo.initial_commit_message()?.expect("Has template");
+    assert_eq!("\n# Say why\n", template);
+
+    // Comments are kept unless stripped
+    repo.commit(NAME, EMAIL, format!("Summary  \n\n\n{template}"))?;
+    let head = repo.refs.head()?.expect("Committed");
+    assert_eq!("Summary\n\n# Say why\n", repo.db.load::<Commit>(head)?.msg);
+
+    let signature = Signature::new_local(NAME, EMAIL, chrono::Local::now());
+    let strip = CommitOptions {
+        cleanup: Some(Cleanup::Strip),
+        ..CommitOptions::default()
+    };
+    let err = repo
+        .commit_as_with(signature.clone(), signature.clone(), &template, &strip)
+        .unwrap_err();
+    assert_eq!("Empty commit message", err.to_string());
+
+    repo.config.set("commit.cleanup", "strip")?;
+    repo.config.set("core.commentChar", ";")?;
+    repo.commit(NAME, EMAIL, "Summary\n# Kept\n; Dropped\n")?;
+    let head = repo.refs.head()?.expect("Committed");
+    assert_eq!("Summary\n# Kept\n", repo.db.load::<Commit>(head)?.msg);
+    Ok(())
+}
+
+#[test]
+fn edits_messages_with_core_editor() -> Result {
+    use writ::core::CommitOptions;
+    init();
+    // GIT_EDITOR would be used instead of core.editor
+    for var in ["GIT_EDITOR", "VISUAL", "EDITOR"] {
+        std::env::remove_var(var);
+    }
+
+    let (dir, mut repo) = repo_fixture()?;
+    let seen = dir.path().join(".git/seen.txt");
+    let seen_s = seen.to_str().unwrap();
+    write_to(dir.path().join("file.txt"), "File contents\n")?;
+    repo.add(vec!["file.txt"])?;
+    repo.config.set(
+        "core.editor",
+        &format!("edit() {{ cp \"$1\" '{seen_s}'; echo Edited >> \"$1\"; }}; edit"),
+    )?;
+
+    let signature = Signature::new_local(NAME, EMAIL, chrono::Local::now());
+    let options = CommitOptions {
+        edit: true,
+        ..CommitOptions::default()
+    };
+    repo.commit_as_with(signature.clone(), signature.clone(), "Summary", &options)?;
+    assert_eq!(
+        "Summary\n\n\
+         # Please enter the commit message for your changes. Lines starting\n\
+         # with '#' will be ignored, and an empty message aborts the commit.\n",
+        fs::read_to_string(&seen)?
+    );
+    let head = repo.refs.head()?.expect("Committed");
+    assert_eq!("Summary\n\nEdited\n", repo.db.load::<Commit>(head)?.msg);
+
+    // Failing aborts the commit
+    repo.config.set("core.editor", "false")?;
+    assert!(repo
+        .commit_as_with(signature.clone(), signature, "Summary", &options)
+        .is_err());
+    assert_eq!(Some(head), repo.refs.head()?);
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{discover::DiscoverError, DiscoverOptions, OpenOptions};
+
+#[test]
+fn finds_repos_from_subdirectories() -> Result {
+    init();
+    let (dir, _) = repo_fixture()?;
+    let root = dir.path().canonicalize()?;
+    fs::create_dir_all(root.join("a/b/c"))?;
+
+    let repo = Repo::discover_with(root.join("a/b/c"), &DiscoverOptions::default())?;
+    assert_eq!(root.join(".git"), repo.git_dir());
+    assert_eq!(root, repo.workspace.as_ref().unwrap().path());
+
+    // The nearest repository wins
+    let nested = Repo::init(root.join("a/nested"))?;
+    fs::create_dir_all(root.join("a/nested/dir"))?;
+    let repo = Repo::discover_with(root.join("a/nested/dir"), &DiscoverOptions::default())?;
+    assert_eq!(nested.git_dir(), repo.git_dir());
+
+    // As does a `.git` file
+    write_to(root.join("a/b/.git"), "gitdir: ../nested/.git\n")?;
+    let repo = Repo::discover_with(root.join("a/b/c"), &DiscoverOptions::default())?;
+    assert_eq!(nested.git_dir(), repo.git_dir());
+    assert_eq!(root.join("a/b"), repo.workspace.as_ref().unwrap().path());
+
+    // Inside a git directory, the git directory is the repository
+    let repo = Repo::discover_with(root.join(".git/objects"), &DiscoverOptions::default())?;
+    assert_eq!(root.join(".git"), repo.git_dir());
+    assert!(repo.is_bare());
+    Ok(())
+}
+
+#[test]
+fn stops_at_ceiling_directories() -> Result {
+    init();
+    let (dir, _) = repo_fixture()?;
+    let root = dir.path().canonicalize()?;
+    fs::create_dir_all(root.join("a/b"))?;
+
+    let options = |ceiling: &str| DiscoverOptions {
+        ceiling_dirs: vec![root.join(ceiling)],
+        ..DiscoverOptions::default()
+    };
+    let err = Repo::discover_with(root.join("a/b"), &options("a")).unwrap_err();
+    assert!(matches!(err, DiscoverError::NotFound(start) if start == root.join("a/b")));
+    // The ceiling itself isn't looked in either
+    assert!(Repo::discover_with(root.join("a/b"), &options("")).is_err());
+    // Unless that's where the search starts
+    let repo = Repo::discover_with(root.join("a"), &options("a"))?;
+    assert_eq!(root.join(".git"), repo.git_dir());
+    Ok(())
+}
+
+#[test]
+fn opens_locations_given_like_git_environment_variables() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let root = dir.path().canonicalize()?;
+    write_to(root.join("file.txt"), "contents")?;
+    repo.add(vec!["file.txt"])?;
+    repo.commit(
+        "Name".to_string(),
+        "email@example.com".to_string(),
+        "Message".to_string(),
+    )?;
+    let head = repo.refs.head()?.expect("Committed");
+    let elsewhere = tempdir()?;
+    let elsewhere = elsewhere.path().canonicalize()?;
+
+    // Without discovering, and with the workspace where it's opened
+    let options = OpenOptions {
+        git_dir: Some(root.join(".git")),
+        ..OpenOptions::default()
+    };
+    let repo = Repo::open_with(&elsewhere, &options)?;
+    assert_eq!(root.join(".git"), repo.git_dir());
+    assert_eq!(elsewhere, repo.workspace.as_ref().unwrap().path());
+
+    let options = OpenOptions {
+        work_tree: Some(root.clone()),
+        ..options
+    };
+    let repo = Repo::open_with(&elsewhere, &options)?;
+    assert_eq!(root, repo.workspace.as_ref().unwrap().path());
+
+    // Relative to where it's opened
+    fs::rename(root.join(".git/objects"), root.join("objects"))?;
+    fs::create_dir(root.join(".git/objects"))?;
+    let options = OpenOptions {
+        objects_dir: Some("objects".into()),
+        index_file: Some("other-index".into()),
+        ..OpenOptions::default()
+    };
+    let mut repo = Repo::open_with(&root, &options)?;
+    assert_eq!(root.join("objects"), repo.db.objects_dir());
+    repo.db.load(head)?;
+    assert_eq!(root.join("other-index"), repo.index.path());
+    assert_eq!(0, repo.index.entries().count());
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::{path::Path, process::Command};
+
+/// A merge of two branches with an annotated tag, a lightweight tag, an
+/// executable and a deleted file
+fn history(dir: &Path) -> Result {
+    let dir_s = dir.to_str().unwrap();
+    write_to(dir.join("a.txt"), "a\n")?;
+    write_to(dir.join("dir/b.txt"), "b\n")?;
+    run_fun! {
+        cd $dir_s;
+        git init -q -b main;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git add .;
+        git commit -q -m "Root";
+        git tag -a -m "First release" v1;
+        git checkout -q -b side;
+        sh -c "echo side > c.sh";
+        chmod +x c.sh;
+        git add c.sh;
+        git commit -q -m "Add c";
+        git checkout -q main;
+        git rm -q dir/b.txt;
+        git commit -q -m "Remove b";
+        git merge -q --no-edit side;
+        git tag light;
+    }?;
+    Ok(())
+}
+
+#[test]
+fn round_trips_through_fast_import() -> Result {
+    init();
+    let src = tempdir()?;
+    history(src.path())?;
+    let mut repo = Repo::new(src.path())?;
+
+    let range = repo.rev_range(["main"])?;
+    let mut stream = Vec::new();
+    let marks = repo.fast_export(&range, &mut stream)?;
+    // Three blobs and four commits
+    assert_eq!(7, marks.len());
+
+    let dst = tempdir()?;
+    let dst_s = dst.path().to_str().unwrap();
+    run_fun!(cd $dst_s; git init -q --bare)?;
+    let mut import = Command::new("git")
+        .args(["fast-import", "--quiet"])
+        .current_dir(dst.path())
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+    std::io::Write::write_all(import.stdin.as_mut().unwrap(), &stream)?;
+    assert!(import.wait()?.success());
+
+    let src_s = src.path().to_str().unwrap();
+    for rev in ["main", "side", "v1", "light"] {
+        assert_eq!(
+            run_fun!(cd $src_s; git rev-parse $rev)?,
+            run_fun!(cd $dst_s; git rev-parse $rev)?,
+            "{}",
+            rev
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn writes_excluded_parents_as_roots() -> Result {
+    init();
+    let src = tempdir()?;
+    history(src.path())?;
+    let mut repo = Repo::new(src.path())?;
+
+    // Only the merge and the commit it merged
+    let range = repo.rev_range(["main~..main"])?;
+    let mut stream = Vec::new();
+    repo.fast_export(&range, &mut stream)?;
+    let stream = String::from_utf8(stream)?;
+
+    // Every file is written for the commit, since its parent isn't
+    assert!(stream.starts_with("blob\nmark :1\ndata 2\na\n\n"));
+    assert!(stream.contains("M 100755 :2 c.sh\n"));
+    assert!(stream.contains("M 100644 :3 dir/b.txt\n"));
+    assert!(stream.contains("reset refs/heads/main\ncommit refs/heads/main\nmark :4\n"));
+    // The merge has no first parent written, only the commit it merged
+    let merge = &stream[stream.find("mark :5\n").unwrap()..];
+    assert!(merge.contains("\nmerge :4\n"));
+    assert!(!merge[..merge.find("merge :4").unwrap()].contains("from :"));
+    assert!(stream.contains("reset refs/heads/side\nfrom :4\n"));
+    Ok(())
+}
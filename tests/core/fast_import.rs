@@ -0,0 +1,127 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::path::Path;
+
+use writ::core::FastImportOptions;
+
+/// A merge of two branches with an annotated tag, an executable, a file
+/// with a space in its name and a deleted file
+fn history(dir: &Path) -> Result {
+    let dir_s = dir.to_str().unwrap();
+    write_to(dir.join("a.txt"), "a\n")?;
+    write_to(dir.join("dir/b c.txt"), "b\n")?;
+    run_fun! {
+        cd $dir_s;
+        git init -q -b main;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git add .;
+        git commit -q -m "Root";
+        git tag -a -m "First release" v1;
+        git checkout -q -b side;
+        sh -c "echo side > c.sh";
+        chmod +x c.sh;
+        git add c.sh;
+        git commit -q -m "Add c";
+        git checkout -q main;
+        git rm -q "dir/b c.txt";
+        git commit -q -m "Remove b";
+        git merge -q --no-edit side;
+    }?;
+    Ok(())
+}
+
+fn empty_repo(dir: &Path) -> eyre::Result<Repo> {
+    let dir_s = dir.to_str().unwrap();
+    run_fun!(cd $dir_s; git init -q)?;
+    Ok(Repo::new(dir)?)
+}
+
+fn assert_same_revs(src: &Path, dst: &Path, revs: &[&str]) -> Result {
+    let (src_s, dst_s) = (src.to_str().unwrap(), dst.to_str().unwrap());
+    for rev in revs {
+        assert_eq!(
+            run_fun!(cd $src_s; git rev-parse $rev)?,
+            run_fun!(cd $dst_s; git rev-parse $rev)?,
+            "{}",
+            rev
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn imports_what_git_exports() -> Result {
+    init();
+    let src = tempdir()?;
+    history(src.path())?;
+    let src_s = src.path().to_str().unwrap();
+    let stream = run_fun!(cd $src_s; git fast-export --all)?;
+
+    let dst = tempdir()?;
+    let mut repo = empty_repo(dst.path())?;
+    let marks = repo.fast_import(stream.as_bytes(), FastImportOptions::default())?;
+    assert_eq!(7, marks.len());
+
+    assert_same_revs(src.path(), dst.path(), &["main", "side", "v1"])?;
+    let dst_s = dst.path().to_str().unwrap();
+    run_fun!(cd $dst_s; git fsck --strict)?;
+    Ok(())
+}
+
+#[test]
+fn round_trips_fast_export() -> Result {
+    init();
+    let src = tempdir()?;
+    history(src.path())?;
+    let mut src_repo = Repo::new(src.path())?;
+    let range = src_repo.rev_range(["main", "v1"])?;
+    let mut stream = Vec::new();
+    let exported = src_repo.fast_export(&range, &mut stream)?;
+
+    let dst = tempdir()?;
+    let mut repo = empty_repo(dst.path())?;
+    let imported = repo.fast_import(&stream[..], FastImportOptions::default())?;
+    assert_eq!(exported, imported);
+    assert_same_revs(src.path(), dst.path(), &["main", "side", "v1"])?;
+    Ok(())
+}
+
+#[test]
+fn continues_refs_and_refuses_rewinds() -> Result {
+    init();
+    let dir = tempdir()?;
+    let mut repo = empty_repo(dir.path())?;
+    let committer = format!("committer {NAME} <{EMAIL}> 1600000000 +0200");
+
+    let first = format!(
+        "commit refs/heads/main\n{committer}\ndata <<EOF\nFirst\nEOF\n\
+         M 644 inline \"quoted\\tname\"\ndata 6\nfirst\n\n\
+         commit refs/heads/main\nmark :1\n{committer}\ndata 7\nSecond\n\
+         M 100644 inline dir/file.txt\ndata 7\nsecond\n\n"
+    );
+    let marks = repo.fast_import(first.as_bytes(), FastImportOptions::default())?;
+    assert_eq!(1, marks.len());
+
+    let dir_s = dir.path().to_str().unwrap();
+    assert_eq!(
+        "Second\nFirst",
+        run_fun!(cd $dir_s; git log --format=%s main)?
+    );
+    assert_eq!(
+        "dir/file.txt\n\"quoted\\tname\"",
+        run_fun!(cd $dir_s; git ls-tree -r --name-only main)?
+    );
+
+    // A new root for main doesn't fast-forward
+    let rewind =
+        format!("reset refs/heads/main\n\ncommit refs/heads/main\n{committer}\ndata 7\nRewind\n\n");
+    assert!(repo
+        .fast_import(rewind.as_bytes(), FastImportOptions::default())
+        .is_err());
+    assert_eq!("Second", run_fun!(cd $dir_s; git log -1 --format=%s main)?);
+    repo.fast_import(rewind.as_bytes(), FastImportOptions { force: true })?;
+    assert_eq!("Rewind", run_fun!(cd $dir_s; git log --format=%s main)?);
+    Ok(())
+}
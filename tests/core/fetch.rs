@@ -0,0 +1,563 @@
+use std::path::Path;
+
+use bstr::{BString, ByteSlice};
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{
+    db::{Commit, UntypedOid},
+    progress::Stage,
+    transport::upload_pack::{Depth, Filter},
+    FetchOptions, Oid, Tags,
+};
+
+/// Each revision changes one line of a large file, so that the packs we're
+/// sent have deltas
+fn commit_revision(src: &Path, revision: usize) -> Result {
+    let src_s = src.to_str().unwrap();
+    let big = (0..200)
+        .map(|line| {
+            let changed = if line == revision * 10 { revision } else { 0 };
+            format!("line {line}, changed in revision {changed}\n")
+        })
+        .collect::<String>();
+    write_to(src.join("big.txt"), big)?;
+    write_to(
+        src.join(format!("dir/{revision}.txt")),
+        revision.to_string(),
+    )?;
+    let msg = format!("Revision {revision}");
+    run_fun! {
+        cd $src_s;
+        git add .;
+        git commit -q -m $msg;
+    }?;
+    Ok(())
+}
+
+/// The tempdir holding the source, and its url
+fn served_source() -> eyre::Result<(TempDir, String)> {
+    let root = source()?;
+    let url = serve_git_http(root.path())?;
+    Ok((root, format!("{url}/src")))
+}
+
+/// A tempdir holding the source as `src`
+fn source() -> eyre::Result<TempDir> {
+    let root = tempdir()?;
+    let src = root.path().join("src");
+    fs::create_dir(&src)?;
+    let src_s = src.to_str().unwrap();
+    run_fun! {
+        cd $src_s;
+        git init -q -b trunk;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    for revision in 0..3 {
+        commit_revision(&src, revision)?;
+    }
+    run_fun!(cd $src_s; git branch other HEAD~1)?;
+    Ok(root)
+}
+
+fn rev_parse(repo: &Path, rev: &str) -> eyre::Result<Oid<Commit>> {
+    let repo = repo.to_str().unwrap();
+    let hex = run_fun!(cd $repo; git rev-parse $rev)?;
+    Ok(Oid::parse(hex)?)
+}
+
+#[test]
+fn fetches_remote_branches_over_http() -> Result {
+    init();
+    let (root, url) = served_source()?;
+    let src = root.path().join("src");
+    let dst = tempdir()?;
+    let dst_s = dst.path().to_str().unwrap();
+
+    let mut repo = Repo::init(dst.path())?;
+    repo.config.set("remote.origin.url", &url)?;
+    repo.save_config()?;
+    // So that git recognizes the repository
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+
+    let fetched = repo.fetch("origin")?;
+    let trunk = rev_parse(&src, "trunk")?;
+    let other = rev_parse(&src, "other")?;
+    assert_eq!(
+        vec![
+            (BString::from("refs/remotes/origin/other"), None, other),
+            (BString::from("refs/remotes/origin/trunk"), None, trunk),
+        ],
+        fetched.updated
+    );
+    assert_eq!(trunk, rev_parse(dst.path(), "origin/trunk")?);
+    assert_eq!(other, rev_parse(dst.path(), "origin/other")?);
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+
+    // Only what we don't have is sent, as a thin pack
+    commit_revision(&src, 3)?;
+    let fetched = repo.fetch("origin")?;
+    let new_trunk = rev_parse(&src, "trunk")?;
+    assert_eq!(
+        vec![(
+            BString::from("refs/remotes/origin/trunk"),
+            Some(trunk),
+            new_trunk
+        )],
+        fetched.updated
+    );
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+
+    assert!(repo.fetch("origin")?.updated.is_empty());
+    Ok(())
+}
+
+#[test]
+fn fetches_into_bare_repos() -> Result {
+    init();
+    let (root, url) = served_source()?;
+    let src = root.path().join("src");
+    let dst = tempdir()?;
+    // Without creating a workspace around it
+    let git_dir = dst.path().join("nested/repo.git");
+    let git_dir_s = git_dir.to_str().unwrap();
+
+    let mut repo = Repo::init_bare(&git_dir)?;
+    assert_eq!(1, fs::read_dir(dst.path().join("nested"))?.count());
+    repo.config.set("remote.origin.url", &url)?;
+    repo.config
+        .set("remote.origin.fetch", "+refs/heads/*:refs/heads/*")?;
+    repo.save_config()?;
+
+    repo.fetch("origin")?;
+    let trunk = rev_parse(&src, "trunk")?;
+    assert_eq!(trunk, rev_parse(&git_dir, "trunk")?);
+    assert_eq!(
+        "true",
+        run_fun!(cd $git_dir_s; git rev-parse --is-bare-repository)?
+    );
+    run_fun!(cd $git_dir_s; git fsck --full --no-dangling)?;
+
+    let mut repo = Repo::new(&git_dir)?;
+    assert_eq!(trunk.to_hex(), repo.rev_parse("trunk~0")?.to_hex());
+    let err = repo.checkout(trunk).unwrap_err();
+    assert!(
+        matches!(err, writ::core::repo::CheckoutError::Bare(_)),
+        "{:?}",
+        err
+    );
+    Ok(())
+}
+
+#[test]
+fn records_fetch_head_like_git() -> Result {
+    init();
+    let (root, url) = served_source()?;
+    let src = root.path().join("src");
+    let dst = tempdir()?;
+    let by_git = tempdir()?;
+    let by_git_s = by_git.path().to_str().unwrap();
+
+    let mut repo = Repo::init(dst.path())?;
+    repo.config.set("remote.origin.url", &url)?;
+    repo.config.set("branch.trunk.remote", "origin")?;
+    repo.config.set("branch.trunk.merge", "refs/heads/trunk")?;
+    repo.save_config()?;
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+    repo.fetch("origin")?;
+
+    run_fun! {
+        cd $by_git_s;
+        git init -q -b trunk;
+        git remote add origin $url;
+        git config branch.trunk.remote origin;
+        git config branch.trunk.merge refs/heads/trunk;
+        git fetch -q origin;
+    }?;
+    assert_eq!(
+        fs::read_to_string(by_git.path().join(".git/FETCH_HEAD"))?,
+        fs::read_to_string(dst.path().join(".git/FETCH_HEAD"))?
+    );
+    assert_eq!(
+        rev_parse(&src, "trunk")?.into_untyped(),
+        repo.rev_parse("FETCH_HEAD")?
+    );
+    assert_eq!(
+        rev_parse(&src, "trunk")?,
+        rev_parse(dst.path(), "FETCH_HEAD")?
+    );
+    Ok(())
+}
+
+#[test]
+fn fetches_after_local_history_diverges() -> Result {
+    init();
+    let (root, url) = served_source()?;
+    let src = root.path().join("src");
+    let dst = tempdir()?;
+    let dst_s = dst.path().to_str().unwrap();
+
+    let mut repo = Repo::init(dst.path())?;
+    repo.config.set("remote.origin.url", &url)?;
+    repo.save_config()?;
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+    repo.fetch("origin")?;
+
+    // More than a round of haves the remote doesn't have
+    run_fun! {
+        cd $dst_s;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git checkout -q -b trunk origin/trunk;
+    }?;
+    for revision in 10..50 {
+        commit_revision(dst.path(), revision)?;
+    }
+    commit_revision(&src, 3)?;
+
+    let fetched = repo.fetch("origin")?;
+    assert_eq!(1, fetched.updated.len());
+    assert_eq!(
+        rev_parse(&src, "trunk")?,
+        rev_parse(dst.path(), "origin/trunk")?
+    );
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+    Ok(())
+}
+
+#[test]
+fn reports_fetch_progress() -> Result {
+    init();
+    let (_root, url) = served_source()?;
+    let (_dir, mut repo) = repo_fixture()?;
+    repo.config.set("remote.origin.url", &url)?;
+
+    let mut progress = RecordedProgress::default();
+    repo.fetch_with("origin", &FetchOptions::default(), &mut progress)?;
+    let received = progress.last(Stage::Receiving).expect("Received");
+    assert!(received.bytes > 0);
+    let resolved = progress.last(Stage::Resolving).expect("Resolved");
+    assert_eq!(Some(resolved.objects), resolved.total);
+    assert!(resolved.bytes > 0);
+    // Like `Total 9 (delta 2), reused 0 (delta 0)`
+    assert!(
+        progress
+            .messages
+            .iter()
+            .any(|msg| msg.starts_with(b"Total")),
+        "{:?}",
+        progress.messages
+    );
+    Ok(())
+}
+
+#[test]
+fn fetches_objects_from_url() -> Result {
+    init();
+    let (root, url) = served_source()?;
+    let (_dir, mut repo) = repo_fixture()?;
+
+    let fetched = repo.fetch(&url)?;
+    assert!(fetched.updated.is_empty());
+
+    let head = fetched
+        .refs
+        .iter()
+        .find(|remote_ref| remote_ref.name == "HEAD")
+        .expect("Listed HEAD");
+    assert_eq!(Some(BString::from("refs/heads/trunk")), head.symref_target);
+    assert_eq!(
+        rev_parse(&root.path().join("src"), "trunk")?,
+        head.oid.to_typed()
+    );
+
+    let commit = repo.db.load::<Commit>(head.oid.to_typed())?;
+    assert_eq!("Revision 2\n", commit.msg);
+    Ok(())
+}
+
+#[test]
+fn fetches_over_git_protocol() -> Result {
+    init();
+    let root = source()?;
+    let url = serve_git_daemon(root.path())?;
+    let dst = tempdir()?;
+    let dst_s = dst.path().to_str().unwrap();
+
+    let mut repo = Repo::init(dst.path())?;
+    repo.config
+        .set("remote.origin.url", &format!("{url}/src"))?;
+    repo.save_config()?;
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+
+    let fetched = repo.fetch("origin")?;
+    assert_eq!(2, fetched.updated.len());
+    let src = root.path().join("src");
+    assert_eq!(
+        rev_parse(&src, "trunk")?,
+        rev_parse(dst.path(), "origin/trunk")?
+    );
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+
+    commit_revision(&src, 3)?;
+    assert_eq!(1, repo.fetch("origin")?.updated.len());
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+    Ok(())
+}
+
+#[test]
+fn fetches_shallow_history() -> Result {
+    init();
+    let (root, url) = served_source()?;
+    let dst = tempdir()?;
+    let dst_s = dst.path().to_str().unwrap();
+
+    let mut repo = Repo::init(dst.path())?;
+    repo.config.set("remote.origin.url", &url)?;
+    repo.save_config()?;
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+
+    let options = FetchOptions {
+        depth: Some(Depth::Commits(1)),
+        ..FetchOptions::default()
+    };
+    repo.fetch_with("origin", &options, &mut ())?;
+    let trunk = rev_parse(&root.path().join("src"), "trunk")?;
+    let other = rev_parse(&root.path().join("src"), "other")?;
+    let mut shallow = [other.to_hex(), trunk.to_hex()];
+    shallow.sort();
+    assert_eq!(
+        format!("{}\n{}\n", shallow[0], shallow[1]),
+        fs::read_to_string(dst.path().join(".git/shallow"))?
+    );
+    assert_eq!("1", run_fun!(cd $dst_s; git rev-list --count origin/trunk)?);
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+
+    assert_eq!(None, repo.db.load(trunk)?.parent);
+    repo.checkout(trunk)?;
+    assert_eq!("2", fs::read_to_string(dst.path().join("dir/2.txt"))?);
+
+    // Reopened, the shallow commits are read back
+    let mut repo = Repo::new(dst.path())?;
+    assert_eq!(None, repo.db.load(trunk)?.parent);
+
+    // `other` is `trunk~1`, and deepening it reaches the root
+    let options = FetchOptions {
+        depth: Some(Depth::Deepen(1)),
+        ..FetchOptions::default()
+    };
+    repo.fetch_with("origin", &options, &mut ())?;
+    assert_eq!("3", run_fun!(cd $dst_s; git rev-list --count origin/trunk)?);
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+    assert_eq!(Some(other), repo.db.load(trunk)?.parent);
+    Ok(())
+}
+
+#[test]
+fn fetches_blobs_left_out_by_filter_when_checking_out() -> Result {
+    init();
+    let (root, url) = served_source()?;
+    let src = root.path().join("src");
+    let src_s = src.to_str().unwrap();
+    run_fun!(cd $src_s; git config uploadpack.allowFilter true)?;
+    let dst = tempdir()?;
+    let dst_s = dst.path().to_str().unwrap();
+
+    let mut repo = Repo::init(dst.path())?;
+    repo.config.set("remote.origin.url", &url)?;
+    repo.save_config()?;
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+
+    let options = FetchOptions {
+        filter: Some(Filter::BlobNone),
+        ..FetchOptions::default()
+    };
+    repo.fetch_with("origin", &options, &mut ())?;
+    let config = |name: &str| run_fun!(cd $dst_s; git config $name);
+    assert_eq!("origin", config("extensions.partialClone")?);
+    assert_eq!("true", config("remote.origin.promisor")?);
+    assert_eq!("blob:none", config("remote.origin.partialclonefilter")?);
+    let big = |rev: &str| -> eyre::Result<UntypedOid> {
+        let rev = format!("{rev}:big.txt");
+        Ok(UntypedOid::parse(run_fun!(cd $src_s; git rev-parse $rev)?)?)
+    };
+    assert!(!repo.db.contains(&big("trunk")?));
+    assert_eq!("3", run_fun!(cd $dst_s; git rev-list --count origin/trunk)?);
+
+    let trunk = rev_parse(&src, "trunk")?;
+    repo.checkout(trunk)?;
+    assert!(repo.db.contains(&big("trunk")?));
+    assert!(!repo.db.contains(&big("other")?));
+    assert_eq!("2", fs::read_to_string(dst.path().join("dir/2.txt"))?);
+    assert_eq!("", run_fun!(cd $dst_s; git status --porcelain)?);
+
+    // Later fetches are filtered too
+    commit_revision(&src, 3)?;
+    repo.fetch("origin")?;
+    assert!(!repo.db.contains(&big("trunk")?));
+    Ok(())
+}
+
+#[test]
+fn fetches_by_refspecs() -> Result {
+    init();
+    let (root, url) = served_source()?;
+    let src = root.path().join("src");
+    let src_s = src.to_str().unwrap();
+    let dst = tempdir()?;
+
+    let mut repo = Repo::init(dst.path())?;
+    repo.config.set("remote.origin.url", &url)?;
+    repo.config
+        .add("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?;
+    repo.config
+        .add("remote.origin.fetch", "^refs/heads/other")?;
+    repo.config
+        .add("remote.origin.fetch", "refs/heads/trunk:refs/heads/mirror")?;
+    repo.save_config()?;
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+
+    let fetched = repo.fetch("origin")?;
+    let trunk = rev_parse(&src, "trunk")?;
+    assert_eq!(
+        vec![
+            (BString::from("refs/remotes/origin/trunk"), None, trunk),
+            (BString::from("refs/heads/mirror"), None, trunk),
+        ],
+        fetched.updated
+    );
+    assert_eq!(trunk, rev_parse(dst.path(), "mirror")?);
+    assert!(!dst.path().join(".git/refs/remotes/origin/other").exists());
+
+    // Only the forced refspec allows rewriting history
+    run_fun!(cd $src_s; git reset -q --hard HEAD~1)?;
+    commit_revision(&src, 3)?;
+    let fetched = repo.fetch("origin")?;
+    let new_trunk = rev_parse(&src, "trunk")?;
+    assert_eq!(
+        vec![(
+            BString::from("refs/remotes/origin/trunk"),
+            Some(trunk),
+            new_trunk
+        )],
+        fetched.updated
+    );
+    assert_eq!(vec![BString::from("refs/heads/mirror")], fetched.rejected);
+    assert_eq!(trunk, rev_parse(dst.path(), "mirror")?);
+    Ok(())
+}
+
+#[test]
+fn prunes_deleted_remote_branches() -> Result {
+    init();
+    let (root, url) = served_source()?;
+    let src = root.path().join("src");
+    let src_s = src.to_str().unwrap();
+    let dst = tempdir()?;
+
+    let mut repo = Repo::init(dst.path())?;
+    repo.config.set("remote.origin.url", &url)?;
+    repo.save_config()?;
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+    repo.fetch("origin")?;
+    repo.refs.update_symbolic_ref(
+        b"refs/remotes/origin/HEAD".as_bstr(),
+        b"refs/remotes/origin/trunk".as_bstr(),
+    )?;
+    let other = rev_parse(dst.path(), "origin/other")?;
+
+    run_fun!(cd $src_s; git branch -q -D other)?;
+    assert!(repo.fetch("origin")?.pruned.is_empty());
+    assert_eq!(other, rev_parse(dst.path(), "origin/other")?);
+
+    repo.config.set("fetch.prune", "true")?;
+    let options = FetchOptions {
+        prune: Some(false),
+        ..FetchOptions::default()
+    };
+    assert!(repo
+        .fetch_with("origin", &options, &mut ())?
+        .pruned
+        .is_empty());
+
+    let fetched = repo.fetch("origin")?;
+    assert_eq!(
+        vec![(BString::from("refs/remotes/origin/other"), other)],
+        fetched.pruned
+    );
+    assert!(!dst.path().join(".git/refs/remotes/origin/other").exists());
+    assert!(dst.path().join(".git/refs/remotes/origin/HEAD").exists());
+    assert_eq!(
+        rev_parse(&src, "trunk")?,
+        rev_parse(dst.path(), "origin/trunk")?
+    );
+    Ok(())
+}
+
+#[test]
+fn follows_tags_into_fetched_history() -> Result {
+    init();
+    let (root, url) = served_source()?;
+    let src = root.path().join("src");
+    let src_s = src.to_str().unwrap();
+    let unrelated = run_fun! {
+        cd $src_s;
+        git tag -a -m v1 v1 HEAD~1;
+        git tag light;
+        git commit-tree "HEAD^{tree}" -m Unrelated;
+    }?;
+    run_fun!(cd $src_s; git tag unrelated $unrelated)?;
+    let dst = tempdir()?;
+
+    let mut repo = Repo::init(dst.path())?;
+    repo.config.set("remote.origin.url", &url)?;
+    repo.config.set("remote.origin.tagOpt", "--no-tags")?;
+    repo.save_config()?;
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+    repo.fetch("origin")?;
+    assert!(repo.refs.list(b"refs/tags/".as_bstr())?.is_empty());
+
+    // v1 and light point at what we have, and v2 at what we fetch
+    commit_revision(&src, 3)?;
+    run_fun!(cd $src_s; git tag -a -m v2 v2)?;
+    let options = FetchOptions {
+        tags: Some(Tags::Follow),
+        ..FetchOptions::default()
+    };
+    repo.fetch_with("origin", &options, &mut ())?;
+    let tags = repo.refs.list(b"refs/tags/".as_bstr())?;
+    let expected = ["light", "v1", "v2"]
+        .iter()
+        .map(|tag| Ok((format!("refs/tags/{tag}").into(), rev_parse(&src, tag)?)))
+        .collect::<eyre::Result<Vec<(BString, _)>>>()?;
+    assert_eq!(expected, tags);
+    let dst_s = dst.path().to_str().unwrap();
+    assert_eq!("tag", run_fun!(cd $dst_s; git cat-file -t v2)?);
+
+    run_fun!(cd $src_s; git tag -f light HEAD~2)?;
+    repo.config.set("remote.origin.tagOpt", "--tags")?;
+    let fetched = repo.fetch("origin")?;
+    assert_eq!(vec![BString::from("refs/tags/light")], fetched.rejected);
+    assert_eq!(
+        rev_parse(&src, "unrelated")?,
+        rev_parse(dst.path(), "unrelated")?
+    );
+    Ok(())
+}
+
+#[test]
+fn reports_unsupported_urls() {
+    init();
+    let (_dir, mut repo) = repo_fixture().unwrap();
+    assert!(repo.fetch("ftp://example.com/repo").is_err());
+}
@@ -0,0 +1,51 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::{core::db::HashOptions, Error, ErrorKind};
+
+#[test]
+fn hashes_like_git() -> Result {
+    init();
+    let (dir, repo) = repo_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+    let hash = |o_type: &str, content: &str, options: HashOptions| {
+        repo.db
+            .hash_object(o_type.as_bytes(), content.as_bytes(), options)
+    };
+
+    let content = "some contents\n";
+    let blob = hash("blob", content, HashOptions::default())?;
+    let expected = run_fun!(cd $dir_s; echo "some contents" | git hash-object --stdin)?;
+    assert_eq!(expected, blob.to_string());
+    assert!(!repo.db.contains(&blob));
+
+    let write = HashOptions {
+        write: true,
+        ..HashOptions::default()
+    };
+    let blob = hash("blob", content, write)?;
+    assert!(repo.db.contains(&blob));
+    let blob_s = blob.to_string();
+    assert_eq!(
+        "some contents",
+        run_fun!(cd $dir_s; git cat-file -p $blob_s)?
+    );
+
+    let err = hash("commit", "not a commit", write).unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, Error::from(err).kind());
+    assert!(hash("bogus", content, write).is_err());
+
+    let literally = HashOptions {
+        literally: true,
+        ..write
+    };
+    let bogus = hash("bogus", content, literally)?;
+    let expected = run_fun! {
+        cd $dir_s;
+        echo "some contents" | git hash-object -t bogus --literally --stdin
+    }?;
+    assert_eq!(expected, bogus.to_string());
+    assert!(repo.db.contains(&bogus));
+
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use bstr::ByteSlice;
+use writ::core::{
+    db::Blob,
+    index::{Conflict, Entry, Stage},
+    ListedFile, LsFilesOptions, Pathspecs, Stat, WsPath,
+};
+
+fn listed(repo: &mut Repo, options: &LsFilesOptions) -> eyre::Result<Vec<String>> {
+    Ok(repo
+        .ls_files(options)?
+        .map(|file| {
+            let kind = match &file {
+                ListedFile::Cached(_) => "cached",
+                ListedFile::Deleted(_) => "deleted",
+                ListedFile::Modified(_) => "modified",
+                ListedFile::Other(_) => "other",
+            };
+            format!("{} {}", kind, file.path())
+        })
+        .collect())
+}
+
+#[test]
+fn lists_index_and_workspace() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join("a.txt"), "a")?;
+    write_to(dir.join("b.txt"), "b")?;
+    write_to(dir.join("dir/c.txt"), "c")?;
+    repo.add(["."])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+
+    write_to(dir.join("a.txt"), "changed")?;
+    std::fs::remove_file(dir.join("b.txt"))?;
+    write_to(dir.join("new.txt"), "new")?;
+    write_to(dir.join(".gitignore"), "*.log\n")?;
+    write_to(dir.join("debug.log"), "")?;
+
+    assert_eq!(
+        vec!["cached a.txt", "cached b.txt", "cached dir/c.txt"],
+        listed(&mut repo, &LsFilesOptions::default())?
+    );
+
+    let options = LsFilesOptions {
+        cached: true,
+        deleted: true,
+        modified: true,
+        others: true,
+        ..LsFilesOptions::default()
+    };
+    assert_eq!(
+        vec![
+            "other .gitignore",
+            "cached a.txt",
+            "modified a.txt",
+            "cached b.txt",
+            "deleted b.txt",
+            "modified b.txt",
+            "cached dir/c.txt",
+            "other new.txt",
+        ],
+        listed(&mut repo, &options)?
+    );
+
+    let options = LsFilesOptions {
+        others: true,
+        ignored: true,
+        ..LsFilesOptions::default()
+    };
+    assert_eq!(vec!["other debug.log"], listed(&mut repo, &options)?);
+
+    let pathspecs = Pathspecs::parse(["dir"])?;
+    let files = repo
+        .ls_files_matching(&pathspecs, &LsFilesOptions::default())?
+        .collect::<Vec<_>>();
+    assert_eq!(1, files.len());
+    assert_eq!("dir/c.txt", files[0].path());
+
+    Ok(())
+}
+
+#[test]
+fn lists_stages_of_unmerged_paths() -> Result {
+    init();
+    let (_dir, mut repo) = repo_fixture()?;
+
+    let entry = |contents: &str| {
+        let oid = Blob::oid_for_file(contents.as_bytes().as_bstr());
+        Entry::new(WsPath::new_unchecked("1.txt"), oid, Stat::zeroed())
+    };
+    let mut index = repo.index.modify()?;
+    index.add_conflict(
+        &WsPath::new_unchecked("1.txt"),
+        Conflict {
+            base: Some(entry("base")),
+            ours: Some(entry("ours")),
+            theirs: Some(entry("theirs")),
+        },
+    );
+    index.commit()?;
+
+    assert_eq!(
+        vec!["cached 1.txt"],
+        listed(&mut repo, &LsFilesOptions::default())?
+    );
+
+    let options = LsFilesOptions {
+        stage: true,
+        ..LsFilesOptions::default()
+    };
+    let stages = repo
+        .ls_files(&options)?
+        .map(|file| match file {
+            ListedFile::Cached(file) => Ok((file.stage, file.oid)),
+            other => Err(eyre::eyre!("Expected cached, got {:?}", other)),
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+    assert_eq!(
+        vec![
+            (Stage::Base, entry("base").oid),
+            (Stage::Ours, entry("ours").oid),
+            (Stage::Theirs, entry("theirs").oid),
+        ],
+        stages
+    );
+
+    Ok(())
+}
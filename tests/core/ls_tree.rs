@@ -0,0 +1,94 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use bstr::ByteSlice;
+use writ::core::{db::UntypedOid, ListedNode, LsTreeOptions, Pathspecs};
+
+fn format(node: &ListedNode) -> String {
+    let size = node.size.map_or("-".to_string(), |size| size.to_string());
+    format!(
+        "{} {} {} {:>7}\t{}",
+        node.mode_base8(),
+        node.object_type.name().as_bstr(),
+        node.oid,
+        size,
+        node.path
+    )
+}
+
+#[test]
+fn lists_like_git() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+
+    write_to(dir.path().join("a.txt"), "a")?;
+    write_to(dir.path().join("dir/b.txt"), "bb")?;
+    write_to(dir.path().join("dir/sub/c.txt"), "ccc")?;
+    repo.add(["."])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let head = repo.refs.head()?.unwrap().into_untyped();
+
+    let mut ls_tree = |specs: &[&str], options: LsTreeOptions| -> eyre::Result<Vec<String>> {
+        let pathspecs = Pathspecs::parse(specs)?;
+        Ok(repo
+            .ls_tree(head, &pathspecs, &options)?
+            .map(|node| format(&node))
+            .collect())
+    };
+    let git = |args: &[&str]| -> eyre::Result<Vec<String>> {
+        let out = std::process::Command::new("git")
+            .current_dir(dir_s)
+            .arg("ls-tree")
+            .args(args)
+            .output()?;
+        let out = String::from_utf8(out.stdout)?;
+        Ok(out.lines().map(str::to_string).collect())
+    };
+
+    let long = |options: LsTreeOptions| LsTreeOptions {
+        long: true,
+        ..options
+    };
+    let recursive = LsTreeOptions {
+        recursive: true,
+        ..LsTreeOptions::default()
+    };
+    let trees_only = LsTreeOptions {
+        trees_only: true,
+        ..LsTreeOptions::default()
+    };
+
+    assert_eq!(
+        git(&["-l", "HEAD"])?,
+        ls_tree(&[], long(LsTreeOptions::default()))?
+    );
+    assert_eq!(git(&["-l", "-r", "HEAD"])?, ls_tree(&[], long(recursive))?);
+    assert_eq!(git(&["-l", "-d", "HEAD"])?, ls_tree(&[], long(trees_only))?);
+    assert_eq!(
+        git(&["-l", "HEAD", "dir/sub/c.txt"])?,
+        ls_tree(&["dir/sub/c.txt"], long(LsTreeOptions::default()))?
+    );
+    assert_eq!(
+        git(&["-l", "-r", "HEAD", "dir"])?,
+        ls_tree(&["dir"], long(recursive))?
+    );
+
+    let tree = repo.db.load(repo.refs.head()?.unwrap())?.tree;
+    let listed = repo.ls_tree(
+        tree.into_untyped(),
+        &Pathspecs::parse(["a.txt"])?,
+        &LsTreeOptions::default(),
+    )?;
+    assert_eq!(
+        vec!["a.txt"],
+        listed.map(|node| node.path.to_string()).collect::<Vec<_>>()
+    );
+
+    let blob = UntypedOid::parse(run_fun!(cd $dir_s; git rev-parse HEAD:a.txt)?)?;
+    assert!(repo
+        .ls_tree(blob, &Pathspecs::parse(["a.txt"])?, &recursive)
+        .is_err());
+
+    Ok(())
+}
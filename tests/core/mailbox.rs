@@ -0,0 +1,145 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::path::Path;
+
+use writ::core::FormatPatchOptions;
+
+/// `topic` has a commit by an author with a non-ASCII name that changes,
+/// adds and removes files, and one that makes a file executable and takes
+/// the newline off the end of another
+fn branches(dir: &Path) -> Result {
+    let dir_s = dir.to_str().unwrap();
+    let lines = (1..=20).map(|n| format!("{n}\n")).collect::<String>();
+    write_to(dir.join("a.txt"), &lines)?;
+    write_to(dir.join("b.txt"), "b\n")?;
+    write_to(dir.join("run.sh"), "echo run\n")?;
+    run_fun! {
+        cd $dir_s;
+        git init -q -b main;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git add .;
+        git commit -q -m "Root";
+        git checkout -q -b topic;
+        git rm -q b.txt;
+    }?;
+    write_to(dir.join("a.txt"), lines.replace("\n3\n", "\nthree\n"))?;
+    write_to(dir.join("dir/c.txt"), "c\n")?;
+    run_fun! {
+        cd $dir_s;
+        git add .;
+        git -c "user.name=Jörg Müller" commit -q -m "Change a, remove b, add c" -m "Because.";
+        chmod +x run.sh;
+    }?;
+    write_to(
+        dir.join("a.txt"),
+        lines.replace("\n3\n", "\nthree\n") + "21",
+    )?;
+    run_fun!(cd $dir_s; git commit -q -am "Make run executable")?;
+    Ok(())
+}
+
+fn clone(src: &Path, dst: &Path) -> Result {
+    let (src_s, dst_s) = (src.to_str().unwrap(), dst.to_str().unwrap());
+    run_fun! {
+        git clone -q -b main $src_s $dst_s;
+        cd $dst_s;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    Ok(())
+}
+
+fn mbox(repo: &mut Repo) -> eyre::Result<Vec<u8>> {
+    let range = repo.rev_range(["main..topic"])?;
+    let patches = repo.format_patch(&range, &FormatPatchOptions::default())?;
+    Ok(patches
+        .into_iter()
+        .flat_map(|patch| Vec::from(patch.mail))
+        .collect())
+}
+
+#[test]
+fn formats_patches_git_applies() -> Result {
+    init();
+    let src = tempdir()?;
+    branches(src.path())?;
+    let mut repo = Repo::new(src.path())?;
+
+    let range = repo.rev_range(["main..topic"])?;
+    let patches = repo.format_patch(&range, &FormatPatchOptions::default())?;
+    let names = patches
+        .iter()
+        .map(|patch| patch.file_name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            "0001-Change-a-remove-b-add-c.patch",
+            "0002-Make-run-executable.patch"
+        ],
+        names
+    );
+    let first = patches[0].mail.to_string();
+    assert!(first.contains("From: =?UTF-8?q?J=C3=B6rg_M=C3=BCller?="));
+    assert!(first.contains("Subject: [PATCH 1/2] Change a, remove b, add c\n"));
+    assert!(first.contains(" 3 files changed, 2 insertions(+), 2 deletions(-)\n"));
+
+    let dst = tempdir()?;
+    clone(src.path(), dst.path())?;
+    write_to(dst.path().join("series.mbox"), mbox(&mut repo)?)?;
+    let (src_s, dst_s) = (src.path().to_str().unwrap(), dst.path().to_str().unwrap());
+    run_fun!(cd $dst_s; git am -q series.mbox)?;
+    assert_eq!(
+        run_fun!(cd $src_s; git log "--format=%T %an %s %b" topic)?,
+        run_fun!(cd $dst_s; git log "--format=%T %an %s %b" HEAD)?
+    );
+    Ok(())
+}
+
+#[test]
+fn applies_patches_git_formats() -> Result {
+    init();
+    let src = tempdir()?;
+    branches(src.path())?;
+    let src_s = src.path().to_str().unwrap();
+    let mbox = run_fun!(cd $src_s; git format-patch -q --stdout main..topic)?;
+
+    let dst = tempdir()?;
+    clone(src.path(), dst.path())?;
+    let mut repo = Repo::new(dst.path())?;
+    let made = repo.am(mbox.as_bytes())?;
+    assert_eq!(2, made.len());
+
+    let dst_s = dst.path().to_str().unwrap();
+    assert_eq!(
+        run_fun!(cd $src_s; git log "--format=%T %an %ae %ad %s %b" topic)?,
+        run_fun!(cd $dst_s; git log "--format=%T %an %ae %ad %s %b" HEAD)?
+    );
+    assert_eq!("", run_fun!(cd $dst_s; git status --porcelain)?);
+    Ok(())
+}
+
+#[test]
+fn round_trips_and_refuses_patches_that_do_not_apply() -> Result {
+    init();
+    let src = tempdir()?;
+    branches(src.path())?;
+    let mut repo = Repo::new(src.path())?;
+    let mbox = mbox(&mut repo)?;
+
+    let dst = tempdir()?;
+    clone(src.path(), dst.path())?;
+    let mut other = Repo::new(dst.path())?;
+    other.am(mbox.as_slice())?;
+    let (src_s, dst_s) = (src.path().to_str().unwrap(), dst.path().to_str().unwrap());
+    assert_eq!(
+        run_fun!(cd $src_s; git rev-parse "topic^{tree}")?,
+        run_fun!(cd $dst_s; git rev-parse "HEAD^{tree}")?
+    );
+
+    let head = run_fun!(cd $dst_s; git rev-parse HEAD)?;
+    assert!(other.am(mbox.as_slice()).is_err());
+    assert_eq!(head, run_fun!(cd $dst_s; git rev-parse HEAD)?);
+    Ok(())
+}
@@ -0,0 +1,326 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::{fs, os::unix::fs::PermissionsExt};
+
+use bstr::ByteSlice;
+
+use writ::core::{
+    db::{BatchOptions, Batched, UntypedOid},
+    Fsync, Status, WsPath,
+};
+
+#[test]
+fn packs_reachable_objects_and_prunes_the_rest() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    run_fun! {
+        cd $dir_s;
+        git init -q;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    let mut repo = Repo::new(dir.path())?;
+    for contents in ["first", "second"] {
+        write_to(dir.path().join("file.txt"), contents)?;
+        repo.add(["file.txt"])?;
+        repo.commit(NAME, EMAIL, MSG)?;
+    }
+    write_to(dir.path().join("file.txt"), "third")?;
+    run_fun!(cd $dir_s; git commit -q -am $MSG)?;
+    let unreachable = run_fun!(cd $dir_s; echo unreachable | git hash-object -w --stdin)?;
+    let unreachable = UntypedOid::parse(unreachable)?;
+    let log = run_fun!(cd $dir_s; git log --format=%H)?;
+
+    assert_eq!(None, repo.maintenance_run(true)?);
+
+    let maintained = repo.maintenance_run(false)?.unwrap();
+    let pack = maintained.pack.unwrap();
+    assert_eq!(0, maintained.expired_reflog_entries);
+    let pack_s = pack.to_str().unwrap();
+    run_fun! {
+        cd $dir_s;
+        git verify-pack $pack_s;
+        git fsck --strict;
+    }?;
+    assert_eq!(log, run_fun!(cd $dir_s; git log --format=%H)?);
+    assert!(repo.db.contains(&unreachable), "Not old enough to prune");
+
+    let mut repo = Repo::new(dir.path())?;
+    let head = repo.refs.head()?.unwrap();
+    let tree = repo.db.load(head)?.tree;
+    let blob = repo
+        .db
+        .load_tree_file(tree, &WsPath::new_unchecked("file.txt"))?
+        .unwrap()
+        .oid;
+    assert_eq!("third", repo.db.load(blob)?.bytes);
+
+    repo.config.set("gc.pruneExpire", "now")?;
+    repo.config.set("gc.reflogExpire", "now")?;
+    repo.save_config()?;
+    let maintained = repo.maintenance_run(false)?.unwrap();
+    assert!(maintained.expired_reflog_entries > 0);
+    assert!(!repo.db.contains(&unreachable));
+    assert_eq!(log, run_fun!(cd $dir_s; git log --format=%H)?);
+    run_fun!(cd $dir_s; git fsck --strict)?;
+    Ok(())
+}
+
+#[test]
+fn runs_automatically_when_there_are_too_many_packs() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    run_fun! {
+        cd $dir_s;
+        git init -q;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git config gc.autoPackLimit 1;
+    }?;
+    for contents in ["first", "second"] {
+        write_to(dir.path().join("file.txt"), contents)?;
+        run_fun! {
+            cd $dir_s;
+            git add file.txt;
+            git commit -q -m $MSG;
+            git repack -q -d;
+        }?;
+    }
+    let mut repo = Repo::new(dir.path())?;
+    assert_eq!(2, repo.db.packs().len());
+    assert!(repo.needs_maintenance()?);
+
+    repo.maintenance_run(true)?.unwrap();
+    assert_eq!(1, repo.db.packs().len());
+    assert!(!repo.needs_maintenance()?);
+    let packs = fs::read_dir(dir.path().join(".git/objects/pack"))?.count();
+    assert_eq!(2, packs, "A pack and its index");
+    run_fun!(cd $dir_s; git fsck --strict)?;
+    Ok(())
+}
+
+#[test]
+fn reads_packs_git_made() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    run_fun! {
+        cd $dir_s;
+        git init -q;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    for contents in ["first", "first and second"] {
+        write_to(dir.path().join("file.txt"), contents)?;
+        run_fun! {
+            cd $dir_s;
+            git add file.txt;
+            git commit -q -m $MSG;
+        }?;
+    }
+    run_fun!(cd $dir_s; git repack -q -a -d)?;
+
+    let mut repo = Repo::new(dir.path())?;
+    assert_eq!(1, repo.db.packs().len());
+    let head = repo.refs.head()?.unwrap();
+    let commit = repo.db.load(head)?;
+    let tree = repo.db.load(commit.tree)?;
+    assert_eq!(1, tree.direct_children().count());
+    assert!(repo.db.load(commit.parent.unwrap()).is_ok());
+    assert!(repo
+        .status()?
+        .values()
+        .all(|s| s.workspace == Status::Unmodified && s.index == Status::Unmodified));
+    Ok(())
+}
+
+#[test]
+fn verifies_packs_like_git() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    run_fun! {
+        cd $dir_s;
+        git init -q;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    let mut contents = String::new();
+    for line in 0..50 {
+        contents.push_str(&format!("line {}\n", line));
+        write_to(dir.path().join("file.txt"), &contents)?;
+        run_fun! {
+            cd $dir_s;
+            git add file.txt;
+            git commit -q -m $MSG;
+        }?;
+    }
+    run_fun!(cd $dir_s; git repack -q -a -d -f --depth=10)?;
+
+    let repo = Repo::new(dir.path())?;
+    let pack = repo.db.packs()[0].path().to_owned();
+    let mut ours = repo
+        .db
+        .verify_pack(&pack)?
+        .into_iter()
+        .map(|object| {
+            let mut line = format!(
+                "{} {} {} {} {}",
+                object.oid,
+                object.object_type.name().as_bstr(),
+                object.size,
+                object.packed_size,
+                object.offset
+            );
+            if let Some(base) = object.base {
+                line.push_str(&format!(" {} {}", object.depth, base));
+            }
+            line
+        })
+        .collect::<Vec<_>>();
+    ours.sort();
+    assert!(ours.iter().any(|line| line.split(' ').count() == 7));
+
+    let pack_s = pack.to_str().unwrap();
+    let mut theirs = run_fun!(cd $dir_s; git verify-pack -v $pack_s)?
+        .lines()
+        .filter(|line| line.len() > 40 && line[..40].bytes().all(|b| b.is_ascii_hexdigit()))
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>();
+    theirs.sort();
+    assert_eq!(theirs, ours);
+
+    let mut corrupted = fs::read(&pack)?;
+    let middle = corrupted.len() / 2;
+    corrupted[middle] ^= 0xff;
+    fs::set_permissions(&pack, fs::Permissions::from_mode(0o644))?;
+    fs::write(&pack, corrupted)?;
+    assert!(repo.db.verify_pack(&pack).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn batches_objects_like_git() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    run_fun! {
+        cd $dir_s;
+        git init -q;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    let mut contents = String::new();
+    for line in 0..20 {
+        contents.push_str(&format!("line {}\n", line));
+        write_to(dir.path().join("file.txt"), &contents)?;
+        run_fun! {
+            cd $dir_s;
+            git add file.txt;
+            git commit -q -m $MSG;
+        }?;
+        if line == 15 {
+            run_fun!(cd $dir_s; git repack -q -a -d)?;
+        }
+    }
+
+    let all = run_fun!(cd $dir_s; git rev-list --objects --all)?;
+    let mut oids = all
+        .lines()
+        .map(|line| UntypedOid::parse(&line[..40]))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let missing = UntypedOid::parse("0123456789012345678901234567890123456789")?;
+    oids.push(missing);
+
+    let repo = Repo::new(dir.path())?;
+    let mut ours = repo
+        .db
+        .batch(oids.iter().copied(), BatchOptions::default())
+        .map(|batched| {
+            Ok(match batched? {
+                Batched::Found(object) => {
+                    assert_eq!(None, object.contents);
+                    format!(
+                        "{} {} {}",
+                        object.oid,
+                        object.object_type.name().as_bstr(),
+                        object.size
+                    )
+                }
+                Batched::Missing(oid) => format!("{} missing", oid),
+            })
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+    ours.sort();
+
+    let input = oids
+        .iter()
+        .map(|oid| format!("{}\n", oid))
+        .collect::<String>();
+    let mut git = std::process::Command::new("git")
+        .current_dir(dir_s)
+        .args(["cat-file", "--batch-check"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    std::io::Write::write_all(&mut git.stdin.take().unwrap(), input.as_bytes())?;
+    let out = String::from_utf8(git.wait_with_output()?.stdout)?;
+    let mut theirs = out.lines().map(str::to_string).collect::<Vec<_>>();
+    theirs.sort();
+    assert_eq!(theirs, ours);
+
+    let blob = UntypedOid::parse(run_fun!(cd $dir_s; git rev-parse HEAD~6:file.txt)?)?;
+    let options = BatchOptions { contents: true };
+    let batched = repo
+        .db
+        .batch([blob], options)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let blob_s = blob.to_string();
+    let expected = run_fun!(cd $dir_s; git cat-file -p $blob_s)? + "\n";
+    match &batched[..] {
+        [Batched::Found(object)] => {
+            assert_eq!(Some(expected.as_bytes()), object.contents.as_deref());
+        }
+        other => panic!("{:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn syncs_writes_as_configured() -> Result {
+    init();
+    let (dir, repo) = repo_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+    assert_eq!(Fsync::default(), repo.db.fsync());
+
+    run_fun!(cd $dir_s; git config core.fsync "none,loose-object, reference")?;
+    let repo = Repo::new(dir.path())?;
+    let expected = Fsync {
+        loose_objects: true,
+        refs: true,
+        ..Fsync::NONE
+    };
+    assert_eq!(expected, repo.db.fsync());
+
+    // Syncing everything still writes what git reads
+    run_fun!(cd $dir_s; git config core.fsync all)?;
+    let mut repo = Repo::new(dir.path())?;
+    assert_eq!(Fsync::ALL, repo.db.fsync());
+    write_to(dir.path().join("file.txt"), "contents")?;
+    repo.add(["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let pack = repo.maintenance_run(false)?.unwrap().pack.unwrap();
+    let pack_s = pack.to_str().unwrap();
+    run_fun! {
+        cd $dir_s;
+        git verify-pack $pack_s;
+        git fsck --strict;
+        git diff-index --quiet --cached HEAD;
+    }?;
+    Ok(())
+}
@@ -0,0 +1,317 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::{fs, path::Path, process::Command};
+
+use writ::core::{
+    db::{Commit, Signature},
+    merge::{Favor, FileMergeOptions, MergeOptions, Merged, Strategy},
+    Cleanup, CommitOptions, Oid, Status, WsPath,
+};
+
+#[test]
+fn merges_text_like_git() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    let base = "one\ntwo\nthree\nfour\nfive\nsix\n";
+    let ours = "one\nTWO\nthree\nfour\nsame\nours\n";
+    let theirs = "zero\none\ntwo\nthree\nsame\ntheirs\n";
+    for (name, content) in [("base", base), ("ours", ours), ("theirs", theirs)] {
+        write_to(dir.path().join(name), content)?;
+    }
+    run_fun!(cd $dir_s; git init -q)?;
+    let mut repo = Repo::new(dir.path())?;
+
+    for style in ["merge", "diff3", "zdiff3"] {
+        let output = Command::new("git")
+            .args([
+                "merge-file",
+                "-p",
+                "-L",
+                "ours",
+                "-L",
+                "base",
+                "-L",
+                "theirs",
+            ])
+            .args((style != "merge").then(|| format!("--{style}")))
+            .args(["ours", "base", "theirs"])
+            .current_dir(dir.path())
+            .output()?;
+        assert_eq!(Some(1), output.status.code(), "One conflict");
+
+        repo.config.set("merge.conflictStyle", style)?;
+        let merged = repo.merge_file(
+            &WsPath::new_unchecked("file"),
+            base.as_bytes(),
+            ours.as_bytes(),
+            theirs.as_bytes(),
+            &FileMergeOptions::from_config(&repo.config)?,
+        )?;
+        assert_eq!(1, merged.conflicts);
+        assert_eq!(
+            String::from_utf8(output.stdout)?,
+            String::from_utf8(merged.data)?,
+            "{style}"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn merges_with_drivers_from_attributes() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    let changelog = dir.path().join("CHANGELOG");
+    write_to(
+        dir.path().join(".gitattributes"),
+        "CHANGELOG merge=changelog\n",
+    )?;
+    write_to(&changelog, "1.0\n")?;
+    // Puts the new top lines of both sides above the base
+    let driver = "(head -n1 %A; head -n1 %B; cat %O) >%A.new && mv %A.new %A";
+    run_fun! {
+        cd $dir_s;
+        git init -q -b main;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git config merge.changelog.name "Changelog merging";
+        git config merge.changelog.driver $driver;
+        git add .;
+        git commit -q -m $MSG;
+        git checkout -q -b side;
+        sed -i "1i side" CHANGELOG;
+        git commit -q -am $MSG;
+        git checkout -q main;
+        sed -i "1i main" CHANGELOG;
+        git commit -q -am $MSG;
+    }?;
+
+    let repo = Repo::new(dir.path())?;
+    let merged = repo.merge_file(
+        &WsPath::new_unchecked("CHANGELOG"),
+        b"1.0\n",
+        b"main\n1.0\n",
+        b"side\n1.0\n",
+        &FileMergeOptions::default(),
+    )?;
+    assert!(merged.is_clean());
+    assert_eq!("main\nside\n1.0\n", String::from_utf8(merged.data)?);
+
+    // Git runs the driver the same way
+    run_fun!(cd $dir_s; git merge -q --no-edit side)?;
+    assert_eq!("main\nside\n1.0\n", fs::read_to_string(&changelog)?);
+    Ok(())
+}
+
+/// `main` and `side` both change `both.txt` from the root commit, `side` with
+/// the sed expression, and `side` changes and deletes files of its own
+fn branches(dir: &Path, side_edit: &str) -> Result {
+    let dir_s = dir.to_str().unwrap();
+    write_to(dir.join("both.txt"), "one\ntwo\nthree\nfour\n")?;
+    write_to(dir.join("main.txt"), "main\n")?;
+    write_to(dir.join("side.txt"), "side\n")?;
+    run_fun! {
+        cd $dir_s;
+        git init -q -b main;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git add .;
+        git commit -q -m $MSG;
+        git checkout -q -b side;
+        sed -i $side_edit both.txt;
+        sed -i "s/$/, changed/" side.txt;
+        git rm -q main.txt;
+        git commit -q -am $MSG;
+        git checkout -q main;
+        sed -i "s/one/ONE/" both.txt;
+        git commit -q -am $MSG;
+    }?;
+    Ok(())
+}
+
+fn rev(dir: &Path, rev: &str) -> eyre::Result<Oid<Commit>> {
+    let dir_s = dir.to_str().unwrap();
+    Ok(Oid::parse(run_fun!(cd $dir_s; git rev-parse $rev)?)?)
+}
+
+#[test]
+fn merges_branches_like_git() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    branches(dir.path(), "s/four/FOUR/")?;
+    let (main, side) = (rev(dir.path(), "main")?, rev(dir.path(), "side")?);
+
+    let mut repo = Repo::new(dir.path())?;
+    let merged = repo.merge(side, &MergeOptions::default())?;
+    let head = rev(dir.path(), "HEAD")?;
+    assert_eq!(Merged::Commit(head), merged);
+    let commit = repo.db.load(head)?;
+    assert_eq!((Some(main), vec![side]), (commit.parent, commit.merged));
+    assert_eq!(
+        "ONE\ntwo\nthree\nFOUR\n",
+        fs::read_to_string(dir.path().join("both.txt"))?
+    );
+    assert!(!dir.path().join("main.txt").exists());
+    assert!(repo
+        .status()?
+        .values()
+        .all(|s| (s.index, s.workspace) == (Status::Unmodified, Status::Unmodified)));
+    assert_eq!(
+        Merged::UpToDate,
+        repo.merge(side, &MergeOptions::default())?
+    );
+
+    run_fun! {
+        cd $dir_s;
+        git checkout -q -b by-git main;
+        git merge -q --no-edit side;
+    }?;
+    assert_eq!(
+        run_fun!(cd $dir_s; git rev-parse "by-git^{tree}")?,
+        run_fun!(cd $dir_s; git rev-parse "main^{tree}")?
+    );
+    Ok(())
+}
+
+#[test]
+fn fast_forwards() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    branches(dir.path(), "s/four/FOUR/")?;
+    run_fun!(cd $dir_s; git checkout -q -b behind main~)?;
+    let main = rev(dir.path(), "main")?;
+
+    let mut repo = Repo::new(dir.path())?;
+    assert_eq!(
+        Merged::FastForward(main),
+        repo.merge(main, &MergeOptions::default())?
+    );
+    assert_eq!(main, rev(dir.path(), "behind")?);
+    Ok(())
+}
+
+#[test]
+fn leaves_conflicts_to_resolve() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    branches(dir.path(), "s/one/first/")?;
+    let (main, side) = (rev(dir.path(), "main")?, rev(dir.path(), "side")?);
+
+    let mut repo = Repo::new(dir.path())?;
+    let options = MergeOptions {
+        file: FileMergeOptions {
+            ours_label: "HEAD".into(),
+            theirs_label: "side".into(),
+            ..FileMergeOptions::default()
+        },
+        ..MergeOptions::default()
+    };
+    assert_eq!(
+        Merged::Conflicts(vec![WsPath::new_unchecked("both.txt")]),
+        repo.merge(side, &options)?
+    );
+    assert_eq!(main, rev(dir.path(), "HEAD")?, "Nothing committed");
+    assert_eq!(
+        "side, changed\n",
+        fs::read_to_string(dir.path().join("side.txt"))?
+    );
+    let ours = fs::read_to_string(dir.path().join("both.txt"))?;
+    assert_eq!(3, run_fun!(cd $dir_s; git ls-files -u)?.lines().count());
+
+    // Git leaves the same conflict
+    run_fun!(cd $dir_s; git reset -q --hard)?;
+    assert!(run_fun!(cd $dir_s; git merge -q side).is_err());
+    assert_eq!(fs::read_to_string(dir.path().join("both.txt"))?, ours);
+    Ok(())
+}
+
+#[test]
+fn concludes_merges_once_resolved() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    branches(dir.path(), "s/one/first/")?;
+    let (main, side) = (rev(dir.path(), "main")?, rev(dir.path(), "side")?);
+
+    let mut repo = Repo::new(dir.path())?;
+    let merged = repo.merge(side, &MergeOptions::default())?;
+    assert!(matches!(merged, Merged::Conflicts(_)));
+    assert_eq!(side, rev(dir.path(), "MERGE_HEAD")?);
+    assert_eq!(main, rev(dir.path(), "ORIG_HEAD")?);
+    let msg = format!("Merge commit '{}'\n", side.to_hex());
+    assert_eq!(
+        format!("{msg}\n# Conflicts:\n#\tboth.txt\n"),
+        fs::read_to_string(dir.path().join(".git/MERGE_MSG"))?
+    );
+    assert_eq!(
+        Some(fs::read_to_string(dir.path().join(".git/MERGE_MSG"))?),
+        repo.initial_commit_message()?
+    );
+
+    write_to(dir.path().join("both.txt"), "resolved\n")?;
+    repo.add(["both.txt"])?;
+    assert!(repo.merge(side, &MergeOptions::default()).is_err());
+    let signature = Signature::new_local(NAME, EMAIL, chrono::Local::now());
+    let options = CommitOptions {
+        cleanup: Some(Cleanup::Strip),
+        ..CommitOptions::default()
+    };
+    let initial = repo.initial_commit_message()?.expect("Merging");
+    repo.commit_as_with(signature.clone(), signature, initial, &options)?;
+    let commit = repo.db.load(rev(dir.path(), "HEAD")?)?;
+    assert_eq!((Some(main), vec![side]), (commit.parent, commit.merged));
+    assert_eq!(msg, commit.msg);
+    assert!(run_fun!(cd $dir_s; git rev-parse -q --verify MERGE_HEAD).is_err());
+    assert!(!dir.path().join(".git/MERGE_MSG").exists());
+    Ok(())
+}
+
+#[test]
+fn favors_sides_and_strategies() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    branches(dir.path(), "s/one/first/")?;
+    let (main, side) = (rev(dir.path(), "main")?, rev(dir.path(), "side")?);
+
+    let mut repo = Repo::new(dir.path())?;
+    let theirs = MergeOptions {
+        file: FileMergeOptions {
+            favor: Some(Favor::Theirs),
+            ..FileMergeOptions::default()
+        },
+        ..MergeOptions::default()
+    };
+    assert!(matches!(repo.merge(side, &theirs)?, Merged::Commit(_)));
+    assert_eq!(
+        "first\ntwo\nthree\nfour\n",
+        fs::read_to_string(dir.path().join("both.txt"))?
+    );
+    assert_eq!(
+        "side, changed\n",
+        fs::read_to_string(dir.path().join("side.txt"))?
+    );
+
+    let main_hex = main.to_hex();
+    run_fun!(cd $dir_s; git reset -q --hard $main_hex)?;
+    let ours = MergeOptions {
+        strategy: Strategy::Ours,
+        message: Some("Keep ours".into()),
+        ..MergeOptions::default()
+    };
+    let Merged::Commit(commit) = repo.merge(side, &ours)? else {
+        panic!("Didn't commit");
+    };
+    let commit = repo.db.load(commit)?;
+    assert_eq!(repo.db.load(main)?.tree, commit.tree);
+    assert_eq!(vec![side], commit.merged);
+    assert_eq!("Keep ours\n", commit.msg);
+    assert_eq!("side\n", fs::read_to_string(dir.path().join("side.txt"))?);
+    Ok(())
+}
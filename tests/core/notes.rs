@@ -0,0 +1,69 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::collections::BTreeMap;
+
+use writ::core::notes::NotesError;
+
+#[test]
+fn adds_and_removes_notes() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    run_fun! {
+        cd $dir_s;
+        git init -q;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    let mut repo = Repo::new(dir.path())?;
+    write_to(dir.path().join("file.txt"), "first")?;
+    repo.add(["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let first = repo.refs.head()?.unwrap();
+    write_to(dir.path().join("file.txt"), "second")?;
+    repo.add(["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let second = repo.refs.head()?.unwrap();
+    let (first_hex, second_hex) = (first.to_hex(), second.to_hex());
+
+    repo.add_note(first, "Reviewed", false)?;
+    assert_eq!("Reviewed", run_fun!(cd $dir_s; git notes show $first_hex)?);
+    assert!(matches!(
+        repo.add_note(first, "Again", false),
+        Err(NotesError::Exists(commit)) if commit == first
+    ));
+
+    run_fun!(cd $dir_s; git notes add -m "From git" $second_hex)?;
+    assert_eq!(Some("From git\n".into()), repo.note(second)?);
+
+    repo.append_note(first, "Tested")?;
+    assert_eq!(
+        "Reviewed\n\nTested",
+        run_fun!(cd $dir_s; git notes show $first_hex)?
+    );
+    repo.copy_note(first, second, true)?;
+    assert_eq!(
+        BTreeMap::from([
+            (first, "Reviewed\n\nTested\n".into()),
+            (second, "Reviewed\n\nTested\n".into())
+        ]),
+        repo.notes()?
+    );
+
+    repo.remove_note(first)?;
+    assert_eq!(None, repo.note(first)?);
+    assert!(matches!(
+        repo.remove_note(first),
+        Err(NotesError::Missing(_))
+    ));
+    assert_eq!(
+        format!(
+            "{} {second_hex}",
+            run_fun!(cd $dir_s; git notes list $second_hex)?
+        ),
+        run_fun!(cd $dir_s; git notes list)?
+    );
+    run_fun!(cd $dir_s; git fsck --strict)?;
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::path::Path;
+
+use writ::core::{db::Commit, Cherry, Oid};
+
+/// `main` has a commit that `topic` made first, with its whitespace changed,
+/// and `topic` has a commit of its own
+fn branches(dir: &Path) -> Result {
+    let dir_s = dir.to_str().unwrap();
+    let lines = |changed: &[(usize, &str)]| {
+        (1..=10)
+            .map(|n| match changed.iter().find(|(at, _)| *at == n) {
+                Some((_, line)) => format!("{line}\n"),
+                None => format!("{n}\n"),
+            })
+            .collect::<String>()
+    };
+    write_to(dir.join("a.txt"), lines(&[]))?;
+    write_to(dir.join("b.txt"), "b\n")?;
+    run_fun! {
+        cd $dir_s;
+        git init -q -b main;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git add .;
+        git commit -q -m "Root";
+        git checkout -q -b topic;
+        git rm -q b.txt;
+    }?;
+    write_to(dir.join("a.txt"), lines(&[(2, "two")]))?;
+    write_to(dir.join("c.txt"), "c\n")?;
+    run_fun!(cd $dir_s; git add .; git commit -q -m "Change a, remove b, add c")?;
+    write_to(dir.join("d.txt"), "d\n")?;
+    run_fun! {
+        cd $dir_s;
+        git add d.txt;
+        git commit -q -m "Add d";
+        git checkout -q main;
+    }?;
+    write_to(dir.join("a.txt"), lines(&[(9, "nine")]))?;
+    run_fun!(cd $dir_s; git commit -q -am "Change 9"; git rm -q b.txt)?;
+    write_to(dir.join("a.txt"), lines(&[(2, " two\t"), (9, "nine")]))?;
+    write_to(dir.join("c.txt"), "c\n")?;
+    run_fun!(cd $dir_s; git add .; git commit -q -m "Change a, remove b, add c again")?;
+    Ok(())
+}
+
+fn rev(dir: &Path, rev: &str) -> eyre::Result<Oid<Commit>> {
+    let dir_s = dir.to_str().unwrap();
+    Ok(Oid::parse(run_fun!(cd $dir_s; git rev-parse $rev)?)?)
+}
+
+#[test]
+fn computes_patch_ids_like_git() -> Result {
+    init();
+    let dir = tempdir()?;
+    branches(dir.path())?;
+    let dir_s = dir.path().to_str().unwrap();
+    let mut repo = Repo::new(dir.path())?;
+
+    for commit in ["main", "main~", "main~2", "topic", "topic~"] {
+        let expected = run_fun!(cd $dir_s; git show $commit | git patch-id --stable)?;
+        let oid = rev(dir.path(), commit)?;
+        let id = repo.patch_id(oid)?.unwrap();
+        assert_eq!(format!("{} {}", id, oid.to_hex()), expected, "{}", commit);
+    }
+    assert_eq!(
+        repo.patch_id(rev(dir.path(), "main")?)?,
+        repo.patch_id(rev(dir.path(), "topic~")?)?
+    );
+    Ok(())
+}
+
+#[test]
+fn finds_changes_already_upstream() -> Result {
+    init();
+    let dir = tempdir()?;
+    branches(dir.path())?;
+    let mut repo = Repo::new(dir.path())?;
+
+    let cherries = repo.cherry(*rev(dir.path(), "main")?, *rev(dir.path(), "topic")?)?;
+    assert_eq!(
+        vec![
+            Cherry {
+                commit: rev(dir.path(), "topic~")?,
+                upstream: true
+            },
+            Cherry {
+                commit: rev(dir.path(), "topic")?,
+                upstream: false
+            },
+        ],
+        cherries
+    );
+    let dir_s = dir.path().to_str().unwrap();
+    assert_eq!(
+        format!(
+            "- {}\n+ {}",
+            rev(dir.path(), "topic~")?.to_hex(),
+            rev(dir.path(), "topic")?.to_hex()
+        ),
+        run_fun!(cd $dir_s; git cherry main topic)?
+    );
+    Ok(())
+}
@@ -0,0 +1,526 @@
+use std::path::Path;
+
+use bstr::BString;
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{
+    db::Commit, progress::Stage, push::PushError, Lease, Oid, PushOptions, PushStatus, PushUpdate,
+    Pushed, Refspec,
+};
+
+/// A local repository with commits, set up to push to an empty bare one
+/// served over HTTP, which is in the returned tempdir
+fn local_and_remote() -> eyre::Result<(TempDir, TempDir, Repo)> {
+    let root = tempdir()?;
+    let root_s = root.path().to_str().unwrap();
+    run_fun!(cd $root_s; git init --bare -q -b trunk dst)?;
+    let url = serve_git_http(root.path())?;
+
+    let src = tempdir()?;
+    let src_s = src.path().to_str().unwrap();
+    run_fun! {
+        cd $src_s;
+        git init -q -b trunk;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    commit(src.path(), "a")?;
+    commit(src.path(), "b")?;
+
+    let mut repo = Repo::new(src.path())?;
+    repo.config
+        .set("remote.origin.url", &format!("{url}/dst"))?;
+    repo.save_config()?;
+    Ok((root, src, repo))
+}
+
+fn commit(repo: &Path, name: &str) -> Result {
+    let repo_s = repo.to_str().unwrap();
+    write_to(repo.join(format!("dir/{name}.txt")), name)?;
+    run_fun! {
+        cd $repo_s;
+        git add .;
+        git commit -q -m $name;
+    }?;
+    Ok(())
+}
+
+fn rev_parse(repo: &Path, rev: &str) -> eyre::Result<Oid<Commit>> {
+    let repo = repo.to_str().unwrap();
+    let hex = run_fun!(cd $repo; git rev-parse $rev)?;
+    Ok(Oid::parse(hex)?)
+}
+
+fn update(new: Option<Oid<Commit>>, force: bool) -> PushUpdate {
+    PushUpdate {
+        local_ref: None,
+        remote_ref: "refs/heads/trunk".into(),
+        new,
+        force,
+        lease: None,
+    }
+}
+
+fn statuses(pushed: &[Pushed]) -> Vec<&PushStatus> {
+    pushed.iter().map(|pushed| &pushed.status).collect()
+}
+
+#[test]
+fn pushes_branches_over_http() -> Result {
+    init();
+    let (root, src, repo) = local_and_remote()?;
+    let dst = root.path().join("dst");
+    let dst_s = dst.to_str().unwrap();
+
+    let trunk = rev_parse(src.path(), "trunk")?;
+    let pushed = repo.push("origin", &[update(Some(trunk), false)])?;
+    assert_eq!(
+        vec![Pushed {
+            remote_ref: BString::from("refs/heads/trunk"),
+            old: None,
+            new: Some(trunk),
+            status: PushStatus::Updated,
+        }],
+        pushed
+    );
+    assert_eq!(trunk, rev_parse(&dst, "trunk")?);
+    assert_eq!(trunk, rev_parse(src.path(), "origin/trunk")?);
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+
+    // Only what the remote doesn't have is sent
+    commit(src.path(), "c")?;
+    let new_trunk = rev_parse(src.path(), "trunk")?;
+    let pushed = repo.push("origin", &[update(Some(new_trunk), false)])?;
+    assert_eq!(Some(trunk), pushed[0].old);
+    assert_eq!(vec![&PushStatus::Updated], statuses(&pushed));
+    assert_eq!(new_trunk, rev_parse(&dst, "trunk")?);
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+
+    let pushed = repo.push("origin", &[update(Some(new_trunk), false)])?;
+    assert_eq!(vec![&PushStatus::UpToDate], statuses(&pushed));
+
+    // Going back loses a commit
+    let pushed = repo.push("origin", &[update(Some(trunk), false)])?;
+    assert_eq!(vec![&PushStatus::NonFastForward], statuses(&pushed));
+    assert_eq!(new_trunk, rev_parse(&dst, "trunk")?);
+    let pushed = repo.push("origin", &[update(Some(trunk), true)])?;
+    assert_eq!(vec![&PushStatus::Updated], statuses(&pushed));
+    assert_eq!(trunk, rev_parse(&dst, "trunk")?);
+
+    // The remote won't delete its current branch
+    let other = |new| PushUpdate {
+        local_ref: None,
+        remote_ref: "refs/heads/other".into(),
+        new,
+        force: false,
+        lease: None,
+    };
+    repo.push("origin", &[other(Some(trunk))])?;
+    assert_eq!(trunk, rev_parse(src.path(), "origin/other")?);
+    let pushed = repo.push("origin", &[other(None)])?;
+    assert_eq!(vec![&PushStatus::Updated], statuses(&pushed));
+    assert!(run_fun!(cd $dst_s; git rev-parse --verify -q other).is_err());
+    assert!(!src.path().join(".git/refs/remotes/origin/other").exists());
+    Ok(())
+}
+
+#[test]
+fn pushes_by_refspecs() -> Result {
+    init();
+    let (root, src, mut repo) = local_and_remote()?;
+    let src_s = src.path().to_str().unwrap();
+    run_fun! {
+        cd $src_s;
+        git branch side HEAD~1;
+        git branch wip/x HEAD~1;
+        git tag v1 HEAD~1;
+    }?;
+    let trunk = rev_parse(src.path(), "trunk")?;
+    let side = rev_parse(src.path(), "side")?;
+    let push = |local_ref: Option<&str>, remote_ref: &str, new, force| PushUpdate {
+        local_ref: local_ref.map(Into::into),
+        remote_ref: remote_ref.into(),
+        new,
+        force,
+        lease: None,
+    };
+
+    // The current branch by default
+    assert_eq!(
+        vec![push(
+            Some("refs/heads/trunk"),
+            "refs/heads/trunk",
+            Some(trunk),
+            false
+        )],
+        repo.push_updates("origin", &[])?
+    );
+
+    let refspecs = [
+        "+refs/heads/*:refs/heads/*",
+        "^refs/heads/wip/*",
+        "v1",
+        "trunk:mirror",
+        ":refs/heads/gone",
+    ]
+    .iter()
+    .map(|spec| Refspec::parse(spec))
+    .collect::<std::result::Result<Vec<_>, _>>()?;
+    let updates = repo.push_updates("origin", &refspecs)?;
+    assert_eq!(
+        vec![
+            push(Some("refs/heads/side"), "refs/heads/side", Some(side), true),
+            push(
+                Some("refs/heads/trunk"),
+                "refs/heads/trunk",
+                Some(trunk),
+                true
+            ),
+            push(Some("refs/tags/v1"), "refs/tags/v1", Some(side), false),
+            push(
+                Some("refs/heads/trunk"),
+                "refs/heads/mirror",
+                Some(trunk),
+                false
+            ),
+            push(None, "refs/heads/gone", None, false),
+        ],
+        updates
+    );
+    let pushed = repo.push("origin", &updates)?;
+    assert_eq!(
+        vec![
+            &PushStatus::Updated,
+            &PushStatus::Updated,
+            &PushStatus::Updated,
+            &PushStatus::Updated,
+            &PushStatus::UpToDate,
+        ],
+        statuses(&pushed)
+    );
+    let dst = root.path().join("dst");
+    assert_eq!(trunk, rev_parse(&dst, "mirror")?);
+    assert_eq!(side, rev_parse(&dst, "v1")?);
+
+    repo.config
+        .set("remote.origin.push", "side:refs/heads/published")?;
+    assert_eq!(
+        vec![push(
+            Some("refs/heads/side"),
+            "refs/heads/published",
+            Some(side),
+            false
+        )],
+        repo.push_updates("origin", &[])?
+    );
+    assert!(repo
+        .push_updates("origin", &[Refspec::parse("missing")?])
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn force_pushes_with_lease() -> Result {
+    init();
+    let (root, src, mut repo) = local_and_remote()?;
+    let src_s = src.path().to_str().unwrap();
+    let dst = root.path().join("dst");
+    let dst_s = dst.to_str().unwrap();
+    let first = rev_parse(src.path(), "trunk~1")?;
+    let second = rev_parse(src.path(), "trunk")?;
+    repo.push("origin", &[update(Some(second), false)])?;
+
+    // Someone else pushes something we haven't fetched
+    commit(src.path(), "c")?;
+    let third = rev_parse(src.path(), "trunk")?;
+    let third_hex = third.to_hex();
+    run_fun! {
+        cd $dst_s;
+        git fetch -q $src_s trunk;
+        git update-ref refs/heads/trunk $third_hex;
+    }?;
+    let leased = |lease| PushUpdate {
+        lease: Some(lease),
+        ..update(Some(first), false)
+    };
+    let pushed = repo.push("origin", &[leased(Lease::Tracking)])?;
+    assert_eq!(vec![&PushStatus::Stale], statuses(&pushed));
+    let pushed = repo.push("origin", &[leased(Lease::Oid(Some(second)))])?;
+    assert_eq!(vec![&PushStatus::Stale], statuses(&pushed));
+    assert_eq!(third, rev_parse(&dst, "trunk")?);
+
+    repo.fetch("origin")?;
+    let pushed = repo.push("origin", &[leased(Lease::Tracking)])?;
+    assert_eq!(vec![&PushStatus::Updated], statuses(&pushed));
+    assert_eq!(first, rev_parse(&dst, "trunk")?);
+    let pushed = repo.push(
+        "origin",
+        &[PushUpdate {
+            remote_ref: "refs/heads/new".into(),
+            ..leased(Lease::Oid(None))
+        }],
+    )?;
+    assert_eq!(vec![&PushStatus::Updated], statuses(&pushed));
+    Ok(())
+}
+
+#[test]
+fn reports_push_progress() -> Result {
+    init();
+    let (_root, src, repo) = local_and_remote()?;
+
+    let trunk = rev_parse(src.path(), "trunk")?;
+    let mut progress = RecordedProgress::default();
+    repo.push_with(
+        "origin",
+        &[update(Some(trunk), false)],
+        &PushOptions::default(),
+        &mut progress,
+    )?;
+    // Two commits, each with a root tree, a tree for `dir` and a blob
+    let counted = progress.last(Stage::Counting).expect("Counted");
+    assert_eq!(8, counted.objects);
+    let written = progress.last(Stage::Writing).expect("Written");
+    assert_eq!((8, Some(8)), (written.objects, written.total));
+    assert!(written.bytes > 0);
+    Ok(())
+}
+
+#[test]
+fn pushes_merges() -> Result {
+    init();
+    let (root, src, repo) = local_and_remote()?;
+    let src_s = src.path().to_str().unwrap();
+    run_fun!(cd $src_s; git checkout -q -b side HEAD~1)?;
+    commit(src.path(), "side")?;
+    run_fun! {
+        cd $src_s;
+        git checkout -q trunk;
+        git merge -q --no-edit side;
+    }?;
+
+    let trunk = rev_parse(src.path(), "trunk")?;
+    let pushed = repo.push("origin", &[update(Some(trunk), false)])?;
+    assert_eq!(vec![&PushStatus::Updated], statuses(&pushed));
+    let dst = root.path().join("dst");
+    let dst_s = dst.to_str().unwrap();
+    assert_eq!(trunk, rev_parse(&dst, "trunk")?);
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+    Ok(())
+}
+
+#[test]
+fn reports_remote_rejections() -> Result {
+    init();
+    let (root, src, repo) = local_and_remote()?;
+    let dst = root.path().join("dst");
+    let dst_s = dst.to_str().unwrap();
+    run_fun!(cd $dst_s; git config receive.denyDeletes true)?;
+
+    let trunk = rev_parse(src.path(), "trunk")?;
+    repo.push("origin", &[update(Some(trunk), false)])?;
+    let pushed = repo.push("origin", &[update(None, false)])?;
+    assert!(matches!(pushed[0].status, PushStatus::Rejected(_)));
+    assert_eq!(trunk, rev_parse(&dst, "trunk")?);
+    // Left alone, as the remote didn't change
+    assert_eq!(trunk, rev_parse(src.path(), "origin/trunk")?);
+    Ok(())
+}
+
+#[test]
+fn pushes_atomically() -> Result {
+    init();
+    let (root, src, repo) = local_and_remote()?;
+    let dst = root.path().join("dst");
+    let dst_s = dst.to_str().unwrap();
+    run_fun!(cd $dst_s; git config receive.denyDeletes true)?;
+    let trunk = rev_parse(src.path(), "trunk")?;
+    repo.push("origin", &[update(Some(trunk), false)])?;
+
+    let atomic = PushOptions {
+        atomic: true,
+        ..PushOptions::default()
+    };
+    let other = PushUpdate {
+        local_ref: None,
+        remote_ref: "refs/heads/other".into(),
+        new: Some(trunk),
+        force: false,
+        lease: None,
+    };
+    // Going back loses a commit, so nothing is sent
+    let first = rev_parse(src.path(), "trunk~1")?;
+    let err = repo
+        .push_with(
+            "origin",
+            &[other.clone(), update(Some(first), false)],
+            &atomic,
+            &mut (),
+        )
+        .unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            PushError::Atomic(remote_ref, PushStatus::NonFastForward)
+                if remote_ref == "refs/heads/trunk"
+        ),
+        "{:?}",
+        err
+    );
+
+    // The remote won't delete trunk, so it doesn't create other either
+    let err = repo
+        .push_with(
+            "origin",
+            &[other.clone(), update(None, false)],
+            &atomic,
+            &mut (),
+        )
+        .unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            PushError::Atomic(remote_ref, PushStatus::Rejected(_))
+                if remote_ref == "refs/heads/trunk"
+        ),
+        "{:?}",
+        err
+    );
+    assert!(run_fun!(cd $dst_s; git rev-parse --verify -q other).is_err());
+    assert!(!src.path().join(".git/refs/remotes/origin/other").exists());
+    assert_eq!(trunk, rev_parse(&dst, "trunk")?);
+
+    let pushed = repo.push_with("origin", &[other], &atomic, &mut ())?;
+    assert_eq!(vec![&PushStatus::Updated], statuses(&pushed));
+    assert_eq!(trunk, rev_parse(&dst, "other")?);
+    Ok(())
+}
+
+#[test]
+fn pushes_thin_packs() -> Result {
+    init();
+    let (root, src, repo) = local_and_remote()?;
+    let src_s = src.path().to_str().unwrap();
+    let lines = |changed: usize| {
+        (0..5000)
+            .map(|line| {
+                let value = if line == changed {
+                    0
+                } else {
+                    line * 7919 % 10007
+                };
+                format!("line {line}: {value}\n")
+            })
+            .collect::<String>()
+    };
+    write_to(src.path().join("dir/big.txt"), lines(usize::MAX))?;
+    run_fun!(cd $src_s; git add .; git commit -q -m Big)?;
+    let trunk = rev_parse(src.path(), "trunk")?;
+    repo.push("origin", &[update(Some(trunk), false)])?;
+
+    // Only the changes to the file and its trees are sent, as deltas
+    write_to(src.path().join("dir/big.txt"), lines(2500))?;
+    run_fun!(cd $src_s; git commit -q -a -m Changed)?;
+    let trunk = rev_parse(src.path(), "trunk")?;
+    let mut progress = RecordedProgress::default();
+    repo.push_with(
+        "origin",
+        &[update(Some(trunk), false)],
+        &PushOptions::default(),
+        &mut progress,
+    )?;
+    let written = progress.last(Stage::Writing).expect("Written");
+    assert_eq!(4, written.objects);
+    assert!(written.bytes < 1000, "{}", written.bytes);
+
+    let dst = root.path().join("dst");
+    let dst_s = dst.to_str().unwrap();
+    assert_eq!(trunk, rev_parse(&dst, "trunk")?);
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+    Ok(())
+}
+
+#[test]
+fn runs_registered_pre_push_hooks() -> Result {
+    use writ::core::hook::{Hooks, Rejected};
+
+    /// Only lets trunk be created
+    #[derive(Debug)]
+    struct NoRewrites;
+
+    impl Hooks for NoRewrites {
+        fn pre_push(
+            &self,
+            remote: &str,
+            _url: &str,
+            pushing: &[Pushed],
+        ) -> std::result::Result<(), Rejected> {
+            assert_eq!("origin", remote);
+            match pushing {
+                [Pushed { old: None, .. }] => Ok(()),
+                _ => Err(Rejected("No rewriting trunk".into())),
+            }
+        }
+    }
+
+    init();
+    let (root, src, mut repo) = local_and_remote()?;
+    repo.register_hooks(NoRewrites);
+    let dst = root.path().join("dst");
+    let trunk = rev_parse(src.path(), "trunk")?;
+    repo.push("origin", &[update(Some(trunk), false)])?;
+
+    let first = rev_parse(src.path(), "trunk~1")?;
+    let err = repo
+        .push("origin", &[update(Some(first), true)])
+        .unwrap_err();
+    assert!(matches!(err, PushError::Rejected(_)), "{:?}", err);
+    assert_eq!(trunk, rev_parse(&dst, "trunk")?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn runs_pre_push_hook() -> Result {
+    init();
+    let (root, src, repo) = local_and_remote()?;
+    let dst = root.path().join("dst");
+    let first = rev_parse(src.path(), "trunk~1")?;
+    write_hook(
+        src.path(),
+        "pre-push",
+        "#!/bin/sh\necho \"$1\" > pushing\ncat >> pushing\necho No pushing >&2\nexit 1\n",
+    )?;
+
+    let updates = [
+        PushUpdate {
+            local_ref: Some("refs/heads/trunk".into()),
+            ..update(Some(first), false)
+        },
+        PushUpdate {
+            remote_ref: "refs/heads/gone".into(),
+            ..update(None, false)
+        },
+    ];
+    let err = repo.push("origin", &updates).unwrap_err();
+    assert_eq!("pre-push hook failed: No pushing", err.to_string());
+    assert!(run_fun!(git -C $dst rev-parse -q --verify trunk).is_err());
+    // Deleting a missing ref is up to date, so isn't sent
+    let zero = "0".repeat(40);
+    assert_eq!(
+        format!(
+            "origin\nrefs/heads/trunk {} refs/heads/trunk {zero}\n",
+            first.to_hex()
+        ),
+        fs::read_to_string(src.path().join("pushing"))?
+    );
+
+    let no_verify = PushOptions {
+        no_verify: true,
+        ..PushOptions::default()
+    };
+    repo.push_with("origin", &updates[..1], &no_verify, &mut ())?;
+    assert_eq!(first, rev_parse(&dst, "trunk")?);
+    Ok(())
+}
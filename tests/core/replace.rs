@@ -0,0 +1,94 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::collections::BTreeMap;
+
+use writ::core::{
+    db::{Commit, UntypedOid},
+    replace::ReplaceError,
+    revwalk::RevWalk,
+    WsPath,
+};
+
+fn walk(repo: &Repo, from: UntypedOid) -> eyre::Result<Vec<UntypedOid>> {
+    let mut walk = RevWalk::new(&repo.db);
+    walk.push(from)?;
+    Ok(walk
+        .map(|commit| commit.map(|commit| commit.oid))
+        .collect::<std::result::Result<_, _>>()?)
+}
+
+#[test]
+fn replaces_objects_and_grafts_history() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    run_fun! {
+        cd $dir_s;
+        git init -q;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    let mut repo = Repo::new(dir.path())?;
+    let mut commits = Vec::new();
+    for contents in ["first", "second", "third"] {
+        write_to(dir.path().join("file.txt"), contents)?;
+        repo.add(["file.txt"])?;
+        repo.commit(NAME, EMAIL, MSG)?;
+        commits.push(repo.refs.head()?.unwrap().into_untyped());
+    }
+    let [first, second, third] = commits[..] else {
+        unreachable!()
+    };
+
+    let grafted = repo.graft(third, &[first], false)?;
+    assert_eq!(vec![third, first], walk(&repo, third)?);
+    assert_eq!(
+        Some(first.to_typed()),
+        repo.db.load(third.to_typed::<Commit>())?.parent
+    );
+    let first_hex = first.to_hex();
+    assert_eq!(
+        format!("{}\n{first_hex}", third.to_hex()),
+        run_fun!(cd $dir_s; git rev-list HEAD)?
+    );
+    assert!(matches!(
+        repo.graft(third, &[], false),
+        Err(ReplaceError::Exists(_))
+    ));
+
+    let tree = repo.db.load(second.to_typed::<Commit>())?.tree;
+    let blob = repo
+        .db
+        .load_tree_file(tree, &WsPath::new_unchecked("file.txt"))?
+        .unwrap()
+        .oid;
+    let other = run_fun!(cd $dir_s; echo replaced | git hash-object -w --stdin)?;
+    let other = UntypedOid::parse(other)?;
+    assert!(matches!(
+        repo.replace(blob.into_untyped(), second, false),
+        Err(ReplaceError::WrongType { .. })
+    ));
+    repo.replace(blob.into_untyped(), other, false)?;
+    assert_eq!("replaced\n", repo.db.load(blob)?.bytes);
+    assert_eq!(
+        BTreeMap::from([(blob.into_untyped(), other), (third, grafted)]),
+        repo.replacements()?
+    );
+
+    repo.config.set("core.useReplaceRefs", "false")?;
+    repo.save_config()?;
+    let unreplaced = Repo::new(dir.path())?;
+    assert_eq!(vec![third, second, first], walk(&unreplaced, third)?);
+    repo.config.set("core.useReplaceRefs", "true")?;
+    repo.save_config()?;
+
+    repo.remove_replacement(third)?;
+    assert_eq!(vec![third, second, first], walk(&repo, third)?);
+    assert!(matches!(
+        repo.remove_replacement(third),
+        Err(ReplaceError::NotReplaced(_))
+    ));
+    run_fun!(cd $dir_s; git fsck --strict)?;
+    Ok(())
+}
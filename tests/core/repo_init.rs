@@ -1,3 +1,4 @@
+use test_support::assert_eq;
 use test_support::*;
 
 #[test]
@@ -20,3 +21,248 @@ fn can_init_nonexistent_ws() -> Result {
 
     Ok(())
 }
+
+#[test]
+fn saves_edited_config() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+    let path = dir.path().join(".git/config");
+    let original = fs::read_to_string(&path).unwrap_or_default();
+
+    repo.config
+        .set("remote.origin.url", "https://example.com/repo.git")?;
+    repo.save_config()?;
+
+    let saved = fs::read_to_string(&path)?;
+    assert!(saved.starts_with(&original));
+    let actual = run_fun! {
+        cd $dir_s;
+        git config --file .git/config remote.origin.url;
+    }?;
+    assert_eq!("https://example.com/repo.git", actual);
+    Ok(())
+}
+
+#[test]
+fn opens_bare_repos() -> Result {
+    init();
+    let dir = tempdir()?;
+    let git_dir = dir.path().join("repo.git");
+    Repo::init_bare(&git_dir)?;
+
+    let mut repo = Repo::new(&git_dir)?;
+    assert!(repo.is_bare());
+    let err = repo.status().unwrap_err();
+    assert!(
+        matches!(err, writ::core::repo::StatusError::Bare(_)),
+        "{:?}",
+        err
+    );
+    assert!(repo.add(["file.txt"]).is_err());
+
+    // Commits don't need a workspace
+    repo.commit(NAME, EMAIL, MSG)?;
+    assert!(repo.refs.head()?.is_some());
+    Ok(())
+}
+
+#[test]
+fn honors_core_bare_and_worktree() -> Result {
+    init();
+    let dir = tempdir()?;
+    let git_dir = dir.path().join("repo.git");
+    let work = dir.path().join("work");
+    fs::create_dir_all(&work)?;
+    Repo::init_bare(&git_dir)?;
+
+    let mut repo = Repo::new(&git_dir)?;
+    repo.config.set("core.bare", "false")?;
+    repo.config.set("core.worktree", "../work")?;
+    repo.save_config()?;
+
+    write_to(work.join("file.txt"), "contents")?;
+    let mut repo = Repo::new(&git_dir)?;
+    assert!(!repo.is_bare());
+    assert_eq!(
+        vec![writ::core::WsPath::new_unchecked("file.txt")],
+        repo.add(["file.txt"])?
+    );
+    let status = repo.status()?;
+    assert_eq!(
+        vec!["file.txt"],
+        status
+            .keys()
+            .map(|path| path.to_string())
+            .collect::<Vec<_>>()
+    );
+
+    // A workspace with a .git can still be marked bare
+    let (dir, mut repo) = repo_fixture()?;
+    repo.config.set("core.bare", "true")?;
+    repo.save_config()?;
+    assert!(Repo::new(dir.path())?.is_bare());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn refuses_repos_owned_by_someone_else() -> Result {
+    init();
+    let (dir, _) = repo_fixture()?;
+    let dir = dir.path().canonicalize()?;
+    let dir_s = dir.to_str().unwrap();
+    // Only root can give files away
+    if run_fun!(chown -R 65534 $dir_s).is_err() {
+        return Ok(());
+    }
+
+    let err = Repo::new(&dir).unwrap_err();
+    assert!(
+        matches!(&err, writ::core::repo::ReadError::Unsafe(path) if *path == dir),
+        "{:?}",
+        err
+    );
+    assert!(err.to_string().contains("safe.directory"));
+    Ok(())
+}
+
+#[test]
+fn opens_linked_worktrees() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let root = dir.path().canonicalize()?;
+    write_to(root.join("file.txt"), "contents")?;
+    repo.add(["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let main = repo.refs.head()?.expect("Committed");
+
+    let linked = root.join("linked");
+    let (root_s, linked_s) = (root.to_str().unwrap(), linked.to_str().unwrap());
+    run_fun!(cd $root_s; git worktree add -q -b topic $linked_s)?;
+
+    // Follows `.git` to `.git/worktrees/linked`, and from there `commondir`
+    let mut repo = Repo::new(&linked)?;
+    assert_eq!(root.join(".git/worktrees/linked"), repo.git_dir());
+    assert_eq!(root.join(".git"), repo.common_dir());
+    assert_eq!(linked, repo.workspace.as_ref().unwrap().path());
+    assert_eq!(Some(main), repo.refs.head()?);
+    assert!(repo
+        .status()?
+        .values()
+        .all(|status| status.short_code() == "  "));
+
+    write_to(linked.join("file.txt"), "changed")?;
+    repo.add(["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    let topic = repo.refs.head()?;
+    assert_eq!(
+        run_fun!(cd $linked_s; git rev-parse topic)?,
+        topic.unwrap().to_hex()
+    );
+    assert_eq!(run_fun!(cd $root_s; git rev-parse HEAD)?, main.to_hex());
+    assert_eq!(Some(main), Repo::new(&root)?.refs.head()?);
+    Ok(())
+}
+
+#[test]
+fn init_writes_config_and_head_like_git() -> Result {
+    init();
+    let dir = tempdir()?;
+    let work = dir.path().join("work");
+    let template = dir.path().join("template");
+    write_to(template.join("info/exclude"), "*.log\n")?;
+    write_to(template.join("config"), "[user]\n\tname = Template\n")?;
+
+    let mut repo = writ::core::init::Builder::new(&work)
+        .initial_branch("main")
+        .template(&template)
+        .init()?;
+    assert!(!repo.is_bare());
+    assert_eq!(Some("Template"), repo.config.get("user.name"));
+    assert_eq!(
+        "*.log\n",
+        fs::read_to_string(work.join(".git/info/exclude"))?
+    );
+
+    let work_s = work.to_str().unwrap();
+    let head = run_fun!(cd $work_s; git symbolic-ref HEAD)?;
+    assert_eq!("refs/heads/main", head);
+    let bare = run_fun!(cd $work_s; git config core.bare)?;
+    assert_eq!("false", bare);
+
+    repo.commit(NAME, EMAIL, MSG)?;
+    let branch = run_fun!(cd $work_s; git branch --show-current)?;
+    assert_eq!("main", branch);
+    Ok(())
+}
+
+#[test]
+fn inits_bare_and_separate_git_dirs() -> Result {
+    init();
+    let dir = tempdir()?;
+    let bare = dir.path().join("repo.git");
+    Repo::init_bare(&bare)?;
+    let bare_s = bare.to_str().unwrap();
+    let actual = run_fun!(cd $bare_s; git rev-parse --is-bare-repository)?;
+    assert_eq!("true", actual);
+    assert!(matches!(
+        Repo::init_bare(&bare),
+        Err(writ::core::repo::InitError::Exists(_))
+    ));
+
+    let work = dir.path().join("work");
+    let git_dir = dir.path().join("git");
+    writ::core::init::Builder::new(&work)
+        .separate_git_dir(&git_dir)
+        .init()?;
+    write_to(work.join("file.txt"), "contents")?;
+    let mut repo = Repo::new(&work)?;
+    repo.add(["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    assert!(git_dir.join("HEAD").is_file());
+
+    let work_s = work.to_str().unwrap();
+    let actual = run_fun!(cd $work_s; git ls-files)?;
+    assert_eq!("file.txt", actual);
+    Ok(())
+}
+
+#[test]
+fn init_rejects_invalid_options() -> Result {
+    init();
+    let dir = tempdir()?;
+    let builder = writ::core::init::Builder::new(dir.path().join("repo"));
+
+    let err = builder
+        .clone()
+        .initial_branch("bad..name")
+        .init()
+        .unwrap_err();
+    assert!(
+        matches!(err, writ::core::repo::InitError::InvalidBranch(_)),
+        "{:?}",
+        err
+    );
+    let err = builder
+        .clone()
+        .bare(true)
+        .separate_git_dir(dir.path().join("git"))
+        .init()
+        .unwrap_err();
+    assert!(
+        matches!(err, writ::core::repo::InitError::BareSeparate),
+        "{:?}",
+        err
+    );
+    let err = builder
+        .object_format(writ::core::init::ObjectFormat::Sha256)
+        .init()
+        .unwrap_err();
+    assert!(
+        matches!(err, writ::core::repo::InitError::UnsupportedObjectFormat(_)),
+        "{:?}",
+        err
+    );
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::fs;
+
+use writ::core::{rerere::Rerere, WsPath};
+
+#[test]
+fn reuses_recorded_resolutions() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    let file = dir.path().join("file.txt");
+    write_to(&file, "top\nmiddle\nbottom\n")?;
+    run_fun! {
+        cd $dir_s;
+        git init -q -b main;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git add file.txt;
+        git commit -q -m $MSG;
+        git checkout -q -b side;
+        sed -i "s/middle/side/" file.txt;
+        git commit -q -am $MSG;
+        git checkout -q main;
+        sed -i "s/middle/main/" file.txt;
+        git commit -q -am $MSG;
+    }?;
+    let conflict = || run_fun!(cd $dir_s; git -c rerere.enabled=false merge -q side).is_err();
+    assert!(conflict());
+
+    let mut repo = Repo::new(dir.path())?;
+    assert_eq!(Rerere::default(), repo.rerere()?, "Disabled");
+    repo.config.set("rerere.enabled", "true")?;
+    repo.save_config()?;
+    let path = WsPath::new_unchecked("file.txt");
+    assert_eq!(vec![path.clone()], repo.rerere()?.recorded);
+    assert!(dir.path().join(".git/MERGE_RR").exists());
+    assert_eq!(Rerere::default(), repo.rerere()?, "Already recorded");
+
+    write_to(&file, "top\nresolved\nbottom\n")?;
+    repo.add(["file.txt"])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    assert!(!dir.path().join(".git/MERGE_RR").exists());
+    let cache = fs::read_dir(dir.path().join(".git/rr-cache"))?
+        .next()
+        .unwrap()?
+        .path();
+    assert_eq!(
+        "top\nresolved\nbottom\n",
+        fs::read_to_string(cache.join("postimage"))?
+    );
+
+    // Git resolves it the same, as it's recorded the same way
+    run_fun!(cd $dir_s; git reset -q --hard HEAD~)?;
+    assert!(conflict());
+    run_fun!(cd $dir_s; git rerere)?;
+    assert_eq!("top\nresolved\nbottom\n", fs::read_to_string(&file)?);
+
+    run_fun!(cd $dir_s; git reset -q --hard)?;
+    assert!(conflict());
+    let mut repo = Repo::new(dir.path())?;
+    assert_eq!(vec![path], repo.rerere()?.resolved);
+    assert_eq!("top\nresolved\nbottom\n", fs::read_to_string(&file)?);
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use test_support::assert_eq;
+use test_support::*;
+
+#[test]
+fn resolves_like_git() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+
+    for n in 0..3 {
+        write_to(dir.path().join("file.txt"), n.to_string())?;
+        repo.add(["file.txt"])?;
+        repo.commit(NAME, EMAIL, MSG)?;
+    }
+    run_fun! {
+        cd $dir_s;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git tag -a -m $MSG v1 HEAD~1;
+        git checkout -q -b topic HEAD~2;
+        git commit -q --allow-empty -m $MSG;
+        git checkout -q master;
+        git merge -q --no-edit topic;
+    }?;
+
+    for rev in [
+        "HEAD",
+        "@",
+        "master",
+        "topic",
+        "refs/heads/topic",
+        "v1",
+        "v1^{}",
+        "v1^{commit}",
+        "v1^{tree}",
+        "v1~1",
+        "HEAD^2",
+        "HEAD^1~2",
+        "HEAD~",
+        "HEAD^0",
+        "HEAD^{tree}",
+    ] {
+        let expected = run_fun!(cd $dir_s; git rev-parse $rev)?;
+        assert_eq!(expected, repo.rev_parse(rev)?.to_string(), "{}", rev);
+    }
+    assert!(repo.rev_parse("nonexistent").is_err());
+    assert!(repo.rev_parse("HEAD~10").is_err());
+    assert!(repo.rev_parse("HEAD^{bogus}").is_err());
+
+    for revs in [
+        &["HEAD~2..HEAD"][..],
+        &["topic..master"],
+        &["master...topic"],
+        &["..topic"],
+        &["master", "^topic"],
+        &["v1", "^HEAD~3"],
+    ] {
+        let expected = run_fun!(cd $dir_s; git rev-list $[revs])?;
+        let walked = repo
+            .rev_range(revs)?
+            .walk(&repo.db)?
+            .map(|commit| Ok(commit?.oid.to_string()))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let mut expected = expected.lines().collect::<Vec<_>>();
+        let mut walked = walked.iter().map(String::as_str).collect::<Vec<_>>();
+        expected.sort_unstable();
+        walked.sort_unstable();
+        assert_eq!(expected, walked, "{:?}", revs);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn lists_objects_like_git() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+
+    write_to(dir.path().join("a.txt"), "a")?;
+    write_to(dir.path().join("dir/b.txt"), "b")?;
+    repo.add(["."])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    write_to(dir.path().join("dir/sub/c.txt"), "c")?;
+    write_to(dir.path().join("a.txt"), "changed")?;
+    repo.add(["."])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    write_to(dir.path().join("d.txt"), "b")?;
+    repo.add(["."])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+
+    for revs in [&["HEAD"][..], &["HEAD~2..HEAD"], &["HEAD", "^HEAD~1"]] {
+        let expected = run_fun!(cd $dir_s; git rev-list --objects $[revs])?;
+        let mut expected = expected.lines().map(str::trim_end).collect::<Vec<_>>();
+        let mut listed = repo
+            .rev_range(revs)?
+            .walk(&repo.db)?
+            .objects()
+            .map(|object| {
+                let object = object?;
+                Ok(match object.path {
+                    Some(path) => format!("{} {}", object.oid, path).trim_end().to_string(),
+                    None => object.oid.to_string(),
+                })
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let mut deduped = listed.clone();
+        deduped.sort();
+        deduped.dedup();
+        assert_eq!(listed.len(), deduped.len(), "{:?}", revs);
+
+        expected.sort_unstable();
+        listed.sort();
+        assert_eq!(expected, listed, "{:?}", revs);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::path::Path;
+
+use writ::core::{db::Commit, Oid, Sequenced, WsPath};
+
+/// `main` changes `b.txt`, while `side` has three commits: changing `a.txt`,
+/// changing `b.txt` differently, and adding `c.txt`
+fn branches(dir: &Path) -> Result {
+    let dir_s = dir.to_str().unwrap();
+    write_to(dir.join("a.txt"), "a\n")?;
+    write_to(dir.join("b.txt"), "b\n")?;
+    run_fun! {
+        cd $dir_s;
+        git init -q -b main;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git add .;
+        git commit -q -m "Root";
+        git checkout -q -b side;
+        sh -c "echo side > a.txt";
+        git commit -q -am "Change a";
+        sh -c "echo side > b.txt";
+        git commit -q -am "Change b";
+        sh -c "echo side > c.txt";
+        git add c.txt;
+        git commit -q -m "Add c";
+        git checkout -q main;
+        sh -c "echo main > b.txt";
+        git commit -q -am "Change b on main";
+    }?;
+    Ok(())
+}
+
+fn rev(dir: &Path, rev: &str) -> eyre::Result<Oid<Commit>> {
+    let dir_s = dir.to_str().unwrap();
+    Ok(Oid::parse(run_fun!(cd $dir_s; git rev-parse $rev)?)?)
+}
+
+fn subjects(dir: &Path) -> eyre::Result<String> {
+    let dir_s = dir.to_str().unwrap();
+    Ok(run_fun!(cd $dir_s; git log --format=%s)?)
+}
+
+#[test]
+fn cherry_picks_ranges_through_conflicts() -> Result {
+    init();
+    let dir = tempdir()?;
+    branches(dir.path())?;
+    let mut repo = Repo::new(dir.path())?;
+
+    let range = repo.rev_range(["main..side"])?;
+    let stopped = repo.cherry_pick(&range)?;
+    let change_b = rev(dir.path(), "side~")?;
+    assert_eq!(
+        Sequenced::Conflicts {
+            commit: change_b,
+            paths: vec![WsPath::new_unchecked("b.txt")],
+        },
+        stopped
+    );
+    assert_eq!(change_b, rev(dir.path(), "CHERRY_PICK_HEAD")?);
+    assert_eq!("side\n", fs::read_to_string(dir.path().join("a.txt"))?);
+    assert!(repo.cherry_pick(&range).is_err(), "Already in progress");
+
+    write_to(dir.path().join("b.txt"), "resolved\n")?;
+    repo.add(["b.txt"])?;
+    let Sequenced::Done(made) = repo.continue_sequence()? else {
+        panic!("Nothing else conflicts");
+    };
+    assert_eq!(2, made.len());
+    assert_eq!(
+        "Add c\nChange b\nChange a\nChange b on main\nRoot",
+        subjects(dir.path())?
+    );
+    let picked = repo.db.load(rev(dir.path(), "HEAD~")?)?;
+    assert_eq!("Change b\n", picked.msg);
+    assert_eq!("side\n", fs::read_to_string(dir.path().join("c.txt"))?);
+    assert!(!dir.path().join(".git/sequencer").exists());
+    assert!(!dir.path().join(".git/CHERRY_PICK_HEAD").exists());
+    assert!(repo.continue_sequence().is_err(), "Nothing in progress");
+    Ok(())
+}
+
+#[test]
+fn skips_and_aborts() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    branches(dir.path())?;
+    let main = rev(dir.path(), "main")?;
+    let mut repo = Repo::new(dir.path())?;
+
+    let range = repo.rev_range(["main..side"])?;
+    repo.cherry_pick(&range)?;
+    let Sequenced::Done(made) = repo.skip_sequence()? else {
+        panic!("Nothing else conflicts");
+    };
+    assert_eq!(1, made.len());
+    assert_eq!("main\n", fs::read_to_string(dir.path().join("b.txt"))?);
+    assert_eq!(
+        "Add c\nChange a\nChange b on main\nRoot",
+        subjects(dir.path())?
+    );
+
+    // The picks moved `main`
+    let main_hex = main.to_hex();
+    run_fun!(cd $dir_s; git reset -q --hard $main_hex)?;
+    repo.cherry_pick(&range)?;
+    repo.abort_sequence()?;
+    assert_eq!(main, rev(dir.path(), "HEAD")?);
+    assert_eq!("a\n", fs::read_to_string(dir.path().join("a.txt"))?);
+    assert_eq!("main\n", fs::read_to_string(dir.path().join("b.txt"))?);
+    assert!(!dir.path().join(".git/sequencer").exists());
+    assert!(!dir.path().join(".git/CHERRY_PICK_HEAD").exists());
+    assert!(!repo.index.has_conflicts());
+    Ok(())
+}
+
+#[test]
+fn reverts_like_git() -> Result {
+    init();
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    branches(dir.path())?;
+    run_fun!(cd $dir_s; git checkout -q side)?;
+    let change_a = rev(dir.path(), "side~2")?;
+    let mut repo = Repo::new(dir.path())?;
+
+    let change_a_hex = change_a.to_hex();
+    let range = repo.rev_range([&change_a_hex])?;
+    let Sequenced::Done(made) = repo.revert(&range)? else {
+        panic!("Doesn't conflict");
+    };
+    let reverted = repo.db.load(made[0])?;
+    assert_eq!(
+        format!("Revert \"Change a\"\n\nThis reverts commit {change_a_hex}.\n"),
+        reverted.msg
+    );
+    assert_eq!("a\n", fs::read_to_string(dir.path().join("a.txt"))?);
+
+    // Git reverts it the same way
+    run_fun! {
+        cd $dir_s;
+        git checkout -q -b by-git side~;
+        git revert --no-edit $change_a_hex;
+    }?;
+    assert_eq!(
+        run_fun!(cd $dir_s; git rev-parse "by-git^{tree}")?,
+        reverted.tree.to_hex()
+    );
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use std::path::Path;
+
+use bstr::ByteSlice;
+use test_support::assert_eq;
+use test_support::*;
+
+/// How git runs us in place of `git-receive-pack`
+const RECEIVE_PACK: &str = concat!(env!("CARGO_BIN_EXE_writ"), " receive-pack");
+
+fn commit(repo: &Path, name: &str) -> Result {
+    let repo_s = repo.to_str().unwrap();
+    write_to(repo.join(format!("dir/{name}.txt")), name)?;
+    run_fun! {
+        cd $repo_s;
+        git add .;
+        git commit -q -m $name;
+    }?;
+    Ok(())
+}
+
+/// A git repository with a commit on `trunk`
+fn git_source() -> eyre::Result<TempDir> {
+    let src = tempdir()?;
+    let src_s = src.path().to_str().unwrap();
+    run_fun! {
+        cd $src_s;
+        git init -q -b trunk;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    commit(src.path(), "a")?;
+    Ok(src)
+}
+
+fn rev_parse(repo: &Path, rev: &str) -> eyre::Result<String> {
+    let repo = repo.to_str().unwrap();
+    Ok(run_fun!(cd $repo; git rev-parse $rev)?)
+}
+
+#[test]
+fn receives_pushes_from_git() -> Result {
+    init();
+    let src = git_source()?;
+    let src_s = src.path().to_str().unwrap();
+    let root = tempdir()?;
+    let dst = root.path().join("dst");
+    let dst_s = dst.to_str().unwrap();
+    let repo = Repo::init_bare(&dst)?;
+    // So that git recognizes the repository
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+
+    run_fun! {
+        cd $src_s;
+        git tag -a -m "Version 1" v1;
+        git push -q --receive-pack=$RECEIVE_PACK $dst_s trunk v1;
+    }?;
+    assert_eq!(rev_parse(src.path(), "trunk")?, rev_parse(&dst, "trunk")?);
+    assert_eq!(rev_parse(src.path(), "v1")?, rev_parse(&dst, "v1")?);
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+
+    // Only what's new is sent, as a thin pack with deltas
+    write_to(src.path().join("dir/a.txt"), "a\n".repeat(100))?;
+    commit(src.path(), "b")?;
+    run_fun!(cd $src_s; git push -q --receive-pack=$RECEIVE_PACK $dst_s trunk)?;
+    assert_eq!(rev_parse(src.path(), "trunk")?, rev_parse(&dst, "trunk")?);
+    run_fun!(cd $dst_s; git fsck --full --no-dangling)?;
+
+    run_fun!(cd $src_s; git push -q --receive-pack=$RECEIVE_PACK $dst_s :refs/tags/v1)?;
+    assert!(!dst.join("refs/tags/v1").exists());
+    Ok(())
+}
+
+#[test]
+fn refuses_to_update_the_checked_out_branch() -> Result {
+    init();
+    let src = git_source()?;
+    let src_s = src.path().to_str().unwrap();
+    let dst = tempdir()?;
+    let dst_s = dst.path().to_str().unwrap();
+    let repo = Repo::init(dst.path())?;
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+
+    let pushed = run_fun!(cd $src_s; git push -q --receive-pack=$RECEIVE_PACK $dst_s trunk);
+    assert!(pushed.is_err());
+    assert_eq!(None, repo.refs.head()?);
+
+    run_fun!(cd $src_s; git push -q --receive-pack=$RECEIVE_PACK $dst_s trunk:other)?;
+    assert_eq!(
+        rev_parse(src.path(), "trunk")?,
+        rev_parse(dst.path(), "other")?
+    );
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{FileStatus, Status};
+
+fn committed_nested_fixture() -> eyre::Result<(TempDir, Repo)> {
+    let (dir, mut repo) = repo_fixture()?;
+    create_nested_files(dir.path())?;
+    repo.add(["."])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+    Ok((dir, repo))
+}
+
+fn sorted_files(dir: &std::path::Path) -> eyre::Result<Vec<String>> {
+    let mut files = all_files(dir)?
+        .into_iter()
+        .filter(|file| !file.starts_with(".git"))
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+#[test]
+fn removes_files_outside_cone() -> Result {
+    init();
+    let (dir, mut repo) = committed_nested_fixture()?;
+    let dir = dir.path();
+
+    repo.sparse_checkout_set(["dir_1/dir_a/dir_x"])?;
+
+    assert_eq!(
+        vec![
+            "dir_1/dir_a/dir_x/f",
+            "dir_1/dir_a/f",
+            "dir_1/f",
+            "dir_1/f2",
+            "f"
+        ],
+        sorted_files(dir)?
+    );
+    assert!(!dir.join("dir_2").exists());
+    assert_eq!(
+        "/*\n!/*/\n/dir_1/\n!/dir_1/*/\n/dir_1/dir_a/\n!/dir_1/dir_a/*/\n/dir_1/dir_a/dir_x/\n",
+        fs::read_to_string(dir.join(".git/info/sparse-checkout"))?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn status_ignores_skipped_files() -> Result {
+    init();
+    let (_dir, mut repo) = committed_nested_fixture()?;
+
+    repo.sparse_checkout_set(["dir_2"])?;
+
+    let status = repo.status()?;
+    assert!(status
+        .values()
+        .all(|s: &FileStatus| s.workspace == Status::Unmodified && s.index == Status::Unmodified));
+    assert!(status.keys().any(|path| *path == "dir_1/dir_a/dir_y/f"));
+
+    Ok(())
+}
+
+#[test]
+fn git_sees_skip_worktree_bits() -> Result {
+    init();
+    let (dir, mut repo) = committed_nested_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+    write_to(dir.path().join(".git/HEAD"), "ref: refs/heads/master")?;
+
+    repo.sparse_checkout_set(["dir_2"])?;
+
+    let actual = run_fun! {
+        cd $dir_s;
+        git ls-files -t;
+    }?;
+    assert_eq!(
+        "S dir_1/dir_a/dir_x/f\nS dir_1/dir_a/dir_y/f\nS dir_1/dir_a/f\nS dir_1/f\nS dir_1/f2\nH dir_2/dir_a/dir_x/f\nH f",
+        actual
+    );
+
+    Ok(())
+}
+
+#[test]
+fn widening_checks_files_out() -> Result {
+    init();
+    let (dir, mut repo) = committed_nested_fixture()?;
+    let dir = dir.path();
+
+    repo.sparse_checkout_set(["dir_2"])?;
+    repo.sparse_checkout_set(["dir_1/dir_a", "dir_2"])?;
+
+    assert_eq!(
+        vec![
+            "dir_1/dir_a/dir_x/f",
+            "dir_1/dir_a/dir_y/f",
+            "dir_1/dir_a/f",
+            "dir_1/f",
+            "dir_1/f2",
+            "dir_2/dir_a/dir_x/f",
+            "f"
+        ],
+        sorted_files(dir)?
+    );
+    assert_eq!(
+        "in /1/a/y",
+        fs::read_to_string(dir.join("dir_1/dir_a/dir_y/f"))?
+    );
+
+    let status = repo.status()?;
+    assert!(status
+        .values()
+        .all(|s: &FileStatus| s.workspace == Status::Unmodified));
+
+    Ok(())
+}
+
+#[test]
+fn keeps_modified_files_outside_cone() -> Result {
+    init();
+    let (dir, mut repo) = committed_nested_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join("dir_2/dir_a/dir_x/f"), "changed")?;
+    repo.sparse_checkout_set(["dir_1"])?;
+
+    assert_eq!(
+        "changed",
+        fs::read_to_string(dir.join("dir_2/dir_a/dir_x/f"))?
+    );
+    let status = repo.status()?;
+    assert_eq!(
+        Status::Modified,
+        status[&writ::core::WsPath::new_unchecked("dir_2/dir_a/dir_x/f")].workspace
+    );
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::os::unix::fs::PermissionsExt;
+
+use bstr::ByteSlice;
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{db::Commit, Oid, PushStatus, PushUpdate};
+
+/// Like `ssh`, but runs the command here, logging its arguments
+const FAKE_SSH: &str = r#"#!/bin/sh
+echo "$@" >> "$(dirname "$0")/ssh.log"
+while [ $# -gt 2 ]; do shift; done
+exec sh -c "$2"
+"#;
+
+/// The tempdir has `fake-ssh`, a source repository `src` and a bare
+/// repository `dst`
+fn fake_ssh_remotes() -> eyre::Result<(TempDir, Repo)> {
+    let root = tempdir()?;
+    let root_s = root.path().to_str().unwrap();
+    let ssh = root.path().join("fake-ssh");
+    write_to(&ssh, FAKE_SSH)?;
+    fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755))?;
+
+    let src = root.path().join("src");
+    write_to(src.join("a.txt"), "a\n")?;
+    let src_s = src.to_str().unwrap();
+    run_fun! {
+        cd $src_s;
+        git init -q -b trunk;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+        git add .;
+        git commit -q -m $MSG;
+        cd $root_s;
+        git init --bare -q -b trunk dst;
+    }?;
+
+    let local = root.path().join("local");
+    let mut repo = Repo::init(&local)?;
+    repo.config.set("core.sshCommand", ssh.to_str().unwrap())?;
+    repo.config
+        .set("remote.origin.url", &format!("git@example.com:{src_s}"))?;
+    repo.config.set(
+        "remote.dst.url",
+        &format!("ssh://example.com:2222{root_s}/dst"),
+    )?;
+    repo.save_config()?;
+    // So that git recognizes the repository
+    repo.refs
+        .update_symbolic_ref(b"HEAD".as_bstr(), b"refs/heads/trunk".as_bstr())?;
+    Ok((root, repo))
+}
+
+fn rev_parse(repo: &std::path::Path, rev: &str) -> eyre::Result<Oid<Commit>> {
+    let repo = repo.to_str().unwrap();
+    let hex = run_fun!(cd $repo; git rev-parse $rev)?;
+    Ok(Oid::parse(hex)?)
+}
+
+#[test]
+fn fetches_and_pushes_over_ssh() -> Result {
+    init();
+    let (root, mut repo) = fake_ssh_remotes()?;
+    let trunk = rev_parse(&root.path().join("src"), "trunk")?;
+
+    repo.fetch("origin")?;
+    assert_eq!(
+        trunk,
+        rev_parse(&root.path().join("local"), "origin/trunk")?
+    );
+
+    let pushed = repo.push(
+        "dst",
+        &[PushUpdate {
+            local_ref: None,
+            remote_ref: "refs/heads/trunk".into(),
+            new: Some(trunk),
+            force: false,
+            lease: None,
+        }],
+    )?;
+    assert_eq!(PushStatus::Updated, pushed[0].status);
+    assert_eq!(trunk, rev_parse(&root.path().join("dst"), "trunk")?);
+
+    let src = root.path().join("src");
+    let dst = root.path().join("dst");
+    assert_eq!(
+        format!(
+            "-o SendEnv=GIT_PROTOCOL git@example.com git-upload-pack '{}'\n\
+             -p 2222 example.com git-receive-pack '{}'\n",
+            src.display(),
+            dst.display()
+        ),
+        fs::read_to_string(root.path().join("ssh.log"))?
+    );
+    Ok(())
+}
+
+#[test]
+fn reports_failing_ssh() -> Result {
+    init();
+    let (_root, mut repo) = fake_ssh_remotes()?;
+    repo.config.set("core.sshCommand", "false")?;
+    assert!(repo.fetch("origin").is_err());
+    Ok(())
+}
@@ -1,10 +1,21 @@
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
 use std::{thread, time::Duration};
 
+#[cfg(unix)]
 use cmd_lib::run_cmd;
 use test_support::assert_eq;
 use test_support::*;
+#[cfg(unix)]
+use writ::core::stat::Mode;
 
-use writ::core::{FileStatus, Status};
+use bstr::ByteSlice;
+use writ::core::{
+    db::Blob,
+    index::{Conflict, Entry},
+    repo::StatusError,
+    FileStatus, Stat, Status, StatusOptions, WsPath,
+};
 
 #[test]
 fn lists_untracked() -> Result {
@@ -149,6 +160,29 @@ fn reports_no_changed_when_no_files_are_modified() -> Result {
     Ok(())
 }
 
+#[test]
+fn is_clean_until_anything_but_untracked_files_differ() -> Result {
+    let (dir, mut repo) = init_with_commit()?;
+    let dir = dir.path();
+    assert!(repo.is_clean()?);
+    write_to(dir.join("untracked.txt"), "untracked")?;
+    assert!(repo.is_clean()?);
+
+    // The workspace differs from the index
+    write_to(dir.join("a/b/3.txt"), "hello")?;
+    assert!(!repo.is_clean()?);
+
+    // The index differs from HEAD
+    repo.add(["a/b/3.txt"])?;
+    assert!(!repo.is_clean()?);
+    repo.commit(NAME, EMAIL, MSG)?;
+    assert!(repo.is_clean()?);
+
+    fs::remove_file(dir.join("1.txt"))?;
+    assert!(!repo.is_clean()?);
+    Ok(())
+}
+
 #[test]
 fn reports_file_with_modified_contents() -> Result {
     let (dir, repo) = init_with_commit()?;
@@ -169,6 +203,7 @@ fn reports_file_with_modified_contents() -> Result {
     Ok(())
 }
 
+#[cfg(unix)]
 #[test]
 fn reports_mode_change_as_modified() -> Result {
     let (dir, repo) = init_with_commit()?;
@@ -259,6 +294,7 @@ fn reports_file_added_to_untracked_dir() -> Result {
     Ok(())
 }
 
+#[cfg(unix)]
 #[test]
 fn reports_index_modified_mode() -> Result {
     let (dir, mut repo) = init_with_commit()?;
@@ -343,3 +379,332 @@ fn reports_all_deleted_files_in_dir() -> Result {
 
     Ok(())
 }
+
+#[test]
+fn limits_to_pathspecs() -> Result {
+    let (dir, mut repo) = init_with_commit()?;
+    let dir = dir.path();
+
+    write_to(dir.join("1.txt"), "changed")?;
+    write_to(dir.join("a/2.txt"), "changed")?;
+    write_to(dir.join("a/untracked.txt"), "")?;
+    write_to(dir.join("untracked.txt"), "")?;
+    fs::remove_file(dir.join("a/b/3.txt"))?;
+
+    let status = repo
+        .status_of(vec!["a"])?
+        .into_values()
+        .filter(|s| s.workspace != Status::Unmodified || s.index != Status::Unmodified);
+
+    assert_contains_unordered(
+        status,
+        [
+            |s: &FileStatus| s.workspace == Status::Modified && s.path == "a/2.txt",
+            |s: &FileStatus| s.workspace == Status::Untracked && s.path == "a/untracked.txt",
+            |s: &FileStatus| s.workspace == Status::Deleted && s.path == "a/b/3.txt",
+        ],
+    );
+
+    Ok(())
+}
+
+#[test]
+fn pathspec_of_deleted_file() -> Result {
+    let (dir, mut repo) = init_with_commit()?;
+    fs::remove_file(dir.path().join("a/2.txt"))?;
+
+    let status = repo.status_of(vec!["a/2.txt", "./a/2.txt"])?.into_values();
+    assert_contains_unordered(
+        status,
+        [|s: &FileStatus| s.workspace == Status::Deleted && s.path == "a/2.txt"],
+    );
+
+    Ok(())
+}
+
+#[test]
+fn reports_conflicts() -> Result {
+    let (_dir, mut repo) = init_with_commit()?;
+
+    let entry = |path: &str, contents: &str| {
+        let oid = Blob::oid_for_file(contents.as_bytes().as_bstr());
+        Entry::new(WsPath::new_unchecked(path), oid, Stat::zeroed())
+    };
+
+    let mut index = repo.index.modify()?;
+    index.add_conflict(
+        &WsPath::new_unchecked("1.txt"),
+        Conflict {
+            base: Some(entry("1.txt", "one")),
+            ours: Some(entry("1.txt", "ours")),
+            theirs: Some(entry("1.txt", "theirs")),
+        },
+    );
+    index.add_conflict(
+        &WsPath::new_unchecked("new.txt"),
+        Conflict {
+            ours: Some(entry("new.txt", "ours")),
+            theirs: Some(entry("new.txt", "theirs")),
+            ..Conflict::default()
+        },
+    );
+    index.add_conflict(
+        &WsPath::new_unchecked("a/2.txt"),
+        Conflict {
+            base: Some(entry("a/2.txt", "two")),
+            theirs: Some(entry("a/2.txt", "theirs")),
+            ..Conflict::default()
+        },
+    );
+    index.commit()?;
+
+    let codes = not_unmodified_statuses(repo)?
+        .into_iter()
+        .map(|s| (s.path.to_string(), s.short_code()))
+        .collect::<Vec<_>>();
+    let expected = vec![
+        ("1.txt".to_string(), "UU".to_string()),
+        ("a/2.txt".to_string(), "DU".to_string()),
+        ("new.txt".to_string(), "AA".to_string()),
+    ];
+    assert_eq!(expected, codes);
+
+    Ok(())
+}
+
+#[test]
+fn conflict_status_has_sides() -> Result {
+    let (_dir, mut repo) = init_with_commit()?;
+
+    let ours = Blob::oid_for_file(b"ours".as_bstr());
+    let mut index = repo.index.modify()?;
+    index.add_conflict(
+        &WsPath::new_unchecked("1.txt"),
+        Conflict {
+            ours: Some(Entry::new(
+                WsPath::new_unchecked("1.txt"),
+                ours,
+                Stat::zeroed(),
+            )),
+            ..Conflict::default()
+        },
+    );
+    index.commit()?;
+
+    let status = repo.status()?;
+    let status = &status[&WsPath::new_unchecked("1.txt")];
+    assert_eq!(
+        Status::Conflicted {
+            base: None,
+            ours: Some(ours),
+            theirs: None
+        },
+        status.index
+    );
+    assert_eq!("AU", status.short_code());
+
+    Ok(())
+}
+
+#[test]
+fn doesnt_list_ignored_as_untracked() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join(".gitignore"), "*.log\ntarget/\n")?;
+    write_to(dir.join("a.log"), "")?;
+    write_to(dir.join("target/out"), "")?;
+    write_to(dir.join("src/b.log"), "")?;
+    write_to(dir.join("src/main.rs"), "")?;
+    write_to(dir.join(".git/info/exclude"), "secret\n")?;
+    write_to(dir.join("secret"), "")?;
+
+    let status = repo.status()?.into_values();
+    assert_contains_unordered(
+        status,
+        [
+            |s: &FileStatus| s.workspace == Status::Untracked && s.path == ".gitignore",
+            |s: &FileStatus| s.workspace == Status::Untracked && s.path == "src/main.rs",
+        ],
+    );
+
+    Ok(())
+}
+
+#[test]
+fn lists_ignored_when_requested() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join(".gitignore"), "*.log\ntarget/\n")?;
+    write_to(dir.join("a.log"), "")?;
+    write_to(dir.join("target/out"), "")?;
+    write_to(dir.join("src/.gitignore"), "!keep.log\n")?;
+    write_to(dir.join("src/keep.log"), "")?;
+
+    let options = StatusOptions {
+        ignored: true,
+        ..StatusOptions::default()
+    };
+    let status = repo.status_with(["."], &options)?.into_values();
+    assert_contains_unordered(
+        status,
+        [
+            |s: &FileStatus| s.workspace == Status::Untracked && s.path == ".gitignore",
+            |s: &FileStatus| s.workspace == Status::Untracked && s.path == "src/.gitignore",
+            |s: &FileStatus| s.workspace == Status::Untracked && s.path == "src/keep.log",
+            |s: &FileStatus| s.workspace == Status::Ignored && s.path == "a.log",
+            |s: &FileStatus| s.workspace == Status::Ignored && s.path == "target",
+        ],
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stops_when_cancelled() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    write_to(dir.path().join("file.txt"), "")?;
+
+    let options = StatusOptions::default();
+    options.cancel.cancel();
+    assert!(matches!(
+        repo.status_with(["."], &options),
+        Err(StatusError::Cancelled(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn reports_tracked_files_in_ignored_dirs() -> Result {
+    let (dir, mut repo) = init_with_commit()?;
+    let dir = dir.path();
+
+    write_to(dir.join(".gitignore"), "a/\n")?;
+    write_to(dir.join("a/2.txt"), "changed")?;
+    write_to(dir.join("a/new.txt"), "")?;
+
+    let options = StatusOptions {
+        ignored: true,
+        ..StatusOptions::default()
+    };
+    let status = repo
+        .status_with(["a"], &options)?
+        .into_values()
+        .filter(|s| s.workspace != Status::Unmodified);
+    assert_contains_unordered(
+        status,
+        [
+            |s: &FileStatus| s.workspace == Status::Modified && s.path == "a/2.txt",
+            |s: &FileStatus| s.workspace == Status::Ignored && s.path == "a/new.txt",
+        ],
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn reports_workspace_typechange() -> Result {
+    let (dir, repo) = init_with_commit()?;
+    let dir = dir.path();
+
+    fs::remove_file(dir.join("1.txt"))?;
+    std::os::unix::fs::symlink("a/2.txt", dir.join("1.txt"))?;
+
+    assert_contains_unordered(
+        not_unmodified_statuses(repo)?,
+        [|s: &FileStatus| {
+            s.workspace == Status::TypeChanged && s.index == Status::Unmodified && s.path == "1.txt"
+        }],
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn reports_index_typechange() -> Result {
+    let (dir, mut repo) = init_with_commit()?;
+    let dir = dir.path();
+
+    fs::remove_file(dir.join("1.txt"))?;
+    std::os::unix::fs::symlink("a/2.txt", dir.join("1.txt"))?;
+    repo.add(["1.txt"])?;
+
+    let status = repo.status_of(["1.txt"])?;
+    let status = &status[&WsPath::new_unchecked("1.txt")];
+    assert_eq!(Status::Unmodified, status.workspace);
+    assert_eq!(Status::TypeChanged, status.index);
+    assert_eq!("T ", status.short_code());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn lists_symlinks_without_following() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir = dir.path();
+
+    write_to(dir.join("dir/file.txt"), b"")?;
+    std::os::unix::fs::symlink("dir", dir.join("link"))?;
+
+    let status = repo.status()?.into_values();
+    assert_contains_unordered(
+        status,
+        [
+            |s: &FileStatus| s.workspace == Status::Untracked && s.path == "dir/file.txt",
+            |s: &FileStatus| s.workspace == Status::Untracked && s.path == "link",
+        ],
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn ignores_mode_changes_without_file_mode() -> Result {
+    let (dir, _repo) = init_with_commit()?;
+    let dir = dir.path();
+
+    write_to(dir.join(".git/config"), "[core]\n\tfilemode = false\n")?;
+    let mut perms = fs::metadata(dir.join("1.txt"))?.permissions();
+    perms.set_mode(0o755);
+    fs::set_permissions(dir.join("1.txt"), perms)?;
+
+    let mut repo = Repo::new(dir)?;
+    let status = repo.status_of(["1.txt"])?;
+    let status = &status[&WsPath::new_unchecked("1.txt")];
+    assert_eq!(Status::Unmodified, status.workspace);
+
+    // Adding keeps the recorded mode
+    repo.add(["1.txt"])?;
+    let entry = repo.index.entry(&WsPath::new_unchecked("1.txt")).unwrap();
+    assert_eq!(Mode::Regular, entry.mode());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn reports_mode_changes_with_file_mode() -> Result {
+    let (dir, repo) = init_with_commit()?;
+    let dir = dir.path();
+
+    let mut perms = fs::metadata(dir.join("1.txt"))?.permissions();
+    perms.set_mode(0o755);
+    fs::set_permissions(dir.join("1.txt"), perms)?;
+
+    assert_contains_unordered(
+        not_unmodified_statuses(repo)?,
+        [|s: &FileStatus| s.workspace == Status::Modified && s.path == "1.txt"],
+    );
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{submodule::SubmoduleStatus, Oid, WsPath};
+
+#[test]
+fn clones_and_records_submodules() -> Result {
+    init();
+    let root = tempdir()?;
+    let root_s = root.path().to_str().unwrap();
+    let url = serve_git_http(root.path())?;
+    let lib_url = format!("{url}/lib.git");
+    run_fun! {
+        cd $root_s;
+        git init -q -b trunk lib;
+        git -C lib -c user.name=$NAME -c user.email=$EMAIL commit -q --allow-empty -m first;
+        git -C lib -c user.name=$NAME -c user.email=$EMAIL commit -q --allow-empty -m second;
+        git clone -q --bare lib lib.git;
+        git init -q -b trunk app;
+        git -C app submodule -q add $lib_url lib;
+        git -C app -c user.name=$NAME -c user.email=$EMAIL commit -q -m "Add lib";
+        git clone -q app work;
+    }?;
+    let first = Oid::parse(run_fun!(cd $root_s; git -C lib rev-parse HEAD~1)?)?;
+    let second = Oid::parse(run_fun!(cd $root_s; git -C lib rev-parse HEAD)?)?;
+    let work = root.path().join("work");
+    let lib = WsPath::new_unchecked("lib");
+
+    let mut repo = Repo::new(&work)?;
+    let submodules = repo.submodules()?;
+    assert_eq!(1, submodules.len());
+    assert_eq!(
+        ("lib", &lib),
+        (submodules[0].name.as_str(), &submodules[0].path)
+    );
+    assert_eq!(Some(second), submodules[0].commit);
+    assert_eq!(SubmoduleStatus::Uninitialized, repo.submodule_status(&lib)?);
+
+    assert_eq!(vec!["lib".to_owned()], repo.init_submodules()?);
+    assert_eq!(Some(lib_url.as_str()), repo.config.get("submodule.lib.url"));
+    repo.update_submodules(&mut ())?;
+    assert_eq!(SubmoduleStatus::UpToDate, repo.submodule_status(&lib)?);
+    assert!(work.join(".git/modules/lib/config").exists());
+    let work_s = work.to_str().unwrap();
+    // Followed by what git describes it as
+    let status = run_fun!(cd $work_s; git submodule status)?;
+    assert!(
+        status.starts_with(&format!(" {} lib ", second.to_hex())),
+        "{}",
+        status
+    );
+
+    run_fun!(cd $work_s; git -C lib checkout -q HEAD~1)?;
+    assert_eq!(
+        SubmoduleStatus::Modified(first),
+        repo.submodule_status(&lib)?
+    );
+    assert_eq!(first, repo.record_submodule(&lib)?);
+    assert_eq!(
+        format!("160000 {} 0\tlib", first.to_hex()),
+        run_fun!(cd $work_s; git ls-files -s lib)?
+    );
+    Ok(())
+}
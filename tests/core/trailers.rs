@@ -0,0 +1,72 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use std::{path::Path, process::Command};
+
+use writ::core::{Trailer, TrailerOptions};
+
+const MESSAGES: &[&str] = &[
+    "Subject\n",
+    "Subject\n\nBody\n",
+    "Subject\n\nBody\n\nAcked-by: A\nReviewed-by: B\n",
+    "Subject\n\nBody\n\nReviewed-by: B\n  continued\nAcked-by: A\n\n# Comment\n",
+    "Subject\n\nSigned-off-by: A\nSome text\nthat isn't trailers\n",
+    "Subject\n\nReviewed-by: B\n---\n file.txt | 1 +\n",
+];
+
+fn trailer(token: &str, value: &str) -> Trailer {
+    Trailer {
+        token: token.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn git_interpret_trailers(dir: &Path, msg: &str, trailers: &[Trailer]) -> eyre::Result<String> {
+    let file = dir.join(".git/MSG");
+    write_to(&file, msg)?;
+    let mut command = Command::new("git");
+    command.current_dir(dir).arg("interpret-trailers");
+    for trailer in trailers {
+        command.arg(format!("--trailer={}: {}", trailer.token, trailer.value));
+    }
+    let output = command.arg(&file).output()?;
+    assert!(output.status.success());
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[test]
+fn adds_trailers_like_git() -> Result {
+    init();
+    let trailers = [trailer("Reviewed-by", "B"), trailer("Tested-by", "C")];
+
+    let configs: &[&[(&str, &str)]] = &[
+        &[],
+        &[("trailer.where", "start")],
+        &[("trailer.where", "after"), ("trailer.ifExists", "add")],
+        &[("trailer.where", "before"), ("trailer.ifExists", "replace")],
+        &[("trailer.ifExists", "addIfDifferent")],
+        &[
+            ("trailer.reviewed.key", "Reviewed-by"),
+            ("trailer.reviewed.ifExists", "doNothing"),
+            ("trailer.separators", ":#"),
+        ],
+    ];
+    for config in configs {
+        let (dir, _) = repo_fixture()?;
+        let dir_s = dir.path().to_str().unwrap();
+        for (name, value) in *config {
+            run_fun!(cd $dir_s; git config $name $value)?;
+        }
+        let repo = Repo::new(dir.path())?;
+        for msg in MESSAGES {
+            assert_eq!(
+                git_interpret_trailers(dir.path(), msg, &trailers)?,
+                repo.interpret_trailers(msg, &trailers, &TrailerOptions::default())?,
+                "{:?} with {:?}",
+                msg,
+                config
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{db::UntypedOid, stat::Mode, ws::Attributes, CacheInfo, WsPath};
+
+#[test]
+fn adds_entries_without_workspace() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+
+    let blob = run_fun!(cd $dir_s; echo "contents" | git hash-object -w --stdin)?;
+    let oid = UntypedOid::parse(&blob)?.to_typed();
+    repo.update_index_cacheinfo([CacheInfo::new(
+        Mode::Executable,
+        oid,
+        WsPath::new_normalized("bin/run")?,
+    )])?;
+    assert_eq!(
+        format!("100755 {blob} 0\tbin/run"),
+        run_fun!(cd $dir_s; git ls-files -s)?
+    );
+    assert!(!dir.path().join("bin/run").exists());
+
+    let info = format!(
+        "100644 blob {blob}\ta.txt\n\
+         100644 {blob} 1\tconflicted.txt\n\
+         100644 {blob} 2\tconflicted.txt\n\
+         0 {blob}\tbin/run\n"
+    );
+    repo.update_index_info(info.as_bytes())?;
+    assert_eq!(
+        format!(
+            "100644 {blob} 0\ta.txt\n\
+             100644 {blob} 1\tconflicted.txt\n\
+             100644 {blob} 2\tconflicted.txt"
+        ),
+        run_fun!(cd $dir_s; git ls-files -s)?
+    );
+
+    repo.update_index_info(format!("100644 {blob} 0\tconflicted.txt\n").as_bytes())?;
+    assert_eq!(
+        format!("100644 {blob} 0\ta.txt\n100644 {blob} 0\tconflicted.txt"),
+        run_fun!(cd $dir_s; git ls-files -s)?
+    );
+
+    assert!(repo
+        .update_index_info(format!("040000 tree {blob}\tdir\n").as_bytes())
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn refreshes_stat_of_touched_files() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let dir_s = dir.path().to_str().unwrap();
+    write_to(dir.path().join("a.txt"), "a")?;
+    write_to(dir.path().join("dir/b.txt"), "b")?;
+    repo.add(["a.txt", "dir/b.txt"])?;
+
+    run_fun!(cd $dir_s; touch -d "2001-01-01" a.txt dir/b.txt)?;
+    write_to(dir.path().join("dir/b.txt"), "B")?;
+    assert_eq!(
+        "a.txt\ndir/b.txt",
+        run_fun!(cd $dir_s; git diff-files --name-only)?
+    );
+
+    let mut attrs = Attributes::new(repo.git_dir())?;
+    let changed = repo
+        .index
+        .refresh(repo.workspace.as_ref().unwrap(), &mut attrs)?;
+    assert_eq!(vec![WsPath::new_unchecked("dir/b.txt")], changed);
+    // Only the file that changed still looks changed to git
+    assert_eq!(
+        "dir/b.txt",
+        run_fun!(cd $dir_s; git diff-files --name-only)?
+    );
+    Ok(())
+}
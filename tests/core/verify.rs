@@ -0,0 +1,183 @@
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{
+    db::UntypedOid,
+    verify::{SignatureFormat, SignatureStatus, Verification, Verifier, VerifyError},
+};
+
+fn signing_repo() -> eyre::Result<(tempfile::TempDir, Repo)> {
+    let dir = tempdir()?;
+    let dir_s = dir.path().to_str().unwrap();
+    run_fun! {
+        cd $dir_s;
+        git init -q;
+        git config user.name $NAME;
+        git config user.email $EMAIL;
+    }?;
+    let repo = Repo::new(dir.path())?;
+    Ok((dir, repo))
+}
+
+#[test]
+fn verifies_ssh_signatures() -> Result {
+    init();
+    let (dir, mut repo) = signing_repo()?;
+    let dir_s = dir.path().to_str().unwrap();
+    let key = dir.path().join("key");
+    let key_s = key.to_str().unwrap();
+    // Without a passphrase, which run_fun! can't pass as it's empty
+    let generated = std::process::Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f", key_s, "-C", EMAIL])
+        .status()?;
+    assert!(generated.success());
+    run_fun! {
+        cd $dir_s;
+        git config gpg.format ssh;
+        git config user.signingkey $key_s.pub;
+        git commit -q --allow-empty -m $MSG;
+        git commit -q -S --allow-empty -m $MSG;
+        git tag -s -m $MSG signed;
+        git tag -a -m $MSG unsigned;
+    }?;
+    let head = repo.refs.head()?.unwrap();
+    let parent = repo.db.load(head)?.parent.unwrap();
+    let tag = |name: &str| -> eyre::Result<UntypedOid> {
+        Ok(UntypedOid::parse(
+            run_fun!(cd $dir_s; git rev-parse $name)?,
+        )?)
+    };
+
+    assert_eq!(None, repo.verify_commit(parent)?);
+    assert_eq!(None, repo.verify_tag(tag("unsigned")?)?);
+    let unknown = repo.verify_commit(head)?.unwrap();
+    assert_eq!(
+        (SignatureFormat::Ssh, SignatureStatus::UnknownKey, None),
+        (unknown.format, unknown.status, unknown.signer)
+    );
+    let fingerprint = run_fun!(ssh-keygen -l -f $key_s.pub | cut -d " " -f 2)?;
+    assert_eq!(Some(fingerprint), unknown.key);
+
+    let allowed = dir.path().join("allowed_signers");
+    let public = std::fs::read_to_string(dir.path().join("key.pub"))?;
+    write_to(&allowed, format!("{EMAIL} {public}"))?;
+    repo.config
+        .set("gpg.ssh.allowedSignersFile", allowed.to_str().unwrap())?;
+    repo.save_config()?;
+    for verification in [
+        repo.verify_commit(head)?.unwrap(),
+        repo.verify_tag(tag("signed")?)?.unwrap(),
+    ] {
+        assert_eq!(
+            (SignatureStatus::Good, Some(EMAIL.to_owned())),
+            (verification.status, verification.signer)
+        );
+    }
+
+    let (_, mut data) = repo.db.load_raw(head.as_untyped())?.unwrap();
+    data.extend_from_slice(b"Tampered\n");
+    let tampered = repo.db.store_raw(b"commit", &data)?.to_typed();
+    assert_eq!(
+        SignatureStatus::Bad,
+        repo.verify_commit(tampered)?.unwrap().status
+    );
+    assert!(matches!(
+        repo.verify_tag(head.into_untyped()),
+        Err(VerifyError::WrongType { .. })
+    ));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn verifies_gpg_signatures() -> Result {
+    use std::os::unix::fs::PermissionsExt;
+
+    init();
+    let (dir, mut repo) = signing_repo()?;
+    let dir_s = dir.path().to_str().unwrap();
+    let home = dir.path().join("gnupg");
+    let home_s = home.to_str().unwrap();
+    let gpg = dir.path().join("gpg");
+    write_to(
+        &gpg,
+        format!("#!/bin/sh\nGNUPGHOME={home_s} exec gpg \"$@\"\n"),
+    )?;
+    std::fs::set_permissions(&gpg, std::fs::Permissions::from_mode(0o755))?;
+    let gpg_s = gpg.to_str().unwrap();
+    let uid = format!("{NAME} <{EMAIL}>");
+    run_fun! {
+        mkdir -m 700 $home_s;
+        $gpg_s -q --batch --passphrase= --quick-gen-key $uid ed25519 sign never 2>/dev/null;
+        cd $dir_s;
+        git config gpg.program $gpg_s;
+        git config user.signingkey $EMAIL;
+        git commit -q -S --allow-empty -m $MSG;
+    }?;
+    repo.config.set("gpg.program", gpg_s)?;
+    repo.save_config()?;
+    let head = repo.refs.head()?.unwrap();
+
+    let good = repo.verify_commit(head)?.unwrap();
+    assert_eq!(
+        (SignatureFormat::OpenPgp, SignatureStatus::Good, Some(uid)),
+        (good.format, good.status, good.signer)
+    );
+
+    let other = dir.path().join("empty");
+    let other_s = other.to_str().unwrap();
+    run_fun!(mkdir -m 700 $other_s)?;
+    write_to(
+        &gpg,
+        format!("#!/bin/sh\nGNUPGHOME={other_s} exec gpg \"$@\"\n"),
+    )?;
+    let unknown = repo.verify_commit(head)?.unwrap();
+    assert_eq!(SignatureStatus::UnknownKey, unknown.status);
+    assert_eq!(good.key, unknown.key);
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Recorded(std::cell::RefCell<Vec<(Vec<u8>, Vec<u8>)>>);
+
+impl Verifier for Recorded {
+    fn verify(
+        &self,
+        format: SignatureFormat,
+        payload: &[u8],
+        signature: &[u8],
+    ) -> std::result::Result<Verification, VerifyError> {
+        self.0
+            .borrow_mut()
+            .push((payload.to_vec(), signature.to_vec()));
+        Ok(Verification {
+            format,
+            status: SignatureStatus::Good,
+            signer: Some("recorded".to_owned()),
+            key: None,
+        })
+    }
+}
+
+#[test]
+fn uses_pluggable_verifiers() -> Result {
+    init();
+    let (dir, repo) = signing_repo()?;
+    let dir_s = dir.path().to_str().unwrap();
+    let signature = "-----BEGIN PGP SIGNATURE-----\n\niQ\n-----END PGP SIGNATURE-----\n";
+    let tag = format!(
+        "object {}\ntype commit\ntag v1\ntagger {NAME} <{EMAIL}> 0 +0000\n\n{MSG}\n{signature}",
+        UntypedOid::zero().to_hex()
+    );
+    let tag = run_fun!(cd $dir_s; echo -n $tag | git hash-object -w -t tag --stdin --literally)?;
+
+    let recorded = Recorded(Default::default());
+    let verification = repo
+        .verify_tag_with(UntypedOid::parse(tag)?, &recorded)?
+        .unwrap();
+    assert_eq!(SignatureFormat::OpenPgp, verification.format);
+    let (payload, signed) = recorded.0.into_inner().pop().unwrap();
+    assert!(payload.ends_with(format!("\n\n{MSG}\n").as_bytes()));
+    assert_eq!(signature.as_bytes(), &signed[..]);
+    Ok(())
+}
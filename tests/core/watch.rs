@@ -0,0 +1,101 @@
+use std::{
+    collections::BTreeMap,
+    thread,
+    time::{Duration, Instant},
+};
+
+use test_support::assert_eq;
+use test_support::*;
+
+use writ::core::{FileStatus, Status, WsPath};
+
+/// Events arrive asynchronously, so we wait for the cached status to catch
+/// up with the real one
+fn assert_catches_up(repo: &mut Repo) -> eyre::Result<BTreeMap<WsPath, FileStatus>> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let cached = repo.status_cached()?;
+        let actual = repo.status()?;
+        if cached == actual {
+            return Ok(cached);
+        }
+        if Instant::now() > deadline {
+            assert_eq!(actual, cached);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn workspace_status(statuses: &BTreeMap<WsPath, FileStatus>, path: &str) -> Option<Status> {
+    statuses
+        .get(&WsPath::new_unchecked(path))
+        .map(|status| status.workspace)
+}
+
+#[test]
+fn sees_workspace_changes() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let path = dir.path();
+    write_to(path.join("a.txt"), "a")?;
+    write_to(path.join("dir/b.txt"), "b")?;
+    repo.add(["."])?;
+    repo.commit(NAME, EMAIL, MSG)?;
+
+    let statuses = repo.status_cached()?;
+    assert_eq!(
+        Some(Status::Unmodified),
+        workspace_status(&statuses, "a.txt")
+    );
+
+    write_to(path.join("a.txt"), "changed")?;
+    write_to(path.join("dir/new.txt"), "new")?;
+    let statuses = assert_catches_up(&mut repo)?;
+    assert_eq!(Some(Status::Modified), workspace_status(&statuses, "a.txt"));
+    assert_eq!(
+        Some(Status::Untracked),
+        workspace_status(&statuses, "dir/new.txt")
+    );
+
+    fs::remove_dir_all(path.join("dir"))?;
+    let statuses = assert_catches_up(&mut repo)?;
+    assert_eq!(
+        Some(Status::Deleted),
+        workspace_status(&statuses, "dir/b.txt")
+    );
+    assert_eq!(None, workspace_status(&statuses, "dir/new.txt"));
+
+    Ok(())
+}
+
+#[test]
+fn sees_index_and_ignore_changes() -> Result {
+    init();
+    let (dir, mut repo) = repo_fixture()?;
+    let path = dir.path();
+    write_to(path.join("a.txt"), "a")?;
+    write_to(path.join("b.log"), "b")?;
+
+    let statuses = repo.status_cached()?;
+    assert_eq!(
+        Some(Status::Untracked),
+        statuses
+            .get(&WsPath::new_unchecked("a.txt"))
+            .map(|s| s.index)
+    );
+
+    repo.add(["a.txt"])?;
+    let statuses = assert_catches_up(&mut repo)?;
+    assert_eq!(
+        Some(Status::Added),
+        statuses
+            .get(&WsPath::new_unchecked("a.txt"))
+            .map(|s| s.index)
+    );
+
+    write_to(path.join(".gitignore"), "*.log\n")?;
+    let statuses = assert_catches_up(&mut repo)?;
+    assert_eq!(None, workspace_status(&statuses, "b.log"));
+
+    Ok(())
+}